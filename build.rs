@@ -0,0 +1,5 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // the root package builds the sources of the controller, the proto included
+    tonic_build::compile_protos("chaos-tproxy-controller/proto/tproxy.proto")?;
+    Ok(())
+}
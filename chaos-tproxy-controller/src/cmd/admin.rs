@@ -0,0 +1,259 @@
+use std::convert::{Infallible, TryInto};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use chaos_tproxy_proxy::raw_config::{check_rule_names, RawConfig as ProxyRawConfig, RawRule};
+use chaos_tproxy_proxy::snapshot::read_latest_snapshot;
+use http::header::CONTENT_TYPE;
+use http::{Method, Request, Response, StatusCode};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Client, Server};
+use serde::Serialize;
+use tokio::sync::oneshot::Receiver;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+
+use crate::cmd::command_line::{read_raw_config, DiffOpt};
+use crate::proxy::config::Config;
+use crate::proxy::diff::ConfigDiff;
+use crate::proxy::exec::Proxy;
+use crate::proxy::summary::ConfigSummary;
+use crate::raw_config::{parse_document, RawConfig, CONFIG_VERSION};
+
+/// AdminStatus is the body of `GET /status`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AdminStatus {
+    pub running: bool,
+    /// armed is false if the rules are paused.
+    pub armed: bool,
+    pub summary: Option<ConfigSummary>,
+}
+
+/// RULES is the prefix of the paths of the rules, e.g. `/rules/slow-api`.
+const RULES: &str = "/rules";
+
+/// DIFF is the path previewing the changes of a candidate config, nothing is applied.
+const DIFF: &str = "/config/diff";
+
+/// SLO is the path of the SLO impact report of the running proxy, read from its latest snapshot.
+const SLO: &str = "/slo";
+
+/// serve_admin serves the admin API on the localhost port until the shutdown is received. The
+/// changes are applied to the running proxy by [Proxy::update] under the lock of the proxy, so
+/// that they are applied one by one, and a change failing to apply leaves the config as it is.
+pub fn serve_admin(
+    port: u16,
+    proxy: Arc<Mutex<Proxy>>,
+    shutdown: Receiver<()>,
+) -> Result<JoinHandle<Result<()>>> {
+    let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+    let make_service = make_service_fn(move |_| {
+        let proxy = proxy.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |request| handle(proxy.clone(), request))) }
+    });
+    tracing::info!("Admin API listening on {}", addr);
+    let server = Server::try_bind(&addr)?
+        .serve(make_service)
+        .with_graceful_shutdown(async {
+            let _ = shutdown.await;
+        });
+    Ok(tokio::spawn(async move { Ok(server.await?) }))
+}
+
+async fn handle(proxy: Arc<Mutex<Proxy>>, request: Request<Body>) -> Result<Response<Body>> {
+    let status = |status: StatusCode, message: String| {
+        Response::builder().status(status).body(Body::from(message))
+    };
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    let mut proxy = proxy.lock().await;
+    let current = proxy.config.clone();
+
+    if method == Method::GET && path == "/status" {
+        let status = AdminStatus {
+            running: proxy.pid.is_some(),
+            armed: proxy.armed,
+            summary: proxy.summary(),
+        };
+        return Ok(Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .body(serde_json::to_vec(&status)?.into())?);
+    }
+    if method == Method::GET && path == SLO {
+        // the proxy runs in another process, its metrics are only shared by the snapshots
+        let dir = match current.as_ref().and_then(|config| config.snapshot.as_ref()) {
+            Some(snapshot) => snapshot.path.clone(),
+            None => {
+                return Ok(status(
+                    StatusCode::NOT_FOUND,
+                    "the SLO report is read from the snapshots, which are disabled".to_string(),
+                )?)
+            }
+        };
+        let report = read_latest_snapshot(&dir)
+            .await?
+            .and_then(|mut snapshot| snapshot.get_mut("slo").map(serde_json::Value::take))
+            .filter(|report| !report.is_null());
+        return match report {
+            Some(report) => Ok(Response::builder()
+                .header(CONTENT_TYPE, "application/json")
+                .body(serde_json::to_vec(&report)?.into())?),
+            None => Ok(status(
+                StatusCode::NOT_FOUND,
+                "no SLO report is written yet".to_string(),
+            )?),
+        };
+    }
+    if method == Method::POST && (path == "/pause" || path == "/resume") {
+        proxy.arm(path == "/resume");
+        return Ok(Response::new(Body::empty()));
+    }
+    if method == Method::POST && path == DIFF {
+        let body = hyper::body::to_bytes(request.into_body()).await?;
+        let diff = match read_config(&body).and_then(|config| proxy.diff(config)) {
+            Ok(diff) => diff,
+            Err(e) => return Ok(status(StatusCode::BAD_REQUEST, e.to_string())?),
+        };
+        return Ok(Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .body(serde_json::to_vec(&diff)?.into())?);
+    }
+
+    let config = match (&method, path.as_str(), current) {
+        (&Method::GET, "/config", Some(current)) => {
+            return Ok(Response::builder()
+                .header(CONTENT_TYPE, "application/json")
+                .body(serde_json::to_vec(&current)?.into())?)
+        }
+        (&Method::PUT, "/config", _) => {
+            let body = hyper::body::to_bytes(request.into_body()).await?;
+            match read_config(&body) {
+                Ok(config) => config,
+                Err(e) => return Ok(status(StatusCode::BAD_REQUEST, e.to_string())?),
+            }
+        }
+        (&Method::POST, RULES, Some(current)) => {
+            let body = hyper::body::to_bytes(request.into_body()).await?;
+            let rule: RawRule = match serde_json::from_slice(&body) {
+                Ok(rule) => rule,
+                Err(e) => return Ok(status(StatusCode::BAD_REQUEST, e.to_string())?),
+            };
+            match add_rule(current, rule) {
+                Ok(config) => config,
+                Err(e) => return Ok(status(StatusCode::CONFLICT, e.to_string())?),
+            }
+        }
+        (&Method::DELETE, _, Some(current)) if path.starts_with(RULES) => {
+            let name = match path.strip_prefix(RULES).and_then(|p| p.strip_prefix('/')) {
+                Some(name) if !name.is_empty() => name,
+                _ => return Ok(status(StatusCode::NOT_FOUND, String::new())?),
+            };
+            match remove_rule(current, name) {
+                Ok(config) => config,
+                Err(e) => return Ok(status(StatusCode::NOT_FOUND, e.to_string())?),
+            }
+        }
+        (&Method::GET | &Method::POST | &Method::DELETE, _, None) => {
+            return Ok(status(
+                StatusCode::NOT_FOUND,
+                "proxy is not running".to_string(),
+            )?)
+        }
+        _ => return Ok(status(StatusCode::NOT_FOUND, String::new())?),
+    };
+
+    if let Err(e) = proxy.update(config).await {
+        tracing::error!("fail to apply config by admin API: {}", e);
+        return Ok(status(StatusCode::UNPROCESSABLE_ENTITY, e.to_string())?);
+    }
+    tracing::info!("Admin API applied {} {}", method, path);
+    Ok(Response::new(Body::empty()))
+}
+
+/// diff_main previews the changes of the candidate config to the config of the running instance,
+/// by the admin API of the instance, and prints them. Nothing is applied.
+pub async fn diff_main(opt: &DiffOpt) -> Result<()> {
+    let mut config = read_raw_config(&opt.config, opt.format).await?;
+    // the included rules are read here, the config is sent as a document of the latest version
+    config.version = Some(CONFIG_VERSION);
+    let request = Request::post(format!("http://127.0.0.1:{}{}", opt.admin_port, DIFF))
+        .header(CONTENT_TYPE, "application/json")
+        .body(serde_json::to_vec(&config)?.into())?;
+    let response = Client::new().request(request).await?;
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    if !status.is_success() {
+        return Err(anyhow!(
+            "fail to diff config: {}",
+            String::from_utf8_lossy(&body)
+        ));
+    }
+    let diff: ConfigDiff = serde_json::from_slice(&body)?;
+    print!("{}", diff);
+    Ok(())
+}
+
+/// read_config reads a full config replacing the current one.
+fn read_config(body: &[u8]) -> Result<ProxyRawConfig> {
+    let raw: RawConfig = parse_document(serde_json::from_slice(body)?)?;
+    let config: Config = raw.try_into()?;
+    Ok(config.proxy_config)
+}
+
+/// add_rule appends the rule to the config, the rule must be named by a name not taken yet.
+fn add_rule(mut config: ProxyRawConfig, rule: RawRule) -> Result<ProxyRawConfig> {
+    if rule.name.is_none() {
+        return Err(anyhow!("the rules added by the admin API must be named"));
+    }
+    config.rules.push(rule);
+    check_rule_names(&config.rules)?;
+    Ok(config)
+}
+
+/// remove_rule removes the rule of the name from the config.
+fn remove_rule(mut config: ProxyRawConfig, name: &str) -> Result<ProxyRawConfig> {
+    let len = config.rules.len();
+    config
+        .rules
+        .retain(|rule| rule.name.as_deref() != Some(name));
+    if config.rules.len() == len {
+        return Err(anyhow!("rule {} not found", name));
+    }
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use chaos_tproxy_proxy::raw_config::{RawConfig as ProxyRawConfig, RawRule};
+
+    use crate::cmd::admin::{add_rule, remove_rule};
+
+    #[test]
+    fn test_admin_rules() {
+        let rule = |name: Option<&str>| -> RawRule {
+            serde_json::from_value(serde_json::json!({
+                "name": name,
+                "target": "Request",
+                "selector": {"path": "/api/*"},
+                "actions": {"abort": true},
+            }))
+            .unwrap()
+        };
+        let config = ProxyRawConfig {
+            rules: vec![rule(Some("abort-api")), rule(None)],
+            ..Default::default()
+        };
+
+        let added = add_rule(config.clone(), rule(Some("slow-api"))).unwrap();
+        assert_eq!(added.rules.len(), 3);
+        assert_eq!(added.rules[2].name.as_deref(), Some("slow-api"));
+        assert!(add_rule(config.clone(), rule(Some("abort-api"))).is_err());
+        assert!(add_rule(config.clone(), rule(None)).is_err());
+
+        let removed = remove_rule(added, "abort-api").unwrap();
+        assert_eq!(removed.rules.len(), 2);
+        assert_eq!(removed.rules[0].name, None);
+        assert!(remove_rule(removed, "abort-api").is_err());
+    }
+}
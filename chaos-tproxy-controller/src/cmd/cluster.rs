@@ -0,0 +1,376 @@
+use std::collections::BTreeMap;
+use std::convert::{Infallible, TryInto};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use anyhow::{anyhow, Result};
+use chaos_tproxy_proxy::metrics::PhaseReport;
+use chaos_tproxy_proxy::raw_config::RawReportConfig;
+use chaos_tproxy_proxy::report::AgentReport;
+use chaos_tproxy_proxy::signal::Signals;
+use http::header::{AUTHORIZATION, CONTENT_TYPE};
+use http::{Method, Request, Response, StatusCode};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Client, Server};
+use serde::{Deserialize, Serialize};
+use tokio::select;
+use tokio::signal::unix::SignalKind;
+use tokio::sync::oneshot::channel;
+use tokio::time::sleep;
+
+use crate::cmd::command_line::{read_raw_config, AgentOpt, ClusterOpt};
+use crate::proxy::config::Config;
+use crate::proxy::exec::Proxy;
+use crate::raw_config::{parse_document, RawConfig};
+
+/// VersionedConfig is the body of `GET /config`, the agents reload the proxy once the version
+/// changes.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VersionedConfig {
+    pub version: u64,
+    pub config: RawConfig,
+}
+
+/// ClusterReport is the body of `GET /report`, the totals sum up the latest reports of all the
+/// agents.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClusterReport {
+    pub agents: BTreeMap<String, AgentStatus>,
+    pub baseline: PhaseReport,
+    pub faulted: PhaseReport,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AgentStatus {
+    pub reported_secs_ago: f64,
+    pub report: AgentReport,
+}
+
+#[derive(Debug)]
+struct ClusterState {
+    config: VersionedConfig,
+    reports: BTreeMap<String, (Instant, AgentReport)>,
+}
+
+impl ClusterState {
+    fn report(&self) -> ClusterReport {
+        let sum = |phases: Vec<&PhaseReport>| {
+            let requests = phases.iter().map(|phase| phase.requests).sum();
+            let errors = phases.iter().map(|phase| phase.errors).sum();
+            PhaseReport {
+                requests,
+                errors,
+                error_ratio: if requests == 0 {
+                    0.0
+                } else {
+                    errors as f64 / requests as f64
+                },
+                // percentiles could not be summed up
+                p50_ms: None,
+                p99_ms: None,
+            }
+        };
+        ClusterReport {
+            agents: self
+                .reports
+                .iter()
+                .map(|(name, (at, report))| {
+                    (
+                        name.clone(),
+                        AgentStatus {
+                            reported_secs_ago: at.elapsed().as_secs_f64(),
+                            report: report.clone(),
+                        },
+                    )
+                })
+                .collect(),
+            baseline: sum(self.reports.values().map(|(_, r)| &r.baseline).collect()),
+            faulted: sum(self.reports.values().map(|(_, r)| &r.faulted).collect()),
+        }
+    }
+}
+
+/// cluster_main runs the controller of a cluster, it serves the config to the agents and
+/// aggregates their reports.
+pub async fn cluster_main(opt: &ClusterOpt) -> Result<()> {
+    let token = read_token(opt.token_file.as_deref()).await?.map(Arc::new);
+    // the config and the reports are only left open to the processes of the host
+    if token.is_none() && !opt.listen.ip().is_loopback() {
+        return Err(anyhow!(
+            "a token is required to listen on {}, see --token-file",
+            opt.listen
+        ));
+    }
+    let config = read_raw_config(&opt.config, opt.format).await?;
+    let state = Arc::new(Mutex::new(ClusterState {
+        config: VersionedConfig { version: 1, config },
+        reports: Default::default(),
+    }));
+    let make_service = make_service_fn(move |_| {
+        let state = state.clone();
+        let token = token.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                handle(state.clone(), token.clone(), request)
+            }))
+        }
+    });
+
+    let (sender, rx) = channel();
+    tracing::info!("Cluster controller listening on {}", opt.listen);
+    let server = Server::try_bind(&opt.listen)?
+        .serve(make_service)
+        .with_graceful_shutdown(async {
+            let _ = rx.await;
+        });
+    let spawn = tokio::spawn(server);
+
+    let mut signals = Signals::from_kinds(&[SignalKind::interrupt(), SignalKind::terminate()])?;
+    signals.wait().await?;
+    let _ = sender.send(());
+    spawn.await??;
+    Ok(())
+}
+
+async fn handle(
+    state: Arc<Mutex<ClusterState>>,
+    token: Option<Arc<String>>,
+    request: Request<Body>,
+) -> Result<Response<Body>> {
+    if !authorized(&request, token.as_deref().map(String::as_str)) {
+        return Ok(Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .body(Body::empty())?);
+    }
+    let json = |body: Vec<u8>| {
+        Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+    };
+    match (request.method(), request.uri().path()) {
+        (&Method::GET, "/config") => {
+            let body = serde_json::to_vec(&state.lock().unwrap().config)?;
+            Ok(json(body)?)
+        }
+        (&Method::PUT, "/config") => {
+            let body = hyper::body::to_bytes(request.into_body()).await?;
+            let config: RawConfig = match serde_json::from_slice(&body)
+                .map_err(Into::into)
+                .and_then(parse_document)
+            {
+                Ok(config) => config,
+                Err(e) => {
+                    return Ok(Response::builder()
+                        .status(StatusCode::BAD_REQUEST)
+                        .body(e.to_string().into())?)
+                }
+            };
+            let mut state = state.lock().unwrap();
+            state.config = VersionedConfig {
+                version: state.config.version + 1,
+                config,
+            };
+            tracing::info!("Cluster config updated to version {}", state.config.version);
+            Ok(Response::new(Body::empty()))
+        }
+        (&Method::POST, "/report") => {
+            let body = hyper::body::to_bytes(request.into_body()).await?;
+            let report: AgentReport = serde_json::from_slice(&body)?;
+            state
+                .lock()
+                .unwrap()
+                .reports
+                .insert(report.name.clone(), (Instant::now(), report));
+            Ok(Response::builder()
+                .status(StatusCode::NO_CONTENT)
+                .body(Body::empty())?)
+        }
+        (&Method::GET, "/report") => {
+            let body = serde_json::to_vec(&state.lock().unwrap().report())?;
+            Ok(json(body)?)
+        }
+        _ => Ok(Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())?),
+    }
+}
+
+/// authorized tells whether the request presents the bearer token, if the controller requires
+/// one.
+fn authorized<T>(request: &Request<T>, token: Option<&str>) -> bool {
+    let token = match token {
+        Some(token) => token,
+        None => return true,
+    };
+    request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        // the time taken tells nothing of how much of the token is guessed right
+        .is_some_and(|presented| {
+            presented.len() == token.len()
+                && presented
+                    .bytes()
+                    .zip(token.bytes())
+                    .fold(0, |diff, (a, b)| diff | (a ^ b))
+                    == 0
+        })
+}
+
+/// read_token reads the bearer token of the file, the surrounding whitespaces are trimmed.
+async fn read_token(path: Option<&Path>) -> Result<Option<String>> {
+    let path = match path {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    let token = tokio::fs::read_to_string(path).await?.trim().to_string();
+    if token.is_empty() {
+        return Err(anyhow!("token file {:?} is empty", path));
+    }
+    Ok(Some(token))
+}
+
+/// agent_main runs the data plane of a cluster, it applies the config of the controller to the
+/// proxy whenever it changes, and the proxy pushes its metrics to the controller.
+pub async fn agent_main(opt: &AgentOpt, verbose: u8) -> Result<()> {
+    let name = match &opt.name {
+        Some(name) => name.clone(),
+        None => hostname().await,
+    };
+    let token = read_token(opt.token_file.as_deref()).await?;
+    let mut proxy = Proxy::new(verbose).await;
+    let mut signals = Signals::from_kinds(&[SignalKind::interrupt(), SignalKind::terminate()])?;
+    let client = Client::new();
+    let mut version = 0;
+    loop {
+        match fetch_config(&client, &opt.controller, token.as_deref()).await {
+            Ok(config) if config.version != version => {
+                let raw_report = RawReportConfig {
+                    url: format!("http://{}/report", opt.controller),
+                    name: name.clone(),
+                    interval: opt.interval,
+                    token: token.clone(),
+                };
+                match apply_config(&mut proxy, config.config, raw_report).await {
+                    Ok(()) => {
+                        tracing::info!("Agent applied config version {}", config.version);
+                        version = config.version;
+                    }
+                    Err(e) => tracing::error!("fail to apply config {}: {}", config.version, e),
+                }
+            }
+            Ok(_) => {}
+            Err(e) => tracing::warn!("fail to fetch config from {}: {}", opt.controller, e),
+        }
+        select! {
+            _ = sleep(opt.interval) => {},
+            ret = signals.wait() => {
+                ret?;
+                break;
+            }
+        }
+    }
+    proxy.stop().await
+}
+
+/// apply_config applies the config like the admin API, the proxy is only restarted if the
+/// listeners or the network of the new config differ, so that the connections are kept.
+async fn apply_config(proxy: &mut Proxy, raw: RawConfig, report: RawReportConfig) -> Result<()> {
+    let mut config: Config = raw.try_into()?;
+    config.proxy_config.report = Some(report);
+    proxy.update(config.proxy_config).await
+}
+
+async fn fetch_config(
+    client: &Client<hyper::client::HttpConnector>,
+    controller: &str,
+    token: Option<&str>,
+) -> Result<VersionedConfig> {
+    let mut request = Request::get(format!("http://{}/config", controller));
+    if let Some(token) = token {
+        request = request.header(AUTHORIZATION, format!("Bearer {}", token));
+    }
+    let response = client.request(request.body(Body::empty())?).await?;
+    if !response.status().is_success() {
+        return Err(anyhow!("unexpected status {}", response.status()));
+    }
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    Ok(serde_json::from_slice(&body)?)
+}
+
+async fn hostname() -> String {
+    match tokio::fs::read_to_string("/proc/sys/kernel/hostname").await {
+        Ok(name) if !name.trim().is_empty() => name.trim().to_string(),
+        _ => "agent".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use chaos_tproxy_proxy::metrics::PhaseReport;
+    use chaos_tproxy_proxy::report::AgentReport;
+    use http::header::AUTHORIZATION;
+    use http::Request;
+
+    use crate::cmd::cluster::{authorized, ClusterState, VersionedConfig};
+
+    #[test]
+    fn test_authorized() {
+        let request = |value: Option<&str>| {
+            let mut request = Request::get("/config");
+            if let Some(value) = value {
+                request = request.header(AUTHORIZATION, value);
+            }
+            request.body(()).unwrap()
+        };
+        assert!(authorized(&request(None), None));
+        assert!(authorized(&request(Some("Bearer secret")), Some("secret")));
+        assert!(!authorized(&request(None), Some("secret")));
+        assert!(!authorized(&request(Some("Bearer secreT")), Some("secret")));
+        assert!(!authorized(
+            &request(Some("Bearer secret2")),
+            Some("secret")
+        ));
+        assert!(!authorized(&request(Some("secret")), Some("secret")));
+    }
+
+    #[test]
+    fn test_cluster_report() {
+        let report = |name: &str, requests, errors| AgentReport {
+            name: name.to_string(),
+            window_secs: 10.0,
+            baseline: PhaseReport {
+                requests,
+                errors: 0,
+                ..Default::default()
+            },
+            faulted: PhaseReport {
+                requests,
+                errors,
+                ..Default::default()
+            },
+            comparison: None,
+        };
+        let mut state = ClusterState {
+            config: VersionedConfig {
+                version: 1,
+                config: Default::default(),
+            },
+            reports: Default::default(),
+        };
+        for report in [report("a", 10, 1), report("b", 30, 9)] {
+            state
+                .reports
+                .insert(report.name.clone(), (Instant::now(), report));
+        }
+        let cluster = state.report();
+        assert_eq!(cluster.agents.len(), 2);
+        assert_eq!(cluster.baseline.requests, 40);
+        assert_eq!(cluster.faulted.errors, 10);
+        assert!((cluster.faulted.error_ratio - 0.25).abs() < f64::EPSILON);
+    }
+}
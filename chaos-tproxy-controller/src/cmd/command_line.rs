@@ -1,22 +1,60 @@
 use std::convert::TryInto;
-use std::path::PathBuf;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
 
 use anyhow::{anyhow, Result};
+use chaos_tproxy_proxy::raw_config::RawRule;
+use http::Uri;
+use humantime_serde::re::humantime::parse_duration;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::Value;
 use structopt::StructOpt;
 use tokio::fs::read_to_string;
+use tokio::io::AsyncReadExt;
 use tracing_subscriber::filter::LevelFilter;
+use wildmatch::WildMatch;
 
+use crate::cmd::logging::LogFormat;
+use crate::cmd::remote::read_remote_config;
 use crate::proxy::config::Config;
-use crate::raw_config::RawConfig;
+use crate::raw_config::{parse_document, RawConfig};
+
+/// PID_FILE is the pid file of the daemon by default.
+pub const PID_FILE: &str = "/var/run/chaos-tproxy.pid";
 
 //todo: name & about. (need discussion)
 #[derive(Debug, StructOpt)]
 #[structopt(name = "chaos-tproxy", about = "The option of chaos-tproxy")]
 pub struct Opt {
-    /// path of config file, required if interactive and daemon mode is disabled
+    /// path of config file, `-` reads it from stdin, or an HTTP(S) URL polled for the changes,
+    /// required if interactive and daemon mode is disabled
     #[structopt(name = "FILE", parse(from_os_str))]
     pub input: Option<PathBuf>,
 
+    /// inline config in json or yaml (or toml with `--format toml`), instead of a config file
+    #[structopt(long, conflicts_with = "FILE")]
+    pub config_text: Option<String>,
+
+    /// Watch the config file and its included files, applying their changes on the fly.
+    #[structopt(long)]
+    pub watch: bool,
+
+    /// how often the config of an HTTP(S) URL is polled, with the ETag of the last response.
+    #[structopt(long, default_value = "30s", parse(try_from_str = parse_duration))]
+    pub poll_interval: Duration,
+
+    /// port of the admin API managing the rules at runtime, served on localhost only
+    #[structopt(long)]
+    pub admin_port: Option<u16>,
+
+    /// address of the gRPC control API called by the chaos-daemon of Chaos Mesh, the config is
+    /// optional with it
+    #[structopt(long)]
+    pub grpc_listen: Option<SocketAddr>,
+
     /// Allows applying json config by stdin/stdout
     #[structopt(short, long)]
     pub interactive: bool,
@@ -26,6 +64,15 @@ pub struct Opt {
     #[structopt(short, long, parse(from_occurrences))]
     pub verbose: u8,
 
+    /// levels of the logs in the syntax of `RUST_LOG`, e.g. `info,hyper=warn`, on top of the `log`
+    /// section of the config
+    #[structopt(long)]
+    pub log_filter: Option<String>,
+
+    /// format of the logs: pretty or json, pretty by default
+    #[structopt(long, possible_values = &["pretty", "json"])]
+    pub log_format: Option<LogFormat>,
+
     /// Only run the sub proxy.
     #[structopt(long)]
     pub proxy: bool,
@@ -33,6 +80,200 @@ pub struct Opt {
     /// ipc path for sub proxy.
     #[structopt(long)]
     pub ipc_path: Option<PathBuf>,
+
+    /// Start the sub proxy disarmed, applying no rules until `SIGUSR2`.
+    #[structopt(long)]
+    pub disarmed: bool,
+
+    /// worker threads of the runtime, the number of CPUs by default. Told to the sub proxy by the
+    /// `runtime` section of the config.
+    #[structopt(long)]
+    pub worker_threads: Option<usize>,
+
+    /// format of config file: json, yaml or toml, told by the file extension by default.
+    #[structopt(long, possible_values = &["json", "yaml", "toml"])]
+    pub format: Option<ConfigFormat>,
+
+    /// Run in the background, the pid is written to the pid file.
+    #[structopt(long)]
+    pub daemon: bool,
+
+    /// pid file of the running instance, removed on exit, `/var/run/chaos-tproxy.pid` in daemon mode
+    /// by default
+    #[structopt(long, parse(from_os_str))]
+    pub pid_file: Option<PathBuf>,
+
+    /// file of the output in daemon mode, discarded by default
+    #[structopt(long, parse(from_os_str))]
+    pub log_file: Option<PathBuf>,
+
+    /// network namespace to divert the traffic of, e.g. of a container, instead of the current
+    /// one. A path like `/proc/<pid>/ns/net` or `/var/run/netns/<name>`, or the pid of a process
+    /// in it
+    #[structopt(long)]
+    pub netns: Option<String>,
+
+    /// file recording the changes of the network, to restore it after a crash,
+    /// `/var/run/chaos-tproxy-<netns inode>.state` by default
+    #[structopt(long, parse(from_os_str))]
+    pub state_file: Option<PathBuf>,
+
+    #[structopt(subcommand)]
+    pub cmd: Option<SubCommand>,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum SubCommand {
+    /// Run a built-in upstream server for demos and tests.
+    Stub(StubOpt),
+    /// Run the controller of a cluster, serving the config to the agents and aggregating their
+    /// reports.
+    Cluster(ClusterOpt),
+    /// Run the data plane of a cluster, driven by the controller.
+    Agent(AgentOpt),
+    /// Tell whether the instance of the pid file is running.
+    Status(StatusOpt),
+    /// Stop the instance of the pid file, and wait for it to exit.
+    Stop(StopOpt),
+    /// Restore the network left by an instance killed without restoring it.
+    Cleanup(CleanupOpt),
+    /// Print the changes a config file would make to the config of the running instance, by its
+    /// admin API, without applying it.
+    Diff(DiffOpt),
+}
+
+#[derive(Debug, StructOpt)]
+pub struct StatusOpt {
+    /// pid file of the instance.
+    #[structopt(long, default_value = PID_FILE, parse(from_os_str))]
+    pub pid_file: PathBuf,
+
+    /// port of the admin API of the instance, to tell whether its rules are active.
+    #[structopt(long)]
+    pub admin_port: Option<u16>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct StopOpt {
+    /// pid file of the instance.
+    #[structopt(long, default_value = PID_FILE, parse(from_os_str))]
+    pub pid_file: PathBuf,
+
+    /// how long to wait for the instance to drain and exit.
+    #[structopt(long, default_value = "30s", parse(try_from_str = parse_duration))]
+    pub timeout: Duration,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct CleanupOpt {
+    /// restore the network even if the instance of the state file is still running.
+    #[structopt(long)]
+    pub force: bool,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct DiffOpt {
+    /// path of the candidate config file.
+    #[structopt(name = "FILE", parse(from_os_str))]
+    pub config: PathBuf,
+
+    /// format of config file: json, yaml or toml, told by the file extension by default.
+    #[structopt(long, possible_values = &["json", "yaml", "toml"])]
+    pub format: Option<ConfigFormat>,
+
+    /// port of the admin API of the running instance.
+    #[structopt(long)]
+    pub admin_port: u16,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct ClusterOpt {
+    /// address the controller listens on, a token is required off the loopback addresses.
+    #[structopt(long, default_value = "127.0.0.1:7070")]
+    pub listen: SocketAddr,
+
+    /// path of the file of the bearer token the agents must present.
+    #[structopt(long, parse(from_os_str))]
+    pub token_file: Option<PathBuf>,
+
+    /// path of config file distributed to the agents.
+    #[structopt(name = "FILE", parse(from_os_str))]
+    pub config: PathBuf,
+
+    /// format of config file: json, yaml or toml, told by the file extension by default.
+    #[structopt(long, possible_values = &["json", "yaml", "toml"])]
+    pub format: Option<ConfigFormat>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct AgentOpt {
+    /// host:port of the controller.
+    #[structopt(long)]
+    pub controller: String,
+
+    /// name of the agent in the reports, the hostname by default.
+    #[structopt(long)]
+    pub name: Option<String>,
+
+    /// how often the agent polls the config and pushes the reports.
+    #[structopt(long, default_value = "5s", parse(try_from_str = parse_duration))]
+    pub interval: Duration,
+
+    /// path of the file of the bearer token of the controller.
+    #[structopt(long, parse(from_os_str))]
+    pub token_file: Option<PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct StubOpt {
+    /// port the stub listens on.
+    #[structopt(long, default_value = "9000")]
+    pub port: u16,
+
+    /// how the stub responds: echo, static or flaky.
+    #[structopt(long, default_value = "echo", possible_values = &["echo", "static", "flaky"])]
+    pub behavior: String,
+
+    /// status code of the static behavior.
+    #[structopt(long, default_value = "200")]
+    pub code: u16,
+
+    /// response body of the static behavior.
+    #[structopt(long, default_value = "")]
+    pub body: String,
+
+    /// probability of 503 responses of the flaky behavior.
+    #[structopt(long, default_value = "0.5")]
+    pub failure_rate: f64,
+
+    /// path of config file, its rules would be applied to the exchanges of the stub.
+    #[structopt(long, parse(from_os_str))]
+    pub config: Option<PathBuf>,
+
+    /// format of config file: json, yaml or toml, told by the file extension by default.
+    #[structopt(long, possible_values = &["json", "yaml", "toml"])]
+    pub format: Option<ConfigFormat>,
+}
+
+/// ConfigFormat introduces how the config file is parsed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Json,
+    Yaml,
+    Toml,
+}
+
+impl FromStr for ConfigFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "json" => Ok(ConfigFormat::Json),
+            "yaml" | "yml" => Ok(ConfigFormat::Yaml),
+            "toml" => Ok(ConfigFormat::Toml),
+            _ => Err(anyhow!("invalid config format {}", s)),
+        }
+    }
 }
 
 impl Opt {
@@ -50,24 +291,349 @@ impl Opt {
     }
 
     fn checked(self) -> Result<Self> {
-        if !self.interactive && !self.proxy && self.input.is_none() {
+        if !self.interactive
+            && !self.proxy
+            && self.input.is_none()
+            && self.config_text.is_none()
+            && self.grpc_listen.is_none()
+            && self.cmd.is_none()
+        {
             return Err(anyhow!("config file is required when interactive mode and daemon mode is all disabled, use `-h | --help` for more details"));
         }
+        if self.daemon && (self.interactive || self.reads_stdin()) {
+            return Err(anyhow!("stdin is not available in daemon mode"));
+        }
+        if self.watch && (self.input.is_none() || self.reads_stdin()) {
+            return Err(anyhow!("`--watch` requires a config file"));
+        }
+        if let Some(url) = self.remote_url() {
+            if self.watch {
+                return Err(anyhow!(
+                    "`--watch` is not available with a URL, it is polled"
+                ));
+            }
+            url.parse::<Uri>()
+                .map_err(|e| anyhow!("invalid config URL {}: {}", url, e))?;
+        }
         Ok(self)
     }
+
+    /// has_config tells whether the config is given by a file, stdin or the command line.
+    pub fn has_config(&self) -> bool {
+        self.input.is_some() || self.config_text.is_some()
+    }
+
+    /// reads_stdin tells whether the config is read from stdin, which is read only once.
+    pub fn reads_stdin(&self) -> bool {
+        self.input.as_deref() == Some(Path::new(STDIN))
+    }
+
+    /// remote_url returns the URL of the config if it is polled from an HTTP(S) server.
+    pub fn remote_url(&self) -> Option<&str> {
+        let input = self.input.as_deref()?.to_str()?;
+        if input.starts_with("http://") || input.starts_with("https://") {
+            Some(input)
+        } else {
+            None
+        }
+    }
 }
 
+/// STDIN is the path of the config read from stdin.
+const STDIN: &str = "-";
+
 pub async fn get_config_from_opt(opt: &Opt) -> Result<Config> {
-    match opt.input {
-        None => RawConfig::default(),
-        Some(ref path_buf) => {
-            let buffer = read_to_string(path_buf).await?;
-            match path_buf.extension().and_then(|ext| ext.to_str()) {
-                Some("json") => serde_json::from_str(&buffer)?,
-                Some("yaml") => serde_yaml::from_str(&buffer)?,
-                _ => return Err(anyhow!("invalid file extension")),
-            }
+    // the includes of the inline configs are relative to the working directory
+    let cwd = Path::new(".");
+    match (&opt.config_text, &opt.input) {
+        (Some(text), _) => parse_raw_config_with_includes(text, opt.format, cwd).await?,
+        (None, Some(_)) if opt.reads_stdin() => {
+            let mut buffer = String::new();
+            tokio::io::stdin().read_to_string(&mut buffer).await?;
+            parse_raw_config_with_includes(&buffer, opt.format, cwd).await?
+        }
+        (None, Some(_)) if opt.remote_url().is_some() => {
+            read_remote_config(opt.remote_url().unwrap(), opt.format).await?
         }
+        (None, Some(path_buf)) => read_raw_config(path_buf, opt.format).await?,
+        (None, None) => RawConfig::default(),
     }
     .try_into()
 }
+
+/// RawRuleFile is a file included by the config, holding some of the rules.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRuleFile {
+    // the rule files are migrated on their own, by their versions
+    #[allow(dead_code)]
+    version: Option<u64>,
+    rules: Vec<RawRule>,
+}
+
+/// read_raw_config reads the config file in the format, or the one told by its extension. The
+/// rules of the included files are appended to the rules of the config.
+pub async fn read_raw_config(path: &Path, format: Option<ConfigFormat>) -> Result<RawConfig> {
+    let format = match format {
+        Some(format) => format,
+        None => format_of(path)?,
+    };
+    let buffer = read_to_string(path).await?;
+    let base = path.parent().unwrap_or_else(|| Path::new(""));
+    parse_raw_config_with_includes(&buffer, Some(format), base).await
+}
+
+/// parse_raw_config_with_includes parses the config in the format, yaml (a superset of json) by
+/// default, and appends the rules of the files included relative to the base.
+async fn parse_raw_config_with_includes(
+    buffer: &str,
+    format: Option<ConfigFormat>,
+    base: &Path,
+) -> Result<RawConfig> {
+    let mut config = parse_raw_config(buffer, format.unwrap_or(ConfigFormat::Yaml))?;
+    if let Some(include) = config.include.take() {
+        let rules = config.rules.get_or_insert_with(Vec::new);
+        for include in include {
+            for file in expand_include(base, &include)? {
+                let buffer = read_to_string(&file).await?;
+                let included: RawRuleFile = parse_config(&buffer, format_of(&file)?)
+                    .map_err(|e| anyhow!("fail to read {}: {}", file.display(), e))?;
+                rules.extend(included.rules);
+            }
+        }
+    }
+    Ok(config)
+}
+
+/// watched_dirs returns the directories to watch for the changes of the config file and its
+/// included files. The directories are watched instead of the files, as the editors and the
+/// ConfigMap volumes replace the files by renaming.
+pub async fn watched_dirs(path: &Path, format: Option<ConfigFormat>) -> Result<Vec<PathBuf>> {
+    let format = match format {
+        Some(format) => format,
+        None => format_of(path)?,
+    };
+    let base = path.parent().unwrap_or_else(|| Path::new(""));
+    let config = parse_raw_config(&read_to_string(path).await?, format)?;
+    let mut dirs = vec![base.to_path_buf()];
+    for include in config.include.unwrap_or_default() {
+        let path = base.join(include);
+        let dir = if path.is_dir() {
+            path
+        } else {
+            path.parent().unwrap_or(base).to_path_buf()
+        };
+        if !dirs.contains(&dir) {
+            dirs.push(dir);
+        }
+    }
+    Ok(dirs)
+}
+
+/// format_of tells the format of the config file by its extension.
+fn format_of(path: &Path) -> Result<ConfigFormat> {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .ok_or_else(|| anyhow!("invalid file extension"))?
+        .parse()
+        .map_err(|_| anyhow!("invalid file extension"))
+}
+
+/// expand_include returns the files of an include in order of their paths. The include is a file,
+/// a directory whose config files are included, or a pattern with wildcards (`*` and `?`) in the
+/// file name.
+fn expand_include(base: &Path, include: &str) -> Result<Vec<PathBuf>> {
+    let path = base.join(include);
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default();
+    let (dir, pattern) = if path.is_dir() {
+        (path.clone(), None)
+    } else if name.contains(|c| c == '*' || c == '?') {
+        let dir = path.parent().unwrap_or_else(|| Path::new("")).to_path_buf();
+        (dir, Some(WildMatch::new(name)))
+    } else {
+        return Ok(vec![path]);
+    };
+    let mut files = vec![];
+    for entry in
+        std::fs::read_dir(&dir).map_err(|e| anyhow!("fail to read {}: {}", dir.display(), e))?
+    {
+        let file = entry?.path();
+        let matched = match &pattern {
+            Some(pattern) => file
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| pattern.matches(name)),
+            None => format_of(&file).is_ok(),
+        };
+        if matched && file.is_file() {
+            files.push(file);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+pub fn parse_raw_config(buffer: &str, format: ConfigFormat) -> Result<RawConfig> {
+    parse_config(buffer, format)
+}
+
+/// parse_config parses a config document of any supported version of the schema.
+fn parse_config<T: DeserializeOwned>(buffer: &str, format: ConfigFormat) -> Result<T> {
+    let document: Value = match format {
+        ConfigFormat::Json => serde_json::from_str(buffer)?,
+        ConfigFormat::Yaml => serde_yaml::from_str(buffer)?,
+        ConfigFormat::Toml => toml::from_str(buffer)?,
+    };
+    parse_document(document)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir, write};
+
+    use structopt::StructOpt;
+
+    use crate::cmd::command_line::{
+        parse_raw_config, parse_raw_config_with_includes, read_raw_config, watched_dirs,
+        ConfigFormat, Opt,
+    };
+    use crate::raw_config::RawConfig;
+
+    #[test]
+    fn test_parse_raw_config() {
+        let yaml = r#"
+proxy_ports: [80]
+rules:
+  - target: Request
+    selector:
+      path: /api/*
+    actions:
+      delay: 1s
+"#;
+        let toml = r#"
+proxy_ports = [80]
+
+[[rules]]
+target = "Request"
+selector = { path = "/api/*" }
+actions = { delay = "1s" }
+"#;
+        assert_eq!(
+            parse_raw_config(toml, ConfigFormat::Toml).unwrap(),
+            parse_raw_config(yaml, ConfigFormat::Yaml).unwrap()
+        );
+        assert!(parse_raw_config(yaml, ConfigFormat::Toml).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_include() {
+        let dir = tempfile::tempdir().unwrap();
+        let rule = |path: &str| {
+            format!(
+                "rules:\n  - {{target: Request, selector: {{path: {}}}, actions: {{}}}}\n",
+                path
+            )
+        };
+        create_dir(dir.path().join("rules")).unwrap();
+        write(dir.path().join("rules/b.yaml"), rule("/b")).unwrap();
+        write(dir.path().join("rules/a.yaml"), rule("/a")).unwrap();
+        write(dir.path().join("rules/README.md"), "").unwrap();
+        write(
+            dir.path().join("extra.json"),
+            r#"{"rules": [{"target": "Response", "selector": {"path": "/c"}, "actions": {}}]}"#,
+        )
+        .unwrap();
+        let config = dir.path().join("config.yaml");
+        write(
+            &config,
+            format!(
+                "proxy_ports: [80]\ninclude: [rules/*.yaml, extra.json]\n{}",
+                rule("/")
+            ),
+        )
+        .unwrap();
+
+        let paths = |config: RawConfig| -> Vec<String> {
+            config
+                .rules
+                .unwrap()
+                .into_iter()
+                .map(|rule| rule.selector.path.unwrap())
+                .collect()
+        };
+        let raw = read_raw_config(&config, None).await.unwrap();
+        assert_eq!(raw.include, None);
+        // the rules of the config come first, then the included files in order
+        assert_eq!(paths(raw), vec!["/", "/a", "/b", "/c"]);
+
+        // the config files of a directory are included
+        write(&config, "include: [rules]\n").unwrap();
+        let raw = read_raw_config(&config, None).await.unwrap();
+        assert_eq!(paths(raw), vec!["/a", "/b"]);
+
+        write(&config, "include: [missing.yaml]\n").unwrap();
+        assert!(read_raw_config(&config, None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_watched_dirs() {
+        let dir = tempfile::tempdir().unwrap();
+        create_dir(dir.path().join("rules")).unwrap();
+        let config = dir.path().join("config.yaml");
+        write(
+            &config,
+            "include: [rules, rules/*.yaml, extra.json, other/slow.yaml]\n",
+        )
+        .unwrap();
+        assert_eq!(
+            watched_dirs(&config, None).await.unwrap(),
+            vec![
+                dir.path().to_path_buf(),
+                dir.path().join("rules"),
+                dir.path().join("other"),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_config_text() {
+        let dir = tempfile::tempdir().unwrap();
+        let json = r#"{"proxy_ports": [80], "safe_mode": true}"#;
+        let yaml = "proxy_ports: [80]\nsafe_mode: true\n";
+        // json is parsed as yaml without a format
+        assert_eq!(
+            parse_raw_config_with_includes(json, None, dir.path())
+                .await
+                .unwrap(),
+            parse_raw_config_with_includes(yaml, None, dir.path())
+                .await
+                .unwrap()
+        );
+        let toml = "proxy_ports = [80]";
+        assert!(parse_raw_config_with_includes(toml, None, dir.path())
+            .await
+            .is_err());
+        assert!(
+            parse_raw_config_with_includes(toml, Some(ConfigFormat::Toml), dir.path())
+                .await
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_remote_url() {
+        let opt = |args: &[&str]| -> anyhow::Result<Opt> { Opt::from_iter_safe(args)?.checked() };
+        let url = "https://chaos.example.com/experiments/checkout.yaml";
+        assert_eq!(opt(&["chaos-tproxy", url]).unwrap().remote_url(), Some(url));
+        assert_eq!(
+            opt(&["chaos-tproxy", "config.yaml"]).unwrap().remote_url(),
+            None
+        );
+        // the URL is polled instead
+        assert!(opt(&["chaos-tproxy", "--watch", url]).is_err());
+        assert!(opt(&["chaos-tproxy", "https://"]).is_err());
+    }
+}
@@ -1 +1,231 @@
-//todo (delay)
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Result};
+use hyper::Client;
+use tokio::time::sleep;
+
+use crate::cmd::command_line::{CleanupOpt, StatusOpt, StopOpt};
+use crate::proxy::net::state::{load, restore, state_file};
+
+/// PidFile is the pid file of the running instance, it is removed when the instance exits.
+#[derive(Debug)]
+pub struct PidFile(PathBuf);
+
+impl PidFile {
+    /// create writes the pid of this process, it fails if the pid file is of another running
+    /// instance. The pid files of the instances exited without removing them are overwritten.
+    pub fn create(path: &Path) -> Result<Self> {
+        if let Some(pid) = read_pid(path)? {
+            if is_running(pid) {
+                return Err(anyhow!("chaos-tproxy is already running, pid {}", pid));
+            }
+        }
+        let mut file = File::create(path)
+            .with_context(|| format!("fail to create pid file {}", path.display()))?;
+        writeln!(file, "{}", std::process::id())?;
+        Ok(Self(path.to_path_buf()))
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.0) {
+            tracing::error!("fail to remove pid file {}: {}", self.0.display(), e);
+        }
+    }
+}
+
+/// read_pid reads the pid file, it returns None if there is none.
+pub fn read_pid(path: &Path) -> Result<Option<i32>> {
+    match fs::read_to_string(path) {
+        Ok(pid) => {
+            Ok(Some(pid.trim().parse().with_context(|| {
+                format!("invalid pid file {}", path.display())
+            })?))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// is_running tells whether the process of the pid exists.
+pub fn is_running(pid: i32) -> bool {
+    if pid <= 0 {
+        return false;
+    }
+    // the signal 0 only checks the process, EPERM tells it is of another user
+    let result = unsafe { libc::kill(pid, 0) };
+    result == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// daemonize runs this command again in the background in a new session, without `--daemon`.
+/// The output goes to the log file if any, and the background instance writes the pid file.
+pub fn daemonize(pid_file: &Path, log_file: Option<&Path>) -> Result<()> {
+    let args = daemon_args(std::env::args_os().skip(1), pid_file);
+    let (stdout, stderr) = match log_file {
+        Some(path) => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("fail to open log file {}", path.display()))?;
+            (Stdio::from(file.try_clone()?), Stdio::from(file))
+        }
+        None => (Stdio::null(), Stdio::null()),
+    };
+    let mut command = Command::new(std::env::current_exe()?);
+    command
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(stdout)
+        .stderr(stderr);
+    unsafe {
+        command.pre_exec(|| {
+            // detached from the terminal of the caller
+            if libc::setsid() == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let mut child = command.spawn()?;
+    // the errors of the config are reported by the caller
+    std::thread::sleep(Duration::from_millis(500));
+    if let Some(status) = child.try_wait()? {
+        return Err(anyhow!("chaos-tproxy exited on start: {}", status));
+    }
+    println!(
+        "chaos-tproxy is running in the background, pid {}",
+        child.id()
+    );
+    Ok(())
+}
+
+/// daemon_args are the arguments of the background instance.
+fn daemon_args(args: impl Iterator<Item = OsString>, pid_file: &Path) -> Vec<OsString> {
+    let mut args: Vec<OsString> = args.filter(|arg| arg != "--daemon").collect();
+    if !args.iter().any(|arg| {
+        arg.to_str()
+            .is_some_and(|arg| arg.starts_with("--pid-file"))
+    }) {
+        args.push("--pid-file".into());
+        args.push(pid_file.into());
+    }
+    args
+}
+
+/// status_main tells whether the instance of the pid file is running, and whether its rules are
+/// active by the admin API if the port is given.
+pub async fn status_main(opt: &StatusOpt) -> Result<()> {
+    let pid = match read_pid(&opt.pid_file)? {
+        Some(pid) if is_running(pid) => pid,
+        _ => return Err(anyhow!("chaos-tproxy is not running")),
+    };
+    println!("chaos-tproxy is running, pid {}", pid);
+    if let Some(port) = opt.admin_port {
+        let response = Client::new()
+            .get(format!("http://127.0.0.1:{}/status", port).parse()?)
+            .await?;
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        println!("{}", String::from_utf8_lossy(&body));
+    }
+    Ok(())
+}
+
+/// stop_main stops the instance of the pid file by `SIGTERM`, and waits for it to drain and exit.
+pub async fn stop_main(opt: &StopOpt) -> Result<()> {
+    let pid = match read_pid(&opt.pid_file)? {
+        Some(pid) if is_running(pid) => pid,
+        _ => return Err(anyhow!("chaos-tproxy is not running")),
+    };
+    unsafe {
+        libc::kill(pid, libc::SIGTERM);
+    }
+    let started = Instant::now();
+    while is_running(pid) {
+        if started.elapsed() > opt.timeout {
+            return Err(anyhow!(
+                "chaos-tproxy is still running after {:?}",
+                opt.timeout
+            ));
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    println!("chaos-tproxy stopped, pid {}", pid);
+    Ok(())
+}
+
+/// cleanup_main restores the network recorded in the state file by an instance killed without
+/// restoring it, e.g. by `SIGKILL`.
+pub fn cleanup_main(opt: &CleanupOpt) -> Result<()> {
+    let path = state_file();
+    let state = match load(&path)? {
+        Some(state) => state,
+        None => {
+            println!("no network to restore, state file {}", path.display());
+            return Ok(());
+        }
+    };
+    if is_running(state.pid as i32) && !opt.force {
+        return Err(anyhow!(
+            "chaos-tproxy is running, pid {}, stop it instead",
+            state.pid
+        ));
+    }
+    restore(&path, &state)?;
+    println!("network of chaos-tproxy restored, pid {}", state.pid);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsString;
+    use std::path::Path;
+
+    use crate::cmd::daemon::handler::{daemon_args, read_pid, PidFile};
+
+    #[test]
+    fn test_pid_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("chaos-tproxy.pid");
+        assert_eq!(read_pid(&path).unwrap(), None);
+
+        let pid_file = PidFile::create(&path).unwrap();
+        assert_eq!(read_pid(&path).unwrap(), Some(std::process::id() as i32));
+        // this process is running
+        assert!(PidFile::create(&path).is_err());
+        drop(pid_file);
+        assert!(!path.exists());
+
+        // the pid file of an exited instance is overwritten
+        std::fs::write(&path, format!("{}\n", i32::MAX)).unwrap();
+        let _pid_file = PidFile::create(&path).unwrap();
+        assert_eq!(read_pid(&path).unwrap(), Some(std::process::id() as i32));
+    }
+
+    #[test]
+    fn test_daemon_args() {
+        let args = |args: &[&str]| args.iter().map(OsString::from).collect::<Vec<_>>();
+        let pid_file = Path::new("/run/tproxy.pid");
+        assert_eq!(
+            daemon_args(
+                args(&["--daemon", "-v", "config.yaml"]).into_iter(),
+                pid_file
+            ),
+            args(&["-v", "config.yaml", "--pid-file", "/run/tproxy.pid"])
+        );
+        assert_eq!(
+            daemon_args(
+                args(&["config.yaml", "--pid-file=my.pid", "--daemon"]).into_iter(),
+                pid_file
+            ),
+            args(&["config.yaml", "--pid-file=my.pid"])
+        );
+    }
+}
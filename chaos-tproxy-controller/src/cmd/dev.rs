@@ -0,0 +1,102 @@
+use std::convert::TryInto;
+
+use anyhow::{anyhow, Result};
+use chaos_tproxy_proxy::embedded::Proxy;
+use chaos_tproxy_proxy::experiment::write_experiment_report;
+use chaos_tproxy_proxy::proxy::http::config::Config as ProxyConfig;
+use chaos_tproxy_proxy::signal::Signals;
+#[cfg(unix)]
+use tokio::select;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::cmd::command_line::{get_config_from_opt, Opt, SubCommand};
+use crate::cmd::logging::init_logging;
+use crate::cmd::stub::stub_main;
+
+/// dev_main runs the explicit proxy of the config in process off Linux, where the traffic could
+/// not be redirected, so that the rule files could be tried locally before being deployed to the
+/// nodes. The other modes need the network of Linux.
+pub async fn dev_main(opt: Opt) -> Result<()> {
+    let config = if opt.has_config() && opt.cmd.is_none() {
+        Some(get_config_from_opt(&opt).await?)
+    } else {
+        None
+    };
+    init_logging(&opt, config.as_ref().and_then(|config| config.log.as_ref()))?;
+    match &opt.cmd {
+        Some(SubCommand::Stub(stub)) => return stub_main(stub).await,
+        Some(_) => {
+            return Err(anyhow!(
+                "only the stub subcommand is available on this platform"
+            ))
+        }
+        None => {}
+    }
+    if opt.proxy
+        || opt.daemon
+        || opt.interactive
+        || opt.watch
+        || opt.netns.is_some()
+        || opt.admin_port.is_some()
+        || opt.grpc_listen.is_some()
+    {
+        return Err(anyhow!(
+            "only the explicit proxy of a config is available on this platform"
+        ));
+    }
+    let config = config.ok_or_else(|| anyhow!("config is required"))?;
+
+    let proxy_config: ProxyConfig = config.proxy_config.try_into()?;
+    let experiment_report = proxy_config.experiment_report.clone();
+    let mut proxy = Proxy::new(proxy_config);
+    proxy.arm(!opt.disarmed);
+    tracing::info!("Proxy starting in dev mode, only the explicit proxy is served");
+    proxy.start()?;
+
+    let mut signals = Signals::shutdown()?;
+    #[cfg(unix)]
+    serve_signals(&opt, &mut proxy, &mut signals).await?;
+    // the console of Windows only stops the proxy, nothing is reloaded or armed by the signals
+    #[cfg(not(unix))]
+    signals.wait().await?;
+    proxy.stop().await?;
+    if let Some(experiment_report) = &experiment_report {
+        write_experiment_report(experiment_report, &proxy.metrics()).await?;
+    }
+    Ok(())
+}
+
+/// serve_signals reloads the config on `SIGHUP`, disarms the proxy on `SIGUSR1` and arms it again
+/// on `SIGUSR2`, until one of the signals stopping the proxy is received.
+#[cfg(unix)]
+async fn serve_signals(opt: &Opt, proxy: &mut Proxy, signals: &mut Signals) -> Result<()> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut disarm = signal(SignalKind::user_defined1())?;
+    let mut arm = signal(SignalKind::user_defined2())?;
+    loop {
+        select! {
+            _ = signals.wait() => break,
+            _ = disarm.recv() => proxy.arm(false),
+            _ = arm.recv() => proxy.arm(true),
+            _ = hangup.recv() => {
+                if opt.reads_stdin() {
+                    tracing::warn!("no config file to reload");
+                    continue;
+                }
+                tracing::info!("Reloading config from {:?}", opt.input);
+                let result: Result<ProxyConfig> = get_config_from_opt(opt)
+                    .await
+                    .and_then(|config| config.proxy_config.try_into());
+                match result {
+                    // the listeners are kept, only the rules and the HTTP settings are swapped
+                    Ok(ProxyConfig { http_config, .. }) => proxy.reload(http_config),
+                    Err(e) => {
+                        tracing::error!("fail to reload config, the current one is kept: {}", e)
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,217 @@
+use std::convert::TryInto;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use anyhow::Result;
+use serde_json::Value;
+use tokio::sync::oneshot::Receiver;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use tonic::transport::Server;
+use tonic::{Request, Response, Status};
+
+use crate::cmd::grpc::pb::t_proxy_server::{TProxy, TProxyServer};
+use crate::cmd::grpc::pb::{
+    ApplyHttpChaosRequest, ApplyHttpChaosResponse, RecoverRequest, RecoverResponse, StatusRequest,
+    StatusResponse,
+};
+use crate::proxy::config::Config;
+use crate::proxy::exec::Proxy;
+use crate::raw_config::{parse_document, RawConfig};
+
+/// pb is generated from `proto/tproxy.proto`.
+pub mod pb {
+    tonic::include_proto!("tproxy");
+}
+
+/// serve_grpc serves the gRPC control API of Chaos Mesh until the shutdown is received.
+pub fn serve_grpc(
+    addr: SocketAddr,
+    proxy: Arc<Mutex<Proxy>>,
+    shutdown: Receiver<()>,
+) -> Result<JoinHandle<Result<()>>> {
+    let service = TProxyServer::new(TProxyService {
+        proxy,
+        instance_uid: Default::default(),
+    });
+    tracing::info!("gRPC control API listening on {}", addr);
+    Ok(tokio::spawn(async move {
+        Server::builder()
+            .add_service(service)
+            .serve_with_shutdown(addr, async {
+                let _ = shutdown.await;
+            })
+            .await?;
+        Ok(())
+    }))
+}
+
+#[derive(Debug)]
+pub struct TProxyService {
+    proxy: Arc<Mutex<Proxy>>,
+    /// instance_uid is of the HTTPChaos applied last, the recoveries of the other ones are stale.
+    instance_uid: Mutex<String>,
+}
+
+#[tonic::async_trait]
+impl TProxy for TProxyService {
+    async fn apply_http_chaos(
+        &self,
+        request: Request<ApplyHttpChaosRequest>,
+    ) -> Result<Response<ApplyHttpChaosResponse>, Status> {
+        let request = request.into_inner();
+        let mut proxy = self.proxy.lock().await;
+        let config = match http_chaos_config(&request).and_then(TryInto::<Config>::try_into) {
+            Ok(config) => config,
+            Err(e) => return Ok(Response::new(applied(&proxy, 400, e.to_string()))),
+        };
+        if let Err(e) = proxy.update(config.proxy_config).await {
+            tracing::error!("fail to apply HTTPChaos {}: {}", request.instance_uid, e);
+            return Ok(Response::new(applied(&proxy, 500, e.to_string())));
+        }
+        tracing::info!(
+            "gRPC control API applied HTTPChaos {}",
+            request.instance_uid
+        );
+        *self.instance_uid.lock().await = request.instance_uid;
+        Ok(Response::new(applied(&proxy, 200, String::new())))
+    }
+
+    async fn status(&self, _: Request<StatusRequest>) -> Result<Response<StatusResponse>, Status> {
+        let proxy = self.proxy.lock().await;
+        let summary = match proxy.summary() {
+            Some(summary) => {
+                serde_json::to_string(&summary).map_err(|e| Status::internal(e.to_string()))?
+            }
+            None => String::new(),
+        };
+        Ok(Response::new(StatusResponse {
+            running: proxy.pid.is_some(),
+            instance_uid: self.instance_uid.lock().await.clone(),
+            summary,
+        }))
+    }
+
+    async fn recover(
+        &self,
+        request: Request<RecoverRequest>,
+    ) -> Result<Response<RecoverResponse>, Status> {
+        let request = request.into_inner();
+        // locked in the same order as the applying
+        let mut proxy = self.proxy.lock().await;
+        let mut instance_uid = self.instance_uid.lock().await;
+        if !request.instance_uid.is_empty() && request.instance_uid != *instance_uid {
+            tracing::warn!(
+                "ignore the recovery of stale HTTPChaos {}",
+                request.instance_uid
+            );
+            return Ok(Response::new(RecoverResponse {}));
+        }
+        proxy
+            .stop()
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        tracing::info!("gRPC control API recovered HTTPChaos {}", instance_uid);
+        instance_uid.clear();
+        Ok(Response::new(RecoverResponse {}))
+    }
+}
+
+/// applied returns the response of the chaos-daemon, with the process running the proxy.
+fn applied(proxy: &Proxy, status_code: i32, error: String) -> ApplyHttpChaosResponse {
+    ApplyHttpChaosResponse {
+        instance: proxy.pid.map_or(0, i64::from),
+        start_time: proxy.pid.and_then(start_time_ms).unwrap_or_default(),
+        status_code,
+        error,
+    }
+}
+
+/// start_time_ms returns the start time of the process in unix milliseconds, which tells the
+/// chaos-daemon whether the pid has been reused.
+fn start_time_ms(pid: u32) -> Option<i64> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // the name of the process may contain spaces, the fields after it start from the state
+    let ticks: i64 = stat
+        .rsplit_once(')')?
+        .1
+        .split_whitespace()
+        .nth(19)?
+        .parse()
+        .ok()?;
+    let boot: i64 = std::fs::read_to_string("/proc/stat")
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("btime "))?
+        .trim()
+        .parse()
+        .ok()?;
+    let hz = unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as i64;
+    Some(boot * 1000 + ticks * 1000 / hz)
+}
+
+/// http_chaos_config translates the request of the chaos-daemon to the config, its rules are the
+/// HTTPChaos specs in the json of the rules of chaos-tproxy.
+fn http_chaos_config(request: &ApplyHttpChaosRequest) -> Result<RawConfig> {
+    let rules: Value = match request.rules.trim() {
+        "" => Value::Array(vec![]),
+        rules => serde_json::from_str(rules)?,
+    };
+    let mut document = serde_json::json!({
+        "proxy_ports": request.proxy_ports,
+        "rules": rules,
+    });
+    if !request.tls.trim().is_empty() {
+        document["tls"] = serde_json::from_str(&request.tls)?;
+    }
+    parse_document(document)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use crate::cmd::grpc::pb::ApplyHttpChaosRequest;
+    use crate::cmd::grpc::{http_chaos_config, start_time_ms};
+
+    #[test]
+    fn test_http_chaos_config() {
+        let request = ApplyHttpChaosRequest {
+            rules: r#"[{"target": "Request", "selector": {"port": 80, "path": "/api/*"},
+                "actions": {"abort": true}}]"#
+                .to_string(),
+            proxy_ports: vec![80],
+            instance_uid: "uid".to_string(),
+            ..Default::default()
+        };
+        let config = http_chaos_config(&request).unwrap();
+        assert_eq!(config.proxy_ports, Some(vec![80u16.into()]));
+        assert_eq!(config.rules.unwrap().len(), 1);
+        assert!(config.tls.is_none());
+
+        let empty = ApplyHttpChaosRequest {
+            rules: String::new(),
+            ..request.clone()
+        };
+        assert_eq!(http_chaos_config(&empty).unwrap().rules, Some(vec![]));
+
+        let invalid = ApplyHttpChaosRequest {
+            proxy_ports: vec![70000],
+            ..request
+        };
+        assert!(http_chaos_config(&invalid).is_err());
+    }
+
+    #[test]
+    fn test_start_time_ms() {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as i64;
+        let started = start_time_ms(std::process::id()).unwrap();
+        // the ticks of the clock are coarser than milliseconds
+        assert!(started <= now + 1000);
+        assert!(started > now - 3_600_000);
+        assert!(start_time_ms(u32::MAX).is_none());
+    }
+}
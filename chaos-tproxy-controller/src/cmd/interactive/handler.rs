@@ -7,6 +7,7 @@ use std::task::{Context, Poll};
 
 use anyhow::Error;
 use futures::TryStreamExt;
+use http::header::CONTENT_TYPE;
 use http::{Method, Request, Response, StatusCode};
 use hyper::server::conn::{Connection, Http};
 use hyper::service::Service;
@@ -20,7 +21,7 @@ use tracing::instrument;
 use crate::cmd::interactive::stdio::StdStream;
 use crate::proxy::config::Config;
 use crate::proxy::exec::Proxy;
-use crate::raw_config::RawConfig;
+use crate::raw_config::{parse_document, RawConfig};
 
 #[derive(Debug)]
 pub struct ConfigServer {
@@ -89,12 +90,22 @@ impl ConfigService {
             })
             .await?;
 
-        let raw_config: RawConfig = serde_json::from_slice(&request_data)?;
+        let raw_config: RawConfig = parse_document(serde_json::from_slice(&request_data)?)?;
         raw_config.try_into()
     }
 
     #[instrument]
     async fn handle(proxy: &mut Proxy, request: Request<Body>) -> anyhow::Result<Response<Body>> {
+        if request.method() == Method::GET && request.uri().path() == "/config" {
+            return Ok(match proxy.summary() {
+                Some(summary) => Response::builder()
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(serde_json::to_vec(&summary)?.into())?,
+                None => Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body("proxy is not running".into())?,
+            });
+        }
         if request.method() != Method::PUT {
             return Ok(Response::builder()
                 .status(StatusCode::METHOD_NOT_ALLOWED)
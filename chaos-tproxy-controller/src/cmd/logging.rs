@@ -0,0 +1,382 @@
+use std::convert::TryFrom;
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Error, Result};
+use once_cell::sync::OnceCell;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tracing_subscriber::filter::{Directive, LevelFilter};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, EnvFilter};
+
+use crate::cmd::command_line::Opt;
+use crate::raw_config::{RawLogConfig, RawLogFormat};
+
+/// LogConfig is the `log` section of the config, applied on start.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogConfig {
+    pub level: Option<LevelFilter>,
+    /// modules are the levels of the targets, e.g. `hyper=warn`.
+    pub modules: Vec<(String, LevelFilter)>,
+    /// path of the log file, the logs are written to stderr if none.
+    pub path: Option<PathBuf>,
+    pub format: LogFormat,
+    pub rotation: Option<Rotation>,
+}
+
+/// LogFormat introduces how the logs are formatted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Pretty,
+    /// one json object per line.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "pretty" => Ok(LogFormat::Pretty),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(anyhow!("invalid log format {}", s)),
+        }
+    }
+}
+
+impl LogFormat {
+    fn as_str(&self) -> &'static str {
+        match self {
+            LogFormat::Pretty => "pretty",
+            LogFormat::Json => "json",
+        }
+    }
+}
+
+/// Rotation rotates the log file by its size or its age, whichever comes first.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rotation {
+    pub max_bytes: Option<u64>,
+    pub interval: Option<Duration>,
+    /// keep is the number of the rotated files kept, `<path>.1` is the latest one.
+    pub keep: usize,
+}
+
+impl TryFrom<RawLogConfig> for LogConfig {
+    type Error = Error;
+
+    fn try_from(raw: RawLogConfig) -> Result<Self, Self::Error> {
+        let level = |level: &str| {
+            LevelFilter::from_str(level).map_err(|_| anyhow!("invalid log level {}", level))
+        };
+        let rotation = match raw.rotation {
+            None => None,
+            Some(rotation) => {
+                if rotation.max_bytes.is_none() && rotation.interval.is_none() {
+                    return Err(anyhow!("rotation of log requires max_bytes or interval"));
+                }
+                let keep = rotation.keep.unwrap_or(5);
+                if keep == 0 {
+                    return Err(anyhow!("keep of log rotation must be positive"));
+                }
+                Some(Rotation {
+                    max_bytes: rotation.max_bytes,
+                    interval: rotation.interval,
+                    keep,
+                })
+            }
+        };
+        if rotation.is_some() && raw.path.is_none() {
+            return Err(anyhow!("rotation of log requires path"));
+        }
+        Ok(Self {
+            level: raw.level.as_deref().map(level).transpose()?,
+            modules: raw
+                .modules
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(module, module_level)| Ok((module, level(&module_level)?)))
+                .collect::<Result<_>>()?,
+            path: raw.path,
+            format: match raw.format {
+                None | Some(RawLogFormat::Pretty) => LogFormat::Pretty,
+                Some(RawLogFormat::Json) => LogFormat::Json,
+            },
+            rotation,
+        })
+    }
+}
+
+/// Logging is the logging in effect, the sub proxy logs the same way.
+pub struct Logging {
+    format: LogFormat,
+    filter: String,
+    /// file is the log file, the logs of the sub proxy are forwarded to it.
+    file: Option<Arc<LogFile>>,
+}
+
+static LOGGING: OnceCell<Logging> = OnceCell::new();
+
+/// logging returns the logging initialized by [init_logging].
+pub fn logging() -> Option<&'static Logging> {
+    LOGGING.get()
+}
+
+impl Logging {
+    /// proxy_args returns the flags making the sub proxy log the same way.
+    pub fn proxy_args(&self) -> Vec<String> {
+        let mut args = vec![format!("--log-format={}", self.format.as_str())];
+        if !self.filter.is_empty() {
+            args.push(format!("--log-filter={}", self.filter));
+        }
+        args
+    }
+
+    pub fn file(&self) -> Option<Arc<LogFile>> {
+        self.file.clone()
+    }
+}
+
+/// init_logging installs the global subscriber by the flags and the `log` section of the config.
+/// The level of `-v` takes precedence over the one of `--log-filter`, which takes precedence over
+/// the one of the config.
+pub fn init_logging(opt: &Opt, config: Option<&LogConfig>) -> Result<()> {
+    let mut filter = vec![];
+    if let Some(config) = config {
+        filter.extend(config.level.map(|level| level.to_string()));
+        filter.extend(
+            config
+                .modules
+                .iter()
+                .map(|(module, level)| format!("{}={}", module, level)),
+        );
+    }
+    filter.extend(
+        opt.log_filter
+            .iter()
+            .flat_map(|filter| filter.split(','))
+            .filter(|directive| !directive.is_empty())
+            .map(str::to_string),
+    );
+    let (level, directives) = parse_filter(&filter)?;
+    let level = match (opt.verbose, level) {
+        (0, Some(level)) => level,
+        _ => opt.get_level_filter(),
+    };
+    let env_filter = |default: Directive| {
+        directives.iter().cloned().fold(
+            EnvFilter::from_default_env().add_directive(default),
+            EnvFilter::add_directive,
+        )
+    };
+
+    let format = opt
+        .log_format
+        .or_else(|| config.map(|config| config.format))
+        .unwrap_or(LogFormat::Pretty);
+    let file = match config {
+        Some(LogConfig {
+            path: Some(path),
+            rotation,
+            ..
+        }) => Some(Arc::new(LogFile::open(path, rotation.clone())?)),
+        _ => None,
+    };
+    let writer = match &file {
+        Some(file) => BoxMakeWriter::new(file.clone()),
+        None => BoxMakeWriter::new(std::io::stderr),
+    };
+    // the sub proxy writes to the pipe of the controller with `NO_COLOR`
+    let ansi = file.is_none() && std::env::var_os("NO_COLOR").is_none();
+    let registry = tracing_subscriber::registry()
+        .with(env_filter(level.into()))
+        .with(env_filter("chaos_tproxy".parse()?));
+    match format {
+        LogFormat::Pretty => registry
+            .with(fmt::layer().with_ansi(ansi).with_writer(writer))
+            .init(),
+        LogFormat::Json => registry
+            .with(fmt::layer().json().with_writer(writer))
+            .init(),
+    }
+
+    let _ = LOGGING.set(Logging {
+        format,
+        filter: filter.join(","),
+        file,
+    });
+    Ok(())
+}
+
+/// parse_filter parses the directives of the filter, the last bare level is returned apart.
+fn parse_filter(filter: &[String]) -> Result<(Option<LevelFilter>, Vec<Directive>)> {
+    let mut level = None;
+    let mut directives = vec![];
+    for directive in filter {
+        match LevelFilter::from_str(directive) {
+            Ok(filter) => level = Some(filter),
+            Err(_) => directives.push(
+                directive
+                    .parse()
+                    .map_err(|e| anyhow!("invalid log directive {}: {}", directive, e))?,
+            ),
+        }
+    }
+    Ok((level, directives))
+}
+
+/// LogFile appends the logs to the file, rotating it by the rotation.
+pub struct LogFile {
+    path: PathBuf,
+    rotation: Option<Rotation>,
+    state: Mutex<FileState>,
+}
+
+struct FileState {
+    file: File,
+    size: u64,
+    opened: Instant,
+}
+
+impl LogFile {
+    pub fn open(path: &Path, rotation: Option<Rotation>) -> Result<Self> {
+        let state = FileState::open(path)
+            .map_err(|e| anyhow!("fail to open log file {}: {}", path.display(), e))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            rotation,
+            state: Mutex::new(state),
+        })
+    }
+
+    /// rotated returns the path of the rotated file, e.g. `chaos-tproxy.log.1`.
+    fn rotated(&self, index: usize) -> PathBuf {
+        let mut path = OsString::from(&self.path);
+        path.push(format!(".{}", index));
+        path.into()
+    }
+
+    fn rotate(&self, state: &mut FileState, rotation: &Rotation) -> io::Result<()> {
+        for index in (1..rotation.keep).rev() {
+            let rotated = self.rotated(index);
+            if rotated.exists() {
+                std::fs::rename(&rotated, self.rotated(index + 1))?;
+            }
+        }
+        std::fs::rename(&self.path, self.rotated(1))?;
+        *state = FileState::open(&self.path)?;
+        Ok(())
+    }
+}
+
+impl FileState {
+    fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            size: file.metadata()?.len(),
+            file,
+            opened: Instant::now(),
+        })
+    }
+}
+
+impl Write for &LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(rotation) = &self.rotation {
+            let full = rotation
+                .max_bytes
+                .map(|max| state.size > 0 && state.size + buf.len() as u64 > max)
+                .unwrap_or(false);
+            let old = rotation
+                .interval
+                .map(|interval| state.opened.elapsed() >= interval)
+                .unwrap_or(false);
+            if full || old {
+                self.rotate(&mut state, rotation)?;
+            }
+        }
+        // a record is written at once, so that the records never interleave
+        state.file.write_all(buf)?;
+        state.size += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.state.lock().unwrap().file.flush()
+    }
+}
+
+/// forward_lines appends the lines of the output of the sub proxy to the log file.
+pub async fn forward_lines<R: AsyncRead + Unpin>(output: R, file: Arc<LogFile>) {
+    let mut lines = BufReader::new(output).lines();
+    while let Ok(Some(mut line)) = lines.next_line().await {
+        line.push('\n');
+        if let Err(e) = (&*file).write_all(line.as_bytes()) {
+            eprintln!("fail to write log file: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::time::Duration;
+
+    use tracing_subscriber::filter::LevelFilter;
+
+    use crate::cmd::logging::{parse_filter, LogFile, Rotation};
+
+    #[test]
+    fn test_parse_filter() {
+        let filter: Vec<String> = vec!["info".into(), "hyper=warn".into(), "debug".into()];
+        let (level, directives) = parse_filter(&filter).unwrap();
+        assert_eq!(level, Some(LevelFilter::DEBUG));
+        assert_eq!(directives.len(), 1);
+        assert_eq!(directives[0].to_string().to_lowercase(), "hyper=warn");
+        assert!(parse_filter(&["hyper=loud".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_log_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("chaos-tproxy.log");
+        let file = LogFile::open(
+            &path,
+            Some(Rotation {
+                max_bytes: Some(10),
+                interval: None,
+                keep: 2,
+            }),
+        )
+        .unwrap();
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            (&file).write_all(line.as_bytes()).unwrap();
+        }
+        let read = |path| std::fs::read_to_string(path).unwrap();
+        assert_eq!(read(path.clone()), "fourth\n");
+        assert_eq!(read(dir.path().join("chaos-tproxy.log.1")), "third\n");
+        assert_eq!(read(dir.path().join("chaos-tproxy.log.2")), "second\n");
+        assert!(!dir.path().join("chaos-tproxy.log.3").exists());
+
+        let file = LogFile::open(
+            &path,
+            Some(Rotation {
+                max_bytes: None,
+                interval: Some(Duration::ZERO),
+                keep: 1,
+            }),
+        )
+        .unwrap();
+        (&file).write_all(b"fifth\n").unwrap();
+        assert_eq!(read(path), "fifth\n");
+        assert_eq!(read(dir.path().join("chaos-tproxy.log.1")), "fourth\n");
+    }
+}
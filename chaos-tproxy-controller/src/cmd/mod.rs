@@ -1,3 +1,20 @@
+#[cfg(target_os = "linux")]
+pub mod admin;
+#[cfg(target_os = "linux")]
+pub mod cluster;
 pub mod command_line;
+#[cfg(target_os = "linux")]
 pub mod daemon;
+#[cfg(not(target_os = "linux"))]
+pub mod dev;
+#[cfg(target_os = "linux")]
+pub mod grpc;
+#[cfg(target_os = "linux")]
 pub mod interactive;
+pub mod logging;
+#[cfg(target_os = "linux")]
+pub mod netns;
+pub mod remote;
+pub mod stub;
+#[cfg(target_os = "linux")]
+pub mod watch;
@@ -0,0 +1,48 @@
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Result};
+
+/// netns_path returns the path of the network namespace, a pid stands for the one of the process,
+/// e.g. the pid of a container told by `docker inspect -f '{{.State.Pid}}' <id>`.
+pub fn netns_path(netns: &str) -> PathBuf {
+    match netns.parse::<u32>() {
+        Ok(pid) => PathBuf::from(format!("/proc/{}/ns/net", pid)),
+        Err(_) => PathBuf::from(netns),
+    }
+}
+
+/// enter_netns moves the calling thread into the network namespace. It must be called before the
+/// runtime starts, so that the threads of the runtime and the processes they spawn inherit it,
+/// and the network of the proxy is set up in it.
+pub fn enter_netns(netns: &str) -> Result<()> {
+    let path = netns_path(netns);
+    let file =
+        File::open(&path).map_err(|e| anyhow!("fail to open netns {}: {}", path.display(), e))?;
+    if unsafe { libc::setns(file.as_raw_fd(), libc::CLONE_NEWNET) } == -1 {
+        return Err(anyhow!(
+            "fail to enter netns {}: {}",
+            path.display(),
+            io::Error::last_os_error()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::cmd::netns::netns_path;
+
+    #[test]
+    fn test_netns_path() {
+        assert_eq!(netns_path("1234"), Path::new("/proc/1234/ns/net"));
+        assert_eq!(
+            netns_path("/var/run/netns/target"),
+            Path::new("/var/run/netns/target")
+        );
+    }
+}
@@ -0,0 +1,249 @@
+#[cfg(target_os = "linux")]
+use std::convert::TryInto;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use http::header::{ETAG, IF_NONE_MATCH};
+use http::{HeaderValue, Request, StatusCode, Uri};
+use hyper::client::HttpConnector;
+use hyper::{Body, Client};
+use hyper_rustls::HttpsConnector;
+use rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore};
+#[cfg(target_os = "linux")]
+use tokio::sync::Mutex;
+use tokio::time::timeout;
+#[cfg(target_os = "linux")]
+use tokio::time::{interval_at, Instant, Interval, MissedTickBehavior};
+
+use crate::cmd::command_line::{parse_raw_config, ConfigFormat};
+#[cfg(target_os = "linux")]
+use crate::cmd::watch::rule_diff;
+#[cfg(target_os = "linux")]
+use crate::proxy::config::Config;
+#[cfg(target_os = "linux")]
+use crate::proxy::exec::Proxy;
+use crate::raw_config::RawConfig;
+
+/// FETCH_TIMEOUT is how long a poll waits for the server, the current config is kept on timeout.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// RemoteConfig polls the config from an HTTP(S) URL, and hot-swaps the config of the proxy when
+/// it changes. The ETag of the last response is sent back by `If-None-Match`, so that the server
+/// answers `304 Not Modified` without the config if it is not changed.
+#[cfg(target_os = "linux")]
+pub struct RemoteConfig {
+    url: Uri,
+    format: Option<ConfigFormat>,
+    client: Client<HttpsConnector<HttpConnector>>,
+    ticks: Interval,
+    etag: Option<HeaderValue>,
+    /// current is the config fetched last, the responses not changing it are ignored.
+    current: RawConfig,
+}
+
+#[cfg(target_os = "linux")]
+impl RemoteConfig {
+    pub async fn new(url: &str, format: Option<ConfigFormat>, period: Duration) -> Result<Self> {
+        let url: Uri = url.parse()?;
+        let client = client();
+        let (current, etag) = fetch(&client, &url, format, None)
+            .await?
+            .ok_or_else(|| anyhow!("{} is not modified without an ETag", url))?;
+        let mut ticks = interval_at(Instant::now() + period, period);
+        // the polls missed by a slow server are not made up
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        Ok(Self {
+            url,
+            format,
+            client,
+            ticks,
+            etag,
+            current,
+        })
+    }
+
+    /// tick waits for the next poll.
+    pub async fn tick(&mut self) {
+        self.ticks.tick().await;
+    }
+
+    /// reload polls the config and applies it to the proxy if it is changed, the current one is
+    /// kept if the new one is invalid.
+    pub async fn reload(&mut self, proxy: &Mutex<Proxy>) -> Result<()> {
+        let (raw, etag) =
+            match fetch(&self.client, &self.url, self.format, self.etag.as_ref()).await? {
+                None => return Ok(()),
+                Some(fetched) => fetched,
+            };
+        if raw == self.current {
+            self.etag = etag;
+            return Ok(());
+        }
+        let config: Config = raw.clone().try_into()?;
+        proxy.lock().await.update(config.proxy_config).await?;
+
+        let (added, removed) = rule_diff(
+            self.current.rules.as_deref().unwrap_or_default(),
+            raw.rules.as_deref().unwrap_or_default(),
+        );
+        tracing::info!(
+            "Config {} reloaded, rules added: {:?}, removed: {:?}",
+            self.url,
+            added,
+            removed
+        );
+        self.etag = etag;
+        self.current = raw;
+        Ok(())
+    }
+}
+
+/// read_remote_config fetches the config from the HTTP(S) URL once.
+pub async fn read_remote_config(url: &str, format: Option<ConfigFormat>) -> Result<RawConfig> {
+    let url: Uri = url.parse()?;
+    let (raw, _) = fetch(&client(), &url, format, None)
+        .await?
+        .ok_or_else(|| anyhow!("{} is not modified without an ETag", url))?;
+    Ok(raw)
+}
+
+/// client returns the client of the remote configs, trusting the webpki roots.
+fn client() -> Client<HttpsConnector<HttpConnector>> {
+    let mut http = HttpConnector::new();
+    http.enforce_http(false);
+    let mut roots = RootCertStore::empty();
+    roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            ta.subject,
+            ta.spki,
+            ta.name_constraints,
+        )
+    }));
+    let https = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_tls_config(
+            ClientConfig::builder()
+                .with_safe_defaults()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        )
+        .https_or_http()
+        .enable_http1()
+        .enable_http2()
+        .wrap_connector(http);
+    Client::builder().build(https)
+}
+
+/// fetch gets the config and its ETag, or None if the server tells it is not modified since the
+/// response of the ETag. The format is told by the extension of the path, yaml (a superset of
+/// json) by default.
+async fn fetch(
+    client: &Client<HttpsConnector<HttpConnector>>,
+    url: &Uri,
+    format: Option<ConfigFormat>,
+    etag: Option<&HeaderValue>,
+) -> Result<Option<(RawConfig, Option<HeaderValue>)>> {
+    let mut request = Request::get(url.clone());
+    if let Some(etag) = etag {
+        request = request.header(IF_NONE_MATCH, etag);
+    }
+    let response = timeout(FETCH_TIMEOUT, client.request(request.body(Body::empty())?))
+        .await
+        .map_err(|_| anyhow!("fail to fetch {}: timed out", url))??;
+    match response.status() {
+        StatusCode::NOT_MODIFIED if etag.is_some() => return Ok(None),
+        status if !status.is_success() => {
+            return Err(anyhow!("fail to fetch {}: {}", url, status));
+        }
+        _ => {}
+    }
+    let etag = response.headers().get(ETAG).cloned();
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    let format = format.unwrap_or_else(|| {
+        Path::new(url.path())
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .and_then(|ext| ext.parse().ok())
+            .unwrap_or(ConfigFormat::Yaml)
+    });
+    let raw = parse_raw_config(std::str::from_utf8(&body)?, format)
+        .map_err(|e| anyhow!("fail to read {}: {}", url, e))?;
+    if raw.include.is_some() {
+        return Err(anyhow!("include is not available in a remote config"));
+    }
+    Ok(Some((raw, etag)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+
+    use http::header::{ETAG, IF_NONE_MATCH};
+    use http::{Response, StatusCode};
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Server};
+
+    use crate::cmd::remote::{client, fetch};
+
+    #[tokio::test]
+    async fn test_fetch() {
+        // the config served, and its ETag
+        let served = Arc::new(Mutex::new(("\"v1\"", "proxy_ports: [80]\n")));
+        let state = served.clone();
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service_fn(
+            move |_| {
+                let state = state.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |request: http::Request<Body>| {
+                        let (etag, body) = *state.lock().unwrap();
+                        let response = if request.headers().get(IF_NONE_MATCH)
+                            == Some(&etag.parse().unwrap())
+                        {
+                            Response::builder()
+                                .status(StatusCode::NOT_MODIFIED)
+                                .body(Body::empty())
+                        } else if request.uri().path() == "/missing.yaml" {
+                            Response::builder()
+                                .status(StatusCode::NOT_FOUND)
+                                .body(Body::empty())
+                        } else {
+                            Response::builder()
+                                .header(ETAG, etag)
+                                .body(Body::from(body))
+                        };
+                        async move { Ok::<_, Infallible>(response.unwrap()) }
+                    }))
+                }
+            },
+        ));
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        let url = format!("http://{}/config.yaml", addr).parse().unwrap();
+        let client = client();
+
+        let (raw, etag) = fetch(&client, &url, None, None).await.unwrap().unwrap();
+        assert_eq!(raw.proxy_ports, Some(vec![80]));
+        assert_eq!(etag.as_ref().unwrap(), "\"v1\"");
+        // not modified since the ETag
+        assert!(fetch(&client, &url, None, etag.as_ref())
+            .await
+            .unwrap()
+            .is_none());
+
+        *served.lock().unwrap() = ("\"v2\"", "proxy_ports: [80, 8080]\n");
+        let (raw, etag) = fetch(&client, &url, None, etag.as_ref())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(raw.proxy_ports, Some(vec![80, 8080]));
+        assert_eq!(etag.unwrap(), "\"v2\"");
+
+        let missing = format!("http://{}/missing.yaml", addr).parse().unwrap();
+        assert!(fetch(&client, &missing, None, None).await.is_err());
+
+        *served.lock().unwrap() = ("\"v3\"", "include: [rules/*.yaml]\n");
+        assert!(fetch(&client, &url, None, None).await.is_err());
+    }
+}
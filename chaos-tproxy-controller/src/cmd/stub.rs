@@ -0,0 +1,60 @@
+use std::convert::TryInto;
+
+use anyhow::{anyhow, Result};
+use chaos_tproxy_proxy::handler::http::rule::Rule;
+use chaos_tproxy_proxy::signal::Signals;
+use chaos_tproxy_proxy::stub::{Behavior, StubConfig, StubServer};
+use http::StatusCode;
+use tokio::sync::oneshot::channel;
+
+use crate::cmd::command_line::{read_raw_config, StubOpt};
+
+pub async fn get_stub_config(opt: &StubOpt) -> Result<StubConfig> {
+    let behavior = match opt.behavior.as_str() {
+        "echo" => Behavior::Echo,
+        "static" => Behavior::Static {
+            code: StatusCode::from_u16(opt.code)?,
+            body: opt.body.clone().into(),
+        },
+        "flaky" => {
+            if !(0.0..=1.0).contains(&opt.failure_rate) {
+                return Err(anyhow!("failure rate must be in [0, 1]"));
+            }
+            Behavior::Flaky {
+                failure_rate: opt.failure_rate,
+            }
+        }
+        behavior => return Err(anyhow!("invalid behavior {}", behavior)),
+    };
+    let rules = match &opt.config {
+        None => vec![],
+        Some(path) => read_raw_config(path, opt.format)
+            .await?
+            .rules
+            .unwrap_or_default()
+            .into_iter()
+            .map(TryInto::try_into)
+            .collect::<Result<Vec<Rule>, _>>()?,
+    };
+    Ok(StubConfig {
+        port: opt.port,
+        behavior,
+        rules,
+    })
+}
+
+pub async fn stub_main(opt: &StubOpt) -> Result<()> {
+    let server = StubServer::new(get_stub_config(opt).await?);
+    let (sender, rx) = channel();
+    let spawn = tokio::spawn(async move {
+        if let Err(e) = server.serve(rx).await {
+            tracing::error!("Stub exited with error: {}", e);
+        }
+    });
+
+    let mut signals = Signals::shutdown()?;
+    signals.wait().await?;
+    let _ = sender.send(());
+    spawn.await?;
+    Ok(())
+}
@@ -0,0 +1,173 @@
+use std::convert::TryInto;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::Duration;
+
+use anyhow::Result;
+use chaos_tproxy_proxy::raw_config::RawRule;
+use notify::{watcher, DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+use tokio::sync::Mutex;
+
+use crate::cmd::command_line::{read_raw_config, watched_dirs, ConfigFormat};
+use crate::proxy::config::Config;
+use crate::proxy::exec::Proxy;
+use crate::raw_config::RawConfig;
+
+/// DEBOUNCE is how long the events of a change are gathered, as saving a file takes a few of them.
+const DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// ConfigWatcher watches the config file and its included files by inotify, and hot-swaps the
+/// config of the proxy when they change.
+pub struct ConfigWatcher {
+    path: PathBuf,
+    format: Option<ConfigFormat>,
+    watcher: RecommendedWatcher,
+    dirs: Vec<PathBuf>,
+    changes: UnboundedReceiver<()>,
+    /// current is the config read last, the events not changing it are ignored.
+    current: RawConfig,
+}
+
+impl ConfigWatcher {
+    pub async fn new(path: &Path, format: Option<ConfigFormat>) -> Result<Self> {
+        let (tx, rx) = mpsc::channel();
+        let (sender, changes) = unbounded_channel();
+        // the events of notify are received in blocking, they are forwarded to the runtime
+        std::thread::spawn(move || {
+            while let Ok(event) = rx.recv() {
+                match event {
+                    DebouncedEvent::NoticeWrite(_) | DebouncedEvent::NoticeRemove(_) => continue,
+                    DebouncedEvent::Error(e, path) => {
+                        tracing::warn!("fail to watch {:?}: {}", path, e);
+                        continue;
+                    }
+                    _ => {}
+                }
+                if sender.send(()).is_err() {
+                    return;
+                }
+            }
+        });
+        let mut watcher = Self {
+            path: path.to_path_buf(),
+            format,
+            watcher: watcher(tx, DEBOUNCE)?,
+            dirs: vec![],
+            changes,
+            current: read_raw_config(path, format).await?,
+        };
+        watcher.watch().await?;
+        Ok(watcher)
+    }
+
+    /// watch watches the directories of the config and its included files, it is called again on
+    /// every change, as the includes may be changed.
+    async fn watch(&mut self) -> Result<()> {
+        let dirs = watched_dirs(&self.path, self.format).await?;
+        for dir in &self.dirs {
+            if !dirs.contains(dir) {
+                let _ = self.watcher.unwatch(dir);
+            }
+        }
+        for dir in &dirs {
+            if !self.dirs.contains(dir) {
+                self.watcher.watch(dir, RecursiveMode::NonRecursive)?;
+                tracing::debug!("Watching {}", dir.display());
+            }
+        }
+        self.dirs = dirs;
+        Ok(())
+    }
+
+    /// changed waits for the next change of the watched directories.
+    pub async fn changed(&mut self) {
+        if self.changes.recv().await.is_none() {
+            // the watcher is gone, nothing changes anymore
+            std::future::pending::<()>().await;
+        }
+        while self.changes.try_recv().is_ok() {}
+    }
+
+    /// reload applies the config to the proxy if it is changed, the current one is kept if the
+    /// new one is invalid.
+    pub async fn reload(&mut self, proxy: &Mutex<Proxy>) -> Result<()> {
+        let raw = read_raw_config(&self.path, self.format).await?;
+        self.watch().await?;
+        if raw == self.current {
+            return Ok(());
+        }
+        let config: Config = raw.clone().try_into()?;
+        proxy.lock().await.update(config.proxy_config).await?;
+
+        let (added, removed) = rule_diff(
+            self.current.rules.as_deref().unwrap_or_default(),
+            raw.rules.as_deref().unwrap_or_default(),
+        );
+        tracing::info!(
+            "Config {} reloaded, rules added: {:?}, removed: {:?}",
+            self.path.display(),
+            added,
+            removed
+        );
+        self.current = raw;
+        Ok(())
+    }
+}
+
+/// rule_diff returns the rules added and removed, by their names, or by their json if unnamed. A
+/// changed rule is both removed and added.
+pub fn rule_diff(old: &[RawRule], new: &[RawRule]) -> (Vec<String>, Vec<String>) {
+    let label = |rule: &RawRule| match &rule.name {
+        Some(name) => name.clone(),
+        None => serde_json::to_string(rule).unwrap_or_default(),
+    };
+    let added = new
+        .iter()
+        .filter(|rule| !old.contains(rule))
+        .map(label)
+        .collect();
+    let removed = old
+        .iter()
+        .filter(|rule| !new.contains(rule))
+        .map(label)
+        .collect();
+    (added, removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use chaos_tproxy_proxy::raw_config::RawRule;
+
+    use crate::cmd::watch::rule_diff;
+
+    #[test]
+    fn test_rule_diff() {
+        let rule = |name: Option<&str>, path: &str| -> RawRule {
+            serde_json::from_value(serde_json::json!({
+                "name": name,
+                "target": "Request",
+                "selector": {"path": path},
+                "actions": {"abort": true},
+            }))
+            .unwrap()
+        };
+        let old = vec![
+            rule(Some("abort-api"), "/api/*"),
+            rule(Some("abort-login"), "/login"),
+            rule(None, "/health"),
+        ];
+        let new = vec![
+            rule(Some("abort-api"), "/api/v2/*"),
+            rule(Some("abort-login"), "/login"),
+            rule(Some("abort-logout"), "/logout"),
+        ];
+        let (added, removed) = rule_diff(&old, &new);
+        assert_eq!(added, vec!["abort-api", "abort-logout"]);
+        assert_eq!(removed.len(), 2);
+        assert_eq!(removed[0], "abort-api");
+        assert!(removed[1].contains("/health"));
+
+        assert_eq!(rule_diff(&old, &old), (vec![], vec![]));
+    }
+}
@@ -1,22 +1,55 @@
 use std::process::exit;
+#[cfg(target_os = "linux")]
+use std::sync::Arc;
 
+#[cfg(target_os = "linux")]
 use chaos_tproxy_proxy::proxy_main;
+#[cfg(target_os = "linux")]
 use chaos_tproxy_proxy::signal::Signals;
-use tokio::signal::unix::SignalKind;
-use tracing_subscriber::layer::SubscriberExt;
-use tracing_subscriber::util::SubscriberInitExt;
-use tracing_subscriber::{fmt, EnvFilter};
+#[cfg(target_os = "linux")]
+use tokio::select;
+#[cfg(target_os = "linux")]
+use tokio::signal::unix::{signal, SignalKind};
+#[cfg(target_os = "linux")]
+use tokio::sync::oneshot::channel;
+#[cfg(target_os = "linux")]
+use tokio::sync::Mutex;
 
-use crate::cmd::command_line::{get_config_from_opt, Opt};
+#[cfg(target_os = "linux")]
+use crate::cmd::admin::{diff_main, serve_admin};
+#[cfg(target_os = "linux")]
+use crate::cmd::cluster::{agent_main, cluster_main};
+use crate::cmd::command_line::Opt;
+#[cfg(target_os = "linux")]
+use crate::cmd::command_line::{get_config_from_opt, SubCommand, PID_FILE};
+#[cfg(target_os = "linux")]
+use crate::cmd::daemon::handler::{cleanup_main, daemonize, status_main, stop_main, PidFile};
+#[cfg(not(target_os = "linux"))]
+use crate::cmd::dev::dev_main;
+#[cfg(target_os = "linux")]
+use crate::cmd::grpc::serve_grpc;
+#[cfg(target_os = "linux")]
 use crate::cmd::interactive::handler::ConfigServer;
+#[cfg(target_os = "linux")]
+use crate::cmd::logging::init_logging;
+#[cfg(target_os = "linux")]
+use crate::cmd::netns::enter_netns;
+#[cfg(target_os = "linux")]
+use crate::cmd::remote::RemoteConfig;
+#[cfg(target_os = "linux")]
+use crate::cmd::stub::stub_main;
+#[cfg(target_os = "linux")]
+use crate::cmd::watch::ConfigWatcher;
+#[cfg(target_os = "linux")]
 use crate::proxy::exec::Proxy;
+#[cfg(target_os = "linux")]
+use crate::proxy::net::state::set_state_file;
 
 pub mod cmd;
 pub mod proxy;
 pub mod raw_config;
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
+fn main() -> anyhow::Result<()> {
     let opt = match Opt::from_args_checked() {
         Err(e) => {
             println!("{}", e);
@@ -24,24 +57,74 @@ async fn main() -> anyhow::Result<()> {
         }
         Ok(o) => o,
     };
-    tracing_subscriber::registry()
-        .with(fmt::layer().with_writer(std::io::stderr))
-        .with(EnvFilter::from_default_env().add_directive(opt.get_level_filter().into()))
-        .with(EnvFilter::from_default_env().add_directive("chaos_tproxy".parse().unwrap()))
-        .init();
+    // the threads of the runtime inherit the netns of the main thread
+    #[cfg(target_os = "linux")]
+    {
+        if let Some(netns) = &opt.netns {
+            enter_netns(netns)?;
+        }
+    }
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    match opt.worker_threads {
+        Some(0) => return Err(anyhow::anyhow!("worker threads must be positive")),
+        Some(threads) => {
+            runtime.worker_threads(threads);
+        }
+        None => {}
+    }
+    // only the explicit proxy runs off Linux, the traffic could not be redirected
+    #[cfg(not(target_os = "linux"))]
+    let run = dev_main;
+    runtime.enable_all().build()?.block_on(run(opt))
+}
+
+#[cfg(target_os = "linux")]
+async fn run(opt: Opt) -> anyhow::Result<()> {
+    // the config is read once before the logs are set up by its `log` section, as stdin is only
+    // read once
+    let config = if opt.has_config() && opt.cmd.is_none() {
+        Some(get_config_from_opt(&opt).await?)
+    } else {
+        None
+    };
+    init_logging(&opt, config.as_ref().and_then(|config| config.log.as_ref()))?;
+    if let Some(path) = &opt.state_file {
+        set_state_file(path.clone());
+    }
+
+    match &opt.cmd {
+        Some(SubCommand::Stub(stub)) => return stub_main(stub).await,
+        Some(SubCommand::Cluster(cluster)) => return cluster_main(cluster).await,
+        Some(SubCommand::Agent(agent)) => return agent_main(agent, opt.verbose).await,
+        Some(SubCommand::Status(status)) => return status_main(status).await,
+        Some(SubCommand::Stop(stop)) => return stop_main(stop).await,
+        Some(SubCommand::Cleanup(cleanup)) => return cleanup_main(cleanup),
+        Some(SubCommand::Diff(diff)) => return diff_main(diff).await,
+        None => {}
+    }
 
     if opt.proxy {
-        proxy_main(opt.ipc_path.clone().unwrap()).await?;
+        proxy_main(opt.ipc_path.clone().unwrap(), !opt.disarmed).await?;
     }
 
-    if opt.input.is_some() {
-        let cfg = get_config_from_opt(&opt).await?;
+    if opt.daemon {
+        // the config is checked above before going to the background
+        let pid_file = opt.pid_file.clone().unwrap_or_else(|| PID_FILE.into());
+        return daemonize(&pid_file, opt.log_file.as_deref());
+    }
+    let pid_file = opt.pid_file.as_deref().map(PidFile::create).transpose()?;
+
+    if opt.has_config() || opt.grpc_listen.is_some() {
         let mut proxy = Proxy::new(opt.verbose).await;
-        proxy.reload(cfg.proxy_config).await?;
-        let mut signals = Signals::from_kinds(&[SignalKind::interrupt(), SignalKind::terminate()])?;
-        signals.wait().await?;
-        proxy.stop().await?;
-        return Ok(());
+        // the proxy is started by the gRPC control API without a config
+        if let Some(cfg) = config {
+            proxy.reload(cfg.proxy_config).await?;
+        }
+        let proxy = Arc::new(Mutex::new(proxy));
+        let result = serve(&opt, &proxy).await;
+        // the network is cleared even if the serving failed
+        proxy.lock().await.stop().await?;
+        return result;
     }
 
     if opt.interactive {
@@ -51,9 +134,72 @@ async fn main() -> anyhow::Result<()> {
         let mut signals = Signals::from_kinds(&[SignalKind::interrupt(), SignalKind::terminate()])?;
         signals.wait().await?;
         config_server.stop().await?;
+        drop(pid_file);
 
         // Currently we cannot graceful shutdown the config server.
         exit(0);
     }
     Ok(())
 }
+
+/// serve serves the control APIs and the signals until the controller is interrupted.
+#[cfg(target_os = "linux")]
+async fn serve(opt: &Opt, proxy: &Arc<Mutex<Proxy>>) -> anyhow::Result<()> {
+    let mut servers = vec![];
+    if let Some(port) = opt.admin_port {
+        let (sender, rx) = channel();
+        servers.push((sender, serve_admin(port, proxy.clone(), rx)?));
+    }
+    if let Some(addr) = opt.grpc_listen {
+        let (sender, rx) = channel();
+        servers.push((sender, serve_grpc(addr, proxy.clone(), rx)?));
+    }
+    let mut signals = Signals::from_kinds(&[SignalKind::interrupt(), SignalKind::terminate()])?;
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut disarm = signal(SignalKind::user_defined1())?;
+    let mut arm = signal(SignalKind::user_defined2())?;
+    let mut watcher = match &opt.input {
+        Some(path) if opt.watch => Some(ConfigWatcher::new(path, opt.format).await?),
+        _ => None,
+    };
+    let mut remote = match opt.remote_url() {
+        Some(url) => Some(RemoteConfig::new(url, opt.format, opt.poll_interval).await?),
+        None => None,
+    };
+    loop {
+        select! {
+            _ = signals.wait() => break,
+            _ = disarm.recv() => proxy.lock().await.arm(false),
+            _ = arm.recv() => proxy.lock().await.arm(true),
+            _ = async { watcher.as_mut().unwrap().changed().await }, if watcher.is_some() => {
+                if let Err(e) = watcher.as_mut().unwrap().reload(proxy).await {
+                    tracing::error!("fail to reload config, the current one is kept: {}", e);
+                }
+            }
+            _ = async { remote.as_mut().unwrap().tick().await }, if remote.is_some() => {
+                if let Err(e) = remote.as_mut().unwrap().reload(proxy).await {
+                    tracing::error!("fail to poll config, the current one is kept: {}", e);
+                }
+            }
+            _ = hangup.recv() => {
+                if !opt.has_config() || opt.reads_stdin() {
+                    tracing::warn!("no config file to reload");
+                    continue;
+                }
+                tracing::info!("Reloading config from {:?}", opt.input);
+                let result = match get_config_from_opt(opt).await {
+                    Ok(cfg) => proxy.lock().await.update(cfg.proxy_config).await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = result {
+                    tracing::error!("fail to reload config, the current one is kept: {}", e);
+                }
+            }
+        }
+    }
+    for (sender, server) in servers {
+        let _ = sender.send(());
+        server.await??;
+    }
+    Ok(())
+}
@@ -1,68 +1,227 @@
+use std::collections::HashSet;
 use std::convert::TryFrom;
-use std::net::Ipv4Addr;
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
 
 use anyhow::{anyhow, Error};
-use pnet::ipnetwork::IpNetwork;
-use chaos_tproxy_proxy::raw_config::{RawConfig as ProxyRawConfig, Role};
-use crate::proxy::net::bridge::get_default_interface;
+use chaos_tproxy_proxy::raw_config::{
+    check_explicit, check_rule_names, RawConfig as ProxyRawConfig, RawListener, Role,
+};
+use ipnetwork::IpNetwork;
 
-use crate::raw_config::{RawConfig, RawRole};
+use crate::cmd::logging::LogConfig;
+#[cfg(target_os = "linux")]
+use crate::proxy::net::bridge::get_default_interface;
+use crate::raw_config::{RawConfig, RawPortRange, RawRole};
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Config {
     pub proxy_config: ProxyRawConfig,
+    /// log is applied on start, the changes of it are ignored on reload.
+    pub log: Option<LogConfig>,
 }
 
 impl TryFrom<RawConfig> for Config {
     type Error = Error;
 
     fn try_from(raw: RawConfig) -> Result<Self, Self::Error> {
-        let ipv4s: Vec<Ipv4Addr> = get_default_interface()?
-            .ips
-            .iter()
-            .filter_map(|ips| match ips {
-                IpNetwork::V4(ipv4) => Some(ipv4),
-                _ => None,
-            })
-            .map(|ipv4| ipv4.ip())
-            .collect();
-        if ipv4s.is_empty() {
+        // the included files are read along with the config file
+        if raw.include.is_some() {
+            return Err(anyhow!("include is only available in config files"));
+        }
+        if let Some(rules) = &raw.rules {
+            check_rule_names(rules)?;
+        }
+        // both the IPv4 and the IPv6 addresses of the pod are in its role
+        if raw.interfaces.as_ref().is_some_and(Vec::is_empty) {
+            return Err(anyhow!("interfaces must not be empty"));
+        }
+        #[cfg(target_os = "linux")]
+        let ips: Vec<IpAddr> = match get_default_interface(raw.interfaces.as_deref()) {
+            Ok(interface) => interface.ips.iter().map(|ip| ip.ip()).collect(),
+            // the explicit proxy diverts nothing from the interfaces
+            Err(_) if raw.explicit.is_some() => vec![],
+            Err(e) => return Err(e),
+        };
+        // the traffic is only redirected on Linux, the explicit proxy runs in the dev mode
+        #[cfg(not(target_os = "linux"))]
+        let ips: Vec<IpAddr> = match raw.explicit {
+            Some(_) => vec![],
+            None => {
+                return Err(anyhow!(
+                    "only the explicit proxy is available on this platform"
+                ))
+            }
+        };
+        if raw.explicit.is_none() && !ips.iter().any(IpAddr::is_ipv4) {
             return Err(anyhow!("no default ipv4"));
         }
-        Ok(Config {
-            proxy_config: ProxyRawConfig {
-                proxy_ports: raw.proxy_ports.clone().map(|c| {
-                    c.iter()
-                        .map(ToString::to_string)
-                        .collect::<Vec<_>>()
-                        .join(",")
-                }),
-                safe_mode: match &raw.safe_mode {
-                    Some(b) => *b,
-                    None => false,
-                },
-                listen_port: get_free_port(raw.proxy_ports.clone())?,
-                rules: raw.rules.map_or(vec![], |rules| rules),
-                role: raw.role.and_then(|role| {
-                    Option::from(match role {
-                        RawRole::Client => Role::Client(ipv4s),
-                        RawRole::Server => Role::Server(ipv4s),
+        let proxy_ports = raw.proxy_ports.as_deref().map(port_ranges).transpose()?;
+        let listener_ports = raw
+            .listeners
+            .iter()
+            .flatten()
+            .map(|listener| port_ranges(&listener.proxy_ports))
+            .collect::<Result<Vec<_>, Error>>()?;
+        // the listen ports are neither intercepted nor shared
+        let mut reserved: Vec<u16> = proxy_ports
+            .iter()
+            .chain(&listener_ports)
+            .flatten()
+            .flat_map(Clone::clone)
+            .collect();
+        let listen_port = get_free_port(Some(reserved.clone()))?;
+        reserved.push(listen_port);
+        let listeners = raw
+            .listeners
+            .map(|listeners| {
+                listeners
+                    .into_iter()
+                    .zip(&listener_ports)
+                    .map(|(listener, ports)| {
+                        if ports.is_empty() {
+                            return Err(anyhow!("proxy_ports of listener must not be empty"));
+                        }
+                        let listen_port = get_free_port(Some(reserved.clone()))?;
+                        reserved.push(listen_port);
+                        Ok(RawListener {
+                            proxy_ports: multiport(ports),
+                            listen_port,
+                            tls: listener.tls,
+                            workers: listener.workers,
+                            rules: listener.rules,
+                        })
                     })
-                }),
-                tls: raw.tls,
+                    .collect::<Result<Vec<_>, Error>>()
+            })
+            .transpose()?;
+        let proxy_config = ProxyRawConfig {
+            proxy_ports: proxy_ports.as_deref().map(multiport),
+            exclude_ports: raw
+                .exclude_ports
+                .as_deref()
+                .map(port_ranges)
+                .transpose()?
+                .filter(|ports| !ports.is_empty())
+                .as_deref()
+                .map(multiport),
+            ignore_destinations: raw
+                .ignore_destinations
+                .as_deref()
+                .map(networks)
+                .transpose()?,
+            ignore_sources: raw.ignore_sources.as_deref().map(networks).transpose()?,
+            interfaces: raw.interfaces,
+            direction: raw.direction,
+            safe_mode: match &raw.safe_mode {
+                Some(b) => *b,
+                None => false,
             },
+            compare_mode: raw.compare_mode.unwrap_or(false),
+            latency_compensation: raw.latency_compensation.unwrap_or(false),
+            fault_markers: raw.fault_markers.unwrap_or(false),
+            inspect_all: raw.inspect_all.unwrap_or(false),
+            opt_in: raw.opt_in,
+            exchange_id_header: raw.exchange_id_header,
+            listen_port,
+            rules: raw.rules.map_or(vec![], |rules| rules),
+            match_policy: raw.match_policy,
+            role: raw.role.and_then(|role| {
+                Option::from(match role {
+                    RawRole::Client => Role::Client(ips),
+                    RawRole::Server => Role::Server(ips),
+                })
+            }),
+            tls: raw.tls,
+            slo: raw.slo,
+            baseline: raw.baseline,
+            metadata: raw.metadata,
+            coordination: raw.coordination,
+            report: None,
+            snapshot: raw.snapshot,
+            connection_limits: raw.connection_limits,
+            doh: raw.doh,
+            dns: raw.dns,
+            listeners,
+            proxy_protocol: raw.proxy_protocol,
+            validation: raw.validation,
+            drain_timeout: raw.drain_timeout,
+            access_log: raw.access_log,
+            har: raw.har,
+            experiment_report: raw.experiment_report,
+            telemetry: raw.telemetry,
+            pcap: raw.pcap,
+            redirect_backend: raw.redirect_backend,
+            explicit: raw.explicit,
+            upstream_pool: raw.upstream_pool,
+            max_body_size: raw.max_body_size,
+            body_overflow: raw.body_overflow,
+            runtime: raw.runtime,
+            concurrency: raw.concurrency,
+        };
+        check_explicit(&proxy_config)?;
+        Ok(Config {
+            proxy_config,
+            log: raw.log.map(LogConfig::try_from).transpose()?,
         })
     }
 }
 
+/// port_ranges parses the ports and the ranges of ports, e.g. `"8000-8999"`.
+fn port_ranges(ports: &[RawPortRange]) -> Result<Vec<RangeInclusive<u16>>, Error> {
+    ports
+        .iter()
+        .map(|port| match port {
+            RawPortRange::Port(port) => Ok(*port..=*port),
+            RawPortRange::Range(range) => {
+                let invalid = || anyhow!("invalid port range {}", range);
+                let (start, end) = range.split_once('-').unwrap_or((range, range));
+                let start: u16 = start.trim().parse().map_err(|_| invalid())?;
+                let end: u16 = end.trim().parse().map_err(|_| invalid())?;
+                if start > end {
+                    return Err(invalid());
+                }
+                Ok(start..=end)
+            }
+        })
+        .collect()
+}
+
+/// multiport formats the ranges of ports as iptables multiport does, e.g. `80,8000:8999`.
+fn multiport(ports: &[RangeInclusive<u16>]) -> String {
+    ports
+        .iter()
+        .map(|range| match range.start() == range.end() {
+            true => range.start().to_string(),
+            false => format!("{}:{}", range.start(), range.end()),
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// networks parses the networks in CIDR, e.g. `10.96.0.0/12`, an address is a network of itself.
+fn networks(networks: &[String]) -> Result<Vec<String>, Error> {
+    networks
+        .iter()
+        .map(|network| {
+            let parsed: IpNetwork = network
+                .parse()
+                .map_err(|e| anyhow!("invalid network {}: {}", network, e))?;
+            Ok(format!("{}/{}", parsed.network(), parsed.prefix()))
+        })
+        .collect()
+}
+
 pub(crate) fn get_free_port(ports: Option<Vec<u16>>) -> anyhow::Result<u16> {
+    // the ranges of the proxy ports are large
+    let ports: Option<HashSet<u16>> = ports.map(|ports| ports.into_iter().collect());
     for port in 1025..u16::MAX {
         match &ports {
             None => {
                 return Ok(port);
             }
             Some(ports) => {
-                if ports.iter().all(|&p| p != port) {
+                if !ports.contains(&port) {
                     return Ok(port);
                 }
             }
@@ -80,7 +239,7 @@ mod tests {
     use chaos_tproxy_proxy::raw_config::RawConfig as ProxyRawConfig;
 
     use crate::proxy::config::{get_free_port, Config};
-    use crate::raw_config::RawConfig;
+    use crate::raw_config::{RawConfig, RawListenerConfig, RawPortRange};
 
     #[test]
     fn test_get_free_port() {
@@ -90,17 +249,56 @@ mod tests {
     #[test]
     fn test_try_into() {
         let config: Config = RawConfig {
+            version: None,
             proxy_ports: None,
+            exclude_ports: None,
+            ignore_destinations: None,
+            ignore_sources: None,
+            interfaces: None,
+            direction: None,
             safe_mode: None,
+            compare_mode: None,
+            latency_compensation: None,
+            fault_markers: None,
+            inspect_all: None,
+            opt_in: None,
+            exchange_id_header: None,
             rules: None,
+            match_policy: None,
             tls: None,
             role: None,
+            slo: None,
+            baseline: None,
+            metadata: None,
+            coordination: None,
+            snapshot: None,
+            connection_limits: None,
+            doh: None,
+            dns: None,
+            listeners: None,
+            proxy_protocol: None,
+            validation: None,
+            drain_timeout: None,
+            access_log: None,
+            har: None,
+            experiment_report: None,
+            telemetry: None,
+            pcap: None,
+            log: None,
+            redirect_backend: None,
+            explicit: None,
+            upstream_pool: None,
+            max_body_size: None,
+            body_overflow: None,
+            runtime: None,
+            concurrency: None,
 
             interface: None,
             listen_port: None,
             proxy_mark: None,
             ignore_mark: None,
             route_table: None,
+            include: None,
         }
         .try_into()
         .unwrap();
@@ -109,27 +307,107 @@ mod tests {
             Config {
                 proxy_config: ProxyRawConfig {
                     proxy_ports: None,
+                    exclude_ports: None,
+                    ignore_destinations: None,
+                    ignore_sources: None,
+                    interfaces: None,
+                    direction: None,
                     listen_port: get_free_port(None).unwrap(),
                     safe_mode: false,
+                    compare_mode: false,
+                    latency_compensation: false,
+                    fault_markers: false,
+                    inspect_all: false,
+                    opt_in: None,
+                    exchange_id_header: None,
                     rules: vec![],
+                    match_policy: None,
                     role: None,
-                    tls: None
-                }
+                    tls: None,
+                    slo: None,
+                    baseline: None,
+                    metadata: None,
+                    coordination: None,
+                    report: None,
+                    snapshot: None,
+                    connection_limits: None,
+                    doh: None,
+                    dns: None,
+                    listeners: None,
+                    proxy_protocol: None,
+                    validation: None,
+                    drain_timeout: None,
+                    access_log: None,
+                    har: None,
+                    experiment_report: None,
+                    telemetry: None,
+                    pcap: None,
+                    redirect_backend: None,
+                    explicit: None,
+                    upstream_pool: None,
+                    max_body_size: None,
+                    body_overflow: None,
+                    runtime: None,
+                    concurrency: None,
+                },
+                log: None,
             }
         );
 
         let config: Config = RawConfig {
-            proxy_ports: Some(vec![1025u16, 1026u16]),
+            version: None,
+            proxy_ports: Some(vec![
+                1025u16.into(),
+                RawPortRange::Range("1026".to_string()),
+            ]),
+            exclude_ports: None,
+            ignore_destinations: None,
+            ignore_sources: None,
+            interfaces: None,
+            direction: None,
             safe_mode: Some(true),
+            compare_mode: None,
+            latency_compensation: None,
+            fault_markers: None,
+            inspect_all: None,
+            opt_in: None,
+            exchange_id_header: None,
             rules: None,
+            match_policy: None,
             tls: None,
             role: None,
+            slo: None,
+            baseline: None,
+            metadata: None,
+            coordination: None,
+            snapshot: None,
+            connection_limits: None,
+            doh: None,
+            dns: None,
+            listeners: None,
+            proxy_protocol: None,
+            validation: None,
+            drain_timeout: None,
+            access_log: None,
+            har: None,
+            experiment_report: None,
+            telemetry: None,
+            pcap: None,
+            log: None,
+            redirect_backend: None,
+            explicit: None,
+            upstream_pool: None,
+            max_body_size: None,
+            body_overflow: None,
+            runtime: None,
+            concurrency: None,
 
             interface: None,
             listen_port: None,
             proxy_mark: None,
             ignore_mark: None,
             route_table: None,
+            include: None,
         }
         .try_into()
         .unwrap();
@@ -138,13 +416,127 @@ mod tests {
             Config {
                 proxy_config: ProxyRawConfig {
                     proxy_ports: Some("1025,1026".parse().unwrap()),
+                    exclude_ports: None,
+                    ignore_destinations: None,
+                    ignore_sources: None,
+                    interfaces: None,
+                    direction: None,
                     listen_port: 1027u16,
                     safe_mode: true,
+                    compare_mode: false,
+                    latency_compensation: false,
+                    fault_markers: false,
+                    inspect_all: false,
+                    opt_in: None,
+                    exchange_id_header: None,
                     rules: vec![],
+                    match_policy: None,
                     role: None,
-                    tls: None
-                }
+                    tls: None,
+                    slo: None,
+                    baseline: None,
+                    metadata: None,
+                    coordination: None,
+                    report: None,
+                    snapshot: None,
+                    connection_limits: None,
+                    doh: None,
+                    dns: None,
+                    listeners: None,
+                    proxy_protocol: None,
+                    validation: None,
+                    drain_timeout: None,
+                    access_log: None,
+                    har: None,
+                    experiment_report: None,
+                    telemetry: None,
+                    pcap: None,
+                    redirect_backend: None,
+                    explicit: None,
+                    upstream_pool: None,
+                    max_body_size: None,
+                    body_overflow: None,
+                    runtime: None,
+                    concurrency: None,
+                },
+                log: None,
             }
         );
     }
+
+    #[test]
+    fn test_listeners() {
+        let config: Config = RawConfig {
+            version: None,
+            proxy_ports: Some(vec![1025u16.into()]),
+            exclude_ports: Some(vec![RawPortRange::Range("8040-8049".to_string())]),
+            ignore_destinations: Some(vec!["10.96.0.1/12".to_string(), "fd00::1".to_string()]),
+            ignore_sources: None,
+            interfaces: None,
+            direction: None,
+            safe_mode: None,
+            compare_mode: None,
+            latency_compensation: None,
+            fault_markers: None,
+            inspect_all: None,
+            opt_in: None,
+            exchange_id_header: None,
+            rules: None,
+            match_policy: None,
+            tls: None,
+            role: None,
+            slo: None,
+            baseline: None,
+            metadata: None,
+            coordination: None,
+            snapshot: None,
+            connection_limits: None,
+            doh: None,
+            dns: None,
+            listeners: Some(vec![RawListenerConfig {
+                proxy_ports: vec![1026u16.into(), RawPortRange::Range("8000-8080".to_string())],
+                tls: None,
+                workers: Some(2),
+                rules: None,
+            }]),
+            proxy_protocol: None,
+            validation: None,
+            drain_timeout: None,
+            access_log: None,
+            har: None,
+            experiment_report: None,
+            telemetry: None,
+            pcap: None,
+            log: None,
+            redirect_backend: None,
+            explicit: None,
+            upstream_pool: None,
+            max_body_size: None,
+            body_overflow: None,
+            runtime: None,
+            concurrency: None,
+
+            interface: None,
+            listen_port: None,
+            proxy_mark: None,
+            ignore_mark: None,
+            route_table: None,
+            include: None,
+        }
+        .try_into()
+        .unwrap();
+        assert_eq!(config.proxy_config.listen_port, 1027);
+        assert_eq!(
+            config.proxy_config.exclude_ports.as_deref(),
+            Some("8040:8049")
+        );
+        assert_eq!(
+            config.proxy_config.ignore_destinations,
+            Some(vec!["10.96.0.0/12".to_string(), "fd00::1/128".to_string()])
+        );
+        let listeners = config.proxy_config.listeners.unwrap();
+        assert_eq!(listeners[0].proxy_ports, "1026,8000:8080");
+        assert_eq!(listeners[0].listen_port, 1028);
+        assert_eq!(listeners[0].workers, Some(2));
+    }
 }
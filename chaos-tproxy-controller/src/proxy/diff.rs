@@ -0,0 +1,186 @@
+use std::fmt;
+
+use chaos_tproxy_proxy::raw_config::{RawConfig as ProxyRawConfig, RawRule};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// ConfigDiff tells what applying a candidate config to the running proxy would change, it is
+/// served by `POST /config/diff` of the admin API before anything is applied.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ConfigDiff {
+    /// added, removed and changed are the rules by their names, or by their json if unnamed. An
+    /// unnamed rule changed is both removed and added.
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub changed: Vec<String>,
+    /// settings are the other options changed, e.g. `proxy_ports`.
+    pub settings: Vec<String>,
+    /// iptables tells whether the iptables rules are reconciled or set again.
+    pub iptables: bool,
+    /// restart tells whether the proxy is (re)started, which drops the connections in flight.
+    pub restart: bool,
+}
+
+impl ConfigDiff {
+    pub fn new(
+        current: &ProxyRawConfig,
+        candidate: &ProxyRawConfig,
+        iptables: bool,
+        restart: bool,
+    ) -> Self {
+        let label = |rule: &RawRule| match &rule.name {
+            Some(name) => name.clone(),
+            None => serde_json::to_string(rule).unwrap_or_default(),
+        };
+        let (old, new) = (&current.rules, &candidate.rules);
+        let mut diff = Self {
+            iptables,
+            restart,
+            ..Default::default()
+        };
+        for rule in new {
+            match &rule.name {
+                Some(name) => match named(old, name) {
+                    None => diff.added.push(name.clone()),
+                    Some(old) if old != rule => diff.changed.push(name.clone()),
+                    Some(_) => {}
+                },
+                None if !old.contains(rule) => diff.added.push(label(rule)),
+                None => {}
+            }
+        }
+        for rule in old {
+            let removed = match &rule.name {
+                Some(name) => named(new, name).is_none(),
+                None => !new.contains(rule),
+            };
+            if removed {
+                diff.removed.push(label(rule));
+            }
+        }
+        diff.settings = changed_settings(current, candidate);
+        diff
+    }
+
+    /// is_empty tells whether the candidate config is the same as the current one.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.changed.is_empty()
+            && self.settings.is_empty()
+    }
+}
+
+/// named returns the rule of the name among the rules.
+fn named<'a>(rules: &'a [RawRule], name: &str) -> Option<&'a RawRule> {
+    rules.iter().find(|rule| rule.name.as_deref() == Some(name))
+}
+
+/// changed_settings returns the options of the configs changed besides the rules, by their names
+/// in the config file.
+fn changed_settings(current: &ProxyRawConfig, candidate: &ProxyRawConfig) -> Vec<String> {
+    let fields = |config: &ProxyRawConfig| match serde_json::to_value(config) {
+        Ok(Value::Object(fields)) => fields,
+        _ => Default::default(),
+    };
+    let (old, new) = (fields(current), fields(candidate));
+    let mut settings: Vec<String> = old
+        .keys()
+        .chain(new.keys())
+        .filter(|name| *name != "rules" && old.get(*name) != new.get(*name))
+        .cloned()
+        .collect();
+    settings.sort();
+    settings.dedup();
+    settings
+}
+
+impl fmt::Display for ConfigDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            writeln!(f, "config unchanged")?;
+        }
+        for rule in &self.added {
+            writeln!(f, "+ rule {}", rule)?;
+        }
+        for rule in &self.removed {
+            writeln!(f, "- rule {}", rule)?;
+        }
+        for rule in &self.changed {
+            writeln!(f, "~ rule {}", rule)?;
+        }
+        for setting in &self.settings {
+            writeln!(f, "~ {}", setting)?;
+        }
+        writeln!(
+            f,
+            "iptables rules: {}",
+            if self.iptables {
+                "changed"
+            } else {
+                "unchanged"
+            }
+        )?;
+        writeln!(
+            f,
+            "proxy: {}",
+            if self.restart {
+                "restarted, the connections in flight are dropped"
+            } else {
+                "kept running, the changes are swapped in place"
+            }
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chaos_tproxy_proxy::raw_config::{RawConfig as ProxyRawConfig, RawRule};
+
+    use crate::proxy::diff::ConfigDiff;
+
+    #[test]
+    fn test_config_diff() {
+        let rule = |name: Option<&str>, path: &str| -> RawRule {
+            serde_json::from_value(serde_json::json!({
+                "name": name,
+                "target": "Request",
+                "selector": {"path": path},
+                "actions": {"abort": true},
+            }))
+            .unwrap()
+        };
+        let current = ProxyRawConfig {
+            proxy_ports: Some("80".to_string()),
+            rules: vec![
+                rule(Some("abort-api"), "/api/*"),
+                rule(Some("abort-login"), "/login"),
+                rule(None, "/health"),
+            ],
+            ..Default::default()
+        };
+        let candidate = ProxyRawConfig {
+            proxy_ports: Some("80,443".to_string()),
+            rules: vec![
+                rule(Some("abort-api"), "/api/v2/*"),
+                rule(Some("abort-login"), "/login"),
+                rule(Some("abort-logout"), "/logout"),
+            ],
+            ..current.clone()
+        };
+        let diff = ConfigDiff::new(&current, &candidate, true, false);
+        assert_eq!(diff.added, vec!["abort-logout"]);
+        assert_eq!(diff.changed, vec!["abort-api"]);
+        assert_eq!(diff.removed.len(), 1);
+        assert!(diff.removed[0].contains("/health"));
+        assert_eq!(diff.settings, vec!["proxy_ports"]);
+        let printed = diff.to_string();
+        assert!(printed.contains("+ rule abort-logout\n"));
+        assert!(printed.contains("~ proxy_ports\n"));
+        assert!(printed.contains("iptables rules: changed\n"));
+
+        let unchanged = ConfigDiff::new(&current, &current, false, false);
+        assert!(unchanged.is_empty());
+        assert!(unchanged.to_string().starts_with("config unchanged\n"));
+    }
+}
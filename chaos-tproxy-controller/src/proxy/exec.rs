@@ -1,18 +1,30 @@
-use std::env;
+use std::convert::TryFrom;
 use std::path::PathBuf;
 use std::process::Stdio;
+use std::sync::{Mutex, Once};
+use std::time::Duration;
+use std::{env, panic, thread};
 
 use anyhow::Error;
-use chaos_tproxy_proxy::raw_config::RawConfig as ProxyRawConfig;
+use chaos_tproxy_proxy::proxy::http::config::Config as ProxyConfig;
+use chaos_tproxy_proxy::raw_config::{
+    parse_port_ranges, RawConfig as ProxyRawConfig, RawListener, RawRedirectBackend,
+};
+use once_cell::sync::Lazy;
 use rtnetlink::{new_connection, Handle};
 use tokio::process::Command;
 use tokio::select;
 use tokio::sync::oneshot::{channel, Receiver, Sender};
 use tokio::task::JoinHandle;
+use tokio::time::timeout;
 use uuid::Uuid;
 
+use crate::cmd::logging::{forward_lines, logging, Logging};
+use crate::proxy::diff::ConfigDiff;
 use crate::proxy::net::bridge::NetEnv;
-use crate::proxy::net::set_net::set_net;
+use crate::proxy::net::set_net::{reset_net, set_net};
+use crate::proxy::net::state::{recover, set_proxy_pid};
+use crate::proxy::summary::ConfigSummary;
 use crate::proxy::uds_server::UdsDataServer;
 
 #[derive(Debug, Clone)]
@@ -34,7 +46,14 @@ pub struct Proxy {
     pub rtnl_handle: Handle,
     pub sender: Option<Sender<()>>,
     pub rx: Option<Receiver<()>>,
-    pub task: Option<JoinHandle<Result<(), Error>>>,
+    /// task waits for the sub proxy, it returns true if the proxy exited by itself and the network
+    /// is cleared.
+    pub task: Option<JoinHandle<Result<bool, Error>>>,
+    pub pid: Option<u32>,
+    pub config: Option<ProxyRawConfig>,
+    pub uds_server: Option<UdsDataServer<ProxyRawConfig>>,
+    /// armed is false if the rules are paused, it is kept when the proxy restarts.
+    pub armed: bool,
 }
 
 impl Proxy {
@@ -48,13 +67,19 @@ impl Proxy {
 
         let (conn, handle, _) = new_connection().unwrap();
         tokio::spawn(conn);
+        // the device is picked after the network left by a killed instance is restored
+        recover();
         Self {
             opt,
-            net_env: NetEnv::new(&handle).await,
+            net_env: NetEnv::new(&handle, None).await,
             rtnl_handle: handle,
             sender: Some(sender),
             rx: Some(rx),
             task: None,
+            pid: None,
+            config: None,
+            uds_server: None,
+            armed: true,
         }
     }
 
@@ -63,7 +88,8 @@ impl Proxy {
         let uds_server = UdsDataServer::new(config.clone(), self.opt.ipc_path.clone());
         let listener = uds_server.bind()?;
 
-        let server = uds_server;
+        let server = uds_server.clone();
+        self.uds_server = Some(uds_server);
         tokio::spawn(async move {
             let _ = server
                 .listen(listener)
@@ -82,43 +108,85 @@ impl Proxy {
             Ok(path) => path,
         };
 
-        tracing::info!("Network device name {}", self.net_env.device.clone());
-        set_net(
-            &mut self.rtnl_handle,
-            &self.net_env,
-            config.proxy_ports,
-            config.listen_port,
-            config.safe_mode,
-        )
-        .await?;
-
-        let mut proxy = Command::new("ip");
+        // the explicit proxy is reached by the clients directly, without the netns and the rules
+        let explicit = config.explicit.is_some();
+        let mut proxy = match explicit {
+            true => Command::new(exe_path),
+            false => {
+                tracing::info!("Network device name {}", self.net_env.device.clone());
+                set_net(&mut self.rtnl_handle, &self.net_env, &config).await?;
+                let mut proxy = Command::new("ip");
+                proxy
+                    .arg("netns")
+                    .arg("exec")
+                    .arg(&self.net_env.netns)
+                    .arg(exe_path);
+                proxy
+            }
+        };
         proxy
-            .arg("netns")
-            .arg("exec")
-            .arg(&self.net_env.netns)
-            .arg(exe_path)
             .arg(format!(
                 "-{}",
                 String::from_utf8(vec![b'v'; self.opt.verbose as usize]).unwrap()
             ))
             .arg("--proxy")
             .arg(format!("--ipc-path={}", opt.ipc_path.to_str().unwrap()));
+        if !self.armed {
+            proxy.arg("--disarmed");
+        }
+        // the runtime of the proxy is built before its config is received
+        if let Some(threads) = config
+            .runtime
+            .as_ref()
+            .and_then(|runtime| runtime.worker_threads)
+        {
+            proxy.arg(format!("--worker-threads={}", threads));
+        }
+        let log_file = logging().and_then(Logging::file);
+        if let Some(logging) = logging() {
+            proxy.args(logging.proxy_args());
+        }
+        // the logs of the proxy are appended to the log file of the controller, which rotates it
+        if log_file.is_some() {
+            proxy.stderr(Stdio::piped()).env("NO_COLOR", "1");
+        }
+
+        tracing::info!("Proxy executor Starting proxy.");
+        let mut process = match proxy.stdin(Stdio::piped()).spawn() {
+            Ok(process) => {
+                tracing::info!("Proxy executor Proxy is running.");
+                process
+            }
+            Err(e) => {
+                return Err(anyhow::anyhow!("failed to exec sub proxy : {:?}", e));
+            }
+        };
+        self.pid = process.id();
+        set_proxy_pid(self.pid);
+        if let (Some(file), Some(stderr)) = (log_file, process.stderr.take()) {
+            tokio::spawn(forward_lines(stderr, file));
+        }
+        CLEAR_ON_PANIC.call_once(clear_on_panic);
+        *CLEANUP.lock().unwrap() = Some(((!explicit).then(|| self.net_env.clone()), self.pid));
+        // the proxy is killed if it is still draining after the drain timeout
+        let kill_timeout = config.drain_timeout.unwrap_or(DRAIN_TIMEOUT) + KILL_GRACE;
+        log_summary(&config);
+        self.config = Some(config);
 
         let rx = self.rx.take().unwrap();
+        let net_env = self.net_env.clone();
+        let mut handle = self.rtnl_handle.clone();
         self.task = Some(tokio::spawn(async move {
-            tracing::info!("Proxy executor Starting proxy.");
-            let mut process = match proxy.stdin(Stdio::piped()).spawn() {
-                Ok(process) => {
-                    tracing::info!("Proxy executor Proxy is running.");
-                    process
-                }
-                Err(e) => {
-                    return Err(anyhow::anyhow!("failed to exec sub proxy : {:?}", e));
-                }
-            };
             select! {
-                _ = process.wait() => {}
+                status = process.wait() => {
+                    // nothing accepts the intercepted traffic any more
+                    tracing::error!("Proxy executor sub process exited unexpectedly: {:?}", status);
+                    CLEANUP.lock().unwrap().take();
+                    if !explicit {
+                        net_env.clear_bridge(&mut handle).await?;
+                    }
+                    return Ok(true);
+                }
                 _ = rx => {
                     tracing::info!("Proxy executor killing sub process");
                     let id = process.id().unwrap() as i32;
@@ -127,41 +195,382 @@ impl Proxy {
                     }
                 }
             };
-            Ok(())
+            if timeout(kill_timeout, process.wait()).await.is_err() {
+                tracing::warn!("Proxy executor sub process is not drained in time, killing it");
+                process.kill().await?;
+            }
+            Ok(false)
         }));
         Ok(())
     }
 
     pub async fn stop(&mut self) -> anyhow::Result<()> {
         if let Some(task) = self.task.take() {
+            let explicit = self.explicit();
             if let Some(sender) = self.sender.take() {
                 let _ = sender.send(());
             };
-            let _ = self.net_env.clear_bridge(&mut self.rtnl_handle).await;
-            let _ = task.await?;
+            // the network is cleared once the proxy exits, so that the exchanges in flight are
+            // drained
+            let cleared = matches!(task.await?, Ok(true));
+            if !cleared && !explicit {
+                let _ = self.net_env.clear_bridge(&mut self.rtnl_handle).await;
+            }
+            CLEANUP.lock().unwrap().take();
         }
+        self.pid = None;
+        self.config = None;
         Ok(())
     }
 
     pub async fn reload(&mut self, config: ProxyRawConfig) -> anyhow::Result<()> {
         self.stop().await?;
-        if config.proxy_ports.is_none() {
+        if config.proxy_ports.is_none() && config.explicit.is_none() {
             return Ok(());
         }
         if self.task.is_none() {
             let mut new = Self::new(self.opt.verbose).await;
-            self.net_env = new.net_env;
+            // the device is picked among the interfaces of the config
+            self.net_env = match &config.interfaces {
+                Some(interfaces) => {
+                    NetEnv::new(&self.rtnl_handle, Some(interfaces.as_slice())).await
+                }
+                None => new.net_env,
+            };
             self.opt = new.opt;
             self.sender = new.sender.take();
             self.rx = new.rx.take();
         }
 
+        let explicit = config.explicit.is_some();
         match self.exec(config).await {
-            Err(e) => {
+            Err(e) if !explicit => {
                 self.net_env.clear_bridge(&mut self.rtnl_handle).await?;
                 Err(e)
             }
+            Err(e) => Err(e),
             Ok(_) => Ok(()),
         }
     }
+
+    /// arm arms or disarms the proxy. The disarmed proxy keeps intercepting the traffic, and
+    /// forwards it transparently without applying the rules.
+    pub fn arm(&mut self, armed: bool) {
+        self.armed = armed;
+        tracing::info!(
+            "Proxy executor {} proxy.",
+            if armed { "arming" } else { "disarming" }
+        );
+        if let Some(pid) = self.pid {
+            let signal = if armed { libc::SIGUSR2 } else { libc::SIGUSR1 };
+            unsafe {
+                libc::kill(pid as i32, signal);
+            }
+        }
+    }
+
+    /// explicit tells whether the running proxy is an explicit proxy, which has no network to clear.
+    fn explicit(&self) -> bool {
+        self.config
+            .as_ref()
+            .is_some_and(|config| config.explicit.is_some())
+    }
+
+    /// summary returns the effective config of the running proxy in short.
+    pub fn summary(&self) -> Option<ConfigSummary> {
+        self.config.as_ref().map(ConfigSummary::from)
+    }
+
+    /// update applies the config to the running proxy without dropping the connections in flight.
+    /// The rules and the other HTTP settings are swapped by the proxy on `SIGHUP`, and the iptables
+    /// rules are reconciled if the `proxy_ports` changed. The proxy is restarted by [Proxy::reload]
+    /// if the listen ports or the other settings changed, or if it is not running.
+    pub async fn update(&mut self, mut config: ProxyRawConfig) -> anyhow::Result<()> {
+        ProxyConfig::try_from(config.clone())?;
+        let current = match (&self.config, &self.uds_server, self.pid) {
+            (Some(current), Some(_), Some(_))
+                if config.proxy_ports.is_some() || config.explicit.is_some() =>
+            {
+                current.clone()
+            }
+            _ => return self.reload(config).await,
+        };
+        keep_listen_ports(&current, &mut config);
+        if restart_required(&current, &config) {
+            tracing::warn!("Proxy executor restarting proxy, the connections would be dropped.");
+            return self.reload(config).await;
+        }
+
+        if reconcile_required(&current, &config) {
+            tracing::info!("Proxy executor reconciling iptables rules.");
+            reset_net(&self.net_env, &config)?;
+        }
+        if let (Some(uds_server), Some(pid)) = (&self.uds_server, self.pid) {
+            tracing::info!("transferring proxy raw config {:?}", &config);
+            uds_server.update(config.clone());
+            unsafe {
+                libc::kill(pid as i32, libc::SIGHUP);
+            }
+        }
+        log_summary(&config);
+        self.config = Some(config);
+        Ok(())
+    }
+
+    /// diff tells what [Proxy::update] would change to apply the config, nothing is applied.
+    pub fn diff(&self, mut config: ProxyRawConfig) -> anyhow::Result<ConfigDiff> {
+        ProxyConfig::try_from(config.clone())?;
+        let running = match (&self.config, &self.uds_server, self.pid) {
+            (Some(current), Some(_), Some(_))
+                if config.proxy_ports.is_some() || config.explicit.is_some() =>
+            {
+                Some(current)
+            }
+            _ => None,
+        };
+        let (restart, reconciled) = match running {
+            Some(current) => {
+                keep_listen_ports(current, &mut config);
+                let restart = restart_required(current, &config);
+                (restart, !restart && reconcile_required(current, &config))
+            }
+            None => (true, false),
+        };
+        // the network is cleared and set again on restart
+        let diverted =
+            |config: &ProxyRawConfig| config.proxy_ports.is_some() && config.explicit.is_none();
+        let current = self.config.clone().unwrap_or_default();
+        let iptables = reconciled || (restart && (diverted(&current) || diverted(&config)));
+        Ok(ConfigDiff::new(&current, &config, iptables, restart))
+    }
+}
+
+/// DRAIN_TIMEOUT is the default drain timeout of the proxy.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// KILL_GRACE is waited for the proxy to exit after the drain timeout.
+const KILL_GRACE: Duration = Duration::from_secs(5);
+
+/// CLEANUP is the network of the running proxy, and its pid, cleared by the panic hook. The
+/// explicit proxy has no network.
+static CLEANUP: Lazy<Mutex<Option<(Option<NetEnv>, Option<u32>)>>> = Lazy::new(Default::default);
+
+static CLEAR_ON_PANIC: Once = Once::new();
+
+/// clear_on_panic installs the panic hook clearing the network of the running proxy, so that a
+/// panicking controller never leaves the node blackholed.
+fn clear_on_panic() {
+    let previous = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        previous(info);
+        // the panics of the other threads are caught by the runtime, the controller keeps running
+        if thread::current().name() != Some("main") {
+            return;
+        }
+        let (net_env, pid) = match CLEANUP.lock().map(|mut cleanup| cleanup.take()) {
+            Ok(Some(cleanup)) => cleanup,
+            _ => return,
+        };
+        if let Some(pid) = pid {
+            unsafe {
+                libc::kill(pid as i32, libc::SIGKILL);
+            }
+        }
+        let net_env = match net_env {
+            Some(net_env) => net_env,
+            None => return,
+        };
+        // the runtime of the controller could not be used in the panic
+        let cleared = thread::spawn(move || -> anyhow::Result<()> {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?;
+            runtime.block_on(async move {
+                let (conn, mut handle, _) = new_connection()?;
+                tokio::spawn(conn);
+                net_env.clear_bridge(&mut handle).await
+            })
+        })
+        .join();
+        if !matches!(cleared, Ok(Ok(()))) {
+            eprintln!("fail to clear the network of the proxy on panic");
+        }
+    }));
+}
+
+/// log_summary logs the effective config applied to the proxy.
+fn log_summary(config: &ProxyRawConfig) {
+    match serde_json::to_string(&ConfigSummary::from(config)) {
+        Ok(summary) => tracing::info!("Proxy effective config: {}", summary),
+        Err(e) => tracing::error!("fail to summarize config: {}", e),
+    }
+}
+
+/// keep_listen_ports keeps the ports listened by the running proxy, if they are not intercepted by
+/// the config, so that a change of the `proxy_ports` needn't bind the listeners again.
+fn keep_listen_ports(current: &ProxyRawConfig, config: &mut ProxyRawConfig) {
+    let listen_ports: Vec<u16> = std::iter::once(current.listen_port)
+        .chain(current.listeners.iter().flatten().map(|l| l.listen_port))
+        .collect();
+    let intercepted = |ports: &str| match parse_port_ranges(ports) {
+        Ok(ranges) => ranges
+            .iter()
+            .any(|range| listen_ports.iter().any(|port| range.contains(port))),
+        Err(_) => true,
+    };
+    let listeners = config.listeners.as_deref().unwrap_or_default();
+    if listeners.len() != listen_ports.len() - 1
+        || config.proxy_ports.as_deref().is_some_and(intercepted)
+        || listeners.iter().any(|l| intercepted(&l.proxy_ports))
+    {
+        return;
+    }
+    config.listen_port = current.listen_port;
+    for (listener, port) in config
+        .listeners
+        .iter_mut()
+        .flatten()
+        .zip(&listen_ports[1..])
+    {
+        listener.listen_port = *port;
+    }
+}
+
+/// reconcile_required tells whether the iptables rules must be reconciled to apply the config to
+/// the running proxy, nothing is diverted to the explicit proxy.
+fn reconcile_required(current: &ProxyRawConfig, config: &ProxyRawConfig) -> bool {
+    config.explicit.is_none()
+        && (current.proxy_ports != config.proxy_ports
+            || current.exclude_ports != config.exclude_ports
+            || current.ignore_destinations != config.ignore_destinations
+            || current.ignore_sources != config.ignore_sources
+            || current.direction != config.direction
+            || current.safe_mode != config.safe_mode
+            || current.listeners != config.listeners)
+}
+
+/// restart_required tells whether the proxy must be restarted to apply the config, i.e. anything
+/// but the HTTP settings and the intercepted ports changed.
+fn restart_required(current: &ProxyRawConfig, config: &ProxyRawConfig) -> bool {
+    // the ports are steered by the proxy itself with ebpf
+    let steered =
+        |config: &ProxyRawConfig| config.redirect_backend == Some(RawRedirectBackend::Ebpf);
+    let fixed = |config: &ProxyRawConfig| ProxyRawConfig {
+        proxy_ports: None,
+        exclude_ports: None,
+        ignore_destinations: None,
+        ignore_sources: None,
+        direction: None,
+        safe_mode: false,
+        compare_mode: false,
+        latency_compensation: false,
+        fault_markers: false,
+        inspect_all: false,
+        opt_in: None,
+        exchange_id_header: None,
+        rules: vec![],
+        match_policy: None,
+        role: None,
+        doh: None,
+        proxy_protocol: None,
+        validation: None,
+        upstream_pool: None,
+        max_body_size: None,
+        body_overflow: None,
+        listeners: config.listeners.clone().map(|listeners| {
+            listeners
+                .into_iter()
+                .map(|listener| RawListener {
+                    proxy_ports: String::new(),
+                    // the rule sets are swapped in place, only the listeners having one are fixed
+                    rules: listener.rules.map(|_| vec![]),
+                    ..listener
+                })
+                .collect()
+        }),
+        ..config.clone()
+    };
+    if steered(current) || steered(config) {
+        return current.proxy_ports != config.proxy_ports
+            || current.exclude_ports != config.exclude_ports
+            || current.ignore_destinations != config.ignore_destinations
+            || current.ignore_sources != config.ignore_sources
+            || current.direction != config.direction
+            || current.listeners != config.listeners
+            || fixed(current) != fixed(config);
+    }
+    fixed(current) != fixed(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use chaos_tproxy_proxy::handler::http::rule::Rule;
+    use chaos_tproxy_proxy::raw_config::{RawConfig as ProxyRawConfig, RawListener};
+
+    use crate::proxy::exec::{keep_listen_ports, restart_required};
+
+    #[test]
+    fn test_update() {
+        let current = ProxyRawConfig {
+            proxy_ports: Some("80".to_string()),
+            listen_port: 1025,
+            listeners: Some(vec![RawListener {
+                proxy_ports: "443".to_string(),
+                listen_port: 1026,
+                tls: None,
+                workers: None,
+                rules: None,
+            }]),
+            ..Default::default()
+        };
+
+        // the listen ports are kept if they are not intercepted
+        let mut config = ProxyRawConfig {
+            proxy_ports: Some("80,8080".to_string()),
+            listen_port: 1027,
+            compare_mode: true,
+            listeners: Some(vec![RawListener {
+                proxy_ports: "443,8443".to_string(),
+                listen_port: 1028,
+                tls: None,
+                workers: None,
+                rules: None,
+            }]),
+            ..Default::default()
+        };
+        keep_listen_ports(&current, &mut config);
+        assert_eq!(config.listen_port, 1025);
+        assert_eq!(config.listeners.as_ref().unwrap()[0].listen_port, 1026);
+        assert!(!restart_required(&current, &config));
+
+        let mut config = ProxyRawConfig {
+            proxy_ports: Some("80,1000:1100".to_string()),
+            listen_port: 1027,
+            ..current.clone()
+        };
+        keep_listen_ports(&current, &mut config);
+        assert_eq!(config.listen_port, 1027);
+        assert!(restart_required(&current, &config));
+
+        let config = ProxyRawConfig {
+            slo: Some(Default::default()),
+            ..current.clone()
+        };
+        assert!(restart_required(&current, &config));
+
+        // the rule sets of the listeners are swapped in place, but a new one is served by restarting
+        let with_rules = |rules| ProxyRawConfig {
+            listeners: Some(vec![RawListener {
+                rules: Some(rules),
+                ..current.listeners.as_ref().unwrap()[0].clone()
+            }]),
+            ..current.clone()
+        };
+        assert!(restart_required(&current, &with_rules(vec![])));
+        let rule = Rule::request().abort().raw();
+        assert!(!restart_required(
+            &with_rules(vec![]),
+            &with_rules(vec![rule])
+        ));
+    }
 }
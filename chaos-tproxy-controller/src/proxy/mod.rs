@@ -1,4 +1,11 @@
 pub mod config;
+#[cfg(target_os = "linux")]
+pub mod diff;
+#[cfg(target_os = "linux")]
 pub mod exec;
+#[cfg(target_os = "linux")]
 pub mod net;
+#[cfg(target_os = "linux")]
+pub mod summary;
+#[cfg(target_os = "linux")]
 pub mod uds_server;
@@ -1,23 +1,36 @@
+use std::convert::TryFrom;
+use std::net::Ipv6Addr;
 use std::process::Command;
 
 use anyhow::{anyhow, Context, Result};
-use default_net;
-use default_net::Gateway;
+use chaos_tproxy_proxy::raw_config::RawDirection;
+use default_net::{self, Gateway};
 use pnet::datalink::NetworkInterface;
-use pnet::ipnetwork::{IpNetwork, Ipv4Network};
+use pnet::ipnetwork::{IpNetwork, Ipv4Network, Ipv6Network};
 use rtnetlink::packet::route::Nla;
 use rtnetlink::packet::RouteMessage;
-use rtnetlink::Handle;
+use rtnetlink::{Handle, IpVersion};
 use uuid::Uuid;
 
 use crate::proxy::net::iptables::clear_ebtables;
+use crate::proxy::net::nftables::clear_nftables;
 use crate::proxy::net::routes::{del_routes_noblock, get_routes_noblock, load_routes};
+use crate::proxy::net::state::{finish, record};
+
+/// FWMARK marks the packets diverted to the proxy, they are routed by [ROUTE_TABLE].
+pub const FWMARK: &str = "1";
+
+/// ROUTE_TABLE delivers the marked packets locally, to the sockets of the proxy.
+pub const ROUTE_TABLE: &str = "100";
 
 #[derive(Debug, Clone)]
 pub struct NetEnv {
     pub netns: String,
     pub device: String,
     pub ip: String,
+    /// ipv6 is the global IPv6 address of the device if any, the IPv6 traffic is diverted as well.
+    pub ipv6: Option<String>,
+    gateway_v6: Option<String>,
 
     bridge1: String,
     bridge2: String,
@@ -28,10 +41,13 @@ pub struct NetEnv {
     pub veth4: String,
 
     save_routes: Vec<RouteMessage>,
+    save_routes_v6: Vec<RouteMessage>,
 }
 
 impl NetEnv {
-    pub async fn new(handle: &Handle) -> Self {
+    /// new picks the device of the traffic diverted among the `interfaces`, by
+    /// [get_default_interface].
+    pub async fn new(handle: &Handle, interfaces: Option<&[String]>) -> Self {
         let interfaces = pnet::datalink::interfaces();
         let prefix = loop {
             let key = Uuid::new_v4().to_string()[0..13].to_string();
@@ -43,7 +59,7 @@ impl NetEnv {
                 break key;
             }
         };
-        let device = get_default_interface().unwrap();
+        let device = get_default_interface(interfaces).unwrap();
         let netns = prefix.clone() + "ns";
         let bridge1 = prefix.clone() + "b1";
         let veth1 = prefix.clone() + "v1";
@@ -52,15 +68,26 @@ impl NetEnv {
         let veth3 = "veth1".to_string();
         let veth4 = prefix + "v4";
         let ip = get_ipv4(&device).unwrap();
+        let ipv6 = get_ipv6(&device);
 
-        let mut routes = get_routes_noblock(handle).await.unwrap();
+        let mut routes = get_routes_noblock(handle, IpVersion::V4).await.unwrap();
 
         routes.reverse();
 
+        let mut routes_v6 = match ipv6 {
+            Some(_) => get_routes_noblock(handle, IpVersion::V6).await.unwrap(),
+            None => vec![],
+        };
+        let gateway_v6 = routes_v6.iter().find_map(default_gateway_v6);
+
+        routes_v6.reverse();
+
         Self {
             netns,
             device: device.name,
             ip,
+            ipv6,
+            gateway_v6,
             bridge1,
             bridge2,
             veth1,
@@ -68,6 +95,17 @@ impl NetEnv {
             veth3,
             veth4,
             save_routes: routes,
+            save_routes_v6: routes_v6,
+        }
+    }
+
+    /// bridge_port returns the port of the bridge in the netns the connections of the direction
+    /// arrive on, the inbound ones from the device and the outbound ones from the target.
+    pub fn bridge_port(&self, direction: RawDirection) -> Option<&str> {
+        match direction {
+            RawDirection::Inbound => Some(&self.veth2),
+            RawDirection::Outbound => Some(&self.veth3),
+            RawDirection::Both => None,
         }
     }
 
@@ -166,7 +204,7 @@ impl NetEnv {
             ),
             ip_netns(
                 &self.netns,
-                vec!["ip", "rule", "add", "fwmark", "1", "lookup", "100"],
+                vec!["ip", "rule", "add", "fwmark", FWMARK, "lookup", ROUTE_TABLE],
             ),
             ip_netns(
                 &self.netns,
@@ -179,7 +217,7 @@ impl NetEnv {
                     "dev",
                     "lo",
                     "table",
-                    "100",
+                    ROUTE_TABLE,
                 ],
             ),
         ];
@@ -196,8 +234,11 @@ impl NetEnv {
             &self.netns,
             arp_set(&net.ip().to_string(), &veth4_mac, &self.bridge2),
         )])?;
+        if let Some(ipv6) = &self.ipv6 {
+            self.setenv_bridge_v6(ipv6, &veth4_mac)?;
+        }
 
-        let all_routes = get_routes_noblock(handle).await?;
+        let all_routes = get_routes_noblock(handle, IpVersion::V4).await?;
 
         let kernel_routes: Vec<RouteMessage> = all_routes
             .into_iter()
@@ -222,51 +263,203 @@ impl NetEnv {
         Ok(())
     }
 
+    /// setenv_bridge_v6 moves the IPv6 address to the bridge as [setenv_bridge](Self::setenv_bridge)
+    /// does to the IPv4 one, and delivers the marked IPv6 packets locally in the netns.
+    fn setenv_bridge_v6(&self, ipv6: &str, veth4_mac: &str) -> Result<()> {
+        let net: Ipv6Network = ipv6
+            .parse()
+            .context(format!("ipv6 {} parsed error", ipv6))?;
+        let net_ip = net.ip().to_string();
+        let net_ip128 = net_ip.clone() + "/128";
+
+        execute_all_with_log_error(vec![ip6_address("del", ipv6, &self.device)])?;
+
+        let mut cmdvv = vec![
+            // the address is moved rather than new, so the duplicate address detection is skipped
+            vec![
+                "ip",
+                "-6",
+                "address",
+                "add",
+                ipv6,
+                "dev",
+                &self.veth4,
+                "nodad",
+            ],
+            ip_netns(
+                &self.netns,
+                vec![
+                    "ip",
+                    "-6",
+                    "route",
+                    "add",
+                    &net_ip128,
+                    "dev",
+                    &self.bridge2,
+                    "proto",
+                    "kernel",
+                ],
+            ),
+            ip_netns(
+                &self.netns,
+                vec![
+                    "ip",
+                    "-6",
+                    "neigh",
+                    "replace",
+                    &net_ip,
+                    "lladdr",
+                    veth4_mac,
+                    "dev",
+                    &self.bridge2,
+                ],
+            ),
+            ip_netns(
+                &self.netns,
+                vec!["sysctl", "-w", "net.ipv6.conf.all.forwarding=1"],
+            ),
+            ip_netns(
+                &self.netns,
+                vec!["sysctl", "-w", "net.ipv6.ip_nonlocal_bind=1"],
+            ),
+            ip_netns(
+                &self.netns,
+                vec![
+                    "ip",
+                    "-6",
+                    "rule",
+                    "add",
+                    "fwmark",
+                    FWMARK,
+                    "lookup",
+                    ROUTE_TABLE,
+                ],
+            ),
+            ip_netns(
+                &self.netns,
+                vec![
+                    "ip",
+                    "-6",
+                    "route",
+                    "add",
+                    "local",
+                    "::/0",
+                    "dev",
+                    "lo",
+                    "table",
+                    ROUTE_TABLE,
+                ],
+            ),
+        ];
+        if let Some(gateway) = &self.gateway_v6 {
+            cmdvv.push(ip6_route_add("default", gateway, &self.veth4));
+            cmdvv.push(ip_netns(
+                &self.netns,
+                ip6_route_add("default", gateway, &self.bridge2),
+            ));
+        }
+        execute_all(cmdvv)
+    }
+
     pub async fn clear_bridge(&self, handle: &mut Handle) -> Result<()> {
-        let restore_dns = "cp /etc/resolv.conf.bak /etc/resolv.conf";
+        execute_all_with_log_error(clear_links(
+            &self.netns,
+            &self.bridge1,
+            &self.device,
+            &self.ip,
+            self.ipv6.as_deref(),
+        ))?;
+
+        restore_routes(handle, IpVersion::V4, self.save_routes.clone()).await;
+        if self.ipv6.is_some() {
+            restore_routes(handle, IpVersion::V6, self.save_routes_v6.clone()).await;
+        }
+        finish();
 
-        let cmdvv = vec![
-            ip_netns_del(&self.netns),
-            ip_link_del_bridge(&self.bridge1),
-            ip_address("add", &self.ip, &self.device),
-            bash_c(restore_dns),
-            clear_ebtables(),
-        ];
-        execute_all_with_log_error(cmdvv)?;
+        set_gateway_arp(&self.device)
+    }
 
-        let routes = get_routes_noblock(handle).await.unwrap_or_else(|e| {
-            tracing::error!("clear routes get_routes_noblock with error {}", e);
-            vec![]
-        });
+    /// bridge returns the bridge the device is put into in the host.
+    pub fn bridge(&self) -> &str {
+        &self.bridge1
+    }
+}
 
-        del_routes_noblock(handle, routes)
-            .await
-            .unwrap_or_else(|e| {
-                tracing::error!("clear routes del_routes_noblock with error {}", e);
-            });
+/// clear_links returns the commands removing the netns and the bridge, and giving the addresses
+/// back to the device, which also removes the routes through the bridge.
+pub fn clear_links<'a>(
+    netns: &'a str,
+    bridge: &'a str,
+    device: &'a str,
+    ip: &'a str,
+    ipv6: Option<&'a str>,
+) -> Vec<Vec<&'a str>> {
+    let restore_dns = "cp /etc/resolv.conf.bak /etc/resolv.conf";
+
+    // the tables of nftables if any, the ones in the netns are removed along with it anyway
+    let mut cmdvv = clear_nftables(netns);
+    cmdvv.extend(vec![
+        ip_netns_del(netns),
+        ip_link_del_bridge(bridge),
+        ip_address("add", ip, device),
+        bash_c(restore_dns),
+        clear_ebtables(),
+    ]);
+    if let Some(ipv6) = ipv6 {
+        cmdvv.push(ip6_address("add", ipv6, device));
+    }
+    cmdvv
+}
 
-        load_routes(handle, self.save_routes.clone())
-            .await
-            .unwrap_or_else(|e| {
-                tracing::error!("clear routes load_routes with error {}", e);
-            });
+/// set_gateway_arp sets the arp entry of the default gateway on the device again.
+pub fn set_gateway_arp(device: &str) -> Result<()> {
+    let Gateway {
+        mac_addr: gateway_mac,
+        ip_addr: gateway_ip,
+    } = try_get_default_gateway()?;
 
-        let Gateway {
-            mac_addr: gateway_mac,
-            ip_addr: gateway_ip,
-        } = try_get_default_gateway()?;
+    if gateway_mac.octets().iter().all(|&i| i == 0) {
+        return Ok(());
+    }
 
-        if gateway_mac.octets().iter().all(|&i| i == 0) {
-            return Ok(());
-        }
+    let gateway_ip = gateway_ip.to_string();
+    let gateway_mac = gateway_mac.to_string();
 
-        let gateway_ip = gateway_ip.to_string();
-        let gateway_mac = gateway_mac.to_string();
+    let cmdvv = vec![arp_set(&gateway_ip, &gateway_mac, device)];
+    execute_all_with_log_error(cmdvv)
+}
 
-        let cmdvv = vec![arp_set(&gateway_ip, &gateway_mac, self.device.as_str())];
-        execute_all_with_log_error(cmdvv)?;
-        Ok(())
+/// restore_routes replaces the routes of the version by the saved ones.
+async fn restore_routes(handle: &mut Handle, version: IpVersion, saved: Vec<RouteMessage>) {
+    let routes = get_routes_noblock(handle, version)
+        .await
+        .unwrap_or_else(|e| {
+            tracing::error!("clear routes get_routes_noblock with error {}", e);
+            vec![]
+        });
+
+    del_routes_noblock(handle, routes)
+        .await
+        .unwrap_or_else(|e| {
+            tracing::error!("clear routes del_routes_noblock with error {}", e);
+        });
+
+    load_routes(handle, saved).await.unwrap_or_else(|e| {
+        tracing::error!("clear routes load_routes with error {}", e);
+    });
+}
+
+/// default_gateway_v6 returns the gateway of the IPv6 default route.
+fn default_gateway_v6(route: &RouteMessage) -> Option<String> {
+    if route.header.destination_prefix_length != 0 {
+        return None;
     }
+    route.nlas.iter().find_map(|nla| match nla {
+        Nla::Gateway(addr) => <[u8; 16]>::try_from(addr.as_slice())
+            .ok()
+            .map(|octets| Ipv6Addr::from(octets).to_string()),
+        _ => None,
+    })
 }
 
 pub fn arp_set<'a>(ip: &'a str, mac: &'a str, device: &'a str) -> Vec<&'a str> {
@@ -340,12 +533,23 @@ pub fn ip_address<'a>(action: &'a str, address: &'a str, device: &'a str) -> Vec
     vec!["ip", "address", action, address, "dev", device]
 }
 
+pub fn ip6_address<'a>(action: &'a str, address: &'a str, device: &'a str) -> Vec<&'a str> {
+    vec!["ip", "-6", "address", action, address, "dev", device]
+}
+
 pub fn ip_route_add<'a>(target: &'a str, gateway_ip: &'a str, device: &'a str) -> Vec<&'a str> {
     vec![
         "ip", "route", "add", target, "via", gateway_ip, "dev", device, "proto", "kernel", "onlink",
     ]
 }
 
+pub fn ip6_route_add<'a>(target: &'a str, gateway_ip: &'a str, device: &'a str) -> Vec<&'a str> {
+    vec![
+        "ip", "-6", "route", "add", target, "via", gateway_ip, "dev", device, "proto", "kernel",
+        "onlink",
+    ]
+}
+
 pub fn try_get_default_gateway() -> Result<Gateway> {
     let mut count = 5;
     while count > 0 {
@@ -367,6 +571,18 @@ pub fn get_ipv4(device: &NetworkInterface) -> Option<String> {
     None
 }
 
+/// get_ipv6 returns the first global IPv6 address of the device, the link-local ones are left.
+pub fn get_ipv6(device: &NetworkInterface) -> Option<String> {
+    for ip in &device.ips {
+        if let IpNetwork::V6(ipv6) = ip {
+            if ipv6.ip().segments()[0] & 0xffc0 != 0xfe80 {
+                return Some(ipv6.ip().to_string() + "/" + &ipv6.prefix().to_string());
+            }
+        }
+    }
+    None
+}
+
 pub fn execute_all_with_log_error(cmdvv: Vec<Vec<&str>>) -> Result<()> {
     for cmdv in cmdvv {
         let _ = execute(cmdv);
@@ -383,6 +599,7 @@ pub fn execute_all(cmdvv: Vec<Vec<&str>>) -> Result<()> {
 
 pub fn execute(cmdv: Vec<&str>) -> Result<()> {
     tracing::trace!("{:?}", cmdv);
+    record(&cmdv);
     let mut iter = cmdv.iter();
     let mut cmd = match iter.next() {
         None => {
@@ -412,12 +629,23 @@ pub fn get_interface(name: String) -> Result<NetworkInterface> {
     Err(anyhow!("no valid interface"))
 }
 
-pub fn get_default_interface() -> Result<NetworkInterface> {
-    let interfaces = pnet::datalink::interfaces();
-    for interface in interfaces {
-        if !interface.is_loopback() && interface.is_up() && !interface.ips.is_empty() {
+/// get_default_interface returns the first interface up with addresses but the loopback, among
+/// the `interfaces` if any, e.g. `eth0` but not the bridge of the CNI.
+pub fn get_default_interface(interfaces: Option<&[String]>) -> Result<NetworkInterface> {
+    let allowed = |interface: &NetworkInterface| {
+        interfaces.is_none_or(|interfaces| interfaces.contains(&interface.name))
+    };
+    for interface in pnet::datalink::interfaces() {
+        if !interface.is_loopback()
+            && interface.is_up()
+            && !interface.ips.is_empty()
+            && allowed(&interface)
+        {
             return Ok(interface);
         }
     }
-    Err(anyhow!("no valid interface"))
+    match interfaces {
+        Some(interfaces) => Err(anyhow!("no valid interface in {}", interfaces.join(", "))),
+        None => Err(anyhow!("no valid interface")),
+    }
 }
@@ -1,75 +1,51 @@
+use chaos_tproxy_proxy::raw_config::{RawConfig as ProxyRawConfig, RawDirection};
+use pnet::ipnetwork::IpNetwork;
+
 use crate::proxy::net::bridge::{ip_netns, NetEnv};
 
+/// Exempt is the traffic never diverted, even if it is of the proxy ports.
+#[derive(Debug, Clone, Copy)]
+pub struct Exempt<'a> {
+    /// ports of multiport, e.g. `8080,9000:9100`.
+    pub ports: Option<&'a str>,
+    /// destinations and sources are the networks in CIDR, of either family.
+    pub destinations: &'a [String],
+    pub sources: &'a [String],
+    /// interface is the port of the bridge the connections of the other direction arrive on.
+    pub interface: Option<&'a str>,
+}
+
+impl<'a> Exempt<'a> {
+    pub fn of(net_env: &'a NetEnv, config: &'a ProxyRawConfig) -> Self {
+        let other = match config.direction {
+            Some(RawDirection::Inbound) => RawDirection::Outbound,
+            Some(RawDirection::Outbound) => RawDirection::Inbound,
+            Some(RawDirection::Both) | None => RawDirection::Both,
+        };
+        Self {
+            interface: net_env.bridge_port(other),
+            ports: config.exclude_ports.as_deref(),
+            destinations: config.ignore_destinations.as_deref().unwrap_or_default(),
+            sources: config.ignore_sources.as_deref().unwrap_or_default(),
+        }
+    }
+}
+
+/// set_iptables redirects the `proxy_ports` (all ports if `None`) but the exempt traffic to the
+/// `listen_port`, and the ports of each listener (`(proxy_ports, listen_port)`) to its own port.
 pub fn set_iptables<'a>(
     net_env: &'a NetEnv,
     proxy_ports: Option<&'a str>,
+    exempt: Exempt<'a>,
     listen_port: &'a str,
+    listeners: &'a [(String, String)],
     device_mac: &'a str,
 ) -> Vec<Vec<&'a str>> {
-    let cmdv = match proxy_ports {
-        Some(proxy_ports) => ip_netns(
-            &net_env.netns,
-            vec![
-                "iptables",
-                "-t",
-                "mangle",
-                "-A",
-                "PREROUTING",
-                "-p",
-                "tcp",
-                "-m",
-                "multiport",
-                "--dports",
-                proxy_ports,
-                "-j",
-                "TPROXY",
-                "--tproxy-mark",
-                "0x1/0x1",
-                "--on-port",
-                listen_port,
-            ],
-        ),
-        None => ip_netns(
-            &net_env.netns,
-            vec![
-                "iptables",
-                "-t",
-                "mangle",
-                "-A",
-                "PREROUTING",
-                "-p",
-                "tcp",
-                "-j",
-                "TPROXY",
-                "--tproxy-mark",
-                "0x1/0x1",
-                "--on-port",
-                listen_port,
-            ],
-        ),
-    };
-
-    vec![
+    let mut rules = vec![
         ip_netns(
             &net_env.netns,
             vec!["iptables", "-t", "mangle", "-N", "DIVERT"],
         ),
-        ip_netns(
-            &net_env.netns,
-            vec![
-                "iptables",
-                "-t",
-                "mangle",
-                "-A",
-                "PREROUTING",
-                "-p",
-                "tcp",
-                "-m",
-                "socket",
-                "-j",
-                "DIVERT",
-            ],
-        ),
         ip_netns(
             &net_env.netns,
             vec![
@@ -88,7 +64,50 @@ pub fn set_iptables<'a>(
             &net_env.netns,
             vec!["iptables", "-t", "mangle", "-A", "DIVERT", "-j", "ACCEPT"],
         ),
-        cmdv,
+    ];
+    rules.extend(tproxy_rules(
+        net_env,
+        proxy_ports,
+        exempt,
+        listen_port,
+        listeners,
+    ));
+    let mut cmds = with_ipv6(net_env, rules);
+    cmds.extend(set_ebtables(net_env, device_mac));
+    cmds
+}
+
+/// set_ebtables routes the TCP frames but the ones of ssh to the proxy instead of bridging them, and
+/// delivers the frames of the device to the bridge.
+pub fn set_ebtables<'a>(net_env: &'a NetEnv, device_mac: &'a str) -> Vec<Vec<&'a str>> {
+    let mut cmds = vec![];
+    if net_env.ipv6.is_some() {
+        cmds.push(ip_netns(
+            &net_env.netns,
+            vec![
+                "ebtables-legacy",
+                "-t",
+                "broute",
+                "-A",
+                "BROUTING",
+                "-p",
+                "IPv6",
+                "--ip6-proto",
+                "6",
+                "--ip6-dport",
+                "!",
+                "22",
+                "--ip6-sport",
+                "!",
+                "22",
+                "-j",
+                "redirect",
+                "--redirect-target",
+                "DROP",
+            ],
+        ));
+    }
+    cmds.extend(vec![
         ip_netns(
             &net_env.netns,
             vec![
@@ -128,10 +147,270 @@ pub fn set_iptables<'a>(
             "--dnat-target",
             "ACCEPT",
         ],
-    ]
+    ]);
+    cmds
+}
+
+/// reset_iptables replaces the rules of the `PREROUTING` chain in place, e.g. the `proxy_ports`
+/// changed. The connections already accepted are still diverted by their sockets.
+pub fn reset_iptables<'a>(
+    net_env: &'a NetEnv,
+    proxy_ports: Option<&'a str>,
+    exempt: Exempt<'a>,
+    listen_port: &'a str,
+    listeners: &'a [(String, String)],
+    safe: bool,
+    dns: Option<bool>,
+) -> Vec<Vec<&'a str>> {
+    let mut rules = vec![ip_netns(
+        &net_env.netns,
+        vec!["iptables", "-t", "mangle", "-F", "PREROUTING"],
+    )];
+    rules.extend(tproxy_rules(
+        net_env,
+        proxy_ports,
+        exempt,
+        listen_port,
+        listeners,
+    ));
+    if let Some(tcp) = dns {
+        rules.extend(dns_tproxy_rules(net_env, listen_port, tcp));
+    }
+    if safe {
+        rules.extend(safe_rules(net_env));
+    }
+    with_ipv6(net_env, rules)
+}
+
+/// with_ipv6 appends the ip6tables counterparts of the iptables rules if the device has an IPv6
+/// address, the IPv4 address of the device is replaced by the IPv6 one. The rules matching the
+/// networks of the other family are dropped from each.
+fn with_ipv6<'a>(net_env: &'a NetEnv, rules: Vec<Vec<&'a str>>) -> Vec<Vec<&'a str>> {
+    let mut cmds: Vec<Vec<&str>> = rules
+        .iter()
+        .filter(|cmd| of_family(cmd, false))
+        .cloned()
+        .collect();
+    let ipv6 = match &net_env.ipv6 {
+        Some(ipv6) => ipv6.as_str(),
+        None => return cmds,
+    };
+    let rules_v6 = rules
+        .iter()
+        .map(|cmd| {
+            cmd.iter()
+                .map(|&arg| match arg {
+                    "iptables" => "ip6tables",
+                    arg if arg == net_env.ip => ipv6,
+                    arg => arg,
+                })
+                .collect::<Vec<_>>()
+        })
+        .filter(|cmd| of_family(cmd, true));
+    cmds.extend(rules_v6);
+    cmds
+}
+
+/// of_family tells whether the networks matched by the rule, e.g. `-d 10.96.0.0/12`, are all of
+/// the family.
+fn of_family(cmd: &[&str], ipv6: bool) -> bool {
+    cmd.windows(2)
+        .all(|args| match (args[0], args[1].parse::<IpNetwork>()) {
+            ("-s" | "-d", Ok(network)) => network.is_ipv6() == ipv6,
+            _ => true,
+        })
+}
+
+/// tproxy_rules are the rules of the `PREROUTING` chain diverting the TCP connections, the exempt
+/// traffic is accepted before any of them.
+fn tproxy_rules<'a>(
+    net_env: &'a NetEnv,
+    proxy_ports: Option<&'a str>,
+    exempt: Exempt<'a>,
+    listen_port: &'a str,
+    listeners: &'a [(String, String)],
+) -> Vec<Vec<&'a str>> {
+    let tproxy = |ports: &'a str, listen_port: &'a str| {
+        ip_netns(
+            &net_env.netns,
+            vec![
+                "iptables",
+                "-t",
+                "mangle",
+                "-A",
+                "PREROUTING",
+                "-p",
+                "tcp",
+                "-m",
+                "multiport",
+                "--dports",
+                ports,
+                "-j",
+                "TPROXY",
+                "--tproxy-mark",
+                "0x1/0x1",
+                "--on-port",
+                listen_port,
+            ],
+        )
+    };
+    // the connections of the other direction are accepted by the port of the bridge they arrive on
+    let networks = exempt
+        .interface
+        .map(|interface| ("-i", interface))
+        .into_iter()
+        .chain(
+            exempt
+                .destinations
+                .iter()
+                .map(|network| ("-d", network.as_str())),
+        )
+        .chain(
+            exempt
+                .sources
+                .iter()
+                .map(|network| ("-s", network.as_str())),
+        );
+    // the DNS queries of the networks, e.g. of the node-local DNS, are accepted as well
+    let network_cmds = networks.map(|(direction, network)| {
+        ip_netns(
+            &net_env.netns,
+            vec![
+                "iptables",
+                "-t",
+                "mangle",
+                "-A",
+                "PREROUTING",
+                direction,
+                network,
+                "-j",
+                "ACCEPT",
+            ],
+        )
+    });
+    let exclude_cmds = exempt
+        .ports
+        .into_iter()
+        .flat_map(multiport_chunks)
+        .map(|ports| {
+            ip_netns(
+                &net_env.netns,
+                vec![
+                    "iptables",
+                    "-t",
+                    "mangle",
+                    "-A",
+                    "PREROUTING",
+                    "-p",
+                    "tcp",
+                    "-m",
+                    "multiport",
+                    "--dports",
+                    ports,
+                    "-j",
+                    "ACCEPT",
+                ],
+            )
+        });
+    // the ports of the listeners are matched before the ones of `listen_port`
+    let listener_cmds = listeners.iter().flat_map(|(proxy_ports, listen_port)| {
+        multiport_chunks(proxy_ports)
+            .into_iter()
+            .map(move |ports| tproxy(ports, listen_port.as_str()))
+    });
+    let proxy_cmds = match proxy_ports {
+        Some(proxy_ports) => multiport_chunks(proxy_ports)
+            .into_iter()
+            .map(|ports| tproxy(ports, listen_port))
+            .collect(),
+        None => vec![ip_netns(
+            &net_env.netns,
+            vec![
+                "iptables",
+                "-t",
+                "mangle",
+                "-A",
+                "PREROUTING",
+                "-p",
+                "tcp",
+                "-j",
+                "TPROXY",
+                "--tproxy-mark",
+                "0x1/0x1",
+                "--on-port",
+                listen_port,
+            ],
+        )],
+    };
+
+    let mut cmds = vec![ip_netns(
+        &net_env.netns,
+        vec![
+            "iptables",
+            "-t",
+            "mangle",
+            "-A",
+            "PREROUTING",
+            "-p",
+            "tcp",
+            "-m",
+            "socket",
+            "-j",
+            "DIVERT",
+        ],
+    )];
+    cmds.extend(network_cmds);
+    cmds.extend(exclude_cmds);
+    cmds.extend(listener_cmds);
+    cmds.extend(proxy_cmds);
+    cmds
+}
+
+/// MULTIPORT_SLOTS is the number of the ports a multiport match takes at most, a range takes two.
+const MULTIPORT_SLOTS: usize = 15;
+
+/// multiport_chunks splits the ports of multiport, e.g. `80,8000:8080`, into the ones fitting in a
+/// single rule.
+fn multiport_chunks(ports: &str) -> Vec<&str> {
+    let mut chunks = vec![];
+    let (mut start, mut slots) = (0, 0);
+    let mut offset = 0;
+    for port in ports.split(',') {
+        let taken = if port.contains(':') { 2 } else { 1 };
+        if slots + taken > MULTIPORT_SLOTS {
+            chunks.push(&ports[start..offset - 1]);
+            start = offset;
+            slots = 0;
+        }
+        slots += taken;
+        offset += port.len() + 1;
+    }
+    chunks.push(&ports[start..]);
+    chunks
 }
 
 pub fn set_iptables_safe<'a>(net_env: &'a NetEnv, device_mac: &'a str) -> Vec<Vec<&'a str>> {
+    let mut cmds = with_ipv6(net_env, safe_rules(net_env));
+    cmds.push(vec![
+        "ebtables",
+        "-t",
+        "nat",
+        "-A",
+        "PREROUTING",
+        "-i",
+        &net_env.device,
+        "-j",
+        "dnat",
+        "--to-dst",
+        device_mac,
+        "--dnat-target",
+        "ACCEPT",
+    ]);
+    cmds
+}
+
+/// safe_rules are the rules of the `PREROUTING` chain accepting the traffic of the low ports.
+fn safe_rules(net_env: &NetEnv) -> Vec<Vec<&str>> {
     vec![
         ip_netns(
             &net_env.netns,
@@ -205,24 +484,193 @@ pub fn set_iptables_safe<'a>(net_env: &'a NetEnv, device_mac: &'a str) -> Vec<Ve
                 "ACCEPT",
             ],
         ),
-        vec![
-            "ebtables",
-            "-t",
-            "nat",
-            "-A",
-            "PREROUTING",
-            "-i",
-            &net_env.device,
-            "-j",
-            "dnat",
-            "--to-dst",
-            device_mac,
-            "--dnat-target",
-            "ACCEPT",
-        ],
     ]
 }
 
+/// set_iptables_dns redirects the DNS queries over UDP to the same port number as the TCP listener,
+/// and the ones over TCP as well if `tcp` is set, e.g. the `proxy_ports` don't contain 53.
+pub fn set_iptables_dns<'a>(
+    net_env: &'a NetEnv,
+    listen_port: &'a str,
+    tcp: bool,
+) -> Vec<Vec<&'a str>> {
+    let mut cmds = with_ipv6(net_env, dns_tproxy_rules(net_env, listen_port, tcp));
+    if net_env.ipv6.is_some() {
+        for port in ["--ip6-dport", "--ip6-sport"] {
+            cmds.push(ip_netns(
+                &net_env.netns,
+                vec![
+                    "ebtables-legacy",
+                    "-t",
+                    "broute",
+                    "-A",
+                    "BROUTING",
+                    "-p",
+                    "IPv6",
+                    "--ip6-proto",
+                    "17",
+                    port,
+                    "53",
+                    "-j",
+                    "redirect",
+                    "--redirect-target",
+                    "DROP",
+                ],
+            ));
+        }
+    }
+    cmds.extend(vec![
+        ip_netns(
+            &net_env.netns,
+            vec![
+                "ebtables-legacy",
+                "-t",
+                "broute",
+                "-A",
+                "BROUTING",
+                "-p",
+                "IPv4",
+                "--ip-proto",
+                "17",
+                "--ip-dport",
+                "53",
+                "-j",
+                "redirect",
+                "--redirect-target",
+                "DROP",
+            ],
+        ),
+        ip_netns(
+            &net_env.netns,
+            vec![
+                "ebtables-legacy",
+                "-t",
+                "broute",
+                "-A",
+                "BROUTING",
+                "-p",
+                "IPv4",
+                "--ip-proto",
+                "17",
+                "--ip-sport",
+                "53",
+                "-j",
+                "redirect",
+                "--redirect-target",
+                "DROP",
+            ],
+        ),
+    ]);
+    cmds
+}
+
+/// dns_tproxy_rules are the rules of the `PREROUTING` chain diverting the DNS queries.
+fn dns_tproxy_rules<'a>(net_env: &'a NetEnv, listen_port: &'a str, tcp: bool) -> Vec<Vec<&'a str>> {
+    let mut cmds = vec![
+        ip_netns(
+            &net_env.netns,
+            vec![
+                "iptables",
+                "-t",
+                "mangle",
+                "-A",
+                "PREROUTING",
+                "-p",
+                "udp",
+                "-m",
+                "socket",
+                "-j",
+                "DIVERT",
+            ],
+        ),
+        ip_netns(
+            &net_env.netns,
+            vec![
+                "iptables",
+                "-t",
+                "mangle",
+                "-A",
+                "PREROUTING",
+                "-p",
+                "udp",
+                "--dport",
+                "53",
+                "-j",
+                "TPROXY",
+                "--tproxy-mark",
+                "0x1/0x1",
+                "--on-port",
+                listen_port,
+            ],
+        ),
+    ];
+    if tcp {
+        cmds.push(ip_netns(
+            &net_env.netns,
+            vec![
+                "iptables",
+                "-t",
+                "mangle",
+                "-A",
+                "PREROUTING",
+                "-p",
+                "tcp",
+                "--dport",
+                "53",
+                "-j",
+                "TPROXY",
+                "--tproxy-mark",
+                "0x1/0x1",
+                "--on-port",
+                listen_port,
+            ],
+        ));
+    }
+    cmds
+}
+
 pub fn clear_ebtables() -> Vec<&'static str> {
     vec!["ebtables", "-t", "nat", "-F"]
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::proxy::net::iptables::{multiport_chunks, of_family};
+
+    #[test]
+    fn test_multiport_chunks() {
+        assert_eq!(multiport_chunks("80,8000:8080"), vec!["80,8000:8080"]);
+        let ports: Vec<String> = (1..=20).map(|port| port.to_string()).collect();
+        let ports = format!("{},100:200", ports.join(","));
+        assert_eq!(
+            multiport_chunks(&ports),
+            vec![
+                "1,2,3,4,5,6,7,8,9,10,11,12,13,14,15",
+                "16,17,18,19,20,100:200"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_of_family() {
+        let rule = |network| {
+            vec![
+                "iptables",
+                "-A",
+                "PREROUTING",
+                "-d",
+                network,
+                "-j",
+                "ACCEPT",
+            ]
+        };
+        assert!(of_family(&rule("10.96.0.0/12"), false));
+        assert!(!of_family(&rule("10.96.0.0/12"), true));
+        assert!(of_family(&rule("fd00::/8"), true));
+        // the ports are never taken as networks
+        assert!(of_family(
+            &["iptables", "--dports", "1", "-j", "ACCEPT"],
+            true
+        ));
+    }
+}
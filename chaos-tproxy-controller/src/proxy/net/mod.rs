@@ -1,6 +1,8 @@
 pub mod arp;
 pub mod bridge;
 pub mod iptables;
+pub mod nftables;
 pub mod ping;
 pub mod routes;
 pub mod set_net;
+pub mod state;
@@ -0,0 +1,235 @@
+use pnet::ipnetwork::IpNetwork;
+
+use crate::proxy::net::bridge::{ip_netns, NetEnv, FWMARK};
+use crate::proxy::net::iptables::Exempt;
+
+/// TABLE is the name of the tables holding all the rules, so that they are removed as a whole.
+pub const TABLE: &str = "chaos_tproxy";
+
+/// set_nftables returns the nft commands creating the tables in the netns. They divert the
+/// `proxy_ports` (all ports if `None`) but the exempt traffic to the `listen_port`, and the ports
+/// of each listener to its own port, as [set_iptables](crate::proxy::net::iptables::set_iptables)
+/// does. The DNS queries are diverted if `dns` is set, over TCP as well if it is true.
+pub fn set_nftables(
+    net_env: &NetEnv,
+    proxy_ports: Option<&str>,
+    exempt: Exempt<'_>,
+    listen_port: &str,
+    listeners: &[(String, String)],
+    safe: bool,
+    dns: Option<bool>,
+) -> Vec<String> {
+    let mut cmds = vec![
+        format!("add table inet {}", TABLE),
+        format!(
+            "add chain inet {} prerouting {{ type filter hook prerouting priority -150; policy accept; }}",
+            TABLE
+        ),
+        format!("add table bridge {}", TABLE),
+        format!(
+            "add chain bridge {} brouting {{ type filter hook prerouting priority -300; policy accept; }}",
+            TABLE
+        ),
+    ];
+    // the frames are routed to the proxy instead of bridged, except the ones of ssh
+    let mut families = vec!["ip"];
+    if net_env.ipv6.is_some() {
+        families.push("ip6");
+    }
+    for family in &families {
+        cmds.push(format!(
+            "add rule bridge {} brouting ether type {} meta l4proto tcp tcp dport != 22 tcp sport != 22 meta broute set 1",
+            TABLE, family
+        ));
+        if dns.is_some() {
+            for port in ["dport", "sport"] {
+                cmds.push(format!(
+                    "add rule bridge {} brouting ether type {} meta l4proto udp udp {} 53 meta broute set 1",
+                    TABLE, family, port
+                ));
+            }
+        }
+    }
+    cmds.extend(prerouting_rules(
+        net_env,
+        proxy_ports,
+        exempt,
+        listen_port,
+        listeners,
+        safe,
+        dns,
+    ));
+    cmds
+}
+
+/// reset_nftables replaces the rules of the `prerouting` chain in place, e.g. the `proxy_ports`
+/// changed. The connections already accepted are still diverted by their sockets.
+pub fn reset_nftables(
+    net_env: &NetEnv,
+    proxy_ports: Option<&str>,
+    exempt: Exempt<'_>,
+    listen_port: &str,
+    listeners: &[(String, String)],
+    safe: bool,
+    dns: Option<bool>,
+) -> Vec<String> {
+    let mut cmds = vec![format!("flush chain inet {} prerouting", TABLE)];
+    cmds.extend(prerouting_rules(
+        net_env,
+        proxy_ports,
+        exempt,
+        listen_port,
+        listeners,
+        safe,
+        dns,
+    ));
+    cmds
+}
+
+/// set_nftables_host returns the nft commands creating the table in the host, which delivers the
+/// frames of the device to the bridge as the `ebtables` nat rule does.
+pub fn set_nftables_host(net_env: &NetEnv, device_mac: &str) -> Vec<String> {
+    vec![
+        format!("add table bridge {}", TABLE),
+        format!(
+            "add chain bridge {} prerouting {{ type filter hook prerouting priority -300; policy accept; }}",
+            TABLE
+        ),
+        format!(
+            "add rule bridge {} prerouting iifname \"{}\" ether daddr set {} accept",
+            TABLE, net_env.device, device_mac
+        ),
+    ]
+}
+
+/// clear_nftables removes the tables of both the host and the netns, the missing ones fail.
+pub fn clear_nftables(netns: &str) -> Vec<Vec<&str>> {
+    vec![
+        vec!["nft", "delete", "table", "bridge", TABLE],
+        ip_netns(netns, vec!["nft", "delete", "table", "inet", TABLE]),
+        ip_netns(netns, vec!["nft", "delete", "table", "bridge", TABLE]),
+    ]
+}
+
+/// nft returns the command running the nft command, in the netns if any.
+pub fn nft<'a>(netns: Option<&'a str>, cmd: &'a str) -> Vec<&'a str> {
+    match netns {
+        Some(netns) => ip_netns(netns, vec!["nft", cmd]),
+        None => vec!["nft", cmd],
+    }
+}
+
+/// prerouting_rules are the rules of the `prerouting` chain diverting the connections and the DNS
+/// queries, the ones accepting the traffic of the low ports come first if `safe` is set.
+fn prerouting_rules(
+    net_env: &NetEnv,
+    proxy_ports: Option<&str>,
+    exempt: Exempt<'_>,
+    listen_port: &str,
+    listeners: &[(String, String)],
+    safe: bool,
+    dns: Option<bool>,
+) -> Vec<String> {
+    let rule = |statement: String| format!("add rule inet {} prerouting {}", TABLE, statement);
+    let tproxy = |port: &str| format!("tproxy to :{} meta mark set {} accept", port, FWMARK);
+    let mut cmds = vec![];
+    if safe {
+        let networks = std::iter::once(&net_env.ip)
+            .chain(&net_env.ipv6)
+            .filter_map(|ip| ip.parse::<IpNetwork>().ok());
+        for network in networks {
+            let family = if network.is_ipv4() { "ip" } else { "ip6" };
+            let network = format!("{}/{}", network.network(), network.prefix());
+            cmds.push(rule(format!(
+                "{} saddr {} tcp dport 1-1025 accept",
+                family, network
+            )));
+            cmds.push(rule(format!(
+                "{} daddr {} tcp sport 1-1025 accept",
+                family, network
+            )));
+        }
+    }
+    cmds.push(rule(format!(
+        "meta l4proto tcp socket transparent 1 meta mark set {} accept",
+        FWMARK
+    )));
+    // the connections of the other direction are accepted by the port of the bridge they arrive on
+    if let Some(interface) = exempt.interface {
+        cmds.push(rule(format!("iifname \"{}\" accept", interface)));
+    }
+    for (direction, networks) in [("daddr", exempt.destinations), ("saddr", exempt.sources)] {
+        let networks: Vec<IpNetwork> = networks
+            .iter()
+            .filter_map(|network| network.parse().ok())
+            .collect();
+        for (family, ipv6) in [("ip", false), ("ip6", true)] {
+            let networks: Vec<String> = networks
+                .iter()
+                .filter(|network| network.is_ipv6() == ipv6)
+                .map(ToString::to_string)
+                .collect();
+            if !networks.is_empty() {
+                cmds.push(rule(format!(
+                    "{} {} {{ {} }} accept",
+                    family,
+                    direction,
+                    networks.join(", ")
+                )));
+            }
+        }
+    }
+    if let Some(exclude_ports) = exempt.ports {
+        cmds.push(rule(format!(
+            "tcp dport {} accept",
+            nft_ports(exclude_ports)
+        )));
+    }
+    // the ports of the listeners are matched before the ones of `listen_port`
+    for (proxy_ports, listen_port) in listeners {
+        cmds.push(rule(format!(
+            "tcp dport {} {}",
+            nft_ports(proxy_ports),
+            tproxy(listen_port)
+        )));
+    }
+    cmds.push(rule(match proxy_ports {
+        Some(proxy_ports) => format!(
+            "tcp dport {} {}",
+            nft_ports(proxy_ports),
+            tproxy(listen_port)
+        ),
+        None => format!("meta l4proto tcp {}", tproxy(listen_port)),
+    }));
+    if let Some(tcp) = dns {
+        cmds.push(rule(format!(
+            "meta l4proto udp socket transparent 1 meta mark set {} accept",
+            FWMARK
+        )));
+        cmds.push(rule(format!("udp dport 53 {}", tproxy(listen_port))));
+        if tcp {
+            cmds.push(rule(format!("tcp dport 53 {}", tproxy(listen_port))));
+        }
+    }
+    cmds
+}
+
+/// nft_ports converts the ports of iptables multiport, e.g. `80,8000:8080`, to a set of nft.
+fn nft_ports(ports: &str) -> String {
+    let ports: Vec<String> = ports
+        .split(',')
+        .map(|port| port.replace(':', "-"))
+        .collect();
+    format!("{{ {} }}", ports.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::proxy::net::nftables::nft_ports;
+
+    #[test]
+    fn test_nft_ports() {
+        assert_eq!(nft_ports("80"), "{ 80 }");
+        assert_eq!(nft_ports("80,8000:8080"), "{ 80, 8000-8080 }");
+    }
+}
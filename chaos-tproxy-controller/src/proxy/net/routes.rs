@@ -4,8 +4,8 @@ use iproute2_rs::ip::iproute::{del_routes, get_routes, Action, IPRoute};
 use rtnetlink::packet::RouteMessage;
 use rtnetlink::{Handle, IpVersion};
 
-pub async fn get_routes_noblock(handle: &Handle) -> Result<Vec<RouteMessage>> {
-    let routes = get_routes(handle, IpVersion::V4).await?;
+pub async fn get_routes_noblock(handle: &Handle, version: IpVersion) -> Result<Vec<RouteMessage>> {
+    let routes = get_routes(handle, version).await?;
     Ok(routes
         .into_iter()
         .filter(|route| route.header.table != 255)
@@ -42,7 +42,7 @@ pub async fn load_routes(handle: &mut Handle, msgs: Vec<RouteMessage>) -> Result
 
 #[cfg(test)]
 mod test {
-    use rtnetlink::new_connection;
+    use rtnetlink::{new_connection, IpVersion};
     use tokio::spawn;
 
     use crate::proxy::net::routes::{del_routes_noblock, get_routes_noblock, load_routes};
@@ -54,7 +54,7 @@ mod test {
 
         spawn(conn);
 
-        let mut routes = get_routes_noblock(&handle).await.unwrap();
+        let mut routes = get_routes_noblock(&handle, IpVersion::V4).await.unwrap();
         del_routes_noblock(&handle, routes.clone()).await.unwrap();
 
         routes.reverse();
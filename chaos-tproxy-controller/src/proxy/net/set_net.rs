@@ -1,24 +1,36 @@
-use std::option::Option::Some;
-
 use anyhow::anyhow;
+use chaos_tproxy_proxy::raw_config::{
+    parse_port_ranges, RawConfig as ProxyRawConfig, RawListener, RawRedirectBackend,
+};
 use libarp::interfaces::Interface;
+use pnet::ipnetwork::IpNetwork;
 use rtnetlink::Handle;
 
 use crate::proxy::net::arp::gratuitous_arp;
-use crate::proxy::net::bridge::{bash_c, execute, execute_all, get_interface, NetEnv};
-use crate::proxy::net::iptables::{set_iptables, set_iptables_safe};
+use crate::proxy::net::bridge::{
+    bash_c, execute, execute_all, get_interface, ip_netns, NetEnv, ROUTE_TABLE,
+};
+use crate::proxy::net::iptables::{
+    reset_iptables, set_ebtables, set_iptables, set_iptables_dns, set_iptables_safe, Exempt,
+};
+use crate::proxy::net::nftables::{nft, reset_nftables, set_nftables, set_nftables_host};
 use crate::proxy::net::ping::try_ping;
+use crate::proxy::net::state::begin;
 
+/// set_net sets the bridge up and diverts the ports of the config to the proxy. The changes are
+/// recorded into the state file until the network is cleared.
 #[cfg(target_os = "linux")]
 pub async fn set_net(
     handle: &mut Handle,
     net_env: &NetEnv,
-    proxy_ports: Option<String>,
-    listen_port: u16,
-    safe: bool,
+    config: &ProxyRawConfig,
 ) -> anyhow::Result<()> {
+    begin(net_env)?;
     net_env.setenv_bridge(handle).await?;
-    let port = listen_port.to_string();
+    let proxy_ports = config.proxy_ports.as_deref();
+    let exempt = Exempt::of(net_env, config);
+    let (safe, dns) = (config.safe_mode, config.dns.is_some());
+    let port = config.listen_port.to_string();
     let restore_dns = "cp /etc/resolv.conf.bak /etc/resolv.conf";
     let device_interface = get_interface(net_env.veth4.clone()).unwrap();
     let device_mac = device_interface.mac.unwrap().to_string();
@@ -30,14 +42,65 @@ pub async fn set_net(
         arp_interface.get_mac(),
     );
 
-    if let Some(ref proxy_ports) = proxy_ports {
-        execute_all(set_iptables(net_env, Some(proxy_ports), &port, &device_mac))?;
-    } else {
-        execute_all(set_iptables(net_env, None, &port, &device_mac))?;
-    }
+    let listeners = listener_ports(config.listeners.as_deref().unwrap_or_default());
+    match config
+        .redirect_backend
+        .unwrap_or(RawRedirectBackend::Iptables)
+    {
+        RawRedirectBackend::Iptables => {
+            execute_all(set_iptables(
+                net_env,
+                proxy_ports,
+                exempt,
+                &port,
+                &listeners,
+                &device_mac,
+            ))?;
+
+            if dns {
+                let tcp = dns_over_tcp(proxy_ports);
+                execute_all(set_iptables_dns(net_env, &port, tcp))?;
+            }
 
-    if safe {
-        execute_all(set_iptables_safe(net_env, &device_mac))?;
+            if safe {
+                execute_all(set_iptables_safe(net_env, &device_mac))?;
+            }
+        }
+        RawRedirectBackend::Nftables => {
+            let cmds = set_nftables(
+                net_env,
+                proxy_ports,
+                exempt,
+                &port,
+                &listeners,
+                safe,
+                dns.then(|| dns_over_tcp(proxy_ports)),
+            );
+            execute_all(
+                cmds.iter()
+                    .map(|cmd| nft(Some(&net_env.netns), cmd))
+                    .collect(),
+            )?;
+            let cmds = set_nftables_host(net_env, &device_mac);
+            execute_all(cmds.iter().map(|cmd| nft(None, cmd)).collect())?;
+        }
+        RawRedirectBackend::Ebpf => {
+            let ports = proxy_ports.into_iter().chain(
+                listeners
+                    .iter()
+                    .map(|(proxy_ports, _)| proxy_ports.as_str()),
+            );
+            let ports = ip_rule_ports(ports);
+            execute_all(set_ebtables(net_env, &device_mac))?;
+            execute_all(port_rules(net_env, &ports, ROUTE_TABLE))?;
+            if let Some(interface) = exempt.interface {
+                execute_all(interface_rules(net_env, interface, &ports))?;
+            }
+            // the rules added later are looked up first, the exempt traffic is routed as usual
+            let excluded = ip_rule_ports(exempt.ports);
+            execute_all(port_rules(net_env, &excluded, "main"))?;
+            execute_all(network_rules(net_env, exempt))?;
+        }
     }
     let _ = execute(bash_c(restore_dns));
 
@@ -47,5 +110,147 @@ pub async fn set_net(
     Ok(())
 }
 
+/// reset_net redirects the ports again in the network set by [set_net], without tearing down the
+/// bridge, so that the connections already accepted by the proxy are kept.
+#[cfg(target_os = "linux")]
+pub fn reset_net(net_env: &NetEnv, config: &ProxyRawConfig) -> anyhow::Result<()> {
+    let proxy_ports = config.proxy_ports.as_deref();
+    let exempt = Exempt::of(net_env, config);
+    let safe = config.safe_mode;
+    let port = config.listen_port.to_string();
+    let listeners = listener_ports(config.listeners.as_deref().unwrap_or_default());
+    let dns = config.dns.is_some().then(|| dns_over_tcp(proxy_ports));
+    match config
+        .redirect_backend
+        .unwrap_or(RawRedirectBackend::Iptables)
+    {
+        RawRedirectBackend::Iptables => execute_all(reset_iptables(
+            net_env,
+            proxy_ports,
+            exempt,
+            &port,
+            &listeners,
+            safe,
+            dns,
+        )),
+        RawRedirectBackend::Nftables => {
+            let cmds = reset_nftables(net_env, proxy_ports, exempt, &port, &listeners, safe, dns);
+            execute_all(
+                cmds.iter()
+                    .map(|cmd| nft(Some(&net_env.netns), cmd))
+                    .collect(),
+            )
+        }
+        // the ports are steered by the proxy, changing them restarts it
+        RawRedirectBackend::Ebpf => Ok(()),
+    }
+}
+
+/// port_rules are the ip rules looking the TCP segments from and to the ports up in the table. The
+/// ones of the route table deliver them locally, the connections are steered to the listeners by
+/// the sk_lookup program of the proxy, and the segments of the upstreams are delivered to its
+/// transparent sockets.
+fn port_rules<'a>(net_env: &'a NetEnv, ports: &'a [String], table: &'a str) -> Vec<Vec<&'a str>> {
+    let mut families = vec!["-4"];
+    if net_env.ipv6.is_some() {
+        families.push("-6");
+    }
+    let mut cmds = vec![];
+    for family in families {
+        for port in ports {
+            for direction in ["dport", "sport"] {
+                cmds.push(ip_netns(
+                    &net_env.netns,
+                    vec![
+                        "ip", family, "rule", "add", "ipproto", "tcp", direction, port, "lookup",
+                        table,
+                    ],
+                ));
+            }
+        }
+    }
+    cmds
+}
+
+/// interface_rules are the ip rules routing the connections to the ports arriving on the interface
+/// as usual, i.e. the ones of the direction not diverted. The segments from the ports are still
+/// delivered to the proxy.
+fn interface_rules<'a>(
+    net_env: &'a NetEnv,
+    interface: &'a str,
+    ports: &'a [String],
+) -> Vec<Vec<&'a str>> {
+    let mut families = vec!["-4"];
+    if net_env.ipv6.is_some() {
+        families.push("-6");
+    }
+    let mut cmds = vec![];
+    for family in families {
+        for port in ports {
+            cmds.push(ip_netns(
+                &net_env.netns,
+                vec![
+                    "ip", family, "rule", "add", "iif", interface, "ipproto", "tcp", "dport", port,
+                    "lookup", "main",
+                ],
+            ));
+        }
+    }
+    cmds
+}
+
+/// network_rules are the ip rules routing the traffic of the exempt networks as usual, in both
+/// directions.
+fn network_rules<'a>(net_env: &'a NetEnv, exempt: Exempt<'a>) -> Vec<Vec<&'a str>> {
+    let mut cmds = vec![];
+    for network in exempt.destinations.iter().chain(exempt.sources) {
+        let family = match network.parse::<IpNetwork>() {
+            Ok(IpNetwork::V4(_)) => "-4",
+            Ok(IpNetwork::V6(_)) if net_env.ipv6.is_some() => "-6",
+            _ => continue,
+        };
+        for direction in ["to", "from"] {
+            cmds.push(ip_netns(
+                &net_env.netns,
+                vec![
+                    "ip", family, "rule", "add", direction, network, "lookup", "main",
+                ],
+            ));
+        }
+    }
+    cmds
+}
+
+/// ip_rule_ports converts the ports of multiport, e.g. `80,8000:8080`, to the ones of ip rules.
+fn ip_rule_ports<'a>(ports: impl IntoIterator<Item = &'a str>) -> Vec<String> {
+    ports
+        .into_iter()
+        .flat_map(|ports| ports.split(','))
+        .map(|port| port.replace(':', "-"))
+        .collect()
+}
+
+/// listener_ports returns the `(proxy_ports, listen_port)` of each listener.
+fn listener_ports(listeners: &[RawListener]) -> Vec<(String, String)> {
+    listeners
+        .iter()
+        .map(|listener| {
+            (
+                listener.proxy_ports.clone(),
+                listener.listen_port.to_string(),
+            )
+        })
+        .collect()
+}
+
+/// dns_over_tcp tells whether the DNS queries over TCP should be redirected apart, i.e. the
+/// `proxy_ports` don't contain 53.
+fn dns_over_tcp(proxy_ports: Option<&str>) -> bool {
+    proxy_ports
+        .and_then(|ports| parse_port_ranges(ports).ok())
+        .map(|ranges| ranges.iter().all(|range| !range.contains(&53)))
+        .unwrap_or(false)
+}
+
 #[cfg(target_os = "windows")]
 pub fn set_env() {}
@@ -0,0 +1,313 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Context, Result};
+use once_cell::sync::{Lazy, OnceCell};
+use serde::{Deserialize, Serialize};
+
+use crate::cmd::daemon::handler::is_running;
+use crate::proxy::net::bridge::{clear_links, execute_all_with_log_error, set_gateway_arp, NetEnv};
+
+/// STATE_DIR is the directory of the state files by default.
+pub const STATE_DIR: &str = "/var/run";
+
+/// NetState is the network changed for the proxy. It is written to the state file before the
+/// network is changed and removed once it is restored, so that the network left by a controller
+/// killed by `SIGKILL` is restored by the `cleanup` subcommand or the next start.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NetState {
+    /// pid is the pid of the controller.
+    pub pid: u32,
+    /// proxy_pid is the pid of the sub proxy once it is spawned.
+    pub proxy_pid: Option<u32>,
+    pub netns: String,
+    pub bridge: String,
+    pub device: String,
+    pub ip: String,
+    pub ipv6: Option<String>,
+    /// routes are the routes of the host before the network is changed, as listed by `ip route`.
+    pub routes: Vec<String>,
+    pub routes_v6: Vec<String>,
+    /// mutations are the commands changing the network, in the order they are executed.
+    pub mutations: Vec<Vec<String>>,
+}
+
+/// STATE is the state of the network being set by this instance, written on each change.
+static STATE: Lazy<Mutex<Option<NetState>>> = Lazy::new(Default::default);
+
+static STATE_FILE: OnceCell<PathBuf> = OnceCell::new();
+
+/// set_state_file sets the path of the state file instead of the default one.
+pub fn set_state_file(path: PathBuf) {
+    let _ = STATE_FILE.set(path);
+}
+
+/// state_file returns the path of the state file, the default one is named after the inode of the
+/// current netns, so that the instances attached to different netns keep their own state.
+pub fn state_file() -> PathBuf {
+    if let Some(path) = STATE_FILE.get() {
+        return path.clone();
+    }
+    match fs::metadata("/proc/self/ns/net") {
+        Ok(netns) => Path::new(STATE_DIR).join(format!("chaos-tproxy-{}.state", netns.ino())),
+        Err(_) => Path::new(STATE_DIR).join("chaos-tproxy.state"),
+    }
+}
+
+/// begin writes the state of the network of `net_env` before it is set, the commands executed
+/// afterwards are recorded into it until [finish]. It fails if the network is set by another
+/// running instance.
+pub fn begin(net_env: &NetEnv) -> Result<()> {
+    let path = state_file();
+    if let Some(state) = load(&path)? {
+        if state.pid != std::process::id() && is_running(state.pid as i32) {
+            return Err(anyhow!(
+                "the network is set by the running instance {}, state file {}",
+                state.pid,
+                path.display()
+            ));
+        }
+    }
+    let state = NetState {
+        pid: std::process::id(),
+        proxy_pid: None,
+        netns: net_env.netns.clone(),
+        bridge: net_env.bridge().to_string(),
+        device: net_env.device.clone(),
+        ip: net_env.ip.clone(),
+        ipv6: net_env.ipv6.clone(),
+        routes: list_routes("-4")?,
+        routes_v6: match net_env.ipv6 {
+            Some(_) => list_routes("-6")?,
+            None => vec![],
+        },
+        mutations: vec![],
+    };
+    write(&path, &state)?;
+    *STATE.lock().unwrap() = Some(state);
+    Ok(())
+}
+
+/// record appends the command to the state if the network is being set, it is called by
+/// [execute](crate::proxy::net::bridge::execute) before the command runs.
+pub fn record(cmdv: &[&str]) {
+    update(|state| {
+        state
+            .mutations
+            .push(cmdv.iter().map(ToString::to_string).collect())
+    });
+}
+
+/// set_proxy_pid records the pid of the sub proxy, which is killed if it is still running when
+/// the network is restored.
+pub fn set_proxy_pid(pid: Option<u32>) {
+    update(|state| state.proxy_pid = pid);
+}
+
+/// finish removes the state file once the network is restored.
+pub fn finish() {
+    let state = match STATE.lock() {
+        Ok(mut state) => state.take(),
+        Err(_) => return,
+    };
+    if state.is_none() {
+        return;
+    }
+    let path = state_file();
+    match fs::remove_file(&path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            tracing::error!("fail to remove state file {}: {}", path.display(), e);
+        }
+        _ => {}
+    }
+}
+
+/// load reads the state file, it returns None if there is none.
+pub fn load(path: &Path) -> Result<Option<NetState>> {
+    match fs::read(path) {
+        Ok(state) => {
+            Ok(Some(serde_json::from_slice(&state).with_context(|| {
+                format!("invalid state file {}", path.display())
+            })?))
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// restore restores the network of the state left by an exited instance as
+/// [clear_bridge](NetEnv::clear_bridge) does, and removes the state file. The sub proxy is killed
+/// if it is still running.
+pub fn restore(path: &Path, state: &NetState) -> Result<()> {
+    tracing::info!(
+        "restoring the network changed by {} commands of the instance {}",
+        state.mutations.len(),
+        state.pid
+    );
+    if let Some(pid) = state.proxy_pid.filter(|&pid| is_proxy(pid)) {
+        unsafe {
+            libc::kill(pid as i32, libc::SIGKILL);
+        }
+    }
+    execute_all_with_log_error(clear_links(
+        &state.netns,
+        &state.bridge,
+        &state.device,
+        &state.ip,
+        state.ipv6.as_deref(),
+    ))?;
+    // the routes through the bridge are gone with it, the saved ones are put back
+    execute_all_with_log_error(route_cmds(state))?;
+    if let Err(e) = set_gateway_arp(&state.device) {
+        tracing::warn!("fail to set the arp entry of the gateway: {}", e);
+    }
+    fs::remove_file(path).with_context(|| format!("fail to remove state file {}", path.display()))
+}
+
+/// recover restores the network left by an instance exited without restoring it, e.g. killed by
+/// `SIGKILL`, before the network of this one is set up. The one of a running instance is kept.
+pub fn recover() {
+    let path = state_file();
+    let result = match load(&path) {
+        Ok(None) => return,
+        Ok(Some(state)) if is_running(state.pid as i32) => {
+            tracing::warn!(
+                "the network of the running instance {} is kept, state file {}",
+                state.pid,
+                path.display()
+            );
+            return;
+        }
+        Ok(Some(state)) => {
+            tracing::warn!("the network is left by the instance {}", state.pid);
+            restore(&path, &state)
+        }
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        tracing::error!("fail to restore the network of the state file: {}", e);
+    }
+}
+
+/// write replaces the state file by renaming, so that it is never left partially written.
+fn write(path: &Path, state: &NetState) -> Result<()> {
+    let temp = path.with_extension("tmp");
+    fs::write(&temp, serde_json::to_vec(state)?)
+        .with_context(|| format!("fail to write state file {}", temp.display()))?;
+    fs::rename(&temp, path).with_context(|| format!("fail to write state file {}", path.display()))
+}
+
+/// update changes the state being set and writes it.
+fn update(f: impl FnOnce(&mut NetState)) {
+    let mut state = match STATE.lock() {
+        Ok(state) => state,
+        Err(_) => return,
+    };
+    if let Some(state) = state.as_mut() {
+        f(state);
+        if let Err(e) = write(&state_file(), state) {
+            tracing::error!("{:?}", e);
+        }
+    }
+}
+
+/// list_routes lists the routes of the family, one per line in the syntax of `ip route`.
+fn list_routes(family: &str) -> Result<Vec<String>> {
+    let out = Command::new("ip")
+        .args([family, "route", "show"])
+        .output()
+        .context("fail to list routes")?;
+    Ok(String::from_utf8_lossy(&out.stdout)
+        .lines()
+        .map(str::trim)
+        .filter(|route| !route.is_empty())
+        .map(ToString::to_string)
+        .collect())
+}
+
+/// route_cmds returns the commands putting the saved routes back.
+fn route_cmds(state: &NetState) -> Vec<Vec<&str>> {
+    let routes = state.routes.iter().map(|route| ("-4", route));
+    let routes_v6 = state.routes_v6.iter().map(|route| ("-6", route));
+    routes
+        .chain(routes_v6)
+        .map(|(family, route)| {
+            let mut cmdv = vec!["ip", family, "route", "replace"];
+            cmdv.extend(route.split_whitespace());
+            cmdv
+        })
+        .collect()
+}
+
+/// is_proxy tells whether the process of the pid is a sub proxy, rather than another one reusing
+/// the pid.
+fn is_proxy(pid: u32) -> bool {
+    fs::read(format!("/proc/{}/cmdline", pid))
+        .map(|cmdline| cmdline.split(|&b| b == 0).any(|arg| arg == b"--proxy"))
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::proxy::net::state::{load, route_cmds, write, NetState};
+
+    #[test]
+    fn test_state_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("chaos-tproxy.state");
+        assert_eq!(load(&path).unwrap(), None);
+
+        let state = NetState {
+            pid: 1,
+            proxy_pid: Some(2),
+            netns: "0a1b2c3d-4e5fns".to_string(),
+            bridge: "0a1b2c3d-4e5fb1".to_string(),
+            device: "eth0".to_string(),
+            ip: "10.0.0.2/24".to_string(),
+            ipv6: None,
+            routes: vec![
+                "default via 10.0.0.1 dev eth0".to_string(),
+                "10.0.0.0/24 dev eth0 proto kernel scope link src 10.0.0.2".to_string(),
+            ],
+            routes_v6: vec![],
+            mutations: vec![vec![
+                "ip".to_string(),
+                "netns".to_string(),
+                "add".to_string(),
+                "0a1b2c3d-4e5fns".to_string(),
+            ]],
+        };
+        write(&path, &state).unwrap();
+        assert_eq!(load(&path).unwrap(), Some(state.clone()));
+        assert!(!path.with_extension("tmp").exists());
+
+        assert_eq!(
+            route_cmds(&state),
+            vec![
+                vec!["ip", "-4", "route", "replace", "default", "via", "10.0.0.1", "dev", "eth0"],
+                vec![
+                    "ip",
+                    "-4",
+                    "route",
+                    "replace",
+                    "10.0.0.0/24",
+                    "dev",
+                    "eth0",
+                    "proto",
+                    "kernel",
+                    "scope",
+                    "link",
+                    "src",
+                    "10.0.0.2"
+                ],
+            ]
+        );
+
+        std::fs::write(&path, "{").unwrap();
+        assert!(load(&path).is_err());
+    }
+}
@@ -0,0 +1,132 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+
+use chaos_tproxy_proxy::raw_config::RawConfig as ProxyRawConfig;
+use serde::Serialize;
+
+use crate::proxy::net::bridge::{FWMARK, ROUTE_TABLE};
+
+/// ConfigSummary is the effective config of the proxy in short, after the defaults are applied and
+/// the ports are allocated. It is logged on startup and served by `GET /config`, to audit the
+/// drifts of the config.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ConfigSummary {
+    pub proxy_ports: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exclude_ports: Option<String>,
+    /// explicit is the address of the forward proxy, the ports are not diverted if set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub explicit: Option<SocketAddr>,
+    pub listen_port: u16,
+    pub listeners: Vec<ListenerSummary>,
+    pub fwmark: &'static str,
+    pub route_table: &'static str,
+    /// rules is the number of rules of each target.
+    pub rules: BTreeMap<String, usize>,
+    /// subsystems are the optional features enabled, e.g. `tls` or `dns`.
+    pub subsystems: Vec<&'static str>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ListenerSummary {
+    pub proxy_ports: String,
+    pub listen_port: u16,
+    pub tls: bool,
+    pub workers: Option<usize>,
+    /// rules is the number of rules of the listener with its own rule set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rules: Option<usize>,
+}
+
+impl From<&ProxyRawConfig> for ConfigSummary {
+    fn from(config: &ProxyRawConfig) -> Self {
+        let mut rules = BTreeMap::new();
+        for rule in &config.rules {
+            *rules.entry(format!("{:?}", rule.target)).or_insert(0) += 1;
+        }
+        let subsystems = [
+            ("safe_mode", config.safe_mode),
+            ("compare_mode", config.compare_mode),
+            ("latency_compensation", config.latency_compensation),
+            ("opt_in", config.opt_in.is_some()),
+            ("role", config.role.is_some()),
+            ("tls", config.tls.is_some()),
+            ("slo", config.slo.is_some()),
+            ("baseline", config.baseline.is_some()),
+            ("metadata", config.metadata.is_some()),
+            ("coordination", config.coordination.is_some()),
+            ("report", config.report.is_some()),
+            ("snapshot", config.snapshot.is_some()),
+            ("connection_limits", config.connection_limits.is_some()),
+            ("doh", config.doh.is_some()),
+            ("dns", config.dns.is_some()),
+            ("proxy_protocol", config.proxy_protocol.is_some()),
+            ("validation", config.validation.is_some()),
+        ]
+        .iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(name, _)| *name)
+        .collect();
+        Self {
+            proxy_ports: config.proxy_ports.clone(),
+            exclude_ports: config.exclude_ports.clone(),
+            explicit: config.explicit.as_ref().map(|explicit| explicit.listen),
+            listen_port: config.listen_port,
+            listeners: config
+                .listeners
+                .iter()
+                .flatten()
+                .map(|listener| ListenerSummary {
+                    proxy_ports: listener.proxy_ports.clone(),
+                    listen_port: listener.listen_port,
+                    tls: listener.tls.is_some(),
+                    workers: listener.workers,
+                    rules: listener.rules.as_ref().map(Vec::len),
+                })
+                .collect(),
+            fwmark: FWMARK,
+            route_table: ROUTE_TABLE,
+            rules,
+            subsystems,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chaos_tproxy_proxy::raw_config::{RawConfig as ProxyRawConfig, RawRule};
+
+    use crate::proxy::summary::ConfigSummary;
+
+    #[test]
+    fn test_summary() {
+        let rule = |target: &str| -> RawRule {
+            serde_json::from_value(serde_json::json!({
+                "target": target,
+                "selector": {},
+                "actions": {},
+            }))
+            .unwrap()
+        };
+        let config = ProxyRawConfig {
+            proxy_ports: Some("80,443".to_string()),
+            listen_port: 1025,
+            compare_mode: true,
+            rules: vec![rule("Request"), rule("Response"), rule("Request")],
+            ..Default::default()
+        };
+        let summary = ConfigSummary::from(&config);
+        assert_eq!(
+            serde_json::to_value(&summary).unwrap(),
+            serde_json::json!({
+                "proxy_ports": "80,443",
+                "listen_port": 1025,
+                "listeners": [],
+                "fwmark": "1",
+                "route_table": "100",
+                "rules": {"Request": 2, "Response": 1},
+                "subsystems": ["compare_mode"],
+            })
+        );
+    }
+}
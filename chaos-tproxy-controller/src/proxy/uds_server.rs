@@ -1,17 +1,27 @@
 use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
 
 use tokio::io::AsyncWriteExt;
 use tokio::net::UnixListener;
 
 #[derive(Debug, Clone)]
 pub struct UdsDataServer<T> {
-    pub data: T,
+    pub data: Arc<RwLock<T>>,
     pub path: PathBuf,
 }
 
 impl<T: serde::ser::Serialize> UdsDataServer<T> {
     pub fn new(data: T, path: PathBuf) -> Self {
-        Self { data, path }
+        Self {
+            data: Arc::new(RwLock::new(data)),
+            path,
+        }
+    }
+
+    /// update replaces the data served to the connections accepted from now on, the clones of
+    /// this server share the data.
+    pub fn update(&self, data: T) {
+        *self.data.write().unwrap() = data;
     }
 
     pub fn bind(&self) -> anyhow::Result<UnixListener> {
@@ -30,7 +40,7 @@ impl<T: serde::ser::Serialize> UdsDataServer<T> {
         loop {
             match (listener).accept().await {
                 Ok((mut stream, addr)) => {
-                    let buf = serde_json::to_vec(&self.data)?;
+                    let buf = serde_json::to_vec(&*self.data.read().unwrap())?;
                     tokio::spawn(async move {
                         match stream.write_all(buf.as_slice()).await {
                             Ok(_) => {
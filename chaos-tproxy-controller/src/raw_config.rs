@@ -1,14 +1,115 @@
-use chaos_tproxy_proxy::raw_config::{RawRule, TLSRawConfig};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use chaos_tproxy_proxy::raw_config::{
+    RawAccessLogConfig, RawBaselineConfig, RawBodyOverflow, RawConcurrencyConfig,
+    RawConnectionLimit, RawCoordinationConfig, RawDirection, RawDnsConfig, RawDoHConfig,
+    RawExperimentReportConfig, RawExplicitConfig, RawHarConfig, RawMatchPolicy, RawMetadataSource,
+    RawOptIn, RawPcapConfig, RawProxyProtocol, RawRedirectBackend, RawRule, RawRuntimeConfig,
+    RawSnapshotConfig, RawTelemetryConfig, RawUpstreamPool, RawValidationConfig, SLORawConfig,
+    TLSRawConfig,
+};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+/// CONFIG_VERSION is the latest version of the config schema, the configs without a version are
+/// of version 1.
+pub const CONFIG_VERSION: u64 = 1;
+
+/// Migration upgrades a config document of a version to the next one, e.g. renames its fields.
+type Migration = fn(&mut Map<String, Value>) -> Result<()>;
 
-#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize, Default)]
+/// MIGRATIONS upgrade the documents of version `i + 1` to version `i + 2` by the `i`th one.
+const MIGRATIONS: [Migration; CONFIG_VERSION as usize - 1] = [];
+
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize, Default)]
 #[serde(deny_unknown_fields)] // To prevent typos.
 pub struct RawConfig {
-    pub proxy_ports: Option<Vec<u16>>,
+    // version of the schema, 1 by default, the configs of the earlier versions are migrated
+    #[serde(alias = "apiVersion")]
+    pub version: Option<u64>,
+    pub proxy_ports: Option<Vec<RawPortRange>>,
+    // ports never diverted even if they are in the `proxy_ports`, or all ports are diverted
+    pub exclude_ports: Option<Vec<RawPortRange>>,
+    // networks in CIDR never diverted even if the ports are, e.g. the kube-apiserver or the
+    // node-local DNS
+    pub ignore_destinations: Option<Vec<String>>,
+    pub ignore_sources: Option<Vec<String>>,
+    // interfaces the traffic is diverted on, the first one up with addresses is bridged to the
+    // proxy, e.g. `eth0` but not the bridge of the CNI; the default interface by default
+    pub interfaces: Option<Vec<String>>,
+    // `inbound`, `outbound` or `both` (by default), the connections to the ports of the target or
+    // the ones of the target to the remote ports
+    pub direction: Option<RawDirection>,
     pub safe_mode: Option<bool>,
+    pub compare_mode: Option<bool>,
+    pub latency_compensation: Option<bool>,
+    // stamp the exchanges modified by the rules with the `x-chaos-tproxy-rule` and
+    // `x-chaos-tproxy-faults` headers, false by default
+    pub fault_markers: Option<bool>,
+    // parse every connection as HTTP, false by default: the connections to the ports no HTTP rule
+    // selects are relayed as raw TCP, which costs far less CPU
+    pub inspect_all: Option<bool>,
+    pub opt_in: Option<RawOptIn>,
+    // stamp the forwarded requests and the responses with the ID of their exchange in the header
+    pub exchange_id_header: Option<String>,
     pub rules: Option<Vec<RawRule>>,
+    pub match_policy: Option<RawMatchPolicy>,
     pub tls: Option<TLSRawConfig>,
     pub role: Option<RawRole>,
+    pub slo: Option<SLORawConfig>,
+    pub baseline: Option<RawBaselineConfig>,
+    pub metadata: Option<RawMetadataSource>,
+    pub coordination: Option<RawCoordinationConfig>,
+    pub snapshot: Option<RawSnapshotConfig>,
+    pub connection_limits: Option<Vec<RawConnectionLimit>>,
+    pub doh: Option<RawDoHConfig>,
+    pub dns: Option<RawDnsConfig>,
+    pub listeners: Option<Vec<RawListenerConfig>>,
+    pub proxy_protocol: Option<RawProxyProtocol>,
+    pub validation: Option<RawValidationConfig>,
+    // how long the exchanges in flight are let finish on shutdown, 10s by default
+    #[serde(default)]
+    #[serde(with = "chaos_tproxy_proxy::duration")]
+    pub drain_timeout: Option<Duration>,
+    // a json record of every exchange, with the rules matched and applied
+    pub access_log: Option<RawAccessLogConfig>,
+    // capture the exchanges to a HAR file, written on shutdown
+    pub har: Option<RawHarConfig>,
+    // a json summary of the experiment written on shutdown, with the hits, aborts and delays of
+    // each rule
+    pub experiment_report: Option<RawExperimentReportConfig>,
+    // export a span of every exchange over OTLP
+    pub telemetry: Option<RawTelemetryConfig>,
+    // capture the connections to pcap files
+    pub pcap: Option<RawPcapConfig>,
+    // logs of the controller and the proxy, read on start
+    pub log: Option<RawLogConfig>,
+    // `iptables` (by default) or `nftables`, the tool the traffic is diverted with
+    pub redirect_backend: Option<RawRedirectBackend>,
+    // serve as a plain forward proxy on the address without the netns and the diverting rules,
+    // e.g. `listen: 127.0.0.1:8080` and `protocol: socks5`, neither root nor `NET_ADMIN` is
+    // required
+    pub explicit: Option<RawExplicitConfig>,
+    // keep-alive of the upstream connections, e.g. `idle_timeout: 30s` and `max_idle_per_host: 8`
+    pub upstream_pool: Option<RawUpstreamPool>,
+    // bodies buffered by the proxy, e.g. to be rewritten, mirrored or validated, are capped at the
+    // size in bytes, unlimited by default
+    pub max_body_size: Option<usize>,
+    // `pass_through` (by default), `reject` or `truncate`, how a body over max_body_size is handled
+    pub body_overflow: Option<RawBodyOverflow>,
+    // worker threads and socket options of the proxy, e.g. `worker_threads: 4`,
+    // `accept_shards: 4` and `recv_buffer: 262144`, changing them restarts the proxy
+    pub runtime: Option<RawRuntimeConfig>,
+    // caps on the client connections and the requests in flight of the proxy, e.g.
+    // `max_connections: 10000`, `max_requests: 2000` and `excess: reject`
+    pub concurrency: Option<RawConcurrencyConfig>,
+    // rule files appended to the rules in order, a file, a directory or a pattern with wildcards
+    // in the file name, relative to the config file, e.g. `rules/*.yaml`
+    pub include: Option<Vec<String>>,
 
     // Useless options now. TODO: complete them
     pub interface: Option<String>,
@@ -19,8 +120,278 @@ pub struct RawConfig {
     pub route_table: Option<u8>,
 }
 
+/// RawListenerConfig opens another listener for some of the ports, with its own TLS config,
+/// workers and rules.
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RawListenerConfig {
+    pub proxy_ports: Vec<RawPortRange>,
+    pub tls: Option<TLSRawConfig>,
+    pub workers: Option<usize>,
+    // rules of the ports of this listener instead of the top-level ones
+    pub rules: Option<Vec<RawRule>>,
+}
+
+/// RawPortRange is a port, e.g. `80`, or a range of ports, e.g. `"8000-8999"`.
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum RawPortRange {
+    Port(u16),
+    Range(String),
+}
+
+impl From<u16> for RawPortRange {
+    fn from(port: u16) -> Self {
+        RawPortRange::Port(port)
+    }
+}
+
+/// RawLogConfig configures the logs instead of `RUST_LOG` and stderr, the flags of the command line
+/// take precedence.
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RawLogConfig {
+    // level of the logs, `error` by default
+    pub level: Option<String>,
+    // levels of the modules, e.g. `hyper: warn`
+    pub modules: Option<BTreeMap<String, String>>,
+    // file the logs are appended to, stderr by default
+    pub path: Option<PathBuf>,
+    // `pretty` by default
+    pub format: Option<RawLogFormat>,
+    // rotation of the file, never rotated by default
+    pub rotation: Option<RawLogRotation>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RawLogFormat {
+    Pretty,
+    Json,
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RawLogRotation {
+    // the file is rotated once it would exceed the bytes
+    pub max_bytes: Option<u64>,
+    // the file is rotated once it is opened for the interval
+    #[serde(default)]
+    #[serde(with = "chaos_tproxy_proxy::duration")]
+    pub interval: Option<Duration>,
+    // rotated files kept, e.g. `chaos-tproxy.log.1`, 5 by default
+    pub keep: Option<usize>,
+}
+
 #[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
 pub enum RawRole {
     Client,
     Server,
-}
\ No newline at end of file
+}
+
+/// parse_document parses a config document (a config or a rule file) of any supported version of
+/// the schema, it is migrated to the latest version first.
+pub fn parse_document<T: DeserializeOwned>(document: Value) -> Result<T> {
+    let document = match document["kind"] == HTTP_CHAOS {
+        true => from_http_chaos(document)?,
+        false => document,
+    };
+    Ok(serde_json::from_value(migrate(document, &MIGRATIONS)?)?)
+}
+
+/// HTTP_CHAOS is the kind of the HTTPChaos resources of Chaos Mesh, which are accepted as configs.
+const HTTP_CHAOS: &str = "HTTPChaos";
+
+/// The fields of the HTTPChaos spec choosing the pods and the time of the chaos, they are left to
+/// the operator.
+const HTTP_CHAOS_SCHEDULING: [&str; 5] = ["selector", "mode", "value", "duration", "remoteCluster"];
+
+/// from_http_chaos converts an HTTPChaos resource into a config of the latest version, with a
+/// rule named after the resource, diverting the port of its spec.
+fn from_http_chaos(document: Value) -> Result<Value> {
+    let name = document.pointer("/metadata/name").cloned();
+    let spec = document
+        .get("spec")
+        .and_then(Value::as_object)
+        .ok_or_else(|| anyhow!("spec of {} is required", HTTP_CHAOS))?;
+    let port = spec
+        .get("port")
+        .and_then(Value::as_u64)
+        .ok_or_else(|| anyhow!("port of {} is required", HTTP_CHAOS))?;
+    let (mut selector, mut actions) = (Map::new(), Map::new());
+    selector.insert("port".to_string(), port.into());
+    for (field, value) in spec {
+        match field.as_str() {
+            "target" | "port" => {}
+            "path" | "method" | "code" | "request_headers" | "response_headers" => {
+                selector.insert(field.clone(), value.clone());
+            }
+            "abort" | "delay" => {
+                actions.insert(field.clone(), value.clone());
+            }
+            // the body of the resource is base64 encoded
+            "replace" => {
+                let mut replace = value.clone();
+                if let Some(body) = replace.get_mut("body") {
+                    *body = json!({"contents": {"type": "BASE64", "value": body.take()}});
+                }
+                actions.insert(field.clone(), replace);
+            }
+            "patch" => {
+                let mut patch = value.clone();
+                if let Some(body) = patch.get_mut("body") {
+                    *body = json!({"contents": body.take()});
+                }
+                actions.insert(field.clone(), patch);
+            }
+            "tls" => {
+                return Err(anyhow!(
+                "tls of {} refers to the secrets of Kubernetes, set the tls of the config instead",
+                HTTP_CHAOS
+            ))
+            }
+            field if HTTP_CHAOS_SCHEDULING.contains(&field) => {}
+            field => return Err(anyhow!("unknown field {} of {}", field, HTTP_CHAOS)),
+        }
+    }
+    Ok(json!({
+        "version": CONFIG_VERSION,
+        "proxy_ports": [port],
+        "rules": [{
+            "name": name,
+            "target": spec.get("target"),
+            "selector": selector,
+            "actions": actions,
+        }],
+    }))
+}
+
+/// migrate upgrades the document by the migrations from its version, and stamps it with the
+/// latest version.
+fn migrate(mut document: Value, migrations: &[Migration]) -> Result<Value> {
+    let latest = migrations.len() as u64 + 1;
+    let map = document
+        .as_object_mut()
+        .ok_or_else(|| anyhow!("config must be a map"))?;
+    let version = match map.remove("apiVersion").or_else(|| map.remove("version")) {
+        None | Some(Value::Null) => 1,
+        Some(Value::Number(version)) => version.as_u64().unwrap_or(0),
+        // e.g. `v1`
+        Some(Value::String(version)) => version.trim_start_matches('v').parse().unwrap_or(0),
+        Some(version) => return Err(anyhow!("invalid config version {}", version)),
+    };
+    if !(1..=latest).contains(&version) {
+        return Err(anyhow!(
+            "unsupported config version {}, the latest is {}",
+            version,
+            latest
+        ));
+    }
+    for migration in &migrations[version as usize - 1..] {
+        migration(map)?;
+    }
+    map.insert("version".to_string(), latest.into());
+    Ok(document)
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use serde_json::{json, Map, Value};
+
+    use crate::raw_config::{migrate, parse_document, RawConfig, RawPortRange, CONFIG_VERSION};
+
+    #[test]
+    fn test_migrate() {
+        let config: RawConfig = parse_document(json!({"proxy_ports": [80]})).unwrap();
+        assert_eq!(config.version, Some(CONFIG_VERSION));
+        let config: RawConfig =
+            parse_document(json!({"apiVersion": "v1", "proxy_ports": [80]})).unwrap();
+        assert_eq!(config.version, Some(CONFIG_VERSION));
+        let error = parse_document::<RawConfig>(json!({"version": 99})).unwrap_err();
+        assert!(error.to_string().contains("unsupported config version 99"));
+        assert!(parse_document::<RawConfig>(json!({"version": "next"})).is_err());
+
+        // e.g. a version 2 renaming `safe` to `safe_mode`
+        fn rename_safe(map: &mut Map<String, Value>) -> Result<()> {
+            if let Some(safe) = map.remove("safe") {
+                map.insert("safe_mode".to_string(), safe);
+            }
+            Ok(())
+        }
+        let migrations = [rename_safe as fn(&mut Map<String, Value>) -> Result<()>];
+        assert_eq!(
+            migrate(json!({"safe": true}), &migrations).unwrap(),
+            json!({"safe_mode": true, "version": 2})
+        );
+        // the documents of the latest version are not migrated again
+        assert_eq!(
+            migrate(json!({"version": 2, "safe": true}), &migrations).unwrap(),
+            json!({"safe": true, "version": 2})
+        );
+    }
+
+    #[test]
+    fn test_http_chaos() {
+        let document: Value = serde_yaml::from_str(
+            r#"
+apiVersion: chaos-mesh.org/v1alpha1
+kind: HTTPChaos
+metadata:
+  name: slow-api
+  namespace: default
+spec:
+  mode: all
+  selector:
+    labelSelectors:
+      app: api
+  target: Request
+  port: 8080
+  path: /api/*
+  method: GET
+  request_headers:
+    x-test: "1"
+  delay: 10s
+  replace:
+    code: 503
+    body: aGVsbG8=
+  patch:
+    headers: [["x-chaos", "1"]]
+    body:
+      type: JSON
+      value: '{"foo": "bar"}'
+  duration: 5m
+"#,
+        )
+        .unwrap();
+        let config: RawConfig = parse_document(document.clone()).unwrap();
+        assert_eq!(config.version, Some(CONFIG_VERSION));
+        assert_eq!(config.proxy_ports, Some(vec![RawPortRange::Port(8080)]));
+        let rules = config.rules.unwrap();
+        assert_eq!(rules.len(), 1);
+        let rule = serde_json::to_value(&rules[0]).unwrap();
+        assert_eq!(rule["name"], "slow-api");
+        assert_eq!(rule["target"], "Request");
+        assert_eq!(rule["selector"]["port"], 8080);
+        assert_eq!(rule["selector"]["path"], "/api/*");
+        assert_eq!(rule["selector"]["request_headers"]["x-test"], "1");
+        assert_eq!(
+            rule["actions"]["replace"]["body"]["contents"],
+            json!({"type": "BASE64", "value": "aGVsbG8="})
+        );
+        assert_eq!(
+            rule["actions"]["patch"]["headers"],
+            json!([["x-chaos", "1"]])
+        );
+        assert_eq!(rule["actions"]["patch"]["body"]["contents"]["type"], "JSON");
+        assert!(rules[0].actions.delay.is_some());
+
+        let mut tls = document.clone();
+        tls["spec"]["tls"] = json!({"secretName": "api-tls", "secretNamespace": "default"});
+        assert!(parse_document::<RawConfig>(tls).is_err());
+        let mut typo = document;
+        typo["spec"]["pth"] = json!("/api");
+        let error = parse_document::<RawConfig>(typo).unwrap_err();
+        assert!(error.to_string().contains("unknown field pth"));
+    }
+}
@@ -0,0 +1,286 @@
+use std::fs::OpenOptions;
+use std::io::{LineWriter, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
+
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use hyper::{Body, Request, Response};
+use serde::Serialize;
+
+use crate::handler::http::action::Actions;
+use crate::handler::http::exchange::ExchangeId;
+use crate::handler::http::rule::Rule;
+use crate::handler::http::tap::tap;
+use crate::snapshot::unix_millis;
+
+/// AccessLogConfig makes the proxy write a json record of every exchange, one per line.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccessLogConfig {
+    /// path of the file the records are appended to, they are written to stdout if none.
+    pub path: Option<PathBuf>,
+}
+
+/// AccessRecord is the record of an exchange.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AccessRecord {
+    pub timestamp_ms: u64,
+    /// exchange is the ID of the exchange in the logs of the proxy.
+    pub exchange: String,
+    pub client: SocketAddr,
+    pub target: SocketAddr,
+    pub method: String,
+    pub path: String,
+    /// status is none if the exchange failed without a response, e.g. aborted.
+    pub status: Option<u16>,
+    pub error: Option<String>,
+    /// headers_ms is the time until the response headers are sent.
+    pub headers_ms: f64,
+    /// total_ms is the time until the response body is sent.
+    pub total_ms: f64,
+    /// request_bytes are the bytes of the request body read from the client.
+    pub request_bytes: u64,
+    /// response_bytes are the bytes of the response body sent to the client.
+    pub response_bytes: u64,
+    /// matched are the rules whose selectors matched the exchange.
+    pub matched: Vec<String>,
+    /// applied are the rules whose actions were applied, under the match policy.
+    pub applied: Vec<String>,
+}
+
+/// Attribution collects the rules of an exchange, by their names or `#<index>` if unnamed.
+#[derive(Debug, Clone, Default)]
+pub struct Attribution {
+    pub matched: Vec<String>,
+    pub applied: Vec<String>,
+    /// faults are the actions of the rules applied, e.g. `delay=1s`.
+    pub faults: Vec<String>,
+}
+
+impl Attribution {
+    pub fn matched(&mut self, rules: &[(usize, &Rule)]) {
+        self.matched
+            .extend(rules.iter().map(|(index, rule)| rule.label(*index)));
+    }
+
+    /// applied records the rule applied with the actions sampled for the exchange.
+    pub fn applied(&mut self, index: usize, rule: &Rule, actions: &Actions) {
+        self.applied.push(rule.label(index));
+        self.faults.extend(actions.summary());
+    }
+
+    /// faulted tells whether any rule is applied to the exchange.
+    pub fn faulted(&self) -> bool {
+        !self.applied.is_empty()
+    }
+}
+
+/// AccessLog writes the records of the exchanges, shared by all the connections of the server.
+pub struct AccessLog {
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+impl AccessLog {
+    pub fn open(config: &AccessLogConfig) -> Result<Self> {
+        let writer: Box<dyn Write + Send> = match &config.path {
+            None => Box::new(std::io::stdout()),
+            Some(path) => Box::new(LineWriter::new(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .map_err(|e| anyhow!("fail to open access log {}: {}", path.display(), e))?,
+            )),
+        };
+        Ok(Self {
+            writer: Mutex::new(writer),
+        })
+    }
+
+    pub fn write(&self, record: &AccessRecord) {
+        let mut line = match serde_json::to_vec(record) {
+            Ok(line) => line,
+            Err(e) => {
+                tracing::warn!("fail to serialize access record: {}", e);
+                return;
+            }
+        };
+        line.push(b'\n');
+        // a line is written at once, so that the records of the connections never interleave
+        if let Err(e) = self.writer.lock().unwrap().write_all(&line) {
+            tracing::warn!("fail to write access log: {}", e);
+        }
+    }
+
+    /// start begins the record of an exchange, counting the bytes of the request body.
+    pub fn start(
+        self: &Arc<Self>,
+        request: Request<Body>,
+        client: SocketAddr,
+        target: SocketAddr,
+    ) -> (Request<Body>, Pending) {
+        let request_bytes = Arc::new(AtomicU64::new(0));
+        let (parts, body) = request.into_parts();
+        let counter = request_bytes.clone();
+        let body = tap(body, move |chunk| count(&counter, chunk), ());
+        let pending = Pending {
+            log: self.clone(),
+            started: Instant::now(),
+            request_bytes,
+            response_bytes: Default::default(),
+            record: AccessRecord {
+                timestamp_ms: unix_millis(SystemTime::now()),
+                exchange: ExchangeId::of(&parts.extensions).to_string(),
+                client,
+                target,
+                method: parts.method.to_string(),
+                path: parts.uri.path().to_string(),
+                status: None,
+                error: None,
+                headers_ms: 0.0,
+                total_ms: 0.0,
+                request_bytes: 0,
+                response_bytes: 0,
+                matched: vec![],
+                applied: vec![],
+            },
+        };
+        (Request::from_parts(parts, body), pending)
+    }
+}
+
+/// Pending is the record of an exchange in flight.
+pub struct Pending {
+    log: Arc<AccessLog>,
+    started: Instant,
+    request_bytes: Arc<AtomicU64>,
+    response_bytes: Arc<AtomicU64>,
+    record: AccessRecord,
+}
+
+impl Pending {
+    /// finish completes the record with the result of the exchange. The record is written once
+    /// the response body is sent, or dropped by the client.
+    pub fn finish(
+        mut self,
+        attribution: Attribution,
+        result: Result<Response<Body>>,
+    ) -> Result<Response<Body>> {
+        self.record.headers_ms = millis(self.started);
+        self.record.matched = attribution.matched;
+        self.record.applied = attribution.applied;
+        match result {
+            Ok(response) => {
+                self.record.status = Some(response.status().as_u16());
+                let (parts, body) = response.into_parts();
+                let counter = self.response_bytes.clone();
+                let body = tap(body, move |chunk| count(&counter, chunk), self);
+                Ok(Response::from_parts(parts, body))
+            }
+            Err(e) => {
+                self.record.error = Some(e.to_string());
+                Err(e)
+            }
+        }
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        self.record.total_ms = millis(self.started);
+        self.record.request_bytes = self.request_bytes.load(Ordering::Relaxed);
+        self.record.response_bytes = self.response_bytes.load(Ordering::Relaxed);
+        self.log.write(&self.record);
+    }
+}
+
+fn count(counter: &AtomicU64, chunk: &Bytes) {
+    counter.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+}
+
+fn millis(started: Instant) -> f64 {
+    started.elapsed().as_secs_f64() * 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+    use std::sync::Arc;
+
+    use anyhow::anyhow;
+    use hyper::{Body, Request, Response};
+
+    use crate::access_log::{AccessLog, AccessLogConfig, Attribution};
+    use crate::handler::http::exchange::ExchangeId;
+    use crate::handler::http::rule::Rule;
+    use crate::raw_config::RawRule;
+
+    #[tokio::test]
+    async fn test_access_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("access.log");
+        let log = Arc::new(
+            AccessLog::open(&AccessLogConfig {
+                path: Some(path.clone()),
+            })
+            .unwrap(),
+        );
+        let rule = |name: Option<&str>| -> Rule {
+            let raw: RawRule = serde_json::from_value(serde_json::json!({
+                "name": name,
+                "target": "Request",
+                "selector": {"path": "/api/*"},
+                "actions": {"abort": true},
+            }))
+            .unwrap();
+            raw.try_into().unwrap()
+        };
+        let (slow, unnamed) = (rule(Some("slow-api")), rule(None));
+        let client = "10.0.0.1:50000".parse().unwrap();
+        let target = "10.0.0.2:80".parse().unwrap();
+
+        let mut request = Request::post("/api/users")
+            .body(Body::from("hello"))
+            .unwrap();
+        request.extensions_mut().insert(ExchangeId(42));
+        let (request, pending) = log.start(request, client, target);
+        hyper::body::to_bytes(request.into_body()).await.unwrap();
+        let mut attribution = Attribution::default();
+        attribution.matched(&[(0, &slow), (1, &unnamed)]);
+        attribution.applied(0, &slow, &slow.actions);
+        let response = pending
+            .finish(attribution, Ok(Response::new(Body::from("hello world"))))
+            .unwrap();
+        // the record is written once the response body is sent
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "");
+        hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        let request = Request::get("/health").body(Body::empty()).unwrap();
+        let (_, pending) = log.start(request, client, target);
+        assert!(pending
+            .finish(Attribution::default(), Err(anyhow!("aborted")))
+            .is_err());
+
+        let records: Vec<serde_json::Value> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["exchange"], "000000000000002a");
+        assert_eq!(records[0]["method"], "POST");
+        assert_eq!(records[0]["path"], "/api/users");
+        assert_eq!(records[0]["client"], "10.0.0.1:50000");
+        assert_eq!(records[0]["status"], 200);
+        assert_eq!(records[0]["request_bytes"], 5);
+        assert_eq!(records[0]["response_bytes"], 11);
+        assert_eq!(records[0]["matched"], serde_json::json!(["slow-api", "#1"]));
+        assert_eq!(records[0]["applied"], serde_json::json!(["slow-api"]));
+        assert_eq!(records[1]["status"], serde_json::Value::Null);
+        assert_eq!(records[1]["error"], "aborted");
+        assert_eq!(records[1]["request_bytes"], 0);
+    }
+}
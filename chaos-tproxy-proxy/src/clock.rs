@@ -0,0 +1,98 @@
+use std::fmt::Debug;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::{Duration, Instant, SystemTime};
+
+use tokio::time::{self, sleep};
+
+/// Clock tells the time to the time-dependent actions, e.g. the delays, the patterns and the rate
+/// limits, so that they could be driven by a [MockClock] in tests.
+pub trait Clock: Debug + Send + Sync {
+    /// now returns the monotonic time, e.g. to refill the buckets of the rate limits.
+    fn now(&self) -> Instant;
+
+    /// system_time returns the wall-clock time, e.g. to tell the phases of the patterns.
+    fn system_time(&self) -> SystemTime;
+
+    /// sleep waits for the duration, e.g. the delays of the actions.
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send>>;
+}
+
+/// SystemClock is the clock of the proxy, the delays are slept by the timer of tokio.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        time::Instant::now().into_std()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        Box::pin(sleep(duration))
+    }
+}
+
+/// MockClock starts at a given wall-clock time, and only passes with the timer of tokio. With
+/// `tokio::time::pause`, the delays are skipped at once and `tokio::time::advance` moves the time
+/// of the patterns and the rate limits, which makes the tests fast and deterministic.
+#[derive(Debug, Clone)]
+pub struct MockClock {
+    epoch: SystemTime,
+    started: time::Instant,
+}
+
+impl MockClock {
+    /// new returns a clock telling `epoch` as the wall-clock time now, e.g. `started_at` of the
+    /// patterns.
+    pub fn new(epoch: SystemTime) -> Self {
+        Self {
+            epoch,
+            started: time::Instant::now(),
+        }
+    }
+
+    /// elapsed returns the time passed on the timer of tokio since the clock is created.
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        time::Instant::now().into_std()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        self.epoch + self.elapsed()
+    }
+
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        Box::pin(sleep(duration))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use crate::clock::{Clock, MockClock};
+
+    #[tokio::test(start_paused = true)]
+    async fn test_mock_clock() {
+        let clock = MockClock::new(UNIX_EPOCH);
+        let now = clock.now();
+        // an hour passes at once
+        clock.sleep(Duration::from_secs(3600)).await;
+        assert_eq!(clock.now() - now, Duration::from_secs(3600));
+        assert_eq!(
+            clock.system_time(),
+            SystemTime::UNIX_EPOCH + Duration::from_secs(3600)
+        );
+        tokio::time::advance(Duration::from_secs(60)).await;
+        assert_eq!(clock.elapsed(), Duration::from_secs(3660));
+    }
+}
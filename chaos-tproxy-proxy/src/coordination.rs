@@ -0,0 +1,308 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use http::uri::Authority;
+use http::{Method, Request, Response, StatusCode};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Client, Server};
+use serde::{Deserialize, Serialize};
+use tokio::time::sleep;
+
+use crate::timeline::{EventKind, Timeline};
+
+/// The cluster-wide counters are halved on every tick, so that the cap follows the recent
+/// traffic.
+const DECAY: f64 = 0.5;
+
+/// CoordinationConfig makes multiple proxies (one per node) act as one experiment.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CoordinationConfig {
+    pub role: CoordinationRole,
+    /// how often the followers sync with the leader.
+    pub interval: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CoordinationRole {
+    /// Leader serves the followers, and caps the cluster-wide percent of faulted exchanges.
+    Leader {
+        listen: SocketAddr,
+        max_faulted_percent: Option<f64>,
+    },
+    /// Follower syncs its counters and the shared state with the leader.
+    Follower { leader: Authority },
+}
+
+/// Report is sent by the followers, counting the exchanges since the last report.
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub struct Report {
+    pub requests: u64,
+    pub faulted: u64,
+}
+
+/// SyncState is the state shared by the leader.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub struct SyncState {
+    /// Unix timestamp in milliseconds, at which the periods of the patterns start.
+    pub epoch_ms: u64,
+    /// Whether the faults could be injected now.
+    pub allowed: bool,
+}
+
+#[derive(Debug, Default)]
+struct ClusterStats {
+    requests: f64,
+    faulted: f64,
+}
+
+/// Coordinator holds the state shared by the coordinated instances.
+#[derive(Debug)]
+pub struct Coordinator {
+    config: CoordinationConfig,
+    epoch_ms: AtomicU64,
+    allowed: AtomicBool,
+    // local counters since the last report
+    requests: AtomicU64,
+    faulted: AtomicU64,
+    // decayed counters of the cluster, only used by the leader
+    cluster: Mutex<ClusterStats>,
+    timeline: Arc<Timeline>,
+}
+
+impl Coordinator {
+    pub fn new(config: CoordinationConfig, timeline: Arc<Timeline>) -> Self {
+        let epoch_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        Self {
+            config,
+            epoch_ms: AtomicU64::new(epoch_ms),
+            allowed: AtomicBool::new(true),
+            requests: Default::default(),
+            faulted: Default::default(),
+            cluster: Default::default(),
+            timeline,
+        }
+    }
+
+    /// epoch returns the start of the periods of the patterns, shared by all the instances.
+    pub fn epoch(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.epoch_ms.load(Ordering::Relaxed))
+    }
+
+    /// is_allowed returns false if the cluster-wide cap of faulted exchanges is reached, or the
+    /// leader is unreachable.
+    pub fn is_allowed(&self) -> bool {
+        self.allowed.load(Ordering::Relaxed)
+    }
+
+    pub fn record(&self, faulted: bool) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        if faulted {
+            self.faulted.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn take_report(&self) -> Report {
+        Report {
+            requests: self.requests.swap(0, Ordering::Relaxed),
+            faulted: self.faulted.swap(0, Ordering::Relaxed),
+        }
+    }
+
+    fn state(&self) -> SyncState {
+        SyncState {
+            epoch_ms: self.epoch_ms.load(Ordering::Relaxed),
+            allowed: self.is_allowed(),
+        }
+    }
+
+    /// merge adds the report into the cluster counters and decides whether the faults are allowed,
+    /// only used by the leader.
+    fn merge(&self, report: Report, decay: bool) -> SyncState {
+        if let CoordinationRole::Leader {
+            max_faulted_percent: Some(max),
+            ..
+        } = &self.config.role
+        {
+            let mut cluster = self.cluster.lock().unwrap();
+            if decay {
+                cluster.requests *= DECAY;
+                cluster.faulted *= DECAY;
+            }
+            cluster.requests += report.requests as f64;
+            cluster.faulted += report.faulted as f64;
+            let percent = if cluster.requests > 0.0 {
+                cluster.faulted * 100.0 / cluster.requests
+            } else {
+                0.0
+            };
+            let reason = format!(
+                "faulted percent of the cluster is {:.2}, the cap is {}",
+                percent, max
+            );
+            self.set_allowed(percent < *max, reason);
+        }
+        self.state()
+    }
+
+    fn apply(&self, state: SyncState) {
+        self.epoch_ms.store(state.epoch_ms, Ordering::Relaxed);
+        let reason = if state.allowed {
+            "the leader allows the faults"
+        } else {
+            "the leader stops the faults"
+        };
+        self.set_allowed(state.allowed, reason.to_string());
+    }
+
+    /// set_allowed records the transitions in the timeline.
+    fn set_allowed(&self, allowed: bool, reason: String) {
+        if self.allowed.swap(allowed, Ordering::Relaxed) == allowed {
+            return;
+        }
+        self.timeline.record(if allowed {
+            EventKind::ThresholdRecovered { reason }
+        } else {
+            EventKind::ThresholdBreached { reason }
+        });
+    }
+
+    /// run would serve the followers (as leader) or sync with the leader (as follower) until the
+    /// future is dropped.
+    pub async fn run(self: Arc<Self>) -> Result<()> {
+        match self.config.role.clone() {
+            CoordinationRole::Leader { listen, .. } => {
+                let coordinator = self.clone();
+                tokio::spawn(async move {
+                    loop {
+                        sleep(coordinator.config.interval).await;
+                        let report = coordinator.take_report();
+                        coordinator.merge(report, true);
+                    }
+                });
+                self.serve(listen).await
+            }
+            CoordinationRole::Follower { leader } => loop {
+                sleep(self.config.interval).await;
+                let report = self.take_report();
+                match self.sync(&leader, report).await {
+                    Ok(state) => self.apply(state),
+                    Err(e) => {
+                        // stop injecting faults if the blast radius is unknown
+                        tracing::warn!("fail to sync with the leader {}: {}", leader, e);
+                        self.set_allowed(false, format!("leader {} is unreachable", leader));
+                    }
+                }
+            },
+        }
+    }
+
+    async fn serve(self: Arc<Self>, listen: SocketAddr) -> Result<()> {
+        let make_service = make_service_fn(move |_| {
+            let coordinator = self.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    coordinator.clone().handle(request)
+                }))
+            }
+        });
+        tracing::info!("Coordination leader listening on {}", listen);
+        Server::try_bind(&listen)?.serve(make_service).await?;
+        Ok(())
+    }
+
+    async fn handle(self: Arc<Self>, request: Request<Body>) -> Result<Response<Body>> {
+        if request.method() != Method::POST || request.uri().path() != "/sync" {
+            return Ok(Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::empty())?);
+        }
+        let body = hyper::body::to_bytes(request.into_body()).await?;
+        let report: Report = serde_json::from_slice(&body)?;
+        let state = self.merge(report, false);
+        Ok(Response::new(serde_json::to_vec(&state)?.into()))
+    }
+
+    async fn sync(&self, leader: &Authority, report: Report) -> Result<SyncState> {
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(format!("http://{}/sync", leader))
+            .body(serde_json::to_vec(&report)?.into())?;
+        let response = Client::new().request(request).await?;
+        if !response.status().is_success() {
+            return Err(anyhow!("unexpected status {}", response.status()));
+        }
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::coordination::{CoordinationConfig, CoordinationRole, Coordinator, Report};
+    use crate::timeline::{EventKind, Timeline};
+
+    #[test]
+    fn test_merge() {
+        let timeline = Arc::new(Timeline::default());
+        let leader = Coordinator::new(
+            CoordinationConfig {
+                role: CoordinationRole::Leader {
+                    listen: "127.0.0.1:0".parse().unwrap(),
+                    max_faulted_percent: Some(10.0),
+                },
+                interval: Duration::from_secs(1),
+            },
+            timeline.clone(),
+        );
+        let state = leader.merge(
+            Report {
+                requests: 100,
+                faulted: 5,
+            },
+            false,
+        );
+        assert!(state.allowed);
+
+        let state = leader.merge(
+            Report {
+                requests: 100,
+                faulted: 20,
+            },
+            false,
+        );
+        assert!(!state.allowed);
+
+        // the faulted exchanges decay with the ticks
+        for _ in 0..5 {
+            leader.merge(
+                Report {
+                    requests: 100,
+                    faulted: 0,
+                },
+                true,
+            );
+        }
+        assert!(leader.is_allowed());
+
+        let events = timeline.events();
+        assert_eq!(events.len(), 2);
+        assert!(matches!(
+            events[0].kind,
+            EventKind::ThresholdBreached { .. }
+        ));
+        assert!(matches!(
+            events[1].kind,
+            EventKind::ThresholdRecovered { .. }
+        ));
+    }
+}
@@ -0,0 +1,132 @@
+//! Serde of the durations in the config, used by `#[serde(with = "crate::duration")]` on the
+//! `Duration` and `Option<Duration>` fields. The durations are serialized as humantime strings,
+//! e.g. `500ms` or `1m30s`, and the serde form of `Duration` (`{secs: 1, nanos: 0}`) is still
+//! accepted.
+
+use std::fmt;
+use std::time::Duration;
+
+use humantime_serde::re::humantime::parse_duration;
+use humantime_serde::Serde;
+use serde::de::value::{MapAccessDeserializer, SeqAccessDeserializer};
+use serde::de::{Error, MapAccess, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Flexible is a duration, or an optional one, in either form.
+pub struct Flexible<T>(T);
+
+struct DurationVisitor;
+
+impl<'de> Visitor<'de> for DurationVisitor {
+    type Value = Duration;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a duration like `500ms` or `1m30s`, or {secs, nanos}")
+    }
+
+    fn visit_str<E: Error>(self, value: &str) -> Result<Duration, E> {
+        parse_duration(value).map_err(|e| E::custom(format!("invalid duration {}: {}", value, e)))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Duration, A::Error> {
+        Duration::deserialize(MapAccessDeserializer::new(map))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Duration, A::Error> {
+        Duration::deserialize(SeqAccessDeserializer::new(seq))
+    }
+}
+
+impl<'de> Deserialize<'de> for Flexible<Duration> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(DurationVisitor).map(Flexible)
+    }
+}
+
+struct OptionVisitor;
+
+impl<'de> Visitor<'de> for OptionVisitor {
+    type Value = Option<Duration>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an optional duration")
+    }
+
+    fn visit_none<E: Error>(self) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_unit<E: Error>(self) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        Flexible::<Duration>::deserialize(deserializer).map(|duration| Some(duration.0))
+    }
+}
+
+impl<'de> Deserialize<'de> for Flexible<Option<Duration>> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_option(OptionVisitor).map(Flexible)
+    }
+}
+
+pub fn serialize<T, S>(value: &T, serializer: S) -> Result<S::Ok, S::Error>
+where
+    for<'a> Serde<&'a T>: Serialize,
+    S: Serializer,
+{
+    humantime_serde::serialize(value, serializer)
+}
+
+pub fn deserialize<'de, T, D>(deserializer: D) -> Result<T, D::Error>
+where
+    Flexible<T>: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    Flexible::deserialize(deserializer).map(|flexible| flexible.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, PartialEq, Deserialize, Serialize)]
+    struct Durations {
+        #[serde(with = "crate::duration")]
+        timeout: Duration,
+        #[serde(default)]
+        #[serde(with = "crate::duration")]
+        delay: Option<Duration>,
+    }
+
+    #[test]
+    fn test_duration() {
+        let durations: Durations =
+            serde_json::from_str(r#"{"timeout": "1m30s", "delay": "500ms"}"#).unwrap();
+        assert_eq!(
+            durations,
+            Durations {
+                timeout: Duration::from_secs(90),
+                delay: Some(Duration::from_millis(500)),
+            }
+        );
+        assert_eq!(
+            serde_json::to_value(&durations).unwrap(),
+            serde_json::json!({"timeout": "1m 30s", "delay": "500ms"})
+        );
+
+        // the serde form of the earlier configs
+        let durations: Durations =
+            serde_yaml::from_str("timeout: {secs: 2, nanos: 5}\ndelay: ~\n").unwrap();
+        assert_eq!(durations.timeout, Duration::new(2, 5));
+        assert_eq!(durations.delay, None);
+        let durations: Durations = serde_json::from_str(r#"{"timeout": [2, 0]}"#).unwrap();
+        assert_eq!(durations.timeout, Duration::from_secs(2));
+        assert_eq!(durations.delay, None);
+
+        assert!(serde_json::from_str::<Durations>(r#"{"timeout": "2 parsecs"}"#).is_err());
+    }
+}
@@ -0,0 +1,116 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use tokio::sync::oneshot::{channel, Sender};
+use tokio::task::JoinHandle;
+
+use crate::experiment::ExperimentReport;
+use crate::handler::http::rule::Rule;
+use crate::har::HarRecorder;
+use crate::metrics::Metrics;
+use crate::proxy::http::config::{Config, HTTPConfig};
+use crate::proxy::http::server::{HttpServer, Reloader};
+
+/// Proxy runs the fault-injection engine inside another tool, e.g. an operator or a test
+/// framework, instead of the binary driven by the controller. The traffic is not diverted to it,
+/// so it is mostly served with `explicit`, or on the ports the clients are pointed to.
+///
+/// ```no_run
+/// # async fn run(raw: chaos_tproxy_proxy::raw_config::RawConfig) -> anyhow::Result<()> {
+/// use std::convert::TryInto;
+///
+/// use chaos_tproxy_proxy::embedded::Proxy;
+///
+/// let mut proxy = Proxy::new(raw.try_into()?);
+/// proxy.start()?;
+/// // ... run the experiment, `update_rules` swaps the faults in place
+/// proxy.update_rules(vec![]);
+/// proxy.stop().await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Proxy {
+    /// server is taken once it is started.
+    server: Option<HttpServer>,
+    metrics: Arc<Metrics>,
+    har: Option<Arc<HarRecorder>>,
+    reloader: Reloader,
+    /// http_config is the config applied last, the rules are updated on a copy of it.
+    http_config: HTTPConfig,
+    running: Option<(Sender<()>, JoinHandle<Result<()>>)>,
+}
+
+impl Proxy {
+    pub fn new(config: Config) -> Self {
+        let http_config = config.http_config.clone();
+        let server = HttpServer::new(config);
+        Self {
+            metrics: server.metrics(),
+            har: server.har(),
+            reloader: server.reloader(),
+            server: Some(server),
+            http_config,
+            running: None,
+        }
+    }
+
+    /// start serves the listeners on the current tokio runtime, it fails if the proxy has been
+    /// started. The errors of serving, e.g. of binding the listeners, are returned by
+    /// [stop](Proxy::stop).
+    pub fn start(&mut self) -> Result<()> {
+        let mut server = self
+            .server
+            .take()
+            .ok_or_else(|| anyhow!("the proxy has been started"))?;
+        let (sender, rx) = channel();
+        let serving = tokio::spawn(async move { server.serve(rx).await });
+        self.running = Some((sender, serving));
+        Ok(())
+    }
+
+    /// stop shuts the listeners down and waits for the exchanges in flight to drain, up to the
+    /// drain timeout of the config.
+    pub async fn stop(&mut self) -> Result<()> {
+        let (sender, serving) = self
+            .running
+            .take()
+            .ok_or_else(|| anyhow!("the proxy is not running"))?;
+        let _ = sender.send(());
+        serving.await?
+    }
+
+    /// update_rules swaps the rules, the connections accepted from now on get them. See
+    /// [RawRule](crate::raw_config::RawRule) to build them from the config syntax.
+    pub fn update_rules(&mut self, rules: Vec<Rule>) {
+        self.http_config.rules = rules;
+        self.reload(self.http_config.clone());
+    }
+
+    /// reload swaps the rules and the other HTTP settings, as `SIGHUP` does to the binary.
+    pub fn reload(&mut self, config: HTTPConfig) {
+        self.http_config = config.clone();
+        self.reloader.reload(config);
+    }
+
+    /// arm applies the rules or pauses them, the exchanges are forwarded untouched while the
+    /// proxy is disarmed.
+    pub fn arm(&self, armed: bool) {
+        self.metrics.arm(armed);
+    }
+
+    /// metrics returns the recorder of the exchanges, e.g. for the SLO report and the timeline.
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    /// experiment_report summarizes what the experiment has done so far, e.g. to be written once
+    /// the proxy is stopped.
+    pub fn experiment_report(&self) -> ExperimentReport {
+        ExperimentReport::new(&self.metrics)
+    }
+
+    /// har returns the recorder of the exchanges captured, if enabled.
+    pub fn har(&self) -> Option<Arc<HarRecorder>> {
+        self.har.clone()
+    }
+}
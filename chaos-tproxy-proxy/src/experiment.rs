@@ -0,0 +1,125 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use anyhow::Result;
+use serde::Serialize;
+use tokio::fs;
+
+use crate::metrics::{Metrics, PhaseReport, RuleReport, SLOReport};
+use crate::snapshot::unix_millis;
+
+/// ExperimentReportConfig makes the proxy write a summary of what the experiment did on
+/// shutdown, instead of leaving the debug log as the only record of it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExperimentReportConfig {
+    pub path: PathBuf,
+}
+
+/// ExperimentReport is the machine-readable summary of an experiment, from the start of the proxy
+/// to its shutdown.
+#[derive(Debug, Clone, Serialize)]
+pub struct ExperimentReport {
+    pub started_at_ms: u64,
+    pub finished_at_ms: u64,
+    pub duration_secs: f64,
+    /// baseline are the exchanges no rule is applied to, faulted are the other ones.
+    pub baseline: PhaseReport,
+    pub faulted: PhaseReport,
+    /// added_p50_ms and added_p99_ms are the latency percentiles of the faulted exchanges minus
+    /// the ones of the baseline, `None` unless both are observed.
+    pub added_p50_ms: Option<i64>,
+    pub added_p99_ms: Option<i64>,
+    /// rules are the rules applied at least once, by their names or `#<index>` if unnamed.
+    pub rules: BTreeMap<String, RuleReport>,
+    pub slo: Option<SLOReport>,
+}
+
+impl ExperimentReport {
+    pub fn new(metrics: &Metrics) -> Self {
+        let (baseline, faulted) = metrics.phase_reports();
+        let elapsed = metrics.elapsed();
+        let finished_at_ms = unix_millis(SystemTime::now());
+        let added =
+            |faulted: Option<u64>, baseline: Option<u64>| Some(faulted? as i64 - baseline? as i64);
+        Self {
+            started_at_ms: finished_at_ms.saturating_sub(elapsed.as_millis() as u64),
+            finished_at_ms,
+            duration_secs: elapsed.as_secs_f64(),
+            added_p50_ms: added(faulted.p50_ms, baseline.p50_ms),
+            added_p99_ms: added(faulted.p99_ms, baseline.p99_ms),
+            baseline,
+            faulted,
+            rules: metrics.rule_reports(),
+            slo: metrics.slo_report(),
+        }
+    }
+}
+
+/// write_experiment_report writes the report of the metrics to the file of the config.
+pub async fn write_experiment_report(
+    config: &ExperimentReportConfig,
+    metrics: &Metrics,
+) -> Result<()> {
+    let report = ExperimentReport::new(metrics);
+    if let Some(dir) = config
+        .path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+    {
+        fs::create_dir_all(dir).await?;
+    }
+    // rename is atomic, so that the readers never see a partial report
+    let tmp = config.path.with_extension("tmp");
+    fs::write(&tmp, serde_json::to_vec_pretty(&report)?).await?;
+    fs::rename(&tmp, &config.path).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::experiment::{write_experiment_report, ExperimentReportConfig};
+    use crate::handler::http::rule::Rule;
+    use crate::metrics::Metrics;
+
+    #[tokio::test]
+    async fn test_write_experiment_report() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = ExperimentReportConfig {
+            path: dir.path().join("reports").join("experiment.json"),
+        };
+        let slow = Rule::request()
+            .name("slow-api")
+            .delay_ms(200)
+            .build()
+            .unwrap();
+        let abort = Rule::request().abort_with(503).build().unwrap();
+
+        let metrics = Metrics::new(None, None);
+        metrics.record(false, Duration::from_millis(10), false);
+        metrics.rule_applied(0, &slow, &slow.actions, None);
+        metrics.rule_applied(0, &slow, &slow.actions, None);
+        metrics.record(true, Duration::from_millis(200), false);
+        metrics.record(true, Duration::from_millis(200), false);
+        metrics.rule_applied(1, &abort, &abort.actions, None);
+        metrics.record(true, Duration::from_millis(1), true);
+        write_experiment_report(&config, &metrics).await.unwrap();
+
+        let report: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&config.path).unwrap()).unwrap();
+        assert!(report["finished_at_ms"].as_u64() >= report["started_at_ms"].as_u64());
+        assert_eq!(report["baseline"]["requests"], 1);
+        assert_eq!(report["faulted"]["requests"], 3);
+        assert_eq!(report["faulted"]["errors"], 1);
+        assert_eq!(report["added_p50_ms"], 190);
+        let slow = &report["rules"]["slow-api"];
+        assert_eq!(slow["hits"], 2);
+        assert_eq!(slow["aborts"], 0);
+        assert_eq!(slow["delays"], 2);
+        assert_eq!(slow["delay_p99_ms"], 200);
+        assert_eq!(report["rules"]["#1"]["aborts"], 1);
+        assert!(report["rules"]["#1"]["delay_p50_ms"].is_null());
+    }
+}
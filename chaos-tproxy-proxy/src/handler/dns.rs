@@ -0,0 +1,211 @@
+use std::net::IpAddr;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use wildmatch::WildMatch;
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_AAAA: u16 = 28;
+
+const HEADER_LEN: usize = 12;
+const CLASS_IN: u16 = 1;
+const RCODE_NXDOMAIN: u8 = 3;
+/// pointer to the name of the question, which is right after the header.
+const NAME_POINTER: [u8; 2] = [0xc0, HEADER_LEN as u8];
+
+/// DnsRule applies the action to the queries of the matched names.
+#[derive(Debug, Clone)]
+pub struct DnsRule {
+    /// wildcard of the lower-case query names without the trailing dot, e.g. `*.svc.local`.
+    pub name: WildMatch,
+    /// type of the queries, e.g. 1 for A, all types by default.
+    pub query_type: Option<u16>,
+    pub action: DnsAction,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DnsAction {
+    /// delay of the answer, forwarded or synthesized.
+    pub delay: Option<Duration>,
+    pub answer: DnsAnswer,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DnsAnswer {
+    /// Forward the query to the original server.
+    Forward,
+    /// Answer NXDOMAIN without forwarding.
+    NxDomain,
+    /// Answer the addresses of the query type without forwarding, the queries of the other types
+    /// get empty answers.
+    Rewrite { addrs: Vec<IpAddr>, ttl: u32 },
+}
+
+/// Query is the first question of a DNS message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Query {
+    /// lower-case name without the trailing dot.
+    pub name: String,
+    pub query_type: u16,
+    /// end of the question in the message.
+    end: usize,
+}
+
+impl Query {
+    /// parse parses the first question of the query message.
+    pub fn parse(message: &[u8]) -> Result<Self> {
+        if message.len() < HEADER_LEN || message[2] & 0x80 != 0 {
+            return Err(anyhow!("not a DNS query"));
+        }
+        if u16::from_be_bytes([message[4], message[5]]) == 0 {
+            return Err(anyhow!("DNS query without question"));
+        }
+        let mut labels = vec![];
+        let mut pos = HEADER_LEN;
+        loop {
+            let len = *message
+                .get(pos)
+                .ok_or_else(|| anyhow!("truncated DNS question"))? as usize;
+            pos += 1;
+            if len == 0 {
+                break;
+            }
+            // the names of the questions are never compressed
+            if len & 0xc0 != 0 {
+                return Err(anyhow!("invalid label of DNS question"));
+            }
+            let label = message
+                .get(pos..pos + len)
+                .ok_or_else(|| anyhow!("truncated DNS question"))?;
+            labels.push(String::from_utf8_lossy(label).to_lowercase());
+            pos += len;
+        }
+        let fixed = message
+            .get(pos..pos + 4)
+            .ok_or_else(|| anyhow!("truncated DNS question"))?;
+        Ok(Self {
+            name: labels.join("."),
+            query_type: u16::from_be_bytes([fixed[0], fixed[1]]),
+            end: pos + 4,
+        })
+    }
+}
+
+/// select_dns returns the first rule matching the query and its index.
+pub fn select_dns<'a>(rules: &'a [DnsRule], query: &Query) -> Option<(usize, &'a DnsRule)> {
+    rules.iter().enumerate().find(|(_, rule)| {
+        rule.name.matches(&query.name)
+            && rule
+                .query_type
+                .map(|query_type| query_type == query.query_type)
+                .unwrap_or(true)
+    })
+}
+
+/// synthesize_answer returns the response of the query message instead of forwarding it, if any.
+pub fn synthesize_answer(message: &[u8], query: &Query, answer: &DnsAnswer) -> Option<Vec<u8>> {
+    match answer {
+        DnsAnswer::Forward => None,
+        DnsAnswer::NxDomain => Some(response(message, query, RCODE_NXDOMAIN, vec![])),
+        DnsAnswer::Rewrite { addrs, ttl } => {
+            let records = addrs
+                .iter()
+                .filter_map(|addr| match (addr, query.query_type) {
+                    (IpAddr::V4(v4), TYPE_A) => Some(record(TYPE_A, *ttl, &v4.octets())),
+                    (IpAddr::V6(v6), TYPE_AAAA) => Some(record(TYPE_AAAA, *ttl, &v6.octets())),
+                    _ => None,
+                })
+                .collect();
+            Some(response(message, query, 0, records))
+        }
+    }
+}
+
+/// response builds the response with the header and the first question of the query, the other
+/// sections of the query (e.g. EDNS) are dropped.
+fn response(message: &[u8], query: &Query, rcode: u8, records: Vec<Vec<u8>>) -> Vec<u8> {
+    let mut response = message[..query.end].to_vec();
+    // QR is set, the opcode and RD are kept, AA and TC are cleared
+    response[2] = (message[2] | 0x80) & !0x06;
+    // RA is set
+    response[3] = 0x80 | rcode;
+    response[4..6].copy_from_slice(&1u16.to_be_bytes());
+    response[6..8].copy_from_slice(&(records.len() as u16).to_be_bytes());
+    response[8..12].fill(0);
+    for record in records {
+        response.extend(record);
+    }
+    response
+}
+
+fn record(record_type: u16, ttl: u32, data: &[u8]) -> Vec<u8> {
+    let mut record = NAME_POINTER.to_vec();
+    record.extend(record_type.to_be_bytes());
+    record.extend(CLASS_IN.to_be_bytes());
+    record.extend(ttl.to_be_bytes());
+    record.extend((data.len() as u16).to_be_bytes());
+    record.extend(data);
+    record
+}
+
+#[cfg(test)]
+mod tests {
+    use wildmatch::WildMatch;
+
+    use crate::handler::dns::{
+        select_dns, synthesize_answer, DnsAction, DnsAnswer, DnsRule, Query, TYPE_A, TYPE_AAAA,
+    };
+
+    /// query of `API.test.` with RD set.
+    fn message(query_type: u16) -> Vec<u8> {
+        let mut message = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        message.extend(b"\x03API\x04test\x00");
+        message.extend(query_type.to_be_bytes());
+        message.extend(1u16.to_be_bytes());
+        message
+    }
+
+    #[test]
+    fn test_synthesize_answer() {
+        let rules = vec![
+            DnsRule {
+                name: WildMatch::new("*.test"),
+                query_type: Some(TYPE_A),
+                action: DnsAction {
+                    delay: None,
+                    answer: DnsAnswer::Rewrite {
+                        addrs: vec!["10.0.0.1".parse().unwrap()],
+                        ttl: 30,
+                    },
+                },
+            },
+            DnsRule {
+                name: WildMatch::new("api.test"),
+                query_type: None,
+                action: DnsAction {
+                    delay: None,
+                    answer: DnsAnswer::NxDomain,
+                },
+            },
+        ];
+
+        let a = message(TYPE_A);
+        let query = Query::parse(&a).unwrap();
+        assert_eq!(query.name, "api.test");
+        let (index, rule) = select_dns(&rules, &query).unwrap();
+        assert_eq!(index, 0);
+        let response = synthesize_answer(&a, &query, &rule.action.answer).unwrap();
+        assert_eq!(&response[..4], &[0x12, 0x34, 0x81, 0x80]);
+        assert_eq!(&response[6..8], &[0, 1]);
+        assert_eq!(&response[a.len()..a.len() + 2], &[0xc0, 0x0c]);
+        assert_eq!(&response[response.len() - 4..], &[10, 0, 0, 1]);
+
+        let aaaa = message(TYPE_AAAA);
+        let query = Query::parse(&aaaa).unwrap();
+        let (index, rule) = select_dns(&rules, &query).unwrap();
+        assert_eq!(index, 1);
+        let response = synthesize_answer(&aaaa, &query, &rule.action.answer).unwrap();
+        assert_eq!(response[3] & 0x0f, 3);
+        assert_eq!(response.len(), aaaa.len());
+    }
+}
@@ -1,21 +1,444 @@
+use std::borrow::Cow;
 use std::collections::HashMap;
-use std::time::Duration;
+use std::fmt;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
-use anyhow::anyhow;
 use futures::TryStreamExt;
-use http::header::HeaderMap;
-use http::{Method, Request, Response, StatusCode, Uri};
+use http::header::{HeaderMap, CONTENT_LENGTH, CONTENT_TYPE};
+use http::uri::Authority;
+use http::{HeaderValue, Method, Request, Response, StatusCode, Uri};
+use humantime_serde::re::humantime::format_duration;
+use hyper::body::HttpBody;
 use hyper::Body;
 use serde_json::Value;
-use tokio::time::sleep;
 use tracing::{debug, instrument};
 
-#[derive(Debug, Eq, PartialEq, Clone)]
+use crate::clock::Clock;
+use crate::handler::http::client_ip::{ClientAddr, ClientIpAction, ClientIpMode};
+use crate::handler::http::connect::{ConnectFailure, ConnectFault};
+use crate::handler::http::cookie::{apply_cookies, Cookie};
+use crate::handler::http::dedup::DedupAction;
+use crate::handler::http::dribble::DribbleAction;
+use crate::handler::http::exchange::ExchangeId;
+use crate::handler::http::expect::withhold_continue;
+use crate::handler::http::framing::{apply_framing, apply_trailers, Framing};
+use crate::handler::http::header_pattern::HeaderPattern;
+use crate::handler::http::latency_profile::LatencyProfile;
+use crate::handler::http::pattern::PatternAction;
+use crate::handler::http::rate_limit::RateLimitAction;
+use crate::handler::http::segment::SegmentAction;
+use crate::handler::http::semantics::{body_allowed, conform_response, sized_body};
+use crate::handler::http::stall::stall_body;
+use crate::handler::http::truncate::{TruncateAction, TruncateAfter};
+use crate::handler::http::websocket::WebSocketAction;
+use crate::handler::tcp::TcpAction;
+
+#[derive(Debug, PartialEq, Clone)]
 pub struct Actions {
     pub abort: bool,
+    pub abort_mode: AbortMode,
+    pub abort_response: Option<AbortResponse>,
+    pub dedup: Option<DedupAction>,
+    pub duplicate: Option<DuplicateAction>,
+    pub mirror: Option<MirrorAction>,
     pub delay: Option<Duration>,
+    /// delay_profile draws the delay of each exchange from the latency profile, see
+    /// [sampled](Actions::sampled).
+    pub delay_profile: Option<Arc<LatencyProfile>>,
+    pub delay_position: DelayPosition,
+    /// timeout abandons the upstream exchange if it is not answered in time.
+    pub timeout: Option<TimeoutAction>,
+    /// connect delays or fails the connection to the upstream, before it sees the request.
+    pub connect: Option<ConnectFault>,
+    /// remove_headers removes the headers of the names matched by any of the patterns.
+    pub remove_headers: Option<Vec<HeaderPattern>>,
     pub replace: Option<ReplaceAction>,
     pub patch: Option<PatchAction>,
+    pub redirect: Option<RedirectAction>,
+    pub pattern: Option<PatternAction>,
+    pub rate_limit: Option<RateLimitAction>,
+    pub set_cookies: Option<Vec<Cookie>>,
+    pub delete_cookies: Option<Vec<Cookie>>,
+    pub dribble: Option<DribbleAction>,
+    pub segment: Option<SegmentAction>,
+    pub framing: Option<Framing>,
+    pub trailers: Option<HeaderMap>,
+    pub websocket: Option<WebSocketAction>,
+    pub tcp: Option<TcpAction>,
+    /// withhold_continue withholds the `100 Continue` for the duration.
+    pub withhold_continue: Option<Duration>,
+    /// stall_body holds the body of the response back for the duration, after the headers.
+    pub stall_body: Option<Duration>,
+    /// truncate cuts the body of the response once a part of it is sent.
+    pub truncate: Option<TruncateAction>,
+    /// client_ip rewrites the headers telling the client to the upstream.
+    pub client_ip: Option<ClientIpAction>,
+    /// weighted are the alternative action sets, one of them applies to each exchange instead of
+    /// the other actions, see [sampled](Actions::sampled).
+    pub weighted: Option<WeightedActions>,
+}
+
+impl Actions {
+    /// is_active checks whether the actions should be applied at the time of the clock, `epoch` is
+    /// the start of the patterns shared by the coordinated instances.
+    pub fn is_active(&self, epoch: Option<SystemTime>, clock: &dyn Clock) -> bool {
+        self.pattern
+            .as_ref()
+            .map(|pattern| pattern.is_active(epoch, clock.system_time()))
+            .unwrap_or(true)
+    }
+
+    /// summary returns the compact description of the mutations, e.g. `delay=2s`,
+    /// `replace.code=500`, echoed to the clients by the rules with `echo_applied`.
+    pub fn summary(&self) -> Vec<String> {
+        let mut applied = vec![];
+        if let Some(response) = &self.abort_response {
+            applied.push(format!("abort.code={}", response.code.as_u16()));
+        }
+        if self.dedup.is_some() {
+            applied.push("dedup".to_string());
+        }
+        if let Some(duplicate) = &self.duplicate {
+            applied.push(format!("duplicate={}", duplicate.count));
+        }
+        if let Some(mirror) = &self.mirror {
+            applied.push(format!("mirror={}", mirror.target));
+        }
+        if let Some(delay) = self.delay {
+            applied.push(format!("delay={}", format_duration(delay)));
+        }
+        if self.delay_profile.is_some() {
+            applied.push("delay=profile".to_string());
+        }
+        if let Some(timeout) = &self.timeout {
+            applied.push(format!("timeout={}", format_duration(timeout.after)));
+        }
+        if let Some(connect) = &self.connect {
+            if let Some(delay) = connect.delay {
+                applied.push(format!("connect_delay={}", format_duration(delay)));
+            }
+            match connect.failure {
+                Some(ConnectFailure::Refused) => applied.push("connect_refused".to_string()),
+                Some(ConnectFailure::DnsFailure) => applied.push("dns_failure".to_string()),
+                None => {}
+            }
+        }
+        if self.weighted.is_some() {
+            applied.push("weighted".to_string());
+        }
+        if self.remove_headers.is_some() {
+            applied.push("remove_headers".to_string());
+        }
+        if let Some(replace) = &self.replace {
+            if let Some(path) = &replace.path {
+                applied.push(format!("replace.path={}", path));
+            }
+            if let Some(method) = &replace.method {
+                applied.push(format!("replace.method={}", method));
+            }
+            if replace.body.is_some() {
+                applied.push("replace.body".to_string());
+            }
+            if let Some(code) = replace.code {
+                applied.push(format!("replace.code={}", code.as_u16()));
+            }
+            if replace.queries.is_some() {
+                applied.push("replace.queries".to_string());
+            }
+            if replace.headers.is_some() {
+                applied.push("replace.headers".to_string());
+            }
+            if let Some(authority) = &replace.authority {
+                applied.push(format!("replace.authority={}", authority));
+            }
+            if let Some(upstream) = &replace.upstream {
+                applied.push(format!("replace.upstream={}", upstream));
+            }
+        }
+        if let Some(patch) = &self.patch {
+            if patch.body.is_some() {
+                applied.push("patch.body".to_string());
+            }
+            if patch.queries.is_some() {
+                applied.push("patch.queries".to_string());
+            }
+            if patch.headers.is_some() {
+                applied.push("patch.headers".to_string());
+            }
+        }
+        if let Some(redirect) = &self.redirect {
+            applied.push(format!("redirect.code={}", redirect.code.as_u16()));
+        }
+        if self.rate_limit.is_some() {
+            applied.push("rate_limit".to_string());
+        }
+        if let Some(cookies) = &self.set_cookies {
+            for cookie in cookies {
+                applied.push(format!("set_cookie={}", cookie.name));
+            }
+        }
+        if let Some(cookies) = &self.delete_cookies {
+            for cookie in cookies {
+                applied.push(format!("delete_cookie={}", cookie.name));
+            }
+        }
+        if let Some(dribble) = &self.dribble {
+            applied.push(format!("dribble={}", format_duration(dribble.duration)));
+        }
+        if let Some(segment) = &self.segment {
+            applied.push(format!(
+                "segment={}/{}",
+                segment.size,
+                format_duration(segment.inter_segment_delay)
+            ));
+        }
+        if let Some(framing) = self.framing {
+            applied.push(format!(
+                "framing={}",
+                match framing {
+                    Framing::Chunked => "chunked",
+                    Framing::ContentLength => "content_length",
+                }
+            ));
+        }
+        if self.trailers.is_some() {
+            applied.push("trailers".to_string());
+        }
+        if self.websocket.is_some() {
+            applied.push("websocket".to_string());
+        }
+        if self.tcp.is_some() {
+            applied.push("tcp".to_string());
+        }
+        if let Some(withhold) = self.withhold_continue {
+            applied.push(format!("withhold_continue={}", format_duration(withhold)));
+        }
+        if let Some(stall) = self.stall_body {
+            applied.push(format!("stall_body={}", format_duration(stall)));
+        }
+        if let Some(truncate) = &self.truncate {
+            applied.push(format!("truncate={}", truncate.after));
+        }
+        if let Some(client_ip) = &self.client_ip {
+            applied.push(format!(
+                "client_ip={}",
+                match client_ip.mode {
+                    ClientIpMode::Append => "append",
+                    ClientIpMode::Set => "set",
+                    ClientIpMode::Strip => "strip",
+                }
+            ));
+        }
+        applied
+    }
+
+    /// rewrites_body tells whether the actions replace or patch the body. Only such bodies are
+    /// decoded by `decode_body`, the others are streamed through untouched.
+    pub fn rewrites_body(&self) -> bool {
+        let replaced = self
+            .replace
+            .as_ref()
+            .is_some_and(|replace| replace.body.is_some());
+        let patched = self
+            .patch
+            .as_ref()
+            .is_some_and(|patch| patch.body.is_some());
+        replaced || patched || self.any_weighted(Actions::rewrites_body)
+    }
+
+    /// buffers_body tells whether the actions read the whole body in memory, which is capped by
+    /// `max_body_size`.
+    pub fn buffers_body(&self) -> bool {
+        self.rewrites_body()
+            || self.mirror.is_some()
+            || self.duplicate.is_some()
+            || self.dribble.is_some()
+            || self.framing == Some(Framing::ContentLength)
+            || self
+                .truncate
+                .as_ref()
+                .is_some_and(|truncate| matches!(truncate.after, TruncateAfter::Percent(_)))
+            || self.any_weighted(Actions::buffers_body)
+    }
+
+    /// any_weighted tells whether any alternative action set satisfies the predicate.
+    fn any_weighted(&self, predicate: impl Fn(&Actions) -> bool) -> bool {
+        self.weighted
+            .iter()
+            .flat_map(|weighted| &weighted.0)
+            .any(|(_, actions)| predicate(actions))
+    }
+
+    /// response_delay returns the delay injected after the upstream has answered a request.
+    pub fn response_delay(&self) -> Option<Duration> {
+        match self.delay_position {
+            DelayPosition::BeforeForward => None,
+            DelayPosition::AfterReceive => self.delay,
+        }
+    }
+
+    /// sampled returns the actions of an exchange, the alternative drawn by weight if any, with
+    /// the delay drawn from the latency profile if any. They are drawn once, so the actions
+    /// sampled should be used for the whole exchange.
+    pub fn sampled(&self) -> Cow<'_, Actions> {
+        self.sampled_by(rand::random())
+    }
+
+    /// sampled_by returns the actions of an exchange as [sampled](Actions::sampled) does, with the
+    /// alternative of the roll in [0, 1), e.g. the one of a sticky session.
+    pub fn sampled_by(&self, roll: f64) -> Cow<'_, Actions> {
+        if let Some(weighted) = &self.weighted {
+            return weighted.draw(roll).sampled();
+        }
+        match &self.delay_profile {
+            None => Cow::Borrowed(self),
+            Some(profile) => {
+                let mut actions = self.clone();
+                actions.delay = Some(profile.sample());
+                actions.delay_profile = None;
+                Cow::Owned(actions)
+            }
+        }
+    }
+}
+
+/// WeightedActions are the alternative action sets of a rule with their weights, e.g. 70 for
+/// passing through, 20 for a delay and 10 for an abort.
+#[derive(Debug, PartialEq, Clone)]
+pub struct WeightedActions(pub Vec<(u32, Actions)>);
+
+impl WeightedActions {
+    /// draw returns the action set of the roll in [0, 1), each as likely as its share of the total
+    /// weight.
+    pub fn draw(&self, roll: f64) -> &Actions {
+        let total: u64 = self.0.iter().map(|(weight, _)| *weight as u64).sum();
+        let mut drawn = ((roll * total as f64) as u64).min(total - 1);
+        for (weight, actions) in &self.0 {
+            if drawn < *weight as u64 {
+                return actions;
+            }
+            drawn -= *weight as u64;
+        }
+        unreachable!("the weights are drawn within their total")
+    }
+}
+
+/// DelayPosition introduces when the delay of a request-target rule is injected, the delay of a
+/// response-target rule is always injected after the upstream has answered.
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Default)]
+pub enum DelayPosition {
+    /// Delay the request before forwarding it to the upstream.
+    #[default]
+    BeforeForward,
+    /// Forward the request at once, and delay the response after receiving it from the upstream.
+    AfterReceive,
+}
+
+/// AbortMode introduces how the connection would be handled when the exchange is aborted.
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Default)]
+pub enum AbortMode {
+    /// Send a TCP RST to the client.
+    Reset,
+    /// Close the connection with a FIN.
+    #[default]
+    Close,
+    /// Never respond and keep the connection open.
+    Timeout,
+}
+
+/// Abort is the error returned by the actions when the exchange is aborted.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct Abort(pub AbortMode);
+
+impl fmt::Display for Abort {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Abort applied")
+    }
+}
+
+impl std::error::Error for Abort {}
+
+/// AbortResponse is the response synthesized in place of the upstream one, the connection would
+/// be kept alive.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct AbortResponse {
+    pub code: StatusCode,
+    pub headers: Option<HeaderMap>,
+    pub body: Vec<u8>,
+}
+
+impl AbortResponse {
+    fn response(&self) -> anyhow::Result<Response<Body>> {
+        let mut response = Response::builder().status(self.code).body(Body::empty())?;
+        let body = sized_body(response.headers_mut(), self.body.clone());
+        *response.body_mut() = body;
+        if let Some(hdrs) = &self.headers {
+            for (key, value) in hdrs {
+                response.headers_mut().insert(key, value.clone());
+            }
+        }
+        Ok(response)
+    }
+}
+
+/// DuplicateAction sends `count` extra copies of the request to the upstream, one per `interval`
+/// after the original one. The responses of the copies are discarded.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct DuplicateAction {
+    pub count: u32,
+    pub interval: Option<Duration>,
+}
+
+/// MirrorAction copies `percent` of the requests, before any chaos is injected, to the `target`
+/// backend. The responses of the copies are discarded.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct MirrorAction {
+    pub target: Authority,
+    pub percent: u8,
+}
+
+/// TimeoutAction forwards the request but abandons the upstream exchange unless the headers of the
+/// response are received `after` it, like an upstream accepting the requests and then hanging.
+/// The client is answered with 504, or its connection is handled as the `abort_mode` if any.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct TimeoutAction {
+    pub after: Duration,
+    pub abort_mode: Option<AbortMode>,
+}
+
+impl TimeoutAction {
+    /// shortest returns the timeout of the shorter duration, the timeouts of the rules matching
+    /// an exchange are not cumulative.
+    pub fn shortest(a: Option<Self>, b: Option<Self>) -> Option<Self> {
+        a.into_iter().chain(b).min_by_key(|timeout| timeout.after)
+    }
+
+    /// run waits for the upstream exchange until the timeout.
+    pub async fn run<F>(&self, exchange: F, clock: &dyn Clock) -> anyhow::Result<Response<Body>>
+    where
+        F: Future<Output = anyhow::Result<Response<Body>>>,
+    {
+        tokio::select! {
+            response = exchange => response,
+            _ = clock.sleep(self.after) => {
+                debug!("upstream exchange abandoned after {}", format_duration(self.after));
+                match self.abort_mode {
+                    Some(mode) => Err(Abort(mode).into()),
+                    None => Ok(Response::builder()
+                        .status(StatusCode::GATEWAY_TIMEOUT)
+                        .body(Body::empty())?),
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct RedirectAction {
+    pub code: StatusCode,
+    pub location: String,
 }
 
 #[derive(Debug, Eq, PartialEq, Clone)]
@@ -33,8 +456,15 @@ pub struct ReplaceAction {
     pub code: Option<StatusCode>,
     pub queries: Option<HashMap<String, String>>,
     pub headers: Option<HeaderMap>,
+    pub authority: Option<Authority>,
+    pub upstream: Option<Authority>,
 }
 
+/// Upstream is attached to the extensions of a request which should be forwarded to the given
+/// backend instead of its original destination.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Upstream(pub Authority);
+
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub struct ReplaceBodyAction {
     pub contents: Vec<u8>,
@@ -63,19 +493,46 @@ async fn read_value(body: &mut Body) -> anyhow::Result<Value> {
 
 /// apply_request_action would inject chaos actions into the given request.
 /// TODO(@STRRL): refactor this function, it is NOT extensible with more actions.
-#[instrument]
+#[instrument(fields(exchange = %ExchangeId::of(request.extensions())))]
 pub async fn apply_request_action(
     mut request: Request<Body>,
     actions: &Actions,
+    clock: &dyn Clock,
 ) -> anyhow::Result<Request<Body>> {
     // abort the request
     if actions.abort {
-        return Err(anyhow!("Abort applied"));
+        return Err(Abort(actions.abort_mode).into());
     }
 
-    // delay the request
-    if let Some(delay) = actions.delay {
-        sleep(delay).await
+    // drop the request by its idempotency key
+    if let Some(dedup) = &actions.dedup {
+        if dedup.should_drop(&request) {
+            debug!("request dropped by idempotency key");
+            return Err(Abort(actions.abort_mode).into());
+        }
+    }
+
+    // delay the request, the delay after receiving the response is applied by the caller
+    if let (Some(delay), DelayPosition::BeforeForward) = (actions.delay, actions.delay_position) {
+        clock.sleep(delay).await
+    }
+
+    if let Some(withhold) = actions.withhold_continue {
+        request = withhold_continue(request, withhold);
+    }
+
+    // the connect faults are run when the request is forwarded
+    if let Some(connect) = &actions.connect {
+        let fault = match request.extensions_mut().remove::<ConnectFault>() {
+            Some(fault) => fault.merge(connect),
+            None => connect.clone(),
+        };
+        request.extensions_mut().insert(fault);
+    }
+
+    // remove the headers before the replaced ones are set
+    for pattern in actions.remove_headers.iter().flatten() {
+        pattern.remove(request.headers_mut());
     }
 
     if let Some(replace) = &actions.replace {
@@ -89,8 +546,8 @@ pub async fn apply_request_action(
 
         if let Some(body) = &replace.body {
             // replace the request body
-            *request.body_mut() = body.contents.clone().into();
-            request.headers_mut().remove(http::header::CONTENT_LENGTH);
+            let body = sized_body(request.headers_mut(), body.contents.clone());
+            *request.body_mut() = body;
         }
 
         // replace request query parameters
@@ -102,6 +559,23 @@ pub async fn apply_request_action(
                 request.headers_mut().insert(key, value.clone());
             }
         }
+
+        if let Some(authority) = &replace.authority {
+            // replace the request authority and host
+            let mut parts = request.uri().clone().into_parts();
+            if parts.authority.is_some() {
+                parts.authority = Some(authority.clone());
+                *request.uri_mut() = Uri::from_parts(parts)?;
+            }
+            request
+                .headers_mut()
+                .insert(http::header::HOST, authority.as_str().parse()?);
+        }
+
+        if let Some(upstream) = &replace.upstream {
+            // reroute the request to another backend
+            request.extensions_mut().insert(Upstream(upstream.clone()));
+        }
     }
 
     if let Some(patch) = &actions.patch {
@@ -114,8 +588,8 @@ pub async fn apply_request_action(
             let mut data = read_value(request.body_mut()).await?;
             json_patch::merge(&mut data, value);
             let merged = serde_json::to_vec(&data)?;
-            *request.body_mut() = merged.into();
-            request.headers_mut().remove(http::header::CONTENT_LENGTH);
+            let body = sized_body(request.headers_mut(), merged);
+            *request.body_mut() = body;
         }
 
         // patch headers
@@ -126,7 +600,16 @@ pub async fn apply_request_action(
         }
     }
 
-    debug!("action applied: {:?}", request);
+    // set, strip or falsify the client told to the upstream
+    if let Some(client_ip) = &actions.client_ip {
+        let client = request
+            .extensions()
+            .get::<ClientAddr>()
+            .map(|client| client.0.ip());
+        client_ip.apply(request.headers_mut(), client)?;
+    }
+
+    debug!("action applied: {} {}", request.method(), request.uri());
     Ok(request)
 }
 
@@ -196,21 +679,173 @@ fn replace_queries(uri: &mut Uri, queries: Option<&HashMap<String, String>>) ->
     Ok(())
 }
 
-/// apply_response_action would inject chaos actions into the given response.
+/// APPLIED_HEADER carries the summaries of the rules with `echo_applied` back to the clients.
+pub const APPLIED_HEADER: &str = "x-chaos-applied";
+
+/// echo_applied would set the summaries of the applied mutations to the response, joined by `;`.
+pub fn echo_applied(response: &mut Response<Body>, applied: &[String]) -> anyhow::Result<()> {
+    if !applied.is_empty() {
+        response
+            .headers_mut()
+            .insert(APPLIED_HEADER, HeaderValue::from_str(&applied.join(";"))?);
+    }
+    Ok(())
+}
+
+/// RULE_HEADER carries the rules modifying the exchange, stamped by the fault markers.
+pub const RULE_HEADER: &str = "x-chaos-tproxy-rule";
+
+/// FAULTS_HEADER carries the summaries of the mutations of the rules, stamped by the fault markers.
+pub const FAULTS_HEADER: &str = "x-chaos-tproxy-faults";
+
+/// FaultMarkers collects the rules modifying an exchange, stamped on the requests forwarded and
+/// the responses sent, so that the injected failures are told from the organic ones downstream.
+#[derive(Debug, Clone, Default)]
+pub struct FaultMarkers {
+    rules: Vec<String>,
+    faults: Vec<String>,
+}
+
+impl FaultMarkers {
+    pub fn add(&mut self, label: String, actions: &Actions) {
+        self.rules.push(label);
+        self.faults.extend(actions.summary());
+    }
+
+    /// mark would set the markers to the headers, joined by `,`.
+    pub fn mark(&self, headers: &mut HeaderMap) -> anyhow::Result<()> {
+        if !self.rules.is_empty() {
+            headers.insert(RULE_HEADER, HeaderValue::from_str(&self.rules.join(","))?);
+            headers.insert(
+                FAULTS_HEADER,
+                HeaderValue::from_str(&self.faults.join(","))?,
+            );
+        }
+        Ok(())
+    }
+}
+
+/// PROBLEM_JSON is the media type of the RFC 7807 problem documents.
+pub const PROBLEM_JSON: &str = "application/problem+json";
+
+/// problem_json would fill the empty body of the synthesized error response with a RFC 7807 problem
+/// document, carrying the index of the rule and the summary of its actions. The responses with a
+/// body or a non-error status are untouched.
+pub fn problem_json(
+    response: &mut Response<Body>,
+    instance: &Uri,
+    rule: usize,
+    actions: &Actions,
+) -> anyhow::Result<()> {
+    let status = response.status();
+    if !(status.is_client_error() || status.is_server_error()) || !response.body().is_end_stream() {
+        return Ok(());
+    }
+    let document = serde_json::json!({
+        "type": "about:blank",
+        "title": status.canonical_reason().unwrap_or_default(),
+        "status": status.as_u16(),
+        "detail": "the response is synthesized by chaos-tproxy",
+        "instance": instance.path(),
+        "chaos": {
+            "rule": rule,
+            "actions": actions.summary(),
+        },
+    });
+    *response.body_mut() = serde_json::to_vec(&document)?.into();
+    let headers = response.headers_mut();
+    headers.remove(CONTENT_LENGTH);
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static(PROBLEM_JSON));
+    Ok(())
+}
+
+/// synthesize_response returns the response which should be sent instead of forwarding the
+/// given request, if any.
+pub fn synthesize_response(
+    request: &Request<Body>,
+    actions: &Actions,
+    clock: &dyn Clock,
+) -> anyhow::Result<Option<Response<Body>>> {
+    let mut response = if let Some(redirect) = &actions.redirect {
+        Some(redirect_response(request, redirect)?)
+    } else if let Some(response) = actions
+        .rate_limit
+        .as_ref()
+        .map(|rate_limit| rate_limit.response(clock.now()))
+        .transpose()?
+        .flatten()
+    {
+        Some(response)
+    } else {
+        actions
+            .abort_response
+            .as_ref()
+            .map(AbortResponse::response)
+            .transpose()?
+    };
+    if let Some(response) = &mut response {
+        conform_response(request.method(), response);
+    }
+    Ok(response)
+}
+
+/// redirect_response would build the redirect response of the given request, the upstream would
+/// never be called.
+pub fn redirect_response(
+    request: &Request<Body>,
+    redirect: &RedirectAction,
+) -> anyhow::Result<Response<Body>> {
+    let host = request
+        .headers()
+        .get(http::header::HOST)
+        .and_then(|value| value.to_str().ok());
+    let location = render_location(&redirect.location, request.uri(), host);
+    debug!("redirect to {} with {}", location, redirect.code);
+    Ok(Response::builder()
+        .status(redirect.code)
+        .header(http::header::LOCATION, location)
+        .body(Body::empty())?)
+}
+
+fn render_location(template: &str, uri: &Uri, host: Option<&str>) -> String {
+    template
+        .replace(
+            "{host}",
+            host.or_else(|| uri.authority().map(|a| a.as_str()))
+                .unwrap_or(""),
+        )
+        .replace("{path}", uri.path())
+        .replace("{query}", uri.query().unwrap_or(""))
+}
+
+/// apply_response_action would inject chaos actions into the given response to a request of the
+/// method, the body is never rewritten where the method and the status forbid one.
 /// TODO(@STRRL): refactor this function, it is NOT extensible with more actions.
-#[instrument]
+#[instrument(fields(exchange = %ExchangeId::of(response.extensions())))]
 pub async fn apply_response_action(
     mut response: Response<Body>,
+    method: &Method,
     actions: &Actions,
+    clock: &dyn Clock,
 ) -> anyhow::Result<Response<Body>> {
     // abort the response
     if actions.abort {
-        return Err(anyhow!("Abort applied"));
+        return Err(Abort(actions.abort_mode).into());
+    }
+
+    // respond with the synthesized response instead of the upstream one
+    if let Some(abort) = &actions.abort_response {
+        response = abort.response()?;
     }
 
     // delay the response
     if let Some(delay) = actions.delay {
-        sleep(delay).await
+        clock.sleep(delay).await
+    }
+
+    // remove the headers before the replaced ones are set
+    for pattern in actions.remove_headers.iter().flatten() {
+        pattern.remove(response.headers_mut());
     }
 
     if let Some(replace) = &actions.replace {
@@ -220,9 +855,13 @@ pub async fn apply_response_action(
         }
 
         // replace the response body
-        if let Some(body) = &replace.body {
-            *response.body_mut() = body.contents.clone().into();
-            response.headers_mut().remove(http::header::CONTENT_LENGTH);
+        if let Some(body) = replace
+            .body
+            .as_ref()
+            .filter(|_| body_allowed(method, response.status()))
+        {
+            let body = sized_body(response.headers_mut(), body.contents.clone());
+            *response.body_mut() = body;
         }
 
         // replace the response header
@@ -235,13 +874,17 @@ pub async fn apply_response_action(
 
     if let Some(patch) = &actions.patch {
         // patch response body with JSON Patch
-        if let Some(patch_body) = &patch.body {
+        if let Some(patch_body) = patch
+            .body
+            .as_ref()
+            .filter(|_| body_allowed(method, response.status()))
+        {
             let PatchBodyActionContents::JSON(ref value) = patch_body.contents;
             let mut data = read_value(response.body_mut()).await?;
             json_patch::merge(&mut data, value);
             let merged = serde_json::to_vec(&data)?;
-            *response.body_mut() = merged.into();
-            response.headers_mut().remove(http::header::CONTENT_LENGTH);
+            let body = sized_body(response.headers_mut(), merged);
+            *response.body_mut() = body;
         }
         // patch headers
         if let Some(hdrs) = &patch.headers {
@@ -251,13 +894,67 @@ pub async fn apply_response_action(
         }
     }
 
-    debug!("action applied: {:?}", response);
+    // set or expire cookies
+    let cookies = actions.set_cookies.iter().chain(&actions.delete_cookies);
+    apply_cookies(response.headers_mut(), cookies.flatten())?;
+
+    // re-frame the body
+    if let Some(framing) = actions
+        .framing
+        .filter(|_| body_allowed(method, response.status()))
+    {
+        apply_framing(&mut response, framing).await?;
+    }
+    if let Some(trailers) = &actions.trailers {
+        apply_trailers(&mut response, trailers).await?;
+    }
+
+    // trickle the body slowly after the headers
+    if let Some(dribble) = &actions.dribble {
+        let contents = hyper::body::to_bytes(response.body_mut()).await?;
+        *response.body_mut() = dribble.body(contents);
+    }
+    // stream the body in segments with pauses between them
+    if let Some(segment) = &actions.segment {
+        let body = std::mem::take(response.body_mut());
+        *response.body_mut() = segment.body(body);
+    }
+    // send the headers at once, but stall before any byte of the body
+    if let Some(stall) = actions.stall_body {
+        let body = std::mem::take(response.body_mut());
+        *response.body_mut() = stall_body(body, stall);
+    }
+    // cut the body once a part of it is sent
+    if let Some(truncate) = actions
+        .truncate
+        .as_ref()
+        .filter(|_| body_allowed(method, response.status()))
+    {
+        truncate.apply(&mut response).await?;
+    }
+    conform_response(method, &mut response);
+
+    debug!("action applied: {}", response.status());
     Ok(response)
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::handler::http::action::{append_queries, replace_path};
+    use std::convert::TryInto;
+    use std::time::Duration;
+
+    use http::header::{CONTENT_LENGTH, CONTENT_TYPE, RETRY_AFTER};
+    use http::{HeaderMap, Method, Request, Response, StatusCode};
+    use hyper::Body;
+
+    use crate::clock::SystemClock;
+    use crate::handler::http::action::{
+        append_queries, apply_response_action, echo_applied, problem_json, render_location,
+        replace_path, synthesize_response, AbortResponse, Actions, FaultMarkers, APPLIED_HEADER,
+        FAULTS_HEADER, PROBLEM_JSON, RULE_HEADER,
+    };
+    use crate::handler::http::rule::Rule;
+    use crate::raw_config::{RawActions, RawRule};
 
     #[test]
     fn test_append_queries() {
@@ -298,6 +995,216 @@ mod tests {
         assert_eq!(&uri.to_string(), "https://hyper.rs/hhh?a=b");
     }
 
+    #[test]
+    fn test_render_location() {
+        let uri: http::Uri = "/api/v1?a=b".parse().unwrap();
+        assert_eq!(
+            render_location("https://{host}{path}?{query}", &uri, Some("hyper.rs")),
+            "https://hyper.rs/api/v1?a=b"
+        );
+        assert_eq!(
+            render_location("/login?from={path}", &uri, None),
+            "/login?from=/api/v1"
+        );
+    }
+
+    #[test]
+    fn test_redirect_code() {
+        let rule = |code: u16| -> anyhow::Result<Rule> {
+            let yaml = format!(
+                "{{target: Request, selector: {{}}, \
+                 actions: {{redirect: {{code: {}, location: /}}}}}}",
+                code
+            );
+            serde_yaml::from_str::<RawRule>(&yaml)?.try_into()
+        };
+        for code in [301, 302, 303, 307, 308] {
+            assert!(rule(code).is_ok());
+        }
+        assert!(rule(304).is_err());
+        assert!(rule(300).is_err());
+        assert!(rule(200).is_err());
+    }
+
+    #[test]
+    fn test_synthesize_response() {
+        let request = Request::new(Body::empty());
+        let mut actions = Actions::builder().build().unwrap();
+        assert!(synthesize_response(&request, &actions, &SystemClock)
+            .unwrap()
+            .is_none());
+
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, "120".parse().unwrap());
+        actions.abort_response = Some(AbortResponse {
+            code: StatusCode::SERVICE_UNAVAILABLE,
+            headers: Some(headers),
+            body: b"unavailable".to_vec(),
+        });
+        let response = synthesize_response(&request, &actions, &SystemClock)
+            .unwrap()
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[RETRY_AFTER], "120");
+
+        actions.delay = Some(Duration::from_secs(2));
+        let applied = actions.summary();
+        assert_eq!(applied, vec!["abort.code=503", "delay=2s"]);
+        let mut response = response;
+        echo_applied(&mut response, &applied).unwrap();
+        assert_eq!(
+            response.headers()[APPLIED_HEADER],
+            "abort.code=503;delay=2s"
+        );
+
+        actions.abort_response.as_mut().unwrap().body.clear();
+        let mut response = synthesize_response(&request, &actions, &SystemClock)
+            .unwrap()
+            .unwrap();
+        problem_json(&mut response, request.uri(), 2, &actions).unwrap();
+        assert_eq!(response.headers()[CONTENT_TYPE], PROBLEM_JSON);
+        let body =
+            futures::executor::block_on(hyper::body::to_bytes(response.into_body())).unwrap();
+        let document: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(document["status"], 503);
+        assert_eq!(document["title"], "Service Unavailable");
+        assert_eq!(document["chaos"]["rule"], 2);
+
+        let mut headers = HeaderMap::new();
+        let mut markers = FaultMarkers::default();
+        markers.mark(&mut headers).unwrap();
+        assert!(headers.is_empty());
+        markers.add("slow-api".to_string(), &actions);
+        markers.add("#1".to_string(), &actions);
+        markers.mark(&mut headers).unwrap();
+        assert_eq!(headers[RULE_HEADER], "slow-api,#1");
+        assert_eq!(
+            headers[FAULTS_HEADER],
+            "abort.code=503,delay=2s,abort.code=503,delay=2s"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_apply_response_action_semantics() {
+        let upstream = || {
+            Response::builder()
+                .header(CONTENT_LENGTH, "8")
+                .body(Body::from("upstream"))
+                .unwrap()
+        };
+        let replace = Rule::response().replace_body("hello").build().unwrap();
+        let response =
+            apply_response_action(upstream(), &Method::GET, &replace.actions, &SystemClock)
+                .await
+                .unwrap();
+        assert_eq!(response.headers()[CONTENT_LENGTH], "5");
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "hello");
+
+        // the response to HEAD keeps the length of the upstream body it stands for
+        let response =
+            apply_response_action(upstream(), &Method::HEAD, &replace.actions, &SystemClock)
+                .await
+                .unwrap();
+        assert_eq!(response.headers()[CONTENT_LENGTH], "8");
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(body.is_empty());
+
+        let no_content = Rule::response()
+            .replace_code(204)
+            .replace_body("hello")
+            .build()
+            .unwrap();
+        let response =
+            apply_response_action(upstream(), &Method::GET, &no_content.actions, &SystemClock)
+                .await
+                .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(response.headers().get(CONTENT_LENGTH).is_none());
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(body.is_empty());
+
+        let not_modified = Rule::request().abort_with(304).build().unwrap();
+        let request = Request::head("/").body(Body::empty()).unwrap();
+        let response = synthesize_response(&request, &not_modified.actions, &SystemClock)
+            .unwrap()
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(body.is_empty());
+    }
+
+    #[test]
+    fn test_weighted_actions() {
+        let rule = |actions: &str| -> anyhow::Result<Rule> {
+            let yaml = format!("{{target: Request, selector: {{}}, actions: {}}}", actions);
+            serde_yaml::from_str::<RawRule>(&yaml)?.try_into()
+        };
+        let weighted = rule(
+            "{weighted: [{weight: 70}, {weight: 20, actions: {delay: 500ms}}, \
+             {weight: 10, actions: {abort: true}}]}",
+        )
+        .unwrap();
+        let (mut passed, mut delayed, mut aborted) = (0, 0, 0);
+        for _ in 0..1000 {
+            let actions = weighted.actions.sampled();
+            assert!(actions.weighted.is_none());
+            if actions.abort {
+                aborted += 1;
+            } else if actions.delay == Some(Duration::from_millis(500)) {
+                delayed += 1;
+            } else {
+                assert!(actions.summary().is_empty());
+                passed += 1;
+            }
+        }
+        assert!(passed > delayed && delayed > aborted && aborted > 0);
+
+        assert!(rule("{delay: 1s, weighted: [{weight: 1}]}").is_err());
+        assert!(rule("{weighted: [{weight: 0}]}").is_err());
+        assert!(rule("{weighted: [{weight: 1, actions: {weighted: [{weight: 1}]}}]}").is_err());
+        assert!(rule("{weighted: [{weight: 1, actions: {set_cookies: []}}]}").is_err());
+        // the mirrors are copied before the alternative is drawn, whatever holds the actions
+        let mirrored = "{weighted: [{weight: 1, actions: {mirror: {target: \"127.0.0.1:80\"}}}]}";
+        assert!(rule(mirrored).is_err());
+        let actions: anyhow::Result<Actions> = serde_yaml::from_str::<RawActions>(mirrored)
+            .unwrap()
+            .try_into();
+        assert!(actions.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_remove_headers() {
+        let rule: RawRule = serde_yaml::from_str(
+            "{target: Response, selector: {response_headers: {x-internal-*: ~}}, \
+             actions: {remove_headers: [x-internal-*, /^x-debug/], \
+             replace: {headers: {x-internal-user: bob}}}}",
+        )
+        .unwrap();
+        let rule: Rule = rule.try_into().unwrap();
+        let response = Response::builder()
+            .header("x-internal-token", "secret")
+            .header("x-internal-user", "alice")
+            .header("X-Debug-Trace", "1")
+            .header("x-request-id", "1")
+            .body(Body::empty())
+            .unwrap();
+        let response = apply_response_action(response, &Method::GET, &rule.actions, &SystemClock)
+            .await
+            .unwrap();
+        let mut headers: Vec<_> = response
+            .headers()
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value.to_str().unwrap()))
+            .collect();
+        headers.sort();
+        assert_eq!(headers, vec!["x-internal-user=bob", "x-request-id=1"]);
+        assert_eq!(
+            rule.actions.summary(),
+            vec!["remove_headers", "replace.headers"]
+        );
+    }
+
     #[test]
     fn test_replace_queries() {
         //todo
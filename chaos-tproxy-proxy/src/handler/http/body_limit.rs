@@ -0,0 +1,129 @@
+use anyhow::Result;
+use bytes::{Bytes, BytesMut};
+use futures::{stream, StreamExt};
+use http::header::{CONTENT_LENGTH, TRANSFER_ENCODING};
+use http::HeaderMap;
+use hyper::body::HttpBody;
+use hyper::Body;
+
+/// BodyLimit caps the bodies buffered by the proxy, e.g. to rewrite, mirror or validate them, so
+/// that a large body does not exhaust the memory of a sidecar.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct BodyLimit {
+    pub max: usize,
+    pub overflow: Overflow,
+}
+
+/// Overflow introduces how a body over the limit is handled.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Overflow {
+    /// Stream the body untouched, the rules needing it are skipped for the exchange.
+    PassThrough,
+    /// Reject the exchange, the requests get 413 and the responses are replaced by 502.
+    Reject,
+    /// Cut the body at the limit.
+    Truncate,
+}
+
+/// Limited tells how a body is left by [limit_body].
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Limited {
+    /// Buffered is a body within the limit, or truncated to it.
+    Buffered,
+    /// Streamed is a body over the limit passed through, which should not be buffered.
+    Streamed,
+    /// Rejected is a body over the limit of an exchange to reject.
+    Rejected,
+}
+
+/// limit_body reads the body up to the limit before it is buffered by the rules. The bodies
+/// announced by `Content-Length` over the limit are never read.
+pub async fn limit_body(
+    headers: &mut HeaderMap,
+    body: &mut Body,
+    limit: BodyLimit,
+) -> Result<Limited> {
+    let mut read = BytesMut::new();
+    if body.size_hint().lower() <= limit.max as u64 {
+        while let Some(chunk) = body.data().await {
+            read.extend_from_slice(&chunk?);
+            if read.len() > limit.max {
+                break;
+            }
+        }
+        if read.len() <= limit.max {
+            *body = read.freeze().into();
+            return Ok(Limited::Buffered);
+        }
+    }
+    match limit.overflow {
+        Overflow::PassThrough => {
+            let rest = std::mem::take(body);
+            let read = stream::once(async move { Ok::<_, hyper::Error>(read.freeze()) });
+            *body = Body::wrap_stream(read.chain(rest));
+            Ok(Limited::Streamed)
+        }
+        Overflow::Reject => Ok(Limited::Rejected),
+        Overflow::Truncate => {
+            while read.len() < limit.max {
+                match body.data().await {
+                    Some(chunk) => read.extend_from_slice(&chunk?),
+                    None => break,
+                }
+            }
+            read.truncate(limit.max);
+            let read: Bytes = read.freeze();
+            headers.remove(TRANSFER_ENCODING);
+            headers.insert(CONTENT_LENGTH, read.len().into());
+            *body = read.into();
+            Ok(Limited::Buffered)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::header::CONTENT_LENGTH;
+    use http::HeaderMap;
+    use hyper::Body;
+
+    use crate::handler::http::body_limit::{limit_body, BodyLimit, Limited, Overflow};
+
+    fn chunked(chunks: &'static [&'static str]) -> Body {
+        Body::wrap_stream(futures::stream::iter(
+            chunks.iter().map(|chunk| Ok::<_, std::io::Error>(*chunk)),
+        ))
+    }
+
+    #[tokio::test]
+    async fn test_limit_body() {
+        let limit = |overflow| BodyLimit { max: 4, overflow };
+
+        let mut headers = HeaderMap::new();
+        let mut body = chunked(&["ab", "cd"]);
+        let limited = limit_body(&mut headers, &mut body, limit(Overflow::Reject)).await;
+        assert_eq!(limited.unwrap(), Limited::Buffered);
+        assert_eq!(hyper::body::to_bytes(body).await.unwrap(), "abcd");
+
+        let mut body = chunked(&["ab", "cd", "ef"]);
+        let limited = limit_body(&mut headers, &mut body, limit(Overflow::PassThrough)).await;
+        assert_eq!(limited.unwrap(), Limited::Streamed);
+        assert_eq!(hyper::body::to_bytes(body).await.unwrap(), "abcdef");
+
+        let mut body = chunked(&["abc", "def"]);
+        let limited = limit_body(&mut headers, &mut body, limit(Overflow::Truncate)).await;
+        assert_eq!(limited.unwrap(), Limited::Buffered);
+        assert_eq!(headers[CONTENT_LENGTH], "4");
+        assert_eq!(hyper::body::to_bytes(body).await.unwrap(), "abcd");
+
+        // the length is known without reading the body
+        let mut body = Body::from("abcdef");
+        let limited = limit_body(&mut headers, &mut body, limit(Overflow::Reject)).await;
+        assert_eq!(limited.unwrap(), Limited::Rejected);
+
+        let mut body = Body::from("abcdef");
+        let limited = limit_body(&mut headers, &mut body, limit(Overflow::Truncate)).await;
+        assert_eq!(limited.unwrap(), Limited::Buffered);
+        assert_eq!(hyper::body::to_bytes(body).await.unwrap(), "abcd");
+    }
+}
@@ -0,0 +1,430 @@
+use std::convert::TryInto;
+use std::time::Duration;
+
+use anyhow::Result;
+
+use crate::handler::http::action::Actions;
+use crate::handler::http::rule::Rule;
+use crate::handler::http::selector::Selector;
+use crate::raw_config::{
+    RawAbort, RawAbortMode, RawAbortResponse, RawActions, RawDelayPosition, RawReplaceAction,
+    RawReplaceBody, RawReplaceBodyContents, RawRule, RawSelector, RawTarget,
+};
+
+/// RuleBuilder builds a [Rule] in code, e.g. for the embedded proxy or the tests. It fills the
+/// same fields as the `rules` of the config file, which are validated at [build](Self::build).
+///
+/// ```
+/// use chaos_tproxy_proxy::handler::http::rule::Rule;
+///
+/// let rule = Rule::request().path("/api/*").delay_ms(200).abort().build().unwrap();
+/// assert!(rule.actions.abort);
+/// ```
+#[derive(Debug, Clone)]
+pub struct RuleBuilder {
+    rule: RawRule,
+}
+
+impl Rule {
+    /// request starts a rule affecting the requests.
+    pub fn request() -> RuleBuilder {
+        RuleBuilder::new(RawTarget::Request)
+    }
+
+    /// response starts a rule affecting the responses.
+    pub fn response() -> RuleBuilder {
+        RuleBuilder::new(RawTarget::Response)
+    }
+}
+
+impl RuleBuilder {
+    pub fn new(target: RawTarget) -> Self {
+        Self {
+            rule: RawRule {
+                name: None,
+                enabled: None,
+                duration: None,
+                priority: None,
+                target,
+                selector: Default::default(),
+                actions: Default::default(),
+                decode_body: None,
+                echo_applied: None,
+                problem_json: None,
+                fault_marker: None,
+                follow_up: None,
+                sticky_on: None,
+            },
+        }
+    }
+
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.rule.name = Some(name.into());
+        self
+    }
+
+    pub fn priority(mut self, priority: i32) -> Self {
+        self.rule.priority = Some(priority);
+        self
+    }
+
+    /// disabled keeps the rule without applying it.
+    pub fn disabled(mut self) -> Self {
+        self.rule.enabled = Some(false);
+        self
+    }
+
+    /// duration expires the rule once it is loaded for the duration.
+    pub fn duration(mut self, duration: Duration) -> Self {
+        self.rule.duration = Some(duration);
+        self
+    }
+
+    /// inbound restricts the rule to the exchanges received by the local services.
+    pub fn inbound(mut self) -> Self {
+        self.rule.target = match self.rule.target {
+            RawTarget::Request | RawTarget::ClientRequest => RawTarget::ServerRequest,
+            RawTarget::Response | RawTarget::ClientResponse => RawTarget::ServerResponse,
+            target => target,
+        };
+        self
+    }
+
+    /// outbound restricts the rule to the exchanges sent by the local services.
+    pub fn outbound(mut self) -> Self {
+        self.rule.target = match self.rule.target {
+            RawTarget::Request | RawTarget::ServerRequest => RawTarget::ClientRequest,
+            RawTarget::Response | RawTarget::ServerResponse => RawTarget::ClientResponse,
+            target => target,
+        };
+        self
+    }
+
+    pub fn decode_body(mut self) -> Self {
+        self.rule.decode_body = Some(true);
+        self
+    }
+
+    pub fn echo_applied(mut self) -> Self {
+        self.rule.echo_applied = Some(true);
+        self
+    }
+
+    pub fn problem_json(mut self) -> Self {
+        self.rule.problem_json = Some(true);
+        self
+    }
+
+    /// selector replaces the selector built so far.
+    pub fn selector(mut self, selector: SelectorBuilder) -> Self {
+        self.rule.selector = selector.selector;
+        self
+    }
+
+    /// actions replaces the actions built so far.
+    pub fn actions(mut self, actions: ActionsBuilder) -> Self {
+        self.rule.actions = actions.actions;
+        self
+    }
+
+    pub fn port(self, port: u16) -> Self {
+        self.with_selector(|selector| selector.port(port))
+    }
+
+    pub fn path(self, path: impl Into<String>) -> Self {
+        self.with_selector(|selector| selector.path(path))
+    }
+
+    pub fn method(self, method: impl Into<String>) -> Self {
+        self.with_selector(|selector| selector.method(method))
+    }
+
+    pub fn code(self, code: u16) -> Self {
+        self.with_selector(|selector| selector.code(code))
+    }
+
+    pub fn request_header(self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.with_selector(|selector| selector.request_header(name, value))
+    }
+
+    pub fn response_header(self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.with_selector(|selector| selector.response_header(name, value))
+    }
+
+    pub fn abort(self) -> Self {
+        self.with_actions(ActionsBuilder::abort)
+    }
+
+    pub fn abort_with(self, code: u16) -> Self {
+        self.with_actions(|actions| actions.abort_with(code))
+    }
+
+    pub fn abort_mode(self, mode: RawAbortMode) -> Self {
+        self.with_actions(|actions| actions.abort_mode(mode))
+    }
+
+    pub fn delay(self, delay: Duration) -> Self {
+        self.with_actions(|actions| actions.delay(delay))
+    }
+
+    pub fn delay_ms(self, millis: u64) -> Self {
+        self.delay(Duration::from_millis(millis))
+    }
+
+    pub fn replace_code(self, code: u16) -> Self {
+        self.with_actions(|actions| actions.replace_code(code))
+    }
+
+    pub fn replace_body(self, body: impl Into<String>) -> Self {
+        self.with_actions(|actions| actions.replace_body(body))
+    }
+
+    pub fn replace_header(self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.with_actions(|actions| actions.replace_header(name, value))
+    }
+
+    /// raw returns the rule in the syntax of the config file, e.g. to be served by the controller.
+    pub fn raw(self) -> RawRule {
+        self.rule
+    }
+
+    /// build checks the rule as the config file is checked, e.g. an action unavailable on the
+    /// target or an invalid header fails.
+    pub fn build(self) -> Result<Rule> {
+        self.rule.try_into()
+    }
+
+    fn with_selector(mut self, f: impl FnOnce(SelectorBuilder) -> SelectorBuilder) -> Self {
+        let selector = std::mem::take(&mut self.rule.selector);
+        self.rule.selector = f(SelectorBuilder { selector }).selector;
+        self
+    }
+
+    fn with_actions(mut self, f: impl FnOnce(ActionsBuilder) -> ActionsBuilder) -> Self {
+        let actions = std::mem::take(&mut self.rule.actions);
+        self.rule.actions = f(ActionsBuilder { actions }).actions;
+        self
+    }
+}
+
+/// SelectorBuilder builds a [Selector], the fields left unset match everything.
+#[derive(Debug, Clone, Default)]
+pub struct SelectorBuilder {
+    selector: RawSelector,
+}
+
+impl Selector {
+    pub fn builder() -> SelectorBuilder {
+        SelectorBuilder::default()
+    }
+}
+
+impl SelectorBuilder {
+    pub fn port(mut self, port: u16) -> Self {
+        self.selector.port = Some(port);
+        self
+    }
+
+    /// path matches the path of the requests, with wildcards.
+    pub fn path(mut self, path: impl Into<String>) -> Self {
+        self.selector.path = Some(path.into());
+        self
+    }
+
+    pub fn method(mut self, method: impl Into<String>) -> Self {
+        self.selector.method = Some(method.into());
+        self
+    }
+
+    pub fn code(mut self, code: u16) -> Self {
+        self.selector.code = Some(code);
+        self
+    }
+
+    pub fn request_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.selector
+            .request_headers
+            .get_or_insert_with(Default::default)
+            .insert(name.into(), Some(value.into()));
+        self
+    }
+
+    pub fn response_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.selector
+            .response_headers
+            .get_or_insert_with(Default::default)
+            .insert(name.into(), Some(value.into()));
+        self
+    }
+
+    /// label matches a label of the client resolved by `metadata`.
+    pub fn label(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.selector
+            .labels
+            .get_or_insert_with(Default::default)
+            .insert(name.into(), value.into());
+        self
+    }
+
+    pub fn build(self) -> Result<Selector> {
+        self.selector.try_into()
+    }
+}
+
+/// ActionsBuilder builds the [Actions] of a rule, the actions unavailable on the target of the
+/// rule are only reported by [RuleBuilder::build].
+#[derive(Debug, Clone, Default)]
+pub struct ActionsBuilder {
+    actions: RawActions,
+}
+
+impl Actions {
+    pub fn builder() -> ActionsBuilder {
+        ActionsBuilder::default()
+    }
+}
+
+impl ActionsBuilder {
+    pub fn abort(mut self) -> Self {
+        self.actions.abort = Some(RawAbort::Enabled(true));
+        self
+    }
+
+    /// abort_with responds with the code without calling the upstream.
+    pub fn abort_with(mut self, code: u16) -> Self {
+        self.actions.abort = Some(RawAbort::Response(RawAbortResponse {
+            code,
+            headers: None,
+            body: None,
+        }));
+        self
+    }
+
+    pub fn abort_mode(mut self, mode: RawAbortMode) -> Self {
+        self.actions.abort_mode = Some(mode);
+        self
+    }
+
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.actions.delay = Some(delay);
+        self
+    }
+
+    pub fn delay_ms(self, millis: u64) -> Self {
+        self.delay(Duration::from_millis(millis))
+    }
+
+    pub fn delay_position(mut self, position: RawDelayPosition) -> Self {
+        self.actions.delay_position = Some(position);
+        self
+    }
+
+    pub fn replace_path(self, path: impl Into<String>) -> Self {
+        self.with_replace(|replace| replace.path = Some(path.into()))
+    }
+
+    pub fn replace_method(self, method: impl Into<String>) -> Self {
+        self.with_replace(|replace| replace.method = Some(method.into()))
+    }
+
+    pub fn replace_code(self, code: u16) -> Self {
+        self.with_replace(|replace| replace.code = Some(code))
+    }
+
+    pub fn replace_body(self, body: impl Into<String>) -> Self {
+        self.with_replace(|replace| {
+            replace.body = Some(RawReplaceBody {
+                contents: RawReplaceBodyContents::TEXT(body.into()),
+            })
+        })
+    }
+
+    pub fn replace_header(self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.with_replace(|replace| {
+            replace
+                .headers
+                .get_or_insert_with(Default::default)
+                .insert(name.into(), value.into());
+        })
+    }
+
+    pub fn build(self) -> Result<Actions> {
+        self.actions.try_into()
+    }
+
+    fn with_replace(mut self, f: impl FnOnce(&mut RawReplaceAction)) -> Self {
+        f(self.actions.replace.get_or_insert_with(Default::default));
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use http::Method;
+
+    use crate::handler::http::action::Actions;
+    use crate::handler::http::rule::{Direction, Rule, Target};
+    use crate::handler::http::selector::Selector;
+    use crate::raw_config::RawDelayPosition;
+
+    #[test]
+    fn test_builder() {
+        let rule = Rule::request()
+            .name("slow-api")
+            .priority(10)
+            .inbound()
+            .path("/api/*")
+            .method("POST")
+            .delay_ms(200)
+            .abort()
+            .build()
+            .unwrap();
+        assert_eq!(rule.name.as_deref(), Some("slow-api"));
+        assert_eq!(rule.priority, 10);
+        assert_eq!(rule.target, Target::Request);
+        assert_eq!(rule.direction, Some(Direction::Inbound));
+        assert!(rule.selector.path.unwrap().matches("/api/users"));
+        assert_eq!(rule.selector.method, Some(Method::POST));
+        assert_eq!(rule.actions.delay, Some(Duration::from_millis(200)));
+        assert!(rule.actions.abort);
+
+        let rule = Rule::response()
+            .code(200)
+            .replace_code(503)
+            .replace_header("retry-after", "1")
+            .build()
+            .unwrap();
+        let replace = rule.actions.replace.unwrap();
+        assert_eq!(replace.code.unwrap().as_u16(), 503);
+        assert_eq!(replace.headers.unwrap()["retry-after"], "1");
+
+        // validated as the config file
+        assert!(Rule::request().method("NOT A METHOD").build().is_err());
+        assert!(Rule::response()
+            .actions(
+                Actions::builder()
+                    .delay_ms(10)
+                    .delay_position(RawDelayPosition::BeforeForward)
+            )
+            .build()
+            .is_err());
+        assert!(Rule::request()
+            .duration(Duration::from_secs(600))
+            .abort()
+            .build()
+            .is_err());
+
+        let selector = Selector::builder()
+            .port(8080)
+            .request_header("x-test", "1")
+            .build()
+            .unwrap();
+        assert_eq!(selector.port, Some(8080));
+        assert_eq!(
+            selector.request_headers.unwrap().get("x-test").unwrap(),
+            "1"
+        );
+    }
+}
@@ -0,0 +1,164 @@
+use std::net::{IpAddr, SocketAddr};
+
+use anyhow::{anyhow, Result};
+use http::header::{HeaderName, FORWARDED};
+use http::{HeaderMap, HeaderValue};
+
+/// ClientAddr is attached to the extensions of the requests, it is the client told by the PROXY
+/// header, or the peer of the connection.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct ClientAddr(pub SocketAddr);
+
+/// ClientIpAction rewrites the headers telling the client to the upstream, to test whether the
+/// upstream trusts them.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ClientIpAction {
+    pub mode: ClientIpMode,
+    /// address told to the upstream instead of the client, e.g. a falsified one.
+    pub address: Option<IpAddr>,
+    pub headers: Vec<ClientIpHeader>,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ClientIpMode {
+    /// Append appends the address to the lists of `X-Forwarded-For` and `Forwarded`, as a proxy
+    /// does. `X-Real-IP` is not a list, so it is replaced.
+    Append,
+    /// Set replaces the headers with the address only.
+    Set,
+    /// Strip removes the headers.
+    Strip,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ClientIpHeader {
+    XForwardedFor,
+    XRealIp,
+    Forwarded,
+}
+
+impl ClientIpHeader {
+    fn name(self) -> HeaderName {
+        match self {
+            ClientIpHeader::XForwardedFor => HeaderName::from_static("x-forwarded-for"),
+            ClientIpHeader::XRealIp => HeaderName::from_static("x-real-ip"),
+            ClientIpHeader::Forwarded => FORWARDED,
+        }
+    }
+
+    /// element returns the element of the header telling the address.
+    fn element(self, address: IpAddr) -> String {
+        match (self, address) {
+            // the IPv6 addresses are quoted and bracketed in the node of RFC 7239
+            (ClientIpHeader::Forwarded, IpAddr::V6(ip)) => format!("for=\"[{}]\"", ip),
+            (ClientIpHeader::Forwarded, IpAddr::V4(ip)) => format!("for={}", ip),
+            _ => address.to_string(),
+        }
+    }
+}
+
+impl ClientIpAction {
+    /// apply rewrites the headers, the address is the client if it is not set.
+    pub fn apply(&self, headers: &mut HeaderMap, client: Option<IpAddr>) -> Result<()> {
+        let address = match self.mode {
+            ClientIpMode::Strip => None,
+            _ => Some(
+                self.address
+                    .or(client)
+                    .ok_or_else(|| anyhow!("the client is unknown, client_ip needs an address"))?,
+            ),
+        };
+        for header in &self.headers {
+            let name = header.name();
+            // the repeated headers are combined into one list
+            let existing: Vec<_> = headers
+                .get_all(&name)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .map(str::to_string)
+                .collect();
+            headers.remove(&name);
+            let address = match address {
+                None => continue,
+                Some(address) => address,
+            };
+            let mut elements = match (self.mode, header) {
+                (
+                    ClientIpMode::Append,
+                    ClientIpHeader::XForwardedFor | ClientIpHeader::Forwarded,
+                ) => existing,
+                _ => vec![],
+            };
+            elements.push(header.element(address));
+            headers.insert(name, HeaderValue::from_str(&elements.join(", "))?);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::header::FORWARDED;
+    use http::{HeaderMap, HeaderValue};
+
+    use crate::handler::http::client_ip::{ClientIpAction, ClientIpHeader, ClientIpMode};
+
+    #[test]
+    fn test_client_ip() {
+        let headers = || {
+            let mut headers = HeaderMap::new();
+            headers.append("x-forwarded-for", HeaderValue::from_static("10.0.0.1"));
+            headers.append("x-forwarded-for", HeaderValue::from_static("10.0.0.2"));
+            headers.insert("x-real-ip", HeaderValue::from_static("10.0.0.1"));
+            headers.insert(FORWARDED, HeaderValue::from_static("for=10.0.0.1"));
+            headers
+        };
+        let all = vec![
+            ClientIpHeader::XForwardedFor,
+            ClientIpHeader::XRealIp,
+            ClientIpHeader::Forwarded,
+        ];
+        let client = Some("192.168.0.1".parse().unwrap());
+
+        let mut appended = headers();
+        let action = ClientIpAction {
+            mode: ClientIpMode::Append,
+            address: None,
+            headers: all.clone(),
+        };
+        action.apply(&mut appended, client).unwrap();
+        assert_eq!(
+            appended["x-forwarded-for"],
+            "10.0.0.1, 10.0.0.2, 192.168.0.1"
+        );
+        assert_eq!(appended["x-real-ip"], "192.168.0.1");
+        assert_eq!(appended[FORWARDED], "for=10.0.0.1, for=192.168.0.1");
+
+        let mut set = headers();
+        let action = ClientIpAction {
+            mode: ClientIpMode::Set,
+            address: Some("2001:db8::1".parse().unwrap()),
+            headers: vec![ClientIpHeader::XForwardedFor, ClientIpHeader::Forwarded],
+        };
+        action.apply(&mut set, client).unwrap();
+        assert_eq!(set["x-forwarded-for"], "2001:db8::1");
+        assert_eq!(set["x-real-ip"], "10.0.0.1");
+        assert_eq!(set[FORWARDED], "for=\"[2001:db8::1]\"");
+
+        let mut stripped = headers();
+        let action = ClientIpAction {
+            mode: ClientIpMode::Strip,
+            address: None,
+            headers: all.clone(),
+        };
+        action.apply(&mut stripped, None).unwrap();
+        assert!(stripped.is_empty());
+
+        let action = ClientIpAction {
+            mode: ClientIpMode::Set,
+            address: None,
+            headers: all,
+        };
+        assert!(action.apply(&mut headers(), None).is_err());
+    }
+}
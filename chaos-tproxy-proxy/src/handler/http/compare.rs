@@ -0,0 +1,96 @@
+use bytes::Bytes;
+use http::header::{HeaderName, DATE};
+use http::response::Parts;
+use http::StatusCode;
+
+/// ResponseDiff introduces the differences between the response of the untouched (shadow) request
+/// and the response which has passed through the chaos actions.
+#[derive(Debug, Default, Eq, PartialEq, Clone)]
+pub struct ResponseDiff {
+    /// (shadow status, actual status) if they are different.
+    pub status: Option<(StatusCode, StatusCode)>,
+    /// names of the headers whose values are different or missing on one side.
+    pub headers: Vec<HeaderName>,
+    pub body: bool,
+}
+
+impl ResponseDiff {
+    pub fn is_empty(&self) -> bool {
+        self.status.is_none() && self.headers.is_empty() && !self.body
+    }
+}
+
+/// diff_response would compare the shadow response with the actual response.
+pub fn diff_response(
+    shadow: &Parts,
+    shadow_body: &Bytes,
+    actual: &Parts,
+    actual_body: &Bytes,
+) -> ResponseDiff {
+    let status = if shadow.status != actual.status {
+        Some((shadow.status, actual.status))
+    } else {
+        None
+    };
+
+    let mut headers: Vec<HeaderName> = shadow
+        .headers
+        .keys()
+        .chain(actual.headers.keys())
+        // the date is expected to be different between two exchanges
+        .filter(|name| **name != DATE)
+        .filter(|name| {
+            !shadow
+                .headers
+                .get_all(*name)
+                .iter()
+                .eq(actual.headers.get_all(*name).iter())
+        })
+        .cloned()
+        .collect();
+    headers.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+    headers.dedup();
+
+    ResponseDiff {
+        status,
+        headers,
+        body: shadow_body != actual_body,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use http::{Response, StatusCode};
+
+    use crate::handler::http::compare::diff_response;
+
+    #[test]
+    fn test_diff_response() {
+        let (shadow, _) = Response::builder()
+            .header("date", "Mon, 03 May 2021 11:21:31 GMT")
+            .header("x-a", "a")
+            .body(())
+            .unwrap()
+            .into_parts();
+        let (actual, _) = Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .header("date", "Mon, 03 May 2021 11:21:32 GMT")
+            .header("x-a", "a")
+            .header("x-b", "b")
+            .body(())
+            .unwrap()
+            .into_parts();
+
+        let diff = diff_response(&shadow, &Bytes::from("a"), &shadow, &Bytes::from("a"));
+        assert!(diff.is_empty());
+
+        let diff = diff_response(&shadow, &Bytes::from("a"), &actual, &Bytes::from("b"));
+        assert_eq!(
+            diff.status,
+            Some((StatusCode::OK, StatusCode::SERVICE_UNAVAILABLE))
+        );
+        assert_eq!(diff.headers, vec!["x-b"]);
+        assert!(diff.body);
+    }
+}
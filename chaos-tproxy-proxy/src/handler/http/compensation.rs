@@ -0,0 +1,102 @@
+use std::borrow::Cow;
+use std::time::{Duration, Instant};
+
+use crate::handler::http::action::{Actions, DelayPosition};
+
+/// Compensation measures the own processing time of the proxy in an exchange, which is cut from
+/// the delays of the actions, so that the delays are added to the end-to-end latency exactly.
+#[derive(Debug)]
+pub struct Compensation {
+    enabled: bool,
+    started: Instant,
+    /// time spent out of the proxy, e.g. waiting for the upstream or sleeping for the delays.
+    excluded: Duration,
+    /// processing time already cut from the delays.
+    compensated: Duration,
+}
+
+impl Compensation {
+    /// start starts measuring the processing time, the delays are untouched if not enabled.
+    pub fn start(enabled: bool) -> Self {
+        Self {
+            enabled,
+            started: Instant::now(),
+            excluded: Duration::ZERO,
+            compensated: Duration::ZERO,
+        }
+    }
+
+    /// exclude excludes the time spent out of the proxy from the processing time.
+    pub fn exclude(&mut self, duration: Duration) {
+        self.excluded += duration;
+    }
+
+    /// compensate cuts the processing time not compensated yet from the delay, the returned
+    /// delay should be slept at once.
+    pub fn compensate(&mut self, delay: Duration) -> Duration {
+        if !self.enabled {
+            return delay;
+        }
+        let processing = self.started.elapsed().saturating_sub(self.excluded);
+        let cut = processing.saturating_sub(self.compensated).min(delay);
+        self.compensated += cut;
+        self.excluded += delay - cut;
+        delay - cut
+    }
+
+    /// request_actions returns the actions with the delay before forwarding compensated.
+    pub fn request_actions<'a>(&mut self, actions: &'a Actions) -> Cow<'a, Actions> {
+        match (actions.delay, actions.delay_position) {
+            (Some(delay), DelayPosition::BeforeForward) if self.enabled => {
+                self.compensated_actions(actions, delay)
+            }
+            _ => Cow::Borrowed(actions),
+        }
+    }
+
+    /// response_actions returns the actions of a response-target rule with the delay compensated.
+    pub fn response_actions<'a>(&mut self, actions: &'a Actions) -> Cow<'a, Actions> {
+        match actions.delay {
+            Some(delay) if self.enabled => self.compensated_actions(actions, delay),
+            _ => Cow::Borrowed(actions),
+        }
+    }
+
+    fn compensated_actions<'a>(
+        &mut self,
+        actions: &'a Actions,
+        delay: Duration,
+    ) -> Cow<'a, Actions> {
+        let mut actions = actions.clone();
+        actions.delay = Some(self.compensate(delay));
+        Cow::Owned(actions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    use crate::handler::http::compensation::Compensation;
+
+    #[test]
+    fn test_compensate() {
+        let mut compensation = Compensation::start(true);
+        sleep(Duration::from_millis(20));
+        let delay = compensation.compensate(Duration::from_millis(100));
+        assert!(delay <= Duration::from_millis(80));
+        assert!(delay > Duration::from_millis(60));
+        // the time is compensated once, and the delay itself is not processing time
+        sleep(delay);
+        let delay = compensation.compensate(Duration::from_millis(100));
+        assert!(delay > Duration::from_millis(90));
+
+        let mut compensation = Compensation::start(false);
+        sleep(Duration::from_millis(20));
+        assert_eq!(
+            compensation.compensate(Duration::from_millis(100)),
+            Duration::from_millis(100)
+        );
+    }
+}
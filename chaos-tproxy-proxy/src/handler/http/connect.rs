@@ -0,0 +1,90 @@
+use std::fmt;
+use std::time::Duration;
+
+use crate::clock::Clock;
+
+/// ConnectFailure is how the connection to the upstream fails.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ConnectFailure {
+    /// The upstream refuses the connection, as if it were down.
+    Refused,
+    /// The host of the upstream does not resolve.
+    DnsFailure,
+}
+
+impl fmt::Display for ConnectFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectFailure::Refused => write!(f, "connection refused"),
+            ConnectFailure::DnsFailure => write!(f, "dns error: failed to lookup address"),
+        }
+    }
+}
+
+/// ConnectFault delays or fails the connection the request is forwarded on, before the upstream
+/// sees the request, so that a backend down is told from a slow one. It is attached to the
+/// extensions of the request by the actions, and run when the request is forwarded.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConnectFault {
+    pub delay: Option<Duration>,
+    pub failure: Option<ConnectFailure>,
+}
+
+impl ConnectFault {
+    /// merge combines the faults of the rules applied to a request, the delays add up and the
+    /// failure of the first rule wins.
+    pub fn merge(self, other: &ConnectFault) -> Self {
+        let delay = match (self.delay, other.delay) {
+            (Some(a), Some(b)) => Some(a + b),
+            (a, b) => a.or(b),
+        };
+        Self {
+            delay,
+            failure: self.failure.or(other.failure),
+        }
+    }
+
+    /// run waits for the delay by the clock, and returns the failure of the connection if any.
+    pub async fn run(&self, clock: &dyn Clock) -> Result<(), ConnectFailure> {
+        if let Some(delay) = self.delay {
+            clock.sleep(delay).await;
+        }
+        match self.failure {
+            Some(failure) => Err(failure),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::clock::SystemClock;
+    use crate::handler::http::connect::{ConnectFailure, ConnectFault};
+
+    #[tokio::test(start_paused = true)]
+    async fn test_connect_fault() {
+        let slow = ConnectFault {
+            delay: Some(Duration::from_secs(1)),
+            failure: None,
+        };
+        let refused = ConnectFault {
+            delay: Some(Duration::from_secs(2)),
+            failure: Some(ConnectFailure::Refused),
+        };
+        let dns_failure = ConnectFault {
+            delay: None,
+            failure: Some(ConnectFailure::DnsFailure),
+        };
+        let merged = slow.clone().merge(&refused).merge(&dns_failure);
+        assert_eq!(merged.delay, Some(Duration::from_secs(3)));
+        assert_eq!(merged.failure, Some(ConnectFailure::Refused));
+
+        let started = tokio::time::Instant::now();
+        assert_eq!(slow.run(&SystemClock).await, Ok(()));
+        assert_eq!(started.elapsed(), Duration::from_secs(1));
+        assert_eq!(merged.run(&SystemClock).await, Err(ConnectFailure::Refused));
+        assert_eq!(started.elapsed(), Duration::from_secs(4));
+    }
+}
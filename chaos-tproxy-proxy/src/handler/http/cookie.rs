@@ -0,0 +1,176 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use http::header::SET_COOKIE;
+use http::{HeaderMap, HeaderValue};
+
+/// SameSite is the `SameSite` attribute of a cookie.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+/// Cookie would be sent by a `Set-Cookie` header, the cookies with the same name set by the
+/// upstream would be replaced.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Cookie {
+    pub name: String,
+    pub value: String,
+    pub path: Option<String>,
+    pub domain: Option<String>,
+    pub max_age: Option<Duration>,
+    pub secure: bool,
+    pub http_only: bool,
+    pub same_site: Option<SameSite>,
+}
+
+impl Cookie {
+    pub fn new(name: String, value: String) -> Result<Self> {
+        if name.is_empty() || !name.bytes().all(is_token) {
+            return Err(anyhow!("invalid cookie name: {:?}", name));
+        }
+        if !value.bytes().all(is_cookie_octet) {
+            return Err(anyhow!("invalid cookie value: {:?}", value));
+        }
+        Ok(Self {
+            name,
+            value,
+            path: None,
+            domain: None,
+            max_age: None,
+            secure: false,
+            http_only: false,
+            same_site: None,
+        })
+    }
+
+    /// expired returns a cookie making the client delete the cookie with the given name, the path
+    /// and domain must be the same as the ones the cookie was set with.
+    pub fn expired(name: String, path: Option<String>, domain: Option<String>) -> Result<Self> {
+        Ok(Self {
+            path,
+            domain,
+            max_age: Some(Duration::ZERO),
+            ..Self::new(name, String::new())?
+        })
+    }
+
+    fn header_value(&self) -> Result<HeaderValue> {
+        let mut value = format!("{}={}", self.name, self.value);
+        if let Some(path) = &self.path {
+            value.push_str(&format!("; Path={}", path));
+        }
+        if let Some(domain) = &self.domain {
+            value.push_str(&format!("; Domain={}", domain));
+        }
+        if let Some(max_age) = self.max_age {
+            value.push_str(&format!("; Max-Age={}", max_age.as_secs()));
+            if max_age.is_zero() {
+                // for the clients ignoring Max-Age
+                value.push_str("; Expires=Thu, 01 Jan 1970 00:00:00 GMT");
+            }
+        }
+        if self.secure {
+            value.push_str("; Secure");
+        }
+        if self.http_only {
+            value.push_str("; HttpOnly");
+        }
+        if let Some(same_site) = self.same_site {
+            value.push_str(match same_site {
+                SameSite::Strict => "; SameSite=Strict",
+                SameSite::Lax => "; SameSite=Lax",
+                SameSite::None => "; SameSite=None",
+            });
+        }
+        Ok(HeaderValue::from_str(&value)?)
+    }
+}
+
+/// apply_cookies replaces the `Set-Cookie` headers of the cookies with the same names, and appends
+/// the others.
+pub fn apply_cookies<'a>(
+    headers: &mut HeaderMap,
+    cookies: impl IntoIterator<Item = &'a Cookie>,
+) -> Result<()> {
+    let cookies: Vec<_> = cookies.into_iter().collect();
+    let kept: Vec<HeaderValue> = headers
+        .get_all(SET_COOKIE)
+        .iter()
+        .filter(|value| {
+            let name = cookie_name(value.as_bytes());
+            !cookies.iter().any(|cookie| cookie.name.as_bytes() == name)
+        })
+        .cloned()
+        .collect();
+    headers.remove(SET_COOKIE);
+    for value in kept {
+        headers.append(SET_COOKIE, value);
+    }
+    for cookie in cookies {
+        headers.append(SET_COOKIE, cookie.header_value()?);
+    }
+    Ok(())
+}
+
+fn cookie_name(set_cookie: &[u8]) -> &[u8] {
+    let end = set_cookie
+        .iter()
+        .position(|b| *b == b'=' || *b == b';')
+        .unwrap_or(set_cookie.len());
+    set_cookie[..end].trim_ascii()
+}
+
+// token of RFC 7230
+fn is_token(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+// cookie-octet of RFC 6265
+fn is_cookie_octet(b: u8) -> bool {
+    matches!(b, 0x21 | 0x23..=0x2B | 0x2D..=0x3A | 0x3C..=0x5B | 0x5D..=0x7E)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use http::header::SET_COOKIE;
+    use http::HeaderMap;
+
+    use crate::handler::http::cookie::{apply_cookies, Cookie, SameSite};
+
+    #[test]
+    fn test_apply_cookies() {
+        let mut headers = HeaderMap::new();
+        headers.append(SET_COOKIE, "session=abc; Path=/".parse().unwrap());
+        headers.append(SET_COOKIE, "theme=dark".parse().unwrap());
+
+        let mut session = Cookie::new("session".to_string(), "expired".to_string()).unwrap();
+        session.path = Some("/".to_string());
+        session.max_age = Some(Duration::from_secs(60));
+        session.secure = true;
+        session.http_only = true;
+        session.same_site = Some(SameSite::Lax);
+        let theme = Cookie::expired("theme".to_string(), None, None).unwrap();
+        apply_cookies(&mut headers, &[session, theme]).unwrap();
+
+        let values: Vec<_> = headers
+            .get_all(SET_COOKIE)
+            .iter()
+            .map(|value| value.to_str().unwrap())
+            .collect();
+        assert_eq!(
+            values,
+            vec![
+                "session=expired; Path=/; Max-Age=60; Secure; HttpOnly; SameSite=Lax",
+                "theme=; Max-Age=0; Expires=Thu, 01 Jan 1970 00:00:00 GMT",
+            ]
+        );
+
+        assert!(Cookie::new("a b".to_string(), "c".to_string()).is_err());
+        assert!(Cookie::new("a".to_string(), "c;d".to_string()).is_err());
+    }
+}
@@ -0,0 +1,116 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use http::header::HeaderName;
+use http::Request;
+use hyper::Body;
+
+/// DedupMode introduces which attempts of the requests sharing an idempotency key are dropped.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum DedupMode {
+    /// Forward the first attempt and drop every retry.
+    Retries,
+    /// Drop the first attempt and forward the retries.
+    First,
+}
+
+/// DedupAction drops requests by their idempotency key, the keys are remembered for `ttl` since
+/// they are seen for the first time.
+#[derive(Debug, Clone)]
+pub struct DedupAction {
+    pub header: HeaderName,
+    pub mode: DedupMode,
+    cache: Arc<KeyCache>,
+}
+
+impl PartialEq for DedupAction {
+    fn eq(&self, other: &Self) -> bool {
+        self.header == other.header && self.mode == other.mode && self.cache.ttl == other.cache.ttl
+    }
+}
+
+impl Eq for DedupAction {}
+
+impl DedupAction {
+    pub fn new(header: HeaderName, ttl: Duration, mode: DedupMode) -> Self {
+        Self {
+            header,
+            mode,
+            cache: Arc::new(KeyCache::new(ttl)),
+        }
+    }
+
+    /// should_drop returns true if the request should be dropped, requests without the key are
+    /// never dropped.
+    pub fn should_drop(&self, request: &Request<Body>) -> bool {
+        let key = match request.headers().get(&self.header) {
+            None => return false,
+            Some(key) => key.as_bytes(),
+        };
+        let repeated = self.cache.check(key, Instant::now());
+        match self.mode {
+            DedupMode::Retries => repeated,
+            DedupMode::First => !repeated,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct KeyCache {
+    ttl: Duration,
+    inner: Mutex<KeyCacheInner>,
+}
+
+#[derive(Debug, Default)]
+struct KeyCacheInner {
+    seen: HashMap<Vec<u8>, Instant>,
+    // keys in the order they are seen, used to expire them.
+    order: VecDeque<(Instant, Vec<u8>)>,
+}
+
+impl KeyCache {
+    fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            inner: Default::default(),
+        }
+    }
+
+    /// check records the key and returns true if it has been seen within the ttl.
+    fn check(&self, key: &[u8], now: Instant) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        while let Some((at, _)) = inner.order.front() {
+            if now.duration_since(*at) < self.ttl {
+                break;
+            }
+            if let Some((_, expired)) = inner.order.pop_front() {
+                inner.seen.remove(&expired);
+            }
+        }
+        if inner.seen.contains_key(key) {
+            return true;
+        }
+        inner.seen.insert(key.to_vec(), now);
+        inner.order.push_back((now, key.to_vec()));
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::handler::http::dedup::KeyCache;
+
+    #[test]
+    fn test_key_cache() {
+        let cache = KeyCache::new(Duration::from_secs(10));
+        let now = Instant::now();
+        assert!(!cache.check(b"a", now));
+        assert!(cache.check(b"a", now + Duration::from_secs(1)));
+        assert!(!cache.check(b"b", now + Duration::from_secs(1)));
+        assert!(!cache.check(b"a", now + Duration::from_secs(10)));
+        assert!(cache.check(b"b", now + Duration::from_secs(10)));
+    }
+}
@@ -0,0 +1,82 @@
+use std::convert::Infallible;
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures::{future, stream, StreamExt};
+use hyper::Body;
+use tokio::time::sleep;
+
+/// DribbleAction sends the body `chunk_size` bytes at a time, spreading the chunks evenly over
+/// `duration`. The headers are sent as usual, so that the clients see a slow body instead of a
+/// slow response.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct DribbleAction {
+    pub chunk_size: usize,
+    pub duration: Duration,
+    /// never send the last chunk and keep the connection open.
+    pub never_finish: bool,
+}
+
+impl DribbleAction {
+    /// body returns the dribbling body of the contents.
+    pub fn body(&self, contents: Bytes) -> Body {
+        let mut chunks: Vec<Bytes> = (0..contents.len())
+            .step_by(self.chunk_size.max(1))
+            .map(|start| contents.slice(start..(start + self.chunk_size).min(contents.len())))
+            .collect();
+        if self.never_finish {
+            chunks.pop();
+        }
+        let interval = match chunks.len() {
+            0 => Duration::ZERO,
+            n => self.duration / n as u32,
+        };
+        let chunks = stream::iter(chunks).then(move |chunk| async move {
+            sleep(interval).await;
+            Ok::<_, Infallible>(chunk)
+        });
+        if self.never_finish {
+            Body::wrap_stream(chunks.chain(stream::once(future::pending())))
+        } else {
+            Body::wrap_stream(chunks)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use futures::StreamExt;
+    use tokio::time::{timeout, Instant};
+
+    use crate::handler::http::dribble::DribbleAction;
+
+    #[tokio::test]
+    async fn test_dribble() {
+        let action = DribbleAction {
+            chunk_size: 4,
+            duration: Duration::from_millis(100),
+            never_finish: false,
+        };
+        let started_at = Instant::now();
+        let chunks: Vec<_> = action
+            .body(Bytes::from_static(b"hello, world"))
+            .map(|chunk| chunk.unwrap())
+            .collect()
+            .await;
+        assert!(started_at.elapsed() >= Duration::from_millis(90));
+        assert_eq!(chunks, vec!["hell", "o, w", "orld"]);
+
+        let action = DribbleAction {
+            never_finish: true,
+            ..action
+        };
+        let mut body = action.body(Bytes::from_static(b"hello"));
+        assert_eq!(body.next().await.unwrap().unwrap(), "hell");
+        assert!(timeout(Duration::from_millis(200), body.next())
+            .await
+            .is_err());
+    }
+}
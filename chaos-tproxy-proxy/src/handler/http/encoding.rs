@@ -0,0 +1,152 @@
+use std::io::{Read, Write};
+
+use anyhow::Result;
+use flate2::read::{DeflateDecoder, GzDecoder};
+use flate2::write::{DeflateEncoder, GzEncoder};
+use flate2::Compression;
+use http::header::{HeaderMap, HeaderValue, CONTENT_ENCODING};
+use http::{Request, Response};
+use hyper::Body;
+
+use crate::handler::http::semantics::sized_body;
+
+/// Encoding introduces the supported `Content-Encoding`s of the body.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Encoding {
+    Gzip,
+    Deflate,
+    Brotli,
+}
+
+impl Encoding {
+    /// from_headers returns `None` if the body is not encoded, or encoded by an unsupported or
+    /// multiple encodings.
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let mut values = headers.get_all(CONTENT_ENCODING).iter();
+        let value = values.next()?.to_str().ok()?.trim().to_lowercase();
+        if values.next().is_some() {
+            return None;
+        }
+        match value.as_str() {
+            "gzip" | "x-gzip" => Some(Encoding::Gzip),
+            "deflate" => Some(Encoding::Deflate),
+            "br" => Some(Encoding::Brotli),
+            _ => None,
+        }
+    }
+
+    fn header_value(&self) -> HeaderValue {
+        HeaderValue::from_static(match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+            Encoding::Brotli => "br",
+        })
+    }
+
+    pub fn decode(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        let mut decoded = vec![];
+        match self {
+            Encoding::Gzip => GzDecoder::new(data).read_to_end(&mut decoded)?,
+            Encoding::Deflate => DeflateDecoder::new(data).read_to_end(&mut decoded)?,
+            Encoding::Brotli => brotli::Decompressor::new(data, 4096).read_to_end(&mut decoded)?,
+        };
+        Ok(decoded)
+    }
+
+    pub fn encode(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(vec![], Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            Encoding::Deflate => {
+                let mut encoder = DeflateEncoder::new(vec![], Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            Encoding::Brotli => {
+                let mut encoded = vec![];
+                {
+                    let mut encoder = brotli::CompressorWriter::new(&mut encoded, 4096, 5, 22);
+                    encoder.write_all(data)?;
+                }
+                Ok(encoded)
+            }
+        }
+    }
+}
+
+async fn decode_body(headers: &mut HeaderMap, body: Body) -> Result<(Body, Option<Encoding>)> {
+    let encoding = match Encoding::from_headers(headers) {
+        None => return Ok((body, None)),
+        Some(encoding) => encoding,
+    };
+    let data = hyper::body::to_bytes(body).await?;
+    let decoded = encoding.decode(&data)?;
+    headers.remove(CONTENT_ENCODING);
+    Ok((sized_body(headers, decoded), Some(encoding)))
+}
+
+async fn encode_body(headers: &mut HeaderMap, body: Body, encoding: Encoding) -> Result<Body> {
+    let data = hyper::body::to_bytes(body).await?;
+    let encoded = encoding.encode(&data)?;
+    headers.insert(CONTENT_ENCODING, encoding.header_value());
+    Ok(sized_body(headers, encoded))
+}
+
+/// decode_request decompresses the body of the request, the `Content-Encoding` is removed and
+/// returned so that the body could be compressed again by `encode_request`.
+pub async fn decode_request(request: Request<Body>) -> Result<(Request<Body>, Option<Encoding>)> {
+    let (mut parts, body) = request.into_parts();
+    let (body, encoding) = decode_body(&mut parts.headers, body).await?;
+    Ok((Request::from_parts(parts, body), encoding))
+}
+
+pub async fn encode_request(request: Request<Body>, encoding: Encoding) -> Result<Request<Body>> {
+    let (mut parts, body) = request.into_parts();
+    let body = encode_body(&mut parts.headers, body, encoding).await?;
+    Ok(Request::from_parts(parts, body))
+}
+
+/// decode_response works as `decode_request`.
+pub async fn decode_response(
+    response: Response<Body>,
+) -> Result<(Response<Body>, Option<Encoding>)> {
+    let (mut parts, body) = response.into_parts();
+    let (body, encoding) = decode_body(&mut parts.headers, body).await?;
+    Ok((Response::from_parts(parts, body), encoding))
+}
+
+pub async fn encode_response(
+    response: Response<Body>,
+    encoding: Encoding,
+) -> Result<Response<Body>> {
+    let (mut parts, body) = response.into_parts();
+    let body = encode_body(&mut parts.headers, body, encoding).await?;
+    Ok(Response::from_parts(parts, body))
+}
+
+#[cfg(test)]
+mod tests {
+    use http::header::{HeaderMap, CONTENT_ENCODING};
+
+    use crate::handler::http::encoding::Encoding;
+
+    #[test]
+    fn test_encoding() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(Encoding::from_headers(&headers), None);
+        headers.insert(CONTENT_ENCODING, "GZIP".parse().unwrap());
+        assert_eq!(Encoding::from_headers(&headers), Some(Encoding::Gzip));
+        headers.append(CONTENT_ENCODING, "br".parse().unwrap());
+        assert_eq!(Encoding::from_headers(&headers), None);
+
+        let data = br#"{"name": "Chaos Mesh", "message": "Hello!"}"#;
+        for encoding in [Encoding::Gzip, Encoding::Deflate, Encoding::Brotli] {
+            let encoded = encoding.encode(data).unwrap();
+            assert_ne!(&encoded[..], &data[..]);
+            assert_eq!(encoding.decode(&encoded).unwrap(), data);
+        }
+    }
+}
@@ -0,0 +1,65 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::Result;
+use http::header::HeaderName;
+use http::{Extensions, HeaderMap, HeaderValue};
+
+static NEXT_EXCHANGE: AtomicU64 = AtomicU64::new(1);
+
+/// ExchangeId is attached to the extensions of a request and of its response, so that the logs,
+/// the spans and the access log of a modified request could be paired with its (possibly
+/// modified) response. The IDs are unique within the process of the proxy.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Hash)]
+pub struct ExchangeId(pub u64);
+
+impl ExchangeId {
+    /// next returns the ID of a new exchange.
+    pub fn next() -> Self {
+        Self(NEXT_EXCHANGE.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// of returns the ID attached to the extensions, `ExchangeId(0)` if the exchange is not
+    /// tracked, e.g. a request built by hand.
+    pub fn of(extensions: &Extensions) -> Self {
+        extensions.get::<Self>().copied().unwrap_or_default()
+    }
+
+    /// stamp sets the header of the name to the ID.
+    pub fn stamp(self, headers: &mut HeaderMap, name: &HeaderName) -> Result<()> {
+        headers.insert(name.clone(), HeaderValue::from_str(&self.to_string())?);
+        Ok(())
+    }
+}
+
+impl fmt::Display for ExchangeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::header::HeaderName;
+    use http::{HeaderMap, Request};
+
+    use crate::handler::http::exchange::ExchangeId;
+
+    #[test]
+    fn test_exchange_id() {
+        let first = ExchangeId::next();
+        let second = ExchangeId::next();
+        assert!(second.0 > first.0);
+        assert_eq!(ExchangeId(255).to_string(), "00000000000000ff");
+
+        let mut request = Request::new(());
+        assert_eq!(ExchangeId::of(request.extensions()), ExchangeId(0));
+        request.extensions_mut().insert(second);
+        assert_eq!(ExchangeId::of(request.extensions()), second);
+
+        let mut headers = HeaderMap::new();
+        let name = HeaderName::from_static("x-chaos-exchange");
+        second.stamp(&mut headers, &name).unwrap();
+        assert_eq!(headers[&name], second.to_string());
+    }
+}
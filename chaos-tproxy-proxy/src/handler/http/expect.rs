@@ -0,0 +1,68 @@
+use std::time::Duration;
+
+use futures::StreamExt;
+use http::header::EXPECT;
+use http::Request;
+use hyper::Body;
+use tokio::time::sleep;
+
+/// expects_continue checks whether the client waits for a `100 Continue` before sending the body.
+pub fn expects_continue<T>(request: &Request<T>) -> bool {
+    request
+        .headers()
+        .get(EXPECT)
+        .map(|value| value.as_bytes().eq_ignore_ascii_case(b"100-continue"))
+        .unwrap_or(false)
+}
+
+/// strip_expect removes the expectation from the request to the upstream. The proxy answers it
+/// itself, hyper sends the `100 Continue` to the client when the body is polled at the first time,
+/// e.g. when the upstream connection is ready to take the body. The hyper client never waits for
+/// an interim response, so the expectation is useless to the upstream, and may be refused with
+/// `417 Expectation Failed`.
+pub fn strip_expect<T>(request: &mut Request<T>) {
+    request.headers_mut().remove(EXPECT);
+}
+
+/// withhold_continue defers the first poll of the body, so that the `100 Continue` is withheld for
+/// the duration. The clients usually send the body anyway after a timeout of their own.
+pub fn withhold_continue(request: Request<Body>, duration: Duration) -> Request<Body> {
+    if !expects_continue(&request) {
+        return request;
+    }
+    let (parts, body) = request.into_parts();
+    let body = futures::stream::once(async move {
+        sleep(duration).await;
+        body
+    })
+    .flatten();
+    Request::from_parts(parts, Body::wrap_stream(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use http::header::EXPECT;
+    use http::Request;
+    use hyper::Body;
+
+    use crate::handler::http::expect::{expects_continue, strip_expect, withhold_continue};
+
+    #[tokio::test]
+    async fn test_withhold_continue() {
+        let request = Request::builder()
+            .header(EXPECT, "100-Continue")
+            .body(Body::from("hello"))
+            .unwrap();
+        assert!(expects_continue(&request));
+
+        let started = Instant::now();
+        let mut request = withhold_continue(request, Duration::from_millis(50));
+        strip_expect(&mut request);
+        assert!(!expects_continue(&request));
+        let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+        assert_eq!(body, "hello");
+        assert!(started.elapsed() >= Duration::from_millis(50));
+    }
+}
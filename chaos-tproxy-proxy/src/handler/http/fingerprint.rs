@@ -0,0 +1,250 @@
+use std::time::Duration;
+
+use tokio::net::TcpStream;
+use tokio::time::sleep;
+use wildmatch::WildMatch;
+
+/// ClientFingerprint is attached to the extensions of the requests (and responses) of a TLS
+/// connection, so that the selectors could match it.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ClientFingerprint {
+    /// MD5 of the JA3 string of the ClientHello, in lowercase hex.
+    pub ja3: String,
+}
+
+/// UserAgentSelector matches a product token (`family/version`) of the User-Agent header, the
+/// family is matched case-insensitively.
+#[derive(Debug, Clone)]
+pub struct UserAgentSelector {
+    pub family: WildMatch,
+    pub version: Option<WildMatch>,
+}
+
+impl UserAgentSelector {
+    pub fn new(family: &str, version: Option<&str>) -> Self {
+        Self {
+            family: WildMatch::new(&family.to_lowercase()),
+            version: version.map(WildMatch::new),
+        }
+    }
+
+    pub fn matches(&self, user_agent: &str) -> bool {
+        user_agent_products(user_agent)
+            .into_iter()
+            .any(|(family, version)| {
+                self.family.matches(&family.to_lowercase())
+                    && self
+                        .version
+                        .iter()
+                        .all(|v| version.map(|version| v.matches(version)).unwrap_or(false))
+            })
+    }
+}
+
+/// user_agent_products parses the product tokens of a User-Agent, comments are skipped.
+/// e.g. `Mozilla/5.0 (X11; Linux x86_64) okhttp/4.9.0` => `[(Mozilla, 5.0), (okhttp, 4.9.0)]`
+fn user_agent_products(user_agent: &str) -> Vec<(&str, Option<&str>)> {
+    let mut products = vec![];
+    let mut depth = 0;
+    let mut start = None;
+    for (idx, c) in user_agent
+        .char_indices()
+        .chain(Some((user_agent.len(), ' ')))
+    {
+        if c == '(' || c == ')' || c.is_whitespace() {
+            if let Some(s) = start.take() {
+                let token = &user_agent[s..idx];
+                products.push(match token.split_once('/') {
+                    Some((family, version)) => (family, Some(version)),
+                    None => (token, None),
+                });
+            }
+            match c {
+                '(' => depth += 1,
+                ')' => depth = (depth - 1).max(0),
+                _ => {}
+            }
+        } else if depth == 0 && start.is_none() {
+            start = Some(idx);
+        }
+    }
+    products
+}
+
+const CLIENT_HELLO_PEEK_RETRIES: usize = 10;
+
+/// peek_ja3 peeks the ClientHello of the TLS stream without consuming it, and returns its JA3
+/// fingerprint.
+pub async fn peek_ja3(stream: &TcpStream) -> Option<String> {
+    let mut buf = vec![0; 16 * 1024];
+    for _ in 0..CLIENT_HELLO_PEEK_RETRIES {
+        let n = stream.peek(&mut buf).await.ok()?;
+        if n == 0 {
+            return None;
+        }
+        // the record header contains the length of the ClientHello
+        if n >= 5 && n >= 5 + u16::from_be_bytes([buf[3], buf[4]]) as usize {
+            return ja3_string(&buf[..n]).map(|s| format!("{:x}", md5::compute(s)));
+        }
+        sleep(Duration::from_millis(10)).await;
+    }
+    None
+}
+
+/// GREASE values (RFC 8701) are ignored by JA3.
+fn is_grease(value: u16) -> bool {
+    value & 0x0f0f == 0x0a0a && value >> 8 == value & 0xff
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u16_list(data: &[u8]) -> Vec<u16> {
+        data.chunks_exact(2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+            .filter(|v| !is_grease(*v))
+            .collect()
+    }
+}
+
+fn join<T: ToString>(values: &[T]) -> String {
+    values
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// ja3_string builds `SSLVersion,Ciphers,Extensions,EllipticCurves,EllipticCurvePointFormats`
+/// of the TLS record containing a ClientHello.
+fn ja3_string(record: &[u8]) -> Option<String> {
+    let mut r = Reader(record);
+    // record header: content type (handshake), version, length
+    if r.u8()? != 0x16 {
+        return None;
+    }
+    r.take(4)?;
+    // handshake header: type (client hello), length
+    if r.u8()? != 0x01 {
+        return None;
+    }
+    r.take(3)?;
+
+    let version = r.u16()?;
+    r.take(32)?;
+    let session_id_len = r.u8()? as usize;
+    r.take(session_id_len)?;
+    let ciphers_len = r.u16()? as usize;
+    let ciphers = Reader::u16_list(r.take(ciphers_len)?);
+    let compression_len = r.u8()? as usize;
+    r.take(compression_len)?;
+
+    let mut extensions = vec![];
+    let mut curves = vec![];
+    let mut point_formats = vec![];
+    if let Some(len) = r.u16() {
+        let mut ext = Reader(r.take(len as usize)?);
+        while let Some(ty) = ext.u16() {
+            let len = ext.u16()? as usize;
+            let mut data = Reader(ext.take(len)?);
+            if is_grease(ty) {
+                continue;
+            }
+            extensions.push(ty);
+            match ty {
+                // supported_groups
+                10 => {
+                    let len = data.u16()? as usize;
+                    curves = Reader::u16_list(data.take(len)?);
+                }
+                // ec_point_formats
+                11 => {
+                    let len = data.u8()? as usize;
+                    point_formats = data.take(len)?.to_vec();
+                }
+                _ => {}
+            }
+        }
+    }
+
+    Some(format!(
+        "{},{},{},{},{}",
+        version,
+        join(&ciphers),
+        join(&extensions),
+        join(&curves),
+        join(&point_formats)
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::handler::http::fingerprint::{ja3_string, user_agent_products, UserAgentSelector};
+
+    #[test]
+    fn test_user_agent() {
+        let ua = "Mozilla/5.0 (X11; Linux x86_64; rv:60.0) Gecko/20100101 okhttp/4.9.0";
+        assert_eq!(
+            user_agent_products(ua),
+            vec![
+                ("Mozilla", Some("5.0")),
+                ("Gecko", Some("20100101")),
+                ("okhttp", Some("4.9.0"))
+            ]
+        );
+        assert!(UserAgentSelector::new("OkHttp", Some("4.*")).matches(ua));
+        assert!(!UserAgentSelector::new("okhttp", Some("3.*")).matches(ua));
+        assert!(!UserAgentSelector::new("Linux", None).matches(ua));
+    }
+
+    #[test]
+    fn test_ja3_string() {
+        let mut hello = vec![0x03, 0x03];
+        hello.extend([0; 32]);
+        // empty session id
+        hello.push(0);
+        // ciphers with a GREASE value
+        hello.extend([0, 6, 0x0a, 0x0a, 0x13, 0x01, 0xc0, 0x2b]);
+        // null compression
+        hello.extend([1, 0]);
+        let extensions = [
+            // server_name
+            vec![0, 0, 0, 0],
+            // supported_groups: x25519, secp256r1
+            vec![0, 10, 0, 6, 0, 4, 0, 29, 0, 23],
+            // ec_point_formats: uncompressed
+            vec![0, 11, 0, 2, 1, 0],
+        ]
+        .concat();
+        hello.extend((extensions.len() as u16).to_be_bytes());
+        hello.extend(extensions);
+
+        let mut record = vec![0x16, 0x03, 0x01];
+        record.extend((hello.len() as u16 + 4).to_be_bytes());
+        record.extend([0x01, 0, (hello.len() >> 8) as u8, hello.len() as u8]);
+        record.extend(hello);
+
+        assert_eq!(
+            ja3_string(&record).unwrap(),
+            "771,4865-49195,0-10-11,29-23,0"
+        );
+        assert_eq!(ja3_string(&record[..20]), None);
+    }
+}
@@ -0,0 +1,136 @@
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::handler::http::rule::Rule;
+
+/// FollowUp is registered once its rule is applied: the next requests to the same path, of the
+/// same client by default, get the actions of `rule` for the duration. E.g. the retries of an
+/// aborted request are delayed.
+#[derive(Debug, Clone)]
+pub struct FollowUp {
+    pub duration: Duration,
+    /// same_client restricts the follow-up to the client (IP) of the exchange registering it.
+    pub same_client: bool,
+    /// rule is a Request target rule matching any request, whose actions are applied.
+    pub rule: Box<Rule>,
+}
+
+#[derive(Debug)]
+struct Registration {
+    rule: usize,
+    client: Option<IpAddr>,
+    path: String,
+    expires_at: Instant,
+}
+
+/// FollowUps are the follow-ups registered and not expired yet, shared by all the connections.
+#[derive(Debug, Default)]
+pub struct FollowUps {
+    registrations: Mutex<Vec<Registration>>,
+}
+
+impl FollowUps {
+    /// register registers the follow-up of the rule (index in the config) applied to the exchange
+    /// of the client to the path, an active registration of the same exchange is extended.
+    pub fn register(
+        &self,
+        rule: usize,
+        follow_up: &FollowUp,
+        client: IpAddr,
+        path: &str,
+        now: Instant,
+    ) {
+        let client = follow_up.same_client.then_some(client);
+        let expires_at = now + follow_up.duration;
+        let mut registrations = self.registrations.lock().unwrap();
+        registrations.retain(|registration| registration.expires_at > now);
+        match registrations.iter_mut().find(|registration| {
+            registration.rule == rule && registration.client == client && registration.path == path
+        }) {
+            Some(registration) => registration.expires_at = expires_at,
+            None => registrations.push(Registration {
+                rule,
+                client,
+                path: path.to_string(),
+                expires_at,
+            }),
+        }
+    }
+
+    /// matched returns the rules (indexes in the config) whose follow-ups apply to the request of
+    /// the client to the path, in order of the rules.
+    pub fn matched(&self, client: IpAddr, path: &str, now: Instant) -> Vec<usize> {
+        let mut registrations = self.registrations.lock().unwrap();
+        registrations.retain(|registration| registration.expires_at > now);
+        let mut rules: Vec<_> = registrations
+            .iter()
+            .filter(|registration| {
+                registration.path == path && registration.client.is_none_or(|ip| ip == client)
+            })
+            .map(|registration| registration.rule)
+            .collect();
+        rules.sort_unstable();
+        rules.dedup();
+        rules
+    }
+
+    /// rules returns the rules of the follow-ups matched by the request, see
+    /// [matched](Self::matched), with the indexes of the rules in `rules` registering them.
+    pub fn rules<'a>(
+        &self,
+        rules: &'a [Rule],
+        client: IpAddr,
+        path: &str,
+        now: Instant,
+    ) -> Vec<(usize, &'a Rule)> {
+        self.matched(client, path, now)
+            .into_iter()
+            .filter_map(|index| {
+                let follow_up = rules.get(index)?.follow_up.as_ref()?;
+                Some((index, &*follow_up.rule))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+    use std::time::{Duration, Instant};
+
+    use crate::handler::http::follow_up::FollowUps;
+    use crate::handler::http::rule::Rule;
+    use crate::raw_config::RawRule;
+
+    #[test]
+    fn test_follow_ups() {
+        let rule: RawRule = serde_json::from_value(serde_json::json!({
+            "target": "Request",
+            "selector": {"path": "/api/*"},
+            "actions": {"abort": true},
+            "follow_up": {"duration": "30s", "actions": {"delay": "1s"}},
+        }))
+        .unwrap();
+        let rule: Rule = rule.try_into().unwrap();
+        let follow_up = rule.follow_up.unwrap();
+        assert_eq!(follow_up.rule.actions.delay, Some(Duration::from_secs(1)));
+
+        let follow_ups = FollowUps::default();
+        let (client, other) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+        let now = Instant::now();
+        follow_ups.register(3, &follow_up, client, "/api/users", now);
+        assert_eq!(follow_ups.matched(client, "/api/users", now), vec![3]);
+        assert!(follow_ups.matched(other, "/api/users", now).is_empty());
+        assert!(follow_ups.matched(client, "/api/orders", now).is_empty());
+
+        // registered again, the follow-up is extended
+        let later = now + Duration::from_secs(20);
+        follow_ups.register(3, &follow_up, client, "/api/users", later);
+        let extended = now + Duration::from_secs(40);
+        assert_eq!(follow_ups.matched(client, "/api/users", extended), vec![3]);
+        assert!(follow_ups
+            .matched(client, "/api/users", later + Duration::from_secs(30))
+            .is_empty());
+    }
+}
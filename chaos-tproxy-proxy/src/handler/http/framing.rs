@@ -0,0 +1,118 @@
+use anyhow::Result;
+use http::header::{CONTENT_LENGTH, TRAILER, TRANSFER_ENCODING};
+use http::{HeaderMap, HeaderValue, Response};
+use hyper::body::HttpBody;
+use hyper::Body;
+
+/// Framing introduces how the body is delimited on HTTP/1.1.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Framing {
+    /// `Transfer-Encoding: chunked`, the length is unknown to the peer and the body is streamed.
+    Chunked,
+    /// `Content-Length`, the body is buffered to count it.
+    ContentLength,
+}
+
+/// apply_framing forces the framing of the response body.
+pub async fn apply_framing(response: &mut Response<Body>, framing: Framing) -> Result<()> {
+    let headers = response.headers_mut();
+    headers.remove(CONTENT_LENGTH);
+    headers.remove(TRANSFER_ENCODING);
+    match framing {
+        // hyper falls back to chunked if the size of the body is unknown
+        Framing::Chunked => {
+            let body = std::mem::take(response.body_mut());
+            *response.body_mut() = Body::wrap_stream(body);
+        }
+        Framing::ContentLength => {
+            let contents = hyper::body::to_bytes(response.body_mut()).await?;
+            response
+                .headers_mut()
+                .insert(CONTENT_LENGTH, HeaderValue::from(contents.len()));
+            *response.body_mut() = contents.into();
+        }
+    }
+    Ok(())
+}
+
+/// apply_trailers sends the trailers after the body, the names are announced by the `Trailer`
+/// header, the body is streamed before them. Note that hyper only sends trailers on HTTP/2
+/// connections.
+pub async fn apply_trailers(response: &mut Response<Body>, trailers: &HeaderMap) -> Result<()> {
+    let mut contents = std::mem::take(response.body_mut());
+    let headers = response.headers_mut();
+    // trailers require chunked framing on HTTP/1.1
+    headers.remove(CONTENT_LENGTH);
+    for name in trailers.keys() {
+        headers.append(TRAILER, HeaderValue::from_name(name.clone()));
+    }
+
+    let (mut sender, body) = Body::channel();
+    let trailers = trailers.clone();
+    tokio::spawn(async move {
+        while let Some(chunk) = contents.data().await {
+            let sent = match chunk {
+                Ok(chunk) => sender.send_data(chunk).await.is_ok(),
+                Err(_) => false,
+            };
+            // the client sees the body cut short instead of the trailers
+            if !sent {
+                sender.abort();
+                return;
+            }
+        }
+        let _ = sender.send_trailers(trailers).await;
+    });
+    *response.body_mut() = body;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use http::header::{CONTENT_LENGTH, TRAILER};
+    use http::{HeaderMap, Response};
+    use hyper::body::HttpBody;
+    use hyper::Body;
+
+    use crate::handler::http::framing::{apply_framing, apply_trailers, Framing};
+
+    #[tokio::test]
+    async fn test_framing() {
+        let mut response = Response::new(Body::from("hello"));
+        apply_framing(&mut response, Framing::Chunked)
+            .await
+            .unwrap();
+        assert!(response.headers().get(CONTENT_LENGTH).is_none());
+        assert!(response.body().size_hint().exact().is_none());
+
+        apply_framing(&mut response, Framing::ContentLength)
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[CONTENT_LENGTH], "5");
+
+        let mut trailers = HeaderMap::new();
+        trailers.insert("grpc-status", "13".parse().unwrap());
+        apply_trailers(&mut response, &trailers).await.unwrap();
+        assert_eq!(response.headers()[TRAILER], "grpc-status");
+        assert!(response.headers().get(CONTENT_LENGTH).is_none());
+        let body = response.body_mut();
+        assert_eq!(body.data().await.unwrap().unwrap(), "hello");
+        assert_eq!(body.trailers().await.unwrap(), Some(trailers.clone()));
+
+        // the chunks are streamed as they arrive, before the end of the body
+        let (mut sender, body) = Body::channel();
+        let mut response = Response::new(body);
+        apply_framing(&mut response, Framing::Chunked)
+            .await
+            .unwrap();
+        apply_trailers(&mut response, &trailers).await.unwrap();
+        sender.send_data("hel".into()).await.unwrap();
+        let body = response.body_mut();
+        assert_eq!(body.data().await.unwrap().unwrap(), "hel");
+        sender.send_data("lo".into()).await.unwrap();
+        drop(sender);
+        assert_eq!(body.data().await.unwrap().unwrap(), "lo");
+        assert!(body.data().await.is_none());
+        assert_eq!(body.trailers().await.unwrap(), Some(trailers));
+    }
+}
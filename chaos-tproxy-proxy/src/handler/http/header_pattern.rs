@@ -0,0 +1,158 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, Error, Result};
+use http::header::{HeaderMap, HeaderName, HeaderValue};
+use regex::{Regex, RegexBuilder};
+use wildmatch::WildMatch;
+
+/// HeaderPattern matches the names of the headers, by the name itself, a glob with wildcards,
+/// e.g. `x-b3-*`, or a regex between slashes, e.g. `/^x-(b3|ot)-/`. The names are lowercase, the
+/// patterns are case insensitive.
+#[derive(Debug, Clone)]
+pub enum HeaderPattern {
+    Name(HeaderName),
+    /// the glob is kept along with its source, to compare the patterns.
+    Glob(String, WildMatch),
+    Regex(Regex),
+}
+
+impl HeaderPattern {
+    pub fn matches(&self, name: &HeaderName) -> bool {
+        match self {
+            HeaderPattern::Name(expected) => name == expected,
+            HeaderPattern::Glob(_, glob) => glob.matches(name.as_str()),
+            HeaderPattern::Regex(regex) => regex.is_match(name.as_str()),
+        }
+    }
+
+    /// remove removes the headers of the names matched from the headers.
+    pub fn remove(&self, headers: &mut HeaderMap) {
+        if let HeaderPattern::Name(name) = self {
+            headers.remove(name);
+            return;
+        }
+        let names: Vec<_> = headers
+            .keys()
+            .filter(|name| self.matches(name))
+            .cloned()
+            .collect();
+        for name in names {
+            headers.remove(name);
+        }
+    }
+}
+
+impl FromStr for HeaderPattern {
+    type Err = Error;
+
+    fn from_str(pattern: &str) -> Result<Self> {
+        if let Some(regex) = pattern
+            .strip_prefix('/')
+            .and_then(|pattern| pattern.strip_suffix('/'))
+        {
+            return RegexBuilder::new(regex)
+                .case_insensitive(true)
+                .build()
+                .map(HeaderPattern::Regex)
+                .map_err(|e| anyhow!("invalid header pattern {}: {}", pattern, e));
+        }
+        if pattern.contains(['*', '?']) {
+            let glob = pattern.to_lowercase();
+            return Ok(HeaderPattern::Glob(glob.clone(), WildMatch::new(&glob)));
+        }
+        Ok(HeaderPattern::Name(pattern.parse()?))
+    }
+}
+
+impl PartialEq for HeaderPattern {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (HeaderPattern::Name(a), HeaderPattern::Name(b)) => a == b,
+            (HeaderPattern::Glob(a, _), HeaderPattern::Glob(b, _)) => a == b,
+            (HeaderPattern::Regex(a), HeaderPattern::Regex(b)) => a.as_str() == b.as_str(),
+            _ => false,
+        }
+    }
+}
+
+/// HeaderFields are the header fields a selector requires, each of them is present if any header
+/// of a name matched by its pattern has its value, or any value if it has none.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HeaderFields(pub Vec<(HeaderPattern, Option<HeaderValue>)>);
+
+impl HeaderFields {
+    pub fn matches(&self, headers: &HeaderMap) -> bool {
+        self.0.iter().all(|(pattern, expected)| match pattern {
+            HeaderPattern::Name(name) => headers
+                .get_all(name)
+                .iter()
+                .any(|value| expected.iter().all(|expected| value == expected)),
+            _ => headers.iter().any(|(name, value)| {
+                pattern.matches(name) && expected.iter().all(|expected| value == expected)
+            }),
+        })
+    }
+
+    /// get returns the value required of the header of the pattern, or none if any value is.
+    pub fn get(&self, pattern: &str) -> Option<&HeaderValue> {
+        let pattern: HeaderPattern = pattern.parse().ok()?;
+        self.0
+            .iter()
+            .find(|(field, _)| *field == pattern)
+            .and_then(|(_, value)| value.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::header::{HeaderMap, HeaderName};
+
+    use crate::handler::http::header_pattern::{HeaderFields, HeaderPattern};
+
+    #[test]
+    fn test_header_pattern() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-b3-traceid", "80f198ee56343ba8".parse().unwrap());
+        headers.insert("x-internal-token", "secret".parse().unwrap());
+        headers.append("x-internal-user", "alice".parse().unwrap());
+        headers.insert("x-request-id", "1".parse().unwrap());
+
+        let glob: HeaderPattern = "X-Internal-*".parse().unwrap();
+        assert!(matches!(glob, HeaderPattern::Glob(_, _)));
+        let regex: HeaderPattern = "/^X-(b3|ot)-/".parse().unwrap();
+        assert!(matches!(regex, HeaderPattern::Regex(_)));
+        assert!(regex.matches(&HeaderName::from_static("x-b3-traceid")));
+        assert!(!regex.matches(&HeaderName::from_static("x-request-id")));
+        assert!("/(/".parse::<HeaderPattern>().is_err());
+        assert!("x internal".parse::<HeaderPattern>().is_err());
+
+        let fields = |fields: Vec<(&str, Option<&str>)>| {
+            HeaderFields(
+                fields
+                    .into_iter()
+                    .map(|(pattern, value)| {
+                        (
+                            pattern.parse().unwrap(),
+                            value.map(|value| value.parse().unwrap()),
+                        )
+                    })
+                    .collect(),
+            )
+        };
+        assert!(fields(vec![("x-b3-*", None), ("x-request-id", Some("1"))]).matches(&headers));
+        assert!(fields(vec![("x-internal-*", Some("alice"))]).matches(&headers));
+        assert!(!fields(vec![("x-internal-*", Some("bob"))]).matches(&headers));
+        assert!(!fields(vec![("/^x-ot-/", None)]).matches(&headers));
+        assert_eq!(
+            fields(vec![("x-request-id", Some("1"))]).get("x-request-id"),
+            Some(&"1".parse().unwrap())
+        );
+
+        glob.remove(&mut headers);
+        HeaderPattern::Name(HeaderName::from_static("x-request-id")).remove(&mut headers);
+        assert_eq!(
+            headers.keys().map(|name| name.as_str()).collect::<Vec<_>>(),
+            vec!["x-b3-traceid"]
+        );
+    }
+}
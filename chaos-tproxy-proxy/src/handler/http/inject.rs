@@ -0,0 +1,284 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use bytes::Bytes;
+use futures::StreamExt;
+use http::uri::{PathAndQuery, Scheme};
+use http::{HeaderMap, Method, Request, Response, Uri, Version};
+use hyper::client::HttpConnector;
+use hyper::{Body, Client};
+use tracing::{debug, error};
+
+use crate::clock::Clock;
+use crate::handler::http::action::{
+    apply_request_action, apply_response_action, echo_applied, problem_json, synthesize_response,
+    Abort, AbortMode, Actions, DuplicateAction, FaultMarkers, MirrorAction, TimeoutAction,
+};
+use crate::handler::http::compensation::Compensation;
+use crate::handler::http::encoding::{
+    decode_request, decode_response, encode_request, encode_response,
+};
+use crate::handler::http::expect::strip_expect;
+use crate::handler::http::rule::Rule;
+use crate::handler::http::semantics::body_allowed;
+use crate::handler::http::truncate::TruncateAction;
+use crate::handler::http::websocket::WebSocketAction;
+use crate::proxy::http::resolver::Resolver;
+use crate::proxy::tcp::sockopt::{set_linger_zero, wait_sent, RawSocket};
+
+/// RESET_TIMEOUT bounds the wait for the part of a cut body to be acknowledged before the reset.
+const RESET_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Injection applies the rules matched by an exchange in order, for both the proxy and the
+/// [middleware](crate::middleware). The summaries and the fault markers of the request rules are
+/// carried to the response.
+#[derive(Debug)]
+pub struct Injection<'a> {
+    clock: &'a dyn Clock,
+    /// compensation cuts the processing time from the delays if enabled.
+    pub compensation: Compensation,
+    /// forwarding is left by the request rules applied.
+    pub forwarding: Forwarding,
+    /// fault_markers stamps the rules with `fault_marker` and their faults on the exchange.
+    fault_markers: bool,
+    /// reset is the connection reset once a body is cut in the reset mode, the body is only
+    /// closed without a connection of its own, e.g. in the middleware or on HTTP/2.
+    reset: Option<RawSocket>,
+    applied: Vec<String>,
+    markers: FaultMarkers,
+}
+
+/// Forwarding is left by the request rules to the forwarding of the request.
+#[derive(Debug, Default)]
+pub struct Forwarding {
+    pub duplicates: Vec<DuplicateAction>,
+    pub websocket: Option<WebSocketAction>,
+    /// response_delay is slept once the response is received.
+    pub response_delay: Duration,
+    /// timeout is the shortest timeout of the rules.
+    pub timeout: Option<TimeoutAction>,
+}
+
+/// Injected is the request to forward once the request rules are applied, or the response
+/// synthesized by one of them.
+#[derive(Debug)]
+pub enum Injected {
+    Forward(Request<Body>),
+    Respond(Response<Body>),
+}
+
+impl<'a> Injection<'a> {
+    pub fn new(clock: &'a dyn Clock, compensation: Compensation) -> Self {
+        Self {
+            clock,
+            compensation,
+            forwarding: Forwarding::default(),
+            fault_markers: false,
+            reset: None,
+            applied: vec![],
+            markers: FaultMarkers::default(),
+        }
+    }
+
+    pub fn fault_markers(mut self, fault_markers: bool) -> Self {
+        self.fault_markers = fault_markers;
+        self
+    }
+
+    pub fn reset(mut self, reset: Option<RawSocket>) -> Self {
+        self.reset = reset;
+        self
+    }
+
+    /// request applies the request rules in order, `applied` is told the actions drawn for each
+    /// rule before they are applied. The first response synthesized ends the exchange, or else the
+    /// request is forwarded as [forwarding](Self::forwarding) tells.
+    pub async fn request(
+        &mut self,
+        mut request: Request<Body>,
+        rules: Vec<(usize, &Rule)>,
+        mut applied: impl FnMut(usize, &Rule, &Actions),
+    ) -> Result<Injected> {
+        for (index, rule) in rules {
+            let sampled = rule.sampled(request.headers(), request.extensions());
+            applied(index, rule, &sampled);
+            let encoding = if rule.decode_body && sampled.rewrites_body() {
+                let (decoded, encoding) = decode_request(request).await?;
+                request = decoded;
+                encoding
+            } else {
+                None
+            };
+            let actions = self.compensation.request_actions(&sampled);
+            request = apply_request_action(request, &actions, self.clock).await?;
+            if let Some(encoding) = encoding {
+                request = encode_request(request, encoding).await?;
+            }
+            if rule.echo_applied {
+                self.applied.extend(sampled.summary());
+            }
+            if self.fault_markers && rule.fault_marker {
+                self.markers.add(rule.label(index), &sampled);
+            }
+            if let Some(mut response) = synthesize_response(&request, &sampled, self.clock)? {
+                if rule.problem_json {
+                    problem_json(&mut response, request.uri(), index, &sampled)?;
+                }
+                echo_applied(&mut response, &self.applied)?;
+                self.markers.mark(response.headers_mut())?;
+                return Ok(Injected::Respond(response));
+            }
+            self.forwarding.duplicates.extend(sampled.duplicate.clone());
+            if sampled.websocket.is_some() {
+                self.forwarding.websocket = sampled.websocket.clone();
+            }
+            self.forwarding.response_delay += sampled.response_delay().unwrap_or_default();
+            self.forwarding.timeout =
+                TimeoutAction::shortest(self.forwarding.timeout.take(), sampled.timeout.clone());
+        }
+        Ok(Injected::Forward(request))
+    }
+
+    /// mark stamps the fault markers of the request rules on the request forwarded.
+    pub fn mark(&self, request: &mut Request<Body>) -> Result<()> {
+        self.markers.mark(request.headers_mut())
+    }
+
+    /// response applies the response rules in order, see [request](Self::request), then echoes
+    /// the actions applied to the exchange and stamps its fault markers.
+    pub async fn response(
+        &mut self,
+        mut response: Response<Body>,
+        method: &Method,
+        uri: &Uri,
+        headers: &HeaderMap,
+        rules: Vec<(usize, &Rule)>,
+        mut applied: impl FnMut(usize, &Rule, &Actions),
+    ) -> Result<Response<Body>> {
+        for (index, rule) in rules {
+            let sampled = rule.sampled(headers, response.extensions());
+            applied(index, rule, &sampled);
+            // the responses which must not carry a body have nothing to decode
+            let encoding = if rule.decode_body
+                && sampled.rewrites_body()
+                && body_allowed(method, response.status())
+            {
+                let (decoded, encoding) = decode_response(response).await?;
+                response = decoded;
+                encoding
+            } else {
+                None
+            };
+            let actions = self.compensation.response_actions(&sampled);
+            response = apply_response_action(response, method, &actions, self.clock).await?;
+            // the connection is reset once the body is cut, after the part of it is sent
+            if let (
+                Some(TruncateAction {
+                    mode: AbortMode::Reset,
+                    ..
+                }),
+                Some(fd),
+            ) = (&actions.truncate, self.reset)
+            {
+                response = reset_on_cut(response, fd);
+            }
+            if let Some(encoding) = encoding {
+                response = encode_response(response, encoding).await?;
+            }
+            if rule.problem_json && sampled.abort_response.is_some() {
+                problem_json(&mut response, uri, index, &sampled)?;
+            }
+            if rule.echo_applied {
+                self.applied.extend(sampled.summary());
+            }
+            if self.fault_markers && rule.fault_marker {
+                self.markers.add(rule.label(index), &sampled);
+            }
+        }
+        echo_applied(&mut response, &self.applied)?;
+        self.markers.mark(response.headers_mut())?;
+        Ok(response)
+    }
+}
+
+/// mirrors returns the mirrors of the rules drawn by their percents, the alternatives of the
+/// weighted actions have no mirror.
+pub fn mirrors<'a>(rules: &[(usize, &'a Rule)]) -> Vec<&'a MirrorAction> {
+    rules
+        .iter()
+        .filter_map(|(_, rule)| rule.actions.mirror.as_ref())
+        .filter(|mirror| rand::random::<f64>() * 100.0 < mirror.percent as f64)
+        .collect()
+}
+
+/// mirror would send a copy of the request to the shadow backend in background, the response is
+/// discarded.
+pub fn mirror(
+    parts: &http::request::Parts,
+    body: &Bytes,
+    action: &MirrorAction,
+    resolver: Resolver,
+) -> Result<()> {
+    let mut request = copy_request(parts, body.clone())?;
+    strip_expect(&mut request);
+    // the shadow backend is not known to speak HTTP/2
+    *request.version_mut() = Version::HTTP_11;
+    let mut uri = parts.uri.clone().into_parts();
+    uri.scheme = Some(Scheme::HTTP);
+    uri.authority = Some(action.target.clone());
+    if uri.path_and_query.is_none() {
+        uri.path_and_query = Some(PathAndQuery::from_static("/"))
+    }
+    *request.uri_mut() = Uri::from_parts(uri)?;
+    let target = action.target.clone();
+    let client = Client::builder().build::<_, Body>(HttpConnector::new_with_resolver(resolver));
+    tokio::spawn(async move {
+        match client.request(request).await {
+            Ok(response) => debug!("{} : mirrored with {}", target, response.status()),
+            Err(e) => debug!("{} : fail to mirror request: {}", target, e),
+        }
+    });
+    Ok(())
+}
+
+/// copy_request would build a request with the same method, URI, version and headers.
+pub fn copy_request(parts: &http::request::Parts, body: Bytes) -> Result<Request<Body>> {
+    let mut request = Request::builder()
+        .method(parts.method.clone())
+        .uri(parts.uri.clone())
+        .version(parts.version)
+        .body(body.into())?;
+    *request.headers_mut() = parts.headers.clone();
+    Ok(request)
+}
+
+/// reset_on_cut resets the connection once the body of the response is cut in the reset mode.
+/// hyper drops the connection with the error of the body, so the reset is set while the body is
+/// still polled, after the part sent is acknowledged, or else the kernel would discard it.
+fn reset_on_cut(response: Response<Body>, fd: RawSocket) -> Response<Body> {
+    response.map(|body| {
+        Body::wrap_stream(body.then(move |chunk| async move {
+            if let Err(e) = &chunk {
+                if abort_mode(e) == Some(AbortMode::Reset) {
+                    wait_sent(fd, RESET_TIMEOUT).await;
+                    if let Err(e) = set_linger_zero(fd) {
+                        error!("fail to reset connection: {}", e);
+                    }
+                }
+            }
+            chunk
+        }))
+    })
+}
+
+/// abort_mode returns the mode of the [Abort] in the sources of the error, if any.
+fn abort_mode(e: &(dyn std::error::Error + 'static)) -> Option<AbortMode> {
+    let mut source = Some(e);
+    while let Some(e) = source {
+        if let Some(Abort(mode)) = e.downcast_ref::<Abort>() {
+            return Some(*mode);
+        }
+        source = e.source();
+    }
+    None
+}
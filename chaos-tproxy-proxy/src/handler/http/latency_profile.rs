@@ -0,0 +1,106 @@
+use std::convert::TryInto;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+
+use crate::raw_config::RawLatencyProfile;
+
+/// LatencyProfile is a latency distribution, e.g. exported from the histograms of production,
+/// which the delays of a rule are drawn from, so that the injected latency reproduces the real
+/// one instead of a constant.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LatencyProfile {
+    /// Samples are drawn uniformly.
+    Samples(Vec<Duration>),
+    /// Percentiles are the latencies by percentile in `0..=100`, sorted by percentile. The
+    /// latencies between two percentiles are interpolated linearly, the ones below the first and
+    /// over the last are clamped to them.
+    Percentiles(Vec<(f64, Duration)>),
+}
+
+impl LatencyProfile {
+    /// load reads the profile from the yaml or json file.
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read(path)
+            .map_err(|e| anyhow!("fail to read latency profile {}: {}", path.display(), e))?;
+        let raw: RawLatencyProfile = serde_yaml::from_slice(&contents)
+            .map_err(|e| anyhow!("invalid latency profile {}: {}", path.display(), e))?;
+        raw.try_into()
+    }
+
+    /// sample draws a latency from the distribution.
+    pub fn sample(&self) -> Duration {
+        self.quantile(rand::random::<f64>())
+    }
+
+    /// quantile returns the latency of the quantile in `0..=1`.
+    fn quantile(&self, q: f64) -> Duration {
+        match self {
+            LatencyProfile::Samples(samples) => {
+                let index = (q * samples.len() as f64) as usize;
+                samples[index.min(samples.len() - 1)]
+            }
+            LatencyProfile::Percentiles(percentiles) => {
+                let p = q * 100.0;
+                let upper = percentiles
+                    .iter()
+                    .position(|(percentile, _)| *percentile >= p)
+                    .unwrap_or(percentiles.len() - 1);
+                let (p1, latency1) = percentiles[upper];
+                if upper == 0 || p1 < p {
+                    return latency1;
+                }
+                let (p0, latency0) = percentiles[upper - 1];
+                let ratio = (p - p0) / (p1 - p0);
+                latency0.mul_f64(1.0 - ratio) + latency1.mul_f64(ratio)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+    use std::time::Duration;
+
+    use crate::handler::http::latency_profile::LatencyProfile;
+    use crate::raw_config::RawLatencyProfile;
+
+    fn parse(yaml: &str) -> anyhow::Result<LatencyProfile> {
+        serde_yaml::from_str::<RawLatencyProfile>(yaml)?.try_into()
+    }
+
+    #[test]
+    fn test_latency_profile() {
+        let samples = parse("samples_ms: [10, 20, 30, 40]").unwrap();
+        assert_eq!(samples.quantile(0.0), Duration::from_millis(10));
+        assert_eq!(samples.quantile(0.6), Duration::from_millis(30));
+        assert_eq!(samples.quantile(1.0), Duration::from_millis(40));
+
+        let percentiles = parse("percentiles_ms: {p99: 300, p50: 20, '90': 100}").unwrap();
+        assert_eq!(
+            percentiles,
+            LatencyProfile::Percentiles(vec![
+                (50.0, Duration::from_millis(20)),
+                (90.0, Duration::from_millis(100)),
+                (99.0, Duration::from_millis(300)),
+            ])
+        );
+        assert_eq!(percentiles.quantile(0.1), Duration::from_millis(20));
+        assert_eq!(percentiles.quantile(0.7), Duration::from_millis(60));
+        assert_eq!(percentiles.quantile(0.5), Duration::from_millis(20));
+        assert_eq!(percentiles.quantile(1.0), Duration::from_millis(300));
+        for _ in 0..100 {
+            let latency = percentiles.sample();
+            assert!(latency >= Duration::from_millis(20) && latency <= Duration::from_millis(300));
+        }
+
+        assert!(parse("samples_ms: []").is_err());
+        assert!(parse("samples_ms: [-1]").is_err());
+        assert!(parse("percentiles_ms: {p101: 10}").is_err());
+        assert!(parse("percentiles_ms: {median: 10}").is_err());
+        assert!(parse("{samples_ms: [1], percentiles_ms: {p50: 1}}").is_err());
+    }
+}
@@ -1,3 +1,34 @@
 pub mod action;
+pub mod body_limit;
+pub mod builder;
+pub mod client_ip;
+pub mod compare;
+pub mod compensation;
+pub mod connect;
+pub mod cookie;
+pub mod dedup;
+pub mod dribble;
+pub mod encoding;
+pub mod exchange;
+pub mod expect;
+pub mod fingerprint;
+pub mod follow_up;
+pub mod framing;
+pub mod header_pattern;
+pub mod inject;
+pub mod latency_profile;
+pub mod pattern;
+pub mod pressure;
+pub mod rate_limit;
 pub mod rule;
+pub mod segment;
 pub mod selector;
+pub mod semantics;
+pub mod stall;
+pub mod sticky;
+pub mod tap;
+pub mod time_window;
+pub mod tls_client;
+pub mod truncate;
+pub mod validation;
+pub mod websocket;
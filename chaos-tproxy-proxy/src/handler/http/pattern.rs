@@ -0,0 +1,83 @@
+use std::f64::consts::PI;
+use std::time::{Duration, SystemTime};
+
+/// Shape introduces how the activity of the actions oscillates in a period.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Shape {
+    /// Active in the first `duty_cycle` of every period.
+    Square,
+    /// Active with a probability rising from 0 to 1 and falling back in every period.
+    Sine,
+    /// Active with a probability rising from 0 to 1 in every period.
+    Ramp,
+}
+
+/// PatternAction modulates whether the actions of a rule are active over time, the periods start
+/// when the config is loaded, or at the epoch shared by the coordinated instances.
+#[derive(Debug, PartialEq, Clone)]
+pub struct PatternAction {
+    pub shape: Shape,
+    pub period: Duration,
+    pub duty_cycle: f64,
+    pub started_at: SystemTime,
+}
+
+impl PatternAction {
+    /// is_active checks the activity at `now`, the periods start at `epoch` if it is given.
+    pub fn is_active(&self, epoch: Option<SystemTime>, now: SystemTime) -> bool {
+        self.is_active_at(epoch.unwrap_or(self.started_at), now, rand::random())
+    }
+
+    /// is_active_at checks the activity at `now`, `dice` in `[0, 1)` decides the probabilistic
+    /// shapes.
+    fn is_active_at(&self, epoch: SystemTime, now: SystemTime, dice: f64) -> bool {
+        let phase = self.phase(epoch, now);
+        match self.shape {
+            Shape::Square => phase < self.duty_cycle,
+            Shape::Sine => dice < (1.0 - (2.0 * PI * phase).cos()) / 2.0,
+            Shape::Ramp => dice < phase,
+        }
+    }
+
+    /// phase returns the position in the current period, in `[0, 1)`.
+    fn phase(&self, epoch: SystemTime, now: SystemTime) -> f64 {
+        let period = self.period.as_secs_f64();
+        if period == 0.0 {
+            return 0.0;
+        }
+        let elapsed = now.duration_since(epoch).unwrap_or_default().as_secs_f64();
+        (elapsed % period) / period
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use crate::handler::http::pattern::{PatternAction, Shape};
+
+    #[test]
+    fn test_pattern() {
+        let started_at = SystemTime::now();
+        let at = |secs| started_at + Duration::from_secs(secs);
+        let mut pattern = PatternAction {
+            shape: Shape::Square,
+            period: Duration::from_secs(60),
+            duty_cycle: 0.25,
+            started_at,
+        };
+        assert!(pattern.is_active_at(started_at, at(10), 0.99));
+        assert!(!pattern.is_active_at(started_at, at(20), 0.0));
+        assert!(pattern.is_active_at(started_at, at(70), 0.99));
+
+        pattern.shape = Shape::Sine;
+        assert!(!pattern.is_active_at(started_at, at(0), 0.0));
+        assert!(pattern.is_active_at(started_at, at(30), 0.99));
+        assert!(pattern.is_active_at(started_at, at(15), 0.49));
+        assert!(!pattern.is_active_at(started_at, at(15), 0.51));
+
+        pattern.shape = Shape::Ramp;
+        assert!(!pattern.is_active_at(started_at, at(15), 0.26));
+        assert!(pattern.is_active_at(started_at, at(45), 0.74));
+    }
+}
@@ -0,0 +1,148 @@
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The samples of the system metrics are reused in this interval.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// PressureSelector activates the rule only if the node is under pressure, every given threshold
+/// should be exceeded.
+#[derive(Debug, Clone)]
+pub struct PressureSelector {
+    /// CPU usage in percent.
+    pub cpu: Option<f64>,
+    /// Memory usage (excluding the available memory) in percent.
+    pub memory: Option<f64>,
+    /// Load average of 1 minute.
+    pub load: Option<f64>,
+    sampler: Arc<Mutex<Sampler>>,
+}
+
+#[derive(Debug, Default)]
+struct Sampler {
+    sampled_at: Option<Instant>,
+    // (busy, total) jiffies of the last sample
+    cpu_jiffies: Option<(u64, u64)>,
+    sample: Sample,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Sample {
+    cpu: Option<f64>,
+    memory: Option<f64>,
+    load: Option<f64>,
+}
+
+impl PressureSelector {
+    pub fn new(cpu: Option<f64>, memory: Option<f64>, load: Option<f64>) -> Self {
+        Self {
+            cpu,
+            memory,
+            load,
+            sampler: Default::default(),
+        }
+    }
+
+    pub fn is_under_pressure(&self) -> bool {
+        let sample = self.sampler.lock().unwrap().sample();
+        let exceeded = |threshold: Option<f64>, value: Option<f64>| match threshold {
+            None => true,
+            // unknown metrics are never regarded as pressure
+            Some(threshold) => value.map(|v| v >= threshold).unwrap_or(false),
+        };
+        exceeded(self.cpu, sample.cpu)
+            && exceeded(self.memory, sample.memory)
+            && exceeded(self.load, sample.load)
+    }
+}
+
+impl Sampler {
+    fn sample(&mut self) -> Sample {
+        if let Some(sampled_at) = self.sampled_at {
+            if sampled_at.elapsed() < SAMPLE_INTERVAL {
+                return self.sample;
+            }
+        }
+        let jiffies = fs::read_to_string("/proc/stat")
+            .ok()
+            .and_then(|stat| parse_cpu_jiffies(&stat));
+        let cpu = match (self.cpu_jiffies, jiffies) {
+            // usage since boot at the first time
+            (None, Some((busy, total))) => Some((busy, total)),
+            (Some((last_busy, last_total)), Some((busy, total))) => Some((
+                busy.saturating_sub(last_busy),
+                total.saturating_sub(last_total),
+            )),
+            _ => None,
+        }
+        .filter(|(_, total)| *total > 0)
+        .map(|(busy, total)| busy as f64 * 100.0 / total as f64);
+
+        self.cpu_jiffies = jiffies;
+        self.sampled_at = Some(Instant::now());
+        self.sample = Sample {
+            cpu,
+            memory: fs::read_to_string("/proc/meminfo")
+                .ok()
+                .and_then(|meminfo| parse_memory_usage(&meminfo)),
+            load: fs::read_to_string("/proc/loadavg")
+                .ok()
+                .and_then(|loadavg| parse_load(&loadavg)),
+        };
+        self.sample
+    }
+}
+
+/// parse_cpu_jiffies returns (busy, total) jiffies of all CPUs in `/proc/stat`.
+fn parse_cpu_jiffies(stat: &str) -> Option<(u64, u64)> {
+    let line = stat.lines().find(|line| line.starts_with("cpu "))?;
+    let values: Vec<u64> = line
+        .split_whitespace()
+        .skip(1)
+        .map(|v| v.parse().ok())
+        .collect::<Option<_>>()?;
+    // user nice system idle iowait irq softirq steal (guest is counted in user)
+    let total: u64 = values.iter().take(8).sum();
+    let idle = values.get(3)? + values.get(4).unwrap_or(&0);
+    Some((total - idle, total))
+}
+
+/// parse_memory_usage returns the percent of memory in use in `/proc/meminfo`.
+fn parse_memory_usage(meminfo: &str) -> Option<f64> {
+    let field = |name: &str| -> Option<f64> {
+        meminfo
+            .lines()
+            .find(|line| line.starts_with(name))?
+            .split_whitespace()
+            .nth(1)?
+            .parse()
+            .ok()
+    };
+    let total = field("MemTotal:")?;
+    let available = field("MemAvailable:")?;
+    if total == 0.0 {
+        return None;
+    }
+    Some((total - available) * 100.0 / total)
+}
+
+/// parse_load returns the load average of 1 minute in `/proc/loadavg`.
+fn parse_load(loadavg: &str) -> Option<f64> {
+    loadavg.split_whitespace().next()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::handler::http::pressure::{parse_cpu_jiffies, parse_load, parse_memory_usage};
+
+    #[test]
+    fn test_parse_metrics() {
+        let stat = "cpu  100 0 100 700 100 0 0 0 0 0\ncpu0 50 0 50 350 50 0 0 0 0 0\n";
+        assert_eq!(parse_cpu_jiffies(stat), Some((200, 1000)));
+
+        let meminfo = "MemTotal:       1000 kB\nMemFree:         100 kB\nMemAvailable:    250 kB\n";
+        assert_eq!(parse_memory_usage(meminfo), Some(75.0));
+
+        assert_eq!(parse_load("1.50 0.80 0.40 2/345 6789\n"), Some(1.5));
+    }
+}
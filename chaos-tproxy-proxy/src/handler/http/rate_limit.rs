@@ -0,0 +1,97 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use http::header::RETRY_AFTER;
+use http::{Response, StatusCode};
+use hyper::Body;
+
+/// RateLimitAction allows `requests` per `per` with a token bucket shared by all the exchanges
+/// matched by the rule, the exceeded requests are responded with 429.
+#[derive(Debug, Clone)]
+pub struct RateLimitAction {
+    pub requests: u32,
+    pub per: Duration,
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+impl PartialEq for RateLimitAction {
+    fn eq(&self, other: &Self) -> bool {
+        self.requests == other.requests && self.per == other.per
+    }
+}
+
+impl Eq for RateLimitAction {}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl RateLimitAction {
+    pub fn new(requests: u32, per: Duration) -> Self {
+        Self {
+            requests,
+            per,
+            bucket: Arc::new(Mutex::new(Bucket {
+                tokens: requests as f64,
+                updated_at: Instant::now(),
+            })),
+        }
+    }
+
+    /// response returns the 429 response if the rate is exceeded at `now`.
+    pub fn response(&self, now: Instant) -> anyhow::Result<Option<Response<Body>>> {
+        let retry_after = match self.acquire(now) {
+            Ok(()) => return Ok(None),
+            Err(retry_after) => retry_after,
+        };
+        // Retry-After is in seconds, round up to avoid retrying too early.
+        let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+        Ok(Some(
+            Response::builder()
+                .status(StatusCode::TOO_MANY_REQUESTS)
+                .header(RETRY_AFTER, secs.max(1))
+                .body(Body::empty())?,
+        ))
+    }
+
+    /// acquire takes a token from the bucket, or returns how long to wait for the next token.
+    fn acquire(&self, now: Instant) -> Result<(), Duration> {
+        let rate = self.requests as f64 / self.per.as_secs_f64();
+        let mut bucket = self.bucket.lock().unwrap();
+        let elapsed = now.saturating_duration_since(bucket.updated_at);
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * rate).min(self.requests as f64);
+        bucket.updated_at = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::handler::http::rate_limit::RateLimitAction;
+
+    #[test]
+    fn test_rate_limit() {
+        let limit = RateLimitAction::new(2, Duration::from_secs(10));
+        let now = Instant::now();
+        assert!(limit.acquire(now).is_ok());
+        assert!(limit.acquire(now).is_ok());
+        let retry_after = limit.acquire(now).unwrap_err();
+        assert!((retry_after.as_secs_f64() - 5.0).abs() < 1e-6);
+
+        assert!(limit.acquire(now + Duration::from_secs(5)).is_ok());
+        assert!(limit.acquire(now + Duration::from_secs(5)).is_err());
+        // the bucket never holds more than `requests` tokens
+        assert!(limit.acquire(now + Duration::from_secs(100)).is_ok());
+        assert!(limit.acquire(now + Duration::from_secs(100)).is_ok());
+        assert!(limit.acquire(now + Duration::from_secs(100)).is_err());
+    }
+}
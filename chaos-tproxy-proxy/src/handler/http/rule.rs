@@ -1,15 +1,125 @@
+use std::borrow::Cow;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant, SystemTime};
+
+use http::{Extensions, HeaderMap};
+
+use crate::clock::Clock;
 use crate::handler::http::action::Actions;
+use crate::handler::http::follow_up::FollowUp;
 use crate::handler::http::selector::Selector;
+use crate::handler::http::sticky::Sticky;
 
 /// Rule introduces a set of rules would effect the HTTP request/response.
 #[derive(Debug, Clone)]
 pub struct Rule {
+    /// name identifies the rule in the logs and the timeline, unique in the config.
+    pub name: Option<String>,
+    /// enabled would be false to keep the rule in the config without applying it.
+    pub enabled: bool,
+    /// duration expires the rule once it is loaded for the duration, see
+    /// [Metrics::expired](crate::metrics::Metrics::expired).
+    pub duration: Option<Duration>,
+    /// priority would order the matching rules, the higher ones first.
+    pub priority: i32,
     /// target would indicate which would be affected by the rule, HTTP request or response.
     pub target: Target,
+    /// direction restricts the rule to the inbound or outbound exchanges, `None` matches both.
+    pub direction: Option<Direction>,
     /// Selectors contains a set of filters to check whether the request/response should be affected.
     pub selector: Selector,
     /// actions introduces the expected modification.
     pub actions: Actions,
+    /// decode_body would decompress the body (gzip, deflate or br) before the actions are applied,
+    /// and compress it again afterwards.
+    pub decode_body: bool,
+    /// echo_applied would echo the summary of the applied actions to the client in the
+    /// `x-chaos-applied` response header.
+    pub echo_applied: bool,
+    /// problem_json would fill the empty bodies of the synthesized error responses with RFC 7807
+    /// `application/problem+json` documents.
+    pub problem_json: bool,
+    /// fault_marker would be false to leave the exchanges modified by this rule unmarked, even if
+    /// the fault markers are enabled.
+    pub fault_marker: bool,
+    /// follow_up would be registered once the rule is applied, to affect the next requests to the
+    /// same path.
+    pub follow_up: Option<FollowUp>,
+    /// sticky draws the `percent` of the selector and the weighted actions by session, instead of
+    /// by exchange.
+    pub sticky: Option<Sticky>,
+}
+
+impl Rule {
+    /// label returns the name of the rule, or `#<index>` if unnamed.
+    pub fn label(&self, index: usize) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => format!("#{}", index),
+        }
+    }
+
+    /// is_active checks whether the rule is enabled, and its actions should be applied at the time
+    /// of the clock.
+    pub fn is_active(&self, epoch: Option<SystemTime>, clock: &dyn Clock) -> bool {
+        self.enabled && self.actions.is_active(epoch, clock)
+    }
+
+    /// expired checks whether the duration of the rule is over, the rule being loaded at
+    /// `loaded`.
+    pub fn expired(&self, loaded: Instant, now: Instant) -> bool {
+        self.duration
+            .is_some_and(|duration| now.saturating_duration_since(loaded) >= duration)
+    }
+
+    /// admits checks whether the exchange of the headers (of the request) and the extensions is
+    /// among the `percent` of the selector, drawn by session if sticky.
+    pub fn admits(&self, headers: &HeaderMap, extensions: &Extensions) -> bool {
+        let percent = match self.selector.percent {
+            None => return true,
+            Some(percent) => percent,
+        };
+        let roll = match &self.sticky {
+            Some(sticky) => sticky.roll(headers, extensions, "percent"),
+            None => rand::random(),
+        };
+        roll * 100.0 < percent
+    }
+
+    /// sampled returns the actions of the exchange, see [Actions::sampled], the weighted ones
+    /// drawn by session if sticky.
+    pub fn sampled(&self, headers: &HeaderMap, extensions: &Extensions) -> Cow<'_, Actions> {
+        match &self.sticky {
+            Some(sticky) => self
+                .actions
+                .sampled_by(sticky.roll(headers, extensions, "weighted")),
+            None => self.actions.sampled(),
+        }
+    }
+}
+
+/// MatchPolicy tells how the rules matching the same exchange interact.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum MatchPolicy {
+    /// First applies the matching rule of the highest priority only. The Request rules are
+    /// matched before the response is known, so the Response rules only apply if no Request rule
+    /// did.
+    First,
+    /// All applies every matching rule in order of priority, the faults accumulate.
+    #[default]
+    All,
+}
+
+impl MatchPolicy {
+    /// select orders the matching rules (with their indexes in the config) by priority, the
+    /// rules of the same priority keep their order, and keeps the first one if only one applies.
+    pub fn select(self, mut rules: Vec<(usize, &Rule)>) -> Vec<(usize, &Rule)> {
+        rules.sort_by_key(|(_, rule)| std::cmp::Reverse(rule.priority));
+        if self == MatchPolicy::First {
+            rules.truncate(1);
+        }
+        rules
+    }
 }
 
 /// Target introduces the [Rule] should effect on HTTP request or response.
@@ -17,4 +127,143 @@ pub struct Rule {
 pub enum Target {
     Request,
     Response,
+    /// Tcp introduces the rule effects the raw TCP connections, which are not parsed as HTTP.
+    Tcp,
+}
+
+/// Direction introduces whether the exchange is received by the local services (inbound), or sent
+/// by them to their dependencies (outbound).
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+impl Direction {
+    /// of would tell the direction of a connection by its original destination, the inbound
+    /// connections are destined to the addresses owned by the host.
+    pub fn of(target: &SocketAddr) -> Self {
+        // binding fails with EADDRNOTAVAIL on the addresses not owned by the host
+        match UdpSocket::bind(SocketAddr::new(target.ip(), 0)) {
+            Ok(_) => Direction::Inbound,
+            Err(_) => Direction::Outbound,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+
+    use http::Request;
+
+    use crate::clock::SystemClock;
+    use crate::handler::http::rule::{Direction, MatchPolicy, Rule};
+    use crate::raw_config::{check_rule_names, RawRule};
+
+    #[test]
+    fn test_direction() {
+        assert_eq!(
+            Direction::of(&"127.0.0.1:80".parse().unwrap()),
+            Direction::Inbound
+        );
+        // TEST-NET-1 is never assigned to hosts
+        assert_eq!(
+            Direction::of(&"192.0.2.1:80".parse().unwrap()),
+            Direction::Outbound
+        );
+    }
+
+    #[test]
+    fn test_named_rules() {
+        let rule = |name: &str, enabled: bool| -> RawRule {
+            serde_json::from_value(serde_json::json!({
+                "name": name,
+                "enabled": enabled,
+                "target": "Request",
+                "selector": {},
+                "actions": {"abort": true},
+            }))
+            .unwrap()
+        };
+        let disabled: Rule = rule("slow-api", false).try_into().unwrap();
+        assert_eq!(disabled.name.as_deref(), Some("slow-api"));
+        assert!(!disabled.is_active(None, &SystemClock));
+        let enabled: Rule = rule("slow-api", true).try_into().unwrap();
+        assert!(enabled.is_active(None, &SystemClock));
+
+        assert!(check_rule_names(&[rule("slow-api", true), rule("abort-api", true)]).is_ok());
+        assert!(check_rule_names(&[rule("slow-api", true), rule("slow-api", false)]).is_err());
+    }
+
+    #[test]
+    fn test_match_policy() {
+        let rule = |priority: Option<i32>| -> Rule {
+            let rule: RawRule = serde_json::from_value(serde_json::json!({
+                "priority": priority,
+                "target": "Request",
+                "selector": {},
+                "actions": {},
+            }))
+            .unwrap();
+            rule.try_into().unwrap()
+        };
+        let rules = [rule(None), rule(Some(10)), rule(Some(-1)), rule(Some(0))];
+        let matched = || rules.iter().enumerate().collect::<Vec<_>>();
+        let indexes = |selected: Vec<(usize, &Rule)>| -> Vec<usize> {
+            selected.into_iter().map(|(index, _)| index).collect()
+        };
+        assert_eq!(
+            indexes(MatchPolicy::All.select(matched())),
+            vec![1, 0, 3, 2]
+        );
+        assert_eq!(indexes(MatchPolicy::First.select(matched())), vec![1]);
+        assert!(MatchPolicy::First.select(vec![]).is_empty());
+    }
+
+    #[test]
+    fn test_sticky_sessions() {
+        let rule: Rule = serde_json::from_value::<RawRule>(serde_json::json!({
+            "name": "slow-api",
+            "target": "Request",
+            "selector": {"percent": 50},
+            "sticky_on": {"header": "x-session"},
+            "actions": {"delay": "1s"},
+        }))
+        .unwrap()
+        .try_into()
+        .unwrap();
+        let admitted = |session: usize| {
+            let request = Request::get("/")
+                .header("x-session", session)
+                .body(())
+                .unwrap();
+            rule.admits(request.headers(), request.extensions())
+        };
+        let sessions: Vec<_> = (0..100).map(admitted).collect();
+        for _ in 0..10 {
+            assert_eq!((0..100).map(admitted).collect::<Vec<_>>(), sessions);
+        }
+        let selected = sessions.iter().filter(|admitted| **admitted).count();
+        assert!((25..75).contains(&selected), "{}", selected);
+
+        let invalid = |rule: serde_json::Value| {
+            serde_json::from_value::<RawRule>(rule)
+                .unwrap()
+                .try_into()
+                .map(|_: Rule| ())
+                .is_err()
+        };
+        assert!(invalid(serde_json::json!({
+            "target": "Request",
+            "selector": {"percent": 120},
+            "actions": {},
+        })));
+        assert!(invalid(serde_json::json!({
+            "target": "Tcp",
+            "selector": {},
+            "sticky_on": "source_ip",
+            "actions": {},
+        })));
+    }
 }
@@ -0,0 +1,98 @@
+use std::time::Duration;
+
+use bytes::BytesMut;
+use futures::{stream, StreamExt};
+use hyper::Body;
+use tokio::time::sleep;
+
+/// SegmentAction streams the body in segments of `size` bytes, pausing `inter_segment_delay`
+/// between them. Unlike [DribbleAction](crate::handler::http::dribble::DribbleAction), the body is
+/// not buffered, so that the large downloads are slow but progressing.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct SegmentAction {
+    pub size: usize,
+    pub inter_segment_delay: Duration,
+}
+
+/// Segmenting is the state of a segmented body, `None` after the end or an error of the body.
+struct Segmenting {
+    body: Option<Body>,
+    buffer: BytesMut,
+    first: bool,
+}
+
+impl SegmentAction {
+    /// body returns the segmented body, the segments are cut as soon as enough bytes arrive.
+    pub fn body(&self, body: Body) -> Body {
+        let (size, delay) = (self.size, self.inter_segment_delay);
+        let state = Segmenting {
+            body: Some(body),
+            buffer: BytesMut::new(),
+            first: true,
+        };
+        let segments = stream::unfold(state, move |mut state| async move {
+            while state.buffer.len() < size {
+                let chunk = match state.body.as_mut() {
+                    Some(body) => body.next().await,
+                    None => None,
+                };
+                match chunk {
+                    Some(Ok(chunk)) => state.buffer.extend_from_slice(&chunk),
+                    Some(Err(e)) => {
+                        state.body = None;
+                        state.buffer.clear();
+                        return Some((Err(e), state));
+                    }
+                    None => {
+                        state.body = None;
+                        break;
+                    }
+                }
+            }
+            if state.buffer.is_empty() {
+                return None;
+            }
+            if !state.first {
+                sleep(delay).await;
+            }
+            state.first = false;
+            let len = size.min(state.buffer.len());
+            let segment = state.buffer.split_to(len).freeze();
+            Some((Ok(segment), state))
+        });
+        Body::wrap_stream(segments)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::time::Duration;
+
+    use futures::{stream, StreamExt};
+    use hyper::Body;
+    use tokio::time::Instant;
+
+    use crate::handler::http::segment::SegmentAction;
+
+    #[tokio::test]
+    async fn test_segment() {
+        let action = SegmentAction {
+            size: 4,
+            inter_segment_delay: Duration::from_millis(50),
+        };
+        let chunks = stream::iter(vec!["hello", ", ", "world"]).map(Ok::<_, Infallible>);
+        let started_at = Instant::now();
+        let segments: Vec<_> = action
+            .body(Body::wrap_stream(chunks))
+            .map(|segment| segment.unwrap())
+            .collect()
+            .await;
+        // two pauses between the three segments
+        assert!(started_at.elapsed() >= Duration::from_millis(100));
+        assert_eq!(segments, vec!["hell", "o, w", "orld"]);
+
+        let segments: Vec<_> = action.body(Body::empty()).collect().await;
+        assert!(segments.is_empty());
+    }
+}
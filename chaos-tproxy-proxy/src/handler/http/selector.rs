@@ -1,10 +1,18 @@
+use std::collections::HashMap;
 use std::net::IpAddr;
 
-use http::header::HeaderMap;
-use http::{Method, Request, Response, StatusCode, Uri};
+use chrono::Utc;
+use http::header::{Entry, HeaderMap, HeaderName, HeaderValue, USER_AGENT};
+use http::{Extensions, Method, Request, Response, StatusCode, Uri};
 use hyper::Body;
 use wildmatch::WildMatch;
 
+use crate::handler::http::fingerprint::{ClientFingerprint, UserAgentSelector};
+use crate::handler::http::header_pattern::HeaderFields;
+use crate::handler::http::pressure::PressureSelector;
+use crate::handler::http::time_window::TimeWindow;
+use crate::handler::http::tls_client::{TlsClient, TlsClientSelector};
+use crate::metadata::ClientLabels;
 use crate::raw_config::Role;
 
 /// Selector could
@@ -16,40 +24,86 @@ pub struct Selector {
     pub path: Option<WildMatch>,
     pub method: Option<Method>,
     pub code: Option<StatusCode>,
-    pub request_headers: Option<HeaderMap>,
-    pub response_headers: Option<HeaderMap>,
+    /// header fields keyed by name patterns, see [HeaderFields].
+    pub request_headers: Option<HeaderFields>,
+    pub response_headers: Option<HeaderFields>,
+    pub user_agent: Option<UserAgentSelector>,
+    /// JA3 fingerprint of the TLS client, only available if TLS is enabled.
+    pub ja3: Option<String>,
+    /// certificate and ALPN protocol of the TLS client, only available if TLS is enabled.
+    pub tls_client: Option<TlsClientSelector>,
+    /// labels of the client, resolved by the metadata resolver.
+    pub labels: Option<HashMap<String, String>>,
+    pub time_window: Option<TimeWindow>,
+    pub pressure: Option<PressureSelector>,
+    /// percent of the exchanges selected, drawn by
+    /// [Rule::admits](crate::handler::http::rule::Rule::admits).
+    pub percent: Option<f64>,
+}
+
+/// OptIn makes the rules only apply to the requests carrying the header, e.g. sent by the test
+/// clients on demand.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OptIn {
+    pub header: HeaderName,
+    /// the header must have the value if set, e.g. a token.
+    pub value: Option<HeaderValue>,
+}
+
+impl OptIn {
+    /// take strips the header from the request, and returns whether the request opts in.
+    pub fn take<T>(&self, request: &mut Request<T>) -> bool {
+        let values: Vec<_> = match request.headers_mut().entry(&self.header) {
+            Entry::Occupied(entry) => entry.remove_entry_mult().1.collect(),
+            Entry::Vacant(_) => return false,
+        };
+        match &self.value {
+            None => true,
+            Some(expected) => values.iter().any(|value| value == expected),
+        }
+    }
 }
 
 /// select_role checks the given src_ip (or dst_ip) is contained in the give role.
+/// The IPv4-mapped IPv6 addresses are matched as their IPv4 ones.
 pub fn select_role(src_ip: &IpAddr, dst_ip: &IpAddr, role: &Role) -> bool {
-    let src_ipv4 = match src_ip {
-        IpAddr::V4(ipv4) => ipv4,
-        _ => return false,
-    };
-    let dst_ipv4 = match dst_ip {
-        IpAddr::V4(ipv4) => ipv4,
-        _ => return false,
+    let (ips, ip) = match role {
+        Role::Client(ips) => (ips, canonical(src_ip)),
+        Role::Server(ips) => (ips, canonical(dst_ip)),
     };
+    ips.iter().any(|role_ip| canonical(role_ip) == ip)
+}
 
-    match role {
-        Role::Client(ipv4s) => ipv4s.iter().any(|ipv4| ipv4 == src_ipv4),
-        Role::Server(ipv4s) => ipv4s.iter().any(|ipv4| ipv4 == dst_ipv4),
+fn canonical(ip: &IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(ipv6) => match ipv6.to_ipv4_mapped() {
+            Some(ipv4) => IpAddr::V4(ipv4),
+            None => *ip,
+        },
+        IpAddr::V4(_) => *ip,
     }
 }
 
+/// select_connection would check the given connection is matched with the selector of a tcp rule,
+/// only the port and the time selectors are available.
+pub fn select_connection(port: u16, selector: &Selector) -> bool {
+    select_time(selector) && selector.port.iter().all(|p| port == *p)
+}
+
 /// select_request would check the given request is matched with the given selector.
 pub fn select_request(port: u16, request: &Request<Body>, selector: &Selector) -> bool {
-    selector.port.iter().all(|p| port == *p)
+    select_time(selector)
+        && selector.port.iter().all(|p| port == *p)
         && selector
             .path
             .iter()
             .all(|p| p.matches(request.uri().path()))
         && selector.method.iter().all(|m| request.method() == m)
-        && selector.request_headers.iter().all(|fields| {
-            fields
-                .iter()
-                .all(|(header, value)| request.headers().get_all(header).iter().any(|f| f == value))
-        })
+        && selector
+            .request_headers
+            .iter()
+            .all(|fields| fields.matches(request.headers()))
+        && select_client(request.headers(), request.extensions(), selector)
 }
 
 /// select_response would check the given request and response is matched with the given selector.
@@ -61,24 +115,68 @@ pub fn select_response(
     response: &Response<Body>,
     selector: &Selector,
 ) -> bool {
-    selector.port.iter().all(|p| port == *p)
+    select_time(selector)
+        && selector.port.iter().all(|p| port == *p)
         && selector.path.iter().all(|p| p.matches(uri.path()))
         && selector.method.iter().all(|m| method == m)
         && selector.code.iter().all(|code| response.status() == *code)
-        && selector.request_headers.iter().all(|fields| {
-            fields
-                .iter()
-                .all(|(header, value)| request_headers.get_all(header).iter().any(|f| f == value))
-        })
-        && selector.response_headers.iter().all(|fields| {
-            fields.iter().all(|(header, value)| {
-                response
-                    .headers()
-                    .get_all(header)
+        && selector
+            .request_headers
+            .iter()
+            .all(|fields| fields.matches(request_headers))
+        && selector
+            .response_headers
+            .iter()
+            .all(|fields| fields.matches(response.headers()))
+        && select_client(request_headers, response.extensions(), selector)
+}
+
+/// select_time would check the rule is in its active time window and the node is under the
+/// expected pressure.
+fn select_time(selector: &Selector) -> bool {
+    selector
+        .time_window
+        .iter()
+        .all(|window| window.contains(Utc::now()))
+        && selector
+            .pressure
+            .iter()
+            .all(PressureSelector::is_under_pressure)
+}
+
+/// select_client would check the client fingerprints and labels, the JA3 fingerprint, the TLS
+/// client and labels are carried by the extensions of the request or response.
+fn select_client(
+    request_headers: &HeaderMap,
+    extensions: &Extensions,
+    selector: &Selector,
+) -> bool {
+    selector.user_agent.iter().all(|ua| {
+        request_headers
+            .get_all(USER_AGENT)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .any(|value| ua.matches(value))
+    }) && selector.ja3.iter().all(|ja3| {
+        extensions
+            .get::<ClientFingerprint>()
+            .map(|fingerprint| &fingerprint.ja3 == ja3)
+            .unwrap_or(false)
+    }) && selector.tls_client.iter().all(|tls_client| {
+        extensions
+            .get::<TlsClient>()
+            .map(|client| tls_client.matches(client))
+            .unwrap_or(false)
+    }) && selector.labels.iter().all(|labels| {
+        extensions
+            .get::<ClientLabels>()
+            .map(|ClientLabels(client)| {
+                labels
                     .iter()
-                    .any(|f| f == value)
+                    .all(|(name, value)| client.get(name) == Some(value))
             })
-        })
+            .unwrap_or(false)
+    })
 }
 
 #[cfg(test)]
@@ -86,7 +184,57 @@ mod tests {
     use http::Request;
     use hyper::Body;
 
-    use crate::handler::http::selector::{select_request, Selector};
+    use crate::handler::http::selector::{select_request, select_role, OptIn, Selector};
+    use crate::raw_config::Role;
+
+    #[test]
+    fn test_opt_in() {
+        let opt_in = OptIn {
+            header: "x-chaos".parse().unwrap(),
+            value: Some("token".parse().unwrap()),
+        };
+        let request = |value: &str| {
+            Request::builder()
+                .header("x-chaos", value)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let mut req = request("token");
+        assert!(opt_in.take(&mut req));
+        assert!(req.headers().get("x-chaos").is_none());
+
+        let mut req = request("other");
+        assert!(!opt_in.take(&mut req));
+        assert!(req.headers().get("x-chaos").is_none());
+
+        let mut req = Request::builder().body(Body::empty()).unwrap();
+        assert!(!opt_in.take(&mut req));
+    }
+
+    #[test]
+    fn test_select_role() {
+        let role = Role::Server(vec![
+            "10.0.0.2".parse().unwrap(),
+            "fd00::2".parse().unwrap(),
+        ]);
+        let client = "10.0.0.1".parse().unwrap();
+        assert!(select_role(&client, &"10.0.0.2".parse().unwrap(), &role));
+        assert!(select_role(
+            &client,
+            &"::ffff:10.0.0.2".parse().unwrap(),
+            &role
+        ));
+        assert!(select_role(&client, &"fd00::2".parse().unwrap(), &role));
+        assert!(!select_role(&client, &"fd00::3".parse().unwrap(), &role));
+        let role = Role::Client(vec!["fd00::1".parse().unwrap()]);
+        assert!(select_role(
+            &"fd00::1".parse().unwrap(),
+            &"fd00::2".parse().unwrap(),
+            &role
+        ));
+        assert!(!select_role(&client, &"fd00::2".parse().unwrap(), &role));
+    }
 
     #[test]
     fn test_select_request() {
@@ -98,6 +246,13 @@ mod tests {
             code: None,
             request_headers: None,
             response_headers: None,
+            user_agent: None,
+            ja3: None,
+            tls_client: None,
+            labels: None,
+            time_window: None,
+            pressure: None,
+            percent: None,
         };
         let req = Request::builder().body(Body::empty()).unwrap();
         assert_eq!(select_request(port, &req, &selector), true);
@@ -109,6 +264,13 @@ mod tests {
             code: None,
             request_headers: None,
             response_headers: None,
+            user_agent: None,
+            ja3: None,
+            tls_client: None,
+            labels: None,
+            time_window: None,
+            pressure: None,
+            percent: None,
         };
         let req = Request::builder()
             .uri("http://www.google.com/src/")
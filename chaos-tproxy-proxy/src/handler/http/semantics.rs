@@ -0,0 +1,91 @@
+use bytes::Bytes;
+use http::header::{CONTENT_LENGTH, TRANSFER_ENCODING};
+use http::{HeaderMap, HeaderValue, Method, Response, StatusCode};
+use hyper::Body;
+
+/// sized_body returns the body of the contents and declares their length in the headers, so that
+/// the peer never reads the body by the length of the one it replaces.
+pub fn sized_body(headers: &mut HeaderMap, contents: impl Into<Bytes>) -> Body {
+    let contents = contents.into();
+    headers.remove(TRANSFER_ENCODING);
+    headers.insert(CONTENT_LENGTH, HeaderValue::from(contents.len()));
+    contents.into()
+}
+
+/// body_allowed checks whether the response of the status to a request of the method may carry a
+/// body, the responses to HEAD requests and the 1xx, 204 and 304 ones never do (RFC 7230 3.3).
+pub fn body_allowed(method: &Method, status: StatusCode) -> bool {
+    method != Method::HEAD
+        && !status.is_informational()
+        && status != StatusCode::NO_CONTENT
+        && status != StatusCode::NOT_MODIFIED
+}
+
+/// conform_response drops the body the response must not carry, e.g. after a rule replaces its
+/// code by 204. The `Content-Length` of the responses to HEAD requests and of the 304 ones is the
+/// length of the representation they stand for, so it is kept, the 1xx and 204 ones lose it.
+pub fn conform_response(method: &Method, response: &mut Response<Body>) {
+    let status = response.status();
+    if body_allowed(method, status) {
+        return;
+    }
+    *response.body_mut() = Body::empty();
+    let headers = response.headers_mut();
+    headers.remove(TRANSFER_ENCODING);
+    if status.is_informational() || status == StatusCode::NO_CONTENT {
+        headers.remove(CONTENT_LENGTH);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::header::{CONTENT_LENGTH, TRANSFER_ENCODING};
+    use http::{HeaderMap, Method, Response, StatusCode};
+    use hyper::Body;
+
+    use crate::handler::http::semantics::{body_allowed, conform_response, sized_body};
+
+    #[tokio::test]
+    async fn test_conform_response() {
+        let mut headers = HeaderMap::new();
+        headers.insert(TRANSFER_ENCODING, "chunked".parse().unwrap());
+        let body = sized_body(&mut headers, "hello");
+        assert_eq!(headers[CONTENT_LENGTH], "5");
+        assert!(headers.get(TRANSFER_ENCODING).is_none());
+        assert_eq!(hyper::body::to_bytes(body).await.unwrap(), "hello");
+
+        assert!(body_allowed(&Method::GET, StatusCode::OK));
+        assert!(!body_allowed(&Method::HEAD, StatusCode::OK));
+        assert!(!body_allowed(&Method::GET, StatusCode::CONTINUE));
+        assert!(!body_allowed(&Method::GET, StatusCode::NO_CONTENT));
+        assert!(!body_allowed(&Method::GET, StatusCode::NOT_MODIFIED));
+
+        for (method, status, length) in [
+            (Method::GET, StatusCode::OK, Some("5")),
+            (Method::HEAD, StatusCode::OK, Some("5")),
+            (Method::GET, StatusCode::NOT_MODIFIED, Some("5")),
+            (Method::GET, StatusCode::NO_CONTENT, None),
+        ] {
+            let mut response = Response::builder()
+                .status(status)
+                .header(CONTENT_LENGTH, "5")
+                .body(Body::from("hello"))
+                .unwrap();
+            conform_response(&method, &mut response);
+            assert_eq!(
+                response
+                    .headers()
+                    .get(CONTENT_LENGTH)
+                    .map(|value| value.to_str().unwrap()),
+                length
+            );
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let expected = if method == Method::GET && status == StatusCode::OK {
+                "hello"
+            } else {
+                ""
+            };
+            assert_eq!(body, expected);
+        }
+    }
+}
@@ -0,0 +1,34 @@
+use std::time::Duration;
+
+use futures::{future, stream, StreamExt};
+use hyper::Body;
+use tokio::time::sleep;
+
+/// stall_body holds the body back for the duration, the status and the headers are sent at once.
+/// The clients see a response received promptly, whose body is stalled.
+pub fn stall_body(body: Body, duration: Duration) -> Body {
+    let stall = stream::once(sleep(duration)).filter_map(|_| future::ready(None));
+    Body::wrap_stream(stall.chain(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::StreamExt;
+    use hyper::Body;
+    use tokio::time::Instant;
+
+    use crate::handler::http::stall::stall_body;
+
+    #[tokio::test]
+    async fn test_stall_body() {
+        let started_at = Instant::now();
+        let chunks: Vec<_> = stall_body(Body::from("hello"), Duration::from_millis(100))
+            .map(|chunk| chunk.unwrap())
+            .collect()
+            .await;
+        assert!(started_at.elapsed() >= Duration::from_millis(100));
+        assert_eq!(chunks, vec!["hello"]);
+    }
+}
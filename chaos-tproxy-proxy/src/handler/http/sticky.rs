@@ -0,0 +1,117 @@
+use http::header::{HeaderMap, HeaderName, COOKIE};
+use http::Extensions;
+
+use crate::handler::http::client_ip::ClientAddr;
+
+/// StickyOn tells the session of a request, by a header, a cookie or the IP of the client.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StickyOn {
+    Header(HeaderName),
+    Cookie(String),
+    SourceIp,
+}
+
+impl StickyOn {
+    /// session returns the session of the request, none if the request tells none.
+    pub fn session(&self, headers: &HeaderMap, extensions: &Extensions) -> Option<String> {
+        match self {
+            StickyOn::Header(name) => headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string),
+            StickyOn::Cookie(name) => headers
+                .get_all(COOKIE)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(';'))
+                .find_map(|pair| {
+                    let (key, value) = pair.trim().split_once('=')?;
+                    (key == name).then(|| value.to_string())
+                }),
+            StickyOn::SourceIp => extensions
+                .get::<ClientAddr>()
+                .map(|ClientAddr(addr)| addr.ip().to_string()),
+        }
+    }
+}
+
+/// Sticky makes the draws of a rule, by its `percent` and its weighted actions, the same for all
+/// the requests of a session. A draw is a hash of the session and the seed of the rule, so that
+/// the rules draw independently of each other, and the instances of the proxy alike.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sticky {
+    pub on: StickyOn,
+    /// seed is the name of the rule, or its config if unnamed.
+    pub seed: String,
+}
+
+impl Sticky {
+    /// roll returns a number in [0, 1) of the session of the request for the purpose of the
+    /// draw, or a random one if the request tells no session.
+    pub fn roll(&self, headers: &HeaderMap, extensions: &Extensions, purpose: &str) -> f64 {
+        let session = match self.on.session(headers, extensions) {
+            Some(session) => session,
+            None => return rand::random(),
+        };
+        let digest = md5::compute(format!("{}\n{}\n{}", self.seed, purpose, session));
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&digest.0[..8]);
+        // the 53 bits of the mantissa of a f64
+        (u64::from_be_bytes(bytes) >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use http::header::COOKIE;
+    use http::Request;
+
+    use crate::handler::http::client_ip::ClientAddr;
+    use crate::handler::http::sticky::{Sticky, StickyOn};
+
+    #[test]
+    fn test_sticky() {
+        let request = |session: &str| {
+            let mut request = Request::get("/")
+                .header("x-session", session)
+                .header(COOKIE, format!("theme=dark; session={}", session))
+                .body(())
+                .unwrap();
+            let client: SocketAddr = format!("10.0.0.{}:40000", session.len()).parse().unwrap();
+            request.extensions_mut().insert(ClientAddr(client));
+            request
+        };
+        let alice = request("alice");
+        let on = StickyOn::Cookie("session".to_string());
+        assert_eq!(
+            on.session(alice.headers(), alice.extensions()).as_deref(),
+            Some("alice")
+        );
+        let on = StickyOn::SourceIp;
+        assert_eq!(
+            on.session(alice.headers(), alice.extensions()).as_deref(),
+            Some("10.0.0.5")
+        );
+
+        let sticky = |seed: &str| Sticky {
+            on: StickyOn::Header("x-session".parse().unwrap()),
+            seed: seed.to_string(),
+        };
+        let roll = |sticky: &Sticky, session: &str| {
+            let request = request(session);
+            sticky.roll(request.headers(), request.extensions(), "percent")
+        };
+        let slow = sticky("slow-api");
+        let first = roll(&slow, "alice");
+        assert!((0.0..1.0).contains(&first));
+        assert_eq!(roll(&slow, "alice"), first);
+        assert_ne!(roll(&slow, "bob"), first);
+        assert_ne!(roll(&sticky("abort-api"), "alice"), first);
+        assert_ne!(
+            slow.roll(alice.headers(), alice.extensions(), "weighted"),
+            first
+        );
+    }
+}
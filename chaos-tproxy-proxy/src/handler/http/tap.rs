@@ -0,0 +1,64 @@
+use bytes::Bytes;
+use futures::TryStreamExt;
+use hyper::body::HttpBody;
+use hyper::Body;
+
+/// tap passes every chunk of the body to the function on its way, e.g. to count or capture it.
+/// The guard is dropped along with the body, i.e. once the body is sent or dropped by the peer. An
+/// empty body is kept as it is, so that its framing is untouched, and the guard is dropped at once.
+pub fn tap<F, G>(body: Body, mut f: F, guard: G) -> Body
+where
+    F: FnMut(&Bytes) + Send + 'static,
+    G: Send + 'static,
+{
+    if body.is_end_stream() {
+        return body;
+    }
+    Body::wrap_stream(body.map_ok(move |chunk| {
+        let _ = &guard;
+        f(&chunk);
+        chunk
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use futures::stream;
+    use hyper::Body;
+
+    use crate::handler::http::tap::tap;
+
+    struct Guard(Arc<AtomicUsize>);
+
+    impl Drop for Guard {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tap() {
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let chunks = stream::iter(vec![Ok::<_, std::io::Error>("hello"), Ok(" world")]);
+        let seen = Arc::new(AtomicUsize::new(0));
+        let counter = seen.clone();
+        let body = tap(
+            Body::wrap_stream(chunks),
+            move |chunk| {
+                counter.fetch_add(chunk.len(), Ordering::SeqCst);
+            },
+            Guard(dropped.clone()),
+        );
+        assert_eq!(dropped.load(Ordering::SeqCst), 0);
+        assert_eq!(hyper::body::to_bytes(body).await.unwrap(), "hello world");
+        assert_eq!(seen.load(Ordering::SeqCst), 11);
+        assert_eq!(dropped.load(Ordering::SeqCst), 1);
+
+        // the guard of an empty body is dropped at once
+        let _body = tap(Body::empty(), |_| {}, Guard(dropped.clone()));
+        assert_eq!(dropped.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,114 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Datelike, Duration, NaiveTime, Utc, Weekday};
+use chrono_tz::Tz;
+
+/// TimeWindow restricts the rules to be active in a daily time window, e.g. 09:00 - 17:00 on
+/// weekdays. A window whose end is earlier than its start spans midnight, and belongs to the day
+/// it starts.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct TimeWindow {
+    /// Active on all days if `None`.
+    pub days: Option<Vec<Weekday>>,
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+    pub timezone: Tz,
+}
+
+impl TimeWindow {
+    pub fn new(
+        days: Option<&[String]>,
+        start: Option<&str>,
+        end: Option<&str>,
+        timezone: Option<&str>,
+    ) -> Result<Self> {
+        let days = days
+            .map(|days| {
+                days.iter()
+                    .map(|day| {
+                        day.parse::<Weekday>()
+                            .map_err(|_| anyhow!("invalid day of week: {}", day))
+                    })
+                    .collect::<Result<Vec<_>>>()
+            })
+            .transpose()?;
+        let parse_time = |time: Option<&str>, default: NaiveTime| -> Result<NaiveTime> {
+            match time {
+                None => Ok(default),
+                Some(time) => NaiveTime::parse_from_str(time, "%H:%M")
+                    .or_else(|_| NaiveTime::parse_from_str(time, "%H:%M:%S"))
+                    .map_err(|_| anyhow!("invalid time of day: {}", time)),
+            }
+        };
+        Ok(Self {
+            days,
+            start: parse_time(start, NaiveTime::from_hms(0, 0, 0))?,
+            end: parse_time(end, NaiveTime::from_hms(23, 59, 59))?,
+            timezone: timezone
+                .unwrap_or("UTC")
+                .parse()
+                .map_err(|e| anyhow!("invalid timezone: {}", e))?,
+        })
+    }
+
+    pub fn contains(&self, now: DateTime<Utc>) -> bool {
+        let now = now.with_timezone(&self.timezone);
+        let time = now.time();
+        let day = if self.start <= self.end {
+            if time < self.start || time > self.end {
+                return false;
+            }
+            now.weekday()
+        } else if time >= self.start {
+            now.weekday()
+        } else if time <= self.end {
+            (now - Duration::days(1)).weekday()
+        } else {
+            return false;
+        };
+        self.days
+            .as_ref()
+            .map(|days| days.contains(&day))
+            .unwrap_or(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use crate::handler::http::time_window::TimeWindow;
+
+    #[test]
+    fn test_time_window() {
+        let weekdays: Vec<String> = ["Mon", "Tue", "Wed", "Thu", "Friday"]
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        let window = TimeWindow::new(
+            Some(&weekdays),
+            Some("09:00"),
+            Some("17:00"),
+            Some("Asia/Shanghai"),
+        )
+        .unwrap();
+        // Friday 10:00 at Shanghai
+        assert!(window.contains(Utc.ymd(2022, 6, 3).and_hms(2, 0, 0)));
+        // Friday 18:00 at Shanghai
+        assert!(!window.contains(Utc.ymd(2022, 6, 3).and_hms(10, 0, 0)));
+        // Saturday 10:00 at Shanghai
+        assert!(!window.contains(Utc.ymd(2022, 6, 4).and_hms(2, 0, 0)));
+
+        let window = TimeWindow::new(Some(&weekdays), Some("22:00"), Some("06:00"), None).unwrap();
+        // Friday 23:00
+        assert!(window.contains(Utc.ymd(2022, 6, 3).and_hms(23, 0, 0)));
+        // Saturday 01:00, the window started on Friday
+        assert!(window.contains(Utc.ymd(2022, 6, 4).and_hms(1, 0, 0)));
+        // Sunday 01:00
+        assert!(!window.contains(Utc.ymd(2022, 6, 5).and_hms(1, 0, 0)));
+        // Monday 12:00
+        assert!(!window.contains(Utc.ymd(2022, 6, 6).and_hms(12, 0, 0)));
+
+        assert!(TimeWindow::new(None, Some("25:00"), None, None).is_err());
+        assert!(TimeWindow::new(None, None, None, Some("Mars/Base")).is_err());
+    }
+}
@@ -0,0 +1,126 @@
+use rustls::Certificate;
+use tracing::debug;
+use wildmatch::WildMatch;
+use x509_parser::extensions::GeneralName;
+use x509_parser::parse_x509_certificate;
+
+/// TlsClient is attached to the extensions of the requests (and responses) of a TLS connection,
+/// so that the selectors could match the calling identity of an mTLS mesh.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct TlsClient {
+    /// subject of the client certificate, e.g. `CN=checkout, O=shop`. The clients only present a
+    /// certificate if `client_ca_file` is set.
+    pub subject: Option<String>,
+    /// DNS names, URIs (e.g. SPIFFE IDs) and emails of the subject alternative names of the
+    /// client certificate.
+    pub sans: Vec<String>,
+    /// ALPN protocol negotiated with the client, e.g. `h2`.
+    pub alpn: Option<String>,
+}
+
+impl TlsClient {
+    /// new reads the attributes of the certificate, the first one presented by the client, and of
+    /// the ALPN protocol.
+    pub fn new(certificate: Option<&Certificate>, alpn: Option<&[u8]>) -> Self {
+        let mut client = Self {
+            alpn: alpn.map(|alpn| String::from_utf8_lossy(alpn).into_owned()),
+            ..Default::default()
+        };
+        let certificate = match certificate.map(|cert| parse_x509_certificate(&cert.0)) {
+            None => return client,
+            Some(Ok((_, certificate))) => certificate,
+            Some(Err(e)) => {
+                debug!("fail to parse client certificate: {}", e);
+                return client;
+            }
+        };
+        client.subject = Some(certificate.subject().to_string());
+        if let Ok(Some(san)) = certificate.subject_alternative_name() {
+            client.sans = san
+                .value
+                .general_names
+                .iter()
+                .filter_map(|name| match name {
+                    GeneralName::DNSName(name)
+                    | GeneralName::URI(name)
+                    | GeneralName::RFC822Name(name) => Some(name.to_string()),
+                    _ => None,
+                })
+                .collect();
+        }
+        client
+    }
+}
+
+/// TlsClientSelector matches the TLS client of the connection, the subject and the SANs support
+/// wildcards, any SAN of the certificate could match.
+#[derive(Debug, Clone)]
+pub struct TlsClientSelector {
+    pub subject: Option<WildMatch>,
+    pub san: Option<WildMatch>,
+    pub alpn: Option<String>,
+}
+
+impl TlsClientSelector {
+    pub fn matches(&self, client: &TlsClient) -> bool {
+        let subject = client.subject.as_deref();
+        self.subject
+            .iter()
+            .all(|pattern| subject.is_some_and(|subject| pattern.matches(subject)))
+            && self
+                .san
+                .iter()
+                .all(|pattern| client.sans.iter().any(|san| pattern.matches(san)))
+            && self
+                .alpn
+                .iter()
+                .all(|alpn| client.alpn.as_ref() == Some(alpn))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rcgen::{CertificateParams, DistinguishedName, DnType, SanType};
+    use rustls::Certificate;
+    use wildmatch::WildMatch;
+
+    use crate::handler::http::tls_client::{TlsClient, TlsClientSelector};
+
+    #[test]
+    fn test_tls_client() {
+        let mut params = CertificateParams::new(vec!["checkout.shop.svc".to_string()]);
+        let mut name = DistinguishedName::new();
+        name.push(DnType::CommonName, "checkout");
+        params.distinguished_name = name;
+        params
+            .subject_alt_names
+            .push(SanType::Rfc822Name("checkout@shop.local".to_string()));
+        let der = rcgen::Certificate::from_params(params)
+            .unwrap()
+            .serialize_der()
+            .unwrap();
+
+        let client = TlsClient::new(Some(&Certificate(der)), Some(&b"h2"[..]));
+        assert_eq!(client.subject.as_deref(), Some("CN=checkout"));
+        assert_eq!(
+            client.sans,
+            vec!["checkout.shop.svc", "checkout@shop.local"]
+        );
+        assert_eq!(client.alpn.as_deref(), Some("h2"));
+
+        let selector = TlsClientSelector {
+            subject: Some(WildMatch::new("CN=checkout*")),
+            san: Some(WildMatch::new("*.shop.svc")),
+            alpn: Some("h2".to_string()),
+        };
+        assert!(selector.matches(&client));
+        let anonymous = TlsClient::new(None, Some(&b"h2"[..]));
+        assert!(!selector.matches(&anonymous));
+        let selector = TlsClientSelector {
+            subject: None,
+            san: None,
+            alpn: Some("http/1.1".to_string()),
+        };
+        assert!(!selector.matches(&client));
+    }
+}
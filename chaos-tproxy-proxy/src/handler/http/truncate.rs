@@ -0,0 +1,187 @@
+use std::error::Error;
+use std::fmt;
+
+use futures::{future, stream, StreamExt};
+use http::header::CONTENT_LENGTH;
+use http::Response;
+use hyper::body::HttpBody;
+use hyper::Body;
+
+use crate::handler::http::action::{Abort, AbortMode};
+
+/// TruncateAfter tells how much of the body is sent before it is cut.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TruncateAfter {
+    Bytes(u64),
+    /// percent of the length of the body, in [0, 100].
+    Percent(f64),
+}
+
+impl fmt::Display for TruncateAfter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TruncateAfter::Bytes(bytes) => write!(f, "{}", bytes),
+            TruncateAfter::Percent(percent) => write!(f, "{}%", percent),
+        }
+    }
+}
+
+/// TruncateAction cuts the body of the response once a part of it is sent, the status, the
+/// headers (and the `Content-Length` if any) are sent as usual. Unlike an abort, the clients see
+/// a truncated download or a partial JSON document. The connection is then handled as by the
+/// abort mode, closed, reset or kept open without sending anything more.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TruncateAction {
+    pub after: TruncateAfter,
+    pub mode: AbortMode,
+}
+
+/// Truncating is the state of a truncated body, `None` once the body is cut or ended.
+struct Truncating {
+    body: Option<Body>,
+    /// remaining bytes sent before the body is cut.
+    remaining: u64,
+    /// cut is set once bytes of the body are dropped.
+    cut: bool,
+}
+
+impl TruncateAction {
+    /// apply cuts the body of the response. The length of a body cut by percent is its
+    /// `Content-Length`, or its size if known, or else the body is read in memory.
+    pub async fn apply(&self, response: &mut Response<Body>) -> anyhow::Result<()> {
+        let limit = match self.after {
+            TruncateAfter::Bytes(bytes) => bytes,
+            TruncateAfter::Percent(percent) => {
+                let length = response
+                    .headers()
+                    .get(CONTENT_LENGTH)
+                    .and_then(|value| value.to_str().ok()?.parse().ok())
+                    .or_else(|| response.body().size_hint().exact());
+                let length = match length {
+                    Some(length) => length,
+                    None => {
+                        let contents = hyper::body::to_bytes(response.body_mut()).await?;
+                        let length = contents.len() as u64;
+                        *response.body_mut() = contents.into();
+                        length
+                    }
+                };
+                (length as f64 * percent / 100.0) as u64
+            }
+        };
+        let body = std::mem::take(response.body_mut());
+        *response.body_mut() = self.body(body, limit);
+        Ok(())
+    }
+
+    /// body returns the body cut after `limit` bytes, by an [Abort] error making hyper close the
+    /// connection, or by never sending anything more in the timeout mode. A shorter body is sent
+    /// whole.
+    pub fn body(&self, body: Body, limit: u64) -> Body {
+        let mode = self.mode;
+        let state = Truncating {
+            body: Some(body),
+            remaining: limit,
+            cut: false,
+        };
+        let chunks = stream::unfold(state, move |mut state| async move {
+            let body = state.body.as_mut()?;
+            let chunk = if state.cut {
+                None
+            } else {
+                Some(body.next().await?)
+            };
+            match chunk {
+                Some(Ok(mut chunk)) if state.remaining > 0 => {
+                    if chunk.len() as u64 > state.remaining {
+                        chunk.truncate(state.remaining as usize);
+                        state.cut = true;
+                    }
+                    state.remaining -= chunk.len() as u64;
+                    Some((Ok(chunk), state))
+                }
+                Some(Err(e)) => {
+                    state.body = None;
+                    Some((Err(e.into()), state))
+                }
+                // the body goes on beyond the limit
+                _ => {
+                    state.body = None;
+                    if mode == AbortMode::Timeout {
+                        future::pending::<()>().await;
+                    }
+                    // the body is pending once, so that hyper writes the part it buffered before
+                    // the error makes it drop the connection
+                    tokio::task::yield_now().await;
+                    let cut: Box<dyn Error + Send + Sync> = Box::new(Abort(mode));
+                    Some((Err(cut), state))
+                }
+            }
+        });
+        Body::wrap_stream(chunks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use futures::{stream, StreamExt};
+    use http::header::CONTENT_LENGTH;
+    use http::Response;
+    use hyper::Body;
+    use tokio::time::timeout;
+
+    use crate::handler::http::action::{Abort, AbortMode};
+    use crate::handler::http::truncate::{TruncateAction, TruncateAfter};
+
+    #[tokio::test]
+    async fn test_truncate() {
+        let chunked = || {
+            let chunks = ["hello", ", ", "world"].map(Ok::<_, hyper::Error>);
+            Body::wrap_stream(stream::iter(chunks))
+        };
+        let action = TruncateAction {
+            after: TruncateAfter::Bytes(6),
+            mode: AbortMode::Close,
+        };
+        let mut body = action.body(chunked(), 6);
+        assert_eq!(body.next().await.unwrap().unwrap(), "hello");
+        assert_eq!(body.next().await.unwrap().unwrap(), ",");
+        let cut = body.next().await.unwrap().unwrap_err();
+        let cut = std::error::Error::source(&cut).and_then(|e| e.downcast_ref::<Abort>());
+        assert_eq!(cut, Some(&Abort(AbortMode::Close)));
+        assert!(body.next().await.is_none());
+
+        let whole: Vec<_> = action
+            .body(Body::from("hello"), 6)
+            .map(|chunk| chunk.unwrap())
+            .collect()
+            .await;
+        assert_eq!(whole, vec![Bytes::from("hello")]);
+
+        // the percent of the Content-Length, kept as is
+        let action = TruncateAction {
+            after: TruncateAfter::Percent(50.0),
+            mode: AbortMode::Timeout,
+        };
+        let mut response = Response::builder()
+            .header(CONTENT_LENGTH, 12)
+            .body(chunked())
+            .unwrap();
+        action.apply(&mut response).await.unwrap();
+        assert_eq!(response.headers()[CONTENT_LENGTH], "12");
+        let body = response.body_mut();
+        assert_eq!(body.next().await.unwrap().unwrap(), "hello");
+        assert_eq!(body.next().await.unwrap().unwrap(), ",");
+        assert!(timeout(Duration::from_millis(100), body.next())
+            .await
+            .is_err());
+
+        // the percent of the body read in memory
+        let mut response = Response::new(chunked());
+        action.apply(&mut response).await.unwrap();
+        assert_eq!(response.body_mut().next().await.unwrap().unwrap(), "hello,");
+    }
+}
@@ -0,0 +1,233 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use anyhow::Result;
+use bytes::Bytes;
+use http::header::CONTENT_TYPE;
+use http::response::Parts;
+use http::{Method, StatusCode, Uri};
+use serde::Serialize;
+use tokio::fs::OpenOptions;
+use tokio::io::AsyncWriteExt;
+
+use crate::snapshot::unix_millis;
+
+/// ResponseValidator checks the responses of the upstreams during the chaos runs, so that the
+/// proxy detects the canaries going wrong. The responses are buffered to be validated.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResponseValidator {
+    /// status_classes are the accepted classes of the status, e.g. `[2, 3]` for 2xx and 3xx.
+    pub status_classes: Option<Vec<u16>>,
+    /// content_types are the accepted media types, without the parameters.
+    pub content_types: Option<Vec<String>>,
+    pub max_size: Option<usize>,
+    /// json would check that the bodies of the JSON responses are well-formed.
+    pub json: bool,
+    pub on_violation: OnViolation,
+}
+
+/// OnViolation tells what happens to an invalid response, the violations are logged anyway.
+#[derive(Debug, Clone, PartialEq)]
+pub enum OnViolation {
+    /// Log passes the response through.
+    Log,
+    /// Replace replaces the response with an error of the status.
+    Replace(StatusCode),
+    /// Quarantine appends the response to the capture file, and replaces it like `Replace`.
+    Quarantine { status: StatusCode, path: PathBuf },
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Violation {
+    Status(StatusCode),
+    ContentType(Option<String>),
+    TooLarge(usize),
+    MalformedJson,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::Status(status) => write!(f, "unexpected status {}", status.as_u16()),
+            Violation::ContentType(Some(content_type)) => {
+                write!(f, "unexpected content type {}", content_type)
+            }
+            Violation::ContentType(None) => write!(f, "missing content type"),
+            Violation::TooLarge(size) => write!(f, "body of {} bytes is too large", size),
+            Violation::MalformedJson => write!(f, "malformed JSON body"),
+        }
+    }
+}
+
+impl ResponseValidator {
+    /// validate returns the violations of the response, empty if it is valid.
+    pub fn validate(&self, parts: &Parts, body: &Bytes) -> Vec<Violation> {
+        let mut violations = vec![];
+        if let Some(classes) = &self.status_classes {
+            if !classes.contains(&(parts.status.as_u16() / 100)) {
+                violations.push(Violation::Status(parts.status));
+            }
+        }
+        let content_type = media_type(parts);
+        if let Some(content_types) = &self.content_types {
+            let accepted = content_type.as_ref().is_some_and(|content_type| {
+                content_types
+                    .iter()
+                    .any(|accepted| accepted.eq_ignore_ascii_case(content_type))
+            });
+            // the empty bodies have nothing to type
+            if !accepted && !body.is_empty() {
+                violations.push(Violation::ContentType(content_type.clone()));
+            }
+        }
+        if let Some(max_size) = self.max_size {
+            if body.len() > max_size {
+                violations.push(Violation::TooLarge(body.len()));
+            }
+        }
+        let is_json = content_type.is_some_and(|content_type| {
+            content_type == "application/json" || content_type.ends_with("+json")
+        });
+        if self.json && is_json && serde_json::from_slice::<serde::de::IgnoredAny>(body).is_err() {
+            violations.push(Violation::MalformedJson);
+        }
+        violations
+    }
+}
+
+/// media_type returns the lowercase content type of the response without the parameters.
+fn media_type(parts: &Parts) -> Option<String> {
+    let content_type = parts.headers.get(CONTENT_TYPE)?.to_str().ok()?;
+    let media_type = content_type.split(';').next()?.trim();
+    Some(media_type.to_ascii_lowercase())
+}
+
+/// Quarantined is a line of the capture file, the body is encoded in base64.
+#[derive(Debug, Clone, Serialize)]
+pub struct Quarantined {
+    pub timestamp_ms: u64,
+    pub method: String,
+    pub uri: String,
+    pub status: u16,
+    pub headers: BTreeMap<String, String>,
+    pub body: String,
+    pub violations: Vec<String>,
+}
+
+impl Quarantined {
+    pub fn new(
+        method: &Method,
+        uri: &Uri,
+        parts: &Parts,
+        body: &Bytes,
+        violations: &[Violation],
+    ) -> Self {
+        Self {
+            timestamp_ms: unix_millis(SystemTime::now()),
+            method: method.to_string(),
+            uri: uri.to_string(),
+            status: parts.status.as_u16(),
+            headers: parts
+                .headers
+                .iter()
+                .map(|(name, value)| {
+                    (
+                        name.to_string(),
+                        String::from_utf8_lossy(value.as_bytes()).into_owned(),
+                    )
+                })
+                .collect(),
+            body: base64::encode(body),
+            violations: violations.iter().map(ToString::to_string).collect(),
+        }
+    }
+
+    /// append appends the response to the capture file as a JSON line.
+    pub async fn append(&self, path: &Path) -> Result<()> {
+        let mut line = serde_json::to_vec(self)?;
+        line.push(b'\n');
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        file.write_all(&line).await?;
+        // the line is written in the background, it could be lost if the file is dropped unflushed
+        file.flush().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use http::{Method, Response, StatusCode, Uri};
+
+    use crate::handler::http::validation::{
+        OnViolation, Quarantined, ResponseValidator, Violation,
+    };
+
+    #[tokio::test]
+    async fn test_validate() {
+        let validator = ResponseValidator {
+            status_classes: Some(vec![2, 3]),
+            content_types: Some(vec!["application/json".to_string()]),
+            max_size: Some(16),
+            json: true,
+            on_violation: OnViolation::Log,
+        };
+        let (valid, _) = Response::builder()
+            .header("content-type", "application/json; charset=utf-8")
+            .body(())
+            .unwrap()
+            .into_parts();
+        assert!(validator
+            .validate(&valid, &Bytes::from(r#"{"id": 1}"#))
+            .is_empty());
+        assert_eq!(
+            validator.validate(&valid, &Bytes::from(r#"{"id": 1, "name": "a"}"#)),
+            vec![Violation::TooLarge(22)]
+        );
+        assert_eq!(
+            validator.validate(&valid, &Bytes::from(r#"{"id": "#)),
+            vec![Violation::MalformedJson]
+        );
+
+        let (invalid, _) = Response::builder()
+            .status(StatusCode::BAD_GATEWAY)
+            .header("content-type", "text/html")
+            .body(())
+            .unwrap()
+            .into_parts();
+        let body = Bytes::from("<html>");
+        let violations = validator.validate(&invalid, &body);
+        assert_eq!(
+            violations,
+            vec![
+                Violation::Status(StatusCode::BAD_GATEWAY),
+                Violation::ContentType(Some("text/html".to_string())),
+            ]
+        );
+        // the empty bodies have no content type
+        let (empty, _) = Response::builder().body(()).unwrap().into_parts();
+        assert!(validator.validate(&empty, &Bytes::new()).is_empty());
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("quarantine.jsonl");
+        let uri: Uri = "/api/users".parse().unwrap();
+        let quarantined = Quarantined::new(&Method::GET, &uri, &invalid, &body, &violations);
+        quarantined.append(&path).await.unwrap();
+        quarantined.append(&path).await.unwrap();
+        let captured = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<serde_json::Value> = captured
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["status"], 502);
+        assert_eq!(lines[0]["body"], base64::encode("<html>"));
+        assert_eq!(lines[0]["violations"][0], "unexpected status 502");
+    }
+}
@@ -0,0 +1,281 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use anyhow::Result;
+use bytes::Bytes;
+use http::header::{CONNECTION, UPGRADE};
+use http::Request;
+use hyper::upgrade::OnUpgrade;
+use hyper::Body;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf, ReadHalf, WriteHalf};
+use tokio::sync::Mutex;
+use tokio::time::sleep;
+
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+
+/// WebSocketAction injects faults into the frames of the upgraded connection, the frames of both
+/// directions are affected.
+#[derive(Debug, PartialEq, Clone)]
+pub struct WebSocketAction {
+    /// delay of each frame.
+    pub delay: Option<Duration>,
+    /// percent of the data frames to drop, the fragmented messages and the control frames are
+    /// never dropped.
+    pub drop_percent: f64,
+    /// close the connection with the code after the duration.
+    pub close: Option<WebSocketClose>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct WebSocketClose {
+    pub code: u16,
+    pub after: Duration,
+}
+
+/// is_upgrade checks whether the request asks for upgrading to WebSocket.
+pub fn is_upgrade(request: &Request<Body>) -> bool {
+    let contains = |name, token: &str| {
+        request.headers().get_all(name).iter().any(|value| {
+            value
+                .to_str()
+                .map(|value| {
+                    value
+                        .split(',')
+                        .any(|v| v.trim().eq_ignore_ascii_case(token))
+                })
+                .unwrap_or(false)
+        })
+    };
+    contains(CONNECTION, "upgrade") && contains(UPGRADE, "websocket")
+}
+
+/// Tunnel is the upgraded connection to the server, waiting for the one of the client.
+#[derive(Debug)]
+pub struct Tunnel {
+    pub server: OnUpgrade,
+    pub action: Option<WebSocketAction>,
+}
+
+impl Tunnel {
+    /// relay tunnels the connection of the client, `read_buf` is read from the client but not
+    /// parsed yet.
+    pub async fn relay<C>(self, client: C, read_buf: Bytes) -> Result<()>
+    where
+        C: AsyncRead + AsyncWrite + Unpin,
+    {
+        let server = self.server.await?;
+        relay(Rewind::new(client, read_buf), server, self.action).await
+    }
+}
+
+/// Rewind reads the buffered bytes before the ones of the stream.
+struct Rewind<T> {
+    prefix: Bytes,
+    io: T,
+}
+
+impl<T> Rewind<T> {
+    fn new(io: T, prefix: Bytes) -> Self {
+        Self { prefix, io }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Rewind<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if !self.prefix.is_empty() {
+            let n = self.prefix.len().min(buf.remaining());
+            buf.put_slice(&self.prefix.split_to(n));
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.io).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Rewind<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
+
+/// relay tunnels the upgraded connections of the client and the server until both are closed.
+pub async fn relay<C, S>(client: C, server: S, action: Option<WebSocketAction>) -> Result<()>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let action = match action {
+        None => {
+            let (mut client, mut server) = (client, server);
+            tokio::io::copy_bidirectional(&mut client, &mut server).await?;
+            return Ok(());
+        }
+        Some(action) => action,
+    };
+    let (client_read, client_write) = tokio::io::split(client);
+    let (server_read, server_write) = tokio::io::split(server);
+    let client_write = Mutex::new(client_write);
+    let server_write = Mutex::new(server_write);
+    let relay = async {
+        tokio::try_join!(
+            pump(client_read, &server_write, &action),
+            pump(server_read, &client_write, &action)
+        )
+    };
+    match &action.close {
+        None => {
+            relay.await?;
+        }
+        Some(close) => {
+            tokio::select! {
+                r = relay => { r?; }
+                _ = sleep(close.after) => {
+                    // the writers are locked, so that the frames being sent are not torn
+                    let mut client_write = client_write.lock().await;
+                    let mut server_write = server_write.lock().await;
+                    client_write.write_all(&close_frame(close.code, None)).await?;
+                    server_write.write_all(&close_frame(close.code, Some(rand::random()))).await?;
+                    client_write.shutdown().await?;
+                    server_write.shutdown().await?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// pump forwards the frames read from `reader` to `writer`, the writer is shut down at EOF.
+async fn pump<R, W>(
+    mut reader: ReadHalf<R>,
+    writer: &Mutex<WriteHalf<W>>,
+    action: &WebSocketAction,
+) -> Result<()>
+where
+    R: AsyncRead,
+    W: AsyncWrite,
+{
+    while let Some(frame) = read_frame(&mut reader).await? {
+        let (fin, opcode) = (frame[0] & 0x80 != 0, frame[0] & 0x0f);
+        if fin
+            && (opcode == OPCODE_TEXT || opcode == OPCODE_BINARY)
+            && rand::random::<f64>() * 100.0 < action.drop_percent
+        {
+            continue;
+        }
+        if let Some(delay) = action.delay {
+            sleep(delay).await;
+        }
+        writer.lock().await.write_all(&frame).await?;
+    }
+    writer.lock().await.shutdown().await?;
+    Ok(())
+}
+
+/// read_frame reads the raw bytes of a frame, `None` is returned at EOF.
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut frame = vec![0; 2];
+    match reader.read_exact(&mut frame).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let masked = frame[1] & 0x80 != 0;
+    let extended = match frame[1] & 0x7f {
+        126 => 2,
+        127 => 8,
+        _ => 0,
+    };
+    frame.resize(2 + extended, 0);
+    reader.read_exact(&mut frame[2..]).await?;
+    let length = match extended {
+        0 => (frame[1] & 0x7f) as usize,
+        _ => frame[2..]
+            .iter()
+            .fold(0usize, |length, byte| (length << 8) | *byte as usize),
+    };
+    let header = frame.len() + if masked { 4 } else { 0 };
+    frame.resize(header + length, 0);
+    reader.read_exact(&mut frame[2 + extended..]).await?;
+    Ok(Some(frame))
+}
+
+/// close_frame builds a close frame with the code, the frames sent to the server must be masked.
+fn close_frame(code: u16, mask: Option<[u8; 4]>) -> Vec<u8> {
+    let payload = code.to_be_bytes();
+    match mask {
+        None => vec![0x80 | OPCODE_CLOSE, 2, payload[0], payload[1]],
+        Some(key) => vec![
+            0x80 | OPCODE_CLOSE,
+            0x80 | 2,
+            key[0],
+            key[1],
+            key[2],
+            key[3],
+            payload[0] ^ key[0],
+            payload[1] ^ key[1],
+        ],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::handler::http::websocket::{
+        close_frame, read_frame, relay, WebSocketAction, WebSocketClose,
+    };
+
+    #[tokio::test]
+    async fn test_relay() {
+        let (mut client, proxy_client) = tokio::io::duplex(1024);
+        let (proxy_server, mut server) = tokio::io::duplex(1024);
+        let action = WebSocketAction {
+            delay: None,
+            drop_percent: 100.0,
+            close: Some(WebSocketClose {
+                code: 1011,
+                after: Duration::from_millis(100),
+            }),
+        };
+        tokio::spawn(relay(proxy_client, proxy_server, Some(action)));
+
+        // masked text frame `hi` is dropped, the ping is forwarded
+        client
+            .write_all(&[0x81, 0x82, 0, 0, 0, 0, b'h', b'i'])
+            .await
+            .unwrap();
+        client.write_all(&[0x89, 0x80, 0, 0, 0, 0]).await.unwrap();
+        let frame = read_frame(&mut server).await.unwrap().unwrap();
+        assert_eq!(frame, vec![0x89, 0x80, 0, 0, 0, 0]);
+
+        // then both sides are closed
+        let frame = read_frame(&mut client).await.unwrap().unwrap();
+        assert_eq!(frame, close_frame(1011, None));
+        let frame = read_frame(&mut server).await.unwrap().unwrap();
+        assert_eq!(frame[0], 0x88);
+        assert_eq!(frame[6] ^ frame[2], 0x03);
+        assert_eq!(frame[7] ^ frame[3], 0xf3);
+        assert_eq!(server.read(&mut [0; 1]).await.unwrap(), 0);
+    }
+}
@@ -1 +1,3 @@
+pub mod dns;
 pub mod http;
+pub mod tcp;
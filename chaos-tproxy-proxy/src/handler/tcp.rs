@@ -0,0 +1,146 @@
+use std::fmt;
+use std::time::Duration;
+
+use anyhow::Result;
+use rand::Rng;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::sleep;
+
+use crate::proxy::tcp::sockopt::{set_linger_zero, AsRawSocket};
+
+/// TcpAction injects connection-level faults into the raw TCP connections, the segments of both
+/// directions are affected. A segment is the bytes got by a read from the peer.
+#[derive(Debug, PartialEq, Clone)]
+pub struct TcpAction {
+    /// fixed delay of each segment.
+    pub delay: Option<Duration>,
+    /// random extra delay of each segment, up to the jitter.
+    pub jitter: Option<Duration>,
+    /// throughput cap of each direction in bytes per second.
+    pub bandwidth: Option<u64>,
+    /// percent of the segments resetting both sides instead of being forwarded.
+    pub reset_percent: f64,
+    /// percent of the segments with a corrupted byte.
+    pub corrupt_percent: f64,
+}
+
+#[derive(Debug)]
+struct Reset;
+
+impl fmt::Display for Reset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "connection is reset by the tcp action")
+    }
+}
+
+impl std::error::Error for Reset {}
+
+/// relay forwards the bytes between the client and the server until both are closed.
+pub async fn relay<C, S>(client: C, server: S, action: &TcpAction) -> Result<()>
+where
+    C: AsyncRead + AsyncWrite + AsRawSocket + Unpin,
+    S: AsyncRead + AsyncWrite + AsRawSocket + Unpin,
+{
+    let fds = (client.raw_socket(), server.raw_socket());
+    // the halves never shut down the connection on drop, the connections are closed once both
+    // halves are dropped
+    let (mut client_read, mut client_write) = tokio::io::split(client);
+    let (mut server_read, mut server_write) = tokio::io::split(server);
+    let r = tokio::try_join!(
+        pump(&mut client_read, &mut server_write, action),
+        pump(&mut server_read, &mut client_write, action)
+    );
+    match r {
+        Err(e) if e.is::<Reset>() => {
+            // the connections are dropped with SO_LINGER set, so that both peers get a RST
+            set_linger_zero(fds.0)?;
+            set_linger_zero(fds.1)?;
+            Ok(())
+        }
+        r => r.map(|_| ()),
+    }
+}
+
+async fn pump<R, W>(reader: &mut R, writer: &mut W, action: &TcpAction) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0; 16 * 1024];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            writer.shutdown().await?;
+            return Ok(());
+        }
+        let segment = &mut buf[..n];
+        if rand::random::<f64>() * 100.0 < action.reset_percent {
+            return Err(Reset.into());
+        }
+        if rand::random::<f64>() * 100.0 < action.corrupt_percent {
+            let i = rand::thread_rng().gen_range(0..n);
+            segment[i] ^= rand::thread_rng().gen_range(1..=u8::MAX);
+        }
+        let jitter = action
+            .jitter
+            .map(|jitter| jitter.mul_f64(rand::random::<f64>()))
+            .unwrap_or_default();
+        let delay = action.delay.unwrap_or_default() + jitter;
+        if !delay.is_zero() {
+            sleep(delay).await;
+        }
+        writer.write_all(segment).await?;
+        if let Some(bandwidth) = action.bandwidth {
+            sleep(Duration::from_secs_f64(n as f64 / bandwidth as f64)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use crate::handler::tcp::{relay, TcpAction};
+
+    /// pair returns the client and the server of a relayed connection.
+    async fn pair(action: TcpAction) -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr: SocketAddr = listener.local_addr().unwrap();
+        let client = TcpStream::connect(addr).await.unwrap();
+        let (proxy_client, _) = listener.accept().await.unwrap();
+        let proxy_server = TcpStream::connect(addr).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        tokio::spawn(async move { relay(proxy_client, proxy_server, &action).await });
+        (client, server)
+    }
+
+    #[tokio::test]
+    async fn test_relay() {
+        let action = TcpAction {
+            delay: None,
+            jitter: None,
+            bandwidth: None,
+            reset_percent: 0.0,
+            corrupt_percent: 100.0,
+        };
+        let (mut client, mut server) = pair(action.clone()).await;
+        client.write_all(b"PING").await.unwrap();
+        let mut buf = [0; 4];
+        server.read_exact(&mut buf).await.unwrap();
+        let corrupted = buf.iter().zip(b"PING").filter(|(a, b)| a != b).count();
+        assert_eq!(corrupted, 1);
+
+        let (mut client, _server) = pair(TcpAction {
+            reset_percent: 100.0,
+            corrupt_percent: 0.0,
+            ..action
+        })
+        .await;
+        client.write_all(b"PING").await.unwrap();
+        let err = client.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+    }
+}
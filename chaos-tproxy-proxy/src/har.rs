@@ -0,0 +1,504 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
+
+use anyhow::Result;
+use bytes::Bytes;
+use chrono::{DateTime, SecondsFormat, Utc};
+use http::header::{CONTENT_TYPE, HOST};
+use http::HeaderMap;
+use hyper::{Body, Request, Response};
+use serde::Serialize;
+use tokio::fs;
+
+use crate::handler::http::tap::tap;
+
+/// HarConfig makes the proxy capture the exchanges to a HAR file, which is written on shutdown.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HarConfig {
+    pub path: PathBuf,
+    /// all captures every exchange, instead of the ones any rule is applied to.
+    pub all: bool,
+    /// max_body caps the bytes of each body captured, the rest is left out.
+    pub max_body: usize,
+    /// max_entries caps the exchanges captured, the later ones are left out.
+    pub max_entries: usize,
+}
+
+/// HarRecorder collects the entries of the exchanges captured, shared by all the connections of
+/// the server.
+#[derive(Debug)]
+pub struct HarRecorder {
+    config: HarConfig,
+    entries: Mutex<Vec<Entry>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct Har {
+    log: Log,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct Log {
+    version: &'static str,
+    creator: Creator,
+    entries: Vec<Entry>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct Creator {
+    name: &'static str,
+    version: &'static str,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Entry {
+    started_date_time: String,
+    time: f64,
+    request: HarRequest,
+    response: HarResponse,
+    cache: serde_json::Value,
+    timings: Timings,
+    #[serde(rename = "serverIPAddress")]
+    server_ip_address: String,
+    /// _error is of the exchanges failed without a response, e.g. aborted.
+    #[serde(rename = "_error", skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct HarRequest {
+    method: String,
+    url: String,
+    http_version: String,
+    cookies: Vec<NameValue>,
+    headers: Vec<NameValue>,
+    query_string: Vec<NameValue>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    post_data: Option<PostData>,
+    headers_size: i64,
+    body_size: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct HarResponse {
+    status: u16,
+    status_text: String,
+    http_version: String,
+    cookies: Vec<NameValue>,
+    headers: Vec<NameValue>,
+    content: Content,
+    #[serde(rename = "redirectURL")]
+    redirect_url: String,
+    headers_size: i64,
+    body_size: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct NameValue {
+    name: String,
+    value: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PostData {
+    mime_type: String,
+    text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    encoding: Option<&'static str>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Content {
+    size: i64,
+    mime_type: String,
+    text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    encoding: Option<&'static str>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct Timings {
+    send: f64,
+    wait: f64,
+    receive: f64,
+}
+
+/// Capture is a body captured up to the cap.
+#[derive(Debug, Default)]
+struct Capture {
+    bytes: Vec<u8>,
+    size: usize,
+}
+
+impl Capture {
+    fn push(&mut self, chunk: &Bytes, max: usize) {
+        self.size += chunk.len();
+        let room = max.saturating_sub(self.bytes.len());
+        self.bytes
+            .extend_from_slice(&chunk[..room.min(chunk.len())]);
+    }
+
+    /// text returns the body captured as text, or in base64 if it is not.
+    fn text(&self) -> (String, Option<&'static str>) {
+        match std::str::from_utf8(&self.bytes) {
+            Ok(text) => (text.to_string(), None),
+            Err(_) => (base64::encode(&self.bytes), Some("base64")),
+        }
+    }
+}
+
+impl HarRecorder {
+    pub fn new(config: HarConfig) -> Self {
+        Self {
+            config,
+            entries: Default::default(),
+        }
+    }
+
+    /// start begins the entry of an exchange, capturing the request as the client sent it.
+    pub fn start(
+        self: &Arc<Self>,
+        request: Request<Body>,
+        https: bool,
+        target: SocketAddr,
+    ) -> (Request<Body>, HarPending) {
+        let (parts, body) = request.into_parts();
+        let capture = Arc::new(Mutex::new(Capture::default()));
+        let captured = capture.clone();
+        let max = self.config.max_body;
+        let body = tap(
+            body,
+            move |chunk| captured.lock().unwrap().push(chunk, max),
+            (),
+        );
+        let authority = parts
+            .uri
+            .authority()
+            .map(|authority| authority.to_string())
+            .or_else(|| {
+                parts
+                    .headers
+                    .get(HOST)
+                    .and_then(|host| host.to_str().ok())
+                    .map(str::to_string)
+            })
+            .unwrap_or_else(|| target.to_string());
+        let path = parts
+            .uri
+            .path_and_query()
+            .map(|path| path.as_str())
+            .unwrap_or("/");
+        let pending = HarPending {
+            recorder: self.clone(),
+            started_at: SystemTime::now(),
+            started: Instant::now(),
+            request: HarRequest {
+                method: parts.method.to_string(),
+                url: format!(
+                    "{}://{}{}",
+                    if https { "https" } else { "http" },
+                    authority,
+                    path
+                ),
+                http_version: format!("{:?}", parts.version),
+                cookies: vec![],
+                headers: name_values(&parts.headers),
+                query_string: parts
+                    .uri
+                    .query()
+                    .and_then(|query| {
+                        serde_urlencoded::from_str::<Vec<(String, String)>>(query).ok()
+                    })
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|(name, value)| NameValue { name, value })
+                    .collect(),
+                post_data: None,
+                headers_size: -1,
+                body_size: 0,
+            },
+            request_body: capture,
+            request_type: mime_type(&parts.headers),
+            server: target.ip().to_string(),
+            waited: 0.0,
+            response: None,
+            response_body: Default::default(),
+            error: None,
+        };
+        (Request::from_parts(parts, body), pending)
+    }
+
+    fn push(&self, entry: Entry) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() < self.config.max_entries {
+            entries.push(entry);
+        }
+    }
+
+    /// write writes the entries captured so far to the HAR file.
+    pub async fn write(&self) -> Result<()> {
+        let har = Har {
+            log: Log {
+                version: "1.2",
+                creator: Creator {
+                    name: "chaos-tproxy",
+                    version: env!("CARGO_PKG_VERSION"),
+                },
+                entries: self.entries.lock().unwrap().clone(),
+            },
+        };
+        // rename is atomic, so that a crash never leaves a partial file
+        let tmp = self.config.path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec_pretty(&har)?).await?;
+        fs::rename(&tmp, &self.config.path).await?;
+        tracing::info!(
+            "HAR of {} exchanges written to {}",
+            har.log.entries.len(),
+            self.config.path.display()
+        );
+        Ok(())
+    }
+}
+
+/// HarPending is the entry of an exchange in flight, it is recorded once the response body is
+/// sent, or dropped by the client.
+pub struct HarPending {
+    recorder: Arc<HarRecorder>,
+    started_at: SystemTime,
+    started: Instant,
+    request: HarRequest,
+    request_body: Arc<Mutex<Capture>>,
+    request_type: String,
+    server: String,
+    waited: f64,
+    response: Option<HarResponse>,
+    response_body: Arc<Mutex<Capture>>,
+    error: Option<String>,
+}
+
+impl HarPending {
+    /// finish completes the entry with the response as the client receives it. The exchange is
+    /// left out unless it is faulted, or all the exchanges are captured.
+    pub fn finish(
+        mut self,
+        faulted: bool,
+        result: Result<Response<Body>>,
+    ) -> Result<Response<Body>> {
+        if !faulted && !self.recorder.config.all {
+            return result;
+        }
+        self.waited = millis(self.started);
+        match result {
+            Ok(response) => {
+                let (parts, body) = response.into_parts();
+                self.response = Some(HarResponse {
+                    status: parts.status.as_u16(),
+                    status_text: parts
+                        .status
+                        .canonical_reason()
+                        .unwrap_or_default()
+                        .to_string(),
+                    http_version: format!("{:?}", parts.version),
+                    cookies: vec![],
+                    headers: name_values(&parts.headers),
+                    content: Content {
+                        size: 0,
+                        mime_type: mime_type(&parts.headers),
+                        text: String::new(),
+                        encoding: None,
+                    },
+                    redirect_url: parts
+                        .headers
+                        .get(http::header::LOCATION)
+                        .and_then(|location| location.to_str().ok())
+                        .unwrap_or_default()
+                        .to_string(),
+                    headers_size: -1,
+                    body_size: 0,
+                });
+                let captured = self.response_body.clone();
+                let max = self.recorder.config.max_body;
+                let body = tap(
+                    body,
+                    move |chunk| captured.lock().unwrap().push(chunk, max),
+                    self,
+                );
+                Ok(Response::from_parts(parts, body))
+            }
+            Err(e) => {
+                self.error = Some(e.to_string());
+                Err(e)
+            }
+        }
+    }
+
+    fn entry(&mut self) -> Entry {
+        let time = millis(self.started);
+        let mut request = self.request.clone();
+        let request_body = self.request_body.lock().unwrap();
+        request.body_size = request_body.size as i64;
+        if request_body.size > 0 {
+            let (text, encoding) = request_body.text();
+            request.post_data = Some(PostData {
+                mime_type: self.request_type.clone(),
+                text,
+                encoding,
+            });
+        }
+        // the browsers tell the failed exchanges by the status 0
+        let mut response = self.response.clone().unwrap_or_else(|| HarResponse {
+            status: 0,
+            status_text: String::new(),
+            http_version: request.http_version.clone(),
+            cookies: vec![],
+            headers: vec![],
+            content: Content {
+                size: 0,
+                mime_type: String::new(),
+                text: String::new(),
+                encoding: None,
+            },
+            redirect_url: String::new(),
+            headers_size: -1,
+            body_size: 0,
+        });
+        let response_body = self.response_body.lock().unwrap();
+        let (text, encoding) = response_body.text();
+        response.content.size = response_body.size as i64;
+        response.content.text = text;
+        response.content.encoding = encoding;
+        response.body_size = response_body.size as i64;
+        Entry {
+            started_date_time: DateTime::<Utc>::from(self.started_at)
+                .to_rfc3339_opts(SecondsFormat::Millis, true),
+            time,
+            request,
+            response,
+            cache: serde_json::json!({}),
+            timings: Timings {
+                send: 0.0,
+                wait: self.waited,
+                receive: time - self.waited,
+            },
+            server_ip_address: self.server.clone(),
+            error: self.error.take(),
+        }
+    }
+}
+
+impl Drop for HarPending {
+    fn drop(&mut self) {
+        // the exchanges left out are never finished with a response or an error
+        if self.response.is_some() || self.error.is_some() {
+            let entry = self.entry();
+            self.recorder.push(entry);
+        }
+    }
+}
+
+fn name_values(headers: &HeaderMap) -> Vec<NameValue> {
+    headers
+        .iter()
+        .map(|(name, value)| NameValue {
+            name: name.to_string(),
+            value: String::from_utf8_lossy(value.as_bytes()).to_string(),
+        })
+        .collect()
+}
+
+fn mime_type(headers: &HeaderMap) -> String {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_string()
+}
+
+fn millis(started: Instant) -> f64 {
+    started.elapsed().as_secs_f64() * 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use anyhow::anyhow;
+    use hyper::{Body, Request, Response};
+
+    use crate::har::{HarConfig, HarRecorder};
+
+    #[tokio::test]
+    async fn test_har() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("capture.har");
+        let recorder = Arc::new(HarRecorder::new(HarConfig {
+            path: path.clone(),
+            all: false,
+            max_body: 5,
+            max_entries: 2,
+        }));
+        let target = "10.0.0.2:80".parse().unwrap();
+        let request = |uri: &str| {
+            Request::post(uri)
+                .header("host", "example.com")
+                .header("content-type", "text/plain")
+                .body(Body::from("hello world"))
+                .unwrap()
+        };
+
+        // faulted, the bodies are captured up to the cap
+        let (captured, pending) = recorder.start(request("/api?id=1"), false, target);
+        hyper::body::to_bytes(captured.into_body()).await.unwrap();
+        let response = pending
+            .finish(true, Ok(Response::new(Body::from(vec![0xff, 0xfe]))))
+            .unwrap();
+        hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        // not faulted, left out
+        let (_, pending) = recorder.start(request("/health"), false, target);
+        let response = pending.finish(false, Ok(Response::new(Body::empty())));
+        drop(response);
+
+        // aborted
+        let (_, pending) = recorder.start(request("/login"), true, target);
+        assert!(pending.finish(true, Err(anyhow!("aborted"))).is_err());
+
+        // over the cap of entries
+        let (_, pending) = recorder.start(request("/logout"), false, target);
+        assert!(pending.finish(true, Err(anyhow!("aborted"))).is_err());
+
+        recorder.write().await.unwrap();
+        let har: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        let entries = har["log"]["entries"].as_array().unwrap();
+        assert_eq!(har["log"]["version"], "1.2");
+        assert_eq!(entries.len(), 2);
+
+        let request = &entries[0]["request"];
+        assert_eq!(request["url"], "http://example.com/api?id=1");
+        assert_eq!(request["httpVersion"], "HTTP/1.1");
+        assert_eq!(
+            request["queryString"],
+            serde_json::json!([{"name": "id", "value": "1"}])
+        );
+        assert_eq!(request["bodySize"], 11);
+        assert_eq!(request["postData"]["text"], "hello");
+        assert_eq!(request["postData"]["mimeType"], "text/plain");
+        let response = &entries[0]["response"];
+        assert_eq!(response["status"], 200);
+        assert_eq!(response["content"]["size"], 2);
+        assert_eq!(response["content"]["text"], "//4=");
+        assert_eq!(response["content"]["encoding"], "base64");
+
+        assert_eq!(entries[1]["request"]["url"], "https://example.com/login");
+        assert_eq!(entries[1]["response"]["status"], 0);
+        assert_eq!(entries[1]["_error"], "aborted");
+    }
+}
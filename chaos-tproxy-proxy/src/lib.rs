@@ -1,38 +1,158 @@
+#[cfg(unix)]
 use std::convert::TryInto;
+#[cfg(unix)]
 use std::path::PathBuf;
 
-use tokio::signal::unix::SignalKind;
-use tokio::sync::oneshot::channel;
+#[cfg(unix)]
+use tokio::select;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
 
-use crate::proxy::http::server::HttpServer;
+#[cfg(unix)]
+use crate::embedded::Proxy;
+#[cfg(unix)]
+use crate::experiment::write_experiment_report;
+#[cfg(unix)]
+use crate::proxy::http::config::Config;
+#[cfg(unix)]
 use crate::raw_config::RawConfig;
+#[cfg(unix)]
+use crate::report::push_reports;
+#[cfg(unix)]
 use crate::signal::Signals;
+#[cfg(unix)]
+use crate::snapshot::{write_snapshot, write_snapshots};
+#[cfg(unix)]
+use crate::timeline::EventKind;
+#[cfg(unix)]
 use crate::uds_client::UdsDataClient;
 
+pub mod access_log;
+pub mod clock;
+pub mod coordination;
+pub mod duration;
+pub mod embedded;
+pub mod experiment;
 pub mod handler;
+pub mod har;
+pub mod metadata;
+pub mod metrics;
+#[cfg(feature = "middleware")]
+pub mod middleware;
+pub mod pcap;
 pub mod proxy;
 pub mod raw_config;
+pub mod report;
 pub mod signal;
+pub mod snapshot;
+pub mod stub;
+pub mod telemetry;
+#[cfg(feature = "testing")]
+pub mod testing;
+pub mod timeline;
+#[cfg(unix)]
 pub mod uds_client;
 
-pub async fn proxy_main(path: PathBuf) -> anyhow::Result<()> {
+/// proxy_main runs the proxy with the config served on the path. The proxy is disarmed by
+/// `SIGUSR1` and armed again by `SIGUSR2`, it starts disarmed unless `armed`. It is only served on
+/// Unix, where the controller runs the proxy as a child process.
+#[cfg(unix)]
+pub async fn proxy_main(path: PathBuf, armed: bool) -> anyhow::Result<()> {
     tracing::info!("Proxy get uds path {:?}", path);
     let client = UdsDataClient::new(path);
     let mut buf: Vec<u8> = vec![];
     let raw_config: RawConfig = client.read_into(&mut buf).await?;
-    let config = raw_config.try_into()?;
-    let (sender, rx) = channel();
+    let config: Config = raw_config.try_into()?;
 
-    let spawn = tokio::spawn(async move {
-        tracing::info!("Proxy Starting");
-        let mut server = HttpServer::new(config);
-        server.serve(rx).await.unwrap();
-    });
+    let report = config.report.clone();
+    let snapshot = config.snapshot.clone();
+    let experiment_report = config.experiment_report.clone();
+    let mut proxy = Proxy::new(config);
+    let metrics = proxy.metrics();
+    let har = proxy.har();
+    proxy.arm(armed);
+    let reporter = report.map(|report| tokio::spawn(push_reports(report, metrics.clone())));
+    let snapshotter = snapshot
+        .clone()
+        .map(|snapshot| tokio::spawn(write_snapshots(snapshot, metrics.clone())));
+    tracing::info!("Proxy Starting");
+    proxy.start()?;
 
     let mut signals = Signals::from_kinds(&[SignalKind::interrupt(), SignalKind::terminate()])?;
-    signals.wait().await?;
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut disarm = signal(SignalKind::user_defined1())?;
+    let mut arm = signal(SignalKind::user_defined2())?;
+    loop {
+        select! {
+            _ = signals.wait() => break,
+            _ = hangup.recv() => {
+                if let Err(e) = reload(&client, &mut proxy).await {
+                    tracing::error!("fail to reload config, the current one is kept: {}", e);
+                }
+            }
+            _ = disarm.recv() => {
+                tracing::info!("Proxy disarmed, the rules are paused");
+                proxy.arm(false);
+            }
+            _ = arm.recv() => {
+                tracing::info!("Proxy armed, the rules apply");
+                proxy.arm(true);
+            }
+        }
+    }
 
-    let _ = sender.send(());
-    spawn.await?;
+    proxy.stop().await?;
+    if let Some(reporter) = reporter {
+        reporter.abort();
+    }
+    if let Some(snapshotter) = snapshotter {
+        snapshotter.abort();
+    }
+    metrics.timeline().record(EventKind::Teardown);
+    if let Some(har) = &har {
+        if let Err(e) = har.write().await {
+            tracing::error!("fail to write HAR: {}", e);
+        }
+    }
+    // the last snapshot covers the whole experiment
+    if let Some(snapshot) = &snapshot {
+        if let Err(e) = write_snapshot(snapshot, &metrics).await {
+            tracing::error!("fail to write the last snapshot: {}", e);
+        }
+    }
+    if let Some(experiment_report) = &experiment_report {
+        if let Err(e) = write_experiment_report(experiment_report, &metrics).await {
+            tracing::error!("fail to write the experiment report: {}", e);
+        }
+    }
+
+    if let Some(report) = metrics.slo_report() {
+        tracing::info!("SLO impact report: {}", serde_json::to_string(&report)?);
+    }
+    if let Some(report) = metrics.baseline_report() {
+        tracing::info!("Baseline report: {}", serde_json::to_string(&report)?);
+    }
+    if let Some(report) = metrics.comparison_report() {
+        tracing::info!("Comparison report: {}", serde_json::to_string(&report)?);
+    }
+    tracing::info!(
+        "Event timeline: {}",
+        serde_json::to_string(&metrics.timeline().events())?
+    );
+    Ok(())
+}
+
+/// reload reads the config served again, and swaps the rules and the other HTTP settings of the
+/// server. The listeners and the other settings are applied by restarting the proxy.
+#[cfg(unix)]
+async fn reload(client: &UdsDataClient, proxy: &mut Proxy) -> anyhow::Result<()> {
+    let mut buf: Vec<u8> = vec![];
+    let raw_config: RawConfig = client.read_into(&mut buf).await?;
+    let config: Config = raw_config.try_into()?;
+    tracing::info!(
+        "Proxy reloading config with {} rules",
+        config.http_config.rules.len()
+    );
+    proxy.reload(config.http_config);
     Ok(())
 }
@@ -0,0 +1,220 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use hyper::{Client, Uri};
+use ipnetwork::IpNetwork;
+use maxminddb::geoip2;
+
+/// Labels of a client, e.g. `region=us-east-1`.
+pub type Labels = HashMap<String, String>;
+
+/// ClientLabels is attached to the extensions of the requests and responses, so that the
+/// selectors could match it.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct ClientLabels(pub Labels);
+
+/// MetadataResolver maps the IP of a client to its labels.
+#[async_trait]
+pub trait MetadataResolver: Debug + Send + Sync {
+    async fn resolve(&self, ip: IpAddr) -> Labels;
+}
+
+/// CSVResolver loads labels from a CSV file, the header names the labels and the first column is
+/// an IP or CIDR, e.g.
+/// ```csv
+/// cidr,region,cluster
+/// 10.0.0.0/16,us-east-1,prod
+/// ```
+/// The first matched row is used.
+#[derive(Debug)]
+pub struct CSVResolver {
+    entries: Vec<(IpNetwork, Labels)>,
+}
+
+impl CSVResolver {
+    pub fn load(path: &Path) -> Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    fn parse(contents: &str) -> Result<Self> {
+        let mut lines = contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty());
+        let names: Vec<&str> = match lines.next() {
+            None => return Ok(Self { entries: vec![] }),
+            Some(header) => header.split(',').map(str::trim).skip(1).collect(),
+        };
+        let entries = lines
+            .map(|line| {
+                let mut fields = line.split(',').map(str::trim);
+                let network = fields.next().unwrap_or_default();
+                let network: IpNetwork = network
+                    .parse()
+                    .map_err(|e| anyhow!("invalid cidr {}: {}", network, e))?;
+                let labels = names
+                    .iter()
+                    .zip(fields)
+                    .filter(|(_, value)| !value.is_empty())
+                    .map(|(name, value)| (name.to_string(), value.to_string()))
+                    .collect();
+                Ok((network, labels))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { entries })
+    }
+}
+
+#[async_trait]
+impl MetadataResolver for CSVResolver {
+    async fn resolve(&self, ip: IpAddr) -> Labels {
+        self.entries
+            .iter()
+            .find(|(network, _)| network.contains(ip))
+            .map(|(_, labels)| labels.clone())
+            .unwrap_or_default()
+    }
+}
+
+/// HTTPResolver looks up the labels by `GET url`, `{ip}` in the url would be replaced with the
+/// client IP and a JSON object of strings is expected. The results are cached for `ttl`.
+#[derive(Debug)]
+pub struct HTTPResolver {
+    url: String,
+    ttl: Duration,
+    cache: Mutex<HashMap<IpAddr, (Instant, Labels)>>,
+}
+
+impl HTTPResolver {
+    pub fn new(url: String, ttl: Duration) -> Result<Self> {
+        // check the url in advance
+        url.replace("{ip}", "127.0.0.1").parse::<Uri>()?;
+        Ok(Self {
+            url,
+            ttl,
+            cache: Default::default(),
+        })
+    }
+
+    async fn lookup(&self, ip: IpAddr) -> Result<Labels> {
+        let uri: Uri = self.url.replace("{ip}", &ip.to_string()).parse()?;
+        let response = Client::new().get(uri).await?;
+        if !response.status().is_success() {
+            return Err(anyhow!("unexpected status {}", response.status()));
+        }
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+}
+
+#[async_trait]
+impl MetadataResolver for HTTPResolver {
+    async fn resolve(&self, ip: IpAddr) -> Labels {
+        if let Some((at, labels)) = self.cache.lock().unwrap().get(&ip) {
+            if at.elapsed() < self.ttl {
+                return labels.clone();
+            }
+        }
+        let labels = match self.lookup(ip).await {
+            Ok(labels) => labels,
+            Err(e) => {
+                tracing::debug!("fail to lookup labels of {}: {}", ip, e);
+                Labels::default()
+            }
+        };
+        let mut cache = self.cache.lock().unwrap();
+        cache.retain(|_, (at, _)| at.elapsed() < self.ttl);
+        cache.insert(ip, (Instant::now(), labels.clone()));
+        labels
+    }
+}
+
+/// MaxMindResolver looks up a MaxMind (GeoIP2/GeoLite2 City or Country) database, the labels are
+/// `continent`, `country`, `region` (ISO code of the first subdivision) and `city` (English name).
+pub struct MaxMindResolver {
+    reader: maxminddb::Reader<Vec<u8>>,
+}
+
+impl Debug for MaxMindResolver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MaxMindResolver")
+            .field("database_type", &self.reader.metadata.database_type)
+            .finish()
+    }
+}
+
+impl MaxMindResolver {
+    pub fn load(path: &Path) -> Result<Self> {
+        Ok(Self {
+            reader: maxminddb::Reader::open_readfile(path)?,
+        })
+    }
+}
+
+#[async_trait]
+impl MetadataResolver for MaxMindResolver {
+    async fn resolve(&self, ip: IpAddr) -> Labels {
+        let mut labels = Labels::new();
+        let city: geoip2::City = match self.reader.lookup(ip) {
+            Ok(city) => city,
+            Err(e) => {
+                tracing::debug!("fail to lookup labels of {}: {}", ip, e);
+                return labels;
+            }
+        };
+        let mut insert = |name: &str, value: Option<&str>| {
+            if let Some(value) = value {
+                labels.insert(name.to_string(), value.to_string());
+            }
+        };
+        insert("continent", city.continent.and_then(|c| c.code));
+        insert("country", city.country.and_then(|c| c.iso_code));
+        insert(
+            "region",
+            city.subdivisions
+                .as_ref()
+                .and_then(|s| s.first())
+                .and_then(|s| s.iso_code),
+        );
+        insert(
+            "city",
+            city.city
+                .and_then(|c| c.names)
+                .and_then(|names| names.get("en").copied()),
+        );
+        labels
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::metadata::{CSVResolver, MetadataResolver};
+
+    #[tokio::test]
+    async fn test_csv_resolver() {
+        let resolver = CSVResolver::parse(
+            "cidr,region,cluster\n\
+             10.0.1.1,us-west-1,\n\
+             10.0.0.0/16,us-east-1,prod\n",
+        )
+        .unwrap();
+        let labels = resolver.resolve("10.0.3.4".parse().unwrap()).await;
+        assert_eq!(labels["region"], "us-east-1");
+        assert_eq!(labels["cluster"], "prod");
+
+        let labels = resolver.resolve("10.0.1.1".parse().unwrap()).await;
+        assert_eq!(labels["region"], "us-west-1");
+        assert!(!labels.contains_key("cluster"));
+
+        assert!(resolver
+            .resolve("192.168.0.1".parse().unwrap())
+            .await
+            .is_empty());
+    }
+}
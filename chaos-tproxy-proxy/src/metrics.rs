@@ -0,0 +1,749 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::handler::http::action::Actions;
+use crate::handler::http::compare::ResponseDiff;
+use crate::handler::http::exchange::ExchangeId;
+use crate::handler::http::rule::Rule;
+use crate::proxy::drain::Drain;
+use crate::proxy::http::config::HTTPConfig;
+use crate::timeline::{EventKind, Timeline};
+
+/// Upper bounds (in milliseconds) of the latency histogram buckets.
+const BUCKETS_MS: [u64; 15] = [
+    1, 2, 5, 10, 20, 50, 100, 200, 500, 1_000, 2_000, 5_000, 10_000, 30_000, 60_000,
+];
+
+/// Histogram is a lock-free latency histogram with fixed buckets, the percentiles are estimated by
+/// the upper bound of the bucket.
+#[derive(Debug)]
+pub struct Histogram {
+    counts: Vec<AtomicU64>,
+}
+
+impl Histogram {
+    pub fn new() -> Self {
+        Self {
+            // The last bucket holds every latency over the max bound.
+            counts: (0..=BUCKETS_MS.len()).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    pub fn record(&self, latency: Duration) {
+        let ms = latency.as_millis() as u64;
+        let idx = BUCKETS_MS
+            .iter()
+            .position(|bound| ms <= *bound)
+            .unwrap_or(BUCKETS_MS.len());
+        self.counts[idx].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.counts.iter().map(|c| c.load(Ordering::Relaxed)).sum()
+    }
+
+    /// percentile returns the estimated latency of the given quantile, `q` should be in `0..=1`.
+    pub fn percentile(&self, q: f64) -> Option<Duration> {
+        let total = self.count();
+        if total == 0 {
+            return None;
+        }
+        let rank = ((total as f64) * q).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (idx, count) in self.counts.iter().enumerate() {
+            seen += count.load(Ordering::Relaxed);
+            if seen >= rank {
+                let bound = BUCKETS_MS.get(idx).or_else(|| BUCKETS_MS.last());
+                return bound.map(|ms| Duration::from_millis(*ms));
+            }
+        }
+        None
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// SLOConfig describes the service level objectives the experiment is measured against.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SLOConfig {
+    /// Percent of requests expected to succeed, e.g. `99.9`.
+    pub availability: Option<f64>,
+    /// Requests slower than this are counted against the latency objective.
+    pub latency_threshold: Option<Duration>,
+    /// Percent of requests expected to be faster than `latency_threshold`.
+    pub latency_objective: f64,
+}
+
+/// BaselineConfig makes the proxy only observe the exchanges for a while before the rules apply,
+/// so that the experiment is contrasted with the baseline captured per path.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BaselineConfig {
+    pub duration: Duration,
+    /// max_paths caps the paths captured, the exchanges of the other paths are not recorded per
+    /// path.
+    pub max_paths: usize,
+}
+
+#[derive(Debug, Default)]
+struct ExchangeStats {
+    requests: AtomicU64,
+    errors: AtomicU64,
+    slow: AtomicU64,
+    latency: Histogram,
+}
+
+impl ExchangeStats {
+    fn record(&self, latency: Duration, error: bool, slow: bool) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        if error {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        if slow {
+            self.slow.fetch_add(1, Ordering::Relaxed);
+        }
+        self.latency.record(latency);
+    }
+
+    fn report(&self) -> PhaseReport {
+        let requests = self.requests.load(Ordering::Relaxed);
+        let errors = self.errors.load(Ordering::Relaxed);
+        PhaseReport {
+            requests,
+            errors,
+            error_ratio: ratio(errors, requests),
+            p50_ms: self.latency.percentile(0.5).map(|d| d.as_millis() as u64),
+            p99_ms: self.latency.percentile(0.99).map(|d| d.as_millis() as u64),
+        }
+    }
+}
+
+/// RuleStats counts the applications of a rule, and the delays injected by them.
+#[derive(Debug, Default)]
+struct RuleStats {
+    hits: u64,
+    aborts: u64,
+    delays: u64,
+    delay: Histogram,
+}
+
+/// Lifetime is the start of a rule with a duration, the rule expires once the duration is over.
+#[derive(Debug)]
+struct Lifetime {
+    started: Instant,
+    expired: bool,
+}
+
+#[derive(Debug, Default)]
+struct PathStats {
+    baseline: ExchangeStats,
+    experiment: ExchangeStats,
+}
+
+#[derive(Debug)]
+struct BaselineCapture {
+    config: BaselineConfig,
+    paths: Mutex<HashMap<String, PathStats>>,
+    captured: AtomicBool,
+}
+
+#[derive(Debug, Default)]
+struct ComparisonStats {
+    compared: AtomicU64,
+    different: AtomicU64,
+    status: AtomicU64,
+    headers: AtomicU64,
+    body: AtomicU64,
+}
+
+/// Metrics records the outcome of every exchange handled by the proxy. Exchanges without any
+/// matched rule are regarded as the baseline of the faulted ones.
+#[derive(Debug)]
+pub struct Metrics {
+    started_at: Instant,
+    slo: Option<SLOConfig>,
+    baseline: ExchangeStats,
+    faulted: ExchangeStats,
+    comparison: ComparisonStats,
+    /// rules are keyed by the labels of the rules, so that they survive the reloads.
+    rules: Mutex<BTreeMap<String, RuleStats>>,
+    /// lifetimes are the starts of the rules with a duration, by their rule sets and names, the
+    /// rules kept across the reloads keep their starts.
+    lifetimes: Mutex<BTreeMap<(Option<u16>, String), Lifetime>>,
+    capture: Option<BaselineCapture>,
+    armed: AtomicBool,
+    drain: Arc<Drain>,
+    timeline: Arc<Timeline>,
+}
+
+impl Metrics {
+    pub fn new(slo: Option<SLOConfig>, baseline: Option<BaselineConfig>) -> Self {
+        Self {
+            started_at: Instant::now(),
+            slo,
+            baseline: Default::default(),
+            faulted: Default::default(),
+            comparison: Default::default(),
+            rules: Default::default(),
+            lifetimes: Default::default(),
+            capture: baseline.map(|config| BaselineCapture {
+                config,
+                paths: Default::default(),
+                captured: AtomicBool::new(false),
+            }),
+            armed: AtomicBool::new(true),
+            drain: Default::default(),
+            timeline: Default::default(),
+        }
+    }
+
+    /// observing tells whether the baseline is being captured, the rules must not apply meanwhile.
+    pub fn observing(&self) -> bool {
+        let capture = match &self.capture {
+            None => return false,
+            Some(capture) => capture,
+        };
+        if self.elapsed() < capture.config.duration {
+            return true;
+        }
+        if !capture.captured.swap(true, Ordering::Relaxed) {
+            let paths = capture.paths.lock().unwrap().len();
+            self.timeline.record(EventKind::BaselineCaptured { paths });
+        }
+        false
+    }
+
+    /// armed tells whether the rules apply, the traffic is forwarded transparently while the proxy
+    /// is disarmed.
+    pub fn armed(&self) -> bool {
+        self.armed.load(Ordering::Relaxed)
+    }
+
+    /// arm arms or disarms the proxy, the switch is recorded on the timeline.
+    pub fn arm(&self, armed: bool) {
+        if self.armed.swap(armed, Ordering::Relaxed) != armed {
+            self.timeline.record(if armed {
+                EventKind::Armed
+            } else {
+                EventKind::Disarmed
+            });
+        }
+    }
+
+    /// record_path records the outcome of an exchange to the path, to the baseline while it is
+    /// being captured and to the experiment afterwards. Only the paths of the baseline are
+    /// recorded in the experiment.
+    pub fn record_path(&self, path: &str, latency: Duration, error: bool) {
+        let capture = match &self.capture {
+            None => return,
+            Some(capture) => capture,
+        };
+        let observing = self.observing();
+        let mut paths = capture.paths.lock().unwrap();
+        if observing && !paths.contains_key(path) && paths.len() < capture.config.max_paths {
+            paths.insert(path.to_string(), Default::default());
+        }
+        if let Some(stats) = paths.get(path) {
+            let stats = if observing {
+                &stats.baseline
+            } else {
+                &stats.experiment
+            };
+            stats.record(latency, error, false);
+        }
+    }
+
+    /// baseline_report contrasts the experiment with the baseline of each path, `None` if no
+    /// baseline is captured.
+    pub fn baseline_report(&self) -> Option<BaselineReport> {
+        let capture = self.capture.as_ref()?;
+        let captured = !self.observing();
+        let paths = capture
+            .paths
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(path, stats)| {
+                let (baseline, experiment) = (stats.baseline.report(), stats.experiment.report());
+                let p99_ratio = match (baseline.p99_ms, experiment.p99_ms) {
+                    (Some(baseline), Some(experiment)) if baseline > 0 => {
+                        Some(experiment as f64 / baseline as f64)
+                    }
+                    _ => None,
+                };
+                let report = PathReport {
+                    error_ratio_delta: experiment.error_ratio - baseline.error_ratio,
+                    p99_ratio,
+                    baseline,
+                    experiment,
+                };
+                (path.clone(), report)
+            })
+            .collect();
+        Some(BaselineReport {
+            duration_secs: capture.config.duration.as_secs_f64(),
+            captured,
+            paths,
+        })
+    }
+
+    /// drain returns the counter of the exchanges in flight.
+    pub fn drain(&self) -> &Arc<Drain> {
+        &self.drain
+    }
+
+    /// timeline returns the chronology of the experiment.
+    pub fn timeline(&self) -> &Arc<Timeline> {
+        &self.timeline
+    }
+
+    /// record_comparison records the difference between a shadow response and the actual one.
+    pub fn record_comparison(&self, diff: &ResponseDiff) {
+        let stats = &self.comparison;
+        stats.compared.fetch_add(1, Ordering::Relaxed);
+        if diff.is_empty() {
+            return;
+        }
+        stats.different.fetch_add(1, Ordering::Relaxed);
+        for (counter, different) in [
+            (&stats.status, diff.status.is_some()),
+            (&stats.headers, !diff.headers.is_empty()),
+            (&stats.body, diff.body),
+        ] {
+            if different {
+                counter.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// comparison_report returns `None` if no response has been compared.
+    pub fn comparison_report(&self) -> Option<ComparisonReport> {
+        let stats = &self.comparison;
+        let compared = stats.compared.load(Ordering::Relaxed);
+        if compared == 0 {
+            return None;
+        }
+        Some(ComparisonReport {
+            compared,
+            different: stats.different.load(Ordering::Relaxed),
+            status: stats.status.load(Ordering::Relaxed),
+            headers: stats.headers.load(Ordering::Relaxed),
+            body: stats.body.load(Ordering::Relaxed),
+        })
+    }
+
+    /// rule_applied records an application of the rule of the index with the actions, sampled
+    /// from the ones of the rule, and its activation on the timeline (with the exchange, if any)
+    /// if it is the first one.
+    pub fn rule_applied(
+        &self,
+        index: usize,
+        rule: &Rule,
+        actions: &Actions,
+        exchange: Option<ExchangeId>,
+    ) {
+        self.timeline
+            .rule_applied(index, rule.name.as_deref(), exchange);
+        let mut rules = self.rules.lock().unwrap();
+        let stats = rules.entry(rule.label(index)).or_default();
+        stats.hits += 1;
+        if actions.abort || actions.abort_response.is_some() {
+            stats.aborts += 1;
+        }
+        if let Some(delay) = actions.delay {
+            stats.delays += 1;
+            stats.delay.record(delay);
+        }
+    }
+
+    /// rules_loaded starts the durations of the rules loaded with a config, those of the
+    /// listeners included. The rules already started, by their rule sets and names, keep their
+    /// starts, so that a reload does not extend them, the rules no longer loaded are forgotten.
+    pub fn rules_loaded(&self, config: &HTTPConfig) {
+        let now = config.clock.now();
+        let rule_sets = std::iter::once((None, &config.rules)).chain(
+            config
+                .listener_rules
+                .iter()
+                .map(|(port, rules)| (Some(*port), rules)),
+        );
+        let loaded: BTreeSet<_> = rule_sets
+            .flat_map(|(rule_set, rules)| {
+                rules.iter().enumerate().filter_map(move |(index, rule)| {
+                    rule.duration.map(|_| (rule_set, rule.label(index)))
+                })
+            })
+            .collect();
+        let mut lifetimes = self.lifetimes.lock().unwrap();
+        lifetimes.retain(|key, _| loaded.contains(key));
+        for key in loaded {
+            lifetimes.entry(key).or_insert(Lifetime {
+                started: now,
+                expired: false,
+            });
+        }
+    }
+
+    /// expired tells whether the duration of the rule of the index in the rule set is over, the
+    /// expiry is recorded on the timeline the first time it is told.
+    pub fn expired(&self, rule_set: Option<u16>, index: usize, rule: &Rule, now: Instant) -> bool {
+        let duration = match rule.duration {
+            None => return false,
+            Some(duration) => duration,
+        };
+        let label = rule.label(index);
+        let mut lifetimes = self.lifetimes.lock().unwrap();
+        let lifetime = lifetimes
+            .entry((rule_set, label.clone()))
+            .or_insert(Lifetime {
+                started: now,
+                expired: false,
+            });
+        if !rule.expired(lifetime.started, now) {
+            return false;
+        }
+        if !lifetime.expired {
+            lifetime.expired = true;
+            tracing::info!("rule {} expired after {:?}", label, duration);
+            self.timeline.record(EventKind::RuleExpired {
+                rule: index,
+                name: rule.name.clone(),
+            });
+        }
+        true
+    }
+
+    /// rule_reports returns the applications of the rules applied at least once, and the rules
+    /// expired, by their names, or `#<index>` if unnamed.
+    pub fn rule_reports(&self) -> BTreeMap<String, RuleReport> {
+        let mut reports: BTreeMap<_, _> = self
+            .rules
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(label, stats)| {
+                let report = RuleReport {
+                    hits: stats.hits,
+                    aborts: stats.aborts,
+                    delays: stats.delays,
+                    delay_p50_ms: stats.delay.percentile(0.5).map(|d| d.as_millis() as u64),
+                    delay_p99_ms: stats.delay.percentile(0.99).map(|d| d.as_millis() as u64),
+                    expired: false,
+                };
+                (label.clone(), report)
+            })
+            .collect();
+        for ((_, label), lifetime) in self.lifetimes.lock().unwrap().iter() {
+            if lifetime.expired {
+                reports.entry(label.clone()).or_default().expired = true;
+            }
+        }
+        reports
+    }
+
+    /// record the outcome of an exchange, `error` stands for a failed or 5xx exchange.
+    pub fn record(&self, faulted: bool, latency: Duration, error: bool) {
+        let stats = if faulted {
+            &self.faulted
+        } else {
+            &self.baseline
+        };
+        let slow = self
+            .slo
+            .as_ref()
+            .and_then(|slo| slo.latency_threshold)
+            .is_some_and(|threshold| latency > threshold);
+        stats.record(latency, error, slow);
+    }
+
+    /// elapsed returns the duration since the metrics started recording.
+    pub fn elapsed(&self) -> Duration {
+        self.started_at.elapsed()
+    }
+
+    /// phase_reports returns the reports of the baseline and the faulted exchanges.
+    pub fn phase_reports(&self) -> (PhaseReport, PhaseReport) {
+        (self.baseline.report(), self.faulted.report())
+    }
+
+    /// slo_report estimates the error budget burned during the experiment window.
+    pub fn slo_report(&self) -> Option<SLOReport> {
+        let slo = self.slo.as_ref()?;
+        let load = |stats: &ExchangeStats| {
+            (
+                stats.requests.load(Ordering::Relaxed),
+                stats.errors.load(Ordering::Relaxed),
+                stats.slow.load(Ordering::Relaxed),
+            )
+        };
+        let (base_requests, base_errors, base_slow) = load(&self.baseline);
+        let (fault_requests, fault_errors, fault_slow) = load(&self.faulted);
+
+        let availability = slo.availability.map(|objective| {
+            budget(
+                objective,
+                (base_requests, base_errors),
+                (fault_requests, fault_errors),
+            )
+        });
+        let latency = slo.latency_threshold.map(|_| {
+            budget(
+                slo.latency_objective,
+                (base_requests, base_slow),
+                (fault_requests, fault_slow),
+            )
+        });
+
+        Some(SLOReport {
+            window_secs: self.elapsed().as_secs_f64(),
+            baseline: self.baseline.report(),
+            faulted: self.faulted.report(),
+            availability,
+            latency,
+        })
+    }
+}
+
+fn ratio(part: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        part as f64 / total as f64
+    }
+}
+
+/// budget calculates the burn of the error budget, `baseline` and `faulted` are pairs of
+/// (requests, bad requests).
+fn budget(objective: f64, baseline: (u64, u64), faulted: (u64, u64)) -> BudgetReport {
+    let requests = baseline.0 + faulted.0;
+    let bad = baseline.1 + faulted.1;
+    let allowed = (100.0 - objective) / 100.0;
+    let burn = |bad_ratio: f64| {
+        if allowed > 0.0 {
+            bad_ratio / allowed
+        } else if bad_ratio > 0.0 {
+            f64::INFINITY
+        } else {
+            0.0
+        }
+    };
+
+    // Bad requests the faulted exchanges would have had at the baseline rate are not injected.
+    let expected_bad = ratio(baseline.1, baseline.0) * faulted.0 as f64;
+    let injected_bad = (faulted.1 as f64 - expected_bad).max(0.0);
+
+    BudgetReport {
+        objective,
+        observed: 100.0 * (1.0 - ratio(bad, requests)),
+        budget_burn: burn(ratio(bad, requests)),
+        injected_burn: burn(if requests == 0 {
+            0.0
+        } else {
+            injected_bad / requests as f64
+        }),
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SLOReport {
+    pub window_secs: f64,
+    pub baseline: PhaseReport,
+    pub faulted: PhaseReport,
+    pub availability: Option<BudgetReport>,
+    pub latency: Option<BudgetReport>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PhaseReport {
+    pub requests: u64,
+    pub errors: u64,
+    pub error_ratio: f64,
+    pub p50_ms: Option<u64>,
+    pub p99_ms: Option<u64>,
+}
+
+/// RuleReport counts the exchanges a rule is applied to, the ones it aborts and the ones it
+/// delays, with the percentiles of the delays injected.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RuleReport {
+    pub hits: u64,
+    pub aborts: u64,
+    pub delays: u64,
+    pub delay_p50_ms: Option<u64>,
+    pub delay_p99_ms: Option<u64>,
+    /// expired tells whether the duration of the rule is over, it no longer applies.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub expired: bool,
+}
+
+/// ComparisonReport counts the exchanges whose actual response is different from the shadow one.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ComparisonReport {
+    pub compared: u64,
+    pub different: u64,
+    pub status: u64,
+    pub headers: u64,
+    pub body: u64,
+}
+
+/// BaselineReport contrasts the experiment with the baseline captured before it, per path.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BaselineReport {
+    pub duration_secs: f64,
+    /// captured is false while the baseline is still being captured.
+    pub captured: bool,
+    pub paths: BTreeMap<String, PathReport>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PathReport {
+    pub baseline: PhaseReport,
+    pub experiment: PhaseReport,
+    /// error_ratio_delta is the error ratio of the experiment minus the one of the baseline.
+    pub error_ratio_delta: f64,
+    /// p99_ratio is the p99 latency of the experiment over the one of the baseline.
+    pub p99_ratio: Option<f64>,
+}
+
+/// BudgetReport describes how much error budget has been consumed, a `budget_burn` over 1 means
+/// the objective is violated in the experiment window.
+#[derive(Debug, Clone, Serialize)]
+pub struct BudgetReport {
+    pub objective: f64,
+    pub observed: f64,
+    pub budget_burn: f64,
+    pub injected_burn: f64,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::sleep;
+    use std::time::{Duration, Instant};
+
+    use crate::handler::http::rule::Rule;
+    use crate::metrics::{budget, BaselineConfig, Histogram, Metrics};
+    use crate::timeline::EventKind;
+
+    #[test]
+    fn test_histogram_percentile() {
+        let histogram = Histogram::new();
+        assert_eq!(histogram.percentile(0.5), None);
+        for ms in 1..=100 {
+            histogram.record(Duration::from_millis(ms));
+        }
+        assert_eq!(histogram.count(), 100);
+        assert_eq!(histogram.percentile(0.5), Some(Duration::from_millis(50)));
+        assert_eq!(histogram.percentile(0.99), Some(Duration::from_millis(100)));
+
+        histogram.record(Duration::from_secs(120));
+        assert_eq!(histogram.percentile(1.0), Some(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn test_budget() {
+        // 1% errors in baseline, 10% errors in faulted exchanges.
+        let report = budget(99.0, (1000, 10), (1000, 100));
+        assert!((report.observed - 94.5).abs() < 1e-9);
+        assert!((report.budget_burn - 5.5).abs() < 1e-9);
+        assert!((report.injected_burn - 4.5).abs() < 1e-9);
+
+        let report = budget(100.0, (10, 0), (0, 0));
+        assert_eq!(report.budget_burn, 0.0);
+    }
+
+    #[test]
+    fn test_baseline() {
+        let metrics = Metrics::new(
+            None,
+            Some(BaselineConfig {
+                duration: Duration::from_millis(100),
+                max_paths: 1,
+            }),
+        );
+        assert!(metrics.observing());
+        metrics.record_path("/a", Duration::from_millis(10), false);
+        // over max_paths
+        metrics.record_path("/b", Duration::from_millis(10), false);
+        assert!(!metrics.baseline_report().unwrap().captured);
+
+        sleep(Duration::from_millis(150));
+        assert!(!metrics.observing());
+        metrics.record_path("/a", Duration::from_millis(100), true);
+        metrics.record_path("/b", Duration::from_millis(100), true);
+        let report = metrics.baseline_report().unwrap();
+        assert!(report.captured);
+        assert_eq!(report.paths.len(), 1);
+        let path = &report.paths["/a"];
+        assert_eq!(path.baseline.p99_ms, Some(10));
+        assert_eq!(path.experiment.p99_ms, Some(100));
+        assert_eq!(path.p99_ratio, Some(10.0));
+        assert_eq!(path.error_ratio_delta, 1.0);
+
+        let kinds: Vec<_> = metrics
+            .timeline()
+            .events()
+            .into_iter()
+            .map(|event| event.kind)
+            .collect();
+        assert_eq!(kinds, vec![EventKind::BaselineCaptured { paths: 1 }]);
+    }
+
+    #[test]
+    fn test_arm() {
+        let metrics = Metrics::new(None, None);
+        assert!(metrics.armed());
+        metrics.arm(false);
+        metrics.arm(false);
+        assert!(!metrics.armed());
+        metrics.arm(true);
+        assert!(metrics.armed());
+
+        let kinds: Vec<_> = metrics
+            .timeline()
+            .events()
+            .into_iter()
+            .map(|event| event.kind)
+            .collect();
+        assert_eq!(kinds, vec![EventKind::Disarmed, EventKind::Armed]);
+    }
+
+    #[test]
+    fn test_rule_expiry() {
+        let metrics = Metrics::new(None, None);
+        let rule = Rule::request()
+            .name("abort-api")
+            .duration(Duration::from_secs(600))
+            .abort()
+            .build()
+            .unwrap();
+        let unlimited = Rule::request().abort().build().unwrap();
+        let loaded = Instant::now();
+        assert!(!metrics.expired(None, 0, &rule, loaded));
+        assert!(!metrics.expired(None, 0, &rule, loaded + Duration::from_secs(599)));
+        assert!(metrics.expired(None, 0, &rule, loaded + Duration::from_secs(600)));
+        assert!(metrics.expired(None, 0, &rule, loaded + Duration::from_secs(601)));
+        assert!(!metrics.expired(None, 1, &unlimited, loaded + Duration::from_secs(601)));
+        assert!(metrics.rule_reports()["abort-api"].expired);
+
+        let kinds: Vec<_> = metrics
+            .timeline()
+            .events()
+            .into_iter()
+            .map(|event| event.kind)
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![EventKind::RuleExpired {
+                rule: 0,
+                name: Some("abort-api".to_string()),
+            }]
+        );
+    }
+}
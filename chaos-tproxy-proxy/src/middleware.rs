@@ -0,0 +1,478 @@
+use std::future::Future;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use anyhow::{Error, Result};
+use futures::future;
+use http::{Request, Response};
+use hyper::service::Service;
+use hyper::Body;
+use tracing::debug;
+
+use crate::clock::{Clock, SystemClock};
+use crate::handler::http::client_ip::ClientAddr;
+use crate::handler::http::compensation::Compensation;
+use crate::handler::http::exchange::ExchangeId;
+use crate::handler::http::follow_up::FollowUps;
+use crate::handler::http::inject::{copy_request, mirror, mirrors, Injected, Injection};
+use crate::handler::http::rule::{Direction, MatchPolicy, Rule, Target};
+use crate::handler::http::selector::{select_request, select_response};
+use crate::proxy::http::resolver::Resolver;
+
+/// ChaosLayer wraps the services of the applications with the rules, so that the same faults as
+/// the proxy could be injected into them in unit tests, without any network interception.
+///
+/// It is a `tower::Layer`, e.g. `tower::ServiceBuilder::new().layer(chaos)`.
+#[derive(Debug, Clone)]
+pub struct ChaosLayer {
+    rules: Arc<Vec<Rule>>,
+    port: u16,
+    clock: Arc<dyn Clock>,
+    /// loaded is the start of the durations of the rules.
+    loaded: Instant,
+    match_policy: MatchPolicy,
+    fault_markers: bool,
+    /// follow_ups are registered by the rules applied, shared by the services of the layer.
+    follow_ups: Arc<FollowUps>,
+}
+
+impl ChaosLayer {
+    pub fn new(rules: Vec<Rule>) -> Self {
+        Self {
+            rules: Arc::new(rules),
+            port: 0,
+            clock: Arc::new(SystemClock),
+            loaded: SystemClock.now(),
+            match_policy: MatchPolicy::default(),
+            fault_markers: false,
+            follow_ups: Default::default(),
+        }
+    }
+
+    /// port sets the port matched by the `port` selectors, as the proxy matches the original
+    /// destination port.
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// clock sets the clock of the delays, the patterns and the rate limits, e.g. a
+    /// [MockClock](crate::clock::MockClock) to test the timeouts of the applications without
+    /// waiting for them. The durations of the rules start at the time of the clock.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.loaded = clock.now();
+        self.clock = clock;
+        self
+    }
+
+    /// match_policy sets how the rules matching the same exchange interact, all of them apply by
+    /// default.
+    pub fn match_policy(mut self, match_policy: MatchPolicy) -> Self {
+        self.match_policy = match_policy;
+        self
+    }
+
+    /// fault_markers stamps the rules with `fault_marker` and their faults on the exchanges.
+    pub fn fault_markers(mut self, fault_markers: bool) -> Self {
+        self.fault_markers = fault_markers;
+        self
+    }
+
+    pub fn layer<S>(&self, inner: S) -> Chaos<S> {
+        Chaos {
+            inner,
+            layer: self.clone(),
+        }
+    }
+
+    /// admits checks the gates of the rule besides its selector, the outbound-only rules never
+    /// match.
+    fn admits(&self, rule: &Rule) -> bool {
+        rule.direction != Some(Direction::Outbound)
+            && rule.is_active(None, &*self.clock)
+            && !rule.expired(self.loaded, self.clock.now())
+    }
+
+    /// register_follow_up registers the follow-up of the rule applied to the exchange.
+    fn register_follow_up(&self, index: usize, rule: &Rule, client: IpAddr, path: &str) {
+        if let Some(follow_up) = &rule.follow_up {
+            self.follow_ups
+                .register(index, follow_up, client, path, self.clock.now());
+        }
+    }
+}
+
+impl<S> tower::Layer<S> for ChaosLayer {
+    type Service = Chaos<S>;
+
+    fn layer(&self, inner: S) -> Chaos<S> {
+        ChaosLayer::layer(self, inner)
+    }
+}
+
+/// Chaos applies the rules to the exchanges of the inner service as if they were inbound
+/// exchanges of the proxy. The outbound-only rules and the rules of the Tcp target never match.
+/// The follow-ups are registered by the client of [ClientAddr] if set by an outer layer.
+///
+/// The `abort` actions fail the calls with [Abort](crate::handler::http::action::Abort), the
+/// hyper servers close the connections on the errors. There is no connection to reset, the
+/// bodies truncated in the reset mode are closed as well.
+#[derive(Debug, Clone)]
+pub struct Chaos<S> {
+    inner: S,
+    layer: ChaosLayer,
+}
+
+impl<S> Service<Request<Body>> for Chaos<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>> + Clone + Send + 'static,
+    S::Future: Send,
+    S::Error: Into<Error>,
+{
+    type Response = Response<Body>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response<Body>>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        // the ready service is taken to the call, and a clone is left for the next one
+        let clone = self.inner.clone();
+        let inner = mem::replace(&mut self.inner, clone);
+        Box::pin(handle(inner, self.layer.clone(), request))
+    }
+}
+
+async fn handle<S>(
+    mut inner: S,
+    layer: ChaosLayer,
+    mut request: Request<Body>,
+) -> Result<Response<Body>>
+where
+    S: Service<Request<Body>, Response = Response<Body>> + Clone + Send + 'static,
+    S::Future: Send,
+    S::Error: Into<Error>,
+{
+    // the ID assigned by an outer layer is kept
+    if request.extensions().get::<ExchangeId>().is_none() {
+        request.extensions_mut().insert(ExchangeId::next());
+    }
+    let exchange = ExchangeId::of(request.extensions());
+    let client = request
+        .extensions()
+        .get::<ClientAddr>()
+        .map_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED), |client| client.0.ip());
+    let clock = &*layer.clock;
+
+    let request_rules: Vec<_> = layer
+        .rules
+        .iter()
+        .enumerate()
+        .filter(|(_, rule)| {
+            rule.target == Target::Request
+                && select_request(layer.port, &request, &rule.selector)
+                && layer.admits(rule)
+                && rule.admits(request.headers(), request.extensions())
+        })
+        .collect();
+    // the follow-ups registered by the earlier exchanges come first among the same priority
+    let path = request.uri().path().to_string();
+    let follow_up_rules = layer
+        .follow_ups
+        .rules(&layer.rules, client, &path, clock.now());
+    let request_rules = layer
+        .match_policy
+        .select(follow_up_rules.into_iter().chain(request_rules).collect());
+    let request_matched = !request_rules.is_empty();
+
+    // copy the untouched request to the shadow backends
+    let mirrors = mirrors(&request_rules);
+    if !mirrors.is_empty() {
+        let (parts, body) = request.into_parts();
+        let body = hyper::body::to_bytes(body).await?;
+        for action in mirrors {
+            mirror(&parts, &body, action, Resolver::default())?;
+        }
+        request = Request::from_parts(parts, body.into());
+    }
+
+    let mut injection =
+        Injection::new(clock, Compensation::start(false)).fault_markers(layer.fault_markers);
+    let injected = injection
+        .request(request, request_rules, |index, rule, _| {
+            layer.register_follow_up(index, rule, client, &path)
+        })
+        .await?;
+    let mut request = match injected {
+        Injected::Forward(request) => request,
+        Injected::Respond(response) => return Ok(response),
+    };
+    let forwarding = mem::take(&mut injection.forwarding);
+
+    // the copies are served by the clones of the inner service
+    if !forwarding.duplicates.is_empty() {
+        let (parts, body) = request.into_parts();
+        let body = hyper::body::to_bytes(body).await?;
+        for duplicate in forwarding.duplicates {
+            for i in 1..=duplicate.count {
+                let request = copy_request(&parts, body.clone())?;
+                let delay = duplicate.interval.map(|interval| interval * i);
+                let mut inner = inner.clone();
+                let clock = layer.clock.clone();
+                tokio::spawn(async move {
+                    if let Some(delay) = delay {
+                        clock.sleep(delay).await;
+                    }
+                    let ready = future::poll_fn(|cx| inner.poll_ready(cx))
+                        .await
+                        .map_err(Into::<Error>::into);
+                    let result = match ready {
+                        Ok(()) => inner.call(request).await.map_err(Into::into),
+                        Err(e) => Err(e),
+                    };
+                    if let Err(e) = result {
+                        debug!("fail to serve duplicate request: {}", e);
+                    }
+                });
+            }
+        }
+        request = Request::from_parts(parts, body.into());
+    }
+    injection.mark(&mut request)?;
+
+    let uri = request.uri().clone();
+    let method = request.method().clone();
+    let headers = request.headers().clone();
+    let calling = async { inner.call(request).await.map_err(Into::<Error>::into) };
+    let mut response = match &forwarding.timeout {
+        Some(timeout) => timeout.run(calling, clock).await?,
+        None => calling.await?,
+    };
+    clock.sleep(forwarding.response_delay).await;
+    response.extensions_mut().insert(exchange);
+
+    // only one rule applies to the exchange under the first-match policy
+    let skip_response = request_matched && layer.match_policy == MatchPolicy::First;
+    let response_rules: Vec<_> = layer
+        .rules
+        .iter()
+        .enumerate()
+        .filter(|(_, rule)| {
+            !skip_response
+                && rule.target == Target::Response
+                && select_response(
+                    layer.port,
+                    &uri,
+                    &method,
+                    &headers,
+                    &response,
+                    &rule.selector,
+                )
+                && layer.admits(rule)
+                && rule.admits(&headers, response.extensions())
+        })
+        .collect();
+    let response_rules = layer.match_policy.select(response_rules);
+    injection
+        .response(
+            response,
+            &method,
+            &uri,
+            &headers,
+            response_rules,
+            |index, rule, _| layer.register_follow_up(index, rule, client, uri.path()),
+        )
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::{Infallible, TryInto};
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
+
+    use futures::future;
+    use http::{Request, Response, StatusCode};
+    use hyper::service::{service_fn, Service};
+    use hyper::Body;
+
+    use crate::clock::MockClock;
+    use crate::handler::http::action::{Abort, AbortMode};
+    use crate::handler::http::rule::{MatchPolicy, Rule};
+    use crate::middleware::ChaosLayer;
+    use crate::raw_config::RawRule;
+
+    /// rules returns the rules of the raw ones in JSON.
+    fn rules(rules: serde_json::Value) -> Vec<Rule> {
+        let rules: Vec<RawRule> = serde_json::from_value(rules).unwrap();
+        rules
+            .into_iter()
+            .map(|rule| rule.try_into().unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_chaos() {
+        let rules: Vec<RawRule> = serde_json::from_value(serde_json::json!([
+            {
+                "target": "Request",
+                "selector": {"path": "/api/*"},
+                "actions": {"replace": {"headers": {"x-chaos": "1"}}},
+            },
+            {
+                "target": "Response",
+                "selector": {"request_headers": {"x-chaos": "1"}},
+                "actions": {"replace": {"code": 503}},
+            },
+        ]))
+        .unwrap();
+        let rules = rules
+            .into_iter()
+            .map(|rule| rule.try_into().unwrap())
+            .collect();
+        let mut service = ChaosLayer::new(rules).layer(service_fn(|request: Request<Body>| {
+            let chaos = request.headers().contains_key("x-chaos");
+            async move { Ok::<_, Infallible>(Response::new(Body::from(chaos.to_string()))) }
+        }));
+
+        let request = Request::get("/api/users").body(Body::empty()).unwrap();
+        let response = service.call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "true");
+
+        let request = Request::get("/health").body(Body::empty()).unwrap();
+        let response = service.call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_clock() {
+        let rule: RawRule = serde_json::from_value(serde_json::json!({
+            "target": "Response",
+            "selector": {},
+            "actions": {
+                "delay": "1h",
+                "pattern": {"shape": "square", "period": "2h", "duty_cycle": 0.5},
+            },
+        }))
+        .unwrap();
+        let rule: Rule = rule.try_into().unwrap();
+        let clock = Arc::new(MockClock::new(
+            rule.actions.pattern.as_ref().unwrap().started_at,
+        ));
+        let mut service = ChaosLayer::new(vec![rule])
+            .clock(clock.clone())
+            .layer(service_fn(|_| async {
+                Ok::<_, Infallible>(Response::new(Body::empty()))
+            }));
+
+        // the delay of an hour passes at once
+        let request = Request::get("/").body(Body::empty()).unwrap();
+        service.call(request).await.unwrap();
+        assert_eq!(clock.elapsed(), Duration::from_secs(3600));
+        // the pattern is inactive in the second hour of the period
+        let request = Request::get("/").body(Body::empty()).unwrap();
+        service.call(request).await.unwrap();
+        assert_eq!(clock.elapsed(), Duration::from_secs(3600));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_timeout() {
+        let rules: Vec<RawRule> = serde_json::from_value(serde_json::json!([
+            {
+                "target": "Request",
+                "selector": {"path": "/api/*"},
+                "actions": {"timeout": {"after": "30s"}},
+            },
+            {
+                "target": "Request",
+                "selector": {"path": "/api/orders"},
+                "actions": {"timeout": {"after": "5s", "abort_mode": "reset"}},
+            },
+        ]))
+        .unwrap();
+        let rules = rules
+            .into_iter()
+            .map(|rule| rule.try_into().unwrap())
+            .collect();
+        let clock = Arc::new(MockClock::new(SystemTime::now()));
+        let mut service = ChaosLayer::new(rules)
+            .clock(clock.clone())
+            .layer(service_fn(|_| async {
+                future::pending::<()>().await;
+                Ok::<_, Infallible>(Response::new(Body::empty()))
+            }));
+
+        let request = Request::get("/api/users").body(Body::empty()).unwrap();
+        let response = service.call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(clock.elapsed(), Duration::from_secs(30));
+
+        // the shorter timeout of the matched rules applies
+        let request = Request::get("/api/orders").body(Body::empty()).unwrap();
+        let err = service.call(request).await.unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&Abort(AbortMode::Reset)));
+        assert_eq!(clock.elapsed(), Duration::from_secs(35));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_follow_up() {
+        let rules = rules(serde_json::json!([{
+            "target": "Request",
+            "selector": {"path": "/api/orders"},
+            "actions": {"abort": {"code": 503}},
+            "follow_up": {"duration": "30s", "actions": {"delay": "1s"}},
+        }]));
+        let clock = Arc::new(MockClock::new(SystemTime::now()));
+        let chaos = ChaosLayer::new(rules)
+            .clock(clock.clone())
+            .match_policy(MatchPolicy::First);
+        let mut service = tower::Layer::layer(
+            &chaos,
+            service_fn(|_| async { Ok::<_, Infallible>(Response::new(Body::empty())) }),
+        );
+
+        let request = Request::get("/api/orders").body(Body::empty()).unwrap();
+        let response = service.call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        // the follow-up comes first, and the only rule applied under the first-match policy
+        let request = Request::get("/api/orders").body(Body::empty()).unwrap();
+        let response = service.call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(clock.elapsed(), Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn test_duplicate() {
+        let rules = rules(serde_json::json!([{
+            "target": "Request",
+            "selector": {},
+            "actions": {"duplicate": {"count": 2}},
+        }]));
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let mut service =
+            ChaosLayer::new(rules).layer(service_fn(move |request: Request<Body>| {
+                let sender = sender.clone();
+                async move {
+                    let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                    sender.send(body).unwrap();
+                    Ok::<_, Infallible>(Response::new(Body::empty()))
+                }
+            }));
+
+        let request = Request::post("/").body(Body::from("order")).unwrap();
+        service.call(request).await.unwrap();
+        // the copies are served by the inner service as well
+        for _ in 0..3 {
+            assert_eq!(receiver.recv().await.unwrap(), "order");
+        }
+    }
+}
@@ -0,0 +1,462 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::net::{IpAddr, SocketAddr};
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+#[cfg(windows)]
+use std::os::windows::io::{AsRawSocket, RawSocket};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use hyper::client::connect::{Connected, Connection};
+use ipnetwork::IpNetwork;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// LINKTYPE_RAW is the link type of the packets beginning with the IPv4 or IPv6 header.
+const LINKTYPE_RAW: u32 = 101;
+
+const SNAPLEN: u32 = 65535;
+
+/// MAX_SEGMENT is the payload of a packet at most, the bytes read or written at once are split
+/// into the segments of it.
+const MAX_SEGMENT: usize = 16 * 1024;
+
+const FIN: u8 = 0x01;
+const SYN: u8 = 0x02;
+const PSH: u8 = 0x08;
+const ACK: u8 = 0x10;
+
+/// PcapConfig makes the proxy write the byte streams of the connections to pcap files, as TCP
+/// packets rebuilt from the streams.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PcapConfig {
+    /// dir of `client.pcap`, the streams between the clients and the proxy, and `upstream.pcap`,
+    /// the streams between the proxy and the original destinations.
+    pub dir: PathBuf,
+    /// network of the original destinations captured, all by default.
+    pub network: Option<IpNetwork>,
+    /// port of the original destinations captured, all by default.
+    pub port: Option<u16>,
+}
+
+impl PcapConfig {
+    fn matches(&self, target: &SocketAddr) -> bool {
+        self.network
+            .map(|network| network.contains(target.ip()))
+            .unwrap_or(true)
+            && self.port.map(|port| port == target.port()).unwrap_or(true)
+    }
+}
+
+/// Pcap captures the connections of the server to the files.
+pub struct Pcap {
+    config: PcapConfig,
+    client: Arc<PcapWriter>,
+    upstream: Arc<PcapWriter>,
+}
+
+impl Pcap {
+    pub fn create(config: &PcapConfig) -> Result<Self> {
+        std::fs::create_dir_all(&config.dir)
+            .map_err(|e| anyhow!("fail to create pcap dir {}: {}", config.dir.display(), e))?;
+        Ok(Self {
+            config: config.clone(),
+            client: Arc::new(PcapWriter::create(&config.dir.join("client.pcap"))?),
+            upstream: Arc::new(PcapWriter::create(&config.dir.join("upstream.pcap"))?),
+        })
+    }
+
+    /// client captures the connection accepted from the client to the original destination.
+    pub fn client<S>(&self, io: S, client: SocketAddr, target: SocketAddr) -> Captured<S> {
+        if !self.config.matches(&target) {
+            return Captured::new(io);
+        }
+        Captured {
+            io,
+            flow: Some(Flow::open(self.client.clone(), target, client, false)),
+        }
+    }
+
+    /// upstream captures the connection opened from the local address to the original
+    /// destination.
+    pub fn upstream<S>(&self, io: S, local: SocketAddr, target: SocketAddr) -> Captured<S> {
+        if !self.config.matches(&target) {
+            return Captured::new(io);
+        }
+        Captured {
+            io,
+            flow: Some(Flow::open(self.upstream.clone(), local, target, true)),
+        }
+    }
+}
+
+/// PcapWriter writes the packets to a pcap file, shared by the connections.
+pub struct PcapWriter {
+    file: Mutex<File>,
+}
+
+impl PcapWriter {
+    pub fn create(path: &Path) -> Result<Self> {
+        let mut file = File::create(path)
+            .map_err(|e| anyhow!("fail to create pcap {}: {}", path.display(), e))?;
+        let mut header = vec![];
+        header.extend_from_slice(&0xa1b2c3d4u32.to_le_bytes());
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&4u16.to_le_bytes());
+        // the timestamps are in UTC, and their accuracy is unknown
+        header.extend_from_slice(&0i32.to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(&SNAPLEN.to_le_bytes());
+        header.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        file.write_all(&header)?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    fn write(&self, packet: &[u8]) {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut record = Vec::with_capacity(16 + packet.len());
+        record.extend_from_slice(&(time.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&time.subsec_micros().to_le_bytes());
+        record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        record.extend_from_slice(packet);
+        // a packet is written at once, so that the packets of the connections never interleave
+        if let Err(e) = self.file.lock().unwrap().write_all(&record) {
+            tracing::warn!("fail to write pcap: {}", e);
+        }
+    }
+}
+
+/// Flow rebuilds the TCP packets of a connection from its byte stream, with the sequence numbers
+/// of both ends, so that the stream is reassembled by Wireshark.
+struct Flow {
+    writer: Arc<PcapWriter>,
+    local: SocketAddr,
+    peer: SocketAddr,
+    /// the next sequence numbers of the local and the peer end.
+    local_seq: u32,
+    peer_seq: u32,
+    local_fin: bool,
+    peer_fin: bool,
+}
+
+impl Flow {
+    /// open writes the handshake of the connection, initiated by the local end if `active`.
+    fn open(writer: Arc<PcapWriter>, local: SocketAddr, peer: SocketAddr, active: bool) -> Self {
+        let mut flow = Self {
+            writer,
+            local,
+            peer,
+            local_seq: rand::random(),
+            peer_seq: rand::random(),
+            local_fin: false,
+            peer_fin: false,
+        };
+        flow.packet(active, SYN, &[]);
+        flow.packet(!active, SYN | ACK, &[]);
+        flow.packet(active, ACK, &[]);
+        flow
+    }
+
+    fn send(&mut self, local: bool, data: &[u8]) {
+        for segment in data.chunks(MAX_SEGMENT) {
+            self.packet(local, PSH | ACK, segment);
+        }
+    }
+
+    fn fin(&mut self, local: bool) {
+        let fin = if local {
+            &mut self.local_fin
+        } else {
+            &mut self.peer_fin
+        };
+        if !*fin {
+            *fin = true;
+            self.packet(local, FIN | ACK, &[]);
+        }
+    }
+
+    /// packet writes a packet sent by the local end if `local`, or by the peer end, and advances
+    /// the sequence number of the sender.
+    fn packet(&mut self, local: bool, flags: u8, payload: &[u8]) {
+        let (src, dst, seq, ack) = if local {
+            (self.local, self.peer, self.local_seq, self.peer_seq)
+        } else {
+            (self.peer, self.local, self.peer_seq, self.local_seq)
+        };
+        // the SYN and the FIN take a sequence number
+        let len = payload.len() as u32 + (flags & (SYN | FIN) != 0) as u32;
+        let ack = if flags & ACK != 0 { ack } else { 0 };
+        self.writer
+            .write(&encode_packet(src, dst, seq, ack, flags, payload));
+        let seq = if local {
+            &mut self.local_seq
+        } else {
+            &mut self.peer_seq
+        };
+        *seq = seq.wrapping_add(len);
+    }
+}
+
+/// encode_packet encodes an IP packet of the TCP segment, the IPv4 addresses are mapped to IPv6
+/// if the other one is IPv6.
+fn encode_packet(
+    src: SocketAddr,
+    dst: SocketAddr,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    payload: &[u8],
+) -> Vec<u8> {
+    let mut tcp = Vec::with_capacity(20 + payload.len());
+    tcp.extend_from_slice(&src.port().to_be_bytes());
+    tcp.extend_from_slice(&dst.port().to_be_bytes());
+    tcp.extend_from_slice(&seq.to_be_bytes());
+    tcp.extend_from_slice(&ack.to_be_bytes());
+    tcp.push(5 << 4);
+    tcp.push(flags);
+    tcp.extend_from_slice(&u16::MAX.to_be_bytes());
+    tcp.extend_from_slice(&[0, 0, 0, 0]);
+    tcp.extend_from_slice(payload);
+
+    let mut packet = vec![];
+    let mut pseudo = vec![];
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            let mut header = vec![0x45, 0];
+            header.extend_from_slice(&(20 + tcp.len() as u16).to_be_bytes());
+            // no fragmentation
+            header.extend_from_slice(&[0, 0, 0x40, 0, 64, 6, 0, 0]);
+            header.extend_from_slice(&src.octets());
+            header.extend_from_slice(&dst.octets());
+            let checksum = checksum(&header);
+            header[10..12].copy_from_slice(&checksum.to_be_bytes());
+            packet.extend_from_slice(&header);
+
+            pseudo.extend_from_slice(&src.octets());
+            pseudo.extend_from_slice(&dst.octets());
+            pseudo.extend_from_slice(&[0, 6]);
+            pseudo.extend_from_slice(&(tcp.len() as u16).to_be_bytes());
+        }
+        (src, dst) => {
+            let to_v6 = |ip: IpAddr| match ip {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            let (src, dst) = (to_v6(src), to_v6(dst));
+            packet.extend_from_slice(&[0x60, 0, 0, 0]);
+            packet.extend_from_slice(&(tcp.len() as u16).to_be_bytes());
+            packet.extend_from_slice(&[6, 64]);
+            packet.extend_from_slice(&src.octets());
+            packet.extend_from_slice(&dst.octets());
+
+            pseudo.extend_from_slice(&src.octets());
+            pseudo.extend_from_slice(&dst.octets());
+            pseudo.extend_from_slice(&(tcp.len() as u32).to_be_bytes());
+            pseudo.extend_from_slice(&[0, 0, 0, 6]);
+        }
+    }
+    pseudo.extend_from_slice(&tcp);
+    let checksum = checksum(&pseudo);
+    tcp[16..18].copy_from_slice(&checksum.to_be_bytes());
+    packet.extend_from_slice(&tcp);
+    packet
+}
+
+/// checksum is the internet checksum (RFC 1071) of the bytes.
+fn checksum(bytes: &[u8]) -> u16 {
+    let mut sum: u32 = bytes
+        .chunks(2)
+        .map(|pair| u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)]) as u32)
+        .sum();
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Captured writes the bytes read from and written to the stream to the pcap, if the connection
+/// is captured.
+pub struct Captured<S> {
+    io: S,
+    flow: Option<Flow>,
+}
+
+impl<S> Captured<S> {
+    /// new wraps the stream without capturing it.
+    pub fn new(io: S) -> Self {
+        Self { io, flow: None }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.io
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Captured<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut this.io).poll_read(cx, buf);
+        if let (Poll::Ready(Ok(())), Some(flow)) = (&poll, &mut this.flow) {
+            let read = &buf.filled()[filled..];
+            if !read.is_empty() {
+                flow.send(false, read);
+            } else if buf.remaining() > 0 {
+                flow.fin(false);
+            }
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Captured<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.io).poll_write(cx, buf);
+        if let (Poll::Ready(Ok(n)), Some(flow)) = (&poll, &mut this.flow) {
+            flow.send(true, &buf[..*n]);
+        }
+        poll
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.io).poll_shutdown(cx);
+        if let (Poll::Ready(Ok(())), Some(flow)) = (&poll, &mut this.flow) {
+            flow.fin(true);
+        }
+        poll
+    }
+}
+
+#[cfg(unix)]
+impl<S: AsRawFd> AsRawFd for Captured<S> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.io.as_raw_fd()
+    }
+}
+
+#[cfg(windows)]
+impl<S: AsRawSocket> AsRawSocket for Captured<S> {
+    fn as_raw_socket(&self) -> RawSocket {
+        self.io.as_raw_socket()
+    }
+}
+
+impl<S: Connection> Connection for Captured<S> {
+    fn connected(&self) -> Connected {
+        self.io.connected()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::pcap::{checksum, Pcap, PcapConfig, ACK, FIN, PSH, SYN};
+
+    #[test]
+    fn test_checksum() {
+        // the example header of RFC 1071 section 3
+        assert_eq!(
+            checksum(&[0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7]),
+            !0xddf2
+        );
+        // a header with its checksum sums up to zero
+        let header = [
+            0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0xb8, 0x61, 0xc0, 0xa8,
+            0x00, 0x01, 0xc0, 0xa8, 0x00, 0xc7,
+        ];
+        assert_eq!(checksum(&header), 0);
+    }
+
+    #[tokio::test]
+    async fn test_pcap() {
+        let dir = tempfile::tempdir().unwrap();
+        let pcap = Pcap::create(&PcapConfig {
+            dir: dir.path().to_path_buf(),
+            network: None,
+            port: Some(80),
+        })
+        .unwrap();
+        let client = "10.0.0.1:50000".parse().unwrap();
+        let (proxy, mut peer) = tokio::io::duplex(1024);
+        let mut captured = pcap.client(proxy, client, "10.0.0.2:80".parse().unwrap());
+        peer.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        peer.shutdown().await.unwrap();
+        let mut request = vec![];
+        captured.read_to_end(&mut request).await.unwrap();
+        captured
+            .write_all(b"HTTP/1.1 200 OK\r\n\r\n")
+            .await
+            .unwrap();
+        captured.shutdown().await.unwrap();
+
+        // the other ports are not captured
+        let (proxy, _peer) = tokio::io::duplex(1024);
+        let mut captured = pcap.client(proxy, client, "10.0.0.2:443".parse().unwrap());
+        captured.write_all(b"hello").await.unwrap();
+
+        let file = std::fs::read(dir.path().join("client.pcap")).unwrap();
+        assert_eq!(file[..4], 0xa1b2c3d4u32.to_le_bytes());
+        let mut packets = vec![];
+        let mut rest = &file[24..];
+        while !rest.is_empty() {
+            let len = u32::from_le_bytes([rest[8], rest[9], rest[10], rest[11]]) as usize;
+            packets.push(rest[16..16 + len].to_vec());
+            rest = &rest[16 + len..];
+        }
+        let flags: Vec<_> = packets.iter().map(|packet| packet[33]).collect();
+        assert_eq!(
+            flags,
+            vec![
+                SYN,
+                SYN | ACK,
+                ACK,
+                PSH | ACK,
+                FIN | ACK,
+                PSH | ACK,
+                FIN | ACK
+            ]
+        );
+        // the request is sent by the client, and the response by the original destination
+        assert_eq!(packets[3][12..16], [10, 0, 0, 1]);
+        assert_eq!(&packets[3][40..], b"GET / HTTP/1.1\r\n\r\n");
+        assert_eq!(packets[5][12..16], [10, 0, 0, 2]);
+        assert_eq!(&packets[5][40..], b"HTTP/1.1 200 OK\r\n\r\n");
+        for packet in &packets {
+            assert_eq!(checksum(&packet[..20]), 0);
+        }
+        // the sequence numbers continue over the payloads
+        let seq =
+            |packet: &[u8]| u32::from_be_bytes([packet[24], packet[25], packet[26], packet[27]]);
+        assert_eq!(seq(&packets[4]), seq(&packets[3]).wrapping_add(18));
+        assert_eq!(
+            std::fs::read(dir.path().join("upstream.pcap"))
+                .unwrap()
+                .len(),
+            24
+        );
+    }
+}
@@ -0,0 +1,195 @@
+use std::convert::TryFrom;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, Interest};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::time::{sleep, timeout};
+use tracing::debug;
+
+use crate::handler::dns::{select_dns, synthesize_answer, DnsRule, Query};
+use crate::proxy::tcp::sockopt::{recv_orig_dst, set_recv_orig_dst, AsRawSocket};
+use crate::proxy::tcp::transparent_socket::TransparentSocket;
+
+/// DNS_PORT is the port of the queries handled by the DNS proxy.
+pub const DNS_PORT: u16 = 53;
+
+/// The forwarded queries are given up if the original server doesn't answer in time.
+const FORWARD_TIMEOUT: Duration = Duration::from_secs(5);
+/// The max size of the messages over UDP, with EDNS.
+const MAX_UDP_SIZE: usize = 4096;
+
+#[derive(Debug, Clone)]
+pub struct DnsConfig {
+    pub rules: Vec<DnsRule>,
+}
+
+/// DnsServer answers the DNS queries over UDP redirected by the iptables tproxy, the queries over
+/// TCP are accepted by the [HttpServer](crate::proxy::http::server::HttpServer) and handed over to
+/// [serve_stream].
+pub struct DnsServer {
+    config: Arc<DnsConfig>,
+    listen_port: u16,
+}
+
+impl DnsServer {
+    /// new returns the server listening on the UDP port, which is the same number as the TCP one
+    /// of the proxy.
+    pub fn new(config: Arc<DnsConfig>, listen_port: u16) -> Self {
+        Self {
+            config,
+            listen_port,
+        }
+    }
+
+    /// serve answers the queries to both the IPv4 and the IPv6 servers, only the IPv4 ones if
+    /// IPv6 is unavailable on the host.
+    pub async fn serve(&self) -> Result<()> {
+        let socket = bind_udp(SocketAddr::from((Ipv4Addr::UNSPECIFIED, self.listen_port)))?;
+        let socket_v6 = match bind_udp(SocketAddr::from((Ipv6Addr::UNSPECIFIED, self.listen_port)))
+        {
+            Ok(socket) => Some(socket),
+            Err(e) => {
+                debug!("IPv6 DNS proxy is unavailable: {}", e);
+                None
+            }
+        };
+        tracing::info!("DNS Proxy Listening");
+        match socket_v6 {
+            None => self.receive(socket).await,
+            Some(socket_v6) => {
+                tokio::try_join!(self.receive(socket), self.receive(socket_v6))?;
+                Ok(())
+            }
+        }
+    }
+
+    async fn receive(&self, socket: UdpSocket) -> Result<()> {
+        let mut buf = vec![0; MAX_UDP_SIZE];
+        loop {
+            socket.readable().await?;
+            let (n, client, server) = match socket.try_io(Interest::READABLE, || {
+                recv_orig_dst(socket.raw_socket(), &mut buf)
+            }) {
+                Ok(received) => received,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e.into()),
+            };
+            let message = buf[..n].to_vec();
+            let config = self.config.clone();
+            tokio::spawn(async move {
+                if let Err(e) = exchange_udp(&config, message, client, server).await {
+                    debug!("fail to answer DNS query of {}: {}", client, e);
+                }
+            });
+        }
+    }
+}
+
+fn bind_udp(addr: SocketAddr) -> Result<UdpSocket> {
+    let socket = TransparentSocket::bind_udp(addr)?;
+    set_recv_orig_dst(socket.raw_socket(), addr.is_ipv6())?;
+    Ok(socket)
+}
+
+/// exchange_udp answers the query from the original server address, the response is forwarded
+/// from the original server unless it is synthesized.
+async fn exchange_udp(
+    config: &DnsConfig,
+    message: Vec<u8>,
+    client: SocketAddr,
+    server: SocketAddr,
+) -> Result<()> {
+    let response = match answer(config, &message).await {
+        Some(response) => response,
+        None => forward_udp(&message, server).await?,
+    };
+    let socket = TransparentSocket::bind_udp(server)?;
+    socket.send_to(&response, client).await?;
+    Ok(())
+}
+
+/// answer applies the first matched rule to the query, and returns the synthesized response if
+/// any. The messages which can not be parsed are forwarded untouched.
+async fn answer(config: &DnsConfig, message: &[u8]) -> Option<Vec<u8>> {
+    let query = Query::parse(message).ok()?;
+    let (index, rule) = select_dns(&config.rules, &query)?;
+    debug!(
+        "DNS query {} (type {}) matched, rule({})",
+        query.name, query.query_type, index
+    );
+    if let Some(delay) = rule.action.delay {
+        sleep(delay).await;
+    }
+    synthesize_answer(message, &query, &rule.action.answer)
+}
+
+/// forward_udp sends the query from an ephemeral port rather than the one of the client, because
+/// the clients (e.g. glibc) send the A and AAAA queries from a port in parallel.
+async fn forward_udp(message: &[u8], server: SocketAddr) -> Result<Vec<u8>> {
+    let any: IpAddr = match server {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let socket = UdpSocket::bind(SocketAddr::new(any, 0)).await?;
+    socket.connect(server).await?;
+    socket.send(message).await?;
+    let mut buf = vec![0; MAX_UDP_SIZE];
+    let n = timeout(FORWARD_TIMEOUT, socket.recv(&mut buf)).await??;
+    buf.truncate(n);
+    Ok(buf)
+}
+
+/// serve_stream answers the queries over a TCP connection in order, the connection to the
+/// original server is opened on the first forwarded query.
+pub async fn serve_stream(
+    config: &DnsConfig,
+    mut client: TcpStream,
+    addr_remote: SocketAddr,
+    addr_local: SocketAddr,
+) -> Result<()> {
+    let mut upstream = None;
+    while let Some(message) = read_message(&mut client).await? {
+        let response = match answer(config, &message).await {
+            Some(response) => response,
+            None => {
+                let server = match &mut upstream {
+                    Some(server) => server,
+                    None => {
+                        let socket = TransparentSocket::bind(addr_remote)?;
+                        upstream.insert(socket.connect(addr_local).await?)
+                    }
+                };
+                write_message(server, &message).await?;
+                read_message(server)
+                    .await?
+                    .ok_or_else(|| anyhow!("DNS server {} closed the connection", addr_local))?
+            }
+        };
+        write_message(&mut client, &response).await?;
+    }
+    Ok(())
+}
+
+/// read_message reads a message prefixed by its length, `None` is returned at EOF.
+async fn read_message<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut len = [0; 2];
+    match reader.read_exact(&mut len).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let mut message = vec![0; u16::from_be_bytes(len) as usize];
+    reader.read_exact(&mut message).await?;
+    Ok(Some(message))
+}
+
+async fn write_message<W: AsyncWrite + Unpin>(writer: &mut W, message: &[u8]) -> Result<()> {
+    let len = u16::try_from(message.len())?;
+    writer.write_all(&len.to_be_bytes()).await?;
+    writer.write_all(message).await?;
+    Ok(())
+}
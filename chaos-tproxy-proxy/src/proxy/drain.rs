@@ -0,0 +1,82 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::select;
+use tokio::sync::Notify;
+use tokio::time::{sleep_until, Instant};
+
+/// Drain counts the exchanges in flight, so that the server lets them finish on shutdown.
+#[derive(Debug, Default)]
+pub struct Drain {
+    in_flight: AtomicUsize,
+    idle: Notify,
+}
+
+/// InFlight is held by an exchange until it finishes.
+#[derive(Debug)]
+pub struct InFlight(Arc<Drain>);
+
+impl Drain {
+    /// start counts an exchange until the returned guard is dropped.
+    pub fn start(self: &Arc<Self>) -> InFlight {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlight(self.clone())
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// wait waits for the exchanges in flight to finish, it returns false if some of them are
+    /// still in flight after the timeout.
+    pub async fn wait(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        loop {
+            // registered before checking, so that the last exchange finishing is not missed
+            let idle = self.idle.notified();
+            if self.in_flight() == 0 {
+                return true;
+            }
+            select! {
+                _ = idle => {}
+                _ = sleep_until(deadline) => return self.in_flight() == 0,
+            }
+        }
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if self.0.in_flight.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::proxy::drain::Drain;
+
+    #[tokio::test]
+    async fn test_drain() {
+        let drain = Arc::new(Drain::default());
+        assert!(drain.wait(Duration::from_millis(10)).await);
+
+        let first = drain.start();
+        let second = drain.start();
+        assert_eq!(drain.in_flight(), 2);
+        assert!(!drain.wait(Duration::from_millis(10)).await);
+
+        drop(first);
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            drop(second);
+        });
+        assert!(drain.wait(Duration::from_secs(5)).await);
+        assert_eq!(drain.in_flight(), 0);
+    }
+}
@@ -1,12 +1,119 @@
+use std::collections::BTreeMap;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+use std::time::Duration;
+
+use http::header::HeaderName;
 use rustls::{ClientConfig, ServerConfig};
 
-use crate::handler::http::rule::Rule;
+use crate::access_log::AccessLogConfig;
+use crate::clock::Clock;
+use crate::coordination::CoordinationConfig;
+use crate::experiment::ExperimentReportConfig;
+use crate::handler::http::body_limit::BodyLimit;
+use crate::handler::http::follow_up::FollowUps;
+use crate::handler::http::rule::{MatchPolicy, Rule};
+use crate::handler::http::selector::OptIn;
+use crate::handler::http::validation::ResponseValidator;
+use crate::har::HarConfig;
+use crate::metadata::MetadataResolver;
+use crate::metrics::{BaselineConfig, SLOConfig};
+use crate::pcap::PcapConfig;
+use crate::proxy::dns::DnsConfig;
+use crate::proxy::http::explicit::ExplicitConfig;
+use crate::proxy::http::pool::PoolConfig;
+use crate::proxy::http::resolver::Resolver;
+use crate::proxy::tcp::limit::{Capacity, ConnectionLimit};
+use crate::proxy::tcp::proxy_protocol::ProxyProtocol;
+use crate::proxy::tcp::sockopt::SocketOptions;
 use crate::raw_config::Role;
+use crate::report::ReportConfig;
+use crate::snapshot::SnapshotConfig;
+use crate::telemetry::TelemetryConfig;
 
 #[derive(Clone)]
 pub struct Config {
     pub http_config: HTTPConfig,
     pub tls_config: Option<TLSConfig>,
+    pub slo: Option<SLOConfig>,
+    /// baseline makes the proxy only observe the exchanges for a while before the rules apply.
+    pub baseline: Option<BaselineConfig>,
+    /// metadata resolves the labels of the clients for the selectors.
+    pub metadata: Option<Arc<dyn MetadataResolver>>,
+    /// coordination shares the pattern epoch and the blast radius cap with other instances.
+    pub coordination: Option<CoordinationConfig>,
+    /// report pushes the metrics to the controller of a cluster.
+    pub report: Option<ReportConfig>,
+    /// snapshot persists the metrics to local files periodically.
+    pub snapshot: Option<SnapshotConfig>,
+    /// connection_limits cap the concurrent connections forwarded to the original destinations.
+    pub connection_limits: Vec<ConnectionLimit>,
+    /// dns answers the intercepted DNS queries with the faults if enabled.
+    pub dns: Option<DnsConfig>,
+    /// listeners are opened besides the one of `listen_port` and `tls_config`.
+    pub listeners: Vec<ListenerConfig>,
+    /// drain_timeout bounds the wait for the exchanges in flight on shutdown.
+    pub drain_timeout: Duration,
+    /// access_log writes a record of every exchange if enabled.
+    pub access_log: Option<AccessLogConfig>,
+    /// har captures the exchanges to a HAR file if enabled.
+    pub har: Option<HarConfig>,
+    /// experiment_report writes a summary of the experiment on shutdown if enabled.
+    pub experiment_report: Option<ExperimentReportConfig>,
+    /// telemetry exports a span of every exchange over OTLP if enabled.
+    pub telemetry: Option<TelemetryConfig>,
+    /// pcap captures the connections to pcap files if enabled.
+    pub pcap: Option<PcapConfig>,
+    /// sk_lookup steers the connections to the listeners by eBPF if the backend is enabled.
+    pub sk_lookup: Option<SkLookupConfig>,
+    /// explicit is the listener of the forward proxy, served instead of the transparent ones.
+    pub explicit: Option<ExplicitConfig>,
+    /// runtime tunes the worker threads and the sockets.
+    pub runtime: RuntimeConfig,
+    /// connection_capacity caps the concurrent client connections of the proxy.
+    pub connection_capacity: Option<Capacity>,
+    /// request_capacity caps the requests in flight of the proxy.
+    pub request_capacity: Option<Capacity>,
+}
+
+/// RuntimeConfig tunes the proxy for the throughput instead of the defaults.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RuntimeConfig {
+    /// worker_threads of the runtime, the number of CPUs if not set. The runtime is built before
+    /// the config is read, so it is told by the controller on the command line.
+    pub worker_threads: Option<usize>,
+    /// accept_shards is the number of the listeners of each port, each accepted by its own loop.
+    pub accept_shards: usize,
+    /// socket is set on the sockets of the clients and the upstreams.
+    pub socket: SocketOptions,
+}
+
+impl Default for RuntimeConfig {
+    fn default() -> Self {
+        Self {
+            worker_threads: None,
+            accept_shards: 1,
+            socket: SocketOptions::default(),
+        }
+    }
+}
+
+/// SkLookupConfig steers the connections to the listeners by their destination ports, with an
+/// eBPF `sk_lookup` program attached to the netns of the proxy instead of the iptables TPROXY.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SkLookupConfig {
+    /// ports are the destination ports of the default listener, and then of each listener in
+    /// order.
+    pub ports: Vec<Vec<RangeInclusive<u16>>>,
+}
+
+/// ListenerConfig is a socket accepting the connections redirected from some of the proxy ports.
+#[derive(Clone)]
+pub struct ListenerConfig {
+    pub listen_port: u16,
+    pub tls_config: Option<TLSConfig>,
+    /// the connections are served by a dedicated runtime with the worker threads if set.
+    pub workers: Option<usize>,
 }
 
 #[derive(Clone, Debug)]
@@ -14,6 +121,67 @@ pub struct HTTPConfig {
     pub listen_port: u16,
     pub rules: Vec<Rule>,
     pub role: Option<Role>,
+    /// compare_mode would forward an untouched copy of each matched idempotent request and
+    /// record the differences between the responses.
+    pub compare_mode: bool,
+    /// latency_compensation would cut the processing time of the proxy from the delays, so that
+    /// the delays are added to the end-to-end latency exactly.
+    pub latency_compensation: bool,
+    /// fault_markers would stamp the requests and the responses modified by the rules with the
+    /// names of the rules and their faults.
+    pub fault_markers: bool,
+    /// inspect_all parses every connection as HTTP, the connections no HTTP rule could apply to
+    /// are relayed as raw TCP otherwise.
+    pub inspect_all: bool,
+    /// opt_in makes the rules only apply to the requests carrying the header, which is stripped
+    /// before forwarding.
+    pub opt_in: Option<OptIn>,
+    /// exchange_id_header stamps the forwarded requests and the responses to the clients with the
+    /// ID of their exchange in the header if set.
+    pub exchange_id_header: Option<HeaderName>,
+    /// resolver resolves the hostnames of the rerouted and mirrored requests.
+    pub resolver: Resolver,
+    /// proxy_protocol reads the clients from the PROXY headers of a load balancer, and tells them
+    /// to the original destinations.
+    pub proxy_protocol: ProxyProtocol,
+    /// clock tells the time to the delays, the patterns and the rate limits.
+    pub clock: Arc<dyn Clock>,
+    /// follow_ups are registered by the rules applied, shared by the connections accepted with
+    /// this config.
+    pub follow_ups: Arc<FollowUps>,
+    /// match_policy tells whether an exchange gets the actions of all the rules matching it, or
+    /// of the first one by priority.
+    pub match_policy: MatchPolicy,
+    /// validator checks the upstream responses before the response rules are applied.
+    pub validator: Option<Arc<ResponseValidator>>,
+    /// upstream_pool keeps the upstream connections alive for the next requests.
+    pub upstream_pool: PoolConfig,
+    /// body_limit caps the bodies buffered, they are never capped if not set.
+    pub body_limit: Option<BodyLimit>,
+    /// listener_rules are the rule sets of the listeners with their own rules by listen port,
+    /// the other listeners get `rules`.
+    pub listener_rules: BTreeMap<u16, Vec<Rule>>,
+    /// rule_set is the listen port of the listener whose own rules are `rules`, None for the
+    /// rules of the config.
+    pub rule_set: Option<u16>,
+}
+
+impl HTTPConfig {
+    /// for_listener returns the config of the connections of the listener with its own rules.
+    /// The follow-ups are registered by the indexes of the rules, so each rule set gets its own.
+    pub fn for_listener(&self, listen_port: u16) -> HTTPConfig {
+        HTTPConfig {
+            rules: self
+                .listener_rules
+                .get(&listen_port)
+                .cloned()
+                .unwrap_or_default(),
+            follow_ups: Default::default(),
+            listener_rules: Default::default(),
+            rule_set: Some(listen_port),
+            ..self.clone()
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -1,37 +1,98 @@
 use std::future::Future;
 use std::net::SocketAddr;
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::{Context, Poll};
 
 use anyhow::{Error, Result};
+use derivative::Derivative;
 use http::Uri;
 use hyper::service::Service;
 use tokio::net::TcpStream;
 use tracing::{instrument, trace};
 
+use crate::pcap::{Captured, Pcap};
+use crate::proxy::tcp::proxy_protocol::{write_header, Version};
+use crate::proxy::tcp::sockopt::{tcp_socket, SocketOptions};
 use crate::proxy::tcp::transparent_socket::TransparentSocket;
 
-#[derive(Debug, Clone)]
+#[derive(Derivative)]
+#[derivative(Debug)]
+#[derive(Clone)]
 pub struct HttpConnector {
     target: SocketAddr,
-    socket: TransparentSocket,
+    source: SocketAddr,
+    /// socket connects from the source, the connections are from the proxy itself if not set.
+    socket: Option<TransparentSocket>,
+    /// version of the PROXY header and the client it carries, no header is written if not set.
+    proxy_header: Option<(Version, SocketAddr)>,
+    /// options are set on the connections.
+    options: SocketOptions,
+    /// pcap captures the connections if enabled.
+    #[derivative(Debug = "ignore")]
+    pcap: Option<Arc<Pcap>>,
 }
 
 impl HttpConnector {
     pub fn new(dst: SocketAddr, src: SocketAddr) -> Self {
         Self {
             target: dst,
-            socket: TransparentSocket::new(src),
+            source: src,
+            socket: Some(TransparentSocket::new(src)),
+            proxy_header: None,
+            options: SocketOptions::default(),
+            pcap: None,
         }
     }
 
-    async fn connect(self, _: Uri) -> Result<TcpStream> {
-        Ok(self.socket.conn(self.target).await?)
+    /// with_proxy_header makes the connector write a PROXY header of the version carrying the
+    /// client, before anything else is sent on the connections.
+    pub fn with_proxy_header(mut self, version: Option<Version>, client: SocketAddr) -> Self {
+        self.proxy_header = version.map(|version| (version, client));
+        self
+    }
+
+    /// plain makes the connector connect from the address of the proxy instead of the source, e.g.
+    /// for the explicit proxy.
+    pub fn plain(mut self) -> Self {
+        self.socket = None;
+        self
+    }
+
+    /// with_socket_options sets the options on the connections.
+    pub fn with_socket_options(mut self, options: SocketOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// with_pcap makes the connector capture the connections.
+    pub fn with_pcap(mut self, pcap: Option<Arc<Pcap>>) -> Self {
+        self.pcap = pcap;
+        self
+    }
+
+    async fn connect(self, _: Uri) -> Result<Captured<TcpStream>> {
+        let stream = match &self.socket {
+            Some(socket) => socket.conn(self.target, &self.options).await?,
+            None => {
+                self.options
+                    .connect(tcp_socket(&self.target)?, self.target)
+                    .await?
+            }
+        };
+        let mut stream = match &self.pcap {
+            Some(pcap) => pcap.upstream(stream, self.source, self.target),
+            None => Captured::new(stream),
+        };
+        if let Some((version, client)) = self.proxy_header {
+            write_header(&mut stream, version, client, self.target).await?;
+        }
+        Ok(stream)
     }
 }
 
 impl Service<Uri> for HttpConnector {
-    type Response = TcpStream;
+    type Response = Captured<TcpStream>;
     type Error = Error;
     #[allow(clippy::type_complexity)]
     type Future =
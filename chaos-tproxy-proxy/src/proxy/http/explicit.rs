@@ -0,0 +1,354 @@
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use http::uri::{Authority, Uri};
+use http::StatusCode;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{lookup_host, TcpStream};
+use tokio::time::{sleep, timeout};
+
+/// MAX_HEAD is the largest head of the first request of a connection to the explicit proxy.
+const MAX_HEAD: usize = 8192;
+
+/// HEAD_TIMEOUT bounds the wait for the head of the first request.
+const HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// PEEK_INTERVAL is waited before peeking a partial head again, as peeking the bytes already
+/// received returns at once.
+const PEEK_INTERVAL: Duration = Duration::from_millis(5);
+
+/// SOCKS_VERSION is the version of SOCKS served, no authentication is required.
+const SOCKS_VERSION: u8 = 5;
+
+/// ExplicitConfig is the listener of the explicit proxy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExplicitConfig {
+    pub listen: SocketAddr,
+    pub protocol: ExplicitProtocol,
+}
+
+/// ExplicitProtocol is how the clients tell their targets to the explicit proxy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExplicitProtocol {
+    /// `CONNECT` requests and the requests of absolute URIs.
+    Http,
+    /// `CONNECT` commands of SOCKS5.
+    Socks5,
+}
+
+/// handshake returns the target of a connection to the explicit proxy, and whether the connection
+/// is tunneled. The tunnels are served as the intercepted connections to their targets are.
+pub async fn handshake(
+    stream: &mut TcpStream,
+    protocol: ExplicitProtocol,
+) -> Result<(SocketAddr, bool)> {
+    let handshake = async {
+        match protocol {
+            ExplicitProtocol::Http => http_handshake(stream).await,
+            ExplicitProtocol::Socks5 => Ok((socks5_handshake(stream).await?, true)),
+        }
+    };
+    timeout(HEAD_TIMEOUT, handshake)
+        .await
+        .map_err(|_| anyhow!("timeout reading the target"))?
+}
+
+/// http_handshake reads the target from the first request. A `CONNECT` request is answered and
+/// consumed, a request of an absolute URI is left in the stream for the HTTP service, which
+/// forwards each request by its own URI.
+async fn http_handshake(stream: &mut TcpStream) -> Result<(SocketAddr, bool)> {
+    let head = peek_head(stream).await?;
+    let (connect, host, port) = match request_target(&head) {
+        Ok(target) => target,
+        Err(e) => {
+            reject(stream, StatusCode::BAD_REQUEST).await;
+            return Err(e);
+        }
+    };
+    let target = match resolve(&host, port).await {
+        Ok(target) => target,
+        Err(e) => {
+            reject(stream, StatusCode::BAD_GATEWAY).await;
+            return Err(e);
+        }
+    };
+    if connect {
+        let mut consumed = vec![0; head.len()];
+        stream.read_exact(&mut consumed).await?;
+        stream
+            .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+            .await?;
+    }
+    Ok((target, connect))
+}
+
+/// socks5_handshake negotiates no authentication, and reads the target from the `CONNECT` command.
+/// The success is replied before the target is connected, the failures of connecting close the
+/// tunnel instead.
+async fn socks5_handshake(stream: &mut TcpStream) -> Result<SocketAddr> {
+    let mut header = [0; 2];
+    stream.read_exact(&mut header).await?;
+    if header[0] != SOCKS_VERSION {
+        return Err(anyhow!("unsupported SOCKS version {}", header[0]));
+    }
+    let mut methods = vec![0; header[1] as usize];
+    stream.read_exact(&mut methods).await?;
+    // only `NO AUTHENTICATION REQUIRED` is offered
+    if !methods.contains(&0) {
+        stream.write_all(&[SOCKS_VERSION, 0xff]).await?;
+        return Err(anyhow!("no acceptable SOCKS authentication method"));
+    }
+    stream.write_all(&[SOCKS_VERSION, 0]).await?;
+
+    let mut request = [0; 4];
+    stream.read_exact(&mut request).await?;
+    let [version, command, _, address_type] = request;
+    if version != SOCKS_VERSION {
+        return Err(anyhow!("unsupported SOCKS version {}", version));
+    }
+    let host = match address_type {
+        1 => {
+            let mut ip = [0; 4];
+            stream.read_exact(&mut ip).await?;
+            Ipv4Addr::from(ip).to_string()
+        }
+        3 => {
+            let mut name = vec![0; stream.read_u8().await? as usize];
+            stream.read_exact(&mut name).await?;
+            String::from_utf8(name)?
+        }
+        4 => {
+            let mut ip = [0; 16];
+            stream.read_exact(&mut ip).await?;
+            Ipv6Addr::from(ip).to_string()
+        }
+        _ => {
+            socks5_reply(stream, 8).await?;
+            return Err(anyhow!("unsupported SOCKS address type {}", address_type));
+        }
+    };
+    let port = stream.read_u16().await?;
+    // only `CONNECT` is served, neither `BIND` nor `UDP ASSOCIATE`
+    if command != 1 {
+        socks5_reply(stream, 7).await?;
+        return Err(anyhow!("unsupported SOCKS command {}", command));
+    }
+    match resolve(&host, port).await {
+        Ok(target) => {
+            socks5_reply(stream, 0).await?;
+            Ok(target)
+        }
+        Err(e) => {
+            socks5_reply(stream, 4).await?;
+            Err(e)
+        }
+    }
+}
+
+/// socks5_reply replies the command with the code, the bound address is left unspecified.
+async fn socks5_reply(stream: &mut TcpStream, code: u8) -> Result<()> {
+    stream
+        .write_all(&[SOCKS_VERSION, code, 0, 1, 0, 0, 0, 0, 0, 0])
+        .await?;
+    Ok(())
+}
+
+/// resolve returns the first address of the host.
+async fn resolve(host: &str, port: u16) -> Result<SocketAddr> {
+    lookup_host((host, port))
+        .await
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .ok_or_else(|| anyhow!("fail to resolve {}:{}", host, port))
+}
+
+/// peek_head returns the head of the first request without consuming it.
+async fn peek_head(stream: &TcpStream) -> Result<Vec<u8>> {
+    let mut buf = vec![0; MAX_HEAD];
+    loop {
+        let n = stream.peek(&mut buf).await?;
+        if n == 0 {
+            return Err(anyhow!("connection closed before the request head"));
+        }
+        if let Some(end) = buf[..n].windows(4).position(|window| window == b"\r\n\r\n") {
+            buf.truncate(end + 4);
+            return Ok(buf);
+        }
+        if n == buf.len() {
+            return Err(anyhow!("request head exceeds {} bytes", MAX_HEAD));
+        }
+        sleep(PEEK_INTERVAL).await;
+    }
+}
+
+/// request_target parses the request line, it returns whether the request is `CONNECT`, and the
+/// host and the port of its target.
+fn request_target(head: &[u8]) -> Result<(bool, String, u16)> {
+    let line = std::str::from_utf8(head)?
+        .lines()
+        .next()
+        .unwrap_or_default();
+    let mut parts = line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version)) if version.starts_with("HTTP/1.") => {
+            (method, target)
+        }
+        _ => return Err(anyhow!("invalid request line {:?}", line)),
+    };
+    let (connect, authority, port) = if method == "CONNECT" {
+        let authority: Authority = target.parse()?;
+        let port = authority
+            .port_u16()
+            .ok_or_else(|| anyhow!("CONNECT {} without port", target))?;
+        (true, authority, port)
+    } else {
+        let uri: Uri = target.parse()?;
+        if uri.scheme_str() != Some("http") {
+            return Err(anyhow!("{} is not an absolute http URI", target));
+        }
+        let authority = uri
+            .authority()
+            .cloned()
+            .ok_or_else(|| anyhow!("{} without host", target))?;
+        let port = authority.port_u16().unwrap_or(80);
+        (false, authority, port)
+    };
+    // the brackets of the IPv6 literals are not resolved
+    let host = authority
+        .host()
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    Ok((connect, host.to_string(), port))
+}
+
+/// reject answers the request with the status, the connection is closed then.
+async fn reject(stream: &mut TcpStream, status: StatusCode) {
+    let response = format!(
+        "HTTP/1.1 {}\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+        status
+    );
+    let _ = stream.write_all(response.as_bytes()).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use crate::proxy::http::explicit::{handshake, request_target, ExplicitProtocol};
+
+    #[test]
+    fn test_request_target() {
+        let target = |head: &str| request_target(head.as_bytes()).ok();
+        assert_eq!(
+            target("CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n"),
+            Some((true, "example.com".to_string(), 443))
+        );
+        assert_eq!(
+            target("CONNECT [::1]:8443 HTTP/1.1\r\n\r\n"),
+            Some((true, "::1".to_string(), 8443))
+        );
+        assert_eq!(
+            target("GET http://example.com/path?q=1 HTTP/1.1\r\n\r\n"),
+            Some((false, "example.com".to_string(), 80))
+        );
+        assert_eq!(
+            target("GET http://127.0.0.1:8080/ HTTP/1.1\r\n\r\n"),
+            Some((false, "127.0.0.1".to_string(), 8080))
+        );
+        assert_eq!(target("CONNECT example.com HTTP/1.1\r\n\r\n"), None);
+        assert_eq!(target("GET /path HTTP/1.1\r\n\r\n"), None);
+        assert_eq!(target("PRI * HTTP/2.0\r\n\r\n"), None);
+    }
+
+    #[tokio::test]
+    async fn test_handshake() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let (mut stream, _) = listener.accept().await.unwrap();
+        client
+            .write_all(b"CONNECT 127.0.0.1:8443 HTTP/1.1\r\nHost: 127.0.0.1:8443\r\n\r\nhello")
+            .await
+            .unwrap();
+        let target = handshake(&mut stream, ExplicitProtocol::Http)
+            .await
+            .unwrap();
+        assert_eq!(target, ("127.0.0.1:8443".parse().unwrap(), true));
+        // the tunnel starts right after the head
+        let mut tunneled = [0; 5];
+        stream.read_exact(&mut tunneled).await.unwrap();
+        assert_eq!(&tunneled, b"hello");
+        let mut response = [0; 39];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(&response, b"HTTP/1.1 200 Connection Established\r\n\r\n");
+
+        // the request of an absolute URI is left for the HTTP service
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let request = b"GET http://127.0.0.1:8080/ HTTP/1.1\r\nHost: 127.0.0.1:8080\r\n\r\n";
+        client.write_all(request).await.unwrap();
+        let target = handshake(&mut stream, ExplicitProtocol::Http)
+            .await
+            .unwrap();
+        assert_eq!(target, ("127.0.0.1:8080".parse().unwrap(), false));
+        let mut left = vec![0; request.len()];
+        stream.read_exact(&mut left).await.unwrap();
+        assert_eq!(left, request);
+    }
+
+    #[tokio::test]
+    async fn test_socks5_handshake() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let (mut stream, _) = listener.accept().await.unwrap();
+        client.write_all(&[5, 2, 2, 0]).await.unwrap();
+        client
+            .write_all(&[5, 1, 0, 1, 127, 0, 0, 1, 0x1f, 0x90])
+            .await
+            .unwrap();
+        client.write_all(b"hello").await.unwrap();
+        let target = handshake(&mut stream, ExplicitProtocol::Socks5)
+            .await
+            .unwrap();
+        assert_eq!(target, ("127.0.0.1:8080".parse().unwrap(), true));
+        let mut tunneled = [0; 5];
+        stream.read_exact(&mut tunneled).await.unwrap();
+        assert_eq!(&tunneled, b"hello");
+        let mut replies = [0; 12];
+        client.read_exact(&mut replies).await.unwrap();
+        assert_eq!(replies, [5, 0, 5, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+
+        // the hostnames are resolved by the proxy
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let (mut stream, _) = listener.accept().await.unwrap();
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        client.write_all(&[5, 1, 0, 3, 9]).await.unwrap();
+        client.write_all(b"localhost").await.unwrap();
+        client.write_all(&[0, 80]).await.unwrap();
+        let (target, _) = handshake(&mut stream, ExplicitProtocol::Socks5)
+            .await
+            .unwrap();
+        assert!(target.ip().is_loopback());
+        assert_eq!(target.port(), 80);
+
+        // UDP ASSOCIATE is not supported
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let (mut stream, _) = listener.accept().await.unwrap();
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        client
+            .write_all(&[5, 3, 0, 1, 127, 0, 0, 1, 0, 53])
+            .await
+            .unwrap();
+        assert!(handshake(&mut stream, ExplicitProtocol::Socks5)
+            .await
+            .is_err());
+        let mut replies = [0; 12];
+        client.read_exact(&mut replies).await.unwrap();
+        assert_eq!(replies[3], 7);
+    }
+}
@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Result};
+use chrono::{Datelike, Duration, Utc};
+use rcgen::{
+    date_time_ymd, Certificate, CertificateParams, DistinguishedName, DnType, KeyPair, SanType,
+};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::{any_supported_type, CertifiedKey};
+use rustls::PrivateKey;
+
+/// Common name of the leaf certificate for the clients without SNI.
+const DEFAULT_NAME: &str = "chaos-tproxy";
+
+/// MITMResolver generates a leaf certificate signed by the CA for each server name on the fly, so
+/// that the clients trusting the CA could be intercepted. The certificates are cached.
+pub struct MITMResolver {
+    ca: Certificate,
+    ca_der: rustls::Certificate,
+    cache: Mutex<HashMap<String, Arc<CertifiedKey>>>,
+}
+
+impl std::fmt::Debug for MITMResolver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MITMResolver").finish()
+    }
+}
+
+impl MITMResolver {
+    /// new loads the CA from PEM, the key must be PKCS#8.
+    pub fn new(ca_cert: &str, ca_key: &str) -> Result<Self> {
+        let key_pair = KeyPair::from_pem(ca_key)?;
+        let params = CertificateParams::from_ca_cert_pem(ca_cert, key_pair)?;
+        let ca = Certificate::from_params(params)?;
+        let ca_der = rustls_pemfile::certs(&mut ca_cert.as_bytes())?
+            .pop()
+            .ok_or_else(|| anyhow!("empty ca cert"))?;
+        Ok(Self {
+            ca,
+            ca_der: rustls::Certificate(ca_der),
+            cache: Default::default(),
+        })
+    }
+
+    fn certified_key(&self, name: &str) -> Result<Arc<CertifiedKey>> {
+        if let Some(key) = self.cache.lock().unwrap().get(name) {
+            return Ok(key.clone());
+        }
+        let key = Arc::new(self.generate(name)?);
+        self.cache
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), key.clone());
+        Ok(key)
+    }
+
+    fn generate(&self, name: &str) -> Result<CertifiedKey> {
+        let mut params = CertificateParams::default();
+        let mut distinguished_name = DistinguishedName::new();
+        distinguished_name.push(DnType::CommonName, name);
+        params.distinguished_name = distinguished_name;
+        params.subject_alt_names = vec![match name.parse() {
+            Ok(ip) => SanType::IpAddress(ip),
+            Err(_) => SanType::DnsName(name.to_string()),
+        }];
+        // short-lived certificates, the clients may reject the long-lived ones
+        let now = Utc::now();
+        let date =
+            |at: chrono::DateTime<Utc>| date_time_ymd(at.year(), at.month() as u8, at.day() as u8);
+        params.not_before = date(now - Duration::days(1));
+        params.not_after = date(now + Duration::days(30));
+
+        let leaf = Certificate::from_params(params)?;
+        let der = leaf.serialize_der_with_signer(&self.ca)?;
+        let key = any_supported_type(&PrivateKey(leaf.serialize_private_key_der()))
+            .map_err(|e| anyhow!("unsupported key: {}", e))?;
+        Ok(CertifiedKey::new(
+            vec![rustls::Certificate(der), self.ca_der.clone()],
+            key,
+        ))
+    }
+}
+
+impl ResolvesServerCert for MITMResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let name = client_hello.server_name().unwrap_or(DEFAULT_NAME);
+        match self.certified_key(name) {
+            Ok(key) => Some(key),
+            Err(e) => {
+                tracing::error!("fail to generate certificate for {}: {}", name, e);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+    use std::sync::Arc;
+
+    use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa};
+    use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+    use crate::proxy::http::mitm::MITMResolver;
+
+    #[tokio::test]
+    async fn test_generate() {
+        let mut params = CertificateParams::new(vec![]);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = Certificate::from_params(params).unwrap();
+        let resolver = MITMResolver::new(
+            &ca.serialize_pem().unwrap(),
+            &ca.serialize_private_key_pem(),
+        )
+        .unwrap();
+
+        let key = resolver.certified_key("example.com").unwrap();
+        assert_eq!(key.cert.len(), 2);
+        let cached = resolver.certified_key("example.com").unwrap();
+        assert!(std::sync::Arc::ptr_eq(&key, &cached));
+        assert!(resolver.certified_key("10.0.0.1").is_ok());
+
+        // the clients trusting the CA accept the generated certificate
+        let mut roots = rustls::RootCertStore::empty();
+        roots
+            .add(&rustls::Certificate(ca.serialize_der().unwrap()))
+            .unwrap();
+        let connector = TlsConnector::from(Arc::new(
+            rustls::ClientConfig::builder()
+                .with_safe_defaults()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        ));
+        let acceptor = TlsAcceptor::from(Arc::new(
+            rustls::ServerConfig::builder()
+                .with_safe_defaults()
+                .with_no_client_auth()
+                .with_cert_resolver(Arc::new(resolver)),
+        ));
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let server = tokio::spawn(async move { acceptor.accept(server).await.map(|_| ()) });
+        let _client = connector
+            .connect("api.example.com".try_into().unwrap(), client)
+            .await
+            .unwrap();
+        server.await.unwrap().unwrap();
+    }
+}
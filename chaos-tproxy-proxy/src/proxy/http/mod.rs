@@ -1,3 +1,7 @@
 pub mod config;
 pub mod connector;
+pub mod explicit;
+pub mod mitm;
+pub mod pool;
+pub mod resolver;
 pub mod server;
@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use hyper::client::connect::Connect;
+use hyper::{Body, Client};
+use hyper_rustls::HttpsConnector;
+
+/// PoolConfig introduces how the upstream connections are kept alive and reused by the next
+/// requests, instead of opening a connection per request.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct PoolConfig {
+    /// idle_timeout closes the connections idle for longer.
+    pub idle_timeout: Duration,
+    /// max_idle_per_host caps the idle connections kept per upstream, 0 disables the reuse. The
+    /// connections in use are not capped, the ones over the cap are closed once idle.
+    pub max_idle_per_host: usize,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            idle_timeout: Duration::from_secs(90),
+            max_idle_per_host: usize::MAX,
+        }
+    }
+}
+
+impl PoolConfig {
+    /// builder returns the builder of the clients keeping the connections, HTTP/2 without TLS
+    /// (h2c) requires prior knowledge.
+    pub fn builder(&self, http2_only: bool) -> hyper::client::Builder {
+        let mut builder = Client::builder();
+        builder
+            .http2_only(http2_only)
+            .pool_idle_timeout(self.idle_timeout)
+            .pool_max_idle_per_host(self.max_idle_per_host);
+        builder
+    }
+}
+
+/// Clients keeps the clients of hyper forwarding the requests, so that their connections are
+/// reused by the next requests. They are built on the first request of each protocol.
+#[derive(Debug)]
+pub struct Clients<C> {
+    config: PoolConfig,
+    /// http are the clients of plain HTTP, by whether they speak HTTP/2 with prior knowledge.
+    http: Mutex<HashMap<bool, Client<C, Body>>>,
+    https: Mutex<Option<Client<HttpsConnector<C>, Body>>>,
+}
+
+impl<C> Clients<C> {
+    pub fn new(config: PoolConfig) -> Self {
+        Self {
+            config,
+            http: Default::default(),
+            https: Default::default(),
+        }
+    }
+
+    /// http returns the client of plain HTTP, the connector is only called to build it.
+    pub fn http(&self, http2_only: bool, connector: impl FnOnce() -> C) -> Client<C, Body>
+    where
+        C: Connect + Clone + Send + Sync + 'static,
+    {
+        self.http
+            .lock()
+            .unwrap()
+            .entry(http2_only)
+            .or_insert_with(|| self.config.builder(http2_only).build(connector()))
+            .clone()
+    }
+
+    /// https returns the client of HTTPS, the protocol is negotiated by ALPN.
+    pub fn https(
+        &self,
+        connector: impl FnOnce() -> HttpsConnector<C>,
+    ) -> Client<HttpsConnector<C>, Body>
+    where
+        HttpsConnector<C>: Connect + Clone + Send + Sync + 'static,
+    {
+        self.https
+            .lock()
+            .unwrap()
+            .get_or_insert_with(|| self.config.builder(false).build(connector()))
+            .clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use hyper::client::HttpConnector;
+    use hyper::server::conn::AddrStream;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server};
+
+    use crate::proxy::http::pool::{Clients, PoolConfig};
+
+    #[tokio::test]
+    async fn test_clients() {
+        let connections = Arc::new(AtomicUsize::new(0));
+        let counter = connections.clone();
+        let make_service = make_service_fn(move |_: &AddrStream| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            async {
+                Ok::<_, Infallible>(service_fn(|_| async {
+                    Ok::<_, Infallible>(Response::new(Body::from("hello")))
+                }))
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let uri = format!("http://{}/", server.local_addr());
+        tokio::spawn(server);
+
+        let send = |clients: &Clients<HttpConnector>| {
+            let client = clients.http(false, HttpConnector::new);
+            let request = Request::get(&uri).body(Body::empty()).unwrap();
+            async move {
+                let response = client.request(request).await.unwrap();
+                hyper::body::to_bytes(response.into_body()).await.unwrap();
+            }
+        };
+
+        let clients = Clients::new(PoolConfig::default());
+        for _ in 0..3 {
+            send(&clients).await;
+        }
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+
+        // nothing is kept without idle connections
+        let clients = Clients::new(PoolConfig {
+            max_idle_per_host: 0,
+            ..Default::default()
+        });
+        for _ in 0..2 {
+            send(&clients).await;
+        }
+        assert_eq!(connections.load(Ordering::SeqCst), 3);
+    }
+}
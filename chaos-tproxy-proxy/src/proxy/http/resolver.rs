@@ -0,0 +1,297 @@
+use std::collections::HashMap;
+use std::error::Error as StdError;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use http::header::ACCEPT;
+use http::{Request, Uri};
+use hyper::client::connect::dns::Name;
+use hyper::client::HttpConnector;
+use hyper::service::Service;
+use hyper::{Body, Client};
+use hyper_rustls::HttpsConnector;
+use rustls::OwnedTrustAnchor;
+use serde::Deserialize;
+
+/// The answers are cached at least for a second, even if the TTL is zero.
+const MIN_TTL: Duration = Duration::from_secs(1);
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+
+/// DoHServer is a DNS-over-HTTPS server speaking the JSON API, e.g.
+/// `https://cloudflare-dns.com/dns-query`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DoHServer {
+    pub url: Uri,
+    /// bootstrap address of the server, the host of the URL is resolved by the system otherwise.
+    pub address: Option<IpAddr>,
+}
+
+/// Resolver resolves the hostnames of the upstreams, by the DoH servers if configured, or by the
+/// system.
+#[derive(Clone, Default)]
+pub struct Resolver {
+    hosts: Arc<HashMap<String, IpAddr>>,
+    doh: Option<Arc<DoH>>,
+}
+
+struct DoH {
+    /// the servers are tried in order until one answers.
+    servers: Vec<Uri>,
+    client: Client<HttpsConnector<HttpConnector<Resolver>>>,
+    cache: Mutex<HashMap<String, Entry>>,
+}
+
+struct Entry {
+    addrs: Vec<IpAddr>,
+    expires_at: Instant,
+}
+
+#[derive(Debug, Deserialize)]
+struct DnsResponse {
+    #[serde(rename = "Status")]
+    status: u32,
+    #[serde(rename = "Answer", default)]
+    answer: Vec<DnsAnswer>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DnsAnswer {
+    #[serde(rename = "type")]
+    kind: u16,
+    #[serde(rename = "TTL")]
+    ttl: u64,
+    data: String,
+}
+
+impl std::fmt::Debug for Resolver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Resolver")
+            .field("hosts", &self.hosts)
+            .field("doh", &self.doh.as_ref().map(|doh| &doh.servers))
+            .finish()
+    }
+}
+
+impl Resolver {
+    /// doh returns the resolver querying the servers, the servers themselves are connected by
+    /// their bootstrap addresses.
+    pub fn doh(servers: Vec<DoHServer>) -> Self {
+        let hosts = servers
+            .iter()
+            .filter_map(|server| Some((server.url.host()?.to_string(), server.address?)))
+            .collect();
+        let bootstrap = Resolver {
+            hosts: Arc::new(hosts),
+            doh: None,
+        };
+        let mut http = HttpConnector::new_with_resolver(bootstrap);
+        http.enforce_http(false);
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(
+                ta.subject,
+                ta.spki,
+                ta.name_constraints,
+            )
+        }));
+        let https = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_tls_config(
+                rustls::ClientConfig::builder()
+                    .with_safe_defaults()
+                    .with_root_certificates(roots)
+                    .with_no_client_auth(),
+            )
+            .https_or_http()
+            .enable_http1()
+            .enable_http2()
+            .wrap_connector(http);
+        Self {
+            hosts: Default::default(),
+            doh: Some(Arc::new(DoH {
+                servers: servers.into_iter().map(|server| server.url).collect(),
+                client: Client::builder().build(https),
+                cache: Default::default(),
+            })),
+        }
+    }
+
+    /// resolve looks up the addresses of the host, the stale answers are used if all the DoH
+    /// servers fail.
+    pub async fn resolve(&self, host: &str) -> Result<Vec<IpAddr>> {
+        if let Some(addr) = self.hosts.get(host) {
+            return Ok(vec![*addr]);
+        }
+        let doh = match &self.doh {
+            None => {
+                return Ok(tokio::net::lookup_host((host, 0))
+                    .await?
+                    .map(|addr| addr.ip())
+                    .collect())
+            }
+            Some(doh) => doh,
+        };
+        if let Some(entry) = doh.cache.lock().unwrap().get(host) {
+            if entry.expires_at > Instant::now() {
+                return Ok(entry.addrs.clone());
+            }
+        }
+
+        let mut last_error = anyhow!("no DoH server");
+        for server in doh.servers.iter() {
+            match doh.query(server, host).await {
+                Ok((addrs, ttl)) => {
+                    doh.cache.lock().unwrap().insert(
+                        host.to_string(),
+                        Entry {
+                            addrs: addrs.clone(),
+                            expires_at: Instant::now() + ttl.max(MIN_TTL),
+                        },
+                    );
+                    return Ok(addrs);
+                }
+                Err(e) => {
+                    tracing::warn!("fail to resolve {} by {}: {}", host, server, e);
+                    last_error = e;
+                }
+            }
+        }
+        match doh.cache.lock().unwrap().get(host) {
+            Some(entry) => Ok(entry.addrs.clone()),
+            None => Err(last_error),
+        }
+    }
+}
+
+impl DoH {
+    /// query returns the A and AAAA records of the host, and the minimum TTL of them.
+    async fn query(&self, server: &Uri, host: &str) -> Result<(Vec<IpAddr>, Duration)> {
+        let (a, aaaa) = futures::try_join!(
+            self.query_type(server, host, TYPE_A),
+            self.query_type(server, host, TYPE_AAAA)
+        )?;
+        let answers: Vec<_> = a.into_iter().chain(aaaa).collect();
+        let ttl = answers.iter().map(|answer| answer.ttl).min().unwrap_or(0);
+        let addrs = answers
+            .iter()
+            .map(|answer| answer.data.parse())
+            .collect::<Result<Vec<IpAddr>, _>>()?;
+        if addrs.is_empty() {
+            return Err(anyhow!("no address of {}", host));
+        }
+        Ok((addrs, Duration::from_secs(ttl)))
+    }
+
+    async fn query_type(&self, server: &Uri, host: &str, kind: u16) -> Result<Vec<DnsAnswer>> {
+        let query = serde_urlencoded::to_string([("name", host), ("type", &kind.to_string())])?;
+        let request = Request::get(format!("{}?{}", server, query))
+            .header(ACCEPT, "application/dns-json")
+            .body(Body::empty())?;
+        let response = self.client.request(request).await?;
+        if !response.status().is_success() {
+            return Err(anyhow!("unexpected status {}", response.status()));
+        }
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        let response: DnsResponse = serde_json::from_slice(&body)?;
+        // NXDOMAIN is an answer, the other failures of the server are not
+        if response.status != 0 && response.status != 3 {
+            return Err(anyhow!("unexpected DNS status {}", response.status));
+        }
+        // CNAMEs are followed by the server
+        Ok(response
+            .answer
+            .into_iter()
+            .filter(|answer| answer.kind == kind)
+            .collect())
+    }
+}
+
+impl Service<Name> for Resolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = Box<dyn StdError + Send + Sync>;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let resolver = self.clone();
+        Box::pin(async move {
+            // the port is set by the connector
+            Ok(resolver
+                .resolve(name.as_str())
+                .await?
+                .into_iter()
+                .map(|ip| SocketAddr::new(ip, 0))
+                .collect::<Vec<_>>()
+                .into_iter())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server};
+
+    use crate::proxy::http::resolver::{DoHServer, Resolver};
+
+    #[tokio::test]
+    async fn test_doh() {
+        let queries = Arc::new(AtomicUsize::new(0));
+        let counter = queries.clone();
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service_fn(
+            move |_| {
+                let counter = counter.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                        counter.fetch_add(1, Ordering::SeqCst);
+                        let query = request.uri().query().unwrap_or_default().to_string();
+                        async move {
+                            let body = if query.contains("type=1") {
+                                r#"{"Status":0,"Answer":[{"name":"api.test","type":5,"TTL":60,"data":"lb.test"},{"name":"lb.test","type":1,"TTL":60,"data":"10.0.0.1"}]}"#
+                            } else {
+                                r#"{"Status":0}"#
+                            };
+                            Ok::<_, Infallible>(Response::new(Body::from(body)))
+                        }
+                    }))
+                }
+            },
+        ));
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        let resolver = Resolver::doh(vec![
+            // nothing listens on the port, the next server is tried
+            DoHServer {
+                url: "http://dead.test:1/dns-query".parse().unwrap(),
+                address: Some("127.0.0.1".parse().unwrap()),
+            },
+            DoHServer {
+                url: format!("http://doh.test:{}/dns-query", addr.port())
+                    .parse()
+                    .unwrap(),
+                address: Some("127.0.0.1".parse().unwrap()),
+            },
+        ]);
+        let addrs = resolver.resolve("api.test").await.unwrap();
+        assert_eq!(addrs, vec!["10.0.0.1".parse::<std::net::IpAddr>().unwrap()]);
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+
+        // cached
+        resolver.resolve("api.test").await.unwrap();
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+    }
+}
@@ -1,117 +1,663 @@
+use std::collections::BTreeMap;
 use std::convert::TryInto;
 use std::future::Future;
-use std::matches;
 use std::net::SocketAddr;
 use std::pin::Pin;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll};
+use std::time::{Instant, SystemTime};
+use std::{io, matches, mem};
 
 use anyhow::{anyhow, Result};
+use bytes::Bytes;
 use derivative::Derivative;
+use futures::future;
 use http::header::HOST;
 use http::uri::{PathAndQuery, Scheme, Uri};
-use http::StatusCode;
+use http::{Method, StatusCode, Version};
 use hyper::server::conn::Http;
 use hyper::service::Service;
-use hyper::{client, Body, Client, Request, Response};
+use hyper::{client, Body, Request, Response};
 use rustls::ClientConfig;
 use tokio::io::AsyncWriteExt;
 use tokio::net::TcpStream;
 use tokio::select;
-use tokio::sync::oneshot::Receiver;
+use tokio::sync::oneshot::{self, Receiver};
+use tokio::sync::{watch, OwnedSemaphorePermit};
+use tokio::task::JoinHandle;
 use tokio_rustls::TlsAcceptor;
 use tracing::{debug, error, span, trace, Level};
 
-use crate::handler::http::action::{apply_request_action, apply_response_action};
-use crate::handler::http::rule::Target;
-use crate::handler::http::selector::{select_request, select_response, select_role};
-use crate::proxy::http::config::{Config, HTTPConfig};
+use crate::access_log::{AccessLog, Attribution};
+use crate::coordination::Coordinator;
+use crate::handler::http::action::{Abort, AbortMode, DuplicateAction, Upstream};
+use crate::handler::http::body_limit::{limit_body, Limited};
+use crate::handler::http::client_ip::ClientAddr;
+use crate::handler::http::compare::diff_response;
+use crate::handler::http::compensation::Compensation;
+use crate::handler::http::connect::ConnectFault;
+use crate::handler::http::exchange::ExchangeId;
+use crate::handler::http::expect::strip_expect;
+use crate::handler::http::fingerprint::{peek_ja3, ClientFingerprint};
+use crate::handler::http::inject::{
+    copy_request, mirror, mirrors, Forwarding, Injected, Injection,
+};
+use crate::handler::http::rule::{Direction, MatchPolicy, Rule, Target};
+use crate::handler::http::selector::{
+    select_connection, select_request, select_response, select_role,
+};
+use crate::handler::http::tls_client::TlsClient;
+use crate::handler::http::validation::{OnViolation, Quarantined, ResponseValidator};
+use crate::handler::http::websocket::{is_upgrade, Tunnel};
+use crate::handler::tcp::{self, TcpAction};
+use crate::har::HarRecorder;
+use crate::metadata::{ClientLabels, MetadataResolver};
+use crate::metrics::Metrics;
+use crate::pcap::{Captured, Pcap};
+use crate::proxy::dns::{serve_stream, DnsConfig, DnsServer, DNS_PORT};
+use crate::proxy::http::config::{Config, HTTPConfig, ListenerConfig, RuntimeConfig, TLSConfig};
 use crate::proxy::http::connector::HttpConnector;
+use crate::proxy::http::explicit::{handshake, ExplicitProtocol};
+use crate::proxy::http::pool::Clients;
+use crate::proxy::http::resolver::Resolver;
+use crate::proxy::tcp::limit::{CapacityLimiter, ConnectionLimiter};
 use crate::proxy::tcp::listener::TcpListener;
+use crate::proxy::tcp::proxy_protocol::{read_header, write_header};
+#[cfg(target_os = "linux")]
+use crate::proxy::tcp::sk_lookup::SkLookup;
+#[cfg(not(target_os = "linux"))]
+use crate::proxy::tcp::sockopt::unsupported;
+use crate::proxy::tcp::sockopt::{
+    set_linger_zero, tcp_socket, AsRawSocket, RawSocket, SocketOptions,
+};
 use crate::proxy::tcp::transparent_socket::TransparentSocket;
+use crate::telemetry::{self, Telemetry};
+use crate::timeline::EventKind;
 
 /// HttpServer is the proxy service behind the iptables tproxy. It would accept the forwarded
 /// connection from the iptables tproxy, and then let [HttpService] to handle the connection.
 pub struct HttpServer {
     config: Config,
+    metrics: Arc<Metrics>,
+    coordinator: Option<Arc<Coordinator>>,
+    limiter: Arc<ConnectionLimiter>,
+    connections: Option<Arc<CapacityLimiter>>,
+    requests: Option<Arc<CapacityLimiter>>,
+    http_config: watch::Receiver<Arc<HTTPConfig>>,
+    /// listener_configs are the configs of the listeners with their own rules, by listen port.
+    listener_configs: BTreeMap<u16, watch::Receiver<Arc<HTTPConfig>>>,
+    reloader: Reloader,
+    har: Option<Arc<HarRecorder>>,
 }
 
 impl HttpServer {
     pub fn new(config: Config) -> Self {
-        Self { config }
+        let metrics = Arc::new(Metrics::new(config.slo.clone(), config.baseline.clone()));
+        metrics.timeline().record(EventKind::Started {
+            rules: config.http_config.rules.len(),
+        });
+        metrics.rules_loaded(&config.http_config);
+        let coordinator = config.coordination.clone().map(|coordination| {
+            Arc::new(Coordinator::new(coordination, metrics.timeline().clone()))
+        });
+        let limiter = Arc::new(ConnectionLimiter::new(config.connection_limits.clone()));
+        let connections = config
+            .connection_capacity
+            .map(|capacity| Arc::new(CapacityLimiter::new(capacity)));
+        let requests = config
+            .request_capacity
+            .map(|capacity| Arc::new(CapacityLimiter::new(capacity)));
+        let har = config
+            .har
+            .clone()
+            .map(|har| Arc::new(HarRecorder::new(har)));
+        let (sender, http_config) = watch::channel(Arc::new(config.http_config.clone()));
+        let mut listener_senders = BTreeMap::new();
+        let mut listener_configs = BTreeMap::new();
+        for port in config.http_config.listener_rules.keys() {
+            let (sender, receiver) =
+                watch::channel(Arc::new(config.http_config.for_listener(*port)));
+            listener_senders.insert(*port, sender);
+            listener_configs.insert(*port, receiver);
+        }
+        let reloader = Reloader {
+            sender: Arc::new(sender),
+            listener_senders: Arc::new(listener_senders),
+            metrics: metrics.clone(),
+        };
+        Self {
+            config,
+            metrics,
+            coordinator,
+            limiter,
+            connections,
+            requests,
+            http_config,
+            listener_configs,
+            reloader,
+            har,
+        }
     }
 
-    pub async fn serve(&mut self, mut rx: Receiver<()>) -> Result<()> {
-        let addr = SocketAddr::from(([0, 0, 0, 0], self.config.http_config.listen_port));
-        let listener = TcpListener::bind(addr)?;
-        tracing::info!("Proxy Listening");
-        let http_config = Arc::new(self.config.http_config.clone());
-        let rx_mut = &mut rx;
+    /// metrics returns the recorder shared by all the connections of this server.
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
 
-        loop {
-            let stream = select! {
-                stream = listener.accept() => {
-                    stream
-                },
-                _ = &mut *rx_mut => {
-                    return Ok(());
+    /// har returns the recorder of the exchanges captured, if enabled.
+    pub fn har(&self) -> Option<Arc<HarRecorder>> {
+        self.har.clone()
+    }
+
+    /// reloader returns the handle to swap the HTTP config while the server is serving.
+    pub fn reloader(&self) -> Reloader {
+        self.reloader.clone()
+    }
+
+    pub async fn serve(&mut self, rx: Receiver<()>) -> Result<()> {
+        let coordination = self.coordinator.clone().map(|coordinator| {
+            tokio::spawn(async move {
+                if let Err(e) = coordinator.run().await {
+                    error!("coordination stopped: {}", e);
+                }
+            })
+        });
+        let dns = self.config.dns.clone().map(Arc::new);
+        let dns_server = dns.clone().map(|dns| {
+            let server = DnsServer::new(dns, self.config.http_config.listen_port);
+            tokio::spawn(async move {
+                if let Err(e) = server.serve().await {
+                    error!("DNS proxy stopped: {}", e);
                 }
-            }?;
-            let addr_remote = stream.peer_addr()?;
-            let addr_local = stream.local_addr()?;
-            debug!(target : "Accept streaming", "remote={:?}, local={:?}",addr_remote, addr_local);
-            if let Some(tls_config) = &self.config.tls_config {
-                let tls_client_config = Arc::new(tls_config.tls_client_config.clone());
-                let tls_server_config = Arc::new(tls_config.tls_server_config.clone());
-                let service = HttpService::new(
-                    addr_remote,
-                    addr_local,
-                    http_config.clone(),
-                    Some(tls_client_config.clone()),
+            })
+        });
+
+        let access_log = self
+            .config
+            .access_log
+            .as_ref()
+            .map(AccessLog::open)
+            .transpose()?
+            .map(Arc::new);
+        let telemetry = self
+            .config
+            .telemetry
+            .as_ref()
+            .map(Telemetry::new)
+            .transpose()?
+            .map(Arc::new);
+        let pcap = self
+            .config
+            .pcap
+            .as_ref()
+            .map(Pcap::create)
+            .transpose()?
+            .map(Arc::new);
+
+        let (shutdown, watcher) = watch::channel(());
+        let mut listeners = vec![];
+        match self.config.explicit {
+            // the clients connect to the proxy directly, nothing is diverted to the listen ports
+            Some(explicit) => {
+                let mut acceptor = self.acceptor(
+                    self.config.tls_config.as_ref(),
+                    None,
+                    access_log.clone(),
+                    telemetry.clone(),
+                    pcap.clone(),
                 );
-                let acceptor = TlsAcceptor::from(tls_server_config.clone());
-                tokio::spawn(async move {
-                    match serve_https(stream, &service, acceptor).await {
-                        Ok(_) => {}
-                        Err(e) => {
-                            error!("{}", e);
-                        }
-                    };
-                });
-            } else {
-                let service = HttpService::new(addr_remote, addr_local, http_config.clone(), None);
-                tokio::spawn(async move {
-                    match serve_http_with_error_return(stream, &service).await {
-                        Ok(_) => {}
-                        Err(e) => {
-                            error!("{}", e);
-                        }
-                    };
+                acceptor.explicit = Some(explicit.protocol);
+                let socket = TcpListener::bind_plain(explicit.listen, &self.config.runtime.socket)?;
+                tokio::spawn(acceptor.run(socket, watcher.clone()));
+                tracing::info!(
+                    "Proxy serving as an explicit {:?} proxy on {}",
+                    explicit.protocol,
+                    explicit.listen
+                );
+            }
+            None => {
+                listeners.push(ListenerConfig {
+                    listen_port: self.config.http_config.listen_port,
+                    tls_config: self.config.tls_config.clone(),
+                    workers: None,
                 });
+                listeners.extend(self.config.listeners.iter().cloned());
             }
         }
+        let tuning = self.config.runtime;
+        let mut sockets = vec![];
+        for listener in listeners {
+            let port = listener.listen_port;
+            let mut acceptor = self.acceptor(
+                listener.tls_config.as_ref(),
+                dns.clone(),
+                access_log.clone(),
+                telemetry.clone(),
+                pcap.clone(),
+            );
+            if let Some(http_config) = self.listener_configs.get(&port) {
+                acceptor.http_config = http_config.clone();
+            }
+            let watcher = watcher.clone();
+            match listener.workers {
+                None => {
+                    let shards = bind_shards(port, &tuning)?;
+                    sockets.push(shards[0].raw_fds());
+                    for socket in shards {
+                        tokio::spawn(acceptor.clone().run(socket, watcher.clone()));
+                    }
+                }
+                // the socket is registered to the reactor of the dedicated runtime
+                Some(workers) => {
+                    let (bound, bind_result) = oneshot::channel();
+                    let runtime = tokio::runtime::Builder::new_multi_thread()
+                        .worker_threads(workers)
+                        .thread_name(format!("listener-{}", port))
+                        .enable_all()
+                        .build()?;
+                    std::thread::spawn(move || {
+                        runtime.block_on(async move {
+                            match bind_shards(port, &tuning) {
+                                Ok(shards) => {
+                                    let _ = bound.send(Ok(shards[0].raw_fds()));
+                                    future::join_all(shards.into_iter().map(|socket| {
+                                        acceptor.clone().run(socket, watcher.clone())
+                                    }))
+                                    .await;
+                                }
+                                Err(e) => {
+                                    let _ = bound.send(Err(e));
+                                }
+                            }
+                        })
+                    });
+                    sockets.push(bind_result.await??);
+                }
+            }
+        }
+        #[cfg(target_os = "linux")]
+        let sk_lookup = self
+            .config
+            .sk_lookup
+            .as_ref()
+            .map(|config| SkLookup::attach(config, &sockets))
+            .transpose()?;
+        #[cfg(not(target_os = "linux"))]
+        if self.config.sk_lookup.is_some() {
+            return Err(unsupported("the eBPF redirect backend").into());
+        }
+        tracing::info!("Proxy Listening");
+
+        let _ = rx.await;
+        // the listeners stop accepting, and the exchanges in flight are let finish
+        let _ = shutdown.send(());
+        #[cfg(target_os = "linux")]
+        drop(sk_lookup);
+        let drain = self.metrics.drain();
+        tracing::info!("Proxy draining {} exchanges in flight", drain.in_flight());
+        if !drain.wait(self.config.drain_timeout).await {
+            tracing::warn!(
+                "Proxy dropping {} exchanges in flight after the drain timeout",
+                drain.in_flight()
+            );
+        }
+        if let Some(coordination) = coordination {
+            coordination.abort();
+        }
+        if let Some(dns_server) = dns_server {
+            dns_server.abort();
+        }
+        if telemetry.is_some() {
+            // the spans left are flushed in blocking
+            tokio::task::spawn_blocking(Telemetry::shutdown).await?;
+        }
+        Ok(())
+    }
+
+    /// acceptor returns the acceptor of a listener with the TLS config.
+    fn acceptor(
+        &self,
+        tls_config: Option<&TLSConfig>,
+        dns: Option<Arc<DnsConfig>>,
+        access_log: Option<Arc<AccessLog>>,
+        telemetry: Option<Arc<Telemetry>>,
+        pcap: Option<Arc<Pcap>>,
+    ) -> Acceptor {
+        let inspected = access_log.is_some()
+            || self.har.is_some()
+            || telemetry.is_some()
+            || self.config.slo.is_some()
+            || self.config.baseline.is_some()
+            || self.config.http_config.exchange_id_header.is_some();
+        Acceptor {
+            http_config: self.http_config.clone(),
+            tls: tls_config.map(|tls_config| {
+                (
+                    Arc::new(tls_config.tls_client_config.clone()),
+                    TlsAcceptor::from(Arc::new(tls_config.tls_server_config.clone())),
+                )
+            }),
+            metrics: self.metrics.clone(),
+            metadata: self.config.metadata.clone(),
+            coordinator: self.coordinator.clone(),
+            limiter: self.limiter.clone(),
+            connections: self.connections.clone(),
+            requests: self.requests.clone(),
+            dns,
+            access_log,
+            har: self.har.clone(),
+            telemetry,
+            inspected,
+            pcap,
+            explicit: None,
+            socket_options: self.config.runtime.socket,
+            resolved_clients: Default::default(),
+        }
     }
 }
 
+/// bind_shards binds the listeners of the port, each accepted by its own loop. The shards are in
+/// the SO_REUSEPORT group of the first one, so the connections steered to it are spread among
+/// them by the kernel.
+fn bind_shards(port: u16, runtime: &RuntimeConfig) -> io::Result<Vec<TcpListener>> {
+    let reuse_port = runtime.accept_shards > 1;
+    (0..runtime.accept_shards)
+        .map(|_| TcpListener::bind_dual_stack(port, &runtime.socket, reuse_port))
+        .collect()
+}
+
+/// Reloader swaps the HTTP config, e.g. the rules, of a running [HttpServer]. The connections
+/// accepted before keep the config they are accepted with, so none of them is dropped.
+#[derive(Clone)]
+pub struct Reloader {
+    sender: Arc<watch::Sender<Arc<HTTPConfig>>>,
+    listener_senders: Arc<BTreeMap<u16, watch::Sender<Arc<HTTPConfig>>>>,
+    metrics: Arc<Metrics>,
+}
+
+impl Reloader {
+    /// reload applies the config to the connections accepted from now on. The listen port is
+    /// bound once, so the one of the config is ignored, and so are the rule sets of the listeners
+    /// without their own rules at the start.
+    pub fn reload(&self, config: HTTPConfig) {
+        self.metrics.timeline().record(EventKind::Started {
+            rules: config.rules.len(),
+        });
+        self.metrics.rules_loaded(&config);
+        for (port, sender) in self.listener_senders.iter() {
+            let _ = sender.send(Arc::new(config.for_listener(*port)));
+        }
+        let _ = self.sender.send(Arc::new(config));
+    }
+}
+
+/// Acceptor serves the connections accepted by a listener, the connections are spawned on the
+/// runtime of the listener.
+#[derive(Clone)]
+struct Acceptor {
+    http_config: watch::Receiver<Arc<HTTPConfig>>,
+    tls: Option<(Arc<ClientConfig>, TlsAcceptor)>,
+    metrics: Arc<Metrics>,
+    metadata: Option<Arc<dyn MetadataResolver>>,
+    coordinator: Option<Arc<Coordinator>>,
+    limiter: Arc<ConnectionLimiter>,
+    /// connections caps the client connections of all the listeners.
+    connections: Option<Arc<CapacityLimiter>>,
+    /// requests caps the requests in flight of all the listeners.
+    requests: Option<Arc<CapacityLimiter>>,
+    dns: Option<Arc<DnsConfig>>,
+    access_log: Option<Arc<AccessLog>>,
+    har: Option<Arc<HarRecorder>>,
+    telemetry: Option<Arc<Telemetry>>,
+    pcap: Option<Arc<Pcap>>,
+    /// explicit is the protocol the clients connecting to the proxy directly tell the targets by.
+    explicit: Option<ExplicitProtocol>,
+    /// inspected is set if the exchanges are recorded, e.g. by the access log, so that every
+    /// connection is parsed as HTTP.
+    inspected: bool,
+    /// socket_options are set on the upstream connections.
+    socket_options: SocketOptions,
+    /// resolved_clients are shared by the connections of the listener, see [SharedClients].
+    resolved_clients: Arc<SharedClients>,
+}
+
+/// ResolvedClients forward the requests rerouted or of absolute URIs, by the resolver.
+type ResolvedClients = Clients<client::HttpConnector<Resolver>>;
+
+/// SharedClients keeps the [ResolvedClients] of the connections accepted with the same config, so
+/// that their upstream connections are reused across the client connections. They are not bound
+/// to the client like the connections to the original destination, and are replaced along with
+/// the config once it is reloaded.
+#[derive(Default)]
+struct SharedClients(Mutex<Option<(Arc<HTTPConfig>, Arc<ResolvedClients>)>>);
+
+impl SharedClients {
+    /// get returns the clients of the config, built on its first connection.
+    fn get(&self, config: &Arc<HTTPConfig>) -> Arc<ResolvedClients> {
+        let mut shared = self.0.lock().unwrap();
+        match &*shared {
+            Some((current, clients)) if Arc::ptr_eq(current, config) => clients.clone(),
+            _ => {
+                let clients = Arc::new(Clients::new(config.upstream_pool));
+                *shared = Some((config.clone(), clients.clone()));
+                clients
+            }
+        }
+    }
+}
+
+impl Acceptor {
+    /// run accepts the connections until the server is shut down.
+    async fn run(self, listener: TcpListener, mut shutdown: watch::Receiver<()>) {
+        loop {
+            let stream = select! {
+                stream = listener.accept() => stream,
+                _ = shutdown.changed() => return,
+            };
+            let result = match stream {
+                Ok(stream) => self.accept(stream),
+                Err(e) => Err(e.into()),
+            };
+            if let Err(e) = result {
+                error!("fail to accept connection: {}", e);
+            }
+        }
+    }
+
+    fn accept(&self, stream: TcpStream) -> Result<()> {
+        let addr_remote = stream.peer_addr()?;
+        let addr_local = stream.local_addr()?;
+        debug!(target : "Accept streaming", "remote={:?}, local={:?}",addr_remote, addr_local);
+        let acceptor = self.clone();
+        // the connection keeps the config it is accepted with, even if the config is reloaded
+        let http_config = self.http_config.borrow().clone();
+        tokio::spawn(async move {
+            if let Err(e) = acceptor
+                .serve(stream, http_config, addr_remote, addr_local)
+                .await
+            {
+                error!("{}", e);
+            }
+        });
+        Ok(())
+    }
+
+    /// serve handles an accepted connection as DNS, raw TCP or HTTP.
+    async fn serve(
+        self,
+        mut stream: TcpStream,
+        http_config: Arc<HTTPConfig>,
+        addr_remote: SocketAddr,
+        addr_local: SocketAddr,
+    ) -> Result<()> {
+        let fd = stream.raw_socket();
+        // the connection holds its slot until it is closed, nothing is read before it is admitted
+        let _connection = match &self.connections {
+            Some(connections) => match admit_client(connections, addr_remote, fd).await {
+                Some(permit) => Some(permit),
+                None => return Ok(()),
+            },
+            None => None,
+        };
+        // the target of the explicit proxy is told by the client, only the tunnels are TLS
+        let (addr_local, tls, by_uri) = match self.explicit {
+            Some(protocol) => {
+                let (target, tunneled) = handshake(&mut stream, protocol)
+                    .await
+                    .map_err(|e| anyhow!("{} : explicit proxy: {}", addr_remote, e))?;
+                (target, self.tls.as_ref().filter(|_| tunneled), !tunneled)
+            }
+            None => (addr_local, self.tls.as_ref(), false),
+        };
+        if let Some(dns) = self.dns.clone().filter(|_| addr_local.port() == DNS_PORT) {
+            return serve_stream(&dns, stream, addr_remote, addr_local).await;
+        }
+        let mut stream = match &self.pcap {
+            Some(pcap) => pcap.client(stream, addr_remote, addr_local),
+            None => Captured::new(stream),
+        };
+        // the client behind the load balancer is told by the PROXY header, before TLS
+        let client = if http_config.proxy_protocol.accept {
+            read_header(&mut stream)
+                .await
+                .map_err(|e| anyhow!("{} : fail to read PROXY header: {}", addr_remote, e))?
+                .map(|(source, _)| source)
+                .unwrap_or(addr_remote)
+        } else {
+            addr_remote
+        };
+        let resolved_clients = self.resolved_clients.get(&http_config);
+        let service = HttpService::new(
+            addr_remote,
+            addr_local,
+            http_config,
+            tls.map(|(client_config, _)| client_config.clone()),
+            self.metrics.clone(),
+            self.metadata.clone(),
+            fd,
+        )
+        .with_coordinator(self.coordinator.clone())
+        .with_access_log(self.access_log.clone())
+        .with_har(self.har.clone())
+        .with_telemetry(self.telemetry.clone())
+        .with_pcap(self.pcap.clone())
+        .with_client(client)
+        .with_explicit(self.explicit.is_some())
+        .with_socket_options(self.socket_options)
+        .with_requests(self.requests.clone())
+        .with_resolved_clients(resolved_clients);
+        let _permit = match admit(&self.limiter, addr_local, fd).await {
+            Some(permit) => permit,
+            None => return Ok(()),
+        };
+        if let Some(action) = service.tcp_action() {
+            let _in_flight = self.metrics.drain().start();
+            return serve_tcp(stream, &service, &action).await;
+        }
+        // the requests of absolute URIs are forwarded to the hosts of their URIs
+        if !self.inspected && !by_uri && service.passthrough() {
+            let _in_flight = self.metrics.drain().start();
+            return serve_raw(stream, &service).await;
+        }
+        match tls {
+            Some((_, acceptor)) => serve_https(stream, &service, acceptor.clone()).await,
+            None => serve_http_with_error_return(stream, &service).await,
+        }
+    }
+}
+
+/// admit_client waits for a slot of the proxy capacity, the connection is reset if it is
+/// rejected.
+async fn admit_client(
+    connections: &CapacityLimiter,
+    client: SocketAddr,
+    fd: RawSocket,
+) -> Option<OwnedSemaphorePermit> {
+    match connections.acquire().await {
+        Ok(permit) => Some(permit),
+        Err(e) => {
+            debug!("reject connection of {}: {}", client, e);
+            if let Err(e) = set_linger_zero(fd) {
+                error!("fail to set SO_LINGER: {}", e);
+            }
+            None
+        }
+    }
+}
+
+/// admit waits for a slot of the original destination, the connection is reset if it is rejected
+/// by the limiter.
+async fn admit(
+    limiter: &ConnectionLimiter,
+    target: SocketAddr,
+    fd: RawSocket,
+) -> Option<Option<OwnedSemaphorePermit>> {
+    match limiter.acquire(target).await {
+        Ok(permit) => Some(permit),
+        Err(e) => {
+            debug!("reject connection: {}", e);
+            if let Err(e) = set_linger_zero(fd) {
+                error!("fail to set SO_LINGER: {}", e);
+            }
+            None
+        }
+    }
+}
+
+/// serve_tcp relays the raw TCP connection to the original destination with the faults.
+async fn serve_tcp(
+    stream: Captured<TcpStream>,
+    service: &HttpService,
+    action: &TcpAction,
+) -> Result<()> {
+    let upstream = service.connect().await?;
+    tcp::relay(stream, upstream, action).await
+}
+
+/// serve_raw relays the connection no HTTP rule could apply to as raw TCP, without parsing it.
+async fn serve_raw(mut stream: Captured<TcpStream>, service: &HttpService) -> Result<()> {
+    let mut upstream = service.connect().await?;
+    tokio::io::copy_bidirectional(&mut stream, &mut upstream).await?;
+    Ok(())
+}
+
 /// serve_https would make the HttpService resolving the resolve TLS stream.
 pub async fn serve_https(
-    stream: TcpStream,
+    stream: Captured<TcpStream>,
     service: &HttpService,
     acceptor: TlsAcceptor,
 ) -> Result<()> {
     let log_key = format!(
         "{{ peer={},local={} }}",
-        stream.peer_addr()?,
-        stream.local_addr()?
+        stream.get_ref().peer_addr()?,
+        stream.get_ref().local_addr()?
     );
+    let mut service = service.clone();
+    service.fingerprint = peek_ja3(stream.get_ref())
+        .await
+        .map(|ja3| ClientFingerprint { ja3 });
+    trace!("{}: client fingerprint {:?}", log_key, service.fingerprint);
     let mut tls_stream = acceptor.accept(stream).await?;
+    let connection = tls_stream.get_ref().1;
+    service.tls_client = Some(TlsClient::new(
+        connection
+            .peer_certificates()
+            .and_then(|certs| certs.first()),
+        connection.alpn_protocol(),
+    ));
+    trace!("{}: tls client {:?}", log_key, service.tls_client);
     loop {
         let (r, parts) = Http::new()
             .serve_connection_with_parts(tls_stream, service.clone())
             .await;
         let part_stream = match r {
             Ok(()) => match parts {
-                Some(part) => part.io,
+                Some(part) => {
+                    if let Some(tunnel) = service.take_tunnel() {
+                        return tunnel.relay(part.io, part.read_buf).await;
+                    }
+                    part.io
+                }
                 None => {
                     return Ok(());
                 }
@@ -125,17 +671,47 @@ pub async fn serve_https(
     }
 }
 
+/// serve_upstream serves the connections of the listener as if they were destined to the
+/// upstream, without any interception, e.g. for the in-process [Harness](crate::testing::Harness).
+#[cfg(feature = "testing")]
+pub(crate) async fn serve_upstream(
+    listener: tokio::net::TcpListener,
+    upstream: SocketAddr,
+    config: Arc<HTTPConfig>,
+    metrics: Arc<Metrics>,
+) -> Result<()> {
+    loop {
+        let (stream, remote) = listener.accept().await?;
+        let service = HttpService::new(
+            remote,
+            upstream,
+            config.clone(),
+            None,
+            metrics.clone(),
+            None,
+            stream.raw_socket(),
+        )
+        // the clients connect to the harness directly, nothing is diverted to be transparent to
+        .with_explicit(true);
+        tokio::spawn(async move {
+            if let Err(e) = serve_http_with_error_return(Captured::new(stream), &service).await {
+                debug!("fail to serve the connection: {}", e);
+            }
+        });
+    }
+}
+
 ///  serve_http_with_error_return would make the HttpService resolve the incoming TCP stream.
 ///
 /// TODO(@STRRL): rename it to `serve_http` to keep naming consistent with `serve_https`
 pub async fn serve_http_with_error_return(
-    mut stream: TcpStream,
+    mut stream: Captured<TcpStream>,
     service: &HttpService,
 ) -> Result<()> {
     let log_key = format!(
         "{{ peer={},local={} }}",
-        stream.peer_addr()?,
-        stream.local_addr()?
+        stream.get_ref().peer_addr()?,
+        stream.get_ref().local_addr()?
     );
     let span = span!(Level::TRACE, "Stream", "{}", &log_key);
     let _guard = span.enter();
@@ -146,7 +722,12 @@ pub async fn serve_http_with_error_return(
             .await;
         let part_stream = match r {
             Ok(()) => match parts {
-                Some(part) => part.io,
+                Some(part) => {
+                    if let Some(tunnel) = service.take_tunnel() {
+                        return tunnel.relay(part.io, part.read_buf).await;
+                    }
+                    part.io
+                }
                 None => {
                     return Ok(());
                 }
@@ -156,11 +737,7 @@ pub async fn serve_http_with_error_return(
                     debug!("Turn into tcp transfer.");
                     match parts {
                         Some(mut part) => {
-                            let addr_target = part.io.local_addr()?;
-                            let addr_local = part.io.peer_addr()?;
-                            let socket = TransparentSocket::bind(addr_local)?;
-                            debug!("Bind local addrs.");
-                            let mut client_stream = socket.connect(addr_target).await?;
+                            let mut client_stream = service.connect().await?;
                             debug!("Connected target addrs.");
                             client_stream
                                 .write_all(part.read_buf.as_ref())
@@ -196,6 +773,71 @@ pub struct HttpService {
 
     #[derivative(Debug = "ignore")]
     tls_client_config: Option<Arc<ClientConfig>>,
+
+    #[derivative(Debug = "ignore")]
+    metrics: Arc<Metrics>,
+
+    #[derivative(Debug = "ignore")]
+    metadata: Option<Arc<dyn MetadataResolver>>,
+
+    /// fd of the client connection, to set socket options when the exchange is aborted.
+    fd: RawSocket,
+
+    /// fingerprint of the TLS client, set by `serve_https`.
+    fingerprint: Option<ClientFingerprint>,
+
+    /// certificate and ALPN protocol of the TLS client, set by `serve_https`.
+    tls_client: Option<TlsClient>,
+
+    /// coordinator shares the pattern epoch and the blast radius cap with other instances.
+    coordinator: Option<Arc<Coordinator>>,
+
+    /// direction of the connection, only resolved if any rule is restricted to a direction.
+    direction: Option<Direction>,
+
+    /// client is the source told by the PROXY header, the same as `remote` if there is none.
+    client: SocketAddr,
+
+    /// tunnel is set if the upstream has switched protocols, shared by the clones serving the
+    /// same connection.
+    tunnel: Arc<Mutex<Option<Tunnel>>>,
+
+    /// access_log writes a record of every exchange if enabled.
+    #[derivative(Debug = "ignore")]
+    access_log: Option<Arc<AccessLog>>,
+
+    /// har captures the exchanges if enabled.
+    #[derivative(Debug = "ignore")]
+    har: Option<Arc<HarRecorder>>,
+
+    /// telemetry exports a span of every exchange if enabled.
+    #[derivative(Debug = "ignore")]
+    telemetry: Option<Arc<Telemetry>>,
+
+    /// pcap captures the connections to the original destination if enabled.
+    #[derivative(Debug = "ignore")]
+    pcap: Option<Arc<Pcap>>,
+
+    /// explicit is set if the clients connect to the proxy directly, the upstreams are connected
+    /// from the proxy, and the requests of absolute URIs are forwarded by their URIs.
+    explicit: bool,
+
+    /// clients forward the requests to the original destination, shared by the clones serving the
+    /// same connection so that the upstream connections are kept alive across its requests.
+    #[derivative(Debug = "ignore")]
+    clients: Arc<Clients<HttpConnector>>,
+
+    /// resolved_clients forward the requests rerouted or of absolute URIs, by the resolver, shared
+    /// by the connections of the listener with the same config.
+    #[derivative(Debug = "ignore")]
+    resolved_clients: Arc<ResolvedClients>,
+
+    /// socket_options are set on the upstream connections.
+    socket_options: SocketOptions,
+
+    /// requests caps the requests in flight of the proxy, the excess ones get 503.
+    #[derivative(Debug = "ignore")]
+    requests: Option<Arc<CapacityLimiter>>,
 }
 
 impl HttpService {
@@ -204,12 +846,153 @@ impl HttpService {
         addr_target: SocketAddr,
         config: Arc<HTTPConfig>,
         tls_client_config: Option<Arc<ClientConfig>>,
+        metrics: Arc<Metrics>,
+        metadata: Option<Arc<dyn MetadataResolver>>,
+        fd: RawSocket,
     ) -> Self {
+        let direction = config
+            .rules
+            .iter()
+            .any(|rule| rule.direction.is_some())
+            .then(|| Direction::of(&addr_target));
+        let pool = config.upstream_pool;
         Self {
             remote: addr_remote,
             target: addr_target,
             config,
             tls_client_config,
+            metrics,
+            metadata,
+            fd,
+            fingerprint: None,
+            tls_client: None,
+            coordinator: None,
+            direction,
+            client: addr_remote,
+            tunnel: Default::default(),
+            access_log: None,
+            har: None,
+            telemetry: None,
+            pcap: None,
+            explicit: false,
+            clients: Arc::new(Clients::new(pool)),
+            resolved_clients: Arc::new(Clients::new(pool)),
+            socket_options: SocketOptions::default(),
+            requests: None,
+        }
+    }
+
+    /// take_tunnel returns the upgraded connection to the upstream, if the client connection should
+    /// be tunneled instead of serving HTTP.
+    fn take_tunnel(&self) -> Option<Tunnel> {
+        self.tunnel.lock().unwrap().take()
+    }
+
+    fn with_coordinator(mut self, coordinator: Option<Arc<Coordinator>>) -> Self {
+        self.coordinator = coordinator;
+        self
+    }
+
+    fn with_access_log(mut self, access_log: Option<Arc<AccessLog>>) -> Self {
+        self.access_log = access_log;
+        self
+    }
+
+    fn with_har(mut self, har: Option<Arc<HarRecorder>>) -> Self {
+        self.har = har;
+        self
+    }
+
+    fn with_telemetry(mut self, telemetry: Option<Arc<Telemetry>>) -> Self {
+        self.telemetry = telemetry;
+        self
+    }
+
+    fn with_pcap(mut self, pcap: Option<Arc<Pcap>>) -> Self {
+        self.pcap = pcap;
+        self
+    }
+
+    fn with_client(mut self, client: SocketAddr) -> Self {
+        self.client = client;
+        self
+    }
+
+    fn with_explicit(mut self, explicit: bool) -> Self {
+        self.explicit = explicit;
+        self
+    }
+
+    fn with_socket_options(mut self, socket_options: SocketOptions) -> Self {
+        self.socket_options = socket_options;
+        self
+    }
+
+    fn with_requests(mut self, requests: Option<Arc<CapacityLimiter>>) -> Self {
+        self.requests = requests;
+        self
+    }
+
+    fn with_resolved_clients(mut self, resolved_clients: Arc<ResolvedClients>) -> Self {
+        self.resolved_clients = resolved_clients;
+        self
+    }
+
+    /// connect opens a raw connection to the original destination from the address of the remote,
+    /// with the PROXY header if enabled.
+    async fn connect(&self) -> Result<Captured<TcpStream>> {
+        let socket = match self.explicit {
+            true => tcp_socket(&self.target)?,
+            false => TransparentSocket::bind(self.remote)?,
+        };
+        let upstream = self.socket_options.connect(socket, self.target).await?;
+        let mut upstream = match &self.pcap {
+            Some(pcap) => pcap.upstream(upstream, self.remote, self.target),
+            None => Captured::new(upstream),
+        };
+        if let Some(version) = self.config.proxy_protocol.send {
+            write_header(&mut upstream, version, self.client, self.target).await?;
+        }
+        Ok(upstream)
+    }
+
+    /// connector returns the connector of the original destination for the clients of hyper.
+    fn connector(&self) -> HttpConnector {
+        let connector = HttpConnector::new(self.target, self.remote)
+            .with_proxy_header(self.config.proxy_protocol.send, self.client)
+            .with_pcap(self.pcap.clone())
+            .with_socket_options(self.socket_options);
+        match self.explicit {
+            true => connector.plain(),
+            false => connector,
+        }
+    }
+
+    /// coordination returns whether the faults are allowed by the coordinator, and the epoch of
+    /// the patterns shared by the coordinated instances.
+    fn coordination(&self) -> (bool, Option<SystemTime>) {
+        // the rules never apply while the baseline is captured or the proxy is disarmed
+        let paused = self.metrics.observing() || !self.metrics.armed();
+        match &self.coordinator {
+            None => (!paused, None),
+            Some(coordinator) => (
+                !paused && coordinator.is_allowed(),
+                Some(coordinator.epoch()),
+            ),
+        }
+    }
+
+    /// abort would handle the connection as the given abort mode, the returned error would make
+    /// hyper close the connection.
+    async fn abort(&self, mode: AbortMode) {
+        match mode {
+            AbortMode::Reset => {
+                if let Err(e) = set_linger_zero(self.fd) {
+                    error!("fail to reset connection: {}", e);
+                }
+            }
+            AbortMode::Close => {}
+            AbortMode::Timeout => future::pending().await,
         }
     }
 
@@ -221,36 +1004,484 @@ impl HttpService {
             Some(r) => r.clone(),
         };
 
-        select_role(&self.remote.ip(), &self.target.ip(), &role)
+        select_role(&self.client.ip(), &self.target.ip(), &role)
+    }
+
+    /// tcp_action returns the action of the active tcp rule of the highest priority matching the
+    /// connection, the connection is relayed as raw TCP instead of being parsed as HTTP if any.
+    fn tcp_action(&self) -> Option<TcpAction> {
+        if !self.role_ok() {
+            return None;
+        }
+        let (allowed, epoch) = self.coordination();
+        if !allowed {
+            return None;
+        }
+        let tcp_rules = self
+            .config
+            .rules
+            .iter()
+            .enumerate()
+            .filter(|(index, rule)| {
+                matches!(rule.target, Target::Tcp)
+                    && self.direction_ok(rule)
+                    && select_connection(self.target.port(), &rule.selector)
+                    && rule.is_active(epoch, &*self.config.clock)
+                    && !self.metrics.expired(
+                        self.config.rule_set,
+                        *index,
+                        rule,
+                        self.config.clock.now(),
+                    )
+            })
+            .collect();
+        let (index, rule) = MatchPolicy::First.select(tcp_rules).into_iter().next()?;
+        self.metrics.rule_applied(index, rule, &rule.actions, None);
+        rule.actions.tcp.clone()
+    }
+
+    /// passthrough tells whether the connection could be relayed as raw TCP, as no HTTP rule could
+    /// ever apply to it. The rules are taken as applying regardless of their time windows, which
+    /// may open while the connection is kept alive.
+    fn passthrough(&self) -> bool {
+        let config = &self.config;
+        if config.inspect_all
+            || config.compare_mode
+            || config.validator.is_some()
+            || config.opt_in.is_some()
+            || config.exchange_id_header.is_some()
+        {
+            return false;
+        }
+        if !self.role_ok() {
+            return true;
+        }
+        let port = self.target.port();
+        !config.rules.iter().any(|rule| {
+            // the follow-ups apply to the next requests of the client to any port
+            rule.follow_up.is_some()
+                || (!matches!(rule.target, Target::Tcp)
+                    && self.direction_ok(rule)
+                    && rule.selector.port.iter().all(|p| port == *p))
+        })
     }
 
-    /// handle would execute the core inject and forward logic.
-    async fn handle(self, mut request: Request<Body>) -> Result<Response<Body>> {
-        let log_key = format!("{{remote = {}, target = {} }}", self.remote, self.target);
+    /// follow_up_rules returns the rules of the follow-ups registered for the requests of the
+    /// client to the path, with the indexes of the rules registering them.
+    fn follow_up_rules(&self, path: &str) -> Vec<(usize, &Rule)> {
+        self.config.follow_ups.rules(
+            &self.config.rules,
+            self.client.ip(),
+            path,
+            self.config.clock.now(),
+        )
+    }
+
+    /// register_follow_up registers the follow-up of the rule applied to the exchange of the path.
+    fn register_follow_up(&self, index: usize, rule: &Rule, path: &str) {
+        if let Some(follow_up) = &rule.follow_up {
+            self.config.follow_ups.register(
+                index,
+                follow_up,
+                self.client.ip(),
+                path,
+                self.config.clock.now(),
+            );
+        }
+    }
+
+    /// direction_ok checks whether the rule is restricted to the other direction.
+    fn direction_ok(&self, rule: &Rule) -> bool {
+        rule.direction.is_none() || rule.direction == self.direction
+    }
+
+    /// handle would execute the core inject and forward logic, the rules matched and applied are
+    /// collected to the attribution.
+    async fn handle(
+        self,
+        mut request: Request<Body>,
+        attribution: &mut Attribution,
+    ) -> Result<Response<Body>> {
+        let exchange = ExchangeId::of(request.extensions());
+        let log_key = format!(
+            "{{remote = {}, target = {}, exchange = {} }}",
+            self.remote, self.target, exchange
+        );
         debug!("{} : Proxy is handling http request", log_key);
+        let compensation = Compensation::start(self.config.latency_compensation);
 
         let role_ok = self.role_ok();
+        // the opt-in header never reaches the upstream
+        let opted_in = match &self.config.opt_in {
+            None => true,
+            Some(opt_in) => opt_in.take(&mut request),
+        };
+        let (allowed, epoch) = self.coordination();
         let request_rules: Vec<_> = self
             .config
             .rules
             .iter()
-            .filter(|rule| {
+            .enumerate()
+            .filter(|(index, rule)| {
                 role_ok
+                    && opted_in
+                    && allowed
                     && matches!(rule.target, Target::Request)
+                    && self.direction_ok(rule)
                     && select_request(self.target.port(), &request, &rule.selector)
+                    && rule.is_active(epoch, &*self.config.clock)
+                    && !self.metrics.expired(
+                        self.config.rule_set,
+                        *index,
+                        rule,
+                        self.config.clock.now(),
+                    )
+                    && rule.admits(request.headers(), request.extensions())
             })
             .collect();
+        // the follow-ups registered by the earlier exchanges come first among the same priority
+        let path = request.uri().path().to_string();
+        let follow_up_rules = if role_ok && opted_in && allowed {
+            self.follow_up_rules(&path)
+        } else {
+            vec![]
+        };
+        let request_rules: Vec<_> = follow_up_rules.into_iter().chain(request_rules).collect();
+        attribution.matched(&request_rules);
+        let mut request_rules = self.config.match_policy.select(request_rules);
+        let request_matched = !request_rules.is_empty();
+
+        // the body is read within the limit before it is buffered
+        let compared = self.should_compare(&request);
+        let mut streamed = false;
+        let buffered = compared
+            || request_rules
+                .iter()
+                .any(|(_, rule)| rule.actions.buffers_body());
+        if let Some(limit) = self.config.body_limit.filter(|_| buffered) {
+            let (mut parts, mut body) = request.into_parts();
+            match limit_body(&mut parts.headers, &mut body, limit).await? {
+                Limited::Buffered => {}
+                Limited::Streamed => streamed = true,
+                Limited::Rejected => {
+                    debug!("{} : request body over max_body_size is rejected", log_key);
+                    return Ok(Response::builder()
+                        .status(StatusCode::PAYLOAD_TOO_LARGE)
+                        .body(Body::empty())?);
+                }
+            }
+            request = Request::from_parts(parts, body);
+        }
+        if streamed {
+            debug!("{} : request body over max_body_size is streamed", log_key);
+            request_rules.retain(|(_, rule)| !rule.actions.buffers_body());
+        }
+
+        // send an untouched copy to compare with the actual response
+        let shadow = if compared && !streamed {
+            let (parts, body) = request.into_parts();
+            let body = hyper::body::to_bytes(body).await?;
+            let shadow_request = copy_request(&parts, body.clone())?;
+            request = Request::from_parts(parts, body.into());
+            Some(tokio::spawn(self.clone().forward(shadow_request)))
+        } else {
+            None
+        };
+
+        // copy the untouched request to the shadow backends
+        let mirrors = mirrors(&request_rules);
+        if !mirrors.is_empty() {
+            let (parts, body) = request.into_parts();
+            let body = hyper::body::to_bytes(body).await?;
+            for action in mirrors {
+                mirror(&parts, &body, action, self.config.resolver.clone())?;
+            }
+            request = Request::from_parts(parts, body.into());
+        }
+
+        // inject chaos into request, the connection is reset at the cut of a body, hyper resets the
+        // stream of HTTP/2 on its own
+        let reset = (request.version() != Version::HTTP_2).then_some(self.fd);
+        let mut injection = Injection::new(&*self.config.clock, compensation)
+            .fault_markers(self.config.fault_markers)
+            .reset(reset);
+        let injected = injection
+            .request(request, request_rules, |index, rule, sampled| {
+                debug!("{} : request matched, rule({})", log_key, index);
+                attribution.applied(index, rule, sampled);
+                self.metrics
+                    .rule_applied(index, rule, sampled, Some(exchange));
+                self.register_follow_up(index, rule, &path);
+            })
+            .await?;
+        let mut request = match injected {
+            Injected::Forward(request) => request,
+            Injected::Respond(response) => return Ok(response),
+        };
+        let Forwarding {
+            duplicates,
+            websocket,
+            response_delay,
+            timeout,
+        } = mem::take(&mut injection.forwarding);
 
-        // inject chaos into request
-        for rule in request_rules {
-            debug!("{} : request matched, rule({:?})", log_key, rule);
-            request = apply_request_action(request, &rule.actions).await?;
+        if !duplicates.is_empty() {
+            let (parts, body) = request.into_parts();
+            let body = hyper::body::to_bytes(body).await?;
+            for duplicate in duplicates {
+                self.duplicate(&parts, &body, duplicate)?;
+            }
+            request = Request::from_parts(parts, body.into());
+        }
+        injection.mark(&mut request)?;
+        if let Some(header) = &self.config.exchange_id_header {
+            exchange.stamp(request.headers_mut(), header)?;
         }
 
         let uri = request.uri().clone();
         let method = request.method().clone();
         let headers = request.headers().clone();
+        let labels = request.extensions().get::<ClientLabels>().cloned();
+
+        let upgrade = is_upgrade(&request);
+
+        let forwarded = Instant::now();
+        let forwarding = self.clone().forward(request);
+        let mut response = match &timeout {
+            Some(timeout) => timeout.run(forwarding, &*self.config.clock).await?,
+            None => forwarding.await?,
+        };
+        injection.compensation.exclude(forwarded.elapsed());
+        let mut streamed = false;
+        let buffered =
+            self.config.validator.is_some()
+                || shadow.is_some()
+                || self.config.rules.iter().any(|rule| {
+                    matches!(rule.target, Target::Response) && rule.actions.buffers_body()
+                });
+        let limit = self
+            .config
+            .body_limit
+            .filter(|_| buffered && response.status() != StatusCode::SWITCHING_PROTOCOLS);
+        if let Some(limit) = limit {
+            let (mut parts, mut body) = response.into_parts();
+            match limit_body(&mut parts.headers, &mut body, limit).await? {
+                Limited::Buffered => {}
+                Limited::Streamed => streamed = true,
+                Limited::Rejected => {
+                    debug!("{} : response body over max_body_size is rejected", log_key);
+                    return Ok(Response::builder()
+                        .status(StatusCode::BAD_GATEWAY)
+                        .body(Body::empty())?);
+                }
+            }
+            response = Response::from_parts(parts, body);
+        }
+        if streamed {
+            debug!("{} : response body over max_body_size is streamed", log_key);
+        }
+        if let Some(validator) = self.config.validator.as_ref().filter(|_| !streamed) {
+            if response.status() != StatusCode::SWITCHING_PROTOCOLS {
+                response = self.validate(response, &method, &uri, validator).await?;
+            }
+        }
+        // the connection of the client is tunneled by the serving loop after the response is sent
+        if upgrade && response.status() == StatusCode::SWITCHING_PROTOCOLS {
+            *self.tunnel.lock().unwrap() = Some(Tunnel {
+                server: hyper::upgrade::on(&mut response),
+                action: websocket,
+            });
+        }
+        if !response_delay.is_zero() {
+            self.config
+                .clock
+                .sleep(injection.compensation.compensate(response_delay))
+                .await;
+        }
+        response.extensions_mut().insert(exchange);
+        response.extensions_mut().insert(ClientAddr(self.client));
+        if let Some(fingerprint) = &self.fingerprint {
+            response.extensions_mut().insert(fingerprint.clone());
+        }
+        if let Some(tls_client) = &self.tls_client {
+            response.extensions_mut().insert(tls_client.clone());
+        }
+        if let Some(labels) = labels {
+            response.extensions_mut().insert(labels);
+        }
+
+        // only one rule applies to the exchange under the first-match policy
+        let skip_response = request_matched && self.config.match_policy == MatchPolicy::First;
+        let response_rules: Vec<_> = self
+            .config
+            .rules
+            .iter()
+            .enumerate()
+            .filter(|(index, rule)| {
+                !skip_response
+                    && role_ok
+                    && opted_in
+                    && allowed
+                    && matches!(rule.target, Target::Response)
+                    && self.direction_ok(rule)
+                    && select_response(
+                        self.target.port(),
+                        &uri,
+                        &method,
+                        &headers,
+                        &response,
+                        &rule.selector,
+                    )
+                    && rule.is_active(epoch, &*self.config.clock)
+                    && !self.metrics.expired(
+                        self.config.rule_set,
+                        *index,
+                        rule,
+                        self.config.clock.now(),
+                    )
+                    && rule.admits(&headers, response.extensions())
+            })
+            .collect();
+        attribution.matched(&response_rules);
+        let mut response_rules = self.config.match_policy.select(response_rules);
+        if streamed {
+            response_rules.retain(|(_, rule)| !rule.actions.buffers_body());
+        }
+
+        // inject chaos into response
+        let mut response = injection
+            .response(
+                response,
+                &method,
+                &uri,
+                &headers,
+                response_rules,
+                |index, rule, sampled| {
+                    debug!("{} : response matched, rule({})", log_key, index);
+                    attribution.applied(index, rule, sampled);
+                    self.metrics
+                        .rule_applied(index, rule, sampled, Some(exchange));
+                    self.register_follow_up(index, rule, uri.path());
+                },
+            )
+            .await?;
+
+        if let Some(shadow) = shadow.filter(|_| !streamed) {
+            response = self.compare(response, shadow).await?;
+        }
+        Ok(response)
+    }
+
+    /// should_compare checks whether the request is idempotent and would be matched by any rule in
+    /// compare mode.
+    fn should_compare(&self, request: &Request<Body>) -> bool {
+        self.config.compare_mode
+            && request.method().is_idempotent()
+            && self.role_ok()
+            && self
+                .config
+                .rules
+                .iter()
+                .any(|rule| select_request(self.target.port(), request, &rule.selector))
+    }
+
+    /// compare would record the differences between the actual response and the shadow one.
+    async fn compare(
+        &self,
+        response: Response<Body>,
+        shadow: JoinHandle<Result<Response<Body>>>,
+    ) -> Result<Response<Body>> {
+        let (parts, body) = response.into_parts();
+        let body = hyper::body::to_bytes(body).await?;
+        match shadow.await? {
+            Ok(shadow) => {
+                let (shadow_parts, shadow_body) = shadow.into_parts();
+                let shadow_body = hyper::body::to_bytes(shadow_body).await?;
+                let diff = diff_response(&shadow_parts, &shadow_body, &parts, &body);
+                if !diff.is_empty() {
+                    tracing::info!(
+                        "{{remote = {}, target = {} }} : response differs from shadow: {:?}",
+                        self.remote,
+                        self.target,
+                        diff
+                    );
+                }
+                self.metrics.record_comparison(&diff);
+            }
+            Err(e) => debug!("fail to forward shadow request: {}", e),
+        }
+        Ok(Response::from_parts(parts, body.into()))
+    }
+
+    /// validate would log the violations of the upstream response, and replace or quarantine it
+    /// as the validator tells.
+    async fn validate(
+        &self,
+        response: Response<Body>,
+        method: &Method,
+        uri: &Uri,
+        validator: &ResponseValidator,
+    ) -> Result<Response<Body>> {
+        let (parts, body) = response.into_parts();
+        let body = hyper::body::to_bytes(body).await?;
+        let violations = validator.validate(&parts, &body);
+        if violations.is_empty() {
+            return Ok(Response::from_parts(parts, body.into()));
+        }
+        tracing::warn!(
+            "{{remote = {}, target = {} }} : invalid upstream response to {} {}: {:?}",
+            self.remote,
+            self.target,
+            method,
+            uri,
+            violations
+        );
+        let status = match &validator.on_violation {
+            OnViolation::Log => return Ok(Response::from_parts(parts, body.into())),
+            OnViolation::Replace(status) => *status,
+            OnViolation::Quarantine { status, path } => {
+                let quarantined = Quarantined::new(method, uri, &parts, &body, &violations);
+                if let Err(e) = quarantined.append(path).await {
+                    tracing::warn!("fail to quarantine response to {:?}: {}", path, e);
+                }
+                *status
+            }
+        };
+        let reasons: Vec<_> = violations.iter().map(ToString::to_string).collect();
+        Ok(Response::builder()
+            .status(status)
+            .body(format!("invalid upstream response: {}", reasons.join("; ")).into())?)
+    }
+
+    /// duplicate would send the copies of the request in background, the responses are discarded.
+    fn duplicate(
+        &self,
+        parts: &http::request::Parts,
+        body: &Bytes,
+        action: DuplicateAction,
+    ) -> Result<()> {
+        for i in 1..=action.count {
+            let request = copy_request(parts, body.clone())?;
+            let delay = action.interval.map(|interval| interval * i);
+            let service = self.clone();
+            tokio::spawn(async move {
+                if let Some(delay) = delay {
+                    service.config.clock.sleep(delay).await;
+                }
+                if let Err(e) = service.forward(request).await {
+                    debug!("fail to forward duplicate request: {}", e);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    /// forward would send the request to the original destination.
+    async fn forward(self, mut request: Request<Body>) -> Result<Response<Body>> {
         trace!("URI: {}", request.uri());
+        strip_expect(&mut request);
+        // the requests of absolute URIs to the explicit proxy may be of any host
+        let by_uri = self.explicit && request.uri().authority().is_some();
         let mut parts = request.uri().clone().into_parts();
 
         // because the original request URL is not carried in the HTTP request, we should rebuild it.
@@ -259,12 +1490,22 @@ impl HttpService {
             .iter()
             .find(|(header_name, _)| **header_name == HOST)
         {
-            None => match self.target.to_string().parse() {
-                Ok(o) => Some(o),
-                Err(_) => None,
+            // HTTP/2 requests carry the authority in the URI instead of the `Host` header
+            None => match request.uri().authority() {
+                Some(authority) => Some(authority.clone()),
+                None => self.target.to_string().parse().ok(),
             },
             Some((_, value)) => Some(value.as_bytes().try_into()?),
         };
+        // the request is rerouted to another backend by the actions
+        let upstream = request
+            .extensions()
+            .get::<Upstream>()
+            .map(|Upstream(upstream)| upstream.clone());
+        if upstream.is_some() {
+            parts.authority = upstream.clone();
+        }
+        let resolved = upstream.is_some() || by_uri;
         trace!("authority: {:?}", parts.authority);
         if parts.path_and_query.is_none() {
             parts.path_and_query = Some(PathAndQuery::from_static("/"))
@@ -277,56 +1518,75 @@ impl HttpService {
 
         *request.uri_mut() = Uri::from_parts(parts)?;
 
-        // forward HTTP/HTTPS request
+        // the request fails as if the upstream could not be connected, it never sees the request
+        if let Some(fault) = request.extensions_mut().remove::<ConnectFault>() {
+            if let Err(failure) = fault.run(&*self.config.clock).await {
+                debug!(
+                    "{{remote = {}, target = {} }} : fail to connect upstream (injected): {}",
+                    self.remote, self.target, failure
+                );
+                return Ok(Response::builder()
+                    .status(StatusCode::BAD_GATEWAY)
+                    .body(Body::empty())?);
+            }
+        }
+
+        // forward HTTP/HTTPS request, the upstream is connected from the proxy itself rather than
+        // transparently.
+        let resolver = || {
+            let mut http = client::HttpConnector::new_with_resolver(self.config.resolver.clone());
+            http.enforce_http(false);
+            http.set_nodelay(self.socket_options.nodelay);
+            http.set_recv_buffer_size(self.socket_options.recv_buffer.map(|size| size as usize));
+            http.set_send_buffer_size(self.socket_options.send_buffer.map(|size| size as usize));
+            http
+        };
         let rsp_fut = if let Some(tls_client_config) = &self.tls_client_config {
-            let https = hyper_rustls::HttpsConnectorBuilder::new()
-                .with_tls_config((**tls_client_config).clone())
-                .https_only()
-                .enable_http1()
-                .enable_http2()
-                .wrap_connector(HttpConnector::new(self.target, self.remote));
-
-            let client: client::Client<_, hyper::Body> = client::Client::builder().build(https);
-            client.request(request)
+            // the protocol of the upstream is negotiated by ALPN, hyper sends HTTP/1.1 requests on
+            // HTTP/2 connections as well.
+            *request.version_mut() = Version::HTTP_11;
+            let builder = || {
+                hyper_rustls::HttpsConnectorBuilder::new()
+                    .with_tls_config((**tls_client_config).clone())
+                    .https_only()
+                    .enable_http1()
+                    .enable_http2()
+            };
+            if resolved {
+                self.resolved_clients
+                    .https(|| builder().wrap_connector(resolver()))
+                    .request(request)
+            } else {
+                self.clients
+                    .https(|| builder().wrap_connector(self.connector()))
+                    .request(request)
+            }
         } else {
-            let client = Client::builder().build(HttpConnector::new(self.target, self.remote));
-            client.request(request)
+            // HTTP/2 without TLS (h2c) requires prior knowledge
+            let http2_only = request.version() == Version::HTTP_2;
+            if resolved {
+                self.resolved_clients
+                    .http(http2_only, resolver)
+                    .request(request)
+            } else {
+                self.clients
+                    .http(http2_only, || self.connector())
+                    .request(request)
+            }
         };
 
-        let mut response = match rsp_fut.await {
+        Ok(match rsp_fut.await {
             Ok(resp) => resp,
             Err(err) => {
-                error!("{} : fail to forward request: {}", log_key, err);
+                error!(
+                    "{{remote = {}, target = {} }} : fail to forward request: {}",
+                    self.remote, self.target, err
+                );
                 Response::builder()
                     .status(StatusCode::BAD_GATEWAY)
                     .body(Body::empty())?
             }
-        };
-
-        let response_rules: Vec<_> = self
-            .config
-            .rules
-            .iter()
-            .filter(|rule| {
-                role_ok
-                    && matches!(rule.target, Target::Response)
-                    && select_response(
-                        self.target.port(),
-                        &uri,
-                        &method,
-                        &headers,
-                        &response,
-                        &rule.selector,
-                    )
-            })
-            .collect();
-
-        // inject chaos into response
-        for rule in response_rules {
-            debug!("{} : response matched", log_key);
-            response = apply_response_action(response, &rule.actions).await?;
-        }
-        Ok(response)
+        })
     }
 }
 
@@ -342,7 +1602,594 @@ impl Service<Request<Body>> for HttpService {
     }
 
     #[inline]
-    fn call(&mut self, request: Request<Body>) -> Self::Future {
-        Box::pin(self.clone().handle(request))
+    fn call(&mut self, mut request: Request<Body>) -> Self::Future {
+        let service = self.clone();
+        if let Some(fingerprint) = &service.fingerprint {
+            request.extensions_mut().insert(fingerprint.clone());
+        }
+        if let Some(tls_client) = &service.tls_client {
+            request.extensions_mut().insert(tls_client.clone());
+        }
+        request.extensions_mut().insert(ClientAddr(service.client));
+        let exchange = ExchangeId::next();
+        request.extensions_mut().insert(exchange);
+        Box::pin(async move {
+            let _in_flight = service.metrics.drain().start();
+            // the request holds its slot until the response is returned
+            let _request = match &service.requests {
+                Some(requests) => match requests.acquire().await {
+                    Ok(permit) => Some(permit),
+                    Err(e) => {
+                        debug!("reject request of {}: {}", service.client, e);
+                        return Ok(Response::builder()
+                            .status(StatusCode::SERVICE_UNAVAILABLE)
+                            .body(Body::empty())?);
+                    }
+                },
+                None => None,
+            };
+            if let Some(metadata) = &service.metadata {
+                let labels = metadata.resolve(service.client.ip()).await;
+                request.extensions_mut().insert(ClientLabels(labels));
+            }
+            let metrics = service.metrics.clone();
+            let path = request.uri().path().to_string();
+            let span = service
+                .telemetry
+                .as_ref()
+                .map(|telemetry| telemetry.start(&mut request));
+            let (request, pending) = match &service.access_log {
+                Some(access_log) => {
+                    let (request, pending) =
+                        access_log.start(request, service.client, service.target);
+                    (request, Some(pending))
+                }
+                None => (request, None),
+            };
+            let (request, har) = match &service.har {
+                Some(har) => {
+                    let https = service.tls_client_config.is_some();
+                    let (request, pending) = har.start(request, https, service.target);
+                    (request, Some(pending))
+                }
+                None => (request, None),
+            };
+            let start = Instant::now();
+            let mut attribution = Attribution::default();
+            let result = service.clone().handle(request, &mut attribution).await;
+            // the synthesized and rejected responses are paired with the request as well
+            let result = result.and_then(|mut response| {
+                response.extensions_mut().insert(exchange);
+                if let Some(header) = &service.config.exchange_id_header {
+                    exchange.stamp(response.headers_mut(), header)?;
+                }
+                Ok(response)
+            });
+            let faulted = attribution.faulted();
+            let error = match &result {
+                Ok(response) => response.status().is_server_error(),
+                Err(_) => true,
+            };
+            metrics.record(faulted, start.elapsed(), error);
+            metrics.record_path(&path, start.elapsed(), error);
+            if let Some(coordinator) = &service.coordinator {
+                coordinator.record(faulted);
+            }
+            if let Err(e) = &result {
+                if let Some(Abort(mode)) = e.downcast_ref::<Abort>() {
+                    service.abort(*mode).await;
+                }
+            }
+            let result = match har {
+                Some(har) => har.finish(faulted, result),
+                None => result,
+            };
+            let result = match span {
+                Some(span) => telemetry::finish(span, &attribution, result),
+                None => result,
+            };
+            match pending {
+                Some(pending) => pending.finish(attribution, result),
+                None => result,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::{Infallible, TryInto};
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use http::{Request, Response, StatusCode, Version};
+    use hyper::body::HttpBody;
+    use hyper::header::{HeaderName, CONNECTION, CONTENT_LENGTH, EXPECT, UPGRADE};
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Client, Server};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use crate::clock::SystemClock;
+    use crate::metrics::Metrics;
+    use crate::pcap::Captured;
+    use crate::proxy::http::config::HTTPConfig;
+    use crate::proxy::http::server::{serve_http_with_error_return, HttpService, SharedClients};
+    use crate::proxy::tcp::sockopt::AsRawSocket;
+    use crate::raw_config::RawRule;
+
+    /// http_config returns the config of the rules.
+    fn http_config(rules: Vec<serde_json::Value>) -> HTTPConfig {
+        HTTPConfig {
+            listen_port: 0,
+            rules: rules
+                .into_iter()
+                .map(|rule| {
+                    let rule: RawRule = serde_json::from_value(rule).unwrap();
+                    rule.try_into().unwrap()
+                })
+                .collect(),
+            role: None,
+            compare_mode: false,
+            latency_compensation: false,
+            fault_markers: false,
+            inspect_all: false,
+            opt_in: None,
+            exchange_id_header: None,
+            resolver: Default::default(),
+            proxy_protocol: Default::default(),
+            clock: Arc::new(SystemClock),
+            follow_ups: Default::default(),
+            match_policy: Default::default(),
+            validator: None,
+            upstream_pool: Default::default(),
+            body_limit: None,
+            listener_rules: Default::default(),
+            rule_set: None,
+        }
+    }
+
+    /// proxy serves a connection, rerouting the requests to the upstream with the actions.
+    async fn proxy(upstream: SocketAddr, mut actions: serde_json::Value) -> SocketAddr {
+        actions["replace"] = serde_json::json!({"upstream": upstream.to_string()});
+        let config = http_config(vec![serde_json::json!({
+            "target": "Request",
+            "selector": {},
+            "actions": actions,
+        })]);
+        serve(upstream, config).await
+    }
+
+    /// serve serves a connection with the config, forwarding the requests to the upstream.
+    async fn serve(upstream: SocketAddr, config: HTTPConfig) -> SocketAddr {
+        let config = Arc::new(config);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, remote) = listener.accept().await.unwrap();
+            let service = HttpService::new(
+                remote,
+                upstream,
+                config,
+                None,
+                Arc::new(Metrics::new(None, None)),
+                None,
+                stream.raw_socket(),
+            );
+            serve_http_with_error_return(Captured::new(stream), &service)
+                .await
+                .unwrap();
+        });
+        addr
+    }
+
+    #[test]
+    fn test_shared_clients() {
+        let shared = SharedClients::default();
+        let config = Arc::new(http_config(vec![]));
+        let clients = shared.get(&config);
+        assert!(Arc::ptr_eq(&clients, &shared.get(&config)));
+        // the clients are replaced along with the config
+        let reloaded = Arc::new(http_config(vec![]));
+        assert!(!Arc::ptr_eq(&clients, &shared.get(&reloaded)));
+    }
+
+    #[test]
+    fn test_passthrough() {
+        let service = |config: HTTPConfig, port: u16| {
+            HttpService::new(
+                "10.0.0.1:40000".parse().unwrap(),
+                SocketAddr::from(([10, 0, 0, 2], port)),
+                Arc::new(config),
+                None,
+                Arc::new(Metrics::new(None, None)),
+                None,
+                -1,
+            )
+        };
+        assert!(service(http_config(vec![]), 80).passthrough());
+
+        let config = http_config(vec![
+            serde_json::json!({
+                "target": "Request",
+                "selector": {"port": 80},
+                "actions": {"abort": true},
+            }),
+            serde_json::json!({
+                "target": "Tcp",
+                "selector": {"port": 8080},
+                "actions": {"tcp": {"delay": "10ms"}},
+            }),
+        ]);
+        assert!(!service(config.clone(), 80).passthrough());
+        assert!(service(config.clone(), 8080).passthrough());
+        assert!(service(config.clone(), 9090).passthrough());
+
+        // the listeners with their own rule sets ignore the top-level rules
+        let config = HTTPConfig {
+            listener_rules: [(1026, vec![]), (1027, config.rules.clone())].into(),
+            ..config
+        };
+        assert!(service(config.for_listener(1026), 80).passthrough());
+        assert!(!service(config.for_listener(1027), 80).passthrough());
+
+        let config = HTTPConfig {
+            inspect_all: true,
+            ..http_config(vec![])
+        };
+        assert!(!service(config, 80).passthrough());
+
+        // every exchange gets its ID, without any rule
+        let config = HTTPConfig {
+            exchange_id_header: Some(HeaderName::from_static("x-chaos-exchange")),
+            ..http_config(vec![])
+        };
+        assert!(!service(config, 80).passthrough());
+    }
+
+    #[tokio::test]
+    async fn test_h2c() {
+        // the upstream only speaks HTTP/2 with prior knowledge
+        let upstream = Server::bind(&"127.0.0.1:0".parse().unwrap())
+            .http2_only(true)
+            .serve(make_service_fn(|_| async {
+                Ok::<_, Infallible>(service_fn(|request: Request<Body>| async move {
+                    Ok::<_, Infallible>(Response::new(Body::from(format!(
+                        "{:?} {}",
+                        request.version(),
+                        request.uri().path()
+                    ))))
+                }))
+            }));
+        let upstream_addr = upstream.local_addr();
+        tokio::spawn(upstream);
+        let proxy_addr = proxy(upstream_addr, serde_json::json!({})).await;
+
+        let client = Client::builder().http2_only(true).build_http::<Body>();
+        let response = client
+            .get(format!("http://{}/h2c", proxy_addr).parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.version(), Version::HTTP_2);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "HTTP/2.0 /h2c");
+    }
+
+    #[tokio::test]
+    async fn test_connect_fault() {
+        // the upstream counts the requests it sees
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let upstream =
+            Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service_fn(move |_| {
+                let counter = counter.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |_: Request<Body>| {
+                        counter.fetch_add(1, Ordering::SeqCst);
+                        async { Ok::<_, Infallible>(Response::new(Body::empty())) }
+                    }))
+                }
+            }));
+        let upstream_addr = upstream.local_addr();
+        tokio::spawn(upstream);
+        let client = Client::new();
+
+        let slow = proxy(upstream_addr, serde_json::json!({"connect_delay": "100ms"})).await;
+        let started = Instant::now();
+        let response = client
+            .get(format!("http://{}/", slow).parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        for actions in [
+            serde_json::json!({"connect_refused": true}),
+            serde_json::json!({"dns_failure": true}),
+        ] {
+            let down = proxy(upstream_addr, actions).await;
+            let response = client
+                .get(format!("http://{}/", down).parse().unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        }
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_truncate() {
+        let upstream =
+            Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service_fn(|_| async {
+                Ok::<_, Infallible>(service_fn(|_: Request<Body>| async {
+                    Ok::<_, Infallible>(Response::new(Body::from(r#"{"items": [1, 2, 3]}"#)))
+                }))
+            }));
+        let upstream_addr = upstream.local_addr();
+        tokio::spawn(upstream);
+        let config = http_config(vec![
+            serde_json::json!({
+                "target": "Request",
+                "selector": {},
+                "actions": {"replace": {"upstream": upstream_addr.to_string()}},
+            }),
+            serde_json::json!({
+                "target": "Response",
+                "selector": {},
+                "actions": {"truncate": {"after_bytes": 10}},
+            }),
+        ]);
+        let addr = serve(upstream_addr, config).await;
+        let mut response = Client::new()
+            .get(format!("http://{}/", addr).parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_LENGTH], "20");
+        // the client gets a part of the body, then the connection is closed
+        let mut received = vec![];
+        let cut = loop {
+            match response.body_mut().data().await {
+                Some(Ok(chunk)) => received.extend_from_slice(&chunk),
+                Some(Err(_)) => break true,
+                None => break false,
+            }
+        };
+        assert_eq!(received, br#"{"items": "#);
+        assert!(cut);
+    }
+
+    #[tokio::test]
+    async fn test_truncate_reset() {
+        let upstream =
+            Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service_fn(|_| async {
+                Ok::<_, Infallible>(service_fn(|_: Request<Body>| async {
+                    Ok::<_, Infallible>(Response::new(Body::from(vec![b'a'; 4_000_000])))
+                }))
+            }));
+        let upstream_addr = upstream.local_addr();
+        tokio::spawn(upstream);
+        let config = http_config(vec![
+            serde_json::json!({
+                "target": "Request",
+                "selector": {},
+                "actions": {"replace": {"upstream": upstream_addr.to_string()}},
+            }),
+            serde_json::json!({
+                "target": "Response",
+                "selector": {},
+                "actions": {"truncate": {"after_bytes": 3_000_000, "mode": "reset"}},
+            }),
+        ]);
+        let addr = serve(upstream_addr, config).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nhost: test\r\n\r\n")
+            .await
+            .unwrap();
+        // the client reads late, the part of the body is still queued when it is cut, then it
+        // gets the whole part before the reset
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let mut received = vec![];
+        let mut buf = [0; 8192];
+        let reset = loop {
+            match stream.read(&mut buf).await {
+                Ok(0) => break false,
+                Ok(n) => received.extend_from_slice(&buf[..n]),
+                Err(e) => break e.kind() == std::io::ErrorKind::ConnectionReset,
+            }
+        };
+        let head = received
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .unwrap()
+            + 4;
+        assert!(received.starts_with(b"HTTP/1.1 200"));
+        assert_eq!(received.len() - head, 3_000_000);
+        assert!(reset);
+    }
+
+    #[tokio::test]
+    async fn test_exchange_id_header() {
+        // the upstream echoes the ID it got
+        let upstream =
+            Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service_fn(|_| async {
+                Ok::<_, Infallible>(service_fn(|request: Request<Body>| async move {
+                    let id = request.headers()["x-chaos-exchange"].clone();
+                    Ok::<_, Infallible>(Response::new(Body::from(id.as_bytes().to_vec())))
+                }))
+            }));
+        let upstream_addr = upstream.local_addr();
+        tokio::spawn(upstream);
+        let config = HTTPConfig {
+            exchange_id_header: Some(HeaderName::from_static("x-chaos-exchange")),
+            ..http_config(vec![
+                serde_json::json!({
+                    "target": "Request",
+                    "selector": {"path": "/abort"},
+                    "actions": {"abort": {"code": 503}},
+                }),
+                serde_json::json!({
+                    "target": "Request",
+                    "selector": {"path": "/echo"},
+                    "actions": {"replace": {"upstream": upstream_addr.to_string()}},
+                }),
+            ])
+        };
+        let proxy_addr = serve(upstream_addr, config).await;
+
+        let client = Client::new();
+        let mut ids = vec![];
+        for path in ["/echo", "/echo", "/abort"] {
+            let response = client
+                .get(format!("http://{}{}", proxy_addr, path).parse().unwrap())
+                .await
+                .unwrap();
+            let id = response.headers()["x-chaos-exchange"].clone();
+            if path == "/echo" {
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                assert_eq!(body, id.as_bytes());
+            } else {
+                assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+            }
+            ids.push(id);
+        }
+        // every exchange gets its own ID, the synthesized responses included
+        assert_ne!(ids[0], ids[1]);
+        assert_ne!(ids[1], ids[2]);
+    }
+
+    #[tokio::test]
+    async fn test_expect_continue() {
+        // the upstream echoes the expectation it got
+        let upstream =
+            Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service_fn(|_| async {
+                Ok::<_, Infallible>(service_fn(|request: Request<Body>| async move {
+                    let expect = request.headers().contains_key(EXPECT);
+                    let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                    Ok::<_, Infallible>(Response::new(Body::from(format!(
+                        "{} {}",
+                        expect,
+                        String::from_utf8_lossy(&body)
+                    ))))
+                }))
+            }));
+        let upstream_addr = upstream.local_addr();
+        tokio::spawn(upstream);
+        let proxy_addr = proxy(
+            upstream_addr,
+            serde_json::json!({"withhold_continue": "100ms"}),
+        )
+        .await;
+
+        let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+        stream
+            .write_all(b"POST / HTTP/1.1\r\nHost: expect.test\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\n")
+            .await
+            .unwrap();
+        let started = Instant::now();
+        let mut interim = vec![];
+        while !interim.ends_with(b"\r\n\r\n") {
+            interim.push(stream.read_u8().await.unwrap());
+        }
+        assert!(interim.starts_with(b"HTTP/1.1 100 Continue"));
+        assert!(started.elapsed() >= Duration::from_millis(100));
+
+        stream.write_all(b"hello").await.unwrap();
+        let mut response = vec![];
+        while !response.ends_with(b"false hello") {
+            response.push(stream.read_u8().await.unwrap());
+        }
+        assert!(response.starts_with(b"HTTP/1.1 200"));
+    }
+
+    #[tokio::test]
+    async fn test_websocket() {
+        // the upstream echoes the bytes after the upgrade
+        let upstream =
+            Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service_fn(|_| async {
+                Ok::<_, Infallible>(service_fn(|mut request: Request<Body>| async move {
+                    tokio::spawn(async move {
+                        let mut upgraded = hyper::upgrade::on(&mut request).await.unwrap();
+                        let mut buf = vec![0; 1024];
+                        loop {
+                            let n = upgraded.read(&mut buf).await.unwrap();
+                            if n == 0 {
+                                break;
+                            }
+                            upgraded.write_all(&buf[..n]).await.unwrap();
+                        }
+                    });
+                    Ok::<_, Infallible>(
+                        Response::builder()
+                            .status(StatusCode::SWITCHING_PROTOCOLS)
+                            .header(CONNECTION, "Upgrade")
+                            .header(UPGRADE, "websocket")
+                            .body(Body::empty())
+                            .unwrap(),
+                    )
+                }))
+            }));
+        let upstream_addr = upstream.local_addr();
+        tokio::spawn(upstream);
+        let proxy_addr = proxy(
+            upstream_addr,
+            serde_json::json!({"websocket": {"delay": "10ms"}}),
+        )
+        .await;
+
+        // the frame is sent along with the upgrade request
+        let frame = [0x81, 0x82, 0, 0, 0, 0, b'h', b'i'];
+        let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+        let mut request = b"GET /ws HTTP/1.1\r\nHost: ws.test\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\n".to_vec();
+        request.extend_from_slice(&frame);
+        stream.write_all(&request).await.unwrap();
+
+        let mut head = vec![];
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(stream.read_u8().await.unwrap());
+        }
+        assert!(head.starts_with(b"HTTP/1.1 101"));
+        let mut echoed = [0; 8];
+        stream.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(echoed, frame);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rule_lifetimes_across_reloads() {
+        let rule = |name: &str| {
+            serde_json::json!({
+                "name": name,
+                "duration": "10m",
+                "target": "Request",
+                "selector": {},
+                "actions": {"abort": true},
+            })
+        };
+        let metrics = Metrics::new(None, None);
+        metrics.rules_loaded(&http_config(vec![rule("abort-api")]));
+        tokio::time::advance(Duration::from_secs(300)).await;
+
+        // the rule inserted before the other does not take its start, nor does the rule of the
+        // same name of a listener
+        let config = HTTPConfig {
+            listener_rules: [(1026, http_config(vec![rule("abort-api")]).rules)].into(),
+            ..http_config(vec![rule("abort-web"), rule("abort-api")])
+        };
+        metrics.rules_loaded(&config);
+        tokio::time::advance(Duration::from_secs(300)).await;
+        let now = config.clock.now();
+        assert!(!metrics.expired(None, 0, &config.rules[0], now));
+        assert!(metrics.expired(None, 1, &config.rules[1], now));
+        let listener = config.for_listener(1026);
+        assert!(!metrics.expired(listener.rule_set, 0, &listener.rules[0], now));
+
+        // the rule removed and added back starts again
+        metrics.rules_loaded(&http_config(vec![rule("abort-web")]));
+        metrics.rules_loaded(&config);
+        assert!(!metrics.expired(None, 1, &config.rules[1], now));
     }
 }
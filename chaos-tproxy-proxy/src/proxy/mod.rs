@@ -1,2 +1,4 @@
+pub mod dns;
+pub mod drain;
 pub mod http;
 pub mod tcp;
@@ -0,0 +1,247 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use ipnetwork::IpNetwork;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::timeout;
+
+/// ConnectionLimit caps the concurrent connections forwarded to each of the matched original
+/// destinations, emulating an upstream with exhausted connection capacity.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionLimit {
+    /// network of the destinations, all by default.
+    pub network: Option<IpNetwork>,
+    /// port of the destinations, all by default.
+    pub port: Option<u16>,
+    pub max: usize,
+    pub excess: Excess,
+}
+
+/// Excess introduces how the connections over the limit are handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Excess {
+    /// Hold the connection until a slot is free, or reset it after the timeout.
+    Queue { timeout: Option<Duration> },
+    /// Reset the connection at once.
+    Reject,
+}
+
+impl Excess {
+    /// acquire takes a slot of the semaphore, None is returned if there is none in time.
+    async fn acquire(self, semaphore: Arc<Semaphore>) -> Option<OwnedSemaphorePermit> {
+        match self {
+            Excess::Reject => semaphore.try_acquire_owned().ok(),
+            Excess::Queue { timeout: None } => semaphore.acquire_owned().await.ok(),
+            Excess::Queue {
+                timeout: Some(duration),
+            } => timeout(duration, semaphore.acquire_owned())
+                .await
+                .ok()
+                .and_then(Result::ok),
+        }
+    }
+}
+
+/// Capacity caps the concurrent client connections or requests in flight of the proxy itself, so
+/// that a runaway load is pushed back instead of exhausting the file descriptors of the node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capacity {
+    pub max: usize,
+    pub excess: Excess,
+    /// queue_size bounds the excess ones waiting for a slot, the others are rejected at once.
+    pub queue_size: usize,
+}
+
+/// CapacityLimiter keeps the slots of a [Capacity].
+#[derive(Debug)]
+pub struct CapacityLimiter {
+    capacity: Capacity,
+    semaphore: Arc<Semaphore>,
+    /// queued counts the ones waiting for a slot.
+    queued: AtomicUsize,
+}
+
+impl CapacityLimiter {
+    pub fn new(capacity: Capacity) -> Self {
+        Self {
+            capacity,
+            semaphore: Arc::new(Semaphore::new(capacity.max)),
+            queued: AtomicUsize::new(0),
+        }
+    }
+
+    /// acquire waits for a slot, the permit should be held until the connection is closed or the
+    /// response is sent. An error is returned if it should be rejected.
+    pub async fn acquire(&self) -> Result<OwnedSemaphorePermit> {
+        if let Ok(permit) = self.semaphore.clone().try_acquire_owned() {
+            return Ok(permit);
+        }
+        let over = || anyhow!("capacity {} is reached", self.capacity.max);
+        if self.queued.fetch_add(1, Ordering::SeqCst) >= self.capacity.queue_size {
+            self.queued.fetch_sub(1, Ordering::SeqCst);
+            return Err(over());
+        }
+        // the count is kept even if the waiter is dropped
+        let _queued = Queued(&self.queued);
+        self.capacity
+            .excess
+            .acquire(self.semaphore.clone())
+            .await
+            .ok_or_else(over)
+    }
+}
+
+/// Queued leaves the queue when dropped.
+struct Queued<'a>(&'a AtomicUsize);
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl ConnectionLimit {
+    fn matches(&self, target: &SocketAddr) -> bool {
+        self.network
+            .map(|network| network.contains(target.ip()))
+            .unwrap_or(true)
+            && self.port.map(|port| port == target.port()).unwrap_or(true)
+    }
+}
+
+/// ConnectionLimiter keeps a semaphore for each destination, the first matched limit applies.
+#[derive(Debug, Default)]
+pub struct ConnectionLimiter {
+    limits: Vec<ConnectionLimit>,
+    semaphores: Mutex<HashMap<SocketAddr, Arc<Semaphore>>>,
+}
+
+impl ConnectionLimiter {
+    pub fn new(limits: Vec<ConnectionLimit>) -> Self {
+        Self {
+            limits,
+            semaphores: Default::default(),
+        }
+    }
+
+    /// acquire waits for a slot of the destination, the permit should be held until the
+    /// connection is closed. `None` is returned if the destination is unlimited, and an error if
+    /// the connection should be rejected.
+    pub async fn acquire(&self, target: SocketAddr) -> Result<Option<OwnedSemaphorePermit>> {
+        let limit = match self.limits.iter().find(|limit| limit.matches(&target)) {
+            None => return Ok(None),
+            Some(limit) => limit,
+        };
+        let semaphore = self
+            .semaphores
+            .lock()
+            .unwrap()
+            .entry(target)
+            .or_insert_with(|| Arc::new(Semaphore::new(limit.max)))
+            .clone();
+        let permit = limit.excess.acquire(semaphore).await;
+        permit.map(Some).ok_or_else(|| {
+            anyhow!(
+                "connection limit {} of destination {} is reached",
+                limit.max,
+                target
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::proxy::tcp::limit::{
+        Capacity, CapacityLimiter, ConnectionLimit, ConnectionLimiter, Excess,
+    };
+
+    #[tokio::test]
+    async fn test_acquire() {
+        let limiter = ConnectionLimiter::new(vec![
+            ConnectionLimit {
+                network: Some("10.0.0.0/8".parse().unwrap()),
+                port: Some(80),
+                max: 1,
+                excess: Excess::Reject,
+            },
+            ConnectionLimit {
+                network: None,
+                port: Some(80),
+                max: 1,
+                excess: Excess::Queue {
+                    timeout: Some(Duration::from_millis(50)),
+                },
+            },
+        ]);
+        let first = limiter
+            .acquire("10.0.0.1:80".parse().unwrap())
+            .await
+            .unwrap();
+        assert!(first.is_some());
+        assert!(limiter
+            .acquire("10.0.0.1:80".parse().unwrap())
+            .await
+            .is_err());
+        // each destination has its own slots
+        assert!(limiter
+            .acquire("10.0.0.2:80".parse().unwrap())
+            .await
+            .is_ok());
+        assert!(limiter
+            .acquire("10.0.0.1:8080".parse().unwrap())
+            .await
+            .unwrap()
+            .is_none());
+
+        let queued = limiter.acquire("192.168.0.1:80".parse().unwrap()).await;
+        assert!(queued.is_ok());
+        assert!(limiter
+            .acquire("192.168.0.1:80".parse().unwrap())
+            .await
+            .is_err());
+        drop(queued);
+        assert!(limiter
+            .acquire("192.168.0.1:80".parse().unwrap())
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_capacity() {
+        let limiter = CapacityLimiter::new(Capacity {
+            max: 1,
+            excess: Excess::Reject,
+            queue_size: 1,
+        });
+        let first = limiter.acquire().await.unwrap();
+        assert!(limiter.acquire().await.is_err());
+        drop(first);
+        assert!(limiter.acquire().await.is_ok());
+
+        let limiter = Arc::new(CapacityLimiter::new(Capacity {
+            max: 1,
+            excess: Excess::Queue {
+                timeout: Some(Duration::from_secs(1)),
+            },
+            queue_size: 1,
+        }));
+        let first = limiter.acquire().await.unwrap();
+        let queued = tokio::spawn({
+            let limiter = limiter.clone();
+            async move { limiter.acquire().await.is_ok() }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        // the queue is full
+        assert!(limiter.acquire().await.is_err());
+        drop(first);
+        assert!(queued.await.unwrap());
+    }
+}
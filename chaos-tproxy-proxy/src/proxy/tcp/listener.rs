@@ -1,9 +1,10 @@
 use std::io;
-use std::net::SocketAddr;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
 
-use tokio::net::{self, TcpStream};
+use tokio::net::{self, TcpSocket, TcpStream};
 use tracing::{debug, instrument, trace};
 
+use crate::proxy::tcp::sockopt::{tcp_socket, AsRawSocket, RawSocket, SocketOptions};
 use crate::proxy::tcp::transparent_socket::TransparentSocket;
 
 /// A stream of connections from binding to an address.
@@ -11,21 +12,66 @@ use crate::proxy::tcp::transparent_socket::TransparentSocket;
 #[must_use = "streams do nothing unless polled"]
 pub struct TcpListener {
     listener: net::TcpListener,
+    /// listener_v6 accepts the IPv6 connections of the dual-stack listener.
+    listener_v6: Option<net::TcpListener>,
     tcp_nodelay: bool,
 }
 
 impl TcpListener {
-    /// Creates a new `TcpIncoming` binding to provided socket address.
+    /// Creates a new `TcpIncoming` binding to provided socket address. The address may be bound
+    /// by several listeners with `reuse_port`, the kernel spreads the connections among them.
     #[instrument]
-    pub fn bind(addr: SocketAddr) -> io::Result<Self> {
-        let socket = TransparentSocket::bind(addr)?;
+    pub fn bind(addr: SocketAddr, options: &SocketOptions, reuse_port: bool) -> io::Result<Self> {
+        let socket = TransparentSocket::socket(&addr)?;
+        Ok(Self {
+            listener: listen(socket, addr, options, reuse_port)?,
+            listener_v6: None,
+            tcp_nodelay: options.nodelay,
+        })
+    }
 
+    /// bind_plain binds the address without IP_TRANSPARENT, e.g. for the explicit proxy the clients
+    /// connect to directly.
+    pub fn bind_plain(addr: SocketAddr, options: &SocketOptions) -> io::Result<Self> {
+        let socket = tcp_socket(&addr)?;
+        socket.set_reuseaddr(true)?;
         Ok(Self {
-            listener: socket.listen(1024)?,
-            tcp_nodelay: true,
+            listener: listen(socket, addr, options, false)?,
+            listener_v6: None,
+            tcp_nodelay: options.nodelay,
         })
     }
 
+    /// bind_dual_stack binds the port of both the IPv4 and the IPv6 unspecified addresses, only
+    /// the IPv4 one is bound if IPv6 is unavailable on the host.
+    pub fn bind_dual_stack(
+        port: u16,
+        options: &SocketOptions,
+        reuse_port: bool,
+    ) -> io::Result<Self> {
+        let mut listener = Self::bind(
+            SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)),
+            options,
+            reuse_port,
+        )?;
+        let addr = SocketAddr::from((Ipv6Addr::UNSPECIFIED, port));
+        match TransparentSocket::socket(&addr)
+            .and_then(|socket| listen(socket, addr, options, reuse_port))
+        {
+            Ok(listener_v6) => listener.listener_v6 = Some(listener_v6),
+            Err(e) => debug!("IPv6 is unavailable on port {}: {}", port, e),
+        }
+        Ok(listener)
+    }
+
+    /// raw_fds returns the IPv4 and the IPv6 (if any) listening sockets.
+    pub fn raw_fds(&self) -> (RawSocket, Option<RawSocket>) {
+        (
+            self.listener.raw_socket(),
+            self.listener_v6.as_ref().map(AsRawSocket::raw_socket),
+        )
+    }
+
     /// Set the value of `TCP_NODELAY` option for accepted connections.
     pub fn set_nodelay(&mut self, enabled: bool) -> &mut Self {
         self.tcp_nodelay = enabled;
@@ -35,7 +81,14 @@ impl TcpListener {
     /// accept TcpStream.
     pub async fn accept(&self) -> io::Result<TcpStream> {
         loop {
-            match self.listener.accept().await {
+            let accepted = match &self.listener_v6 {
+                None => self.listener.accept().await,
+                Some(listener_v6) => tokio::select! {
+                    accepted = self.listener.accept() => accepted,
+                    accepted = listener_v6.accept() => accepted,
+                },
+            };
+            match accepted {
                 Ok((stream, _)) => {
                     if let Err(e) = stream.set_nodelay(self.tcp_nodelay) {
                         trace!("error trying to set TCP nodelay: {}", e);
@@ -57,6 +110,35 @@ impl TcpListener {
     }
 }
 
+/// listen binds the socket to the address and listens with the options.
+fn listen(
+    socket: TcpSocket,
+    addr: SocketAddr,
+    options: &SocketOptions,
+    reuse_port: bool,
+) -> io::Result<net::TcpListener> {
+    if reuse_port {
+        set_reuseport(&socket)?;
+    }
+    options.set_buffers(&socket)?;
+    socket.bind(addr)?;
+    socket.listen(options.backlog)
+}
+
+/// set_reuseport sets SO_REUSEPORT, which is only available on Unix.
+#[cfg(unix)]
+fn set_reuseport(socket: &TcpSocket) -> io::Result<()> {
+    socket.set_reuseport(true)
+}
+
+#[cfg(not(unix))]
+fn set_reuseport(_socket: &TcpSocket) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "SO_REUSEPORT is only available on Unix",
+    ))
+}
+
 /// This function defines errors that are per-connection. Which basically
 /// means that if we get this error from `accept()` system call it means
 /// next connection might be ready to be accepted.
@@ -1,2 +1,8 @@
+pub mod limit;
 pub mod listener;
+pub mod proxy_protocol;
+// the connections are steered by eBPF on Linux only
+#[cfg(target_os = "linux")]
+pub mod sk_lookup;
+pub mod sockopt;
 pub mod transparent_socket;
@@ -0,0 +1,241 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// SIGNATURE starts the binary headers of PROXY protocol v2.
+const SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// MAX_V1_LENGTH is the longest header of PROXY protocol v1, including the CRLF.
+const MAX_V1_LENGTH: usize = 107;
+
+/// ProxyProtocol introduces whether the PROXY protocol headers are read from the clients, e.g. a
+/// load balancer, and written to the original destinations.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ProxyProtocol {
+    /// accept would read a header (v1 or v2) at the beginning of each connection, the source of
+    /// the header is taken as the client.
+    pub accept: bool,
+    /// send would write a header of the version at the beginning of each connection to the
+    /// original destinations, carrying the client and the original destination.
+    pub send: Option<Version>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    V1,
+    V2,
+}
+
+/// read_header reads a PROXY header from the beginning of the stream, without consuming any byte
+/// after it. The source and the destination are returned, `None` if the header carries no address,
+/// e.g. `UNKNOWN` of v1 or `LOCAL` of v2.
+pub async fn read_header<R: AsyncRead + Unpin>(
+    stream: &mut R,
+) -> io::Result<Option<(SocketAddr, SocketAddr)>> {
+    let mut head = [0u8; 6];
+    stream.read_exact(&mut head).await?;
+    if head == *b"PROXY " {
+        return read_v1(stream).await;
+    }
+    if head != SIGNATURE[..6] {
+        return Err(invalid("missing PROXY protocol header"));
+    }
+    let mut rest = [0u8; 10];
+    stream.read_exact(&mut rest).await?;
+    if rest[..6] != SIGNATURE[6..] {
+        return Err(invalid("invalid PROXY protocol v2 signature"));
+    }
+    let (version_command, family) = (rest[6], rest[7]);
+    let mut addresses = vec![0; u16::from_be_bytes([rest[8], rest[9]]) as usize];
+    stream.read_exact(&mut addresses).await?;
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+    // the health checks of the load balancers are sent with the LOCAL command
+    if version_command & 0x0f == 0 {
+        return Ok(None);
+    }
+    let addresses = &addresses[..];
+    match family {
+        // TCP over IPv4, the TLVs after the addresses are ignored
+        0x11 if addresses.len() >= 12 => {
+            let ip = |at: usize| {
+                IpAddr::from(Ipv4Addr::new(
+                    addresses[at],
+                    addresses[at + 1],
+                    addresses[at + 2],
+                    addresses[at + 3],
+                ))
+            };
+            let port = |at: usize| u16::from_be_bytes([addresses[at], addresses[at + 1]]);
+            Ok(Some((
+                SocketAddr::new(ip(0), port(8)),
+                SocketAddr::new(ip(4), port(10)),
+            )))
+        }
+        // TCP over IPv6
+        0x21 if addresses.len() >= 36 => {
+            let ip = |at: usize| {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&addresses[at..at + 16]);
+                IpAddr::from(Ipv6Addr::from(octets))
+            };
+            let port = |at: usize| u16::from_be_bytes([addresses[at], addresses[at + 1]]);
+            Ok(Some((
+                SocketAddr::new(ip(0), port(32)),
+                SocketAddr::new(ip(16), port(34)),
+            )))
+        }
+        0x11 | 0x21 => Err(invalid("truncated PROXY protocol v2 addresses")),
+        // UNSPEC, UDP and unix sockets
+        _ => Ok(None),
+    }
+}
+
+/// read_v1 reads the rest of a v1 header after `PROXY `, byte by byte to leave the payload.
+async fn read_v1<R: AsyncRead + Unpin>(
+    stream: &mut R,
+) -> io::Result<Option<(SocketAddr, SocketAddr)>> {
+    let mut line = b"PROXY ".to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= MAX_V1_LENGTH {
+            return Err(invalid("PROXY protocol v1 header is too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| invalid("invalid PROXY protocol v1 header"))?;
+    let fields: Vec<_> = line.split(' ').collect();
+    match fields[..] {
+        [_, "UNKNOWN", ..] => Ok(None),
+        [_, "TCP4" | "TCP6", source, destination, source_port, destination_port] => {
+            let addr = |ip: &str, port: &str| -> io::Result<SocketAddr> {
+                Ok(SocketAddr::new(
+                    ip.parse()
+                        .map_err(|_| invalid("invalid address of PROXY protocol v1 header"))?,
+                    port.parse()
+                        .map_err(|_| invalid("invalid port of PROXY protocol v1 header"))?,
+                ))
+            };
+            Ok(Some((
+                addr(source, source_port)?,
+                addr(destination, destination_port)?,
+            )))
+        }
+        _ => Err(invalid("invalid PROXY protocol v1 header")),
+    }
+}
+
+/// encode_header builds the header of the connection from the source to the destination, the
+/// addresses are omitted if their families differ.
+pub fn encode_header(version: Version, source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+    match version {
+        Version::V1 => match (source.ip(), destination.ip()) {
+            (IpAddr::V4(_), IpAddr::V4(_)) | (IpAddr::V6(_), IpAddr::V6(_)) => format!(
+                "PROXY {} {} {} {} {}\r\n",
+                if source.is_ipv4() { "TCP4" } else { "TCP6" },
+                source.ip(),
+                destination.ip(),
+                source.port(),
+                destination.port()
+            )
+            .into_bytes(),
+            _ => b"PROXY UNKNOWN\r\n".to_vec(),
+        },
+        Version::V2 => {
+            let mut header = SIGNATURE.to_vec();
+            let addresses = match (source.ip(), destination.ip()) {
+                (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                    Some((0x11, [&src.octets()[..], &dst.octets()[..]].concat()))
+                }
+                (IpAddr::V6(src), IpAddr::V6(dst)) => {
+                    Some((0x21, [&src.octets()[..], &dst.octets()[..]].concat()))
+                }
+                _ => None,
+            };
+            match addresses {
+                Some((family, mut addresses)) => {
+                    addresses.extend_from_slice(&source.port().to_be_bytes());
+                    addresses.extend_from_slice(&destination.port().to_be_bytes());
+                    // version 2, PROXY command
+                    header.extend_from_slice(&[0x21, family]);
+                    header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+                    header.extend_from_slice(&addresses);
+                }
+                // version 2, LOCAL command, UNSPEC family
+                None => header.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]),
+            }
+            header
+        }
+    }
+}
+
+/// write_header writes the header of the connection from the source to the destination.
+pub async fn write_header<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    version: Version,
+    source: SocketAddr,
+    destination: SocketAddr,
+) -> io::Result<()> {
+    stream
+        .write_all(&encode_header(version, source, destination))
+        .await
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::io::AsyncReadExt;
+
+    use crate::proxy::tcp::proxy_protocol::{encode_header, read_header, Version};
+
+    #[tokio::test]
+    async fn test_header() {
+        let pairs: [(SocketAddr, SocketAddr); 2] = [
+            (
+                "192.168.0.1:56324".parse().unwrap(),
+                "10.0.0.1:443".parse().unwrap(),
+            ),
+            (
+                "[2001:db8::1]:56324".parse().unwrap(),
+                "[2001:db8::2]:443".parse().unwrap(),
+            ),
+        ];
+        for (source, destination) in pairs {
+            for version in [Version::V1, Version::V2] {
+                let mut data = encode_header(version, source, destination);
+                data.extend_from_slice(b"GET / HTTP/1.1\r\n");
+                let mut stream = &data[..];
+                assert_eq!(
+                    read_header(&mut stream).await.unwrap(),
+                    Some((source, destination))
+                );
+                // the payload is left untouched
+                let mut payload = vec![];
+                stream.read_to_end(&mut payload).await.unwrap();
+                assert_eq!(payload, b"GET / HTTP/1.1\r\n");
+            }
+        }
+
+        let mixed = encode_header(
+            Version::V2,
+            "192.168.0.1:56324".parse().unwrap(),
+            "[2001:db8::2]:443".parse().unwrap(),
+        );
+        assert_eq!(read_header(&mut &mixed[..]).await.unwrap(), None);
+        assert_eq!(
+            read_header(&mut &b"PROXY UNKNOWN\r\n"[..]).await.unwrap(),
+            None
+        );
+        assert!(read_header(&mut &b"GET / HTTP/1.1\r\n"[..]).await.is_err());
+        assert!(read_header(&mut &b"PROXY TCP4 1.1.1.1\r\n"[..])
+            .await
+            .is_err());
+    }
+}
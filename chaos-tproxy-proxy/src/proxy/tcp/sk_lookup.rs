@@ -0,0 +1,362 @@
+use std::fs::File;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use crate::proxy::http::config::SkLookupConfig;
+
+/// The IPv4 and the IPv6 socket of the `n`th listener are at `2n` and `2n + 1` of the sockmap.
+const SOCKETS_PER_LISTENER: u32 = 2;
+
+const BPF_MAP_CREATE: libc::c_long = 0;
+const BPF_MAP_UPDATE_ELEM: libc::c_long = 2;
+const BPF_PROG_LOAD: libc::c_long = 5;
+const BPF_LINK_CREATE: libc::c_long = 28;
+
+const BPF_MAP_TYPE_HASH: u32 = 1;
+const BPF_MAP_TYPE_SOCKMAP: u32 = 15;
+const BPF_PROG_TYPE_SK_LOOKUP: u32 = 30;
+const BPF_SK_LOOKUP: u32 = 36;
+const BPF_PSEUDO_MAP_FD: u8 = 1;
+
+const BPF_FUNC_MAP_LOOKUP_ELEM: i32 = 1;
+const BPF_FUNC_SK_RELEASE: i32 = 86;
+const BPF_FUNC_SK_ASSIGN: i32 = 124;
+
+/// The offsets of the fields of `struct bpf_sk_lookup`.
+const CTX_FAMILY: i16 = 8;
+const CTX_PROTOCOL: i16 = 12;
+const CTX_LOCAL_PORT: i16 = 60;
+
+const SK_PASS: i32 = 1;
+
+/// SkLookup is the program attached to the netns, it is detached once dropped.
+pub struct SkLookup {
+    _link: Fd,
+    _program: Fd,
+    _ports: Fd,
+    _sockets: Fd,
+}
+
+impl SkLookup {
+    /// attach attaches the program steering the ports to the sockets, the IPv4 and the optional
+    /// IPv6 listening socket of each listener, in the order of the ports of the config.
+    pub fn attach(config: &SkLookupConfig, sockets: &[(RawFd, Option<RawFd>)]) -> io::Result<Self> {
+        let ports = Fd::map(BPF_MAP_TYPE_HASH, 4, 4, u16::MAX as u32 + 1)?;
+        let sockmap = Fd::map(
+            BPF_MAP_TYPE_SOCKMAP,
+            4,
+            8,
+            sockets.len() as u32 * SOCKETS_PER_LISTENER,
+        )?;
+        for (index, (listener_ports, (v4, v6))) in config.ports.iter().zip(sockets).enumerate() {
+            let slot = index as u32 * SOCKETS_PER_LISTENER;
+            for port in listener_ports.iter().flat_map(|range| range.clone()) {
+                ports.update(&(port as u32).to_ne_bytes(), &slot.to_ne_bytes())?;
+            }
+            sockmap.update(&slot.to_ne_bytes(), &(*v4 as u64).to_ne_bytes())?;
+            if let Some(v6) = v6 {
+                sockmap.update(&(slot + 1).to_ne_bytes(), &(*v6 as u64).to_ne_bytes())?;
+            }
+        }
+        let program = Fd::program(&program(ports.0, sockmap.0))?;
+        let netns = File::open("/proc/self/ns/net")?;
+        let link = Fd::link(program.0, netns.as_raw_fd())?;
+        Ok(Self {
+            _link: link,
+            _program: program,
+            _ports: ports,
+            _sockets: sockmap,
+        })
+    }
+}
+
+/// program assigns the socket of the listener the local port is steered to, the connections of
+/// the other ports are looked up as usual.
+fn program(ports: RawFd, sockets: RawFd) -> Vec<Insn> {
+    vec![
+        Insn::mov_reg(6, 1),
+        Insn::load(2, 6, CTX_PROTOCOL),
+        Insn::jump_ne(2, libc::IPPROTO_TCP, 26),
+        Insn::load(2, 6, CTX_LOCAL_PORT),
+        Insn::store(10, 2, -4),
+        Insn::mov_reg(2, 10),
+        Insn::add(2, -4),
+        Insn::load_map(1, ports),
+        Insn::load_map_next(),
+        Insn::call(BPF_FUNC_MAP_LOOKUP_ELEM),
+        Insn::jump_eq(0, 0, 18),
+        // the slot of the listener, plus one for the IPv6 socket
+        Insn::load(2, 0, 0),
+        Insn::load(3, 6, CTX_FAMILY),
+        Insn::jump_ne(3, libc::AF_INET6, 1),
+        Insn::add(2, 1),
+        Insn::store(10, 2, -8),
+        Insn::mov_reg(2, 10),
+        Insn::add(2, -8),
+        Insn::load_map(1, sockets),
+        Insn::load_map_next(),
+        Insn::call(BPF_FUNC_MAP_LOOKUP_ELEM),
+        Insn::jump_eq(0, 0, 7),
+        Insn::mov_reg(7, 0),
+        Insn::mov_reg(1, 6),
+        Insn::mov_reg(2, 7),
+        Insn::mov_imm(3, 0),
+        Insn::call(BPF_FUNC_SK_ASSIGN),
+        Insn::mov_reg(1, 7),
+        Insn::call(BPF_FUNC_SK_RELEASE),
+        Insn::mov_imm(0, SK_PASS),
+        Insn::exit(),
+    ]
+}
+
+/// Insn is an instruction of eBPF, `struct bpf_insn`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Insn {
+    code: u8,
+    /// the destination register in the low 4 bits, and the source one in the high 4 bits.
+    regs: u8,
+    off: i16,
+    imm: i32,
+}
+
+impl Insn {
+    fn new(code: u8, dst: u8, src: u8, off: i16, imm: i32) -> Self {
+        Self {
+            code,
+            regs: (src << 4) | dst,
+            off,
+            imm,
+        }
+    }
+
+    fn mov_reg(dst: u8, src: u8) -> Self {
+        Self::new(0xbf, dst, src, 0, 0)
+    }
+
+    fn mov_imm(dst: u8, imm: i32) -> Self {
+        Self::new(0xb7, dst, 0, 0, imm)
+    }
+
+    fn add(dst: u8, imm: i32) -> Self {
+        Self::new(0x07, dst, 0, 0, imm)
+    }
+
+    /// load loads the u32 at `src + off`.
+    fn load(dst: u8, src: u8, off: i16) -> Self {
+        Self::new(0x61, dst, src, off, 0)
+    }
+
+    /// store stores the u32 of `src` at `dst + off`.
+    fn store(dst: u8, src: u8, off: i16) -> Self {
+        Self::new(0x63, dst, src, off, 0)
+    }
+
+    fn load_map(dst: u8, fd: RawFd) -> Self {
+        Self::new(0x18, dst, BPF_PSEUDO_MAP_FD, 0, fd)
+    }
+
+    /// load_map_next is the second half of the 64-bit load of [load_map](Self::load_map).
+    fn load_map_next() -> Self {
+        Self::new(0, 0, 0, 0, 0)
+    }
+
+    fn jump_eq(dst: u8, imm: i32, off: i16) -> Self {
+        Self::new(0x15, dst, 0, off, imm)
+    }
+
+    fn jump_ne(dst: u8, imm: i32, off: i16) -> Self {
+        Self::new(0x55, dst, 0, off, imm)
+    }
+
+    fn call(helper: i32) -> Self {
+        Self::new(0x85, 0, 0, 0, helper)
+    }
+
+    fn exit() -> Self {
+        Self::new(0x95, 0, 0, 0, 0)
+    }
+}
+
+/// Fd is a file descriptor of the bpf objects, closed once dropped.
+struct Fd(RawFd);
+
+#[repr(C)]
+#[derive(Default)]
+struct MapCreateAttr {
+    map_type: u32,
+    key_size: u32,
+    value_size: u32,
+    max_entries: u32,
+    map_flags: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct MapUpdateAttr {
+    map_fd: u32,
+    _pad: u32,
+    key: u64,
+    value: u64,
+    flags: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct ProgLoadAttr {
+    prog_type: u32,
+    insn_cnt: u32,
+    insns: u64,
+    license: u64,
+    log_level: u32,
+    log_size: u32,
+    log_buf: u64,
+    kern_version: u32,
+    prog_flags: u32,
+    prog_name: [u8; 16],
+    prog_ifindex: u32,
+    expected_attach_type: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct LinkCreateAttr {
+    prog_fd: u32,
+    target_fd: u32,
+    attach_type: u32,
+    flags: u32,
+}
+
+impl Fd {
+    fn bpf<T>(cmd: libc::c_long, attr: &T) -> io::Result<RawFd> {
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_bpf,
+                cmd,
+                attr as *const T,
+                std::mem::size_of::<T>() as u32,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(ret as RawFd)
+    }
+
+    fn map(map_type: u32, key_size: u32, value_size: u32, max_entries: u32) -> io::Result<Self> {
+        let attr = MapCreateAttr {
+            map_type,
+            key_size,
+            value_size,
+            max_entries,
+            ..Default::default()
+        };
+        Ok(Self(Self::bpf(BPF_MAP_CREATE, &attr)?))
+    }
+
+    fn update(&self, key: &[u8], value: &[u8]) -> io::Result<()> {
+        let attr = MapUpdateAttr {
+            map_fd: self.0 as u32,
+            key: key.as_ptr() as u64,
+            value: value.as_ptr() as u64,
+            ..Default::default()
+        };
+        Self::bpf(BPF_MAP_UPDATE_ELEM, &attr)?;
+        Ok(())
+    }
+
+    fn program(insns: &[Insn]) -> io::Result<Self> {
+        let license = b"Dual BSD/GPL\0";
+        let mut log = vec![0u8; 64 * 1024];
+        let mut prog_name = [0u8; 16];
+        prog_name[..12].copy_from_slice(b"chaos_tproxy");
+        let attr = ProgLoadAttr {
+            prog_type: BPF_PROG_TYPE_SK_LOOKUP,
+            insn_cnt: insns.len() as u32,
+            insns: insns.as_ptr() as u64,
+            license: license.as_ptr() as u64,
+            log_level: 1,
+            log_size: log.len() as u32,
+            log_buf: log.as_mut_ptr() as u64,
+            prog_name,
+            expected_attach_type: BPF_SK_LOOKUP,
+            ..Default::default()
+        };
+        match Self::bpf(BPF_PROG_LOAD, &attr) {
+            Ok(fd) => Ok(Self(fd)),
+            Err(e) => {
+                let end = log.iter().position(|&b| b == 0).unwrap_or(log.len());
+                Err(io::Error::new(
+                    e.kind(),
+                    format!(
+                        "fail to load sk_lookup program: {}: {}",
+                        e,
+                        String::from_utf8_lossy(&log[..end])
+                    ),
+                ))
+            }
+        }
+    }
+
+    fn link(program: RawFd, netns: RawFd) -> io::Result<Self> {
+        let attr = LinkCreateAttr {
+            prog_fd: program as u32,
+            target_fd: netns as u32,
+            attach_type: BPF_SK_LOOKUP,
+            ..Default::default()
+        };
+        Ok(Self(Self::bpf(BPF_LINK_CREATE, &attr)?))
+    }
+}
+
+impl Drop for Fd {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::proxy::tcp::sk_lookup::{program, Insn};
+    use crate::raw_config::{exclude_port_ranges, parse_port_ranges};
+
+    #[test]
+    fn test_program() {
+        let insns = program(3, 4);
+        assert_eq!(std::mem::size_of::<Insn>(), 8);
+        assert_eq!(insns[7], Insn::load_map(1, 3));
+        assert_eq!(insns[7].regs, 0x11);
+        // the jumps land on the `SK_PASS` return
+        let pass = insns.len() - 2;
+        for (index, insn) in insns.iter().enumerate() {
+            if (insn.code == 0x15 || insn.code == 0x55) && insn.off > 1 {
+                assert_eq!(index + 1 + insn.off as usize, pass);
+            }
+        }
+        assert_eq!(insns[pass], Insn::mov_imm(0, 1));
+    }
+
+    #[test]
+    fn test_parse_port_ranges() {
+        assert_eq!(
+            parse_port_ranges("80,8000:8080").unwrap(),
+            vec![80..=80, 8000..=8080]
+        );
+        assert!(parse_port_ranges("8080:8000").is_err());
+        assert!(parse_port_ranges("http").is_err());
+    }
+
+    #[test]
+    fn test_exclude_port_ranges() {
+        let excluded = parse_port_ranges("80,8040:8049,9000:9999").unwrap();
+        assert_eq!(
+            exclude_port_ranges(
+                vec![80..=80, 443..=443, 8000..=8080, 9500..=9600],
+                &excluded
+            ),
+            vec![443..=443, 8000..=8039, 8050..=8080]
+        );
+    }
+}
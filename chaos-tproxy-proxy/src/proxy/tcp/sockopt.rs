@@ -0,0 +1,322 @@
+use std::net::SocketAddr;
+#[cfg(target_os = "linux")]
+use std::net::{Ipv4Addr, Ipv6Addr};
+#[cfg(target_os = "linux")]
+use std::ptr;
+use std::time::Duration;
+use std::{io, mem};
+
+use tokio::net::{TcpSocket, TcpStream};
+use tokio::time::{sleep, Instant};
+
+/// RawSocket is the raw socket the options are set on, the file descriptor on Unix and the socket
+/// handle on Windows.
+#[cfg(unix)]
+pub type RawSocket = std::os::unix::io::RawFd;
+#[cfg(windows)]
+pub type RawSocket = std::os::windows::io::RawSocket;
+
+/// AsRawSocket borrows the raw socket of the sockets and the streams of both Unix and Windows.
+pub trait AsRawSocket {
+    fn raw_socket(&self) -> RawSocket;
+}
+
+#[cfg(unix)]
+impl<T: std::os::unix::io::AsRawFd> AsRawSocket for T {
+    fn raw_socket(&self) -> RawSocket {
+        self.as_raw_fd()
+    }
+}
+
+#[cfg(windows)]
+impl<T: std::os::windows::io::AsRawSocket> AsRawSocket for T {
+    fn raw_socket(&self) -> RawSocket {
+        self.as_raw_socket()
+    }
+}
+
+/// SocketOptions are set on the TCP sockets of both the clients and the upstreams.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct SocketOptions {
+    /// backlog of the listening sockets.
+    pub backlog: u32,
+    /// nodelay sets TCP_NODELAY, the small writes are sent at once instead of being coalesced.
+    pub nodelay: bool,
+    /// recv_buffer sets SO_RCVBUF, the kernel default is kept if not set.
+    pub recv_buffer: Option<u32>,
+    /// send_buffer sets SO_SNDBUF, the kernel default is kept if not set.
+    pub send_buffer: Option<u32>,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        Self {
+            backlog: 1024,
+            nodelay: true,
+            recv_buffer: None,
+            send_buffer: None,
+        }
+    }
+}
+
+impl SocketOptions {
+    /// set_buffers sets the buffer sizes before the socket listens or connects, so that the window
+    /// is scaled by them. The accepted sockets inherit the ones of the listening socket.
+    pub fn set_buffers(&self, socket: &TcpSocket) -> io::Result<()> {
+        if let Some(size) = self.recv_buffer {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = self.send_buffer {
+            socket.set_send_buffer_size(size)?;
+        }
+        Ok(())
+    }
+
+    /// connect connects the socket to the target with the options.
+    pub async fn connect(&self, socket: TcpSocket, target: SocketAddr) -> io::Result<TcpStream> {
+        self.set_buffers(&socket)?;
+        let stream = socket.connect(target).await?;
+        stream.set_nodelay(self.nodelay)?;
+        Ok(stream)
+    }
+}
+
+/// tcp_socket returns a plain TCP socket of the family of the address.
+pub fn tcp_socket(addr: &SocketAddr) -> io::Result<TcpSocket> {
+    if addr.is_ipv6() {
+        TcpSocket::new_v6()
+    } else {
+        TcpSocket::new_v4()
+    }
+}
+
+/// Set SO_LINGER with zero timeout, closing the socket would send a RST instead of a FIN.
+#[cfg(unix)]
+pub fn set_linger_zero(fd: RawSocket) -> io::Result<()> {
+    let linger = libc::linger {
+        l_onoff: 1,
+        l_linger: 0,
+    };
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_LINGER,
+            &linger as *const _ as *const _,
+            mem::size_of_val(&linger) as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+pub fn set_linger_zero(socket: RawSocket) -> io::Result<()> {
+    borrow_socket(socket).set_linger(Some(Duration::from_secs(0)))
+}
+
+/// wait_sent waits until the data written to the socket is acknowledged by the peer, at most for
+/// the timeout, so that a reset sent after it would not discard the data. The send queue is only
+/// told on Linux, elsewhere the writer is only given a moment to flush.
+pub async fn wait_sent(fd: RawSocket, timeout: Duration) {
+    let deadline = Instant::now() + timeout;
+    // the writer flushes between the checks, the queue is empty twice in a row once it is done
+    let mut empty = 0;
+    while empty < 2 && Instant::now() < deadline {
+        sleep(Duration::from_millis(1)).await;
+        empty = match unsent(fd) {
+            Some(0) => empty + 1,
+            Some(_) => 0,
+            None => break,
+        };
+    }
+}
+
+/// unsent returns the bytes in the send queue of the socket, sent or not but not acknowledged.
+#[cfg(target_os = "linux")]
+fn unsent(fd: RawSocket) -> Option<usize> {
+    let mut queued: libc::c_int = 0;
+    let ret = unsafe { libc::ioctl(fd, libc::TIOCOUTQ, &mut queued) };
+    (ret == 0).then_some(queued as usize)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unsent(_fd: RawSocket) -> Option<usize> {
+    None
+}
+
+/// Set IPV6_V6ONLY, the IPv6 socket would not accept the IPv4 connections as mapped addresses.
+#[cfg(unix)]
+pub fn set_only_v6(fd: RawSocket) -> io::Result<()> {
+    set_flag(fd, libc::IPPROTO_IPV6, libc::IPV6_V6ONLY)
+}
+
+#[cfg(windows)]
+pub fn set_only_v6(socket: RawSocket) -> io::Result<()> {
+    borrow_socket(socket).set_only_v6(true)
+}
+
+/// borrow_socket wraps the raw socket to set its options, it is not closed once dropped.
+#[cfg(windows)]
+fn borrow_socket(socket: RawSocket) -> mem::ManuallyDrop<socket2::Socket> {
+    use std::os::windows::io::FromRawSocket;
+
+    mem::ManuallyDrop::new(unsafe { socket2::Socket::from_raw_socket(socket) })
+}
+
+/// unsupported is the error of the redirection by the kernel off Linux, e.g. in the dev mode on
+/// macOS, where only the explicit proxy is available.
+#[cfg(not(target_os = "linux"))]
+pub fn unsupported(feature: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("{} is only available on Linux", feature),
+    )
+}
+
+/// Set IP_RECVORIGDSTADDR (IPV6_RECVORIGDSTADDR of the IPv6 sockets), the original destinations
+/// of the datagrams redirected by the tproxy would be received by [recv_orig_dst].
+#[cfg(target_os = "linux")]
+pub fn set_recv_orig_dst(fd: RawSocket, ipv6: bool) -> io::Result<()> {
+    if ipv6 {
+        set_flag(fd, libc::SOL_IPV6, libc::IPV6_RECVORIGDSTADDR)
+    } else {
+        set_flag(fd, libc::SOL_IP, libc::IP_RECVORIGDSTADDR)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_recv_orig_dst(_fd: RawSocket, _ipv6: bool) -> io::Result<()> {
+    Err(unsupported("IP_RECVORIGDSTADDR"))
+}
+
+#[cfg(unix)]
+fn set_flag(fd: RawSocket, level: libc::c_int, name: libc::c_int) -> io::Result<()> {
+    let enable: libc::c_int = 1;
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &enable as *const _ as *const _,
+            mem::size_of_val(&enable) as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// recv_orig_dst receives a datagram from the non-blocking socket with the original destination
+/// option set, and returns its length, source and original destination.
+#[cfg(target_os = "linux")]
+pub fn recv_orig_dst(fd: RawSocket, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, SocketAddr)> {
+    let mut src: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut _,
+        iov_len: buf.len(),
+    };
+    // aligned for the cmsghdr
+    let mut control = [0u64; 8];
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_name = &mut src as *mut _ as *mut _;
+    msg.msg_namelen = mem::size_of_val(&src) as libc::socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut _;
+    msg.msg_controllen = mem::size_of_val(&control) as _;
+
+    let n = unsafe { libc::recvmsg(fd, &mut msg, 0) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut dst = None;
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+                (libc::SOL_IP, libc::IP_ORIGDSTADDR) => {
+                    let addr: libc::sockaddr_in =
+                        ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::sockaddr_in);
+                    dst = Some(from_sockaddr_in(&addr));
+                }
+                (libc::SOL_IPV6, libc::IPV6_ORIGDSTADDR) => {
+                    let addr: libc::sockaddr_in6 =
+                        ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::sockaddr_in6);
+                    dst = Some(from_sockaddr_in6(&addr));
+                }
+                _ => {}
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    let dst = dst.ok_or_else(|| io::Error::other("no original destination of datagram"))?;
+    let src = match src.ss_family as libc::c_int {
+        libc::AF_INET => from_sockaddr_in(unsafe {
+            &*(&src as *const libc::sockaddr_storage as *const libc::sockaddr_in)
+        }),
+        libc::AF_INET6 => from_sockaddr_in6(unsafe {
+            &*(&src as *const libc::sockaddr_storage as *const libc::sockaddr_in6)
+        }),
+        family => {
+            return Err(io::Error::other(format!(
+                "unknown address family {} of datagram",
+                family
+            )))
+        }
+    };
+    Ok((n as usize, src, dst))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn recv_orig_dst(
+    _fd: RawSocket,
+    _buf: &mut [u8],
+) -> io::Result<(usize, SocketAddr, SocketAddr)> {
+    Err(unsupported("IP_ORIGDSTADDR"))
+}
+
+#[cfg(target_os = "linux")]
+fn from_sockaddr_in(addr: &libc::sockaddr_in) -> SocketAddr {
+    SocketAddr::from((
+        Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+        u16::from_be(addr.sin_port),
+    ))
+}
+
+#[cfg(target_os = "linux")]
+fn from_sockaddr_in6(addr: &libc::sockaddr_in6) -> SocketAddr {
+    SocketAddr::from((
+        Ipv6Addr::from(addr.sin6_addr.s6_addr),
+        u16::from_be(addr.sin6_port),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use crate::proxy::tcp::sockopt::{tcp_socket, SocketOptions};
+
+    #[tokio::test]
+    async fn test_connect_with_options() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let options = SocketOptions::default();
+        let stream = options.connect(tcp_socket(&addr).unwrap(), addr).await;
+        assert!(stream.unwrap().nodelay().unwrap());
+
+        let options = SocketOptions {
+            nodelay: false,
+            recv_buffer: Some(64 * 1024),
+            send_buffer: Some(64 * 1024),
+            ..Default::default()
+        };
+        let stream = options.connect(tcp_socket(&addr).unwrap(), addr).await;
+        assert!(!stream.unwrap().nodelay().unwrap());
+    }
+}
@@ -1,10 +1,16 @@
+use std::io;
+#[cfg(target_os = "linux")]
+use std::mem;
 use std::net::SocketAddr;
-use std::os::unix::io::AsRawFd;
-use std::{io, mem};
 
-use tokio::net::{TcpSocket, TcpStream};
+use socket2::{Domain, Socket, Type};
+use tokio::net::{TcpSocket, TcpStream, UdpSocket};
 
-/// A socket generator with IP_TRANSPARENT flag.
+#[cfg(not(target_os = "linux"))]
+use crate::proxy::tcp::sockopt::unsupported;
+use crate::proxy::tcp::sockopt::{set_only_v6, tcp_socket, AsRawSocket, RawSocket, SocketOptions};
+
+/// A socket generator with IP_TRANSPARENT (or IPV6_TRANSPARENT) flag.
 /// User can Clone this instead of clone a linux socket which may bring mistake.
 #[derive(Debug, Clone)]
 pub struct TransparentSocket {
@@ -17,34 +23,63 @@ impl TransparentSocket {
     }
 
     pub fn bind(addr: SocketAddr) -> io::Result<TcpSocket> {
-        let socket = TransparentSocket::set_socket()?;
+        let socket = TransparentSocket::socket(&addr)?;
         socket.bind(addr)?;
         Ok(socket)
     }
 
-    pub async fn conn(&self, dist: SocketAddr) -> io::Result<TcpStream> {
-        let socket = TransparentSocket::set_socket()?;
-        socket.bind(self.addr)?;
-        socket.connect(dist).await
+    /// bind_udp binds a UDP socket to the address, which may be non-local, e.g. to send the
+    /// datagrams from the original destination.
+    pub fn bind_udp(addr: SocketAddr) -> io::Result<UdpSocket> {
+        let domain = if addr.is_ipv6() {
+            Domain::ipv6()
+        } else {
+            Domain::ipv4()
+        };
+        let socket = Socket::new(domain, Type::dgram(), None)?;
+        TransparentSocket::set_ip_transparent(socket.raw_socket(), addr.is_ipv6())?;
+        if addr.is_ipv6() {
+            socket.set_only_v6(true)?;
+        }
+        socket.set_reuse_address(true)?;
+        socket.bind(&addr.into())?;
+        socket.set_nonblocking(true)?;
+        UdpSocket::from_std(socket.into_udp_socket())
+    }
+
+    pub async fn conn(&self, dist: SocketAddr, options: &SocketOptions) -> io::Result<TcpStream> {
+        options
+            .connect(TransparentSocket::bind(self.addr)?, dist)
+            .await
     }
 
-    fn set_socket() -> io::Result<TcpSocket> {
-        let socket = TcpSocket::new_v4()?;
-        TransparentSocket::set_ip_transparent(&socket)?;
+    /// socket returns an unbound socket of the family of the address, the IPv6 ones never accept
+    /// the IPv4 connections, which are served by the IPv4 sockets.
+    pub fn socket(addr: &SocketAddr) -> io::Result<TcpSocket> {
+        let socket = tcp_socket(addr)?;
+        TransparentSocket::set_ip_transparent(socket.raw_socket(), addr.is_ipv6())?;
+        if addr.is_ipv6() {
+            set_only_v6(socket.raw_socket())?;
+        }
         socket.set_reuseaddr(true)?;
         Ok(socket)
     }
 
-    /// Set IP_TRANSPARENT for use of tproxy.
+    /// Set IP_TRANSPARENT (IPV6_TRANSPARENT of the IPv6 sockets) for use of tproxy.
     /// User may need to get root privilege to use it.
-    fn set_ip_transparent(socket: &TcpSocket) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    fn set_ip_transparent(socket_fd: RawSocket, ipv6: bool) -> io::Result<()> {
+        let (level, name) = if ipv6 {
+            (libc::SOL_IPV6, libc::IPV6_TRANSPARENT)
+        } else {
+            (libc::SOL_IP, libc::IP_TRANSPARENT)
+        };
         unsafe {
-            let socket_fd = socket.as_raw_fd();
             let enable: libc::c_int = 1;
             let ret = libc::setsockopt(
                 socket_fd,
-                libc::SOL_IP,
-                libc::IP_TRANSPARENT,
+                level,
+                name,
                 &enable as *const _ as *const _,
                 mem::size_of_val(&enable) as libc::socklen_t,
             );
@@ -55,4 +90,9 @@ impl TransparentSocket {
         };
         Ok(())
     }
+
+    #[cfg(not(target_os = "linux"))]
+    fn set_ip_transparent(_socket_fd: RawSocket, _ipv6: bool) -> io::Result<()> {
+        Err(unsupported("IP_TRANSPARENT"))
+    }
 }
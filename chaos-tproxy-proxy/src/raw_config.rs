@@ -1,13 +1,16 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::convert::{TryFrom, TryInto};
-use std::net::Ipv4Addr;
-use std::path::PathBuf;
-use std::time::Duration;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 use std::{fs, io};
 
 use anyhow::{anyhow, Error};
-use http::header::{HeaderMap, HeaderName};
-use http::StatusCode;
+use http::header::{HeaderMap, HeaderName, HeaderValue};
+use http::{StatusCode, Uri};
+use rustls::server::AllowAnyAnonymousOrAuthenticatedClient;
 use rustls::OwnedTrustAnchor;
 use rustls_pemfile::{certs, rsa_private_keys};
 use serde::{Deserialize, Serialize};
@@ -15,28 +18,554 @@ use tokio_rustls::rustls::{Certificate, PrivateKey};
 use tokio_rustls::webpki;
 use wildmatch::WildMatch;
 
+use crate::access_log::AccessLogConfig;
+use crate::clock::SystemClock;
+use crate::coordination::{CoordinationConfig, CoordinationRole};
+use crate::experiment::ExperimentReportConfig;
+use crate::handler::dns::{DnsAction, DnsAnswer, DnsRule, TYPE_A, TYPE_AAAA};
 use crate::handler::http::action::{
-    Actions, PatchAction, PatchBodyAction, PatchBodyActionContents, ReplaceAction,
-    ReplaceBodyAction,
+    AbortMode, AbortResponse, Actions, DelayPosition, DuplicateAction, MirrorAction, PatchAction,
+    PatchBodyAction, PatchBodyActionContents, RedirectAction, ReplaceAction, ReplaceBodyAction,
+    TimeoutAction, WeightedActions,
 };
-use crate::handler::http::rule::{Rule, Target};
-use crate::handler::http::selector::Selector;
-use crate::proxy::http::config::{Config, HTTPConfig, TLSConfig};
+use crate::handler::http::body_limit::{BodyLimit, Overflow};
+use crate::handler::http::client_ip::{ClientIpAction, ClientIpHeader, ClientIpMode};
+use crate::handler::http::connect::{ConnectFailure, ConnectFault};
+use crate::handler::http::cookie::{Cookie, SameSite};
+use crate::handler::http::dedup::{DedupAction, DedupMode};
+use crate::handler::http::dribble::DribbleAction;
+use crate::handler::http::fingerprint::UserAgentSelector;
+use crate::handler::http::follow_up::FollowUp;
+use crate::handler::http::framing::Framing;
+use crate::handler::http::header_pattern::{HeaderFields, HeaderPattern};
+use crate::handler::http::latency_profile::LatencyProfile;
+use crate::handler::http::pattern::{PatternAction, Shape};
+use crate::handler::http::pressure::PressureSelector;
+use crate::handler::http::rate_limit::RateLimitAction;
+use crate::handler::http::rule::{Direction, MatchPolicy, Rule, Target};
+use crate::handler::http::segment::SegmentAction;
+use crate::handler::http::selector::{OptIn, Selector};
+use crate::handler::http::sticky::{Sticky, StickyOn};
+use crate::handler::http::time_window::TimeWindow;
+use crate::handler::http::tls_client::TlsClientSelector;
+use crate::handler::http::truncate::{TruncateAction, TruncateAfter};
+use crate::handler::http::validation::{OnViolation, ResponseValidator};
+use crate::handler::http::websocket::{WebSocketAction, WebSocketClose};
+use crate::handler::tcp::TcpAction;
+use crate::har::HarConfig;
+use crate::metadata::{CSVResolver, HTTPResolver, MaxMindResolver, MetadataResolver};
+use crate::metrics::{BaselineConfig, SLOConfig};
+use crate::pcap::PcapConfig;
+use crate::proxy::dns::DnsConfig;
+use crate::proxy::http::config::{
+    Config, HTTPConfig, ListenerConfig, RuntimeConfig, SkLookupConfig, TLSConfig,
+};
+use crate::proxy::http::explicit::{ExplicitConfig, ExplicitProtocol};
+use crate::proxy::http::mitm::MITMResolver;
+use crate::proxy::http::pool::PoolConfig;
+use crate::proxy::http::resolver::{DoHServer, Resolver};
+use crate::proxy::tcp::limit::{Capacity, ConnectionLimit, Excess};
+use crate::proxy::tcp::proxy_protocol::{ProxyProtocol, Version};
+use crate::proxy::tcp::sockopt::SocketOptions;
+use crate::report::ReportConfig;
+use crate::snapshot::SnapshotConfig;
+use crate::telemetry::TelemetryConfig;
 
-#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize, Default)]
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize, Default)]
 pub struct RawConfig {
     pub proxy_ports: Option<String>,
+    // ports never diverted even if they are in the proxy ports, e.g. `8080,9000:9100`
+    pub exclude_ports: Option<String>,
+    // networks in CIDR never diverted, the traffic to the destinations or from the sources
+    pub ignore_destinations: Option<Vec<String>>,
+    pub ignore_sources: Option<Vec<String>>,
+    // interfaces the device diverted is picked among, read by the controller
+    pub interfaces: Option<Vec<String>>,
+    // the connections diverted, both directions by default
+    pub direction: Option<RawDirection>,
     pub listen_port: u16,
     pub safe_mode: bool,
+    pub compare_mode: bool,
+    pub latency_compensation: bool,
+    // stamp the exchanges modified by the rules with the `x-chaos-tproxy-rule` and
+    // `x-chaos-tproxy-faults` headers
+    pub fault_markers: bool,
+    // parse every connection as HTTP, instead of relaying the ones no rule applies to as raw TCP
+    pub inspect_all: bool,
+    pub opt_in: Option<RawOptIn>,
+    // stamp the forwarded requests and the responses with the ID of their exchange in the header,
+    // e.g. `x-chaos-exchange`, the ID is always in the logs and the access log
+    pub exchange_id_header: Option<String>,
     pub rules: Vec<RawRule>,
+    // whether a request gets the actions of all the rules matching it, or of the first one only
+    pub match_policy: Option<RawMatchPolicy>,
     pub role: Option<Role>,
     pub tls: Option<TLSRawConfig>,
+    pub slo: Option<SLORawConfig>,
+    pub baseline: Option<RawBaselineConfig>,
+    pub metadata: Option<RawMetadataSource>,
+    pub coordination: Option<RawCoordinationConfig>,
+    pub report: Option<RawReportConfig>,
+    pub snapshot: Option<RawSnapshotConfig>,
+    pub connection_limits: Option<Vec<RawConnectionLimit>>,
+    pub doh: Option<RawDoHConfig>,
+    pub dns: Option<RawDnsConfig>,
+    pub listeners: Option<Vec<RawListener>>,
+    pub proxy_protocol: Option<RawProxyProtocol>,
+    pub validation: Option<RawValidationConfig>,
+    // how long the exchanges in flight are let finish on shutdown, 10s by default
+    #[serde(default)]
+    #[serde(with = "crate::duration")]
+    pub drain_timeout: Option<Duration>,
+    // a json record of every exchange, with the rules matched and applied
+    pub access_log: Option<RawAccessLogConfig>,
+    // capture the exchanges to a HAR file, written on shutdown
+    pub har: Option<RawHarConfig>,
+    // a json summary of the experiment, written on shutdown
+    pub experiment_report: Option<RawExperimentReportConfig>,
+    // export a span of every exchange over OTLP
+    pub telemetry: Option<RawTelemetryConfig>,
+    // capture the connections to pcap files
+    pub pcap: Option<RawPcapConfig>,
+    // the tool the controller diverts the traffic with, iptables by default. The proxy steers the
+    // ports to its listeners itself with ebpf
+    pub redirect_backend: Option<RawRedirectBackend>,
+    // serve as a plain forward proxy on the address, the controller sets up no diverting rules
+    pub explicit: Option<RawExplicitConfig>,
+    // keep-alive of the upstream connections, reused by the next requests
+    pub upstream_pool: Option<RawUpstreamPool>,
+    // bodies buffered by the proxy, e.g. to be rewritten, mirrored or validated, are capped at the
+    // size in bytes, unlimited by default
+    pub max_body_size: Option<usize>,
+    // how a body over max_body_size is handled, `pass_through` by default
+    pub body_overflow: Option<RawBodyOverflow>,
+    // worker threads and socket options of the proxy, changing them restarts it
+    pub runtime: Option<RawRuntimeConfig>,
+    // caps on the client connections and the requests in flight of the proxy itself
+    pub concurrency: Option<RawConcurrencyConfig>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RawBodyOverflow {
+    // stream the body untouched, skipping the rules needing it
+    PassThrough,
+    // answer the requests with 413, replace the responses with 502
+    Reject,
+    // cut the body at the size
+    Truncate,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RawDirection {
+    // the connections to the ports of the target (ingress)
+    Inbound,
+    // the connections of the target to the remote ports (egress)
+    Outbound,
+    Both,
+}
+
+/// RawExplicitConfig makes the proxy a forward proxy the clients are configured with, e.g. by
+/// `HTTP_PROXY` or `ALL_PROXY=socks5://...`, instead of a transparent one. It needs neither root
+/// nor `NET_ADMIN`.
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RawExplicitConfig {
+    // address of the listener, e.g. `127.0.0.1:8080`
+    pub listen: SocketAddr,
+    // `http` by default
+    pub protocol: Option<RawExplicitProtocol>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RawExplicitProtocol {
+    // `CONNECT` requests and the requests of absolute URIs
+    Http,
+    // `CONNECT` commands of SOCKS5 without authentication
+    Socks5,
+}
+
+impl From<RawExplicitConfig> for ExplicitConfig {
+    fn from(raw: RawExplicitConfig) -> Self {
+        Self {
+            listen: raw.listen,
+            protocol: match raw.protocol {
+                None | Some(RawExplicitProtocol::Http) => ExplicitProtocol::Http,
+                Some(RawExplicitProtocol::Socks5) => ExplicitProtocol::Socks5,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RawRedirectBackend {
+    // iptables, ebtables-legacy and ip rules
+    Iptables,
+    // the tables named `chaos_tproxy` of nftables
+    Nftables,
+    // ip rules by the ports and an sk_lookup program of the proxy, without iptables
+    Ebpf,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RawMatchPolicy {
+    First,
+    All,
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+pub struct RawListener {
+    // the ports redirected to this listener instead of `listen_port`, e.g. `443,8443`
+    pub proxy_ports: String,
+    pub listen_port: u16,
+    // TLS of the connections of this listener, independent of the top-level `tls`
+    pub tls: Option<TLSRawConfig>,
+    // worker threads of the dedicated runtime of this listener, the shared runtime is used by
+    // default
+    pub workers: Option<usize>,
+    // rules of the connections of this listener instead of the top-level ones, so that the
+    // services behind different ports get independent rule sets
+    pub rules: Option<Vec<RawRule>>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+pub struct RawProxyProtocol {
+    // read a PROXY header (v1 or v2) at the beginning of each connection, e.g. from HAProxy, the
+    // source of the header is taken as the client
+    pub accept: Option<bool>,
+    // write a PROXY header of the version to the original destinations
+    pub send: Option<RawProxyProtocolVersion>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RawProxyProtocolVersion {
+    V1,
+    V2,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+pub struct RawValidationConfig {
+    // the accepted classes of the upstream status, e.g. `[2, 3]` for 2xx and 3xx
+    pub status_classes: Option<Vec<u16>>,
+    // the accepted content types without the parameters, e.g. `application/json`
+    pub content_types: Option<Vec<String>>,
+    // the largest accepted body in bytes
+    pub max_size: Option<usize>,
+    // check that the bodies of the JSON responses are well-formed
+    pub json: Option<bool>,
+    // what happens to the invalid responses, `log` by default
+    pub on_violation: Option<RawOnViolation>,
+    // status of the error replacing the invalid responses, 502 by default
+    pub status: Option<u16>,
+    // file the quarantined responses are appended to, required by `quarantine`
+    pub capture_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RawOnViolation {
+    Log,
+    Replace,
+    Quarantine,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+pub struct RawDnsConfig {
+    // the first matched rule applies to a query, the others are forwarded untouched
+    pub rules: Vec<RawDnsRule>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+pub struct RawDnsRule {
+    // wildcard of the query names, e.g. `*.svc.cluster.local`, matched case-insensitively
+    pub name: String,
+    // type of the queries, all types by default
+    #[serde(rename = "type")]
+    pub query_type: Option<RawQueryType>,
+    pub actions: RawDnsActions,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Deserialize, Serialize)]
+pub enum RawQueryType {
+    A,
+    AAAA,
+    CNAME,
+    MX,
+    NS,
+    PTR,
+    SRV,
+    TXT,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+pub struct RawDnsActions {
+    // delay of the answer, forwarded or synthesized
+    #[serde(default)]
+    #[serde(with = "crate::duration")]
+    pub delay: Option<Duration>,
+    // answer NXDOMAIN without forwarding
+    pub nxdomain: Option<bool>,
+    // answer the addresses without forwarding
+    pub rewrite: Option<RawDnsRewrite>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+pub struct RawDnsRewrite {
+    // the A queries get the IPv4 addresses and the AAAA ones get the IPv6 addresses
+    pub addresses: Vec<IpAddr>,
+    // 60 by default
+    pub ttl: Option<u32>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+pub struct RawDoHConfig {
+    // DoH servers speaking the JSON API, they are tried in order
+    pub servers: Vec<RawDoHServer>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+pub struct RawDoHServer {
+    // e.g. `https://cloudflare-dns.com/dns-query`
+    pub url: String,
+    // bootstrap address of the server, the host of the URL is resolved by the system otherwise
+    pub address: Option<IpAddr>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+pub struct RawConnectionLimit {
+    // IP or CIDR of the original destinations, all by default
+    pub destination: Option<String>,
+    // port of the original destinations, all by default
+    pub port: Option<u16>,
+    // concurrent connections forwarded to each destination
+    pub max: usize,
+    // how the excess connections are handled, `queue` by default
+    pub excess: Option<RawExcess>,
+    // the queued connections are reset after the timeout, they wait forever by default
+    #[serde(default)]
+    #[serde(with = "crate::duration")]
+    pub queue_timeout: Option<Duration>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RawExcess {
+    Queue,
+    Reject,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RawConcurrencyConfig {
+    // concurrent client connections, unlimited by default
+    pub max_connections: Option<usize>,
+    // requests in flight, unlimited by default
+    pub max_requests: Option<usize>,
+    // how the excess ones are handled, `queue` by default. The rejected connections are reset,
+    // and the rejected requests get 503
+    pub excess: Option<RawExcess>,
+    // excess ones waiting for a slot, as many as the max by default. The others are rejected
+    pub queue_size: Option<usize>,
+    // the queued ones are rejected after the timeout, they wait forever by default
+    #[serde(default)]
+    #[serde(with = "crate::duration")]
+    pub queue_timeout: Option<Duration>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+pub struct RawSnapshotConfig {
+    // directory of the snapshot files
+    pub path: PathBuf,
+
+    // how often the metrics are persisted, 1m by default
+    #[serde(default)]
+    #[serde(with = "crate::duration")]
+    pub interval: Option<Duration>,
+
+    // snapshots older than the retention are removed, 24h by default
+    #[serde(default)]
+    #[serde(with = "crate::duration")]
+    pub retention: Option<Duration>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+pub struct RawAccessLogConfig {
+    // file the records are appended to, `-` writes them to stdout
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RawUpstreamPool {
+    // how long an idle upstream connection is kept, 90s by default
+    #[serde(default)]
+    #[serde(with = "crate::duration")]
+    pub idle_timeout: Option<Duration>,
+    // idle connections kept per upstream, unlimited by default, the ones in use are not capped. 0
+    // opens a connection per request
+    pub max_idle_per_host: Option<usize>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RawRuntimeConfig {
+    // worker threads of the runtime, the number of CPUs by default
+    pub worker_threads: Option<usize>,
+    // listeners of each port bound with SO_REUSEPORT, each accepted by its own loop, 1 by default
+    pub accept_shards: Option<usize>,
+    // backlog of the listening sockets, 1024 by default
+    pub backlog: Option<u32>,
+    // TCP_NODELAY of the client and the upstream connections, true by default
+    pub tcp_nodelay: Option<bool>,
+    // SO_RCVBUF and SO_SNDBUF in bytes of the client and the upstream connections, the kernel
+    // defaults if not set
+    pub recv_buffer: Option<u32>,
+    pub send_buffer: Option<u32>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+pub struct RawHarConfig {
+    // file the HAR is written to on shutdown
+    pub path: PathBuf,
+    // capture every exchange, instead of the ones any rule is applied to
+    pub all: Option<bool>,
+    // bytes of each body captured, 64KiB by default
+    pub max_body: Option<usize>,
+    // exchanges captured, the later ones are left out, 10000 by default
+    pub max_entries: Option<usize>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RawExperimentReportConfig {
+    // file the summary is written to on shutdown
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+pub struct RawTelemetryConfig {
+    // OTLP collector over gRPC, e.g. `http://otel-collector:4317`
+    pub endpoint: String,
+    // `service.name` of the spans, `chaos-tproxy` by default
+    pub service_name: Option<String>,
+    // continue the traces of the requests by their `traceparent` headers, and pass the span of the
+    // proxy to the upstream, false by default
+    pub propagate: Option<bool>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+pub struct RawPcapConfig {
+    // directory of `client.pcap` and `upstream.pcap`
+    pub path: PathBuf,
+    // IP or CIDR of the original destinations captured, all by default
+    pub destination: Option<String>,
+    // port of the original destinations captured, all by default
+    pub port: Option<u16>,
+}
+
+/// RawReportConfig is set by the agent of a cluster, to push the metrics to the controller.
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+pub struct RawReportConfig {
+    pub url: String,
+    pub name: String,
+
+    #[serde(with = "crate::duration")]
+    pub interval: Duration,
+
+    // bearer token of the controller, if it requires one
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+pub struct RawOptIn {
+    // the rules only apply to the requests carrying the header, it is stripped before forwarding
+    pub header: String,
+    // the header must have the value if set
+    pub value: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+pub struct RawCoordinationConfig {
+    pub role: RawCoordinationRole,
+
+    // address the leader listens on for the followers
+    pub listen: Option<SocketAddr>,
+
+    // authority of the leader, e.g. `10.0.0.1:7000`, required by the followers
+    pub leader: Option<String>,
+
+    // cap of the percent of faulted exchanges across all the instances, only used by the leader
+    pub max_faulted_percent: Option<f64>,
+
+    // how often the followers sync with the leader, 1s by default
+    #[serde(default)]
+    #[serde(with = "crate::duration")]
+    pub interval: Option<Duration>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RawCoordinationRole {
+    Leader,
+    Follower,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", content = "value")]
+pub enum RawMetadataSource {
+    // CSV file whose first column is an IP or CIDR and the others are labels named by the header
+    CSV(PathBuf),
+    // look up the labels by HTTP GET
+    HTTP(RawHTTPMetadataSource),
+    // MaxMind GeoIP2/GeoLite2 database file
+    MaxMind(PathBuf),
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+pub struct RawHTTPMetadataSource {
+    // `{ip}` would be replaced with the client IP, a JSON object of strings is expected
+    pub url: String,
+
+    // how long the labels are cached, 5m by default
+    #[serde(default)]
+    #[serde(with = "crate::duration")]
+    pub ttl: Option<Duration>,
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize, Default)]
+pub struct SLORawConfig {
+    // availability objective in percent, e.g. 99.9
+    pub availability: Option<f64>,
+
+    // requests slower than the threshold are counted against the latency objective
+    #[serde(default)]
+    #[serde(with = "crate::duration")]
+    pub latency_threshold: Option<Duration>,
+
+    // percent of requests expected to be faster than `latency_threshold`, 99 by default
+    pub latency_objective: Option<f64>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+pub struct RawBaselineConfig {
+    // how long the proxy only observes the exchanges before the rules apply
+    #[serde(with = "crate::duration")]
+    pub duration: Duration,
+
+    // the most paths captured, 100 by default
+    pub max_paths: Option<usize>,
 }
 
 #[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
 pub enum Role {
-    Client(Vec<Ipv4Addr>),
-    Server(Vec<Ipv4Addr>),
+    Client(Vec<IpAddr>),
+    Server(Vec<IpAddr>),
 }
 
 #[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
@@ -49,24 +578,110 @@ pub enum RawFile {
 #[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize, Default)]
 pub struct TLSRawConfig {
     pub ca_file: Option<RawFile>,
-    pub cert_file: RawFile,
-    pub key_file: RawFile,
+    // PEM of the CAs of the client certificates, the clients are asked for a certificate, which
+    // is optional, only if it is set
+    pub client_ca_file: Option<RawFile>,
+    // certificate and key of the proxy, required unless `mitm` is set
+    pub cert_file: Option<RawFile>,
+    pub key_file: Option<RawFile>,
+    pub mitm: Option<RawMITMConfig>,
 }
 
+/// RawMITMConfig makes the proxy generate the certificate of each server name, signed by the CA.
 #[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+pub struct RawMITMConfig {
+    // PEM of the CA certificate
+    pub ca_cert: RawFile,
+    // PEM of the CA key in PKCS#8
+    pub ca_key: RawFile,
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
 pub struct RawRule {
+    // identifies the rule in the logs and the timeline, unique in the config
+    pub name: Option<String>,
+    // false to keep the rule in the config without applying it, true by default
+    pub enabled: Option<bool>,
+    // the rule expires once it is loaded for the duration, e.g. `10m`, the reloads keeping it by
+    // its name do not extend it, available on the named rules only
+    #[serde(default)]
+    #[serde(with = "crate::duration")]
+    pub duration: Option<Duration>,
+    // the rules of higher priority are matched first, 0 by default, the rules of the same priority
+    // are matched in order of the config
+    pub priority: Option<i32>,
     pub target: RawTarget,
     pub selector: RawSelector,
     pub actions: RawActions,
+    // decompress the body (gzip, deflate or br) before the actions and compress it afterwards
+    pub decode_body: Option<bool>,
+    // echo the summary of the applied actions in the `x-chaos-applied` response header
+    pub echo_applied: Option<bool>,
+    // fill the empty bodies of the synthesized error responses with `application/problem+json`
+    // documents
+    pub problem_json: Option<bool>,
+    // false leaves the exchanges modified by this rule unmarked by `fault_markers`, true by default
+    pub fault_marker: Option<bool>,
+    // actions applied to the next requests to the same path for a while once this rule is applied,
+    // e.g. delay the retries of an aborted request
+    pub follow_up: Option<RawFollowUp>,
+    // draw the `percent` of the selector and the weighted actions by session, so that all the
+    // requests of a session get the same faults
+    pub sticky_on: Option<RawStickyOn>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RawStickyOn {
+    // the value of the header, e.g. `{header: x-session-id}`
+    Header(String),
+    // the value of the cookie, e.g. `{cookie: session}`
+    Cookie(String),
+    // the IP of the client
+    SourceIp,
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+pub struct RawFollowUp {
+    // how long the follow-up lasts after the rule is applied
+    #[serde(with = "crate::duration")]
+    pub duration: Duration,
+    // only the requests of the same client (IP) are affected, true by default
+    pub same_client: Option<bool>,
+    // actions of a Request target rule
+    pub actions: RawActions,
 }
 
 #[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
 pub enum RawTarget {
     Request,
     Response,
+    // the requests/responses of the calls from the local services to their dependencies (egress)
+    #[serde(alias = "client_request")]
+    ClientRequest,
+    #[serde(alias = "client_response")]
+    ClientResponse,
+    // the requests/responses of the calls received by the local services (ingress)
+    #[serde(alias = "server_request")]
+    ServerRequest,
+    #[serde(alias = "server_response")]
+    ServerResponse,
+    // the raw TCP connections to the selected port, they are never parsed as HTTP
+    #[serde(alias = "tcp")]
+    Tcp,
 }
 
-#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+impl RawTarget {
+    fn direction(&self) -> Option<Direction> {
+        match self {
+            RawTarget::Request | RawTarget::Response | RawTarget::Tcp => None,
+            RawTarget::ClientRequest | RawTarget::ClientResponse => Some(Direction::Outbound),
+            RawTarget::ServerRequest | RawTarget::ServerResponse => Some(Direction::Inbound),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize, Default)]
 pub struct RawSelector {
     pub port: Option<u16>,
     /// Mathc path of `Uri` with wildcard matches.
@@ -84,18 +699,412 @@ pub struct RawSelector {
     pub path: Option<String>,
     pub method: Option<String>,
     pub code: Option<u16>,
-    pub request_headers: Option<HashMap<String, String>>,
-    pub response_headers: Option<HashMap<String, String>>,
+    // the names could be globs, e.g. `x-b3-*`, or regexes between slashes, e.g. `/^x-(b3|ot)-/`,
+    // a null value matches any value of the headers
+    pub request_headers: Option<HashMap<String, Option<String>>>,
+    pub response_headers: Option<HashMap<String, Option<String>>>,
+    pub user_agent: Option<RawUserAgentSelector>,
+    // md5 of the JA3 string of the TLS client, only available if TLS is enabled
+    pub ja3: Option<String>,
+    // certificate and ALPN protocol of the TLS client, only available if TLS is enabled
+    pub tls_client: Option<RawTlsClientSelector>,
+    // labels of the client resolved by `metadata`
+    pub labels: Option<HashMap<String, String>>,
+    pub time_window: Option<RawTimeWindow>,
+    // the rule is only active when the node is under pressure
+    pub pressure: Option<RawPressureSelector>,
+    // percent of the exchanges, or of the sessions if the rule is `sticky_on` them, selected
+    pub percent: Option<f64>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+pub struct RawTlsClientSelector {
+    // subject of the client certificate, e.g. `CN=checkout*`, supports wildcard
+    pub subject: Option<String>,
+    // any DNS name, URI or email of the SANs of the client certificate, e.g.
+    // `spiffe://cluster.local/ns/shop/sa/*`, supports wildcard
+    pub san: Option<String>,
+    // ALPN protocol negotiated, e.g. `h2`
+    pub alpn: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+pub struct RawPressureSelector {
+    // CPU usage in percent
+    pub cpu: Option<f64>,
+    // memory usage in percent
+    pub memory: Option<f64>,
+    // load average of 1 minute
+    pub load: Option<f64>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+pub struct RawTimeWindow {
+    // e.g. [Mon, Tue], all days if not set
+    pub days: Option<Vec<String>>,
+    // `HH:MM`, 00:00 by default
+    pub start: Option<String>,
+    // `HH:MM`, 23:59:59 by default, the window spans midnight if it is earlier than `start`
+    pub end: Option<String>,
+    // IANA timezone, e.g. `Asia/Shanghai`, UTC by default
+    pub timezone: Option<String>,
 }
 
 #[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+pub struct RawUserAgentSelector {
+    // product name in the User-Agent, e.g. `okhttp`, supports wildcard and is case-insensitive
+    pub family: String,
+    // version of the product, e.g. `4.*`, supports wildcard
+    pub version: Option<String>,
+}
+
+/// RawLatencyProfile is the file referenced by `delay_profile`, holding either of the fields.
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RawLatencyProfile {
+    // latencies in milliseconds, each delay is one of them drawn at random
+    pub samples_ms: Option<Vec<f64>>,
+    // latencies in milliseconds by percentile, e.g. `p50: 20` and `p99: 300`, the ones between
+    // the percentiles are interpolated
+    pub percentiles_ms: Option<BTreeMap<String, f64>>,
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize, Default)]
 pub struct RawActions {
-    pub abort: Option<bool>,
+    pub abort: Option<RawAbort>,
+    pub abort_mode: Option<RawAbortMode>,
+    pub dedup: Option<RawDedupAction>,
+    pub duplicate: Option<RawDuplicateAction>,
+    pub mirror: Option<RawMirrorAction>,
     #[serde(default)]
-    #[serde(with = "humantime_serde")]
+    #[serde(with = "crate::duration")]
     pub delay: Option<Duration>,
+    // yaml or json file of a latency profile the delay of each request is drawn from, exclusive
+    // with delay
+    pub delay_profile: Option<PathBuf>,
+    // before_forward by default, response-target rules only support after_receive
+    pub delay_position: Option<RawDelayPosition>,
+    // abandon the upstream exchange unless it is answered in time
+    pub timeout: Option<RawTimeoutAction>,
+    // delay the connection to the upstream, or fail it as refused or unresolved, before the
+    // upstream sees the request, the client gets a 502 on failure
+    #[serde(default)]
+    #[serde(with = "crate::duration")]
+    pub connect_delay: Option<Duration>,
+    pub connect_refused: Option<bool>,
+    pub dns_failure: Option<bool>,
+    // remove the headers of the names matched by the patterns, e.g. `x-internal-*`, before the
+    // replaced ones are set
+    pub remove_headers: Option<Vec<String>>,
     pub replace: Option<RawReplaceAction>,
     pub patch: Option<RawPatchAction>,
+    pub redirect: Option<RawRedirectAction>,
+    pub pattern: Option<RawPatternAction>,
+    pub rate_limit: Option<RawRateLimitAction>,
+    pub set_cookies: Option<Vec<RawCookie>>,
+    pub delete_cookies: Option<Vec<RawDeleteCookie>>,
+    pub dribble: Option<RawDribbleAction>,
+    pub segment: Option<RawSegmentAction>,
+    pub framing: Option<RawFraming>,
+    // trailers sent after the body, only sent on HTTP/2 connections
+    pub trailers: Option<HashMap<String, String>>,
+    // faults of the frames after the WebSocket upgrade
+    pub websocket: Option<RawWebSocketAction>,
+    // connection-level faults, only available and required on Tcp target
+    pub tcp: Option<RawTcpAction>,
+    // withhold the `100 Continue` to the requests with `Expect: 100-continue` for the duration
+    #[serde(default)]
+    #[serde(with = "crate::duration")]
+    pub withhold_continue: Option<Duration>,
+    // send the status and the headers at once, and stall before the body for the duration
+    #[serde(default)]
+    #[serde(with = "crate::duration")]
+    pub stall_body: Option<Duration>,
+    // cut the body once a part of it is sent, e.g. a truncated download or a partial JSON document
+    pub truncate: Option<RawTruncateAction>,
+    // set, strip or falsify the headers telling the client to the upstream
+    pub client_ip: Option<RawClientIpAction>,
+    // alternative action sets, one of them is drawn by weight for each exchange, exclusive with
+    // the other actions but pattern
+    pub weighted: Option<Vec<RawWeightedActions>>,
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+pub struct RawWeightedActions {
+    pub weight: u32,
+    // no action by default, i.e. the exchange passes through
+    #[serde(default)]
+    pub actions: RawActions,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+pub struct RawClientIpAction {
+    // append by default
+    pub mode: Option<RawClientIpMode>,
+    // address told to the upstream instead of the client, e.g. a falsified one
+    pub address: Option<IpAddr>,
+    // all of them by default
+    pub headers: Option<Vec<RawClientIpHeader>>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RawClientIpMode {
+    // append the address to the lists of `X-Forwarded-For` and `Forwarded`, replace `X-Real-IP`
+    Append,
+    // replace the headers with the address only
+    Set,
+    // remove the headers
+    Strip,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RawClientIpHeader {
+    XForwardedFor,
+    XRealIp,
+    Forwarded,
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+pub struct RawTcpAction {
+    // fixed delay of each segment
+    #[serde(default)]
+    #[serde(with = "crate::duration")]
+    pub delay: Option<Duration>,
+    // random extra delay of each segment, up to the jitter
+    #[serde(default)]
+    #[serde(with = "crate::duration")]
+    pub jitter: Option<Duration>,
+    // throughput cap of each direction in bytes per second
+    pub bandwidth: Option<u64>,
+    // percent of the segments resetting the connection
+    pub reset_percent: Option<f64>,
+    // percent of the segments with a corrupted byte
+    pub corrupt_percent: Option<f64>,
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+pub struct RawWebSocketAction {
+    // delay of each frame
+    #[serde(default)]
+    #[serde(with = "crate::duration")]
+    pub delay: Option<Duration>,
+    // percent of the unfragmented data frames to drop
+    pub drop_percent: Option<f64>,
+    // close both sides with the code after the duration
+    pub close: Option<RawWebSocketClose>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+pub struct RawWebSocketClose {
+    // 1011 (internal error) by default
+    pub code: Option<u16>,
+    #[serde(with = "crate::duration")]
+    pub after: Duration,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RawFraming {
+    // Transfer-Encoding: chunked
+    Chunked,
+    // Content-Length
+    ContentLength,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+pub struct RawDribbleAction {
+    // bytes sent at a time, 1 by default
+    pub chunk_size: Option<usize>,
+
+    // the chunks are spread evenly over the duration
+    #[serde(with = "crate::duration")]
+    pub duration: Duration,
+
+    // never send the last chunk and keep the connection open, false by default
+    pub never_finish: Option<bool>,
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+pub struct RawTruncateAction {
+    // bytes of the body sent before it is cut, exclusive with after_percent
+    pub after_bytes: Option<u64>,
+
+    // percent of the body sent before it is cut, of its Content-Length, or of the whole body read
+    // in memory if unknown
+    pub after_percent: Option<f64>,
+
+    // how the connection is handled once the body is cut, close by default, timeout sends
+    // nothing more and keeps it open
+    pub mode: Option<RawAbortMode>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+pub struct RawSegmentAction {
+    // bytes of each segment
+    pub size: usize,
+
+    // pause between the segments
+    #[serde(with = "crate::duration")]
+    pub inter_segment_delay: Duration,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+pub struct RawCookie {
+    pub name: String,
+    pub value: String,
+    pub path: Option<String>,
+    pub domain: Option<String>,
+    #[serde(default)]
+    #[serde(with = "crate::duration")]
+    pub max_age: Option<Duration>,
+    pub secure: Option<bool>,
+    pub http_only: Option<bool>,
+    pub same_site: Option<RawSameSite>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Deserialize, Serialize)]
+pub enum RawSameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+pub struct RawDeleteCookie {
+    pub name: String,
+    // path and domain must be the same as the ones the cookie was set with
+    pub path: Option<String>,
+    pub domain: Option<String>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+pub struct RawRateLimitAction {
+    // allowed requests in every `per`, the exceeded ones are responded with 429
+    pub requests: u32,
+
+    #[serde(with = "crate::duration")]
+    pub per: Duration,
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+pub struct RawPatternAction {
+    pub shape: RawShape,
+
+    #[serde(with = "crate::duration")]
+    pub period: Duration,
+
+    // fraction of the period the square wave is active, 0.5 by default
+    pub duty_cycle: Option<f64>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RawShape {
+    // active in the first `duty_cycle` of every period
+    Square,
+    // active with a probability oscillating between 0 and 1
+    Sine,
+    // active with a probability rising from 0 to 1 in every period
+    Ramp,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum RawAbort {
+    // `abort: true` kills the exchange
+    Enabled(bool),
+    // `abort: {code: 503, ...}` responds without calling the upstream
+    Response(RawAbortResponse),
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+pub struct RawAbortResponse {
+    pub code: u16,
+    pub headers: Option<HashMap<String, String>>,
+    pub body: Option<String>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RawDelayPosition {
+    // delay the request before forwarding it
+    BeforeForward,
+    // delay the response after the upstream has answered
+    AfterReceive,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RawAbortMode {
+    // send a TCP RST
+    Reset,
+    // close the connection with a FIN
+    Close,
+    // never respond, like a blackhole
+    Timeout,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+pub struct RawDedupAction {
+    // header carrying the idempotency key, `Idempotency-Key` by default
+    pub header: Option<String>,
+
+    // how long a key is remembered since it is seen, 5m by default
+    #[serde(default)]
+    #[serde(with = "crate::duration")]
+    pub ttl: Option<Duration>,
+
+    // `retries` (default) drops every retry, `first` drops the first attempt and forwards retries
+    pub mode: Option<RawDedupMode>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RawDedupMode {
+    Retries,
+    First,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+pub struct RawDuplicateAction {
+    // number of extra copies sent to the upstream
+    pub count: u32,
+
+    // interval between the copies, all copies are sent at once if not set
+    #[serde(default)]
+    #[serde(with = "crate::duration")]
+    pub interval: Option<Duration>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+pub struct RawMirrorAction {
+    // `host:port` of the shadow backend
+    pub target: String,
+
+    // percent of the matched requests to be mirrored, 100 by default
+    pub percent: Option<u8>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+pub struct RawTimeoutAction {
+    // how long the headers of the upstream response are waited for
+    #[serde(with = "crate::duration")]
+    pub after: Duration,
+
+    // the client is answered with 504 by default, or its connection is handled as the mode
+    pub abort_mode: Option<RawAbortMode>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+pub struct RawRedirectAction {
+    // redirect status code, 302 by default, one of 301, 302, 303, 307 and 308
+    pub code: Option<u16>,
+
+    // value of the `Location` header, `{host}`, `{path}` and `{query}` would be
+    // replaced with the parts of the original URI
+    pub location: String,
 }
 
 #[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
@@ -123,7 +1132,7 @@ pub enum RawPatchBodyContents {
     JSON(String),
 }
 
-#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize, Default)]
 pub struct RawReplaceAction {
     pub path: Option<String>,
     pub method: Option<String>,
@@ -131,6 +1140,12 @@ pub struct RawReplaceAction {
     pub code: Option<u16>,
     pub queries: Option<HashMap<String, String>>,
     pub headers: Option<HashMap<String, String>>,
+
+    // rewrite the authority (and the `Host` header) of the request, e.g. `staging.local:8080`
+    pub authority: Option<String>,
+
+    // forward the request to `host:port` instead of its original destination
+    pub upstream: Option<String>,
 }
 
 #[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
@@ -158,58 +1173,732 @@ pub(crate) fn try_from_hash_map(
                 .try_into()
                 .map_err(|e: http::Error| -> anyhow::Error { anyhow!(e) })
         })
-        .transpose()
+        .transpose()
+}
+
+/// try_from_header_fields parses the header fields of a selector, keyed by name patterns.
+fn try_from_header_fields(
+    raw: Option<HashMap<String, Option<String>>>,
+) -> Result<Option<HeaderFields>, Error> {
+    raw.map(|fields| {
+        fields
+            .into_iter()
+            .map(|(pattern, value)| {
+                let value = value
+                    .map(|value| value.parse::<HeaderValue>())
+                    .transpose()?;
+                Ok((pattern.parse::<HeaderPattern>()?, value))
+            })
+            .collect::<Result<_, Error>>()
+            .map(HeaderFields)
+    })
+    .transpose()
+}
+
+pub(crate) fn try_from_vec(
+    t: Option<Vec<(String, String)>>,
+) -> Result<Option<HeaderMap>, anyhow::Error> {
+    t.map(|headers| -> Result<_, anyhow::Error> {
+        let mut map = HeaderMap::new();
+        for (key, value) in headers {
+            map.insert(key.parse::<HeaderName>()?, value.parse()?);
+        }
+        Ok(map)
+    })
+    .transpose()
+}
+
+impl Default for RawFile {
+    fn default() -> Self {
+        RawFile::Contents(Default::default())
+    }
+}
+
+impl TryFrom<RawFile> for Vec<u8> {
+    type Error = Error;
+
+    fn try_from(value: RawFile) -> Result<Self, Self::Error> {
+        match value {
+            RawFile::Contents(c) => Ok(c),
+            RawFile::Path(p) => Ok(fs::read(p)?),
+        }
+    }
+}
+
+/// check_rule_names checks whether the names of the rules are unique.
+pub fn check_rule_names(rules: &[RawRule]) -> Result<(), Error> {
+    let mut names = HashSet::new();
+    for name in rules.iter().filter_map(|rule| rule.name.as_deref()) {
+        if !names.insert(name) {
+            return Err(anyhow!("duplicate rule name {}", name));
+        }
+    }
+    Ok(())
+}
+
+/// check_direction checks whether the rules restricted to a direction are of the connections
+/// diverted.
+fn check_direction(raw: &RawConfig) -> Result<(), Error> {
+    let diverted = match raw.direction {
+        Some(RawDirection::Inbound) => Direction::Inbound,
+        Some(RawDirection::Outbound) => Direction::Outbound,
+        Some(RawDirection::Both) | None => return Ok(()),
+    };
+    let never = raw.rules.iter().find(|rule| {
+        rule.target
+            .direction()
+            .is_some_and(|direction| direction != diverted)
+    });
+    match never {
+        Some(rule) => Err(anyhow!(
+            "the {:?} rule never applies, only the {:?} connections are diverted",
+            rule.target,
+            diverted
+        )),
+        None => Ok(()),
+    }
+}
+
+/// check_explicit checks whether the settings of the explicit proxy are available without the
+/// diverting rules, which the listeners, the DNS queries and the other backends rely on.
+pub fn check_explicit(raw: &RawConfig) -> Result<(), Error> {
+    if raw.explicit.is_none() {
+        return Ok(());
+    }
+    if raw
+        .listeners
+        .as_ref()
+        .is_some_and(|listeners| !listeners.is_empty())
+        || raw.dns.is_some()
+        || raw.redirect_backend.is_some()
+    {
+        return Err(anyhow!(
+            "listeners, dns and redirect_backend are not available with explicit"
+        ));
+    }
+    if raw
+        .proxy_protocol
+        .as_ref()
+        .is_some_and(|proxy_protocol| proxy_protocol.accept == Some(true))
+    {
+        return Err(anyhow!(
+            "proxy_protocol.accept is not available with explicit"
+        ));
+    }
+    Ok(())
+}
+
+impl TryFrom<RawConfig> for Config {
+    type Error = Error;
+
+    fn try_from(raw: RawConfig) -> Result<Self, Self::Error> {
+        check_rule_names(&raw.rules)?;
+        let mut listener_rules = BTreeMap::new();
+        for listener in raw.listeners.iter().flatten() {
+            if let Some(rules) = &listener.rules {
+                check_rule_names(rules)?;
+                let rules = rules
+                    .iter()
+                    .cloned()
+                    .map(TryInto::try_into)
+                    .collect::<Result<Vec<_>, Self::Error>>()?;
+                listener_rules.insert(listener.listen_port, rules);
+            }
+        }
+        check_direction(&raw)?;
+        check_explicit(&raw)?;
+        let sk_lookup = match raw.redirect_backend {
+            Some(RawRedirectBackend::Ebpf) => Some(sk_lookup_config(&raw)?),
+            _ => None,
+        };
+        let (connection_capacity, request_capacity) = match &raw.concurrency {
+            Some(concurrency) => (
+                concurrency.capacity(concurrency.max_connections)?,
+                concurrency.capacity(concurrency.max_requests)?,
+            ),
+            None => (None, None),
+        };
+        Ok(Self {
+            http_config: HTTPConfig {
+                listen_port: raw.listen_port,
+                role: raw.role,
+                compare_mode: raw.compare_mode,
+                latency_compensation: raw.latency_compensation,
+                fault_markers: raw.fault_markers,
+                inspect_all: raw.inspect_all,
+                opt_in: raw.opt_in.map(TryInto::try_into).transpose()?,
+                exchange_id_header: raw
+                    .exchange_id_header
+                    .map(|header| header.parse())
+                    .transpose()?,
+                resolver: match raw.doh {
+                    None => Resolver::default(),
+                    Some(doh) => doh.try_into()?,
+                },
+                proxy_protocol: raw.proxy_protocol.map(Into::into).unwrap_or_default(),
+                validator: raw
+                    .validation
+                    .map(TryInto::try_into)
+                    .transpose()?
+                    .map(Arc::new),
+                clock: Arc::new(SystemClock),
+                follow_ups: Default::default(),
+                match_policy: raw.match_policy.map(Into::into).unwrap_or_default(),
+                upstream_pool: raw.upstream_pool.map(Into::into).unwrap_or_default(),
+                body_limit: body_limit(raw.max_body_size, raw.body_overflow)?,
+                listener_rules,
+                rule_set: None,
+                rules: raw
+                    .rules
+                    .into_iter()
+                    .map(TryInto::try_into)
+                    .collect::<Result<Vec<_>, Self::Error>>()?,
+            },
+
+            tls_config: match raw.tls {
+                None => None,
+                Some(tls) => Some(tls.try_into()?),
+            },
+            slo: raw.slo.map(TryInto::try_into).transpose()?,
+            baseline: raw.baseline.map(TryInto::try_into).transpose()?,
+            metadata: raw.metadata.map(TryInto::try_into).transpose()?,
+            coordination: raw.coordination.map(TryInto::try_into).transpose()?,
+            report: raw.report.map(TryInto::try_into).transpose()?,
+            snapshot: raw.snapshot.map(TryInto::try_into).transpose()?,
+            connection_limits: raw
+                .connection_limits
+                .unwrap_or_default()
+                .into_iter()
+                .map(TryInto::try_into)
+                .collect::<Result<Vec<_>, Self::Error>>()?,
+            dns: raw.dns.map(TryInto::try_into).transpose()?,
+            listeners: raw
+                .listeners
+                .unwrap_or_default()
+                .into_iter()
+                .map(TryInto::try_into)
+                .collect::<Result<Vec<_>, Self::Error>>()?,
+            drain_timeout: raw.drain_timeout.unwrap_or(Duration::from_secs(10)),
+            access_log: raw.access_log.map(Into::into),
+            har: raw.har.map(TryInto::try_into).transpose()?,
+            experiment_report: raw.experiment_report.map(Into::into),
+            telemetry: raw.telemetry.map(TryInto::try_into).transpose()?,
+            pcap: raw.pcap.map(TryInto::try_into).transpose()?,
+            sk_lookup,
+            explicit: raw.explicit.map(Into::into),
+            runtime: raw.runtime.unwrap_or_default().try_into()?,
+            connection_capacity,
+            request_capacity,
+        })
+    }
+}
+
+/// body_limit returns the limit of the bodies buffered, None if they are unlimited.
+fn body_limit(
+    max: Option<usize>,
+    overflow: Option<RawBodyOverflow>,
+) -> Result<Option<BodyLimit>, Error> {
+    let max = match max {
+        None if overflow.is_some() => {
+            return Err(anyhow!("body_overflow requires max_body_size"));
+        }
+        None => return Ok(None),
+        Some(max) => max,
+    };
+    let overflow = match overflow.unwrap_or(RawBodyOverflow::PassThrough) {
+        RawBodyOverflow::PassThrough => Overflow::PassThrough,
+        RawBodyOverflow::Reject => Overflow::Reject,
+        RawBodyOverflow::Truncate => Overflow::Truncate,
+    };
+    Ok(Some(BodyLimit { max, overflow }))
+}
+
+/// sk_lookup_config returns the ports steered to each listener by eBPF. The DNS queries and the
+/// safe mode are diverted by iptables only.
+fn sk_lookup_config(raw: &RawConfig) -> Result<SkLookupConfig, Error> {
+    if raw.dns.is_some() || raw.safe_mode {
+        return Err(anyhow!(
+            "dns and safe_mode are not available with the ebpf redirect backend"
+        ));
+    }
+    let proxy_ports = raw
+        .proxy_ports
+        .as_deref()
+        .ok_or_else(|| anyhow!("the ebpf redirect backend requires proxy_ports"))?;
+    let excluded = match &raw.exclude_ports {
+        Some(ports) => parse_port_ranges(ports)?,
+        None => vec![],
+    };
+    let mut ports = vec![exclude_port_ranges(
+        parse_port_ranges(proxy_ports)?,
+        &excluded,
+    )];
+    for listener in raw.listeners.iter().flatten() {
+        ports.push(exclude_port_ranges(
+            parse_port_ranges(&listener.proxy_ports)?,
+            &excluded,
+        ));
+    }
+    Ok(SkLookupConfig { ports })
+}
+
+/// parse_port_ranges parses the ports of iptables multiport, e.g. `80,8000:8080`.
+pub fn parse_port_ranges(ports: &str) -> Result<Vec<RangeInclusive<u16>>, Error> {
+    let invalid = || anyhow!("invalid ports {}", ports);
+    ports
+        .split(',')
+        .map(|port| match port.split_once(':') {
+            Some((start, end)) => {
+                let start: u16 = start.parse().map_err(|_| invalid())?;
+                let end: u16 = end.parse().map_err(|_| invalid())?;
+                if start > end {
+                    return Err(invalid());
+                }
+                Ok(start..=end)
+            }
+            None => {
+                let port: u16 = port.parse().map_err(|_| invalid())?;
+                Ok(port..=port)
+            }
+        })
+        .collect()
+}
+
+/// exclude_port_ranges removes the excluded ports from the ranges, a range is split if the
+/// excluded ones are in the middle of it.
+pub fn exclude_port_ranges(
+    ranges: Vec<RangeInclusive<u16>>,
+    excluded: &[RangeInclusive<u16>],
+) -> Vec<RangeInclusive<u16>> {
+    excluded.iter().fold(ranges, |ranges, excluded| {
+        ranges
+            .into_iter()
+            .flat_map(|range| {
+                let (start, end) = (*range.start(), *range.end());
+                if end < *excluded.start() || start > *excluded.end() {
+                    return vec![range];
+                }
+                let mut kept = vec![];
+                if start < *excluded.start() {
+                    kept.push(start..=excluded.start() - 1);
+                }
+                if end > *excluded.end() {
+                    kept.push(excluded.end() + 1..=end);
+                }
+                kept
+            })
+            .collect()
+    })
+}
+
+impl TryFrom<RawListener> for ListenerConfig {
+    type Error = Error;
+
+    fn try_from(raw: RawListener) -> Result<Self, Self::Error> {
+        if raw.workers == Some(0) {
+            return Err(anyhow!(
+                "workers of listener {} must be positive",
+                raw.listen_port
+            ));
+        }
+        Ok(Self {
+            listen_port: raw.listen_port,
+            tls_config: raw.tls.map(TryInto::try_into).transpose()?,
+            workers: raw.workers,
+        })
+    }
+}
+
+impl From<RawProxyProtocol> for ProxyProtocol {
+    fn from(raw: RawProxyProtocol) -> Self {
+        Self {
+            accept: raw.accept.unwrap_or(false),
+            send: raw.send.map(|version| match version {
+                RawProxyProtocolVersion::V1 => Version::V1,
+                RawProxyProtocolVersion::V2 => Version::V2,
+            }),
+        }
+    }
+}
+
+impl TryFrom<RawValidationConfig> for ResponseValidator {
+    type Error = Error;
+
+    fn try_from(raw: RawValidationConfig) -> Result<Self, Self::Error> {
+        if let Some(class) = raw
+            .status_classes
+            .iter()
+            .flatten()
+            .find(|class| !(1..=5).contains(*class))
+        {
+            return Err(anyhow!("invalid status class {} of validation", class));
+        }
+        let status = StatusCode::from_u16(raw.status.unwrap_or(502))?;
+        let on_violation = match (
+            raw.on_violation.unwrap_or(RawOnViolation::Log),
+            raw.capture_file,
+        ) {
+            (RawOnViolation::Quarantine, Some(path)) => OnViolation::Quarantine { status, path },
+            (RawOnViolation::Quarantine, None) | (_, Some(_)) => {
+                return Err(anyhow!(
+                    "capture_file is required by and only available on quarantine"
+                ))
+            }
+            (RawOnViolation::Replace, None) => OnViolation::Replace(status),
+            (RawOnViolation::Log, None) => OnViolation::Log,
+        };
+        Ok(Self {
+            status_classes: raw.status_classes,
+            content_types: raw.content_types,
+            max_size: raw.max_size,
+            json: raw.json.unwrap_or(false),
+            on_violation,
+        })
+    }
+}
+
+impl TryFrom<RawDnsConfig> for DnsConfig {
+    type Error = Error;
+
+    fn try_from(raw: RawDnsConfig) -> Result<Self, Self::Error> {
+        Ok(Self {
+            rules: raw
+                .rules
+                .into_iter()
+                .map(TryInto::try_into)
+                .collect::<Result<Vec<_>, Self::Error>>()?,
+        })
+    }
+}
+
+impl TryFrom<RawDnsRule> for DnsRule {
+    type Error = Error;
+
+    fn try_from(raw: RawDnsRule) -> Result<Self, Self::Error> {
+        let actions = raw.actions;
+        let answer = match (actions.nxdomain.unwrap_or(false), actions.rewrite) {
+            (false, None) => DnsAnswer::Forward,
+            (true, None) => DnsAnswer::NxDomain,
+            (false, Some(rewrite)) => DnsAnswer::Rewrite {
+                addrs: rewrite.addresses,
+                ttl: rewrite.ttl.unwrap_or(60),
+            },
+            (true, Some(_)) => {
+                return Err(anyhow!(
+                    "nxdomain and rewrite of dns rule {} are exclusive",
+                    raw.name
+                ))
+            }
+        };
+        Ok(Self {
+            name: WildMatch::new(raw.name.trim_end_matches('.').to_lowercase().as_str()),
+            query_type: raw.query_type.map(|query_type| match query_type {
+                RawQueryType::A => TYPE_A,
+                RawQueryType::AAAA => TYPE_AAAA,
+                RawQueryType::CNAME => 5,
+                RawQueryType::MX => 15,
+                RawQueryType::NS => 2,
+                RawQueryType::PTR => 12,
+                RawQueryType::SRV => 33,
+                RawQueryType::TXT => 16,
+            }),
+            action: DnsAction {
+                delay: actions.delay,
+                answer,
+            },
+        })
+    }
+}
+
+impl TryFrom<RawDoHConfig> for Resolver {
+    type Error = Error;
+
+    fn try_from(raw: RawDoHConfig) -> Result<Self, Self::Error> {
+        if raw.servers.is_empty() {
+            return Err(anyhow!("servers of doh must not be empty"));
+        }
+        let servers = raw
+            .servers
+            .into_iter()
+            .map(|server| {
+                Ok(DoHServer {
+                    url: server.url.parse()?,
+                    address: server.address,
+                })
+            })
+            .collect::<Result<Vec<_>, Self::Error>>()?;
+        Ok(Resolver::doh(servers))
+    }
+}
+
+impl TryFrom<RawConnectionLimit> for ConnectionLimit {
+    type Error = Error;
+
+    fn try_from(raw: RawConnectionLimit) -> Result<Self, Self::Error> {
+        let excess = match raw.excess.unwrap_or(RawExcess::Queue) {
+            RawExcess::Queue => Excess::Queue {
+                timeout: raw.queue_timeout,
+            },
+            RawExcess::Reject => {
+                if raw.queue_timeout.is_some() {
+                    return Err(anyhow!("queue_timeout is only available on queue excess"));
+                }
+                Excess::Reject
+            }
+        };
+        Ok(Self {
+            network: raw.destination.map(|network| network.parse()).transpose()?,
+            port: raw.port,
+            max: raw.max,
+            excess,
+        })
+    }
+}
+
+impl RawConcurrencyConfig {
+    /// capacity returns the capacity of the max, None if it is unlimited.
+    fn capacity(&self, max: Option<usize>) -> Result<Option<Capacity>, Error> {
+        let max = match max {
+            None => return Ok(None),
+            Some(0) => return Err(anyhow!("max of concurrency must be positive")),
+            Some(max) => max,
+        };
+        let excess = match self.excess.unwrap_or(RawExcess::Queue) {
+            RawExcess::Queue => Excess::Queue {
+                timeout: self.queue_timeout,
+            },
+            RawExcess::Reject => {
+                if self.queue_timeout.is_some() || self.queue_size.is_some() {
+                    return Err(anyhow!(
+                        "queue_size and queue_timeout are only available on queue excess"
+                    ));
+                }
+                Excess::Reject
+            }
+        };
+        Ok(Some(Capacity {
+            max,
+            excess,
+            queue_size: self.queue_size.unwrap_or(max),
+        }))
+    }
+}
+
+impl TryFrom<RawSnapshotConfig> for SnapshotConfig {
+    type Error = Error;
+
+    fn try_from(raw: RawSnapshotConfig) -> Result<Self, Self::Error> {
+        let interval = raw.interval.unwrap_or(Duration::from_secs(60));
+        if interval.is_zero() {
+            return Err(anyhow!("interval of snapshot must be positive"));
+        }
+        Ok(Self {
+            dir: raw.path,
+            interval,
+            retention: raw.retention.unwrap_or(Duration::from_secs(24 * 60 * 60)),
+        })
+    }
+}
+
+impl From<RawAccessLogConfig> for AccessLogConfig {
+    fn from(raw: RawAccessLogConfig) -> Self {
+        Self {
+            path: Some(raw.path).filter(|path| path != Path::new("-")),
+        }
+    }
+}
+
+impl From<RawExperimentReportConfig> for ExperimentReportConfig {
+    fn from(raw: RawExperimentReportConfig) -> Self {
+        Self { path: raw.path }
+    }
+}
+
+impl From<RawUpstreamPool> for PoolConfig {
+    fn from(raw: RawUpstreamPool) -> Self {
+        let default = PoolConfig::default();
+        Self {
+            idle_timeout: raw.idle_timeout.unwrap_or(default.idle_timeout),
+            max_idle_per_host: raw.max_idle_per_host.unwrap_or(default.max_idle_per_host),
+        }
+    }
+}
+
+impl TryFrom<RawRuntimeConfig> for RuntimeConfig {
+    type Error = Error;
+
+    fn try_from(raw: RawRuntimeConfig) -> Result<Self, Self::Error> {
+        if raw.worker_threads == Some(0) {
+            return Err(anyhow!("worker_threads of runtime must be positive"));
+        }
+        if raw.accept_shards == Some(0) {
+            return Err(anyhow!("accept_shards of runtime must be positive"));
+        }
+        if raw.backlog == Some(0) {
+            return Err(anyhow!("backlog of runtime must be positive"));
+        }
+        let default = RuntimeConfig::default();
+        Ok(Self {
+            worker_threads: raw.worker_threads,
+            accept_shards: raw.accept_shards.unwrap_or(default.accept_shards),
+            socket: SocketOptions {
+                backlog: raw.backlog.unwrap_or(default.socket.backlog),
+                nodelay: raw.tcp_nodelay.unwrap_or(default.socket.nodelay),
+                recv_buffer: raw.recv_buffer,
+                send_buffer: raw.send_buffer,
+            },
+        })
+    }
+}
+
+impl TryFrom<RawHarConfig> for HarConfig {
+    type Error = Error;
+
+    fn try_from(raw: RawHarConfig) -> Result<Self, Self::Error> {
+        let max_entries = raw.max_entries.unwrap_or(10000);
+        if max_entries == 0 {
+            return Err(anyhow!("max_entries of har must be positive"));
+        }
+        Ok(Self {
+            path: raw.path,
+            all: raw.all.unwrap_or(false),
+            max_body: raw.max_body.unwrap_or(64 * 1024),
+            max_entries,
+        })
+    }
+}
+
+impl TryFrom<RawPcapConfig> for PcapConfig {
+    type Error = Error;
+
+    fn try_from(raw: RawPcapConfig) -> Result<Self, Self::Error> {
+        Ok(Self {
+            dir: raw.path,
+            network: raw.destination.map(|network| network.parse()).transpose()?,
+            port: raw.port,
+        })
+    }
+}
+
+impl TryFrom<RawTelemetryConfig> for TelemetryConfig {
+    type Error = Error;
+
+    fn try_from(raw: RawTelemetryConfig) -> Result<Self, Self::Error> {
+        raw.endpoint
+            .parse::<Uri>()
+            .map_err(|e| anyhow!("invalid endpoint of telemetry {}: {}", raw.endpoint, e))?;
+        Ok(Self {
+            endpoint: raw.endpoint,
+            service_name: raw
+                .service_name
+                .unwrap_or_else(|| "chaos-tproxy".to_string()),
+            propagate: raw.propagate.unwrap_or(false),
+        })
+    }
+}
+
+impl TryFrom<RawReportConfig> for ReportConfig {
+    type Error = Error;
+
+    fn try_from(raw: RawReportConfig) -> Result<Self, Self::Error> {
+        if raw.interval.is_zero() {
+            return Err(anyhow!("interval of report must be positive"));
+        }
+        Ok(Self {
+            url: raw.url.parse()?,
+            name: raw.name,
+            interval: raw.interval,
+            token: raw.token,
+        })
+    }
+}
+
+impl TryFrom<RawOptIn> for OptIn {
+    type Error = Error;
+
+    fn try_from(raw: RawOptIn) -> Result<Self, Self::Error> {
+        Ok(Self {
+            header: raw.header.parse()?,
+            value: raw.value.map(|value| value.parse()).transpose()?,
+        })
+    }
 }
 
-pub(crate) fn try_from_vec(
-    t: Option<Vec<(String, String)>>,
-) -> Result<Option<HeaderMap>, anyhow::Error> {
-    t.map(|headers| -> Result<_, anyhow::Error> {
-        let mut map = HeaderMap::new();
-        for (key, value) in headers {
-            map.insert(key.parse::<HeaderName>()?, value.parse()?);
-        }
-        Ok(map)
-    })
-    .transpose()
+impl TryFrom<RawCoordinationConfig> for CoordinationConfig {
+    type Error = Error;
+
+    fn try_from(raw: RawCoordinationConfig) -> Result<Self, Self::Error> {
+        let role = match raw.role {
+            RawCoordinationRole::Leader => {
+                if let Some(percent) = raw.max_faulted_percent {
+                    if !(0.0..=100.0).contains(&percent) {
+                        return Err(anyhow!("invalid max_faulted_percent: {}", percent));
+                    }
+                }
+                CoordinationRole::Leader {
+                    listen: raw
+                        .listen
+                        .ok_or_else(|| anyhow!("listen is required by the coordination leader"))?,
+                    max_faulted_percent: raw.max_faulted_percent,
+                }
+            }
+            RawCoordinationRole::Follower => CoordinationRole::Follower {
+                leader: raw
+                    .leader
+                    .ok_or_else(|| anyhow!("leader is required by the coordination follower"))?
+                    .parse()?,
+            },
+        };
+        Ok(Self {
+            role,
+            interval: raw.interval.unwrap_or(Duration::from_secs(1)),
+        })
+    }
 }
 
-impl Default for RawFile {
-    fn default() -> Self {
-        RawFile::Contents(Default::default())
+impl TryFrom<RawMetadataSource> for Arc<dyn MetadataResolver> {
+    type Error = Error;
+
+    fn try_from(raw: RawMetadataSource) -> Result<Self, Self::Error> {
+        Ok(match raw {
+            RawMetadataSource::CSV(path) => Arc::new(CSVResolver::load(&path)?),
+            RawMetadataSource::HTTP(http) => Arc::new(HTTPResolver::new(
+                http.url,
+                http.ttl.unwrap_or(Duration::from_secs(300)),
+            )?),
+            RawMetadataSource::MaxMind(path) => Arc::new(MaxMindResolver::load(&path)?),
+        })
     }
 }
 
-impl TryFrom<RawFile> for Vec<u8> {
+impl TryFrom<RawBaselineConfig> for BaselineConfig {
     type Error = Error;
 
-    fn try_from(value: RawFile) -> Result<Self, Self::Error> {
-        match value {
-            RawFile::Contents(c) => Ok(c),
-            RawFile::Path(p) => Ok(fs::read(p)?),
+    fn try_from(raw: RawBaselineConfig) -> Result<Self, Self::Error> {
+        if raw.duration.is_zero() {
+            return Err(anyhow!("duration of baseline must be positive"));
         }
+        Ok(Self {
+            duration: raw.duration,
+            max_paths: raw.max_paths.unwrap_or(100),
+        })
     }
 }
 
-impl TryFrom<RawConfig> for Config {
+impl TryFrom<SLORawConfig> for SLOConfig {
     type Error = Error;
 
-    fn try_from(raw: RawConfig) -> Result<Self, Self::Error> {
+    fn try_from(raw: SLORawConfig) -> Result<Self, Self::Error> {
+        let objective = raw.latency_objective.unwrap_or(99.0);
+        for percent in raw.availability.iter().chain(Some(&objective)) {
+            if !(0.0..=100.0).contains(percent) {
+                return Err(anyhow!("invalid slo objective: {}", percent));
+            }
+        }
         Ok(Self {
-            http_config: HTTPConfig {
-                listen_port: raw.listen_port,
-                role: raw.role,
-                rules: raw
-                    .rules
-                    .into_iter()
-                    .map(TryInto::try_into)
-                    .collect::<Result<Vec<_>, Self::Error>>()?,
-            },
-
-            tls_config: match raw.tls {
-                None => None,
-                Some(tls) => Some(tls.try_into()?),
-            },
+            availability: raw.availability,
+            latency_threshold: raw.latency_threshold,
+            latency_objective: objective,
         })
     }
 }
@@ -218,17 +1907,56 @@ impl TryFrom<TLSRawConfig> for TLSConfig {
     type Error = Error;
 
     fn try_from(raw: TLSRawConfig) -> Result<Self, Self::Error> {
-        let certs = certs(&mut &*Vec::<u8>::try_from(raw.cert_file)?)
-            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid cert"))
-            .map(|mut certs| certs.drain(..).map(Certificate).collect())?;
-        let keys: Vec<PrivateKey> = rsa_private_keys(&mut &*Vec::<u8>::try_from(raw.key_file)?)
-            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid key"))
-            .map(|mut keys| keys.drain(..).map(PrivateKey).collect())?;
+        let server_config = rustls::ServerConfig::builder().with_safe_defaults();
+        // the certificates of the clients are verified, but the anonymous clients are still served
+        let server_config = match raw.client_ca_file {
+            None => server_config.with_no_client_auth(),
+            Some(client_ca_file) => {
+                let mut client_roots = rustls::RootCertStore::empty();
+                for cert in certs(&mut &*Vec::<u8>::try_from(client_ca_file)?)? {
+                    client_roots
+                        .add(&Certificate(cert))
+                        .map_err(|e| anyhow!("invalid client ca: {}", e))?;
+                }
+                if client_roots.is_empty() {
+                    return Err(anyhow!("empty client ca"));
+                }
+                server_config.with_client_cert_verifier(
+                    AllowAnyAnonymousOrAuthenticatedClient::new(client_roots),
+                )
+            }
+        };
+        let mut tls_server_config = match (raw.mitm, raw.cert_file, raw.key_file) {
+            (Some(mitm), None, None) => {
+                let ca_cert = String::from_utf8(Vec::<u8>::try_from(mitm.ca_cert)?)?;
+                let ca_key = String::from_utf8(Vec::<u8>::try_from(mitm.ca_key)?)?;
+                server_config.with_cert_resolver(Arc::new(MITMResolver::new(&ca_cert, &ca_key)?))
+            }
+            (None, Some(cert_file), Some(key_file)) => {
+                let certs = certs(&mut &*Vec::<u8>::try_from(cert_file)?)
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid cert"))
+                    .map(|mut certs| certs.drain(..).map(Certificate).collect())?;
+                let keys: Vec<PrivateKey> = rsa_private_keys(&mut &*Vec::<u8>::try_from(key_file)?)
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid key"))
+                    .map(|mut keys| keys.drain(..).map(PrivateKey).collect())?;
 
-        if keys.is_empty() {
-            return Err(anyhow!("empty key"));
-        }
-        let key = keys[0].clone();
+                if keys.is_empty() {
+                    return Err(anyhow!("empty key"));
+                }
+                let key = keys[0].clone();
+                server_config
+                    .with_single_cert(certs, key)
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?
+            }
+            _ => {
+                return Err(anyhow!(
+                    "either cert_file and key_file, or mitm is required by tls"
+                ))
+            }
+        };
+
+        // negotiate HTTP/2 with the clients, the upstream is negotiated separately
+        tls_server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
 
         let mut root_cert_store = rustls::RootCertStore::empty();
         if let Some(cafile) = raw.ca_file {
@@ -259,33 +1987,234 @@ impl TryFrom<TLSRawConfig> for TLSConfig {
                 .with_safe_defaults()
                 .with_root_certificates(root_cert_store)
                 .with_no_client_auth(),
-            tls_server_config: rustls::ServerConfig::builder()
-                .with_safe_defaults()
-                .with_no_client_auth()
-                .with_single_cert(certs, key)
-                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?,
+            tls_server_config,
         };
         Ok(tls_config)
     }
 }
 
+/// check_actions checks the actions are available on the target, the alternatives of `weighted`
+/// are checked as well.
+fn check_actions(target: &Target, actions: &RawActions) -> Result<(), Error> {
+    if *target == Target::Response && actions.redirect.is_some() {
+        return Err(anyhow!(
+            "redirect action is only available on Request target"
+        ));
+    }
+    if *target == Target::Response && actions.duplicate.is_some() {
+        return Err(anyhow!(
+            "duplicate action is only available on Request target"
+        ));
+    }
+    if *target == Target::Response && actions.rate_limit.is_some() {
+        return Err(anyhow!(
+            "rate_limit action is only available on Request target"
+        ));
+    }
+    if *target == Target::Response && actions.mirror.is_some() {
+        return Err(anyhow!("mirror action is only available on Request target"));
+    }
+    if *target == Target::Response && actions.timeout.is_some() {
+        return Err(anyhow!(
+            "timeout action is only available on Request target"
+        ));
+    }
+    if *target == Target::Response && connect_fault(actions).is_some() {
+        return Err(anyhow!(
+            "connect_delay, connect_refused and dns_failure actions are only available on Request target"
+        ));
+    }
+    if actions.connect_refused == Some(true) && actions.dns_failure == Some(true) {
+        return Err(anyhow!(
+            "connect_refused and dns_failure actions are exclusive"
+        ));
+    }
+    if *target == Target::Response
+        && actions.delay_position == Some(RawDelayPosition::BeforeForward)
+    {
+        return Err(anyhow!(
+            "before_forward delay position is only available on Request target"
+        ));
+    }
+    if *target == Target::Request
+        && (actions.set_cookies.is_some() || actions.delete_cookies.is_some())
+    {
+        return Err(anyhow!(
+            "set_cookies and delete_cookies actions are only available on Response target"
+        ));
+    }
+    if (*target == Target::Tcp) != actions.tcp.is_some() {
+        return Err(anyhow!(
+            "tcp action is required by and only available on Tcp target"
+        ));
+    }
+    if *target == Target::Response && actions.withhold_continue.is_some() {
+        return Err(anyhow!(
+            "withhold_continue action is only available on Request target"
+        ));
+    }
+    if *target == Target::Response && actions.websocket.is_some() {
+        return Err(anyhow!(
+            "websocket action is only available on Request target"
+        ));
+    }
+    if *target == Target::Request && actions.dribble.is_some() {
+        return Err(anyhow!(
+            "dribble action is only available on Response target"
+        ));
+    }
+    if *target == Target::Request && actions.segment.is_some() {
+        return Err(anyhow!(
+            "segment action is only available on Response target"
+        ));
+    }
+    if *target == Target::Response && actions.client_ip.is_some() {
+        return Err(anyhow!(
+            "client_ip action is only available on Request target"
+        ));
+    }
+    if *target == Target::Request && actions.stall_body.is_some() {
+        return Err(anyhow!(
+            "stall_body action is only available on Response target"
+        ));
+    }
+    if *target == Target::Request && actions.truncate.is_some() {
+        return Err(anyhow!(
+            "truncate action is only available on Response target"
+        ));
+    }
+    if actions.dribble.is_some() && actions.segment.is_some() {
+        return Err(anyhow!("dribble and segment actions are exclusive"));
+    }
+    for alternative in actions.weighted.iter().flatten() {
+        check_actions(target, &alternative.actions)?;
+    }
+    Ok(())
+}
+
+/// connect_fault returns the fault of the connection to the upstream of the actions, if any.
+fn connect_fault(actions: &RawActions) -> Option<ConnectFault> {
+    let failure = if actions.connect_refused == Some(true) {
+        Some(ConnectFailure::Refused)
+    } else if actions.dns_failure == Some(true) {
+        Some(ConnectFailure::DnsFailure)
+    } else {
+        None
+    };
+    if actions.connect_delay.is_none() && failure.is_none() {
+        return None;
+    }
+    Some(ConnectFault {
+        delay: actions.connect_delay,
+        failure,
+    })
+}
+
 impl TryFrom<RawRule> for Rule {
     type Error = Error;
 
     fn try_from(rule: RawRule) -> Result<Self, Self::Error> {
+        // the sessions are drawn apart for each rule, by its name, or its config if unnamed
+        let sticky = match &rule.sticky_on {
+            None => None,
+            Some(on) => Some(Sticky {
+                on: match on {
+                    RawStickyOn::Header(header) => StickyOn::Header(header.parse()?),
+                    RawStickyOn::Cookie(cookie) => StickyOn::Cookie(cookie.clone()),
+                    RawStickyOn::SourceIp => StickyOn::SourceIp,
+                },
+                seed: match &rule.name {
+                    Some(name) => name.clone(),
+                    None => serde_json::to_string(&rule)?,
+                },
+            }),
+        };
+        let direction = rule.target.direction();
+        let target: Target = rule.target.into();
+        check_actions(&target, &rule.actions)?;
+        if target == Target::Tcp && rule.follow_up.is_some() {
+            return Err(anyhow!("follow_up is not available on Tcp target"));
+        }
+        if target == Target::Tcp && sticky.is_some() {
+            return Err(anyhow!("sticky_on is not available on Tcp target"));
+        }
+        // the lifetimes are kept across the reloads by the names of the rules
+        if rule.duration.is_some() && rule.name.is_none() {
+            return Err(anyhow!("duration is only available on named rules"));
+        }
+        let (name, decode_body, echo_applied, problem_json, fault_marker) = (
+            rule.name,
+            rule.decode_body,
+            rule.echo_applied,
+            rule.problem_json,
+            rule.fault_marker,
+        );
+        let priority = rule.priority;
+        let follow_up = rule
+            .follow_up
+            .map(|follow_up| -> Result<FollowUp, Error> {
+                // the follow-up is validated as a Request target rule matching any request
+                let follow_up_rule = RawRule {
+                    name: name.clone(),
+                    enabled: None,
+                    duration: None,
+                    priority,
+                    target: RawTarget::Request,
+                    selector: Default::default(),
+                    actions: follow_up.actions,
+                    decode_body,
+                    echo_applied,
+                    problem_json,
+                    fault_marker,
+                    follow_up: None,
+                    sticky_on: None,
+                };
+                Ok(FollowUp {
+                    duration: follow_up.duration,
+                    same_client: follow_up.same_client.unwrap_or(true),
+                    rule: Box::new(follow_up_rule.try_into()?),
+                })
+            })
+            .transpose()
+            .map_err(|e| anyhow!("invalid follow_up: {}", e))?;
         Ok(Self {
-            target: rule.target.into(),
+            name,
+            enabled: rule.enabled.unwrap_or(true),
+            duration: rule.duration,
+            priority: rule.priority.unwrap_or(0),
+            target,
+            direction,
             selector: rule.selector.try_into()?,
             actions: rule.actions.try_into()?,
+            decode_body: decode_body.unwrap_or(false),
+            echo_applied: echo_applied.unwrap_or(false),
+            problem_json: problem_json.unwrap_or(false),
+            fault_marker: fault_marker.unwrap_or(true),
+            follow_up,
+            sticky,
         })
     }
 }
 
+impl From<RawMatchPolicy> for MatchPolicy {
+    fn from(policy: RawMatchPolicy) -> Self {
+        match policy {
+            RawMatchPolicy::First => MatchPolicy::First,
+            RawMatchPolicy::All => MatchPolicy::All,
+        }
+    }
+}
+
 impl From<RawTarget> for Target {
     fn from(target: RawTarget) -> Self {
         match target {
-            RawTarget::Request => Target::Request,
-            RawTarget::Response => Target::Response,
+            RawTarget::Request | RawTarget::ClientRequest | RawTarget::ServerRequest => {
+                Target::Request
+            }
+            RawTarget::Response | RawTarget::ClientResponse | RawTarget::ServerResponse => {
+                Target::Response
+            }
+            RawTarget::Tcp => Target::Tcp,
         }
     }
 }
@@ -302,22 +2231,504 @@ impl TryFrom<RawSelector> for Selector {
                 .as_ref()
                 .map(|method| method.parse())
                 .transpose()?,
-            request_headers: try_from_hash_map(raw.request_headers)?,
+            request_headers: try_from_header_fields(raw.request_headers)?,
             code: raw.code.map(StatusCode::from_u16).transpose()?,
-            response_headers: try_from_hash_map(raw.response_headers)?,
+            response_headers: try_from_header_fields(raw.response_headers)?,
+            user_agent: raw
+                .user_agent
+                .map(|ua| UserAgentSelector::new(&ua.family, ua.version.as_deref())),
+            ja3: raw.ja3.map(|ja3| ja3.to_lowercase()),
+            tls_client: raw.tls_client.map(|tls_client| TlsClientSelector {
+                subject: tls_client.subject.as_deref().map(WildMatch::new),
+                san: tls_client.san.as_deref().map(WildMatch::new),
+                alpn: tls_client.alpn,
+            }),
+            labels: raw.labels,
+            time_window: raw
+                .time_window
+                .map(|w| {
+                    TimeWindow::new(
+                        w.days.as_deref(),
+                        w.start.as_deref(),
+                        w.end.as_deref(),
+                        w.timezone.as_deref(),
+                    )
+                })
+                .transpose()?,
+            pressure: raw
+                .pressure
+                .map(|p| PressureSelector::new(p.cpu, p.memory, p.load)),
+            percent: match raw.percent {
+                Some(percent) if !(0.0..=100.0).contains(&percent) => {
+                    return Err(anyhow!("invalid percent of selector: {}", percent))
+                }
+                percent => percent,
+            },
         })
     }
 }
 
+impl TryFrom<RawLatencyProfile> for LatencyProfile {
+    type Error = Error;
+
+    fn try_from(raw: RawLatencyProfile) -> Result<Self, Self::Error> {
+        let latency = |ms: f64| {
+            if ms.is_finite() && ms >= 0.0 {
+                Ok(Duration::from_secs_f64(ms / 1000.0))
+            } else {
+                Err(anyhow!("invalid latency {}ms of latency profile", ms))
+            }
+        };
+        let profile = match (raw.samples_ms, raw.percentiles_ms) {
+            (Some(samples), None) => LatencyProfile::Samples(
+                samples
+                    .into_iter()
+                    .map(latency)
+                    .collect::<Result<_, Self::Error>>()?,
+            ),
+            (None, Some(percentiles)) => {
+                let mut sorted = vec![];
+                for (key, ms) in percentiles {
+                    let percentile = key
+                        .strip_prefix('p')
+                        .unwrap_or(&key)
+                        .parse::<f64>()
+                        .ok()
+                        .filter(|percentile| (0.0..=100.0).contains(percentile))
+                        .ok_or_else(|| anyhow!("invalid percentile {} of latency profile", key))?;
+                    sorted.push((percentile, latency(ms)?));
+                }
+                // the percentiles are never NaN
+                sorted.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+                LatencyProfile::Percentiles(sorted)
+            }
+            _ => {
+                return Err(anyhow!(
+                    "latency profile requires either samples_ms or percentiles_ms"
+                ))
+            }
+        };
+        match &profile {
+            LatencyProfile::Samples(samples) if samples.is_empty() => {
+                Err(anyhow!("samples_ms of latency profile must not be empty"))
+            }
+            LatencyProfile::Percentiles(percentiles) if percentiles.is_empty() => Err(anyhow!(
+                "percentiles_ms of latency profile must not be empty"
+            )),
+            _ => Ok(profile),
+        }
+    }
+}
+
 impl TryFrom<RawActions> for Actions {
     type Error = Error;
 
     fn try_from(raw: RawActions) -> Result<Self, Self::Error> {
+        if raw.delay.is_some() && raw.delay_profile.is_some() {
+            return Err(anyhow!("delay and delay_profile are exclusive"));
+        }
+        let weighted = raw
+            .weighted
+            .clone()
+            .map(|alternatives| -> Result<WeightedActions, Error> {
+                let others = RawActions {
+                    pattern: None,
+                    weighted: None,
+                    ..raw.clone()
+                };
+                if others != RawActions::default() {
+                    return Err(anyhow!(
+                        "weighted actions are exclusive with the other actions but pattern"
+                    ));
+                }
+                if alternatives
+                    .iter()
+                    .all(|alternative| alternative.weight == 0)
+                {
+                    return Err(anyhow!("weighted actions require a positive weight"));
+                }
+                if alternatives
+                    .iter()
+                    .any(|alternative| alternative.actions.weighted.is_some())
+                {
+                    return Err(anyhow!("weighted actions could not be nested"));
+                }
+                // the request is mirrored before the alternative of the rule is drawn
+                if alternatives
+                    .iter()
+                    .any(|alternative| alternative.actions.mirror.is_some())
+                {
+                    return Err(anyhow!(
+                        "mirror action is not available in weighted actions"
+                    ));
+                }
+                let alternatives = alternatives
+                    .into_iter()
+                    .map(|alternative| Ok((alternative.weight, alternative.actions.try_into()?)))
+                    .collect::<Result<_, Error>>()?;
+                Ok(WeightedActions(alternatives))
+            })
+            .transpose()?;
+        let connect = connect_fault(&raw);
+        let (abort, abort_response) = match raw.abort {
+            None => (false, None),
+            Some(RawAbort::Enabled(abort)) => (abort, None),
+            Some(RawAbort::Response(response)) => (false, Some(response.try_into()?)),
+        };
         Ok(Self {
-            abort: raw.abort.unwrap_or(false),
+            abort,
+            abort_mode: raw.abort_mode.map(Into::into).unwrap_or_default(),
+            abort_response,
+            dedup: raw.dedup.map(TryInto::try_into).transpose()?,
+            duplicate: raw.duplicate.map(Into::into),
+            mirror: raw.mirror.map(TryInto::try_into).transpose()?,
             delay: raw.delay,
+            delay_profile: raw
+                .delay_profile
+                .map(|path| LatencyProfile::load(&path).map(Arc::new))
+                .transpose()?,
+            delay_position: match raw.delay_position {
+                None | Some(RawDelayPosition::BeforeForward) => DelayPosition::BeforeForward,
+                Some(RawDelayPosition::AfterReceive) => DelayPosition::AfterReceive,
+            },
+            timeout: raw.timeout.map(TryInto::try_into).transpose()?,
+            connect,
+            remove_headers: raw
+                .remove_headers
+                .map(|patterns| {
+                    patterns
+                        .iter()
+                        .map(|pattern| pattern.parse::<HeaderPattern>())
+                        .collect()
+                })
+                .transpose()?,
             replace: raw.replace.map(TryInto::try_into).transpose()?,
             patch: raw.patch.map(TryInto::try_into).transpose()?,
+            redirect: raw.redirect.map(TryInto::try_into).transpose()?,
+            pattern: raw.pattern.map(TryInto::try_into).transpose()?,
+            rate_limit: raw.rate_limit.map(TryInto::try_into).transpose()?,
+            set_cookies: raw
+                .set_cookies
+                .map(|cookies| cookies.into_iter().map(TryInto::try_into).collect())
+                .transpose()?,
+            delete_cookies: raw
+                .delete_cookies
+                .map(|cookies| cookies.into_iter().map(TryInto::try_into).collect())
+                .transpose()?,
+            dribble: raw.dribble.map(TryInto::try_into).transpose()?,
+            segment: raw.segment.map(TryInto::try_into).transpose()?,
+            framing: raw.framing.map(|framing| match framing {
+                RawFraming::Chunked => Framing::Chunked,
+                RawFraming::ContentLength => Framing::ContentLength,
+            }),
+            trailers: try_from_hash_map(raw.trailers)?,
+            websocket: raw.websocket.map(TryInto::try_into).transpose()?,
+            withhold_continue: raw.withhold_continue,
+            stall_body: raw.stall_body,
+            truncate: raw.truncate.map(TryInto::try_into).transpose()?,
+            client_ip: raw.client_ip.map(TryInto::try_into).transpose()?,
+            tcp: raw.tcp.map(TryInto::try_into).transpose()?,
+            weighted,
+        })
+    }
+}
+
+impl TryFrom<RawTcpAction> for TcpAction {
+    type Error = Error;
+
+    fn try_from(raw: RawTcpAction) -> Result<Self, Self::Error> {
+        let reset_percent = raw.reset_percent.unwrap_or(0.0);
+        let corrupt_percent = raw.corrupt_percent.unwrap_or(0.0);
+        for percent in [reset_percent, corrupt_percent] {
+            if !(0.0..=100.0).contains(&percent) {
+                return Err(anyhow!("invalid percent of tcp action: {}", percent));
+            }
+        }
+        if raw.bandwidth == Some(0) {
+            return Err(anyhow!("bandwidth of tcp action must be positive"));
+        }
+        Ok(Self {
+            delay: raw.delay,
+            jitter: raw.jitter,
+            bandwidth: raw.bandwidth,
+            reset_percent,
+            corrupt_percent,
+        })
+    }
+}
+
+impl TryFrom<RawWebSocketAction> for WebSocketAction {
+    type Error = Error;
+
+    fn try_from(raw: RawWebSocketAction) -> Result<Self, Self::Error> {
+        let drop_percent = raw.drop_percent.unwrap_or(0.0);
+        if !(0.0..=100.0).contains(&drop_percent) {
+            return Err(anyhow!("invalid drop_percent: {}", drop_percent));
+        }
+        Ok(Self {
+            delay: raw.delay,
+            drop_percent,
+            close: raw.close.map(|close| WebSocketClose {
+                code: close.code.unwrap_or(1011),
+                after: close.after,
+            }),
+        })
+    }
+}
+
+impl TryFrom<RawDribbleAction> for DribbleAction {
+    type Error = Error;
+
+    fn try_from(raw: RawDribbleAction) -> Result<Self, Self::Error> {
+        let chunk_size = raw.chunk_size.unwrap_or(1);
+        if chunk_size == 0 {
+            return Err(anyhow!("chunk_size of dribble must be positive"));
+        }
+        Ok(Self {
+            chunk_size,
+            duration: raw.duration,
+            never_finish: raw.never_finish.unwrap_or(false),
+        })
+    }
+}
+
+impl TryFrom<RawTruncateAction> for TruncateAction {
+    type Error = Error;
+
+    fn try_from(raw: RawTruncateAction) -> Result<Self, Self::Error> {
+        let after = match (raw.after_bytes, raw.after_percent) {
+            (Some(bytes), None) => TruncateAfter::Bytes(bytes),
+            (None, Some(percent)) if (0.0..=100.0).contains(&percent) => {
+                TruncateAfter::Percent(percent)
+            }
+            (None, Some(percent)) => {
+                return Err(anyhow!("invalid after_percent of truncate: {}", percent))
+            }
+            _ => {
+                return Err(anyhow!(
+                    "either after_bytes or after_percent of truncate is required"
+                ))
+            }
+        };
+        Ok(Self {
+            after,
+            mode: raw.mode.map(Into::into).unwrap_or_default(),
+        })
+    }
+}
+
+impl TryFrom<RawClientIpAction> for ClientIpAction {
+    type Error = Error;
+
+    fn try_from(raw: RawClientIpAction) -> Result<Self, Self::Error> {
+        let mode = match raw.mode {
+            None | Some(RawClientIpMode::Append) => ClientIpMode::Append,
+            Some(RawClientIpMode::Set) => ClientIpMode::Set,
+            Some(RawClientIpMode::Strip) => ClientIpMode::Strip,
+        };
+        if mode == ClientIpMode::Strip && raw.address.is_some() {
+            return Err(anyhow!("address of client_ip is useless in strip mode"));
+        }
+        let headers = match raw.headers {
+            None => vec![
+                ClientIpHeader::XForwardedFor,
+                ClientIpHeader::XRealIp,
+                ClientIpHeader::Forwarded,
+            ],
+            Some(headers) if headers.is_empty() => {
+                return Err(anyhow!("headers of client_ip must not be empty"))
+            }
+            Some(headers) => headers
+                .into_iter()
+                .map(|header| match header {
+                    RawClientIpHeader::XForwardedFor => ClientIpHeader::XForwardedFor,
+                    RawClientIpHeader::XRealIp => ClientIpHeader::XRealIp,
+                    RawClientIpHeader::Forwarded => ClientIpHeader::Forwarded,
+                })
+                .collect(),
+        };
+        Ok(Self {
+            mode,
+            address: raw.address,
+            headers,
+        })
+    }
+}
+
+impl TryFrom<RawSegmentAction> for SegmentAction {
+    type Error = Error;
+
+    fn try_from(raw: RawSegmentAction) -> Result<Self, Self::Error> {
+        if raw.size == 0 {
+            return Err(anyhow!("size of segment must be positive"));
+        }
+        Ok(Self {
+            size: raw.size,
+            inter_segment_delay: raw.inter_segment_delay,
+        })
+    }
+}
+
+impl TryFrom<RawCookie> for Cookie {
+    type Error = Error;
+
+    fn try_from(raw: RawCookie) -> Result<Self, Self::Error> {
+        Ok(Self {
+            path: raw.path,
+            domain: raw.domain,
+            max_age: raw.max_age,
+            secure: raw.secure.unwrap_or(false),
+            http_only: raw.http_only.unwrap_or(false),
+            same_site: raw.same_site.map(|same_site| match same_site {
+                RawSameSite::Strict => SameSite::Strict,
+                RawSameSite::Lax => SameSite::Lax,
+                RawSameSite::None => SameSite::None,
+            }),
+            ..Cookie::new(raw.name, raw.value)?
+        })
+    }
+}
+
+impl TryFrom<RawDeleteCookie> for Cookie {
+    type Error = Error;
+
+    fn try_from(raw: RawDeleteCookie) -> Result<Self, Self::Error> {
+        Cookie::expired(raw.name, raw.path, raw.domain)
+    }
+}
+
+impl From<RawAbortMode> for AbortMode {
+    fn from(mode: RawAbortMode) -> Self {
+        match mode {
+            RawAbortMode::Reset => AbortMode::Reset,
+            RawAbortMode::Close => AbortMode::Close,
+            RawAbortMode::Timeout => AbortMode::Timeout,
+        }
+    }
+}
+
+impl TryFrom<RawRateLimitAction> for RateLimitAction {
+    type Error = Error;
+
+    fn try_from(raw: RawRateLimitAction) -> Result<Self, Self::Error> {
+        if raw.requests == 0 || raw.per.is_zero() {
+            return Err(anyhow!("requests and per of rate limit must be positive"));
+        }
+        Ok(RateLimitAction::new(raw.requests, raw.per))
+    }
+}
+
+impl TryFrom<RawPatternAction> for PatternAction {
+    type Error = Error;
+
+    fn try_from(raw: RawPatternAction) -> Result<Self, Self::Error> {
+        let duty_cycle = raw.duty_cycle.unwrap_or(0.5);
+        if !(0.0..=1.0).contains(&duty_cycle) {
+            return Err(anyhow!("invalid duty cycle: {}", duty_cycle));
+        }
+        if raw.period.is_zero() {
+            return Err(anyhow!("period of pattern must be positive"));
+        }
+        Ok(Self {
+            shape: match raw.shape {
+                RawShape::Square => Shape::Square,
+                RawShape::Sine => Shape::Sine,
+                RawShape::Ramp => Shape::Ramp,
+            },
+            period: raw.period,
+            duty_cycle,
+            started_at: SystemTime::now(),
+        })
+    }
+}
+
+impl TryFrom<RawMirrorAction> for MirrorAction {
+    type Error = Error;
+
+    fn try_from(raw: RawMirrorAction) -> Result<Self, Self::Error> {
+        let percent = raw.percent.unwrap_or(100);
+        if percent > 100 {
+            return Err(anyhow!("invalid mirror percent: {}", percent));
+        }
+        Ok(Self {
+            target: raw.target.parse()?,
+            percent,
+        })
+    }
+}
+
+impl From<RawDuplicateAction> for DuplicateAction {
+    fn from(raw: RawDuplicateAction) -> Self {
+        Self {
+            count: raw.count,
+            interval: raw.interval,
+        }
+    }
+}
+
+impl TryFrom<RawDedupAction> for DedupAction {
+    type Error = Error;
+
+    fn try_from(raw: RawDedupAction) -> Result<Self, Self::Error> {
+        let header = raw
+            .header
+            .as_deref()
+            .unwrap_or("idempotency-key")
+            .parse::<HeaderName>()?;
+        let mode = match raw.mode {
+            None | Some(RawDedupMode::Retries) => DedupMode::Retries,
+            Some(RawDedupMode::First) => DedupMode::First,
+        };
+        Ok(DedupAction::new(
+            header,
+            raw.ttl.unwrap_or(Duration::from_secs(300)),
+            mode,
+        ))
+    }
+}
+
+impl TryFrom<RawAbortResponse> for AbortResponse {
+    type Error = Error;
+
+    fn try_from(raw: RawAbortResponse) -> Result<Self, Self::Error> {
+        Ok(Self {
+            code: StatusCode::from_u16(raw.code)?,
+            headers: try_from_hash_map(raw.headers)?,
+            body: raw.body.map(String::into_bytes).unwrap_or_default(),
+        })
+    }
+}
+
+impl TryFrom<RawTimeoutAction> for TimeoutAction {
+    type Error = Error;
+
+    fn try_from(raw: RawTimeoutAction) -> Result<Self, Self::Error> {
+        if raw.after.is_zero() {
+            return Err(anyhow!("after of timeout action must be positive"));
+        }
+        Ok(Self {
+            after: raw.after,
+            abort_mode: raw.abort_mode.map(Into::into),
+        })
+    }
+}
+
+impl TryFrom<RawRedirectAction> for RedirectAction {
+    type Error = Error;
+
+    fn try_from(raw: RawRedirectAction) -> Result<Self, Self::Error> {
+        let code = raw
+            .code
+            .map(StatusCode::from_u16)
+            .transpose()?
+            .unwrap_or(StatusCode::FOUND);
+        // the other 3xx codes, e.g. 304, carry no Location to follow
+        if !matches!(code.as_u16(), 301 | 302 | 303 | 307 | 308) {
+            return Err(anyhow!("invalid redirect code: {}", code));
+        }
+        Ok(Self {
+            code,
+            location: raw.location,
         })
     }
 }
@@ -384,6 +2795,8 @@ impl TryFrom<RawReplaceAction> for ReplaceAction {
             code: raw.code.map(StatusCode::from_u16).transpose()?,
             queries: raw.queries,
             headers: try_from_hash_map(raw.headers)?,
+            authority: raw.authority.map(|a| a.parse()).transpose()?,
+            upstream: raw.upstream.map(|u| u.parse()).transpose()?,
         })
     }
 }
@@ -0,0 +1,75 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use http::header::AUTHORIZATION;
+use http::{Method, Request, Uri};
+use hyper::{Body, Client};
+use serde::{Deserialize, Serialize};
+use tokio::time::sleep;
+
+use crate::metrics::{ComparisonReport, Metrics, PhaseReport};
+
+/// ReportConfig makes the proxy push its metrics to the controller of a cluster.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReportConfig {
+    /// `POST` target of the reports, e.g. `http://10.0.0.1:7070/report`.
+    pub url: Uri,
+    /// name of this agent in the cluster.
+    pub name: String,
+    pub interval: Duration,
+    /// token is presented to the controller as a bearer token if set.
+    pub token: Option<String>,
+}
+
+/// AgentReport is the metrics of an agent since its proxy started.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AgentReport {
+    pub name: String,
+    pub window_secs: f64,
+    pub baseline: PhaseReport,
+    pub faulted: PhaseReport,
+    pub comparison: Option<ComparisonReport>,
+}
+
+impl AgentReport {
+    pub fn new(name: String, metrics: &Metrics) -> Self {
+        let (baseline, faulted) = metrics.phase_reports();
+        Self {
+            name,
+            window_secs: metrics.elapsed().as_secs_f64(),
+            baseline,
+            faulted,
+            comparison: metrics.comparison_report(),
+        }
+    }
+}
+
+/// push_reports sends the report to the controller every interval until the future is dropped.
+pub async fn push_reports(config: ReportConfig, metrics: Arc<Metrics>) {
+    let client = Client::new();
+    loop {
+        sleep(config.interval).await;
+        let report = AgentReport::new(config.name.clone(), &metrics);
+        if let Err(e) = push(&client, &config, &report).await {
+            tracing::warn!("fail to push report to {}: {}", config.url, e);
+        }
+    }
+}
+
+async fn push(
+    client: &Client<hyper::client::HttpConnector>,
+    config: &ReportConfig,
+    report: &AgentReport,
+) -> Result<()> {
+    let mut request = Request::builder().method(Method::POST).uri(&config.url);
+    if let Some(token) = &config.token {
+        request = request.header(AUTHORIZATION, format!("Bearer {}", token));
+    }
+    let request = request.body(Body::from(serde_json::to_vec(report)?))?;
+    let response = client.request(request).await?;
+    if !response.status().is_success() {
+        return Err(anyhow!("unexpected status {}", response.status()));
+    }
+    Ok(())
+}
@@ -1,8 +1,14 @@
+#[cfg(unix)]
 use futures::future::select_all;
+#[cfg(unix)]
 use tokio::signal::unix::{signal, Signal, SignalKind};
+#[cfg(windows)]
+use tokio::signal::windows::{ctrl_break, ctrl_c, CtrlBreak, CtrlC};
 
+#[cfg(unix)]
 pub struct Signals(Vec<Signal>);
 
+#[cfg(unix)]
 impl Signals {
     pub fn from_kinds<'a>(
         kinds: impl 'a + IntoIterator<Item = &'a SignalKind>,
@@ -14,8 +20,35 @@ impl Signals {
         Ok(Self(signals))
     }
 
+    /// shutdown returns the signals stopping the process, `SIGINT` and `SIGTERM`.
+    pub fn shutdown() -> anyhow::Result<Self> {
+        Self::from_kinds(&[SignalKind::interrupt(), SignalKind::terminate()])
+    }
+
     pub async fn wait(&mut self) -> anyhow::Result<()> {
         select_all(self.0.iter_mut().map(|sig| Box::pin(sig.recv()))).await;
         Ok(())
     }
 }
+
+/// Signals are the `Ctrl-C` and `Ctrl-Break` of the console on Windows, which has no signals to
+/// reload or arm the proxy.
+#[cfg(windows)]
+pub struct Signals(CtrlC, CtrlBreak);
+
+#[cfg(windows)]
+impl Signals {
+    /// shutdown returns the events of the console stopping the process, `Ctrl-C` and
+    /// `Ctrl-Break`.
+    pub fn shutdown() -> anyhow::Result<Self> {
+        Ok(Self(ctrl_c()?, ctrl_break()?))
+    }
+
+    pub async fn wait(&mut self) -> anyhow::Result<()> {
+        tokio::select! {
+            _ = self.0.recv() => {}
+            _ = self.1.recv() => {}
+        }
+        Ok(())
+    }
+}
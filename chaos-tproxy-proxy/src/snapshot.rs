@@ -0,0 +1,189 @@
+use std::ffi::OsStr;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use serde::Serialize;
+use tokio::fs;
+use tokio::time::sleep;
+
+use crate::metrics::{BaselineReport, ComparisonReport, Metrics, PhaseReport, SLOReport};
+use crate::timeline::Event;
+
+const PREFIX: &str = "snapshot-";
+
+/// SnapshotConfig makes the proxy persist its metrics to local files, so that the evidence of
+/// the injected faults is retained even if the metrics pipeline is down.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SnapshotConfig {
+    /// directory of the snapshot files, named `snapshot-<unix millis>.json`.
+    pub dir: PathBuf,
+    pub interval: Duration,
+    /// snapshots older than the retention are removed.
+    pub retention: Duration,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Snapshot {
+    pub timestamp_ms: u64,
+    pub window_secs: f64,
+    pub baseline: PhaseReport,
+    pub faulted: PhaseReport,
+    pub comparison: Option<ComparisonReport>,
+    pub slo: Option<SLOReport>,
+    /// captured_baseline contrasts the experiment with the baseline captured before it.
+    pub captured_baseline: Option<BaselineReport>,
+    pub events: Vec<Event>,
+}
+
+impl Snapshot {
+    pub fn new(metrics: &Metrics) -> Self {
+        let (baseline, faulted) = metrics.phase_reports();
+        Self {
+            timestamp_ms: unix_millis(SystemTime::now()),
+            window_secs: metrics.elapsed().as_secs_f64(),
+            baseline,
+            faulted,
+            comparison: metrics.comparison_report(),
+            slo: metrics.slo_report(),
+            captured_baseline: metrics.baseline_report(),
+            events: metrics.timeline().events(),
+        }
+    }
+}
+
+/// write_snapshots persists the metrics every interval until the future is dropped.
+pub async fn write_snapshots(config: SnapshotConfig, metrics: Arc<Metrics>) {
+    loop {
+        sleep(config.interval).await;
+        if let Err(e) = write_snapshot(&config, &metrics).await {
+            tracing::warn!("fail to write snapshot to {:?}: {}", config.dir, e);
+        }
+    }
+}
+
+/// write_snapshot writes a snapshot of the metrics, and removes the expired ones.
+pub async fn write_snapshot(config: &SnapshotConfig, metrics: &Metrics) -> Result<()> {
+    let snapshot = Snapshot::new(metrics);
+    fs::create_dir_all(&config.dir).await?;
+    let path = config
+        .dir
+        .join(format!("{}{}.json", PREFIX, snapshot.timestamp_ms));
+    // rename is atomic, so that a crash never leaves a partial snapshot
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, serde_json::to_vec(&snapshot)?).await?;
+    fs::rename(&tmp, &path).await?;
+
+    let expired_before = SystemTime::now()
+        .checked_sub(config.retention)
+        .map(unix_millis)
+        .unwrap_or_default();
+    remove_expired(&config.dir, expired_before).await
+}
+
+/// read_latest_snapshot returns the latest snapshot of the directory, e.g. for the admin API of
+/// the controller to serve the SLO report of the running proxy. It is `None` if no snapshot is
+/// written yet.
+pub async fn read_latest_snapshot(dir: &Path) -> Result<Option<serde_json::Value>> {
+    let mut entries = match fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut latest = None;
+    while let Some(entry) = entries.next_entry().await? {
+        if let Some(timestamp) = snapshot_timestamp(&entry.file_name()) {
+            if latest
+                .as_ref()
+                .is_none_or(|(latest, _)| timestamp > *latest)
+            {
+                latest = Some((timestamp, entry.path()));
+            }
+        }
+    }
+    match latest {
+        Some((_, path)) => Ok(Some(serde_json::from_slice(&fs::read(path).await?)?)),
+        None => Ok(None),
+    }
+}
+
+async fn remove_expired(dir: &Path, expired_before: u64) -> Result<()> {
+    let mut entries = fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let timestamp = snapshot_timestamp(&entry.file_name());
+        if matches!(timestamp, Some(timestamp) if timestamp < expired_before) {
+            fs::remove_file(entry.path()).await?;
+        }
+    }
+    Ok(())
+}
+
+/// snapshot_timestamp returns the timestamp of the name of a snapshot file.
+fn snapshot_timestamp(name: &OsStr) -> Option<u64> {
+    name.to_str()
+        .and_then(|name| name.strip_prefix(PREFIX))
+        .and_then(|name| name.strip_suffix(".json"))
+        .and_then(|timestamp| timestamp.parse::<u64>().ok())
+}
+
+pub(crate) fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::metrics::Metrics;
+    use crate::snapshot::{read_latest_snapshot, write_snapshot, SnapshotConfig};
+
+    #[tokio::test]
+    async fn test_write_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let stale = dir.path().join("snapshot-1000.json");
+        std::fs::write(&stale, "{}").unwrap();
+        let unrelated = dir.path().join("notes.txt");
+        std::fs::write(&unrelated, "").unwrap();
+
+        let metrics = Metrics::new(None, None);
+        metrics.record(true, Duration::from_millis(3), true);
+        let config = SnapshotConfig {
+            dir: dir.path().to_path_buf(),
+            interval: Duration::from_secs(1),
+            retention: Duration::from_secs(3600),
+        };
+        write_snapshot(&config, &metrics).await.unwrap();
+
+        assert!(!stale.exists());
+        assert!(unrelated.exists());
+        let snapshots: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().unwrap() == "json")
+            .collect();
+        assert_eq!(snapshots.len(), 1);
+        let snapshot: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&snapshots[0]).unwrap()).unwrap();
+        assert_eq!(snapshot["faulted"]["errors"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_read_latest_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(read_latest_snapshot(&dir.path().join("missing"))
+            .await
+            .unwrap()
+            .is_none());
+        assert!(read_latest_snapshot(dir.path()).await.unwrap().is_none());
+
+        std::fs::write(dir.path().join("snapshot-2000.json"), r#"{"slo": 2}"#).unwrap();
+        std::fs::write(dir.path().join("snapshot-1000.json"), r#"{"slo": 1}"#).unwrap();
+        std::fs::write(dir.path().join("snapshot-3000.tmp"), "").unwrap();
+        let snapshot = read_latest_snapshot(dir.path()).await.unwrap().unwrap();
+        assert_eq!(snapshot["slo"], 2);
+    }
+}
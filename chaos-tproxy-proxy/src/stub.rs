@@ -0,0 +1,187 @@
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use bytes::Bytes;
+use http::header::CONTENT_TYPE;
+use http::{Request, Response, StatusCode};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Server};
+use serde::Serialize;
+use tokio::sync::oneshot::Receiver;
+
+use crate::clock::{Clock, SystemClock};
+use crate::handler::http::action::{
+    apply_request_action, apply_response_action, echo_applied, problem_json, synthesize_response,
+};
+use crate::handler::http::exchange::ExchangeId;
+use crate::handler::http::rule::{Direction, Rule, Target};
+use crate::handler::http::selector::{select_request, select_response};
+
+/// Behavior introduces how the stub server responds.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Behavior {
+    /// Respond the method, URI, headers and body of the request as JSON.
+    Echo,
+    /// Always respond the given code and body.
+    Static { code: StatusCode, body: Bytes },
+    /// Respond 503 with the given probability, otherwise echo.
+    Flaky { failure_rate: f64 },
+}
+
+#[derive(Debug, Clone)]
+pub struct StubConfig {
+    pub port: u16,
+    pub behavior: Behavior,
+    /// rules would be applied to the exchanges of the stub as if they were proxied, the exchanges
+    /// are inbound.
+    pub rules: Vec<Rule>,
+}
+
+/// StubServer is a simple upstream for demos, tests and trainings.
+pub struct StubServer {
+    config: Arc<StubConfig>,
+    /// loaded is the start of the durations of the rules.
+    loaded: Instant,
+}
+
+#[derive(Debug, Serialize)]
+struct Echo {
+    method: String,
+    uri: String,
+    headers: BTreeMap<String, String>,
+    body: String,
+}
+
+impl StubServer {
+    pub fn new(config: StubConfig) -> Self {
+        Self {
+            config: Arc::new(config),
+            loaded: Instant::now(),
+        }
+    }
+
+    pub async fn serve(&self, rx: Receiver<()>) -> Result<()> {
+        let addr = SocketAddr::from(([0, 0, 0, 0], self.config.port));
+        let (config, loaded) = (self.config.clone(), self.loaded);
+        let make_service = make_service_fn(move |_| {
+            let config = config.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    Self::handle(config.clone(), loaded, request)
+                }))
+            }
+        });
+        tracing::info!("Stub listening on {}", addr);
+        Server::try_bind(&addr)?
+            .serve(make_service)
+            .with_graceful_shutdown(async {
+                let _ = rx.await;
+            })
+            .await?;
+        Ok(())
+    }
+
+    async fn handle(
+        config: Arc<StubConfig>,
+        loaded: Instant,
+        mut request: Request<Body>,
+    ) -> Result<Response<Body>> {
+        let port = config.port;
+        let exchange = ExchangeId::next();
+        request.extensions_mut().insert(exchange);
+        let mut response_delay = Duration::ZERO;
+        let mut applied = vec![];
+        for (index, rule) in config.rules.iter().enumerate() {
+            if rule.target == Target::Request
+                && rule.direction != Some(Direction::Outbound)
+                && select_request(port, &request, &rule.selector)
+                && rule.is_active(None, &SystemClock)
+                && !rule.expired(loaded, Instant::now())
+                && rule.admits(request.headers(), request.extensions())
+            {
+                let actions = rule.sampled(request.headers(), request.extensions());
+                request = apply_request_action(request, &actions, &SystemClock).await?;
+                if rule.echo_applied {
+                    applied.extend(actions.summary());
+                }
+                if let Some(mut response) = synthesize_response(&request, &actions, &SystemClock)? {
+                    if rule.problem_json {
+                        problem_json(&mut response, request.uri(), index, &actions)?;
+                    }
+                    echo_applied(&mut response, &applied)?;
+                    return Ok(response);
+                }
+                response_delay += actions.response_delay().unwrap_or_default();
+            }
+        }
+
+        let uri = request.uri().clone();
+        let method = request.method().clone();
+        let headers = request.headers().clone();
+        let mut response = match &config.behavior {
+            Behavior::Echo => echo(request).await?,
+            Behavior::Static { code, body } => Response::builder()
+                .status(*code)
+                .body(body.clone().into())?,
+            Behavior::Flaky { failure_rate } => {
+                if rand::random::<f64>() < *failure_rate {
+                    Response::builder()
+                        .status(StatusCode::SERVICE_UNAVAILABLE)
+                        .body(Body::empty())?
+                } else {
+                    echo(request).await?
+                }
+            }
+        };
+        SystemClock.sleep(response_delay).await;
+        response.extensions_mut().insert(exchange);
+
+        for (index, rule) in config.rules.iter().enumerate() {
+            if rule.target == Target::Response
+                && rule.direction != Some(Direction::Outbound)
+                && select_response(port, &uri, &method, &headers, &response, &rule.selector)
+                && rule.is_active(None, &SystemClock)
+                && !rule.expired(loaded, Instant::now())
+                && rule.admits(&headers, response.extensions())
+            {
+                let actions = rule.sampled(&headers, response.extensions());
+                response = apply_response_action(response, &method, &actions, &SystemClock).await?;
+                if rule.problem_json && actions.abort_response.is_some() {
+                    problem_json(&mut response, &uri, index, &actions)?;
+                }
+                if rule.echo_applied {
+                    applied.extend(actions.summary());
+                }
+            }
+        }
+        echo_applied(&mut response, &applied)?;
+        Ok(response)
+    }
+}
+
+async fn echo(request: Request<Body>) -> Result<Response<Body>> {
+    let (parts, body) = request.into_parts();
+    let body = hyper::body::to_bytes(body).await?;
+    let echo = Echo {
+        method: parts.method.to_string(),
+        uri: parts.uri.to_string(),
+        headers: parts
+            .headers
+            .iter()
+            .map(|(name, value)| {
+                (
+                    name.to_string(),
+                    String::from_utf8_lossy(value.as_bytes()).to_string(),
+                )
+            })
+            .collect(),
+        body: String::from_utf8_lossy(&body).to_string(),
+    };
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(serde_json::to_vec(&echo)?.into())?)
+}
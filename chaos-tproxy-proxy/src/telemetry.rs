@@ -0,0 +1,177 @@
+use anyhow::Result;
+use hyper::{Body, Request, Response};
+use opentelemetry::propagation::TextMapPropagator;
+use opentelemetry::sdk::propagation::TraceContextPropagator;
+use opentelemetry::sdk::{trace, Resource};
+use opentelemetry::KeyValue;
+use opentelemetry_http::{HeaderExtractor, HeaderInjector};
+use opentelemetry_otlp::WithExportConfig;
+use tracing::field::Empty;
+use tracing::{Dispatch, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::Registry;
+
+use crate::access_log::Attribution;
+use crate::handler::http::exchange::ExchangeId;
+use crate::handler::http::tap::tap;
+
+/// TelemetryConfig makes the proxy export a span of every exchange over OTLP.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TelemetryConfig {
+    /// endpoint of the OTLP collector over gRPC, e.g. `http://otel-collector:4317`.
+    pub endpoint: String,
+    pub service_name: String,
+    /// propagate continues the traces of the requests by their `traceparent` headers, and passes
+    /// the span of the proxy to the upstream as the parent.
+    pub propagate: bool,
+}
+
+/// Telemetry creates the spans of the exchanges. The spans are recorded by a subscriber of their
+/// own, so that the logs of the proxy are left to the global subscriber.
+pub struct Telemetry {
+    dispatch: Dispatch,
+    propagate: bool,
+}
+
+impl Telemetry {
+    /// new installs the OTLP pipeline, the spans are exported in batches on the runtime.
+    pub fn new(config: &TelemetryConfig) -> Result<Self> {
+        let tracer =
+            opentelemetry_otlp::new_pipeline()
+                .tracing()
+                .with_exporter(
+                    opentelemetry_otlp::new_exporter()
+                        .tonic()
+                        .with_endpoint(&config.endpoint),
+                )
+                .with_trace_config(trace::config().with_resource(Resource::new(vec![
+                    KeyValue::new("service.name", config.service_name.clone()),
+                ])))
+                .install_batch(opentelemetry::runtime::Tokio)?;
+        let subscriber =
+            Registry::default().with(tracing_opentelemetry::layer().with_tracer(tracer));
+        Ok(Self {
+            dispatch: Dispatch::new(subscriber),
+            propagate: config.propagate,
+        })
+    }
+
+    /// start begins the span of an exchange.
+    pub fn start(&self, request: &mut Request<Body>) -> Span {
+        let span = tracing::dispatcher::with_default(&self.dispatch, || {
+            tracing::info_span!(
+                "exchange",
+                otel.name = %format!("{} {}", request.method(), request.uri().path()),
+                otel.kind = "server",
+                otel.status_code = Empty,
+                http.method = %request.method(),
+                http.target = %request.uri().path(),
+                http.status_code = Empty,
+                chaos.exchange = %ExchangeId::of(request.extensions()),
+                chaos.rules = Empty,
+                chaos.faults = Empty,
+                error = Empty,
+            )
+        });
+        if self.propagate {
+            propagate(&span, request);
+        }
+        span
+    }
+
+    /// shutdown exports the spans left.
+    pub fn shutdown() {
+        opentelemetry::global::shutdown_tracer_provider();
+    }
+}
+
+/// propagate sets the parent of the span by the `traceparent` header of the request, and replaces
+/// the header by the span, so that the upstream continues the trace as a child of the proxy.
+fn propagate(span: &Span, request: &mut Request<Body>) {
+    let propagator = TraceContextPropagator::new();
+    let parent = propagator.extract(&HeaderExtractor(request.headers()));
+    span.set_parent(parent);
+    propagator.inject_context(&span.context(), &mut HeaderInjector(request.headers_mut()));
+}
+
+/// finish records the rules and the faults applied to the exchange on the span, which ends once
+/// the response body is sent, or dropped by the client.
+pub fn finish(
+    span: Span,
+    attribution: &Attribution,
+    result: Result<Response<Body>>,
+) -> Result<Response<Body>> {
+    if !attribution.applied.is_empty() {
+        span.record("chaos.rules", &attribution.applied.join(",").as_str());
+        span.record("chaos.faults", &attribution.faults.join(",").as_str());
+    }
+    match result {
+        Ok(response) => {
+            let status = response.status();
+            span.record("http.status_code", &status.as_u16());
+            if status.is_server_error() {
+                span.record("otel.status_code", &"ERROR");
+            }
+            let (parts, body) = response.into_parts();
+            Ok(Response::from_parts(parts, tap(body, |_| {}, span)))
+        }
+        Err(e) => {
+            span.record("otel.status_code", &"ERROR");
+            span.record("error", &e.to_string().as_str());
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::{Body, Request};
+    use opentelemetry::trace::TraceContextExt;
+    use tracing::Dispatch;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+    use tracing_subscriber::layer::SubscriberExt;
+    use tracing_subscriber::Registry;
+
+    use crate::telemetry::Telemetry;
+
+    #[test]
+    fn test_propagate() {
+        // the spans are not exported without a pipeline
+        let provider = opentelemetry::sdk::trace::TracerProvider::default();
+        let tracer = opentelemetry::trace::TracerProvider::tracer(&provider, "test");
+        let telemetry = Telemetry {
+            dispatch: Dispatch::new(
+                Registry::default().with(tracing_opentelemetry::layer().with_tracer(tracer)),
+            ),
+            propagate: true,
+        };
+        let trace_id = "4bf92f3577b34da6a3ce929d0e0e4736";
+        let mut request = Request::get("/api")
+            .header(
+                "traceparent",
+                format!("00-{}-00f067aa0ba902b7-01", trace_id),
+            )
+            .body(Body::empty())
+            .unwrap();
+        let span = telemetry.start(&mut request);
+        let context = span.context();
+        let span_context = context.span().span_context().clone();
+        assert_eq!(span_context.trace_id().to_string(), trace_id);
+
+        // the upstream gets the span of the proxy as its parent
+        let traceparent = request.headers()["traceparent"].to_str().unwrap();
+        assert_eq!(
+            traceparent,
+            format!("00-{}-{}-01", trace_id, span_context.span_id())
+        );
+
+        let telemetry = Telemetry {
+            propagate: false,
+            ..telemetry
+        };
+        let mut request = Request::get("/api").body(Body::empty()).unwrap();
+        telemetry.start(&mut request);
+        assert!(request.headers().get("traceparent").is_none());
+    }
+}
@@ -0,0 +1,248 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use anyhow::Result;
+use http::{HeaderValue, Request, Response};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Server};
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+
+use crate::clock::SystemClock;
+use crate::handler::http::rule::Rule;
+use crate::metrics::Metrics;
+use crate::proxy::http::config::HTTPConfig;
+use crate::proxy::http::server::serve_upstream;
+use crate::timeline::EventKind;
+
+/// Harness runs the rules in process on an ephemeral port of the loopback, without iptables, so
+/// that the services could be tested against the faults in their integration tests. Every
+/// connection is forwarded to the upstream, the `port` selectors match the port of the upstream.
+///
+/// ```no_run
+/// # async fn run() -> anyhow::Result<()> {
+/// use chaos_tproxy_proxy::handler::http::rule::Rule;
+/// use chaos_tproxy_proxy::testing::{EchoServer, Harness};
+///
+/// let upstream = EchoServer::start().await?;
+/// let rule = Rule::request().name("abort-api").path("/api/*").abort_with(503);
+/// let harness = Harness::start(upstream.addr(), vec![rule.build()?]).await?;
+/// // ... call harness.url("/api/users") from the service under test
+/// harness.assert_fired("abort-api");
+/// # Ok(())
+/// # }
+/// ```
+pub struct Harness {
+    addr: SocketAddr,
+    rules: Vec<Rule>,
+    metrics: Arc<Metrics>,
+    serving: JoinHandle<Result<()>>,
+}
+
+impl Harness {
+    pub async fn start(upstream: SocketAddr, rules: Vec<Rule>) -> Result<Self> {
+        let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0))).await?;
+        let addr = listener.local_addr()?;
+        let config = Arc::new(HTTPConfig {
+            listen_port: addr.port(),
+            rules: rules.clone(),
+            role: None,
+            compare_mode: false,
+            latency_compensation: false,
+            fault_markers: false,
+            inspect_all: false,
+            opt_in: None,
+            exchange_id_header: None,
+            resolver: Default::default(),
+            proxy_protocol: Default::default(),
+            clock: Arc::new(SystemClock),
+            follow_ups: Default::default(),
+            match_policy: Default::default(),
+            validator: None,
+            upstream_pool: Default::default(),
+            body_limit: None,
+            listener_rules: Default::default(),
+            rule_set: None,
+        });
+        let metrics = Arc::new(Metrics::new(None, None));
+        let serving = tokio::spawn(serve_upstream(listener, upstream, config, metrics.clone()));
+        Ok(Self {
+            addr,
+            rules,
+            metrics,
+            serving,
+        })
+    }
+
+    /// addr returns the address the clients should call instead of the upstream.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// url returns the URL of the path behind the harness.
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.addr, path)
+    }
+
+    /// fired returns the labels of the rules applied at least once, the name of each rule or
+    /// `#<index>` if unnamed, in order of their first application.
+    pub fn fired(&self) -> Vec<String> {
+        self.metrics
+            .timeline()
+            .events()
+            .into_iter()
+            .filter_map(|event| match event.kind {
+                EventKind::RuleActivated { rule, .. } => Some(self.rules[rule].label(rule)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// assert_fired panics unless the rule of the label has been applied.
+    pub fn assert_fired(&self, label: &str) {
+        let fired = self.fired();
+        assert!(
+            fired.iter().any(|fired| fired == label),
+            "rule {} has not fired, fired rules: {:?}",
+            label,
+            fired
+        );
+    }
+
+    /// assert_not_fired panics if the rule of the label has been applied.
+    pub fn assert_not_fired(&self, label: &str) {
+        assert!(
+            !self.fired().iter().any(|fired| fired == label),
+            "rule {} has fired",
+            label
+        );
+    }
+
+    /// metrics returns the recorder of the exchanges, e.g. for the latencies seen by the clients.
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        self.serving.abort();
+    }
+}
+
+/// EchoServer is an upstream answering every request with its body, the method and the path of
+/// the request are echoed in the `x-echo-method` and `x-echo-path` headers.
+pub struct EchoServer {
+    addr: SocketAddr,
+    requests: Arc<AtomicUsize>,
+    serving: JoinHandle<()>,
+}
+
+impl EchoServer {
+    pub async fn start() -> Result<Self> {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let make_service = make_service_fn(move |_| {
+            let counter = counter.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    async move { Ok::<_, Infallible>(echo(request)) }
+                }))
+            }
+        });
+        let server = Server::try_bind(&SocketAddr::from(([127, 0, 0, 1], 0)))?.serve(make_service);
+        let addr = server.local_addr();
+        let serving = tokio::spawn(async move {
+            let _ = server.await;
+        });
+        Ok(Self {
+            addr,
+            requests,
+            serving,
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// requests returns the number of requests received, e.g. to check an aborted request never
+    /// reached the upstream.
+    pub fn requests(&self) -> usize {
+        self.requests.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for EchoServer {
+    fn drop(&mut self) {
+        self.serving.abort();
+    }
+}
+
+fn echo(request: Request<Body>) -> Response<Body> {
+    let method = HeaderValue::from_str(request.method().as_str());
+    let path = request
+        .uri()
+        .path_and_query()
+        .map(|path| HeaderValue::from_str(path.as_str()));
+    let mut response = Response::new(request.into_body());
+    if let Ok(method) = method {
+        response.headers_mut().insert("x-echo-method", method);
+    }
+    if let Some(Ok(path)) = path {
+        response.headers_mut().insert("x-echo-path", path);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use http::StatusCode;
+    use hyper::{Body, Client, Request};
+
+    use crate::handler::http::rule::Rule;
+    use crate::testing::{EchoServer, Harness};
+
+    #[tokio::test]
+    async fn test_harness() {
+        let upstream = EchoServer::start().await.unwrap();
+        let rules = vec![
+            Rule::request()
+                .name("abort-api")
+                .path("/api/*")
+                .abort_with(503)
+                .build()
+                .unwrap(),
+            Rule::request()
+                .name("slow-static")
+                .path("/static/*")
+                .delay_ms(1)
+                .build()
+                .unwrap(),
+        ];
+        let harness = Harness::start(upstream.addr(), rules).await.unwrap();
+        let client = Client::new();
+
+        let request = Request::post(harness.url("/echo?q=1"))
+            .body(Body::from("hello"))
+            .unwrap();
+        let response = client.request(request).await.unwrap();
+        assert_eq!(response.headers()["x-echo-method"], "POST");
+        assert_eq!(response.headers()["x-echo-path"], "/echo?q=1");
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "hello");
+        assert!(harness.fired().is_empty());
+
+        let response = client
+            .get(harness.url("/api/users").parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(upstream.requests(), 1);
+        harness.assert_fired("abort-api");
+        harness.assert_not_fired("slow-static");
+    }
+}
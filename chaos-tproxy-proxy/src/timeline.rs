@@ -0,0 +1,137 @@
+use std::collections::{HashSet, VecDeque};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::handler::http::exchange::ExchangeId;
+
+/// Only the latest events are kept.
+const CAPACITY: usize = 1024;
+
+/// EventKind is what happened in the experiment.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EventKind {
+    /// The proxy started, or was reloaded, with the given number of rules.
+    Started { rules: usize },
+    /// The rule (index in the config, and its name if any) matched an exchange for the first time,
+    /// the ID of the exchange pairs the event with the logs of the exchange.
+    RuleActivated {
+        rule: usize,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        exchange: Option<String>,
+    },
+    /// The duration of the rule (index in the config, and its name if any) is over, it no longer
+    /// applies.
+    RuleExpired {
+        rule: usize,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+    },
+    /// The baseline of the given number of paths is captured, and the rules start to apply.
+    BaselineCaptured { paths: usize },
+    /// A cap of the experiment was reached, and the faults are stopped.
+    ThresholdBreached { reason: String },
+    /// The faults are allowed again.
+    ThresholdRecovered { reason: String },
+    /// The rules apply again after the proxy was disarmed.
+    Armed,
+    /// The rules are paused by the operator, the traffic is forwarded transparently.
+    Disarmed,
+    /// The proxy is shutting down.
+    Teardown,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Event {
+    pub timestamp_ms: u64,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+/// Timeline is the chronology of the experiment, for the post-experiment reviews.
+#[derive(Debug, Default)]
+pub struct Timeline {
+    events: Mutex<VecDeque<Event>>,
+    activated: Mutex<HashSet<usize>>,
+}
+
+impl Timeline {
+    pub fn record(&self, kind: EventKind) {
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let mut events = self.events.lock().unwrap();
+        if events.len() == CAPACITY {
+            events.pop_front();
+        }
+        events.push_back(Event { timestamp_ms, kind });
+    }
+
+    /// rule_applied records the activation of the rule if it is applied for the first time, to the
+    /// exchange of the ID if any.
+    pub fn rule_applied(&self, rule: usize, name: Option<&str>, exchange: Option<ExchangeId>) {
+        if self.activated.lock().unwrap().insert(rule) {
+            self.record(EventKind::RuleActivated {
+                rule,
+                name: name.map(str::to_string),
+                exchange: exchange.map(|exchange| exchange.to_string()),
+            });
+        }
+    }
+
+    pub fn events(&self) -> Vec<Event> {
+        self.events.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::handler::http::exchange::ExchangeId;
+    use crate::timeline::{EventKind, Timeline, CAPACITY};
+
+    #[test]
+    fn test_timeline() {
+        let timeline = Timeline::default();
+        timeline.record(EventKind::Started { rules: 2 });
+        timeline.rule_applied(1, Some("slow-api"), Some(ExchangeId(1)));
+        timeline.rule_applied(1, Some("slow-api"), Some(ExchangeId(2)));
+        timeline.rule_applied(0, None, None);
+        let kinds: Vec<_> = timeline.events().into_iter().map(|e| e.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                EventKind::Started { rules: 2 },
+                EventKind::RuleActivated {
+                    rule: 1,
+                    name: Some("slow-api".to_string()),
+                    exchange: Some("0000000000000001".to_string()),
+                },
+                EventKind::RuleActivated {
+                    rule: 0,
+                    name: None,
+                    exchange: None,
+                },
+            ]
+        );
+        let activated = serde_json::to_value(&timeline.events()[1]).unwrap();
+        assert_eq!(activated["type"], "rule_activated");
+        assert_eq!(activated["name"], "slow-api");
+        assert_eq!(activated["exchange"], "0000000000000001");
+        assert!(serde_json::to_value(&timeline.events()[2])
+            .unwrap()
+            .get("name")
+            .is_none());
+
+        for _ in 0..CAPACITY {
+            timeline.record(EventKind::Teardown);
+        }
+        let events = timeline.events();
+        assert_eq!(events.len(), CAPACITY);
+        assert!(events.iter().all(|e| e.kind == EventKind::Teardown));
+    }
+}
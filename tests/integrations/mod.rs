@@ -1,2 +1,3 @@
+mod test_exchange_id;
 mod test_http_action;
 mod test_uds;
@@ -0,0 +1,72 @@
+use std::convert::{Infallible, TryInto};
+use std::net::SocketAddr;
+
+use chaos_tproxy_proxy::embedded::Proxy;
+use chaos_tproxy_proxy::raw_config::{RawConfig, RawExplicitConfig, RawExplicitProtocol};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{sleep, Duration};
+
+/// test_exchange_id_without_rules checks that the exchanges are stamped with their IDs even if no
+/// rule applies to them, instead of being relayed raw.
+#[tokio::test]
+async fn test_exchange_id_without_rules() {
+    let upstream =
+        Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|_: Request<Body>| async {
+                Ok::<_, Infallible>(Response::new(Body::from("ok")))
+            }))
+        }));
+    let upstream_addr = upstream.local_addr();
+    tokio::spawn(upstream);
+
+    // the port of the explicit proxy is picked by binding it once
+    let listen: SocketAddr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let raw = RawConfig {
+        explicit: Some(RawExplicitConfig {
+            listen,
+            protocol: Some(RawExplicitProtocol::Http),
+        }),
+        exchange_id_header: Some("x-chaos-exchange".to_owned()),
+        ..Default::default()
+    };
+    let mut proxy = Proxy::new(raw.try_into().unwrap());
+    proxy.start().unwrap();
+
+    let mut stream = loop {
+        match TcpStream::connect(listen).await {
+            Ok(stream) => break stream,
+            Err(_) => sleep(Duration::from_millis(10)).await,
+        }
+    };
+    // the tunnel is served as an intercepted connection to the upstream
+    stream
+        .write_all(format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", upstream_addr).as_bytes())
+        .await
+        .unwrap();
+    let mut established = [0; 39];
+    stream.read_exact(&mut established).await.unwrap();
+    assert!(established.starts_with(b"HTTP/1.1 200"));
+
+    let (mut sender, connection) = hyper::client::conn::handshake(stream).await.unwrap();
+    tokio::spawn(connection);
+    let response = sender
+        .send_request(
+            Request::get("/")
+                .header("host", upstream_addr.to_string())
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert!(response.headers().contains_key("x-chaos-exchange"));
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert_eq!(body, "ok");
+
+    proxy.stop().await.unwrap();
+}
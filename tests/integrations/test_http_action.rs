@@ -1,6 +1,6 @@
-use chaos_tproxy_proxy::handler::http::action::{apply_request_action, Actions, ReplaceAction};
+use chaos_tproxy_proxy::clock::SystemClock;
+use chaos_tproxy_proxy::handler::http::action::{apply_request_action, Actions};
 use http::header::CONTENT_LENGTH;
-use http::HeaderMap;
 use hyper::{Body, Client, Method, Request};
 
 #[tokio::test]
@@ -14,26 +14,14 @@ async fn test_http_content_length_replace() {
         .body(Body::from(data.clone()))
         .unwrap();
 
-    let mut headers = HeaderMap::new();
-    headers.insert(
-        CONTENT_LENGTH,
-        (data.len() - 2).to_string().parse().unwrap(),
-    );
-    let actions = Actions {
-        abort: false,
-        delay: None,
-        replace: Some(ReplaceAction {
-            path: None,
-            method: None,
-            body: None,
-            code: None,
-            queries: None,
-            headers: Some(headers),
-        }),
-        patch: None,
-    };
+    let actions = Actions::builder()
+        .replace_header(CONTENT_LENGTH.as_str(), (data.len() - 2).to_string())
+        .build()
+        .unwrap();
 
-    let req = apply_request_action(req, &actions).await.unwrap();
+    let req = apply_request_action(req, &actions, &SystemClock)
+        .await
+        .unwrap();
     let err = client.request(req).await.err().unwrap();
     assert!(err.is_incomplete_message());
 }
@@ -28,4 +28,11 @@ async fn test_uds() {
     let mut buf: Vec<u8> = vec![];
     let data_o: String = client.read_into(&mut buf).await.unwrap();
     assert_eq!(data, data_o);
+
+    // the clones share the data served
+    let data = Uuid::new_v4().to_string();
+    uds_server.update(data.clone());
+    let mut buf: Vec<u8> = vec![];
+    let data_o: String = client.read_into(&mut buf).await.unwrap();
+    assert_eq!(data, data_o);
 }